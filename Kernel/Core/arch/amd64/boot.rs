@@ -52,6 +52,15 @@ struct MultibootInfo
 	vbe_interface_len: u32,
 }
 
+#[repr(C)]
+struct MultibootModule
+{
+	mod_start: u32,
+	mod_end: u32,
+	string: u32,
+	_reserved: u32,
+}
+
 #[repr(C)]
 #[allow(unused)]
 #[derive(Debug)]
@@ -99,6 +108,7 @@ struct MultibootParsed
 	vidmode: Option<VideoMode>,
 	memmap: &'static [::memory::MemoryMapEnt],
 	symbol_info: SymbolInfo,
+	modules: &'static [::arch::boot::BootModule],
 }
 struct UefiParsed
 {
@@ -128,6 +138,7 @@ extern "C"
 	static s_multiboot_pointer : *const ::Void;
 }
 static mut S_MEMMAP_DATA: [::memory::MemoryMapEnt; 16] = [::memory::MAP_PAD; 16];
+static mut S_MODULE_DATA: [::arch::boot::BootModule; 8] = [::arch::boot::MODULE_PAD; 8];
 static mut S_BOOTINFO: BootInfo = BootInfo::Uninit;
 
 fn get_bootinfo() -> &'static BootInfo
@@ -197,6 +208,14 @@ impl BootInfo
 		BootInfo::Uefi(ref i) => i.memmap,
 		}
 	}
+	pub fn modules(&self) -> &'static [::arch::boot::BootModule]
+	{
+		match *self
+		{
+		BootInfo::Multiboot(ref i) => i.modules,
+		_ => &[],
+		}
+	}
 }
 
 unsafe fn valid_c_str_to_slice(ptr: *const i8) -> Option<&'static str>
@@ -234,6 +253,8 @@ impl MultibootParsed
 				vidmode: MultibootParsed::_vidmode(info),
 				symbol_info: MultibootParsed::_syminfo(info),
 				memmap: &[],
+				// SAFE: Should only be called before threading is initialised, so no race
+				modules: unsafe { MultibootParsed::_modules(info, &mut S_MODULE_DATA) },
 			};
 		// SAFE: Should only be called before threading is initialised, so no race
 		ret.memmap = unsafe { ret._memmap(info, &mut S_MEMMAP_DATA) };
@@ -340,6 +361,49 @@ impl MultibootParsed
 		unsafe { valid_c_str_to_slice(charptr).unwrap_or("-INVALID-") }
 	}
 	
+	fn _modules<'a>(info: &MultibootInfo, buf: &'a mut [::arch::boot::BootModule]) -> &'a [::arch::boot::BootModule]
+	{
+		if (info.flags & 1 << 3) == 0 || info.module_count == 0 {
+			return &[];
+		}
+		
+		let count = info.module_count as usize;
+		let list_vaddr = info.module_first as usize + IDENT_START;
+		if list_vaddr + count * ::core::mem::size_of::<MultibootModule>() > IDENT_END {
+			log_error!("Multiboot module list {:#x} outside identity range", info.module_first);
+			return &[];
+		}
+		if count > buf.len() {
+			log_warning!("{} multiboot modules, only {} supported", count, buf.len());
+		}
+		
+		// SAFE: Range checked above, bootloader data is valid for 'static
+		let list = unsafe { ::core::slice::from_raw_parts(list_vaddr as *const MultibootModule, count) };
+		let mut n = 0;
+		for (ent, slot) in Iterator::zip(list.iter(), buf.iter_mut())
+		{
+			if ent.mod_end < ent.mod_start {
+				log_error!("Multiboot module {:#x}--{:#x} malformed", ent.mod_start, ent.mod_end);
+				continue ;
+			}
+			let cmdline = if ent.string != 0 && (ent.string as usize + IDENT_START) < IDENT_END {
+					// SAFE: Module strings are valid for 'static
+					unsafe { valid_c_str_to_slice( (ent.string as usize + IDENT_START) as *const i8 ).unwrap_or("") }
+				}
+				else {
+					""
+				};
+			log_log!("Boot module {:#x}+{:#x} '{}'", ent.mod_start, ent.mod_end - ent.mod_start, cmdline);
+			*slot = ::arch::boot::BootModule {
+				base: ent.mod_start as ::arch::memory::PAddr,
+				len: (ent.mod_end - ent.mod_start) as usize,
+				cmdline: cmdline,
+				};
+			n += 1;
+		}
+		&buf[..n]
+	}
+	
 	fn _vidmode(info: &MultibootInfo) -> Option<VideoMode>
 	{
 		if (info.flags & 1 << 11) == 0 {
@@ -434,6 +498,17 @@ impl MultibootParsed
 					::memory::MemoryState::Used, 0).ok().unwrap();
 				},
 			}
+			// - Modules (and their strings)
+			for m in self.modules
+			{
+				if m.len > 0 {
+					mapbuilder.set_range( m.base, m.len as u64, ::memory::MemoryState::Used, 0 ).ok().unwrap();
+				}
+				if m.cmdline.len() > 0 {
+					mapbuilder.set_range( m.cmdline.as_ptr() as u64 - IDENT_START as u64, m.cmdline.len() as u64,
+						::memory::MemoryState::Used, 0 ).ok().unwrap();
+				}
+			}
 			
 			mapbuilder.size()
 			};
@@ -534,5 +609,11 @@ pub fn get_memory_map() -> &'static[::memory::MemoryMapEnt]
 	get_bootinfo().memmap()
 }
 
+/// Obtain the list of multiboot modules
+pub fn get_modules() -> &'static [::arch::boot::BootModule]
+{
+	get_bootinfo().modules()
+}
+
 // vim: ft=rust
 
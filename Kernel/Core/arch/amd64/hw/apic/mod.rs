@@ -20,6 +20,13 @@ pub struct IRQHandle
 	isr_handle: ::arch::imp::interrupts::ISRHandle,
 }
 
+/// A bound message-signalled interrupt (an ISR on the local APIC)
+pub struct MsiHandle
+{
+	lapic_id: u32,
+	isr_handle: ::arch::imp::interrupts::ISRHandle,
+}
+
 #[derive(Debug,Copy,Clone)]
 pub enum IrqError
 {
@@ -108,16 +115,33 @@ fn get_lapic() -> &'static raw::LAPIC
 	&*s_lapic
 }
 
-///// Registers a message-signalled interrupt handler.
-//pub fn register_msi(callback: fn (*const()), info: *const ()) -> Result<(uint,::arch::interrupts::ISRHandle),()>
-//{
-//	// 1. Find a spare ISR slot on a processor
-//	let lapic_id = 0;
-//	let isrnum = 33u;
-//	// 2. Bind
-//	let h = try!(::arch::interrupts::bind_isr(lapic_id, isrnum as u8, callback, info, cleanup_nop));
-//	Ok( (isrnum, h) )
-//}
+/// Registers a message-signalled interrupt handler
+///
+/// The device is programmed with the handle's `address` and `data`.
+pub fn register_msi(callback: IRQHandler, info: *const ()) -> Result<MsiHandle,IrqError>
+{
+	// TODO: Pick a suitable processor (as with `register_irq`)
+	let lapic_id = 0u32;
+	// - The callback is passed via the ISR's index value
+	let isr_handle = match ::arch::imp::interrupts::bind_free_isr(msi_irq_handler, info, callback as usize)
+		{
+		Ok(v) => v,
+		Err(e) => return Err(IrqError::BindFail(e)),
+		};
+	Ok( MsiHandle {
+		lapic_id: lapic_id,
+		isr_handle: isr_handle,
+		} )
+}
+
+/// Message-signalled interrupt handler (no IOAPIC involved)
+extern "C" fn msi_irq_handler(isr: usize, info: *const(), callback: usize)
+{
+	// SAFE: `callback` is the `IRQHandler` passed to `register_msi`
+	let cb: IRQHandler = unsafe { ::core::mem::transmute(callback) };
+	cb(info);
+	get_lapic().eoi(isr);
+}
 
 /// Local + IO APIC interrupt handler
 //#[req_safe(irq)]
@@ -185,6 +209,17 @@ impl IRQHandle
 {
 	pub fn num(&self) -> u32 { self.num as u32 }
 }
+impl MsiHandle
+{
+	/// Message address (targets the local APIC, physical destination mode)
+	pub fn address(&self) -> u64 {
+		0xFEE0_0000 | (self.lapic_id as u64) << 12
+	}
+	/// Message data (fixed delivery, edge triggered)
+	pub fn data(&self) -> u32 {
+		self.isr_handle.idx() as u32
+	}
+}
 impl ::core::fmt::Debug for IRQHandle
 {
 	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> Result<(),::core::fmt::Error>
@@ -105,6 +105,7 @@ impl HPET
 	pub fn bind_irq(&mut self)
 	{
 		self.irq_handle = ::arch::imp::hw::apic::register_irq(2, HPET::irq, self as *mut _ as *const _).unwrap();
+		::time::set_tick_source();
 	}
 	pub fn ticks_per_ms(&self) -> u64
 	{
@@ -121,6 +122,7 @@ impl HPET
 		s.write_reg(HPETReg::ISR as usize, s.read_reg(HPETReg::ISR as usize));
 		
 		s.oneshot(0, s.current() + 100*1000 );
+		::time::time_tick();
 	}
 	
 	fn read_reg(&self, reg: usize) -> u64 {
@@ -55,6 +55,8 @@ pub enum BindISRError
 pub use super::hw::apic::IRQHandle;
 pub use super::hw::apic::IrqError as BindError;
 pub use super::hw::apic::register_irq as bind_gsi;
+pub use super::hw::apic::MsiHandle;
+pub use super::hw::apic::register_msi as bind_msi;
 
 /// Bind a callback (and params) to an allocatable ISR
 pub fn bind_isr(isr: u8, callback: ISRHandler, info: *const(), idx: usize) -> Result<ISRHandle,BindISRError>
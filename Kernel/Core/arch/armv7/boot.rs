@@ -147,3 +147,8 @@ pub fn get_memory_map() -> &'static [::memory::MemoryMapEnt] {
 	&buf[..len]
 }
 
+
+pub fn get_modules() -> &'static [::arch::boot::BootModule] {
+	// TODO: Expose the FDT's initrd (/chosen/linux,initrd-start) as a module
+	&[]
+}
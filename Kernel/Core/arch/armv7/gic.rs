@@ -0,0 +1,176 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/arch/armv7/gic.rs
+//! ARM Generic Interrupt Controller (GICv2) driver
+//!
+//! Shared between the armv7 and armv8 ports (the register interface is identical)
+use lib::LazyStatic;
+use memory::virt::MmioHandle;
+
+/// Fallback distributor/CPU interface addresses (QEMU's `virt` machine) if the FDT doesn't list a GIC
+const DEFAULT_DIST_BASE: u64 = 0x0800_0000;
+const DEFAULT_CPU_BASE: u64 = 0x0801_0000;
+
+/// Value read from GICC_IAR when there's no pending interrupt
+pub const SPURIOUS: u32 = 1023;
+
+// Distributor registers
+const GICD_CTLR: usize = 0x000;
+const GICD_TYPER: usize = 0x004;
+const GICD_ISENABLER: usize = 0x100;
+const GICD_ICENABLER: usize = 0x180;
+const GICD_IPRIORITYR: usize = 0x400;
+const GICD_ITARGETSR: usize = 0x800;
+// CPU interface registers
+const GICC_CTLR: usize = 0x00;
+const GICC_PMR: usize = 0x04;
+const GICC_IAR: usize = 0x0C;
+const GICC_EOIR: usize = 0x10;
+
+struct Gic
+{
+	dist: MmioHandle,
+	cpu: MmioHandle,
+	/// Number of interrupt lines supported by the distributor
+	line_count: usize,
+}
+
+static S_GIC: LazyStatic<Gic> = lazystatic_init!();
+
+/// Locate and enable the GIC, returns the number of interrupt lines
+pub fn init() -> usize
+{
+	let (dist_base, cpu_base) = match find_gic()
+		{
+		Some(v) => v,
+		None => {
+			log_notice!("No GIC in the FDT, using defaults ({:#x},{:#x})", DEFAULT_DIST_BASE, DEFAULT_CPU_BASE);
+			(DEFAULT_DIST_BASE, DEFAULT_CPU_BASE)
+			},
+		};
+	log_debug!("GIC: Distributor {:#x}, CPU interface {:#x}", dist_base, cpu_base);
+
+	// SAFE: Called in a single-threaded context, and the GIC registers are only accessed via this module
+	unsafe {
+		S_GIC.prep(|| {
+			let dist = ::memory::virt::map_mmio(dist_base as ::memory::PAddr, 0x1000).expect("Unable to map GIC distributor");
+			let cpu = ::memory::virt::map_mmio(cpu_base as ::memory::PAddr, 0x1000).expect("Unable to map GIC CPU interface");
+			let typer = ::core::intrinsics::volatile_load( dist.as_int_mut::<u32>(GICD_TYPER) );
+			Gic {
+				dist: dist,
+				cpu: cpu,
+				line_count: ((typer as usize & 0x1F) + 1) * 32,
+				}
+			});
+	}
+	let gic = &*S_GIC;
+
+	// Distributor: All lines masked, priority 0xA0, and routed to CPU 0
+	gic.dist_write(GICD_CTLR, 0);
+	for i in 0 .. gic.line_count / 32 {
+		gic.dist_write(GICD_ICENABLER + i*4, !0);
+	}
+	for i in 0 .. gic.line_count / 4 {
+		gic.dist_write(GICD_IPRIORITYR + i*4, 0xA0A0A0A0);
+		// - The first 32 lines (SGIs/PPIs) have read-only targets
+		if i >= 32/4 {
+			gic.dist_write(GICD_ITARGETSR + i*4, 0x01010101);
+		}
+	}
+	gic.dist_write(GICD_CTLR, 1);
+
+	// CPU interface: Accept all priorities
+	gic.cpu_write(GICC_PMR, 0xF0);
+	gic.cpu_write(GICC_CTLR, 1);
+
+	gic.line_count
+}
+
+/// Unmask an interrupt line
+pub fn enable(line: usize)
+{
+	let gic = &*S_GIC;
+	assert!(line < gic.line_count);
+	gic.dist_write(GICD_ISENABLER + (line / 32) * 4, 1 << (line % 32));
+}
+
+/// Acknowledge the highest priority pending interrupt, returning its ID (`SPURIOUS` if none)
+pub fn acknowledge() -> u32
+{
+	if ! S_GIC.ls_is_valid() {
+		return SPURIOUS;
+	}
+	S_GIC.cpu_read(GICC_IAR) & 0x3FF
+}
+
+/// Signal the end of handling for an interrupt returned by `acknowledge`
+pub fn end_of_interrupt(id: u32)
+{
+	S_GIC.cpu_write(GICC_EOIR, id);
+}
+
+impl Gic
+{
+	fn dist_write(&self, ofs: usize, val: u32) {
+		// SAFE: Register access, within the mapped region
+		unsafe { ::core::intrinsics::volatile_store( self.dist.as_int_mut::<u32>(ofs), val ) }
+	}
+	fn cpu_read(&self, ofs: usize) -> u32 {
+		// SAFE: Register access, within the mapped region
+		unsafe { ::core::intrinsics::volatile_load( self.cpu.as_int_mut::<u32>(ofs) ) }
+	}
+	fn cpu_write(&self, ofs: usize, val: u32) {
+		// SAFE: Register access, within the mapped region
+		unsafe { ::core::intrinsics::volatile_store( self.cpu.as_int_mut::<u32>(ofs), val ) }
+	}
+}
+
+/// Get the GIC's distributor and CPU interface addresses from the FDT
+fn find_gic() -> Option<(u64, u64)>
+{
+	let fdt = match super::boot::get_fdt()
+		{
+		Some(v) => v,
+		None => return None,
+		};
+	let (acells, scells) = match fdt.get_nodes(&[]).next()
+		{
+		Some(root) => (read_cell_count(&root, "#address-cells"), read_cell_count(&root, "#size-cells")),
+		None => return None,
+		};
+	for node in fdt.get_nodes(&[""])
+	{
+		if node.get_prop("interrupt-controller").is_none() {
+			continue ;
+		}
+		let compat = node.get_prop("compatible").unwrap_or(b"");
+		let is_gic = compat.split(|&b| b == 0).any(|c| c == b"arm,cortex-a15-gic" || c == b"arm,cortex-a9-gic" || c == b"arm,gic-400");
+		if !is_gic {
+			continue ;
+		}
+		if let Some(mut reg) = node.get_prop("reg")
+		{
+			// `reg` = <distributor base, size, CPU interface base, size>
+			let dist = read_cells(&mut reg, acells);
+			read_cells(&mut reg, scells);
+			let cpu = read_cells(&mut reg, acells);
+			if let (Some(dist), Some(cpu)) = (dist, cpu) {
+				return Some( (dist, cpu) );
+			}
+		}
+	}
+	None
+}
+fn read_cell_count(node: &super::fdt::Node, name: &str) -> usize {
+	let mut v = node.get_prop(name).unwrap_or(b"");
+	read_cells(&mut v, 1).unwrap_or(1) as usize
+}
+fn read_cells(bytes: &mut &[u8], count: usize) -> Option<u64> {
+	use lib::byteorder::{ReadBytesExt,BigEndian};
+	let mut rv = 0;
+	for _ in 0 .. count {
+		rv = (rv << 32) | match bytes.read_u32::<BigEndian>() { Ok(v) => v as u64, Err(_) => return None };
+	}
+	Some(rv)
+}
@@ -21,12 +21,11 @@ unsafe impl Send for Binding {}
 static S_IRQS: LazyStatic<Vec< Spinlock<Option<Binding>> >> = lazystatic_init!();
 
 pub fn init() {
-	// TODO: Interrogate the FDT to discover the IRQ controller layout
-	//::arch::int::boot::get_fdt().get_props(&["","intc"])
+	let line_count = super::gic::init();
 
 	// SAFE: Called in a single-threaded context
 	unsafe {
-		S_IRQS.prep(|| Vec::from_fn(32, |_| Default::default()));
+		S_IRQS.prep(|| Vec::from_fn(line_count, |_| Default::default()));
 	}
 }
 
@@ -34,32 +33,33 @@ pub fn init() {
 #[no_mangle]
 pub extern "C" fn interrupt_handler()
 {
-	let irq = get_active_interrupt();
-	if irq as usize >= S_IRQS.len() {
-		// ... No idea!
-	}
-	else {
-		match S_IRQS[irq as usize].try_lock_cpu()
-		{
-		None => {
-			// Lock is already held by this CPU, just drop the IRQ
-			},
-		Some(v) =>
-			match *v
+	loop
+	{
+		let irq = super::gic::acknowledge();
+		if irq == super::gic::SPURIOUS {
+			break ;
+		}
+		if irq as usize >= S_IRQS.len() {
+			// ... No idea!
+		}
+		else {
+			match S_IRQS[irq as usize].try_lock_cpu()
 			{
-			None => {},
-			Some(ref v) => (v.handler)( v.info ),
-					// TODO: Acknowledge the IRQ on the GIC?
-			},
+			None => {
+				// Lock is already held by this CPU, just drop the IRQ
+				},
+			Some(v) =>
+				match *v
+				{
+				None => {},
+				Some(ref v) => (v.handler)( v.info ),
+				},
+			}
 		}
+		super::gic::end_of_interrupt(irq);
 	}
 }
 
-fn get_active_interrupt() -> u32
-{
-	todo!("get_active_interrupt - Query the active interrupt from the GIC");
-}
-
 pub fn bind_gsi(gsi: usize, handler: fn(*const()), info: *const ()) -> Result<IRQHandle,()> {
 
 	if gsi >= S_IRQS.len() {
@@ -71,13 +71,22 @@ pub fn bind_gsi(gsi: usize, handler: fn(*const()), info: *const ()) -> Result<IR
 			Err( () )
 		}
 		else {
-			// TODO: Enable this interrupt on the GIC?
 			*lh = Some(Binding {
 				handler: handler,
 				info: info,
 				});
+			super::gic::enable(gsi);
 			Ok( IRQHandle(gsi as u32) )
 		}
 	}
 }
 
+/// Message-signalled interrupts (not supported, the GICv2 has no MSI frame)
+pub struct MsiHandle;
+impl MsiHandle {
+	pub fn address(&self) -> u64 { 0 }
+	pub fn data(&self) -> u32 { 0 }
+}
+pub fn bind_msi(_handler: fn(*const()), _info: *const ()) -> Result<MsiHandle,BindError> {
+	Err( () )
+}
@@ -9,6 +9,8 @@ pub mod memory;
 pub mod sync;
 
 pub mod interrupts;
+mod gic;
+mod timer;
 
 pub mod boot;
 
@@ -40,6 +42,7 @@ extern "C" {
 fn init()
 {
 	interrupts::init();
+	timer::init();
 }
 
 #[no_mangle]
@@ -107,7 +110,7 @@ pub fn puth(v: u64) {
 }
 
 pub fn cur_timestamp() -> u64 {
-	0
+	timer::cur_timestamp()
 }
 
 pub fn print_backtrace() {
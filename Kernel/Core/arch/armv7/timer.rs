@@ -0,0 +1,83 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/arch/armv7/timer.rs
+//! ARM generic timer (system tick source)
+use core::sync::atomic::{AtomicU32,Ordering};
+
+/// GIC interrupt for the non-secure physical timer (PPI 14)
+const TIMER_IRQ: usize = 16 + 14;
+/// Period of the system tick
+const TICK_PERIOD_MS: u32 = 10;
+
+/// Counter frequency (Hz), zero if the timer isn't usable
+static S_FREQUENCY: AtomicU32 = AtomicU32::new(0);
+
+pub fn init()
+{
+	let freq = read_cntfrq();
+	if freq == 0 {
+		log_error!("Generic timer frequency not set, no system tick");
+		return ;
+	}
+	log_debug!("Generic timer at {}Hz", freq);
+	S_FREQUENCY.store(freq, Ordering::Relaxed);
+
+	match super::interrupts::bind_gsi(TIMER_IRQ, irq, 0 as *const ())
+	{
+	Ok(_) => ::time::set_tick_source(),
+	Err(e) => {
+		log_error!("Unable to bind generic timer IRQ {}: {:?}", TIMER_IRQ, e);
+		return ;
+		},
+	}
+	// SAFE: Timer register access
+	unsafe {
+		write_cntp_tval(tick_reload());
+		write_cntp_ctl(1);	// Enable, unmasked
+	}
+}
+
+/// Current time in milliseconds
+pub fn cur_timestamp() -> u64
+{
+	let freq = S_FREQUENCY.load(Ordering::Relaxed) as u64;
+	if freq == 0 {
+		return 0;
+	}
+	let count = read_cntpct();
+	(count / freq) * 1000 + (count % freq) * 1000 / freq
+}
+
+fn tick_reload() -> u32 {
+	S_FREQUENCY.load(Ordering::Relaxed) / 1000 * TICK_PERIOD_MS
+}
+
+fn irq(_: *const ())
+{
+	// SAFE: Timer register access (re-arming clears the interrupt condition)
+	unsafe {
+		write_cntp_tval(tick_reload());
+	}
+	::time::time_tick();
+}
+
+fn read_cntfrq() -> u32 {
+	let rv: u32;
+	// SAFE: Read-only register access
+	unsafe { asm!("mrc p15,0, $0, c14,c0,0" : "=r" (rv)); }
+	rv
+}
+fn read_cntpct() -> u64 {
+	let lo: u32;
+	let hi: u32;
+	// SAFE: Read-only register access
+	unsafe { asm!("isb; mrrc p15,0, $0,$1, c14" : "=r" (lo), "=r" (hi) : : : "volatile"); }
+	(hi as u64) << 32 | lo as u64
+}
+unsafe fn write_cntp_tval(v: u32) {
+	asm!("mcr p15,0, $0, c14,c2,0; isb" : : "r" (v) : "memory" : "volatile");
+}
+unsafe fn write_cntp_ctl(v: u32) {
+	asm!("mcr p15,0, $0, c14,c2,1; isb" : : "r" (v) : "memory" : "volatile");
+}
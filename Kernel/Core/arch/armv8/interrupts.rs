@@ -1,10 +1,90 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/arch/armv8/interrupts.rs
+//! Interrupt dispatch (via the GIC)
+use lib::Vec;
+use sync::Spinlock;
+use lib::LazyStatic;
 
-#[derive(Default)]
-pub struct IRQHandle;
+pub struct IRQHandle(u32);
+impl Default for IRQHandle {
+	fn default() -> IRQHandle { IRQHandle(!0) }
+}
 #[derive(Debug)]
 pub struct BindError;
 
-pub fn bind_gsi(idx: usize, handler: fn(*const ()), info: *const ()) -> Result<IRQHandle,BindError> {
-	Ok(IRQHandle)
+struct Binding {
+	handler: fn ( *const() ),
+	info: *const (),
+}
+unsafe impl Send for Binding {}
+
+static S_IRQS: LazyStatic<Vec< Spinlock<Option<Binding>> >> = lazystatic_init!();
+
+pub fn init() {
+	let line_count = super::gic::init();
+
+	// SAFE: Called in a single-threaded context
+	unsafe {
+		S_IRQS.prep(|| Vec::from_fn(line_count, |_| Default::default()));
+	}
 }
 
+#[linkage="external"]
+#[no_mangle]
+pub extern "C" fn interrupt_handler()
+{
+	loop
+	{
+		let irq = super::gic::acknowledge();
+		if irq == super::gic::SPURIOUS {
+			break ;
+		}
+		if (irq as usize) < S_IRQS.len() {
+			match S_IRQS[irq as usize].try_lock_cpu()
+			{
+			None => {
+				// Lock is already held by this CPU, just drop the IRQ
+				},
+			Some(v) =>
+				match *v
+				{
+				None => {},
+				Some(ref v) => (v.handler)( v.info ),
+				},
+			}
+		}
+		super::gic::end_of_interrupt(irq);
+	}
+}
+
+pub fn bind_gsi(gsi: usize, handler: fn(*const ()), info: *const ()) -> Result<IRQHandle,BindError> {
+	if gsi >= S_IRQS.len() {
+		Err( BindError )
+	}
+	else {
+		let mut lh = S_IRQS[gsi].lock();
+		if lh.is_some() {
+			Err( BindError )
+		}
+		else {
+			*lh = Some(Binding {
+				handler: handler,
+				info: info,
+				});
+			super::gic::enable(gsi);
+			Ok( IRQHandle(gsi as u32) )
+		}
+	}
+}
+
+/// Message-signalled interrupts (not supported, the GICv2 has no MSI frame)
+pub struct MsiHandle;
+impl MsiHandle {
+	pub fn address(&self) -> u64 { 0 }
+	pub fn data(&self) -> u32 { 0 }
+}
+pub fn bind_msi(_handler: fn(*const()), _info: *const ()) -> Result<MsiHandle,BindError> {
+	Err( BindError )
+}
@@ -9,13 +9,18 @@ pub mod sync;
 pub mod threads;
 pub mod boot;
 pub mod interrupts;
+mod timer;
 
 #[path="../armv7/fdt.rs"]
 mod fdt;
+#[path="../armv7/gic.rs"]
+mod gic;
 
 module_define!{arch, [], init}
 fn init()
 {
+	interrupts::init();
+	timer::init();
 }
 
 pub fn print_backtrace() {
@@ -46,7 +51,7 @@ pub fn print_backtrace() {
 }
 
 pub fn cur_timestamp() -> u64 {
-	0
+	timer::cur_timestamp()
 }
 
 extern "C" {
@@ -78,25 +78,30 @@ impl<'a, T: 'a> ops::Drop for HeldSpinlock<'a, T>
 }
 
 
-pub struct HeldInterrupts;
+/// Interrupts held (masked), with the previous state
+pub struct HeldInterrupts(bool);
 impl ops::Drop for HeldInterrupts {
 	fn drop(&mut self) {
-		// SAFE: TODO
-		unsafe {
-			start_interrupts();
+		if self.0 {
+			// SAFE: Restores the state from before `hold_interrupts`
+			unsafe {
+				start_interrupts();
+			}
 		}
 	}
 }
 
 pub fn hold_interrupts()->HeldInterrupts {
-	// SAFE: TODO
-	unsafe {
-		stop_interrupts();
-	}
-	HeldInterrupts
+	let daif: u64;
+	// SAFE: Reads DAIF and masks IRQs
+	unsafe { asm!("mrs $0, DAIF; msr DAIFSet, #2" : "=r" (daif) : : "memory" : "volatile"); }
+	// DAIF.I (bit 7) set = IRQs masked
+	HeldInterrupts(daif & (1 << 7) == 0)
 }
 pub unsafe fn stop_interrupts() {
+	asm!("msr DAIFSet, #2" : : : "memory" : "volatile");
 }
 pub unsafe fn start_interrupts() {
+	asm!("msr DAIFClr, #2" : : : "memory" : "volatile");
 }
 
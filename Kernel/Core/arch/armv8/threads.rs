@@ -85,9 +85,9 @@ pub fn switch_to(thread: ::threads::ThreadPtr) {
 }
 pub fn idle() {
 	log_trace!("idle");
-	// SAFE: Calls 'wait for interrupt'
+	// SAFE: Enables interrupts and calls 'wait for interrupt'
 	unsafe {
-		asm!("wfi" : : : : "volatile");
+		asm!("msr DAIFClr, #2; wfi" : : : "memory" : "volatile");
 	}
 }
 
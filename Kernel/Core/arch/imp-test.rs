@@ -166,6 +166,14 @@ pub mod interrupts {
 	pub fn bind_gsi(_gsi: usize, _handler: fn(*const()), _info: *const ()) -> Result<IRQHandle, BindError> {
 		todo!("bind_gsi")
 	}
+	pub struct MsiHandle;
+	impl MsiHandle {
+		pub fn address(&self) -> u64 { 0 }
+		pub fn data(&self) -> u32 { 0 }
+	}
+	pub fn bind_msi(_handler: fn(*const()), _info: *const ()) -> Result<MsiHandle, BindError> {
+		Err(BindError)
+	}
 }
 pub mod boot {
 	pub fn get_boot_string() -> &'static str {
@@ -177,6 +185,9 @@ pub mod boot {
 	pub fn get_memory_map() -> &'static [::memory::MemoryMapEnt] {
 		&[]
 	}
+	pub fn get_modules() -> &'static [::arch::boot::BootModule] {
+		&[]
+	}
 }
 pub mod pci {
 	pub fn read(_a: u32) -> u32 {
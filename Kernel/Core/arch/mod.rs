@@ -226,16 +226,37 @@ pub mod interrupts {
 
 	pub type BindError = imp::BindError;
 	pub type IRQHandle = imp::IRQHandle;
+	/// A bound message-signalled interrupt, provides `address()` and `data()` to program into the device
+	pub type MsiHandle = imp::MsiHandle;
 
 	
 	#[inline]
 	pub fn bind_gsi(gsi: usize, handler: fn(*const()), info: *const ()) -> Result<IRQHandle, BindError> {
 		imp::bind_gsi(gsi, handler, info)
 	}
+	/// Bind a handler to a newly allocated message-signalled interrupt (fails if the platform doesn't support MSI)
+	#[inline]
+	pub fn bind_msi(handler: fn(*const()), info: *const ()) -> Result<MsiHandle, BindError> {
+		imp::bind_msi(handler, info)
+	}
 }
 pub mod boot {
 	use super::imp::boot as imp;
 
+	/// A bootloader-provided module (e.g. a disk image)
+	#[derive(Copy,Clone,Debug)]
+	pub struct BootModule
+	{
+		/// Physical address of the first byte of the module
+		pub base: super::memory::PAddr,
+		/// Size of the module in bytes
+		pub len: usize,
+		/// Module "command line" (usually the file path)
+		pub cmdline: &'static str,
+	}
+	/// Placeholder value for static arrays of modules
+	pub const MODULE_PAD: BootModule = BootModule { base: 0, len: 0, cmdline: "" };
+
 	#[inline]
 	pub fn get_boot_string() -> &'static str {
 		imp::get_boot_string()
@@ -248,6 +269,11 @@ pub mod boot {
 	pub fn get_memory_map() -> &'static [::memory::MemoryMapEnt] {
 		imp::get_memory_map()
 	}
+	/// Obtain the list of modules loaded alongside the kernel
+	#[inline]
+	pub fn get_modules() -> &'static [BootModule] {
+		imp::get_modules()
+	}
 }
 pub mod pci {
 	use super::imp::pci as imp;
@@ -63,6 +63,18 @@ impl Source
 			false
 		}
 	}
+	/// Wake all waiting threads
+	pub fn wake_all(&self) -> usize
+	{
+		let mut lh = self.waiters.lock();
+		let mut count = 0;
+		while let Some(waiter) = lh.pop()
+		{
+			waiter.signal();
+			count += 1;
+		}
+		count
+	}
 }
 
 impl<'a> fmt::Debug for Waiter<'a>
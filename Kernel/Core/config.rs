@@ -89,6 +89,14 @@ def_config_set! {
 //		/// Startup - Init executable (first userland process)
 		Init @ "INIT" = "/sysroot/bin/init",
 		TestFlags @ "TEST" = "",
+		/// Storage - RAM disks to create at boot (comma separated, either a size e.g. `512K`/`4M`, or `mod<N>` for boot module N)
+		RamDisks @ "RAMDISK" = "",
+		/// Storage - Mirror (RAID-1) volumes to assemble (comma separated `name:member+member`, members are logical volume names)
+		Mirrors @ "MIRROR" = "",
+		/// GUI - Keyboard layout (`us`, `uk`, `de`, or `fr`)
+		Keymap @ "KEYMAP" = "us",
+		/// GUI - Key repeat delay and rate (`<delay_ms>,<rate_hz>`, a rate of zero disables repeat)
+		KeyRepeat @ "KEYREPEAT" = "500,30",
 	}
 }
 
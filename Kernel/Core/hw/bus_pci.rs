@@ -117,7 +117,7 @@ impl ::device_manager::BusDevice for PCIDev
 			},
 		}
 	}
-	fn set_attr_idx(&mut self, name: &str, _idx: usize, value: ::device_manager::AttrValue) {
+	fn set_attr_idx(&mut self, name: &str, idx: usize, value: ::device_manager::AttrValue) {
 		use device_manager::AttrValue;
 		match (name,value)
 		{
@@ -136,6 +136,15 @@ impl ::device_manager::BusDevice for PCIDev
 			}
 			write_word(self.addr, 1, self.config[1]);
 			},
+		// Raw config space write (for capability registers, e.g. USB legacy support)
+		("raw_config", AttrValue::U32(value)) => {
+			if idx < 0x40 || idx >= 256 || idx % 4 != 0 {
+				log_warning!("Invalid raw config write to {:#x} on device {:#05x}", idx, self.addr);
+			}
+			else {
+				write_word(self.addr, idx as u8 / 4, value);
+			}
+			},
 		_ => {
 			log_warning!("Attempting to set non-existant attr '{}' on device 0x{:05x}", name, self.addr);
 			},
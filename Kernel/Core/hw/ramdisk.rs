@@ -0,0 +1,236 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/hw/ramdisk.rs
+//! RAM-backed physical volumes
+//!
+//! Created at boot from the `RAMDISK` config option, or at runtime via `VolumeHandle::new_ramdisk`
+use prelude::*;
+use core::sync::atomic::{AtomicUsize,Ordering};
+use metadevs::storage;
+use sync::Mutex;
+
+module_define!{RamDisk, [Storage], init}
+
+/// Block size of all RAM disks
+pub const BLOCK_SIZE: usize = 512;
+const BLOCKS_PER_PAGE: usize = ::PAGE_SIZE / BLOCK_SIZE;
+
+static S_NEXT_IDX: AtomicUsize = AtomicUsize::new(0);
+/// Bitmask of boot modules that have been loaded into a RAM disk (their frames are released afterwards)
+static S_USED_MODULES: AtomicUsize = AtomicUsize::new(0);
+/// Registrations for created disks (RAM disks are never removed)
+static S_VOLUMES: Mutex<Vec<storage::PhysicalVolumeReg>> = Mutex::new(Vec::new_const());
+
+struct RamVolume
+{
+	name: String,
+	block_count: u64,
+	/// Backing pages, allocated on first write (`None` reads as zeroes)
+	pages: Mutex<Vec<Option<Box<[u8; ::PAGE_SIZE]>>>>,
+}
+
+fn init()
+{
+	for ent in ::config::get_string(::config::Value::RamDisks).split(',').filter(|v| *v != "")
+	{
+		let rv = if ent.starts_with("mod") {
+				match ent[3..].parse::<usize>().ok().and_then(|i| ::arch::boot::get_modules().get(i).map(|m| (i, m)))
+				{
+				Some( (i, m) ) => create_from_module(i, m),
+				None => {
+					log_warning!("RAMDISK: No such boot module '{}'", ent);
+					continue ;
+					},
+				}
+			}
+			else {
+				match parse_size(ent)
+				{
+				Some(bytes) => create( ((bytes + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64), &[] ),
+				None => {
+					log_warning!("RAMDISK: Malformed size '{}'", ent);
+					continue ;
+					},
+				}
+			};
+		match rv
+		{
+		Ok(name) => log_debug!("RAMDISK: '{}' created as {}", ent, name),
+		Err(e) => log_error!("RAMDISK: Unable to create '{}': {:?}", ent, e),
+		}
+	}
+}
+
+/// Parse a size with an optional K/M/G suffix
+fn parse_size(s: &str) -> Option<u64>
+{
+	let (num, shift) = match s.as_bytes().last()
+		{
+		Some(&b'K') | Some(&b'k') => (&s[..s.len()-1], 10),
+		Some(&b'M') | Some(&b'm') => (&s[..s.len()-1], 20),
+		Some(&b'G') | Some(&b'g') => (&s[..s.len()-1], 30),
+		_ => (s, 0),
+		};
+	num.parse::<u64>().ok().and_then(|v| v.checked_mul(1 << shift))
+}
+
+/// Create a RAM disk pre-populated with the contents of a boot module
+///
+/// The module's frames are handed back to the physical allocator once copied, so each module can only be used once.
+fn create_from_module(idx: usize, m: &::arch::boot::BootModule) -> Result<String, storage::IoError>
+{
+	let mask = 1usize.checked_shl(idx as u32).unwrap_or(0);
+	if mask == 0 || S_USED_MODULES.fetch_or(mask, Ordering::Relaxed) & mask != 0 {
+		log_error!("RAMDISK: Boot module {} already used", idx);
+		return Err( storage::IoError::InvalidParameter );
+	}
+	let rv = {
+		// SAFE: Module memory is reserved at boot, and only read here
+		let data = match unsafe { ::memory::virt::map_hw_slice::<u8>(m.base, m.len) }
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_error!("RAMDISK: Unable to map module {:#x}+{:#x}: {:?}", m.base, m.len, e);
+				return Err( storage::IoError::Unknown("Module map failed") );
+				},
+			};
+		try!( create( ((m.len + BLOCK_SIZE - 1) / BLOCK_SIZE) as u64, &data ) )
+		};
+	// - The contents have been copied (and the mapping dropped), release the module's memory
+	// SAFE: Module has been marked as used (so won't be mapped again), and nothing else references module memory
+	let n = unsafe { ::memory::phys::release_boot_range(m.base, m.len) };
+	log_debug!("RAMDISK: Released {} pages from module {}", n, idx);
+	Ok(rv)
+}
+
+/// Create and register a new RAM disk of `block_count` blocks, returning the PV name
+///
+/// The disk will be extended to fit `initial` (which is copied to the start of the disk)
+pub fn create(block_count: u64, initial: &[u8]) -> Result<String, storage::IoError>
+{
+	let block_count = ::core::cmp::max(block_count, ((initial.len() + BLOCK_SIZE - 1) / BLOCK_SIZE) as u64);
+	if block_count == 0 {
+		return Err( storage::IoError::InvalidParameter );
+	}
+	let page_count = (block_count + BLOCKS_PER_PAGE as u64 - 1) / BLOCKS_PER_PAGE as u64;
+	// - Pages are allocated on write, so reject disks that could never be filled
+	if page_count > ::memory::phys::managed_page_count() as u64 {
+		log_warning!("RAMDISK: {} blocks is larger than system memory", block_count);
+		return Err( storage::IoError::InvalidParameter );
+	}
+
+	let mut pages: Vec<Option<Box<[u8; ::PAGE_SIZE]>>> = (0 .. page_count).map(|_| None).collect();
+	for (slot, src) in Iterator::zip(pages.iter_mut(), initial.chunks(::PAGE_SIZE))
+	{
+		// - Keep the disk sparse, zero pages are left unallocated
+		if src.iter().any(|&b| b != 0) {
+			let mut p = Box::new([0; ::PAGE_SIZE]);
+			p[..src.len()].clone_from_slice(src);
+			*slot = Some(p);
+		}
+	}
+
+	let name = format!("RAM{}", S_NEXT_IDX.fetch_add(1, Ordering::Relaxed));
+	log_log!("{}: {} blocks, {}", name, block_count, storage::SizePrinter(block_count * BLOCK_SIZE as u64));
+	let vol = RamVolume {
+		name: name.clone(),
+		block_count: block_count,
+		pages: Mutex::new(pages),
+		};
+	let reg = storage::register_pv( Box::new(vol) );
+	S_VOLUMES.lock().push(reg);
+	Ok(name)
+}
+
+impl RamVolume
+{
+	/// Check that a request is within the volume, and that the buffer matches the block count
+	fn check_range(&self, idx: u64, count: usize, buflen: usize) -> Result<(), storage::IoError> {
+		if buflen != count * BLOCK_SIZE {
+			Err( storage::IoError::InvalidParameter )
+		}
+		else if idx > self.block_count || count as u64 > self.block_count - idx {
+			Err( storage::IoError::BadAddr )
+		}
+		else {
+			Ok( () )
+		}
+	}
+	/// Page index and byte offset of a block
+	fn block_pos(idx: u64) -> (usize, usize) {
+		( (idx / BLOCKS_PER_PAGE as u64) as usize, (idx % BLOCKS_PER_PAGE as u64) as usize * BLOCK_SIZE )
+	}
+}
+
+impl storage::PhysicalVolume for RamVolume
+{
+	fn name(&self) -> &str { &self.name }
+	fn blocksize(&self) -> Result<usize,storage::IoError> { Ok(BLOCK_SIZE) }
+	fn capacity(&self) -> Option<u64> { Some(self.block_count) }
+
+	fn read<'a>(&'a self, _prio: u8, idx: u64, num: usize, dst: &'a mut [u8]) -> storage::AsyncIoResult<'a,usize>
+	{
+		let rv = self.check_range(idx, num, dst.len()).map(|_| {
+			let pages = self.pages.lock();
+			for (i, blk) in dst.chunks_mut(BLOCK_SIZE).enumerate()
+			{
+				let (page, ofs) = Self::block_pos(idx + i as u64);
+				match pages[page]
+				{
+				Some(ref p) => blk.clone_from_slice( &p[ofs .. ofs + BLOCK_SIZE] ),
+				None => for b in blk.iter_mut() { *b = 0; },
+				}
+			}
+			num
+			});
+		Box::new( ::async::NullResultWaiter::new( move || rv ) )
+	}
+	fn write<'a>(&'a self, _prio: u8, idx: u64, num: usize, src: &'a [u8]) -> storage::AsyncIoResult<'a,usize>
+	{
+		let rv = self.check_range(idx, num, src.len()).map(|_| {
+			let mut pages = self.pages.lock();
+			for (i, blk) in src.chunks(BLOCK_SIZE).enumerate()
+			{
+				let (page, ofs) = Self::block_pos(idx + i as u64);
+				let p = pages[page].get_or_insert_with(|| Box::new([0; ::PAGE_SIZE]));
+				p[ofs .. ofs + BLOCK_SIZE].clone_from_slice(blk);
+			}
+			num
+			});
+		Box::new( ::async::NullResultWaiter::new( move || rv ) )
+	}
+	fn wipe<'a>(&'a self, idx: u64, num: usize) -> storage::AsyncIoResult<'a,()>
+	{
+		let rv = self.check_range(idx, num, num * BLOCK_SIZE).map(|_| {
+			if num == 0 {
+				return ;
+			}
+			let mut pages = self.pages.lock();
+			for i in 0 .. num as u64
+			{
+				let (page, ofs) = Self::block_pos(idx + i);
+				if let Some(ref mut p) = pages[page] {
+					for b in p[ofs .. ofs + BLOCK_SIZE].iter_mut() { *b = 0; }
+				}
+			}
+			// - Release any pages that are now entirely zero
+			let first = Self::block_pos(idx).0;
+			let last = Self::block_pos(idx + num as u64 - 1).0;
+			for p in pages[first ..= last].iter_mut()
+			{
+				if p.as_ref().map(|p| p.iter().all(|&b| b == 0)).unwrap_or(false) {
+					*p = None;
+				}
+			}
+			});
+		Box::new( ::async::NullResultWaiter::new( move || rv ) )
+	}
+	fn flush<'a>(&'a self) -> storage::AsyncIoResult<'a,()>
+	{
+		// Writes are immediately "stable"
+		Box::new( ::async::NullResultWaiter::new( || Ok( () ) ) )
+	}
+}
+
+// vim: ft=rust
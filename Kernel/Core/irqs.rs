@@ -17,6 +17,13 @@ pub struct EventHandle
 	event: Arc<::async::event::Source>,
 }
 pub struct ObjectHandle( BindingHandle );
+/// A handle for an object bound to a message-signalled interrupt
+pub struct MsiHandle
+{
+	_binding: BindingHandle,
+	address: u64,
+	data: u32,
+}
 
 struct BindingHandle(u32, u32);
 
@@ -24,6 +31,8 @@ struct BindingHandle(u32, u32);
 struct IRQBinding
 {
 	arch_handle: interrupts::IRQHandle,
+	/// Architecture handle for message-signalled bindings (`arch_handle` is unused)
+	msi_handle: Option<interrupts::MsiHandle>,
 	has_fired: AtomicBool,	// Set to true if the IRQ fires while the lock is held by this CPU
 	//handlers: Spinlock<Queue<Handler>>,
 	handlers: Spinlock<Vec<Box<dyn FnMut()->bool + Send + 'static>>>,
@@ -41,6 +50,8 @@ struct Bindings
 // - Per IRQ queue of
 /// Map of IRQ numbers to core's dispatcher bindings. Bindings are boxed so the address is known in the constructor
 static S_IRQ_BINDINGS: ::sync::mutex::LazyMutex<Bindings> = lazymutex_init!();
+/// Base of the binding map keys used for message-signalled interrupts (above all GSIs)
+const MSI_BINDING_BASE: u32 = 0x1_0000;
 
 static S_IRQ_WORKER_SIGNAL: ::lib::LazyStatic<::threads::SleepObject<'static>> = lazystatic_init!();
 static S_IRQ_WORKER: ::lib::LazyStatic<::threads::WorkerThread> = lazystatic_init!();
@@ -72,6 +83,25 @@ fn bind(num: u32, obj: Box<dyn FnMut()->bool + Send>) -> BindingHandle
 	
 	BindingHandle( num, index as u32 )
 }
+/// Bind a handler to a newly allocated message-signalled interrupt
+fn bind_msi(obj: Box<dyn FnMut()->bool + Send>) -> Result<(BindingHandle, u64, u32), interrupts::BindError>
+{
+	let mut map_lh = S_IRQ_BINDINGS.lock_init(|| Bindings { mapping: VecMap::new(), next_index: 0 });
+	let index = map_lh.next_index;
+	map_lh.next_index += 1;
+	let num = MSI_BINDING_BASE + index as u32;
+
+	let mut binding = Box::new( IRQBinding::default() );
+	let context = &*binding as *const IRQBinding as *const ();
+	let handle = try!( interrupts::bind_msi(IRQBinding::handler_raw, context) );
+	let (address, data) = (handle.address(), handle.data());
+	log_trace!("bind_msi: #{} address={:#x} data={:#x}", index, address, data);
+	binding.msi_handle = Some(handle);
+	binding.handlers.lock().push( obj );
+	map_lh.mapping.insert(num, binding);
+
+	Ok( (BindingHandle( num, index as u32 ), address, data) )
+}
 impl Drop for BindingHandle
 {
 	fn drop(&mut self)
@@ -115,6 +145,31 @@ pub fn bind_object(num: u32, obj: Box<dyn FnMut()->bool + Send + 'static>) -> Ob
 	ObjectHandle( bind(num, obj) )
 }
 
+/// Bind an object to a newly allocated message-signalled interrupt
+///
+/// The device is then programmed with the handle's `address` and `data`. Fails if the platform doesn't support MSI
+/// (callers fall back to `bind_object`).
+pub fn bind_msi_object(obj: Box<dyn FnMut()->bool + Send + 'static>) -> Result<MsiHandle, interrupts::BindError>
+{
+	let (binding, address, data) = try!( bind_msi(obj) );
+	Ok(MsiHandle {
+		_binding: binding,
+		address: address,
+		data: data,
+		})
+}
+impl MsiHandle
+{
+	/// Message address to program into the device
+	pub fn address(&self) -> u64 {
+		self.address
+	}
+	/// Message data to program into the device
+	pub fn data(&self) -> u32 {
+		self.data
+	}
+}
+
 impl IRQBinding
 {
 	fn new_boxed(num: u32) -> Box<IRQBinding>
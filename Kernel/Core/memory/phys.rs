@@ -24,6 +24,17 @@ static S_MAPALLOC : ::sync::Mutex<(usize,PAddr)> = mutex_init!( (0,0) );
 static S_FREE_STACK : ::sync::Mutex<PAddr> = mutex_init!( NOPAGE );
 // TODO: Reference counts (maybe require arch to expose that)
 
+/// Sources of reclaimable memory (e.g. caches), asked to release pages when memory runs out
+static S_RECLAIM_HANDLERS: ::sync::Mutex<Vec<(&'static str, ReclaimHandler)>> = ::sync::Mutex::new(Vec::new_const());
+/// Worker that runs the reclaim handlers (spawned on first registration)
+static S_RECLAIM_WORKER: ::sync::Mutex<Option<::threads::WorkerThread>> = ::sync::Mutex::new(None);
+static S_RECLAIM_EVENT: ::sync::EventChannel = ::sync::EventChannel::new();
+/// Number of pages to request from the handlers each time memory runs out
+const RECLAIM_BATCH: usize = 64;
+
+/// Reclaim callback: release (at least) the requested number of pages, returning the number released
+pub type ReclaimHandler = fn(usize)->usize;
+
 /// A handle to a physical page (maintaining a reference to it, even when not mapped)
 pub struct FrameHandle(PAddr);
 
@@ -257,11 +268,54 @@ fn allocate_int( address: Option<*mut ()> ) -> Result<Option<TempHandle<u8>>, Er
 			return Ok( Some(handle) );
 		}
 	}
-	// 3. Fail
+	// 3. Fail (and ask caches to release memory for the next attempt)
+	// - Reclaim is deferred to a worker, as the caller can be holding address space/heap locks
 	log_warning!("Out of physical memory");
+	S_RECLAIM_EVENT.post();
 	Err( Error )
 }
 
+/// Register a source of reclaimable memory
+///
+/// Handlers are run from a dedicated worker thread once physical memory runs out
+pub fn register_reclaim_handler(name: &'static str, handler: ReclaimHandler)
+{
+	log_debug!("register_reclaim_handler: {}", name);
+	S_RECLAIM_HANDLERS.lock().push( (name, handler) );
+	let mut wh = S_RECLAIM_WORKER.lock();
+	if wh.is_none() {
+		*wh = Some( ::threads::WorkerThread::new("Memory Reclaim", reclaim_worker) );
+	}
+}
+
+/// Request that reclaim handlers release at least `pages` pages, returns the number released
+///
+/// NOTE: Handlers free memory, so this must not be called with allocator/address space locks held
+pub fn reclaim(pages: usize) -> usize
+{
+	let mut released = 0;
+	for &(name, handler) in S_RECLAIM_HANDLERS.lock().iter()
+	{
+		if released >= pages {
+			break;
+		}
+		let n = handler(pages - released);
+		log_debug!("reclaim: {} released {} pages", name, n);
+		released += n;
+	}
+	released
+}
+
+fn reclaim_worker()
+{
+	loop
+	{
+		S_RECLAIM_EVENT.sleep();
+		let n = reclaim(RECLAIM_BATCH);
+		log_log!("Memory reclaim: {} pages released", n);
+	}
+}
+
 pub fn ref_frame(paddr: PAddr)
 {
 	if ! is_ram(paddr) {
@@ -299,6 +353,38 @@ pub fn deref_frame(paddr: PAddr)
 	}
 }
 
+/// Number of pages the allocator manages (free RAM in the boot memory map)
+pub fn managed_page_count() -> usize
+{
+	get_memory_map().iter()
+		.filter(|e| e.state == ::memory::memorymap::MemoryState::Free)
+		.map(|e| (e.size / ::PAGE_SIZE as u64) as usize)
+		.sum()
+}
+
+/// Hand frames that were reserved at boot (e.g. a boot module that has been consumed) to the allocator
+///
+/// Only the pages entirely within the range are released. Returns the number of pages released.
+/// UNSAFE: Nothing can reference the range after this call
+pub unsafe fn release_boot_range(base: PAddr, len: usize) -> usize
+{
+	let first = (base + ::PAGE_SIZE as PAddr - 1) & !(::PAGE_SIZE as PAddr - 1);
+	let end = (base + len as PAddr) & !(::PAGE_SIZE as PAddr - 1);
+	let mut count = 0;
+	let mut paddr = first;
+	while paddr < end
+	{
+		if is_ram(paddr) {
+			let mut h = S_FREE_STACK.lock();
+			::memory::virt::with_temp(paddr, |page| *(&mut page[0] as *mut u8 as *mut PAddr) = *h);
+			*h = paddr;
+			count += 1;
+		}
+		paddr += ::PAGE_SIZE as PAddr;
+	}
+	count
+}
+
 fn mark_used(_paddr: PAddr)
 {
 	// TODO: This causes a double-lock in the PMM
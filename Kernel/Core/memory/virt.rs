@@ -7,6 +7,7 @@ use core::fmt;
 use core::ops;
 use arch::memory::addresses;
 use arch::memory::{PAddr, PAGE_MASK};
+use lib::Vec;
 
 type Page = [u8; ::PAGE_SIZE];
 
@@ -45,6 +46,18 @@ impl_from! {
 	}
 }
 
+/// Error returned by `reprotect_user`
+#[derive(Copy,Clone,Debug)]
+pub enum ProtectError
+{
+	/// Address is outside user memory, or not mapped
+	BadAddress,
+	/// The page's backing cannot be made writable (read-only shared or file-backed mapping)
+	ReadOnlyBacking,
+	/// The page is a guard page (not user-accessible), it can only be unmapped
+	GuardPage,
+}
+
 /// A handle to an arbitary owned memory allocation.
 pub struct AllocHandle
 {
@@ -77,6 +90,10 @@ static s_userspace_lock : ::sync::Mutex<()> = mutex_init!( () );
 #[allow(non_upper_case_globals)]
 static s_kernelspace_lock : ::sync::Mutex<()> = mutex_init!( () );
 
+/// Per-process list of user page ranges (first page, page count) that must never become writable
+#[derive(Default)]
+struct UserWriteLocks(::sync::Mutex<Vec<(usize,usize)>>);
+
 #[doc(hidden)]
 pub fn init()
 {
@@ -176,10 +193,29 @@ fn allocate_int(addr: *mut (), page_count: usize, is_user: bool) -> Result<(), M
 			}
 		}
 	}
+	if !is_global(addr as usize) {
+		account_user_pages(page_count as isize);
+	}
 
 	Ok( () )
 }
 
+/// Reserve a region of user address space as inaccessible guard pages
+///
+/// Guard pages count as allocated (so can't be allocated over), but fault on any user access.
+// TODO: Map a single shared frame instead of allocating a frame per guard page
+pub fn allocate_user_guard(addr: *mut (), page_count: usize) -> Result<(), MapError>
+{
+	try!( allocate_int(addr, page_count, false) );
+	for pgptr in Pages(addr, page_count) {
+		// SAFE: This region has just been allocated, and is KernelRW, downgrading to prevent any user access
+		unsafe {
+			::arch::memory::virt::reprotect(pgptr, ProtectionMode::KernelRO);
+		}
+	}
+	Ok( () )
+}
+
 /// Atomically reserves a region of address space
 pub fn reserve(addr: *mut (), page_count: usize) -> Result<Reservation, ()>
 {
@@ -207,6 +243,9 @@ pub fn reserve(addr: *mut (), page_count: usize) -> Result<Reservation, ()>
 		// TODO: Instead map in COW zero pages
 		::memory::phys::allocate( pgptr );
 	}
+	if !is_global(addr as usize) {
+		account_user_pages(page_count as isize);
+	}
 	
 	Ok( Reservation(addr, page_count) )
 }
@@ -238,6 +277,42 @@ impl Reservation
 	}
 }
 
+/// Update the current process's count of mapped user pages
+fn account_user_pages(delta: isize) {
+	::threads::with_process_stats(|s| s.adjust_resident_pages(delta));
+}
+
+/// Mark a range of user pages as having a read-only backing (they can never be reprotected as writable)
+pub fn lock_user_writes(addr: *mut (), page_count: usize)
+{
+	let page = addr as usize / ::PAGE_SIZE;
+	::threads::get_process_local::<UserWriteLocks>().0.lock().push( (page, page_count) );
+}
+/// Remove a single page from the current process's write-locked ranges (called when it's unmapped)
+fn unlock_user_writes(addr: *mut ())
+{
+	let page = addr as usize / ::PAGE_SIZE;
+	let locks = ::threads::get_process_local::<UserWriteLocks>();
+	let mut lh = locks.0.lock();
+	if let Some(i) = lh.iter().position(|&(base, count)| base <= page && page < base + count)
+	{
+		let (base, count) = lh[i];
+		lh.remove(i);
+		// Re-insert the parts of the range on either side of the page
+		if base < page {
+			lh.push( (base, page - base) );
+		}
+		if page + 1 < base + count {
+			lh.push( (page + 1, base + count - (page + 1)) );
+		}
+	}
+}
+fn is_write_locked(addr: *mut ()) -> bool
+{
+	let page = addr as usize / ::PAGE_SIZE;
+	::threads::get_process_local::<UserWriteLocks>().0.lock().iter().any(|&(base, count)| base <= page && page < base + count)
+}
+
 /// Map the given physical address to the given virtual address
 /// UNSAFE: Does no checks on validity of the physical address. When deallocated, the mapped address will be dereferenced
 pub unsafe fn map(addr: *mut (), phys: PAddr, prot: ProtectionMode)
@@ -256,34 +331,117 @@ pub unsafe fn map(addr: *mut (), phys: PAddr, prot: ProtectionMode)
 	}
 }
 
-/// Alter the protection flags on a mapping (only allows changing to a user-accessible mode)
+/// Map a set of (possibly shared) frames into the user address space at the given address
+///
+/// Each mapped page holds its own reference to the frame, so the mapping remains valid after `frames` is dropped
+pub fn map_user_frames(addr: *mut (), frames: &[::memory::phys::FrameHandle], prot: ProtectionMode) -> Result<(), MapError>
+{
+	match prot
+	{
+	ProtectionMode::UserRO => {},
+	ProtectionMode::UserRW => {},
+	_ => panic!("Invalid protection mode passed to map_user_frames - {:?}", prot),
+	}
+	assert_eq!(addr as usize % ::PAGE_SIZE, 0);
+	if ::arch::memory::addresses::is_global(addr as usize) || ::arch::memory::addresses::is_global(addr as usize + frames.len() * ::PAGE_SIZE - 1) {
+		return Err(MapError::RangeInUse);
+	}
+
+	let _lh = s_userspace_lock.lock();
+	for pgptr in Pages(addr, frames.len())
+	{
+		if ::arch::memory::virt::is_reserved( pgptr ) {
+			log_trace!("Address {:?} in range {:p}+{}pg reserved", pgptr, addr, frames.len());
+			return Err(MapError::RangeInUse);
+		}
+	}
+	for (pgptr, frame) in Iterator::zip( Pages(addr, frames.len()), frames.iter() )
+	{
+		// SAFE: Range checked as free, and the frame reference is handed to the mapping (released on unmap)
+		unsafe {
+			::arch::memory::virt::map(pgptr, frame.clone().into_addr(), prot);
+		}
+	}
+	if prot == ProtectionMode::UserRO {
+		lock_user_writes(addr, frames.len());
+	}
+	account_user_pages(frames.len() as isize);
+	Ok( () )
+}
+
+/// Alter the protection flags on a range of user pages (only allows changing to a user-accessible mode)
+///
+/// The entire range is checked before any page is changed, so on error nothing has been altered.
+/// Pages with a read-only backing (see `lock_user_writes`) cannot be made writable, guard pages can only be unmapped,
+/// and pages that share their frame (e.g. COW or cloned pages) are given a private copy before becoming writable.
 /// UNSAFE: (Very) Can change the protection mode of a page to anything
-pub unsafe fn reprotect_user(addr: *mut (), prot: ProtectionMode) -> Result<(),()>
+pub unsafe fn reprotect_user(addr: *mut (), page_count: usize, prot: ProtectionMode) -> Result<(),ProtectError>
 {
 	match prot
 	{
 	ProtectionMode::Unmapped => {},
 	ProtectionMode::UserRX => {},
 	ProtectionMode::UserRO => {},
+	ProtectionMode::UserRW => {},
 	_ => panic!("Invalid protection mode passed to reprotect_user - {:?}", prot),
 	}
-	if ::arch::memory::addresses::is_global(addr as usize) {
-		Err( () )
+	if page_count == 0 {
+		return Ok( () );
 	}
-	else if ! ::arch::memory::virt::is_reserved(addr) {
-		Err( () )
+	if ::arch::memory::addresses::is_global(addr as usize) || ::arch::memory::addresses::is_global(addr as usize + page_count * ::PAGE_SIZE - 1) {
+		return Err( ProtectError::BadAddress );
 	}
-	else {
-		if prot == ProtectionMode::Unmapped {
-			if let Some(paddr) = ::arch::memory::virt::unmap(addr) {
+	let _lh = s_userspace_lock.lock();
+	// 1. Validate the entire range (the lock prevents it changing before the update)
+	for pgptr in Pages(addr, page_count)
+	{
+		let cur_mode = match ::arch::memory::virt::get_info(pgptr)
+			{
+			Some( (_, m) ) => m,
+			None => return Err( ProtectError::BadAddress ),
+			};
+		match cur_mode
+		{
+		ProtectionMode::UserRO | ProtectionMode::UserRW | ProtectionMode::UserRX | ProtectionMode::UserCOW | ProtectionMode::UserRWX => {},
+		// Guard pages (mapped, but not user accessible) can be released, but not made accessible
+		_ if prot == ProtectionMode::Unmapped => {},
+		_ => return Err( ProtectError::GuardPage ),
+		}
+		if prot == ProtectionMode::UserRW && cur_mode != ProtectionMode::UserRW && is_write_locked(pgptr) {
+			return Err( ProtectError::ReadOnlyBacking );
+		}
+	}
+	// 2. Apply the change
+	for pgptr in Pages(addr, page_count)
+	{
+		let (paddr, cur_mode) = ::arch::memory::virt::get_info(pgptr).expect("reprotect_user - page vanished with lock held");
+		match prot
+		{
+		ProtectionMode::Unmapped => {
+			if let Some(paddr) = ::arch::memory::virt::unmap(pgptr) {
 				::memory::phys::deref_frame(paddr);
+				account_user_pages(-1);
 			}
+			unlock_user_writes(pgptr);
+			},
+		ProtectionMode::UserRW if cur_mode != ProtectionMode::UserRW => {
+			// Break any sharing of the frame (COW/cloned pages) before it becomes writable
+			let newframe = ::memory::phys::make_unique( paddr, &*(pgptr as *const [u8; ::PAGE_SIZE]) );
+			if newframe != paddr {
+				::arch::memory::virt::unmap(pgptr);
+				::arch::memory::virt::map(pgptr, newframe, prot);
+				::memory::phys::deref_frame(paddr);
+			}
+			else {
+				::arch::memory::virt::reprotect(pgptr, prot);
+			}
+			},
+		_ => {
+			::arch::memory::virt::reprotect(pgptr, prot);
+			},
 		}
-		else {
-			::arch::memory::virt::reprotect(addr, prot);
-		}
-		Ok( () )
 	}
+	Ok( () )
 }
 
 /// Unmap the frame at the given virtual address
@@ -306,10 +464,20 @@ pub unsafe fn unmap(addr: *mut (), count: usize)
 			panic!("Non-aligned page {:p} passed (unmapping {} pages)", addr, count);
 		}
 		
+		let is_user = !::arch::memory::addresses::is_global(pos);
+		if is_user {
+			for pgptr in Pages(addr, count) {
+				unlock_user_writes(pgptr);
+			}
+		}
 		// Dereference the frames returned
 		for i in 0 .. count {
 			if let Some(addr) = ::arch::memory::virt::unmap( (pos + i*::PAGE_SIZE) as *mut () ) {
 				::memory::phys::deref_frame(addr);
+				// Only pages that were actually mapped count against the process
+				if is_user {
+					account_user_pages(-1);
+				}
 			}
 		}
 	}
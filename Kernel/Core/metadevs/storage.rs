@@ -4,8 +4,9 @@
 // Core/metadevs/storage.rs
 // - Storage (block device) subsystem
 use prelude::*;
-use core::sync::atomic::{AtomicUsize};
+use core::sync::atomic::{AtomicUsize,Ordering};
 use sync::mutex::LazyMutex;
+use sync::Mutex;
 use lib::{VecMap};
 use lib::mem::Arc;
 
@@ -16,7 +17,8 @@ pub type AsyncIoResult<'a, T> = ::async::BoxAsyncResult<'a, T, IoError>;
 /// A unique handle to a storage volume (logical)
 pub struct VolumeHandle
 {
-	handle: ::lib::mem::Arc<LogicalVolume>,
+	// NOTE: Manually dropped, so deferred PV detaches can be checked once the handle is released
+	handle: ::core::mem::ManuallyDrop<::lib::mem::Arc<LogicalVolume>>,
 	// TODO: Store within this a single block cache? Or store on the LV?
 }
 
@@ -42,9 +44,48 @@ pub enum IoError
 	BadBlock,
 	ReadOnly,
 	NoMedium,
+	/// The (removable) medium was changed since the last request
+	MediaChanged,
 	Unknown(&'static str),
 }
 
+/// I/O scheduling class, used to order requests waiting on a physical volume
+#[derive(Copy,Clone,Debug,PartialEq,Eq,PartialOrd,Ord)]
+pub enum IoPriority
+{
+	/// Latency-sensitive requests (e.g. metadata lookups for an interactive application)
+	Interactive,
+	/// Default class
+	Normal,
+	/// Background bulk transfers (e.g. cache write-back)
+	Bulk,
+}
+
+impl IoPriority
+{
+	/// Priority value passed to the driver (0 is highest)
+	fn driver_prio(&self) -> u8 {
+		match *self
+		{
+		IoPriority::Interactive => 0,
+		IoPriority::Normal => 128,
+		IoPriority::Bulk => 255,
+		}
+	}
+	/// Class for a driver priority value (for volumes that forward requests to other volumes)
+	pub fn from_driver_prio(prio: u8) -> IoPriority {
+		if prio < 64 {
+			IoPriority::Interactive
+		}
+		else if prio < 192 {
+			IoPriority::Normal
+		}
+		else {
+			IoPriority::Bulk
+		}
+	}
+}
+
 /// Mutable/Immutable data pointer, encoded as host-relative (Send = immutable data)
 pub enum DataPtr<'a>
 {
@@ -88,7 +129,9 @@ pub trait PhysicalVolume: Send + 'static
 	/// Returns the volume name (must be unique to the system)
 	fn name(&self) -> &str;	// Local lifetime string
 	/// Returns the size of a filesystem block, must be a power of two >512
-	fn blocksize(&self) -> usize;
+	///
+	/// Removable volumes return `Err(NoMedium)` if there's no medium present
+	fn blocksize(&self) -> Result<usize,IoError>;
 	/// Returns the number of blocks in this volume (i.e. the capacity)
 	fn capacity(&self) -> Option<u64>;
 	
@@ -108,6 +151,24 @@ pub trait PhysicalVolume: Send + 'static
 	/// Erases (requests the underlying storage forget about) `count` blocks starting at `blockidx`.
 	/// This is functionally equivalent to the SSD "TRIM" command.
 	fn wipe<'a>(&'a self, blockidx: u64, count: usize) -> AsyncIoResult<'a,()>;
+	/// Flush the device's write cache
+	///
+	/// Once the yielded result is `Ok`, all writes that completed before this call are on stable
+	/// storage. Devices without a volatile write cache can complete immediately.
+	fn flush<'a>(&'a self) -> AsyncIoResult<'a,()>;
+
+	/// Maximum number of requests the storage layer may have outstanding on this volume at once
+	///
+	/// Only honoured for volumes registered with `register_concurrent_pv`, others are called one request at a time.
+	fn max_outstanding(&self) -> usize { 1 }
+}
+
+/// A physical volume that is internally synchronised
+///
+/// Registered with `register_concurrent_pv`, which calls `read`/`write` (and waits on their results) from up to
+/// `max_outstanding` threads at once without holding the volume's lock.
+pub trait ConcurrentPhysicalVolume: PhysicalVolume + Sync
+{
 }
 
 /// Registration for a physical volume handling driver
@@ -126,10 +187,32 @@ pub trait Mapper: Send + Sync
 }
 
 
+/// Physical volume device, individually locked so IO doesn't hold the volume list lock
+///
+/// NOTE: Loopback volumes recurse into the storage layer when servicing IO
+struct PhysicalVolumeDev
+{
+	/// Block size (re-read when the medium changes)
+	block_size: AtomicUsize,
+	/// Cached value of `PhysicalVolume::max_outstanding` (1 unless the volume is concurrent)
+	max_outstanding: usize,
+	dev: PvAccess,
+	/// Reads and writes waiting for the device
+	queue: io_queue::IoQueue,
+}
+/// Access to a physical volume's driver
+enum PvAccess
+{
+	/// Calls are serialised by a lock
+	Locked(Mutex<Box<dyn PhysicalVolume>>),
+	/// Internally synchronised driver (`ConcurrentPhysicalVolume`), called without a lock
+	Concurrent(Box<dyn PhysicalVolume + Sync>),
+}
+
 /// A single physical volume
 struct PhysicalVolumeInfo
 {
-	dev: Box<dyn PhysicalVolume>,
+	dev: Arc<PhysicalVolumeDev>,
 	mapper: Option<(usize,&'static dyn Mapper)>,
 }
 /// A single logical volume, composed of 1 or more physical blocks
@@ -162,6 +245,12 @@ static S_PHYSICAL_VOLUMES: LazyMutex<VecMap<usize,PhysicalVolumeInfo>> = lazymut
 static S_NEXT_LV_IDX: AtomicUsize = AtomicUsize::new(0);
 static S_LOGICAL_VOLUMES: LazyMutex<VecMap<usize,Arc<LogicalVolume>>> = lazymutex_init!();
 static S_MAPPERS: LazyMutex<Vec<&'static dyn Mapper>> = lazymutex_init!();
+/// Physical volumes released while in use, removed once none of their logical volumes are open
+static S_DEFERRED_DETACH: Mutex<Vec<PhysicalVolumeReg>> = Mutex::new(Vec::new_const());
+/// Callbacks informed of new logical volumes
+static S_LV_WATCHERS: Mutex<Vec<fn(&str)>> = Mutex::new(Vec::new_const());
+/// Logical volumes created since watchers were last informed
+static S_NEW_LVS: Mutex<Vec<String>> = Mutex::new(Vec::new_const());
 
 // NOTE: Should unbinding of LVs be allowed? (Yes, for volume removal)
 
@@ -176,21 +265,47 @@ fn init()
 }
 
 /// Register a physical volume
+///
+/// Requests to the volume are serialised, see `register_concurrent_pv` for volumes that can handle several at once.
 pub fn register_pv(dev: Box<dyn PhysicalVolume>) -> PhysicalVolumeReg
 {
-	log_trace!("register_pv(pv = \"{}\")", dev.name());
+	if dev.max_outstanding() > 1 {
+		log_notice!("{}: Not registered as concurrent, requests will be serialised", dev.name());
+	}
+	register_pv_inner(PvAccess::Locked(Mutex::new(dev)))
+}
+/// Register an internally synchronised physical volume (up to `max_outstanding` requests are issued at once)
+pub fn register_concurrent_pv<T: ConcurrentPhysicalVolume>(dev: Box<T>) -> PhysicalVolumeReg
+{
+	register_pv_inner(PvAccess::Concurrent(dev))
+}
+fn register_pv_inner(dev: PvAccess) -> PhysicalVolumeReg
+{
+	let (name, has_medium, block_size, max_outstanding) = dev.with(|dev| (
+		String::from_str(dev.name()),
+		dev.capacity().is_some(),
+		// - Zero if there's no medium (requests fail until the medium changes)
+		dev.blocksize().unwrap_or(0),
+		dev.max_outstanding(),
+		));
+	let max_outstanding = match dev
+		{
+		PvAccess::Locked(_) => 1,
+		PvAccess::Concurrent(_) => ::core::cmp::max(1, max_outstanding),
+		};
+	log_trace!("register_pv(pv = \"{}\")", name);
 	let pv_id = S_NEXT_PV_IDX.fetch_add(1, ::core::sync::atomic::Ordering::Relaxed);
 
 	// Now that a new PV has been inserted, handlers should be informed
 	let mut best_mapper: Option<&dyn Mapper> = None;
 	let mut best_mapper_level = 0;
 	// - Only try to resolve a mapper if there's media in the drive
-	if dev.capacity().is_some()
+	if has_medium
 	{
 		let mappers = S_MAPPERS.lock();
 		for &mapper in mappers.iter()
 		{
-			match mapper.handles_pv(&*dev)
+			match dev.with(|dev| mapper.handles_pv(dev))
 			{
 			Err(e) => log_error!("IO Error in mapper detection: {:?}", e),
 			Ok(0) => {},	// Ignore (doesn't handle)
@@ -203,7 +318,7 @@ pub fn register_pv(dev: Box<dyn PhysicalVolume>) -> PhysicalVolumeReg
 				{
 					// Fight!
 					log_warning!("LV Mappers {} and {} are fighting over {}",
-						mapper.name(), best_mapper.unwrap().name(), dev.name());
+						mapper.name(), best_mapper.unwrap().name(), name);
 				}
 				else
 				{
@@ -214,23 +329,56 @@ pub fn register_pv(dev: Box<dyn PhysicalVolume>) -> PhysicalVolumeReg
 		}
 	}
 	
-	// Wait until after checking for a handler before we add the PV to the list
-	S_PHYSICAL_VOLUMES.lock().insert(pv_id, PhysicalVolumeInfo {
-		dev: dev,
+	let mut pvi = PhysicalVolumeInfo {
+		dev: Arc::new(PhysicalVolumeDev {
+			block_size: AtomicUsize::new(block_size),
+			max_outstanding: max_outstanding,
+			dev: dev,
+			queue: io_queue::IoQueue::new(),
+			}),
 		mapper: None,
-		});
-	
+		};
+	// Apply the mapper before adding the PV to the list (the list lock can't be held while enumerating)
 	if let Some(mapper) = best_mapper {
-		apply_mapper_to_pv(mapper, best_mapper_level, pv_id, S_PHYSICAL_VOLUMES.lock().get_mut(&pv_id).unwrap())
+		apply_mapper_to_pv(mapper, best_mapper_level, pv_id, &mut pvi)
 	}
 	else {
 		// Apply the fallback (full volume) mapper
-		apply_mapper_to_pv(&default_mapper::S_MAPPER, 0, pv_id, S_PHYSICAL_VOLUMES.lock().get_mut(&pv_id).unwrap())
+		apply_mapper_to_pv(&default_mapper::S_MAPPER, 0, pv_id, &mut pvi)
 	}
+	S_PHYSICAL_VOLUMES.lock().insert(pv_id, pvi);
+	notify_new_lvs();
 	
 	PhysicalVolumeReg { idx: pv_id }
 }
 
+/// Register a callback to be informed (by name) of logical volumes as they appear
+///
+/// The callback is invoked without any storage locks held, once the volume is usable. It is not
+/// called for volumes that exist at registration time (use `enum_lvs` for those).
+pub fn register_lv_watcher(cb: fn(&str))
+{
+	S_LV_WATCHERS.lock().push(cb);
+}
+/// Inform watchers of any LVs created since the last call
+fn notify_new_lvs()
+{
+	loop
+	{
+		let names: Vec<String> = ::core::mem::replace(&mut *S_NEW_LVS.lock(), Vec::new());
+		if names.len() == 0 {
+			break ;
+		}
+		let watchers: Vec<fn(&str)> = S_LV_WATCHERS.lock().clone();
+		for name in names
+		{
+			for w in watchers.iter() {
+				w(&name);
+			}
+		}
+	}
+}
+
 /// Register a mapper with the storage subsystem
 // TODO: How will it be unregistered. Requires a mapper handle that ensures that the mapper is unregistered when the relevant
 // module is unloaded.
@@ -243,13 +391,16 @@ pub fn register_mapper(mapper: &'static dyn Mapper)
 	// Check unbound PVs
 	for (&id,pv) in S_PHYSICAL_VOLUMES.lock().iter_mut()
 	{
-		if pv.dev.capacity().is_none() {
-			// No media, skip
-			continue ;
-		}
-		match mapper.handles_pv(&*pv.dev)
+		let rv = pv.dev.with_dev(|dev| {
+			if dev.capacity().is_none() {
+				// No media, skip
+				return Ok(0);
+			}
+			mapper.handles_pv(dev).map_err(|e| (e, String::from_str(dev.name())))
+			});
+		match rv
 		{
-		Err(e) => log_error!("Error checking PV{}: {:?}", pv.dev.name(), e),
+		Err((e, name)) => log_error!("Error checking PV{}: {:?}", name, e),
 		Ok(0) => {},	// Ignore
 		Ok(level) => 
 			if let Some( (lvl, _other) ) = pv.mapper
@@ -271,6 +422,7 @@ pub fn register_mapper(mapper: &'static dyn Mapper)
 			},
 		}
 	}
+	notify_new_lvs();
 }
 
 /// Apply the passed mapper to the provided physical volume
@@ -294,7 +446,7 @@ fn apply_mapper_to_pv(mapper: &'static dyn Mapper, level: usize, pv_id: usize, p
 				.filter(|&(_,lv)| lv.is_opened)
 				.count();
 			if num_mounted > 0 {
-				log_notice!("{}LVs using PV #{} {} are mounted, not updating mapping", num_mounted, pv_id, pvi.dev.name() );
+				log_notice!("{}LVs using PV #{} {} are mounted, not updating mapping", num_mounted, pv_id, pvi.dev.with_dev(|d| String::from_str(d.name())) );
 				return ;
 			}
 			// > If none are mounted, then remove the mappings
@@ -314,13 +466,23 @@ fn apply_mapper_to_pv(mapper: &'static dyn Mapper, level: usize, pv_id: usize, p
 	pvi.mapper = Some( (level, mapper) );
 	// - Enumerate volumes
 	//  TODO: Support more complex volume types
-	match mapper.enum_volumes(&*pvi.dev, &mut |name, base, len| {
-		new_simple_lv(name, pv_id, pvi.dev.blocksize(), base, len);
-		})
-	{
-	Err(e) => log_error!("IO Error while enumerating {}: {:?}", pvi.dev.name(), e),
-	Ok(_) => {},
-	}
+	pvi.dev.with_dev(|dev| {
+		let block_size = match dev.blocksize()
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_error!("Unable to get block size of {}: {:?}", dev.name(), e);
+				return ;
+				},
+			};
+		match mapper.enum_volumes(dev, &mut |name, base, len| {
+			new_simple_lv(name, pv_id, block_size, base, len);
+			})
+		{
+		Err(e) => log_error!("IO Error while enumerating {}: {:?}", dev.name(), e),
+		Ok(_) => {},
+		}
+		});
 }
 fn new_simple_lv(name: String, pv_id: usize, block_size: usize, base: u64, size: u64)
 {
@@ -338,18 +500,20 @@ fn new_simple_lv(name: String, pv_id: usize, block_size: usize, base: u64, size:
 	
 	log_log!("Logical Volume: {} {}", lv.name, SizePrinter(size*block_size as u64));
 	
+	// Watchers are informed once the PV is usable (see `notify_new_lvs`)
+	S_NEW_LVS.lock().push(lv.name.clone());
+	
 	// Add to global list
 	{
 		let mut lh = S_LOGICAL_VOLUMES.lock();
 		lh.insert(lvidx, lv);
 	}
-	// TODO: Inform something of the new LV
 }
 
 /// Enumerate present physical volumes (returning both the identifier and name)
 pub fn enum_pvs() -> Vec<(usize,String)>
 {
-	S_PHYSICAL_VOLUMES.lock().iter().map(|(k,v)| (*k, String::from_str(v.dev.name())) ).collect()
+	S_PHYSICAL_VOLUMES.lock().iter().map(|(k,v)| (*k, v.dev.with_dev(|d| String::from_str(d.name()))) ).collect()
 }
 
 
@@ -378,9 +542,24 @@ impl_fmt!{
 
 impl VolumeHandle
 {
-	pub fn new_ramdisk(_count: usize) -> VolumeHandle {
-		VolumeHandle {
-			handle: Arc::new(LogicalVolume::default())
+	/// Create a new RAM-backed volume of `count` blocks, and open it
+	///
+	/// A zero-sized RAM disk is an unbacked placeholder, for filesystems that don't use their volume (e.g. ramfs)
+	pub fn new_ramdisk(count: usize) -> Result<VolumeHandle,IoError> {
+		if count == 0 {
+			return Ok(VolumeHandle {
+				handle: ::core::mem::ManuallyDrop::new( Arc::new(LogicalVolume::default()) )
+				});
+		}
+		let name = try!(::hw::ramdisk::create(count as u64, &[]));
+		// - A blank volume is always bound by the fallback mapper
+		match VolumeHandle::open_named(&format!("{}w", name))
+		{
+		Ok(v) => Ok(v),
+		Err(e) => {
+			log_error!("Unable to open new RAM disk {}: {}", name, e);
+			Err( IoError::Unknown("RAM disk volume unavailable") )
+			},
 		}
 	}
 	/// Acquire an unique handle to a logical volume
@@ -398,7 +577,7 @@ impl VolumeHandle
 		{
 		Some((_,v)) => {
 			if Arc::get_mut(v).is_some() {
-				Ok( VolumeHandle { handle: v.clone() } )
+				Ok( VolumeHandle { handle: ::core::mem::ManuallyDrop::new(v.clone()) } )
 			}
 			else {
 				Err( VolOpenError::Locked )
@@ -411,6 +590,10 @@ impl VolumeHandle
 	pub fn block_size(&self) -> usize {
 		self.handle.block_size
 	}
+	/// Number of blocks in the volume
+	pub fn block_count(&self) -> u64 {
+		self.handle.regions.iter().map(|r| r.block_count as u64).sum()
+	}
 
 	pub fn idx(&self) -> usize {
 		self.handle.index
@@ -450,7 +633,11 @@ impl VolumeHandle
 	/// 
 	/// The buffer must be a multiple of the logical block size
 	pub fn read_blocks(&self, idx: u64, dst: &mut [u8]) -> Result<(),IoError> {
-		log_trace!("VolumeHandle::read_blocks(idx={}, dst={{len={}}})", idx, dst.len());
+		self.read_blocks_prio(IoPriority::Normal, idx, dst)
+	}
+	/// Read a series of blocks, queued with the provided priority class
+	pub fn read_blocks_prio(&self, prio: IoPriority, idx: u64, dst: &mut [u8]) -> Result<(),IoError> {
+		log_trace!("VolumeHandle::read_blocks_prio(prio={:?}, idx={}, dst={{len={}}})", prio, idx, dst.len());
 		if dst.len() % self.block_size() != 0 {
 			log_warning!("Read size {} not a multiple of {} bytes", dst.len(), self.block_size());
 			return Err( IoError::InvalidParameter );
@@ -471,7 +658,7 @@ impl VolumeHandle
 			assert!(count <= rem);
 			let bofs = blk as usize * self.block_size();
 			let dst = &mut dst[bofs .. bofs + count * self.block_size()];
-			try!( S_PHYSICAL_VOLUMES.lock().get(&pv).expect("Volume missing").read(ofs, dst) );
+			try!( try!(get_pv_dev(pv)).io(prio, ofs, DataPtr::Recv(dst)) );
 			blk += count;
 			rem -= count;
 		}
@@ -479,7 +666,11 @@ impl VolumeHandle
 	}
 
 	pub fn write_blocks(&self, idx: u64, dst: &[u8]) -> Result<(),IoError> {
-		log_trace!("VolumeHandle::write_blocks(idx={}, dst={{len={}}})", idx, dst.len());
+		self.write_blocks_prio(IoPriority::Normal, idx, dst)
+	}
+	/// Write a series of blocks, queued with the provided priority class
+	pub fn write_blocks_prio(&self, prio: IoPriority, idx: u64, dst: &[u8]) -> Result<(),IoError> {
+		log_trace!("VolumeHandle::write_blocks_prio(prio={:?}, idx={}, dst={{len={}}})", prio, idx, dst.len());
 		if dst.len() % self.block_size() != 0 {
 			log_warning!("Write size {} not a multiple of {} bytes", dst.len(), self.block_size());
 			return Err( IoError::InvalidParameter );
@@ -500,81 +691,213 @@ impl VolumeHandle
 			assert!(count <= rem);
 			let bofs = blk as usize * self.block_size();
 			let dst = &dst[bofs .. bofs + count * self.block_size()];
-			try!( S_PHYSICAL_VOLUMES.lock().get(&pv).unwrap().write(ofs, dst) );
+			try!( try!(get_pv_dev(pv)).io(prio, ofs, DataPtr::Send(dst)) );
 			blk += count;
 			rem -= count;
 		}
 		Ok( () )
 	}
+
+	/// Flush the write caches of all physical volumes backing this volume
+	///
+	/// Acts as a write barrier: writes that completed before this call are on stable storage on return.
+	pub fn flush(&self) -> Result<(),IoError> {
+		log_trace!("VolumeHandle::flush() - {}", self.name());
+		let mut flushed: Vec<usize> = Vec::new();
+		for r in self.handle.regions.iter()
+		{
+			if flushed.contains(&r.volume) {
+				continue ;
+			}
+			flushed.push(r.volume);
+			try!( try!(get_pv_dev(r.volume)).with_dev(|d| d.flush().wait()) );
+		}
+		Ok( () )
+	}
 }
 
-impl PhysicalVolumeInfo
+impl PvAccess
 {
-	fn max_blocks_per_read(&self) -> usize {
-		// 32 blocks per read op, = 0x4000 (16KB) for 512 byte sectors
-		// TODO: Remove this?
-		32
+	/// Run a closure with access to the driver (locking it unless it's concurrent)
+	fn with<R, F: FnOnce(&dyn PhysicalVolume)->R>(&self, f: F) -> R {
+		match *self
+		{
+		PvAccess::Locked(ref dev) => f( &**dev.lock() ),
+		PvAccess::Concurrent(ref dev) => f( &**dev ),
+		}
 	}
-	
-	/// Read blocks from the device
-	pub fn read(&self, first: u64, dst: &mut [u8]) -> Result<usize,IoError>
+}
+
+impl PhysicalVolumeDev
+{
+	/// Read or write blocks via the volume's request queue
+	fn io(&self, prio: IoPriority, block: u64, data: DataPtr) -> Result<(),IoError> {
+		let block_size = self.block_size.load(Ordering::Relaxed);
+		if block_size == 0 {
+			// - No medium was present when the size was last read, check again
+			try!(self.refresh_block_size());
+			return Err( IoError::MediaChanged );
+		}
+		let rv = self.queue.submit(self, block_size, prio, block, data);
+		if let Err(IoError::MediaChanged) = rv {
+			// - The new medium may have a different block size
+			let _ = self.refresh_block_size();
+		}
+		rv
+	}
+	/// Re-read the device's block size (after a media change)
+	fn refresh_block_size(&self) -> Result<(),IoError> {
+		self.with_dev(|dev| {
+			let new_size = match dev.blocksize()
+				{
+				Ok(v) => v,
+				Err(e) => {
+					self.block_size.store(0, Ordering::Relaxed);
+					return Err(e);
+					},
+				};
+			if self.block_size.swap(new_size, Ordering::Relaxed) != new_size {
+				log_log!("{}: Block size changed to {} after media change", dev.name(), new_size);
+			}
+			Ok( () )
+			})
+	}
+	/// Run a closure with access to the device
+	///
+	/// Concurrent devices are accessed without a lock, others are locked for the call.
+	fn with_dev<R, F: FnOnce(&dyn PhysicalVolume)->R>(&self, f: F) -> R {
+		self.dev.with(f)
+	}
+}
+
+/// Obtain a physical volume's device (without holding the list lock)
+fn get_pv_dev(idx: usize) -> Result<Arc<PhysicalVolumeDev>,IoError>
+{
+	match S_PHYSICAL_VOLUMES.lock().get(&idx)
 	{
-		log_trace!("PhysicalVolumeInfo::read(first={},{} bytes)", first, dst.len());
-		let block_size = self.dev.blocksize();
-		let total_blocks = dst.len() / block_size;
-		// Read up to 'block_step' blocks in each read call
-		// - TODO: Request a read of as much as possible, and be told by the device how many were serviced
+	Some(pv) => Ok( pv.dev.clone() ),
+	None => {
+		// - PV was removed while a LV using it was open
+		log_warning!("Physical volume #{} has been removed", idx);
+		Err( IoError::NoMedium )
+		},
+	}
+}
+
+// 32 blocks per read op, = 0x4000 (16KB) for 512 byte sectors
+// TODO: Remove this?
+const MAX_BLOCKS_PER_WRITE: usize = 32;
+
+/// Read blocks from the device
+fn read_pv(dev: &dyn PhysicalVolume, prio: u8, first: u64, dst: &mut [u8]) -> Result<usize,IoError>
+{
+	log_trace!("read_pv(prio={},first={},{} bytes)", prio, first, dst.len());
+	let block_size = try!(dev.blocksize());
+	let total_blocks = dst.len() / block_size;
+	// Read up to 'block_step' blocks in each read call
+	// - TODO: Request a read of as much as possible, and be told by the device how many were serviced
+	{
+		let mut buf = dst;
+		let mut blk_id = first;
+		while buf.len() > 0
 		{
-			let mut buf = dst;
-			let mut blk_id = first;
-			while buf.len() > 0
-			{
-				assert!(buf.len() % block_size == 0);
-				let prio = 0;
-				let blocks = buf.len() / block_size;
-				
-				// TODO: Async! (maybe return a composite read handle?)
-				let real_count = match self.dev.read(prio, blk_id, blocks, buf).wait()
-					{
-					Ok(v) => v,
-					Err(e) => todo!("Error when PV fails to read: {:?}", e),
-					};
-				assert!(real_count <= blocks);
-				blk_id += real_count as u64;
-
-				// SAFE: Evil stuff to advance the buffer
-				buf = unsafe { &mut *(&mut buf[real_count * block_size..] as *mut _) };
-//				split_at_mut_inplace(&mut buf, real_count * block_size);
-			}
+			assert!(buf.len() % block_size == 0);
+			let blocks = buf.len() / block_size;
+			
+			// TODO: Async! (maybe return a composite read handle?)
+			let real_count = try!( dev.read(prio, blk_id, blocks, buf).wait() );
+			assert!(real_count <= blocks);
+			blk_id += real_count as u64;
+
+			// SAFE: Evil stuff to advance the buffer
+			buf = unsafe { &mut *(&mut buf[real_count * block_size..] as *mut _) };
+//			split_at_mut_inplace(&mut buf, real_count * block_size);
 		}
+	}
 
-		Ok(total_blocks)
+	Ok(total_blocks)
+}
+
+/// Write blocks to the device
+fn write_pv(dev: &dyn PhysicalVolume, prio: u8, first: u64, dst: &[u8]) -> Result<usize,IoError>
+{
+	log_trace!("write_pv(prio={},first={},{} bytes)", prio, first, dst.len());
+	let block_step = MAX_BLOCKS_PER_WRITE;
+	let block_size = try!(dev.blocksize());
+	// Read up to 'block_step' blocks in each read call
+	{
+		let iter_ids  = (first .. ).step_by(block_step);
+		let iter_bufs = dst.chunks( block_step * block_size );
+		for (blk_id,buf) in iter_ids.zip( iter_bufs )
+		{
+			let blocks = buf.len() / block_size;
+			
+			// TODO: Async! (maybe return a composite read handle?)
+			let real_count = try!( dev.write(prio, blk_id, blocks, buf).wait() );
+			assert!(real_count == blocks, "TODO: Handle incomplete writes");
+		}
 	}
-	
-	/// Write blocks from the device
-	pub fn write(&self, first: u64, dst: &[u8]) -> Result<usize,IoError>
+	Ok(dst.len()/block_size)
+}
+
+impl ::core::ops::Drop for VolumeHandle
+{
+	fn drop(&mut self)
 	{
-		log_trace!("PhysicalVolumeInfo::write(first={},{} bytes)", first, dst.len());
-		let block_step = self.max_blocks_per_read();
-		let block_size = self.dev.blocksize();
-		// Read up to 'block_step' blocks in each read call
+		// SAFE: The handle isn't used after this
+		unsafe { ::core::mem::ManuallyDrop::drop(&mut self.handle); }
+		reap_deferred_detach();
+	}
+}
+
+/// Remove any deferred-detach physical volumes that are no longer in use
+fn reap_deferred_detach()
+{
+	let unused: Vec<PhysicalVolumeReg> = {
+		let mut lh = S_DEFERRED_DETACH.lock();
+		if lh.is_empty() {
+			return ;
+		}
+		let mut unused = Vec::new();
+		let mut i = 0;
+		while i < lh.len()
 		{
-			let iter_ids  = (first .. ).step_by(block_step);
-			let iter_bufs = dst.chunks( block_step * block_size );
-			for (blk_id,buf) in iter_ids.zip( iter_bufs )
-			{
-				let prio = 0;
-				let blocks = buf.len() / block_size;
-				
-				// TODO: Async! (maybe return a composite read handle?)
-				match self.dev.write(prio, blk_id, blocks, buf).wait()
-				{
-				Ok(real_count) => { assert!(real_count == blocks, "TODO: Handle incomplete writes"); },
-				Err(e) => todo!("Error when PV fails to write: {:?}", e),
-				}
+			if lh[i].is_in_use() {
+				i += 1;
+			}
+			else {
+				unused.push( lh.remove(i) );
 			}
 		}
-		Ok(dst.len()/block_size)
+		unused
+		};
+	// - Dropped with the lock released (removing the PV takes the volume locks)
+	drop(unused);
+}
+
+impl PhysicalVolumeReg
+{
+	/// Remove this physical volume once none of its logical volumes are open (immediately if none are)
+	pub fn detach_when_unused(self)
+	{
+		// - Queued then reaped (instead of checked first), so a handle closed concurrently can't be missed
+		S_DEFERRED_DETACH.lock().push(self);
+		reap_deferred_detach();
+	}
+	/// Returns true if any logical volume on this PV is currently open
+	pub fn is_in_use(&self) -> bool
+	{
+		S_LOGICAL_VOLUMES.lock().iter_mut()
+			.filter(|e| e.1.regions.iter().any(|r| r.volume == self.idx))
+			.any(|(_,lv)| Arc::get_mut(lv).is_none())
+	}
+	/// Obtain the names of the logical volumes using this PV
+	pub fn volumes(&self) -> Vec<String>
+	{
+		S_LOGICAL_VOLUMES.lock().iter()
+			.filter(|e| e.1.regions.iter().any(|r| r.volume == self.idx))
+			.map(|(_,lv)| lv.name.clone())
+			.collect()
 	}
 }
 
@@ -582,7 +905,25 @@ impl ::core::ops::Drop for PhysicalVolumeReg
 {
 	fn drop(&mut self)
 	{
-		todo!("PhysicalVolumeReg::drop idx={}", self.idx);
+		// Remove all LVs using this PV, any still open will get `IoError::NoMedium`
+		{
+			let mut lh = S_LOGICAL_VOLUMES.lock();
+			let keys: Vec<usize> = lh.iter()
+				.filter(|e| e.1.regions.iter().any(|r| r.volume == self.idx))
+				.map(|(&i,_)| i)
+				.collect();
+			for k in keys
+			{
+				if let Some(mut lv) = lh.remove(&k) {
+					if Arc::get_mut(&mut lv).is_none() {
+						log_warning!("LV '{}' still open while removing PV #{}", lv.name, self.idx);
+					}
+				}
+			}
+		}
+		if let Some(pv) = S_PHYSICAL_VOLUMES.lock().remove(&self.idx) {
+			log_log!("Removed PV #{} {}", self.idx, pv.dev.with_dev(|d| String::from_str(d.name())));
+		}
 	}
 }
 
@@ -614,6 +955,270 @@ impl ::core::fmt::Display for SizePrinter
 	}
 }
 
+/// Per physical volume request queue
+///
+/// Up to `PhysicalVolume::max_outstanding` threads issue requests to a device at a time, others queue up and are
+/// handed a free slot in priority order (then in ascending block order from the previous request, a one-way
+/// elevator). Queued requests adjacent to the one being dispatched are merged into a single device request.
+mod io_queue
+{
+	use prelude::*;
+	use core::cell::Cell;
+	use sync::{Mutex,EventChannel};
+	use super::{PhysicalVolume,PhysicalVolumeDev,IoPriority,IoError,DataPtr};
+
+	/// Maximum size of a merged request (in blocks)
+	const MAX_MERGE_BLOCKS: usize = 128;
+	/// Number of times a request can be passed over before it's dispatched ahead of all classes (prevents starvation)
+	const MAX_SKIPS: usize = 16;
+
+	pub struct IoQueue
+	{
+		state: Mutex<State>,
+	}
+	struct State
+	{
+		/// Number of threads currently issuing requests to the device
+		active: usize,
+		/// Block following the last dispatched request (the elevator position)
+		head: u64,
+		pending: Vec<RequestPtr>,
+	}
+
+	/// Pointer to a request owned by a waiting thread
+	struct RequestPtr(*const Request);
+	// SAFE: Requests are only accessed with the queue locked, or by the thread dispatching them
+	unsafe impl Send for RequestPtr {}
+
+	#[derive(Copy,Clone)]
+	enum Status
+	{
+		Queued,
+		/// The owning thread now has the device
+		Dispatch,
+		/// Completed as part of another thread's (merged) request
+		Done(Result<(),IoError>),
+	}
+
+	/// A request, stored on the stack of the requesting thread
+	struct Request
+	{
+		prio: IoPriority,
+		is_write: bool,
+		block: u64,
+		count: usize,
+		data: *mut u8,
+
+		// Accessed with the queue locked:
+		/// Number of times this request has been passed over
+		skips: Cell<usize>,
+		status: Cell<Status>,
+		/// Posted (with the queue locked) when `status` changes
+		event: EventChannel,
+	}
+
+	impl IoQueue
+	{
+		pub fn new() -> IoQueue {
+			IoQueue {
+				state: Mutex::new(State {
+					active: 0,
+					head: 0,
+					pending: Vec::new(),
+					}),
+				}
+		}
+
+		/// Perform a read or write, waiting for a free slot on the device
+		pub fn submit(&self, dev: &PhysicalVolumeDev, block_size: usize, prio: IoPriority, block: u64, data: DataPtr) -> Result<(),IoError>
+		{
+			let is_write = data.is_send();
+			let (ptr, len) = match data
+				{
+				DataPtr::Send(p) => (p.as_ptr() as *mut u8, p.len()),
+				DataPtr::Recv(p) => (p.as_mut_ptr(), p.len()),
+				};
+			let req = Request {
+				prio: prio,
+				is_write: is_write,
+				block: block,
+				count: len / block_size,
+				data: ptr,
+				skips: Cell::new(0),
+				status: Cell::new(Status::Queued),
+				event: EventChannel::new(),
+				};
+
+			let mut lh = self.state.lock();
+			if lh.active < dev.max_outstanding {
+				lh.active += 1;
+			}
+			else {
+				lh.pending.push( RequestPtr(&req) );
+				loop
+				{
+					drop(lh);
+					req.event.sleep();
+					// NOTE: Re-locking also ensures that the poster is done with `req.event`
+					lh = self.state.lock();
+					match req.status.get()
+					{
+					Status::Queued => {},
+					Status::Dispatch => break,
+					Status::Done(rv) => return rv,
+					}
+				}
+			}
+			drop(lh);
+
+			self.dispatch(dev, block_size, &req)
+		}
+
+		/// Issue a request (and any adjacent queued requests) to the device, then hand the device to the next request
+		fn dispatch(&self, dev: &PhysicalVolumeDev, block_size: usize, req: &Request) -> Result<(),IoError>
+		{
+			// - Collect queued requests that can be merged with this one
+			let mut merged: Vec<RequestPtr> = Vec::new();
+			let (first, count) = {
+				let mut lh = self.state.lock();
+				let mut first = req.block;
+				let mut count = req.count;
+				loop
+				{
+					let pos = lh.pending.iter().position(|p| {
+						// SAFE: Queued requests are valid until completed
+						let r = unsafe { &*p.0 };
+						r.is_write == req.is_write && count + r.count <= MAX_MERGE_BLOCKS
+							&& (r.block == first + count as u64 || r.block + r.count as u64 == first)
+						});
+					match pos
+					{
+					Some(i) => {
+						let p = lh.pending.remove(i);
+						// SAFE: As above
+						let r = unsafe { &*p.0 };
+						first = ::core::cmp::min(first, r.block);
+						count += r.count;
+						merged.push(p);
+						},
+					None => break,
+					}
+				}
+				(first, count)
+				};
+
+			let rv = if merged.is_empty() {
+					// SAFE: This thread owns the request
+					dev.with_dev(|dev| unsafe { req.issue(dev, first, block_size) })
+				}
+				else {
+					log_trace!("IoQueue::dispatch - Merged {} requests into {}+{}", merged.len() + 1, first, count);
+					// SAFE: Merged requests are owned by threads waiting for this one
+					let requests: Vec<&Request> = Some(req).into_iter().chain( merged.iter().map(|p| unsafe { &*p.0 }) ).collect();
+					let prio = requests.iter().map(|r| r.prio).min().unwrap();
+
+					let mut buf = vec![0u8; count * block_size];
+					let rv = if req.is_write {
+							for r in requests.iter() {
+								// SAFE: Data is valid until the request completes
+								buf[(r.block - first) as usize * block_size ..][.. r.count * block_size].clone_from_slice( unsafe { r.data(block_size) } );
+							}
+							dev.with_dev(|dev| super::write_pv(dev, prio.driver_prio(), first, &buf))
+						}
+						else {
+							dev.with_dev(|dev| super::read_pv(dev, prio.driver_prio(), first, &mut buf))
+						};
+					if rv.is_ok() && !req.is_write {
+						for r in requests.iter() {
+							// SAFE: Data is valid until the request completes, and not accessed by the owner until then
+							unsafe { r.data_mut(block_size) }.clone_from_slice( &buf[(r.block - first) as usize * block_size ..][.. r.count * block_size] );
+						}
+					}
+					rv.map(|_| ())
+				};
+
+			let mut lh = self.state.lock();
+			for p in merged
+			{
+				// SAFE: The owner is waiting on the event, and won't return until the queue is unlocked
+				let r = unsafe { &*p.0 };
+				r.status.set(Status::Done(rv));
+				r.event.post();
+			}
+			lh.head = first + count as u64;
+			match lh.next_request()
+			{
+			Some(p) => {
+				// SAFE: As above
+				let r = unsafe { &*p.0 };
+				r.status.set(Status::Dispatch);
+				r.event.post();
+				},
+			None => lh.active -= 1,
+			}
+			rv
+		}
+	}
+
+	impl State
+	{
+		/// Remove the next request to be dispatched from the queue
+		fn next_request(&mut self) -> Option<RequestPtr>
+		{
+			let head = self.head;
+			let best = self.pending.iter().enumerate()
+				.min_by_key(|&(_, p)| {
+					// SAFE: Queued requests are valid until completed
+					let r = unsafe { &*p.0 };
+					let prio = if r.skips.get() >= MAX_SKIPS { IoPriority::Interactive } else { r.prio };
+					// - Requests after the head first (in block order), then wrap around to the start
+					(prio, r.block < head, r.block)
+					})
+				.map(|(i, _)| i);
+			match best
+			{
+			Some(i) => {
+				let p = self.pending.remove(i);
+				for o in self.pending.iter()
+				{
+					// SAFE: As above
+					let r = unsafe { &*o.0 };
+					r.skips.set(r.skips.get() + 1);
+				}
+				Some(p)
+				},
+			None => None,
+			}
+		}
+	}
+
+	impl Request
+	{
+		/// Issue this request directly to the device
+		///
+		/// UNSAFE: Caller must be dispatching this request
+		unsafe fn issue(&self, dev: &dyn PhysicalVolume, first: u64, block_size: usize) -> Result<(),IoError>
+		{
+			let rv = if self.is_write {
+					super::write_pv(dev, self.prio.driver_prio(), first, self.data(block_size))
+				}
+				else {
+					super::read_pv(dev, self.prio.driver_prio(), first, self.data_mut(block_size))
+				};
+			rv.map(|_| ())
+		}
+		/// UNSAFE: Caller must be dispatching this request
+		unsafe fn data(&self, block_size: usize) -> &[u8] {
+			::core::slice::from_raw_parts(self.data, self.count * block_size)
+		}
+		/// UNSAFE: Caller must be dispatching this request, and it must be a read
+		unsafe fn data_mut(&self, block_size: usize) -> &mut [u8] {
+			assert!( !self.is_write );
+			::core::slice::from_raw_parts_mut(self.data, self.count * block_size)
+		}
+	}
+}
+
 mod default_mapper
 {
 	use prelude::*;
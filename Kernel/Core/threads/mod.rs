@@ -14,6 +14,7 @@ mod sleep_object;
 
 pub use self::thread::{Thread,ThreadPtr,ThreadID};
 pub use self::thread::{ThreadHandle,ProcessHandle};
+pub use self::thread::{ProcessStats,ProcessInfo,get_process_info};
 pub use self::thread::new_idle_thread;
 
 pub use self::worker_thread::WorkerThread;
@@ -106,6 +107,7 @@ pub fn yield_time()
 pub fn yield_to(thread: ThreadPtr)
 {
 	log_debug!("Yielding CPU to {:?}", thread);
+	account_cpu_time(&thread);
 	s_runnable_threads.lock().push( get_cur_thread() );
 	::arch::threads::switch_to( thread );
 }
@@ -182,6 +184,33 @@ fn with_cur_thread<T, F: FnOnce(&thread::Thread)->T>(fcn: F) -> T
 	fcn(t)
 }
 
+/// Charge elapsed CPU time to the current thread's process, and start timing the next thread
+fn account_cpu_time(next: &Thread)
+{
+	let now = ::time::ticks();
+	let cur = ::arch::threads::borrow_thread();
+	if !cur.is_null() {
+		// SAFE: Non-NULL, and the current thread is valid until the switch
+		unsafe { (*cur).account_cpu_time(now) };
+	}
+	next.mark_scheduled(now);
+}
+
+/// Run a closure with the resource counters of the current process
+///
+/// NOTE: Does nothing (returns `None`) if called before threading is initialised
+pub fn with_process_stats<T, F: FnOnce(&thread::ProcessStats)->T>(fcn: F) -> Option<T>
+{
+	let tp = ::arch::threads::borrow_thread();
+	if tp.is_null() {
+		None
+	}
+	else {
+		// SAFE: Non-NULL, and the thread is valid while executing
+		Some( fcn( unsafe { (*tp).get_process_info().stats() } ) )
+	}
+}
+
 // TODO: Prevent this pointer from being sent (which will prevent accessing of freed memory)
 pub fn get_process_local<T: Send+Sync+::core::any::Any+Default+'static>() -> ArefBorrow<T>
 {
@@ -230,6 +259,7 @@ pub fn reschedule()
 	{
 		if let Some(thread) = get_thread_to_run()
 		{
+			account_cpu_time(&thread);
 			if &*thread as *const _ == ::arch::threads::borrow_thread() as *const _
 			{
 				log_debug!("Task switch to self, idle");
@@ -250,6 +280,7 @@ pub fn reschedule()
 			if &*thread as *const _ != ::arch::threads::borrow_thread() as *const _
 			{
 				log_trace!("reschedule() - No active threads, idling");
+				account_cpu_time(&thread);
 				
 				// Switch to the idle thread
 				::arch::threads::switch_to( thread );
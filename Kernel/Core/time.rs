@@ -3,6 +3,7 @@
 //
 // Core/time.rs
 //! Kernel timing and timers
+use core::sync::atomic::{AtomicBool, Ordering};
 
 /// Timer ticks (ms)
 pub type TickCount = u64;
@@ -13,6 +14,95 @@ pub fn ticks() -> u64
 	::arch::cur_timestamp()
 }
 
+/// Convert a tick count/duration to milliseconds
+pub fn ticks_to_ms(ticks: TickCount) -> u64
+{
+	// NOTE: All architectures report timestamps in milliseconds
+	ticks
+}
+
+
+/// Set once the architecture's timer interrupt (which calls `time_tick`) is bound
+static S_TICK_SOURCE: AtomicBool = AtomicBool::new(false);
+
+/// Called by the architecture code once its timer interrupt is bound (timed wakeups are only fired after this)
+pub fn set_tick_source()
+{
+	S_TICK_SOURCE.store(true, Ordering::SeqCst);
+}
+
+/// Maximum number of outstanding timed wakeups
+const MAX_TIMED_POSTS: usize = 16;
+/// Pending timed wakeups (expiry tick, event to post)
+static S_TIMED_POSTS: ::sync::Spinlock<[Option<(TickCount, &'static ::sync::EventChannel)>; MAX_TIMED_POSTS]> = ::sync::Spinlock::new([None; MAX_TIMED_POSTS]);
+
+/// Post `event` once the tick count reaches `expiry` (replaces any pending wakeup for the same event)
+///
+/// Allows a worker thread to sleep on its event channel until a deadline instead of polling. Returns false
+/// if the wakeup table is full, or if there's no timer interrupt to fire it (the caller should fall back to a
+/// shorter poll).
+pub fn post_at(expiry: TickCount, event: &'static ::sync::EventChannel) -> bool
+{
+	if ! S_TICK_SOURCE.load(Ordering::SeqCst) {
+		return false;
+	}
+	// - Interrupts are held so the timer IRQ can't spin on this CPU's lock
+	let _irq = ::arch::sync::hold_interrupts();
+	let mut lh = S_TIMED_POSTS.lock();
+	if let Some(slot) = lh.iter_mut().find(|s| match **s { Some((_, ev)) => ev as *const _ == event as *const _, None => false }) {
+		*slot = Some( (expiry, event) );
+		return true;
+	}
+	if let Some(slot) = lh.iter_mut().find(|s| s.is_none()) {
+		*slot = Some( (expiry, event) );
+		true
+	}
+	else {
+		false
+	}
+}
+
+/// Cancel a pending wakeup registered by `post_at` (no-op if it has already fired)
+///
+/// Must be called before an event registered with a borrowed lifetime is dropped.
+pub fn cancel_post(event: &::sync::EventChannel)
+{
+	let _irq = ::arch::sync::hold_interrupts();
+	let mut lh = S_TIMED_POSTS.lock();
+	for slot in lh.iter_mut()
+	{
+		if let Some((_, ev)) = *slot {
+			if ev as *const _ == event as *const _ {
+				*slot = None;
+			}
+		}
+	}
+}
+
+/// Called from the architecture's timer interrupt, posts any expired timed wakeups
+//#[tag_safe(irq)]
+pub fn time_tick()
+{
+	let now = ticks();
+	// - If another CPU holds the lock, leave the work for the next tick
+	let mut lh = match S_TIMED_POSTS.try_lock_cpu()
+		{
+		Some(v) => v,
+		None => return,
+		};
+	for slot in lh.iter_mut()
+	{
+		let fire = match *slot
+			{
+			Some((expiry, ev)) if expiry <= now => Some(ev),
+			_ => None,
+			};
+		if let Some(ev) = fire {
+			*slot = None;
+			ev.post();
+		}
+	}
+}
 
 /// Records the current time on construction, and prints the elapsed time with {:?} / {}
 pub struct ElapsedLogger(TickCount);
@@ -50,6 +140,10 @@ impl CacheTimer
 	pub fn bump(&self) {
 		self.0.store(ticks(), ::core::sync::atomic::Ordering::SeqCst)
 	}
+	/// Tick count of the last bump
+	pub fn get(&self) -> TickCount {
+		self.0.load(::core::sync::atomic::Ordering::SeqCst)
+	}
 }
 
 // vim: ft=rust
@@ -14,8 +14,8 @@ use super::Path;
 pub struct Any {
 	node: CacheHandle,
 }
-#[derive(Debug,Clone)]
-/// Normal file
+#[derive(Debug)]
+/// Normal file (holds an open lock on the file, see `FileOpenMode`)
 pub struct File {
 	node: CacheHandle,
 	mode: FileOpenMode,
@@ -139,15 +139,23 @@ impl File
 		if !node.is_file() {
 			return Err(super::Error::TypeMismatch);
 		}
-		match mode
-		{
-		// TODO: Mark file as shared
-		// TODO: Check permissions (must be readable in current context)
-		FileOpenMode::SharedRO => {},
-		// TODO: Mark file as shared
-		// TODO: Check permissions (must be executable in current context)
-		FileOpenMode::Execute => {},
-		_ => todo!("Acquire lock depending on mode({:?})", mode),
+		let locked = match mode
+			{
+			// TODO: Check permissions (must be readable in current context)
+			FileOpenMode::SharedRO => node.lock_shared(),
+			// TODO: Check permissions (must be executable in current context)
+			FileOpenMode::Execute => node.lock_shared(),
+			FileOpenMode::ExclRW => node.lock_exclusive(),
+			// NOTE: With no other opens allowed, this is the same as exclusive access
+			FileOpenMode::Unsynch => node.lock_exclusive(),
+			// TODO: Copy-on-write and append handles
+			FileOpenMode::UniqueRW | FileOpenMode::Append => {
+				log_notice!("File::from_node - Open mode {:?} not supported", mode);
+				return Err(super::Error::Unknown("Unsupported file open mode"));
+				},
+			};
+		if !locked {
+			return Err(super::Error::Locked);
 		}
 		Ok(File { node: node, mode: mode })
 	}
@@ -155,6 +163,15 @@ impl File
 	pub fn size(&self) -> u64 {
 		self.node.get_valid_size()
 	}
+	/// Returns true if the file was opened in a mode that allows writing
+	pub fn is_writable(&self) -> bool {
+		match self.mode
+		{
+		FileOpenMode::SharedRO => false,
+		FileOpenMode::Execute => false,
+		_ => true,
+		}
+	}
 
 	/// Read data from the file at the specified offset
 	///
@@ -164,9 +181,18 @@ impl File
 		assert!(self.node.is_file());
 		self.node.read(ofs, dst)
 	}
+	/// Write data to the file at the specified offset (the file can only be extended by writing at the end)
 	pub fn write(&self, ofs: u64, src: &[u8]) -> super::Result<usize> {
 		assert!(self.node.is_file());
-		todo!("Handle::write({:#x}, {:p}+{}", ofs, src.as_ptr(), src.len());
+		if !self.is_writable() {
+			return Err(super::Error::PermissionDenied);
+		}
+		self.node.write(ofs, src)
+	}
+	/// Ensure that all data written to the file has reached the underlying volume
+	pub fn flush(&self) -> super::Result<()> {
+		assert!(self.node.is_file());
+		self.node.flush()
 	}
 
 	
@@ -257,6 +283,12 @@ impl File
 			MemoryMapMode::WriteBack => ::memory::virt::ProtectionMode::UserRW,
 			})
 			.unwrap();
+		// - Read-only and executable mappings must never be made writable by the user
+		match mode
+		{
+		MemoryMapMode::ReadOnly | MemoryMapMode::Execute => ::memory::virt::lock_user_writes(address as *mut (), page_count),
+		MemoryMapMode::COW | MemoryMapMode::WriteBack => {},
+		}
 		log_debug!("- Mapped at {:p} + {:#x}", address as *mut (), page_count * ::PAGE_SIZE);
 		Ok(MemoryMapHandle {
 			handle: self,
@@ -265,16 +297,18 @@ impl File
 			})
 	}
 }
+impl Clone for File
+{
+	fn clone(&self) -> File {
+		// - The new handle shares this handle's lock
+		self.node.lock_clone();
+		File { node: self.node.clone(), mode: self.mode.clone() }
+	}
+}
 impl ::core::ops::Drop for File
 {
 	fn drop(&mut self) {
-		match self.mode
-		{
-		FileOpenMode::SharedRO => {},
-		FileOpenMode::Execute => {},
-		_ => todo!("File::drop() - mode={:?}", self.mode),
-		}
-		// TODO: For files, we need to release the lock
+		self.node.unlock();
 	}
 }
 
@@ -319,6 +353,11 @@ impl Dir
 		assert!(node.is_dir());
 		Ok( Dir { node: node } )
 	}
+	/// Create a new (empty) file, and open it with the provided mode
+	pub fn create_file(&self, name: &ByteStr, mode: FileOpenMode) -> super::Result<File> {
+		let node = try!(self.node.create(name, NodeType::File));
+		File::from_node(node, mode)
+	}
 	/// Create a new symbolic link
 	pub fn symlink(&self, name: &str, target: &Path) -> super::Result<()> {
 		try!(self.node.create(name.as_ref(), NodeType::Symlink(target)));
@@ -341,6 +380,11 @@ impl Dir
 	pub fn read_ents(&self, pos: usize, ents: &mut super::node::ReadDirCallback) -> super::Result<usize> {
 		self.node.read_dir(pos, ents)
 	}
+
+	/// Mount a volume on this directory
+	pub fn mount(&self, vol: ::metadevs::storage::VolumeHandle, fs: &str) -> Result<(),super::mount::MountError> {
+		super::mount::mount_at(self.node.clone(), vol, fs)
+	}
 }
 
 pub struct DirIter<'a> {
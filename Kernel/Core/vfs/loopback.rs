@@ -0,0 +1,198 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/vfs/loopback.rs
+//! Loopback volumes (exposing a file as a block device)
+#[allow(unused_imports)]
+use prelude::*;
+use core::sync::atomic::{AtomicUsize,Ordering};
+use metadevs::storage::{self,VolumeHandle,VolOpenError};
+use super::handle;
+
+/// Block size of loopback volumes
+pub const BLOCK_SIZE: usize = 512;
+
+static S_NEXT_IDX: AtomicUsize = AtomicUsize::new(0);
+
+/// An attached loopback volume, detached when dropped
+pub struct Loop
+{
+	name: String,
+	reg: Option<storage::PhysicalVolumeReg>,
+}
+
+struct LoopVolume
+{
+	name: String,
+	file: handle::File,
+	read_only: bool,
+	block_count: u64,
+}
+
+/// Attach a file as a new loopback physical volume
+///
+/// The volume is read-only if requested, or if the file wasn't opened for writing. Trailing bytes
+/// (past the last full block) are not accessible.
+pub fn attach(file: handle::File, read_only: bool) -> Loop
+{
+	let name = format!("LOOP{}", S_NEXT_IDX.fetch_add(1, Ordering::Relaxed));
+	let block_count = file.size() / BLOCK_SIZE as u64;
+	let read_only = read_only || !file.is_writable();
+	log_log!("{}: {} blocks, {}{}", name, block_count, storage::SizePrinter(block_count * BLOCK_SIZE as u64),
+		if read_only { " (read-only)" } else { "" });
+	let vol = LoopVolume {
+		name: name.clone(),
+		file: file,
+		read_only: read_only,
+		block_count: block_count,
+		};
+	Loop {
+		name: name,
+		reg: Some( storage::register_pv(Box::new(vol)) ),
+	}
+}
+
+impl Loop
+{
+	/// Physical volume name
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+	/// Names of the logical volumes the mappers found on this volume
+	pub fn volumes(&self) -> Vec<String> {
+		self.reg.as_ref().unwrap().volumes()
+	}
+	/// Open a logical volume on this loopback volume (an empty name selects the first)
+	pub fn open_volume(&self, name: &str) -> Result<VolumeHandle,VolOpenError> {
+		let volumes = self.volumes();
+		let name = if name == "" {
+				match volumes.first()
+				{
+				Some(v) => &v[..],
+				None => return Err(VolOpenError::NotFound),
+				}
+			}
+			else if volumes.iter().any(|v| &v[..] == name) {
+				name
+			}
+			else {
+				return Err(VolOpenError::NotFound);
+			};
+		VolumeHandle::open_named(name)
+	}
+}
+impl ::core::ops::Drop for Loop
+{
+	fn drop(&mut self)
+	{
+		let reg = self.reg.take().unwrap();
+		if reg.is_in_use() {
+			// NOTE: A mounted volume stays in use (there's currently no unmount)
+			log_notice!("{}: Still in use, detaching once its volumes are closed", self.name);
+		}
+		else {
+			log_log!("{}: Detached", self.name);
+		}
+		reg.detach_when_unused();
+	}
+}
+
+impl LoopVolume
+{
+	fn check_range(&self, idx: u64, count: usize, buflen: usize) -> Result<(), storage::IoError> {
+		if buflen != count * BLOCK_SIZE {
+			Err( storage::IoError::InvalidParameter )
+		}
+		else if idx > self.block_count || count as u64 > self.block_count - idx {
+			Err( storage::IoError::BadAddr )
+		}
+		else {
+			Ok( () )
+		}
+	}
+	fn read_int(&self, idx: u64, num: usize, dst: &mut [u8]) -> Result<usize, storage::IoError> {
+		try!(self.check_range(idx, num, dst.len()));
+		let mut ofs = 0;
+		while ofs < dst.len()
+		{
+			match self.file.read(idx * BLOCK_SIZE as u64 + ofs as u64, &mut dst[ofs..])
+			{
+			Ok(0) => {
+				// - File was truncated under us, pad with zeroes
+				for b in dst[ofs..].iter_mut() { *b = 0; }
+				break;
+				},
+			Ok(v) => ofs += v,
+			Err(e) => return Err( map_error(e) ),
+			}
+		}
+		Ok( num )
+	}
+	fn write_int(&self, idx: u64, num: usize, src: &[u8]) -> Result<usize, storage::IoError> {
+		try!(self.check_range(idx, num, src.len()));
+		if self.read_only {
+			return Err( storage::IoError::ReadOnly );
+		}
+		let mut ofs = 0;
+		while ofs < src.len()
+		{
+			match self.file.write(idx * BLOCK_SIZE as u64 + ofs as u64, &src[ofs..])
+			{
+			Ok(0) => return Err( storage::IoError::Unknown("Loopback file write stalled") ),
+			Ok(v) => ofs += v,
+			Err(e) => return Err( map_error(e) ),
+			}
+		}
+		Ok( num )
+	}
+}
+fn map_error(e: super::Error) -> storage::IoError {
+	match e
+	{
+	super::Error::BlockIoError(e) => e,
+	super::Error::ReadOnlyFilesystem => storage::IoError::ReadOnly,
+	e @ _ => {
+		log_notice!("Loopback file error: {:?}", e);
+		storage::IoError::Unknown("Loopback file error")
+		},
+	}
+}
+
+impl storage::PhysicalVolume for LoopVolume
+{
+	fn name(&self) -> &str { &self.name }
+	fn blocksize(&self) -> Result<usize,storage::IoError> { Ok(BLOCK_SIZE) }
+	fn capacity(&self) -> Option<u64> { Some(self.block_count) }
+
+	fn read<'a>(&'a self, _prio: u8, idx: u64, num: usize, dst: &'a mut [u8]) -> storage::AsyncIoResult<'a,usize>
+	{
+		// TODO: Make this async once the VFS supports it
+		let rv = self.read_int(idx, num, dst);
+		Box::new( ::async::NullResultWaiter::new( move || rv ) )
+	}
+	fn write<'a>(&'a self, _prio: u8, idx: u64, num: usize, src: &'a [u8]) -> storage::AsyncIoResult<'a,usize>
+	{
+		let rv = self.write_int(idx, num, src);
+		Box::new( ::async::NullResultWaiter::new( move || rv ) )
+	}
+	fn wipe<'a>(&'a self, idx: u64, num: usize) -> storage::AsyncIoResult<'a,()>
+	{
+		// Wiping is advisory, and files have no way of releasing space
+		let rv = self.check_range(idx, num, num * BLOCK_SIZE)
+			.and_then(|_| if self.read_only { Err(storage::IoError::ReadOnly) } else { Ok( () ) });
+		Box::new( ::async::NullResultWaiter::new( move || rv ) )
+	}
+	fn flush<'a>(&'a self) -> storage::AsyncIoResult<'a,()>
+	{
+		// TODO: Make this async once the VFS supports it
+		let rv = if self.read_only {
+				Ok( () )
+			}
+			else {
+				self.file.flush().map_err(map_error)
+			};
+		Box::new( ::async::NullResultWaiter::new( move || rv ) )
+	}
+}
+
+// vim: ft=rust
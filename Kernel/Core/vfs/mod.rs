@@ -73,6 +73,7 @@ pub use self::path::{Path,PathBuf};
 pub mod node;
 pub mod mount;
 pub mod handle;
+pub mod loopback;
 mod path;
 mod ramfs;
 
@@ -83,7 +84,7 @@ fn init()
 	node::init();
 	ramfs::init();
 	// 2. Start the root/builtin filesystems
-	mount::mount("/".as_ref(), VolumeHandle::new_ramdisk(0), "ramfs", &[]).expect("Unable to mount /");
+	mount::mount("/".as_ref(), VolumeHandle::new_ramdisk(0).expect("Placeholder volume"), "ramfs", &[]).expect("Unable to mount /");
 	// 3. Initialise root filesystem layout
 	let root = match handle::Dir::open( Path::new("/") )
 		{
@@ -80,31 +80,9 @@ pub fn init()
 // TODO: Parse options
 pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) -> Result<(),MountError>
 {
-	let drivers = S_DRIVERS.read();
-	// 1. (maybe) detect filesystem
-	let driver = if fs == "" {
-			match drivers.iter()
-				.filter_map(|(n,fs)| fs.detect(&vol).ok().map(|r| (r, n, fs)))
-				.max_by_key(|&(l,_,_)| l)
-			{
-			Some((0,_,_)) => return Err(MountError::NoHandler),
-			Some((_,_name,fs)) => fs,
-			None => return Err(MountError::NoHandler),
-			}
-		}
-		else {
-			match drivers.get(fs)
-			{
-			Some(d) => d,
-			None => {
-				log_notice!("Filesystem '{}' not registered", fs);
-				return Err(MountError::UnknownFilesystem);
-				},
-			}
-		};
-	
 	if location == Path::new("/")
 	{
+		let driver = try!(get_driver(&vol, fs));
 		let fs: Box<_> = match driver.mount(vol, SelfHandle(0))
 			{
 			Ok(v) => v,
@@ -116,46 +94,86 @@ pub fn mount(location: &Path, vol: VolumeHandle, fs: &str, _options: &[&str]) ->
 			return Err(MountError::MountpointUsed);
 		}
 		*lh = Some(fs);
+		Ok( () )
 	}
 	else
 	{
-		// 2. Acquire mountpoint
+		// Acquire mountpoint
 		let nh = match CacheHandle::from_path(location)
 			{
 			Ok(nh) => nh,
 			Err(_) => return Err(MountError::InvalidMountpoint),
 			};
-		if ! nh.is_dir() {
-			return Err(MountError::InvalidMountpoint);
-		}
-		if nh.is_mountpoint() {
-			return Err(MountError::MountpointUsed);
-		}
-		
-		// 3. Reserve the mountpoint ID (using a placeholder instance)
-		// NOTE: Nothing should know of this index until after mount is completed
-		let vidx = S_VOLUMES.write().insert(MountedVolume { mountpoint_node: nh, fs: Box::new(NullFs) });
+		mount_at(nh, vol, fs)
+	}
+}
 
-		// 4. Mount and register volume
-		let fs = match driver.mount(vol, SelfHandle(vidx))
-			{
-			Ok(v) => v,
-			Err(_) => return Err(MountError::CallFailed),
-			};
+/// Mount a volume on an already opened directory node
+pub fn mount_at(nh: CacheHandle, vol: VolumeHandle, fs: &str) -> Result<(),MountError>
+{
+	// 1. (maybe) detect filesystem
+	let driver = try!(get_driver(&vol, fs));
 
-		// 5. Store and bind to mountpoint
+	// 2. Check mountpoint
+	if ! nh.is_dir() {
+		return Err(MountError::InvalidMountpoint);
+	}
+	if nh.is_mountpoint() {
+		return Err(MountError::MountpointUsed);
+	}
+	
+	// 3. Reserve the mountpoint ID (using a placeholder instance)
+	// NOTE: Nothing should know of this index until after mount is completed
+	let vidx = S_VOLUMES.write().insert(MountedVolume { mountpoint_node: nh, fs: Box::new(NullFs) });
+
+	// 4. Mount and register volume
+	let fs = match driver.mount(vol, SelfHandle(vidx))
 		{
-			let mut lh = S_VOLUMES.write();
-			lh[vidx].fs = fs;
-			if lh[vidx].mountpoint_node.mount(vidx + 1) == false {
-				lh.remove(vidx);
-				return Err(MountError::MountpointUsed);
-			}
+		Ok(v) => v,
+		Err(_) => {
+			S_VOLUMES.write().remove(vidx);
+			return Err(MountError::CallFailed);
+			},
+		};
+
+	// 5. Store and bind to mountpoint
+	{
+		let mut lh = S_VOLUMES.write();
+		lh[vidx].fs = fs;
+		if lh[vidx].mountpoint_node.mount(vidx + 1) == false {
+			lh.remove(vidx);
+			return Err(MountError::MountpointUsed);
 		}
 	}
 
 	Ok( () )
 }
+
+/// Select the filesystem driver for a volume (detecting it if `fs` is empty)
+fn get_driver(vol: &VolumeHandle, fs: &str) -> Result<&'static dyn Driver,MountError>
+{
+	let drivers = S_DRIVERS.read();
+	if fs == "" {
+		match drivers.iter()
+			.filter_map(|(n,fs)| fs.detect(vol).ok().map(|r| (r, n, fs)))
+			.max_by_key(|&(l,_,_)| l)
+		{
+		Some((0,_,_)) => Err(MountError::NoHandler),
+		Some((_,_name,fs)) => Ok(*fs),
+		None => Err(MountError::NoHandler),
+		}
+	}
+	else {
+		match drivers.get(fs)
+		{
+		Some(d) => Ok(*d),
+		None => {
+			log_notice!("Filesystem '{}' not registered", fs);
+			Err(MountError::UnknownFilesystem)
+			},
+		}
+	}
+}
 #[derive(Debug)]
 pub enum MountError
 {
@@ -46,6 +46,10 @@ pub trait File: NodeBase {
 	fn read(&self, ofs: u64, buf: &mut [u8]) -> Result<usize>;
 	/// Write data to the file, can only grow the file if ofs==size
 	fn write(&self, ofs: u64, buf: &[u8]) -> Result<usize>;
+	/// Ensure that written data (and metadata) has reached the underlying volume
+	fn flush(&self) -> Result<()> {
+		Ok( () )
+	}
 }
 
 // TODO: Should this be &ByteStr instead of an iterator?
@@ -101,7 +105,9 @@ pub enum Node
 enum CacheNodeInt
 {
 	File {
-		fsnode: Box<dyn File>
+		fsnode: Box<dyn File>,
+		/// Open lock state: count of shared handles, or of handles to the exclusive lock (with `FILE_LOCK_EXCL` set)
+		open_lock: AtomicUsize,
 		
 		// File memory map data
 		//mapped_pages: HashMap<u64,FrameHandle>,
@@ -122,7 +128,7 @@ impl_from!{
 	From<Node>(v) for CacheNodeInt {
 		match v
 		{
-		Node::File(f) => CacheNodeInt::File { fsnode: f },
+		Node::File(f) => CacheNodeInt::File { fsnode: f, open_lock: AtomicUsize::new(0) },
 		Node::Dir(f) => CacheNodeInt::Dir { fsnode: f, mountpoint: AtomicUsize::new(0) },
 		Node::Symlink(f) => CacheNodeInt::Symlink { target: f.read(), fsnode: f },
 		Node::Special(f) => CacheNodeInt::Special { fsnode: f },
@@ -130,6 +136,9 @@ impl_from!{
 	}
 }
 
+/// `open_lock` flag: The file is held exclusively
+const FILE_LOCK_EXCL: usize = 1 << (::core::mem::size_of::<usize>() * 8 - 1);
+
 struct CachedNode
 {
 	refcount: AtomicUsize,
@@ -406,9 +415,82 @@ impl CacheHandle
 		_ => Err( super::Error::Unknown("Calling read on non-file") ),
 		}
 	}
+	pub fn write(&self, ofs: u64, src: &[u8]) -> super::Result<usize> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { ref fsnode, .. } => Ok( try!(fsnode.write(ofs, src)) ),
+		_ => Err( super::Error::Unknown("Calling write on non-file") ),
+		}
+	}
+	/// Ensure that data written to the file has reached the underlying volume
+	pub fn flush(&self) -> super::Result<()> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { ref fsnode, .. } => Ok( try!(fsnode.flush()) ),
+		_ => Err( super::Error::Unknown("Calling flush on non-file") ),
+		}
+	}
 }
 
 
+/// File open locking
+impl CacheHandle
+{
+	fn file_open_lock(&self) -> Option<&AtomicUsize> {
+		match self.as_ref()
+		{
+		&CacheNodeInt::File { ref open_lock, .. } => Some(open_lock),
+		_ => None,
+		}
+	}
+	/// Acquire a shared (read-only) open lock, fails if the file is held exclusively
+	pub fn lock_shared(&self) -> bool {
+		let l = match self.file_open_lock() { Some(v) => v, None => return false };
+		let mut cur = l.load(atomic::Ordering::Relaxed);
+		loop
+		{
+			if cur & FILE_LOCK_EXCL != 0 {
+				return false;
+			}
+			match l.compare_exchange(cur, cur + 1, atomic::Ordering::Acquire, atomic::Ordering::Relaxed)
+			{
+			Ok(_) => return true,
+			Err(v) => cur = v,
+			}
+		}
+	}
+	/// Acquire an exclusive open lock, fails if the file is open in any other handle
+	pub fn lock_exclusive(&self) -> bool {
+		match self.file_open_lock()
+		{
+		Some(l) => l.compare_exchange(0, FILE_LOCK_EXCL | 1, atomic::Ordering::Acquire, atomic::Ordering::Relaxed).is_ok(),
+		None => false,
+		}
+	}
+	/// Add another reference to an already-held open lock (shared or exclusive, e.g. when cloning a handle)
+	pub fn lock_clone(&self) {
+		if let Some(l) = self.file_open_lock() {
+			l.fetch_add(1, atomic::Ordering::Relaxed);
+		}
+	}
+	/// Release an open lock acquired by one of the above methods
+	pub fn unlock(&self) {
+		if let Some(l) = self.file_open_lock() {
+			let mut cur = l.load(atomic::Ordering::Relaxed);
+			loop
+			{
+				assert!(cur & !FILE_LOCK_EXCL != 0, "CacheHandle::unlock - File not locked");
+				let new = if cur & !FILE_LOCK_EXCL == 1 { 0 } else { cur - 1 };
+				match l.compare_exchange(cur, new, atomic::Ordering::Release, atomic::Ordering::Relaxed)
+				{
+				Ok(_) => break,
+				Err(v) => cur = v,
+				}
+			}
+		}
+	}
+}
+
 /// Symbolic link methods
 impl CacheHandle
 {
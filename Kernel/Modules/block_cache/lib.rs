@@ -10,7 +10,8 @@
 use kernel::prelude::*;
 use kernel::PAGE_SIZE;
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use kernel::metadevs::storage::{VolumeHandle,IoError};
+use kernel::lib::mem::Arc;
+use kernel::metadevs::storage::{VolumeHandle,IoError,IoPriority};
 use kernel::sync::{RwLock,rwlock};
 use kernel::sync::mutex::LazyMutex;
 
@@ -20,15 +21,41 @@ use kernel::sync::mutex::LazyMutex;
 //  > read/write (unbuffered)
 //  > read_inner/get/edit (buffered)
 //
-// - The global cache is registered with the PMM as a source of reclaimable memory
+// - Cache misses are read with `IoPriority::Interactive` (metadata lookups are latency sensitive), and the
+//   flusher writes back with `IoPriority::Bulk`.
+// - Writes to cached blocks are deferred (write-back), blocks are written out by the flusher thread once
+//   they've been dirty for `WRITEBACK_DELAY_MS`, when `flush` is called, or when the handle is dropped.
+// - Clean unreferenced blocks are evicted (LRU) when the cache is full, and are registered with the PMM
+//   as reclaimable memory.
+// - Entries are at least a page in size, filesystems with larger blocks can request larger entries (which are
+//   stored on the heap instead of in the page cache).
 
 #[macro_use]
 extern crate kernel;
 
+/// Maximum number of pages held by the cache (the page cache has 1024 mapping slots, shared with other users)
+const MAX_CACHED_PAGES: usize = 512;
+/// Time a block can stay dirty before the flusher writes it back
+const WRITEBACK_DELAY_MS: u64 = 1000;
+
 /// A handle into the cache corresponding to a logical volume
 pub struct CacheHandle
 {
-	vh: VolumeHandle,
+	vh: Arc<VolumeHandle>,
+	/// Number of volume blocks in each cache entry
+	blocks_per_entry: u64,
+}
+
+/// Per-volume cache statistics
+#[derive(Default,Copy,Clone,Debug)]
+pub struct CacheStats
+{
+	/// Lookups satisfied by an already cached entry
+	pub hits: u64,
+	/// Lookups that required a read from the volume
+	pub misses: u64,
+	/// Entries dropped to make room for others (or reclaimed by the PMM)
+	pub evictions: u64,
 }
 
 /// A handle to a block in the cache
@@ -41,19 +68,45 @@ struct MetaBlockHandle<'a>(&'a CachedBlock);
 struct Cache
 {
 	map: ::kernel::lib::VecMap< (usize, u64), Box<CachedBlock> >,
+	/// Volumes with a live `CacheHandle`, used to write back dirty blocks
+	volumes: ::kernel::lib::VecMap< usize, Volume >,
+	/// Number of pages used by cached blocks
+	page_count: usize,
+}
+
+/// A volume registered with the cache
+struct Volume
+{
+	vh: Arc<VolumeHandle>,
+	stats: CacheStats,
 }
 
 struct CachedBlock
 {
 	// Constant:
 	index: u64,
-	block_paddr: ::kernel::memory::phys::FrameHandle,
+	/// Size of the entry in bytes (a whole number of volume blocks)
+	size: usize,
+	/// Backing frame for page-sized entries (None for entries larger than a page)
+	block_paddr: Option<::kernel::memory::phys::FrameHandle>,
+	page_count: usize,
 
 	reference_count: AtomicUsize,
 	last_access: ::kernel::time::CacheTimer,
 	is_dirty: AtomicBool,
+	/// Time of the first modification since the last write-back
+	dirty_time: ::kernel::time::CacheTimer,
 
-	mapping: RwLock<Option<::kernel::memory::page_cache::CachedPage>>,
+	mapping: RwLock<Option<BlockData>>,
+}
+
+/// Storage for a cached block
+enum BlockData
+{
+	/// A single page, mapped via the page cache
+	Page(::kernel::memory::page_cache::CachedPage),
+	/// Volume blocks larger than a page, stored on the heap
+	Heap(Box<[u8]>),
 }
 
 
@@ -61,22 +114,61 @@ static S_BLOCK_CACHE: LazyMutex<Cache> = LazyMutex::new();
 //static S_BLOCK_CACHE: Mutex<Cache> = Mutex::new(Cache {
 //	map: ::kernel::lib::VecMap::new(),
 //	});
+static S_FLUSHER: LazyMutex<::kernel::threads::WorkerThread> = LazyMutex::new();
+/// Posted when a block becomes dirty (wakes the flusher)
+static S_FLUSHER_EVENT: ::kernel::sync::EventChannel = ::kernel::sync::EventChannel::new();
+/// Set once the flusher thread has been started
+static S_STARTED: AtomicBool = AtomicBool::new(false);
+/// Number of dirty blocks in the cache
+static S_DIRTY_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Lock the global cache (starting the flusher thread on first use)
+fn lock_cache() -> ::kernel::sync::mutex::HeldLazyMutex<'static, Cache>
+{
+	// NOTE: Registered without the cache locked, as the reclaim worker calls `reclaim` with its own list locked
+	if ! S_STARTED.swap(true, Ordering::AcqRel) {
+		S_FLUSHER.init(|| ::kernel::threads::WorkerThread::new("Block Cache Flush", flusher_thread));
+		::kernel::memory::phys::register_reclaim_handler("block_cache", reclaim);
+	}
+	S_BLOCK_CACHE.lock_init(|| Default::default())
+}
 
 impl CacheHandle
 {
 	pub fn new(vol: VolumeHandle) -> CacheHandle
 	{
-		if vol.block_size() > ::kernel::PAGE_SIZE {
-			todo!("Support caching volumes with block sizes > page size");
-		}
+		CacheHandle::with_entry_size(vol, PAGE_SIZE)
+	}
+
+	/// Create a handle that caches the volume in entries of (at least) `size` bytes
+	///
+	/// Entries are always at least a page, and at least a volume block. Filesystems with blocks larger
+	/// than a page should pass their block size so each block is held in a single entry.
+	pub fn with_entry_size(vol: VolumeHandle, size: usize) -> CacheHandle
+	{
+		let size = ::core::cmp::max(size, PAGE_SIZE);
+		let blocks_per_entry = ::core::cmp::max(1, size / vol.block_size()) as u64;
 
+		let vh = Arc::new(vol);
+		lock_cache().volumes.insert(vh.idx(), Volume { vh: vh.clone(), stats: Default::default() });
 		CacheHandle {
-			vh: vol,
+			vh: vh,
+			blocks_per_entry: blocks_per_entry,
 			}
 	}
 
-	pub fn blocks_per_page(&self) -> u64 {
-		(PAGE_SIZE / self.vh.block_size()) as u64
+	/// Number of volume blocks in each cache entry
+	pub fn blocks_per_entry(&self) -> u64 {
+		self.blocks_per_entry
+	}
+	/// Size of each cache entry in bytes
+	fn entry_size(&self) -> usize {
+		self.blocks_per_entry as usize * self.vh.block_size()
+	}
+
+	/// Obtain the cache statistics for this volume
+	pub fn stats(&self) -> CacheStats {
+		lock_cache().volumes.get(&self.vh.idx()).map(|v| v.stats).unwrap_or_default()
 	}
 }
 
@@ -94,28 +186,146 @@ impl CacheHandle
 	}
 	pub fn read_blocks(&self, block: u64, data: &mut [u8]) -> Result<(), IoError>
 	{
+		// Write back any dirty cached copies first, so the read sees them
+		let (first, last) = self.entry_range(block, data.len());
+		let vol = self.vh.idx();
+		try!( write_back(IoPriority::Normal, |v, b| v == vol && first <= b.index && b.index <= last) );
 		self.vh.read_blocks(block, data)
 	}
 	pub fn write_blocks(&self, block: u64, data: &[u8]) -> Result<(), IoError>
 	{
-		self.vh.write_blocks(block, data)
+		// Update any cached copies of the written blocks before writing to the volume
+		// - Taking the mapping write lock waits for any in-progress write-back of the old data, so that can't
+		//   land on the disk after this write. A dirty entry stays dirty (its later write-back includes this data).
+		let bs = self.block_size();
+		let (first, last) = self.entry_range(block, data.len());
+		let mut patched = Vec::new();
+		let mut entry = first;
+		while entry <= last
+		{
+			if let Some(cached_block) = self.lookup(entry)
+			{
+				let ent_end = entry + self.blocks_per_entry;
+				let start = ::core::cmp::max(entry, block);
+				let end = ::core::cmp::min(ent_end, block + (data.len() / bs) as u64);
+				let src = &data[ (start - block) as usize * bs .. (end - block) as usize * bs ];
+				{
+					let mut lh = cached_block.0.mapping.write();
+					let dst = lh.as_mut().expect("CachedBlock mapping is None").data_mut();
+					dst[ (start - entry) as usize * bs ..][.. src.len()].clone_from_slice(src);
+				}
+				patched.push(cached_block);
+			}
+			entry += self.blocks_per_entry;
+		}
+
+		match self.vh.write_blocks(block, data)
+		{
+		Ok(_) => Ok( () ),
+		Err(e) => {
+			// - The cached copies now differ from the disk, leave them for the flusher to retry
+			for b in patched {
+				let _lh = b.0.mapping.write();
+				b.0.mark_dirty();
+			}
+			Err(e)
+			},
+		}
+	}
+
+	/// Write back all dirty blocks for this volume, and flush the volume's write cache
+	///
+	/// Filesystems should call this at consistency points, once it returns all prior writes are on disk.
+	pub fn flush(&self) -> Result<(), IoError>
+	{
+		let vol = self.vh.idx();
+		try!( write_back(IoPriority::Normal, |v, _| v == vol) );
+		self.vh.flush()
+	}
+
+	/// Write back dirty cached copies of `count` blocks starting at `block`, and flush the volume's write cache
+	///
+	/// A consistency point for a single object (e.g. a file's inode), without writing back the rest of the volume.
+	pub fn flush_blocks(&self, block: u64, count: usize) -> Result<(), IoError>
+	{
+		let (first, last) = self.entry_range(block, count * self.block_size());
+		let vol = self.vh.idx();
+		try!( write_back(IoPriority::Normal, |v, b| v == vol && first <= b.index && b.index <= last) );
+		self.vh.flush()
+	}
+
+	/// First and last cache entry indexes covering a range of blocks
+	fn entry_range(&self, block: u64, len: usize) -> (u64, u64) {
+		entry_range(block, len, self.block_size(), self.blocks_per_entry)
 	}
 }
 
+/// First and last cache entry indexes covering `len` bytes starting at `block`
+fn entry_range(block: u64, len: usize, block_size: usize, blocks_per_entry: u64) -> (u64, u64) {
+	let count = ::core::cmp::max(1, len / block_size) as u64;
+	let first = block - block % blocks_per_entry;
+	let last = block + count - 1;
+	(first, last - last % blocks_per_entry)
+}
+
+/// Statistics for the `n`th volume known to the cache, along with the volume's name (for debugging)
+pub fn get_stats(n: usize) -> Option<(String, CacheStats)>
+{
+	lock_cache().volumes.iter().nth(n).map(|(_, v)| (String::from(v.vh.name()), v.stats))
+}
+
 /// Cached accesses
 impl CacheHandle
 {
+	/// Obtain a handle to an entry if it's already in the cache
+	fn lookup(&self, cache_block: u64) -> Option<MetaBlockHandle>
+	{
+		let lh = lock_cache();
+		lh.map.get( &(self.vh.idx(), cache_block) ).map(|v| {
+			// SAFE: 1. The internal data is boxed, 2. The box won't be dropped while a borrow exists.
+			unsafe { ::core::mem::transmute::<MetaBlockHandle, MetaBlockHandle>(v.borrow()) }
+			})
+	}
+
 	fn get_block_meta(&self, block: u64) -> Result<MetaBlockHandle, IoError>
 	{
-		let cache_block = block - block % self.blocks_per_page();
+		let cache_block = block - block % self.blocks_per_entry;
+		{
+			let mut lh = lock_cache();
+			let hit = lh.map.get( &(self.vh.idx(), cache_block) ).map(|v| {
+				// SAFE: 1. The internal data is boxed, 2. The box won't be dropped while a borrow exists.
+				unsafe { ::core::mem::transmute::<MetaBlockHandle, MetaBlockHandle>(v.borrow()) }
+				});
+			if let Some(v) = hit {
+				lh.count(self.vh.idx(), |s| s.hits += 1);
+				return Ok(v);
+			}
+		}
+
+		// Read the block without the cache locked (loopback volumes can recurse into the cache)
+		let new_block = Box::new( try!(CachedBlock::new(&self.vh, cache_block, self.entry_size())) );
+
+		if ! lock_cache().make_room(new_block.page_count) {
+			// - Cache is full of dirty blocks, write them out and try again
+			try!( write_back(IoPriority::Normal, |_, _| true) );
+			if ! lock_cache().make_room(new_block.page_count) {
+				log_notice!("Block cache over capacity, all entries are in use");
+			}
+		}
+
 		let handle = {
-			use kernel::lib::vec_map::Entry;
-			let mut lh = S_BLOCK_CACHE.lock_init(|| Default::default());
-			let handle = match lh.map.entry( (self.vh.idx(), cache_block) )
-				{
-				Entry::Occupied(v) => v.into_mut().borrow(),
-				Entry::Vacant(v) => v.insert( Box::new( try!(CachedBlock::new(&self.vh, cache_block)) ) ).borrow(),
-				};
+			let key = (self.vh.idx(), cache_block);
+			let mut lh = lock_cache();
+			// - Another thread may have loaded the block while it was being read, if so use that copy
+			if lh.map.get(&key).is_none() {
+				lh.page_count += new_block.page_count;
+				lh.map.insert(key, new_block);
+				lh.count(key.0, |s| s.misses += 1);
+			}
+			else {
+				lh.count(key.0, |s| s.hits += 1);
+			}
+			let handle = lh.map.get(&key).unwrap().borrow();
 			// SAFE: 1. The internal data is boxed, 2. The box won't be dropped while a borrow exists.
 			unsafe { ::core::mem::transmute::<MetaBlockHandle, MetaBlockHandle>(handle) }
 			};
@@ -164,13 +374,42 @@ impl CacheHandle
 			Ok( () )
 			})
 	}
+	/// Read a run of blocks via the cache (which can span several cache entries)
+	///
+	/// Unlike `read_blocks`, the blocks are left in the cache for later accesses.
+	pub fn read_cached(&self, block: u64, data: &mut [u8]) -> Result<(), IoError>
+	{
+		let bs = self.block_size();
+		if data.len() % bs != 0 {
+			return Err(IoError::InvalidParameter);
+		}
+		let mut block = block;
+		for dst in data.chunks_mut(self.entry_size())
+		{
+			let cached_block = try!(self.get_block(block));
+			let blk_ofs = (block - cached_block.index()) as usize * bs;
+			// - The chunk may straddle two entries if the run isn't entry-aligned
+			let len = ::core::cmp::min(dst.len(), self.entry_size() - blk_ofs);
+			dst[..len].clone_from_slice( &cached_block.data()[blk_ofs ..][.. len] );
+			if len < dst.len() {
+				let next = try!(self.get_block(cached_block.index() + self.blocks_per_entry));
+				let rem = dst.len() - len;
+				dst[len..].clone_from_slice( &next.data()[.. rem] );
+			}
+			block += (dst.len() / bs) as u64;
+		}
+		Ok( () )
+	}
+
 	/// Edit block
+	///
+	/// The change is written back to the disk later, use `flush` to ensure it has reached the disk.
 	pub fn edit<F: FnOnce(&mut [u8])->R,R>(&self, block: u64, count: usize, f: F) -> Result<R, IoError>
 	{
 		let cached_block = try!(self.get_block_meta(block));
 		let blk_ofs = (block - cached_block.index()) as usize * self.block_size();
 
-		if (block - cached_block.index()) as usize + count > self.blocks_per_page() as usize {
+		if (block - cached_block.index()) + count as u64 > self.blocks_per_entry {
 			return Err(IoError::InvalidParameter);
 		}
 
@@ -178,11 +417,35 @@ impl CacheHandle
 			f( &mut block_data[blk_ofs ..][ .. count * self.block_size()] )
 			});
 
-		try!(cached_block.0.flush(&self.vh));
-
 		Ok( rv )
 	}
 }
+impl ::core::ops::Drop for CacheHandle
+{
+	fn drop(&mut self)
+	{
+		let vol = self.vh.idx();
+		if let Err(e) = self.flush() {
+			// - Don't retry forever, the volume is going away. Drop the unwritten changes so the entries can be purged.
+			log_error!("{}: Error writing back cache on release - {:?}, discarding unwritten blocks", self.name(), e);
+			for (_, block) in dirty_blocks(|v, _| v == vol) {
+				block.0.discard();
+			}
+		}
+		// Remove this volume's entries (the flusher can briefly hold references, wait for those)
+		loop
+		{
+			{
+				let mut lh = lock_cache();
+				if lh.purge_volume(vol) {
+					lh.volumes.remove(&vol);
+					break;
+				}
+			}
+			::kernel::threads::yield_time();
+		}
+	}
+}
 
 fn map_cached_frame(frame: &::kernel::memory::phys::FrameHandle) -> ::kernel::memory::page_cache::CachedPage
 {
@@ -192,44 +455,278 @@ fn map_cached_frame(frame: &::kernel::memory::phys::FrameHandle) -> ::kernel::me
 }
 
 // --------------------------------------------------------------------
+impl Cache
+{
+	/// Update a volume's statistics
+	fn count<F: FnOnce(&mut CacheStats)>(&mut self, vol: usize, f: F)
+	{
+		if let Some(v) = self.volumes.get_mut(&vol) {
+			f(&mut v.stats);
+		}
+	}
+
+	/// Evict clean unreferenced blocks until there's space for `pages` more pages
+	///
+	/// Returns false if the cache is still over capacity
+	fn make_room(&mut self, pages: usize) -> bool
+	{
+		if self.page_count + pages > MAX_CACHED_PAGES {
+			let target = self.page_count + pages - MAX_CACHED_PAGES;
+			self.evict(target);
+		}
+		self.page_count + pages <= MAX_CACHED_PAGES
+	}
+
+	/// Evict (least recently used first) at least `pages` pages of clean unreferenced blocks
+	///
+	/// Returns the number of pages released
+	fn evict(&mut self, pages: usize) -> usize
+	{
+		let mut released = 0;
+		while released < pages
+		{
+			let lru = self.map.iter()
+				.filter(|&(_, b)| b.is_evictable())
+				.min_by_key(|&(_, b)| b.last_access.get())
+				.map(|(k, _)| *k);
+			match lru
+			{
+			Some(key) => {
+				let b = self.map.remove(&key).unwrap();
+				self.page_count -= b.page_count;
+				released += b.page_count;
+				self.count(key.0, |s| s.evictions += 1);
+				},
+			None => break,
+			}
+		}
+		released
+	}
+
+	/// Remove all blocks for a volume, returns false if any were still in use (and were kept)
+	fn purge_volume(&mut self, vol: usize) -> bool
+	{
+		let keys: Vec<(usize,u64)> = self.map.iter()
+			.filter(|&(k, b)| k.0 == vol && b.is_evictable())
+			.map(|(k, _)| *k)
+			.collect();
+		for k in keys
+		{
+			let b = self.map.remove(&k).unwrap();
+			self.page_count -= b.page_count;
+		}
+		! self.map.iter().any(|(k, _)| k.0 == vol)
+	}
+}
+
+/// Write back dirty blocks matching the filter (called with the volume index and block)
+fn write_back<F: Fn(usize, &CachedBlock)->bool>(prio: IoPriority, filter: F) -> Result<(), IoError>
+{
+	if S_DIRTY_COUNT.load(Ordering::Relaxed) == 0 {
+		return Ok( () );
+	}
+	let mut rv = Ok( () );
+	for (vh, block) in dirty_blocks(filter)
+	{
+		if let Err(e) = block.0.flush(&vh, prio) {
+			log_error!("{}: Write-back of block {} failed - {:?}", vh.name(), block.index(), e);
+			rv = Err(e);
+		}
+	}
+	rv
+}
+
+/// Obtain references to the dirty blocks matching the filter (called with the volume index and block)
+///
+/// References are taken with the cache locked, the caller uses them without it (as writes can recurse into the cache)
+fn dirty_blocks<F: Fn(usize, &CachedBlock)->bool>(filter: F) -> Vec<(Arc<VolumeHandle>, MetaBlockHandle<'static>)>
+{
+	let lh = lock_cache();
+	lh.map.iter()
+		.filter(|&(k, b)| b.is_dirty.load(Ordering::Relaxed) && filter(k.0, b))
+		.filter_map(|(k, b)| lh.volumes.get(&k.0).map(|v| {
+			// SAFE: 1. The internal data is boxed, 2. The box won't be dropped while a borrow exists.
+			(v.vh.clone(), unsafe { ::core::mem::transmute::<MetaBlockHandle, MetaBlockHandle<'static>>(b.borrow()) })
+			}))
+		.collect()
+}
+
+/// Tick at which a block dirtied at `dirty_time` should be written back
+fn writeback_due(dirty_time: ::kernel::time::TickCount) -> ::kernel::time::TickCount
+{
+	dirty_time + WRITEBACK_DELAY_MS
+}
+/// Flusher pass at `now` over the dirty blocks' first-modification ticks
+///
+/// Returns the indexes of the blocks to write back, and the tick of the next pass (None if no others are dirty)
+fn writeback_pass(now: ::kernel::time::TickCount, dirty_times: &[::kernel::time::TickCount]) -> (Vec<usize>, Option<::kernel::time::TickCount>)
+{
+	let mut due = Vec::new();
+	let mut next = None;
+	for (i, &t) in dirty_times.iter().enumerate()
+	{
+		let at = writeback_due(t);
+		if at <= now {
+			due.push(i);
+		}
+		else {
+			next = Some(match next { Some(n) if n < at => n, _ => at });
+		}
+	}
+	(due, next)
+}
+
+/// Write back all dirty blocks on all volumes, and flush the volumes
+pub fn sync_all() -> Result<(), IoError>
+{
+	try!( write_back(IoPriority::Normal, |_, _| true) );
+	let volumes: Vec<Arc<VolumeHandle>> = lock_cache().volumes.iter().map(|(_, v)| v.vh.clone()).collect();
+	for v in volumes
+	{
+		try!( v.flush() );
+	}
+	Ok( () )
+}
+
+/// Background write-back thread
+fn flusher_thread()
+{
+	loop
+	{
+		// - Woken when a block is first dirtied, or by the timed post for the next due block
+		S_FLUSHER_EVENT.sleep();
+		if S_DIRTY_COUNT.load(Ordering::Relaxed) == 0 {
+			continue ;
+		}
+		let now = ::kernel::time::ticks();
+		let blocks = dirty_blocks(|_, _| true);
+		let dirty_times: Vec<_> = blocks.iter().map(|&(_, ref b)| b.0.dirty_time.get()).collect();
+		let (due, mut next) = writeback_pass(now, &dirty_times);
+		for i in due
+		{
+			let (ref vh, ref block) = blocks[i];
+			// - Failed blocks stay dirty for a later attempt
+			if let Err(e) = block.0.flush(vh, IoPriority::Bulk) {
+				log_error!("{}: Write-back of block {} failed - {:?}", vh.name(), block.index(), e);
+				// - Back off after an error, instead of immediately retrying the failed blocks
+				let retry = now + WRITEBACK_DELAY_MS;
+				next = Some(match next { Some(n) if n < retry => n, _ => retry });
+			}
+		}
+		drop(blocks);
+		if let Some(t) = next {
+			if ! ::kernel::time::post_at(t, &S_FLUSHER_EVENT) {
+				// - No timer slots free, poll instead
+				::kernel::threads::yield_time();
+				S_FLUSHER_EVENT.post();
+			}
+		}
+	}
+}
+
+/// PMM reclaim handler
+fn reclaim(pages: usize) -> usize
+{
+	lock_cache().evict(pages)
+}
+
+// --------------------------------------------------------------------
+impl BlockData
+{
+	fn data(&self) -> &[u8] {
+		match self
+		{
+		&BlockData::Page(ref p) => p.data(),
+		&BlockData::Heap(ref b) => b,
+		}
+	}
+	fn data_mut(&mut self) -> &mut [u8] {
+		match self
+		{
+		&mut BlockData::Page(ref mut p) => p.data_mut(),
+		&mut BlockData::Heap(ref mut b) => b,
+		}
+	}
+}
+
 impl CachedBlock
 {
-	fn new(vol: &VolumeHandle, first_block: u64) -> Result<CachedBlock, IoError>
+	fn new(vol: &VolumeHandle, first_block: u64, size: usize) -> Result<CachedBlock, IoError>
 	{
-		let mut mapping = try!(::kernel::memory::page_cache::S_PAGE_CACHE.create().map_err(|_| IoError::Unknown("OOM")));
+		let (frame, mut data) = if size <= PAGE_SIZE {
+				let mapping = try!(::kernel::memory::page_cache::S_PAGE_CACHE.create().map_err(|_| IoError::Unknown("OOM")));
+				(Some(mapping.get_frame_handle()), BlockData::Page(mapping))
+			}
+			else {
+				(None, BlockData::Heap(vec![0u8; size].into_boxed_slice()))
+			};
+
+		try!( vol.read_blocks_prio(IoPriority::Interactive, first_block, &mut data.data_mut()[..size]) );
 
-		// TODO: Defer disk read until after the cache entry is created
-		try!( vol.read_blocks(first_block, mapping.data_mut()) );
-		
 		Ok(CachedBlock {
 			index: first_block,
-			block_paddr: mapping.get_frame_handle(),
+			size: size,
+			block_paddr: frame,
+			page_count: (size + PAGE_SIZE - 1) / PAGE_SIZE,
 			reference_count: AtomicUsize::new(0),
 
 			last_access: Default::default(),
 			is_dirty: AtomicBool::new(false),
-			mapping: RwLock::new(Some(mapping)),
+			dirty_time: Default::default(),
+			mapping: RwLock::new(Some(data)),
 			})
 	}
-	
+
+	/// Returns true if the block can be dropped from the cache
+	fn is_evictable(&self) -> bool {
+		self.reference_count.load(Ordering::Acquire) == 0 && !self.is_dirty.load(Ordering::Acquire)
+	}
+
+	/// Flag the block as modified (called with the mapping write-locked)
+	fn mark_dirty(&self) {
+		if ! self.is_dirty.swap(true, Ordering::AcqRel) {
+			self.dirty_time.bump();
+			S_DIRTY_COUNT.fetch_add(1, Ordering::Relaxed);
+			S_FLUSHER_EVENT.post();
+		}
+	}
+
+	/// Drop unwritten modifications (the block's contents no longer match the disk)
+	fn discard(&self) {
+		let _lh = self.mapping.write();
+		if self.is_dirty.swap(false, Ordering::AcqRel) {
+			S_DIRTY_COUNT.fetch_sub(1, Ordering::Relaxed);
+		}
+	}
+
 	/// Write a modified block back to disk
-	fn flush(&self, vol: &VolumeHandle) -> Result<(), IoError>
+	fn flush(&self, vol: &VolumeHandle, prio: IoPriority) -> Result<(), IoError>
 	{
 		let lh = self.mapping.read();
 		if self.is_dirty.swap(false, Ordering::Acquire)
 		{
-			try!( vol.write_blocks(self.index, lh.as_ref().expect("CachedBlock::flush - None mapping").data()) );
+			let data = lh.as_ref().expect("CachedBlock::flush - None mapping").data();
+			match vol.write_blocks_prio(prio, self.index, &data[..self.size])
+			{
+			Ok(_) => { S_DIRTY_COUNT.fetch_sub(1, Ordering::Relaxed); },
+			Err(e) => {
+				// - Still dirty, leave it for a later attempt
+				self.is_dirty.store(true, Ordering::Release);
+				return Err(e);
+				},
+			}
 		}
 		Ok( () )
 	}
-	
+
 	fn borrow(&self) -> MetaBlockHandle {
 
 		if self.mapping.read().is_none()
 		{
 			let mut lh = self.mapping.write();
 			if lh.is_none() {
-				*lh = Some( map_cached_frame(&self.block_paddr) );
+				let frame = self.block_paddr.as_ref().expect("CachedBlock::borrow - Unmapped heap block");
+				*lh = Some( BlockData::Page(map_cached_frame(frame)) );
 			}
 		}
 
@@ -249,8 +746,9 @@ impl<'a> MetaBlockHandle<'a>
 	pub fn edit<F: FnOnce(&mut [u8])->R, R>(&self, f: F) -> R {
 		let mut lh = self.0.mapping.write();
 		let dataptr = lh.as_mut().expect("CachedBlock mapping is None").data_mut();
-		self.0.is_dirty.store(true, Ordering::Relaxed);
-		f(dataptr)
+		let rv = f(dataptr);
+		self.0.mark_dirty();
+		rv
 	}
 
 	pub fn into_ro(self) -> CachedBlockHandle<'a> {
@@ -263,15 +761,8 @@ impl<'a> ::core::ops::Drop for MetaBlockHandle<'a>
 {
 	fn drop(&mut self)
 	{
-		// TODO: Clearing this when the refcount reaches zero will lead to a lot of mapping/unmapping
-		// - Maybe keep a local queue or run a purge every now and then. Using a LRU list could work.
-		/*
-		if self.0.reference_count.fetch_sub(1, Ordering::Release) == 1
-		{
-			let mut wh = self.0.mapping.write();
-			*wh = None;
-		}
-		*/
+		// NOTE: Mappings are kept while the block is cached (released on eviction), to avoid mapping/unmapping churn
+		self.0.reference_count.fetch_sub(1, Ordering::Release);
 	}
 }
 
@@ -306,3 +797,56 @@ impl<'a> Drop for CachedBlockHandle<'a>
 	}
 }
 
+#[test]
+fn entry_range_single()
+{
+	// One block per entry
+	assert_eq!( entry_range(5, 512, 512, 1), (5, 5) );
+	// Zero-length access still covers the first block
+	assert_eq!( entry_range(5, 0, 512, 1), (5, 5) );
+	// Eight 512-byte blocks per 4K entry
+	assert_eq!( entry_range(9, 512, 512, 8), (8, 8) );
+}
+#[test]
+fn entry_range_straddle()
+{
+	// Blocks 6..10 span the entries at 0 and 8
+	assert_eq!( entry_range(6, 4*512, 512, 8), (0, 8) );
+	// Exactly one entry
+	assert_eq!( entry_range(8, 8*512, 512, 8), (8, 8) );
+	// Ends on the first block of the next entry
+	assert_eq!( entry_range(8, 9*512, 512, 8), (8, 16) );
+}
+#[test]
+fn writeback_delay()
+{
+	// Blocks first dirtied at 0, 500, and 1200
+	let mut dirty = vec![0, 500, 1200];
+	// - Nothing is written before its delay has elapsed, the flusher sleeps until the oldest block is due
+	assert_eq!( writeback_pass(999, &dirty), (vec![], Some(WRITEBACK_DELAY_MS)) );
+
+	// Run the flusher, each pass should write exactly the blocks that are due
+	let mut now = 999;
+	let mut written = Vec::new();
+	loop
+	{
+		let (due, next) = writeback_pass(now, &dirty);
+		for &i in due.iter().rev() {
+			written.push( (dirty.remove(i), now) );
+		}
+		match next
+		{
+		Some(t) => { assert!(t > now); now = t; },
+		None => break,
+		}
+	}
+	assert!( dirty.is_empty() );
+	assert_eq!( written, [(0, 1000), (500, 1500), (1200, 2200)] );
+}
+#[test]
+fn writeback_all_due()
+{
+	// A late pass writes everything that's overdue in one go, and doesn't re-arm
+	assert_eq!( writeback_pass(5000, &[0, 2000, 3000]), (vec![0, 1, 2], None) );
+	assert_eq!( writeback_pass(5000, &[]), (vec![], None) );
+}
@@ -186,5 +186,9 @@ impl vfs::node::File for File
 			Ok( written )
 		}
 	}
+	fn flush(&self) -> vfs::Result<()> {
+		// - Data blocks are written through the block cache, so only the inode needs writing back
+		self.inode.flush()
+	}
 }
 
@@ -36,13 +36,15 @@ impl Inode
 	}
 
 
+	/// Write back the inode (if modified), and ensure it and previously written data reach the disk
 	pub fn flush(&self) -> vfs::Result<()>
 	{
 		if self.is_dirty.swap(false, Ordering::Relaxed)
 		{
 			try!(self.fs.write_inode(self.inode_idx, &self.ondisk));
 		}
-		Ok( () )
+		// - Only this inode's block is written back, not all of the filesystem's cached metadata
+		self.fs.sync_inode(self.inode_idx)
 	}
 }
 
@@ -169,7 +169,8 @@ impl Instance
 			superblock: superblock,
 			group_descriptors: group_descs,
 			mount_handle: mount_handle,
-			vol: ::block_cache::CacheHandle::new(vol),
+			// - Each cache entry holds at least one filesystem block
+			vol: ::block_cache::CacheHandle::with_entry_size(vol, fs_block_size),
 			};
 
 		// SAFE: Boxed instantly
@@ -221,7 +222,7 @@ impl InstanceInner
 }
 
 /// Structure representing a view into a BlockCache entry
-pub struct Block<'a>(::block_cache::CachedBlockHandle<'a>, u16,u16);
+pub struct Block<'a>(::block_cache::CachedBlockHandle<'a>, usize,usize);
 impl<'a> ::core::ops::Deref for Block<'a>
 {
 	type Target = [u32];
@@ -229,10 +230,10 @@ impl<'a> ::core::ops::Deref for Block<'a>
 		let &Block(ref handle, ofs, size) = self;
 		// SAFE: Alignment should be good (but is checked anyway)
 		unsafe {
-			assert!(ofs as usize + size as usize <= handle.data().len());
+			assert!(ofs + size <= handle.data().len());
 			assert!(ofs % 4 == 0);
 			assert!(&handle.data()[0] as *const _ as usize % 4 == 0);
-			::core::slice::from_raw_parts(&handle.data()[ofs as usize] as *const u8 as *const u32, (size / 4) as usize)
+			::core::slice::from_raw_parts(&handle.data()[ofs] as *const u8 as *const u32, size / 4)
 		}
 	}
 }
@@ -242,17 +243,12 @@ impl InstanceInner
 	/// Obtain a block (using the block cache)
 	pub fn get_block(&self, block: u32) -> vfs::node::Result<Block>
 	{
-		if self.fs_block_size > ::kernel::PAGE_SIZE {
-			// TODO: To handle extN blocks larger than the system's page size, we'd need to start packing multiple cache handles into
-			//       the `Block` structure
-			todo!("Handle extN block sizes > PAGE_SIZE - {} > {}", self.fs_block_size, ::kernel::PAGE_SIZE);
-		}
 		log_trace!("get_block({})", block);
 		let sector = block as u64 * self.vol_blocks_per_fs_block();
 
 		let ch = try!(self.vol.get_block(sector));
 		let ofs = (sector - ch.index()) as usize * self.vol.block_size();
-		Ok( Block(ch, ofs as u16, self.fs_block_size as u16) )
+		Ok( Block(ch, ofs, self.fs_block_size) )
 	}
 
 	/// Edit a block in the cache using the provided closure
@@ -260,11 +256,6 @@ impl InstanceInner
 	where
 		F: FnOnce(&mut [u32]) -> vfs::node::Result<R>
 	{
-		if self.fs_block_size > ::kernel::PAGE_SIZE {
-			// TODO: To handle extN blocks larger than the system's page size, we'd need to start packing multiple cache handles into
-			//       the `Block` structure
-			todo!("Handle extN block sizes > PAGE_SIZE - {} > {}", self.fs_block_size, ::kernel::PAGE_SIZE);
-		}
 		log_trace!("get_block({})", block);
 		let sector = block as u64 * self.vol_blocks_per_fs_block();
 
@@ -300,10 +291,18 @@ impl InstanceInner
 	/// Write a sequence of blocks from a user-provided buffer
 	pub fn write_blocks(&self, first_block: u32, data: &[u8]) -> vfs::node::Result<()>
 	{
-		// TODO: Requires maybe interfacing with the cache used by get_block?
+		// NOTE: The block cache updates any cached copies of these blocks
 		try!( self.vol.write_blocks( first_block as u64 * self.vol_blocks_per_fs_block(), data) );
 		Ok( () )
 	}
+
+	/// Write back the cached block holding an inode, and flush the volume (a consistency point for that inode)
+	pub fn sync_inode(&self, inode_num: u32) -> vfs::node::Result<()>
+	{
+		let (vol_block, _) = self.get_inode_pos(inode_num);
+		try!( self.vol.flush_blocks(vol_block, 1) );
+		Ok( () )
+	}
 }
 
 /// Inode lookup and save
@@ -15,7 +15,6 @@ use kernel::lib::mem::aref::{ArefInner,ArefBorrow};
 use kernel::lib::mem::Arc;
 
 extern crate utf16;
-extern crate blockcache;
 extern crate block_cache;
 
 module_define!{FS_FAT, [VFS], init}
@@ -73,11 +72,6 @@ pub struct FilesystemInner
 	
 	root_first_cluster: u32,
 	root_sector_count: u32,
-	
-	//fat_cache: vfs::Cache<[u32; FAT_CACHE_BLOCK_SIZE]>,
-	// XXX: Should really use the above line for this, but BlockCache exists
-	/// A cache of metadata clusters (i.e. directories)
-	metadata_block_cache: ::blockcache::BlockCache,
 }
 
 /// Inodes IDs destrucure into two 28-bit cluster IDs, and a 16-bit dir offset
@@ -194,8 +188,6 @@ impl mount::Driver for Driver
 					_ => FATL_ROOT_CLUSTER as u32,
 					},
 				root_sector_count: root_dir_sectors as u32,
-				
-				metadata_block_cache: ::blockcache::BlockCache::new(),
 
 				vh: vol,
 				}) },
@@ -207,47 +199,42 @@ type Cluster = Arc<[u8]>;
 
 impl FilesystemInner
 {
-	/// Load a cluster from disk
-	fn read_cluster(&self, cluster: u32, dst: &mut [u8]) -> Result<(), storage::IoError> {
-		assert_eq!(dst.len(), self.cluster_size);
-		self.read_clusters(cluster, dst)
-	}
+	/// Load a run of clusters from disk (uncached)
 	fn read_clusters(&self, cluster: u32, dst: &mut [u8]) -> Result<(), storage::IoError> {
 		log_trace!("Filesystem::read_clusters({:#x}, {})", cluster, dst.len() / self.cluster_size);
 		assert_eq!(dst.len() % self.cluster_size, 0);
-		// For now, just read the bytes, screw caching
-		let sector = if !is!(self.ty, Size::Fat32) && cluster >= FATL_ROOT_CLUSTER {
-				// Root directory (for FAT12/16, where it was not a normal file)
-				let rc = cluster - FATL_ROOT_CLUSTER;
-				assert!( (rc as u64 * self.spc as u64) < self.root_sector_count as u64);
-				(self.first_data_sector - self.root_sector_count as usize) as u64
-				+ (rc * self.spc as u32) as u64
-			}
-			else {
-				// Anything else
-				assert!(cluster >= 2);
-				assert!(cluster - 2 < self.cluster_count as u32);
-				self.first_data_sector as u64 + (cluster as u64 - 2) * self.spc as u64
-			};
+		let sector = self.cluster_to_sector(cluster);
 		log_debug!("read_clusters: cluster = {:#x}, sector = 0x{:x}", cluster, sector);
 		try!(self.vh.read_blocks(sector, dst));
 		//::kernel::logging::hex_dump("FAT Cluster", &buf);
 		Ok( () )
 	}
 
-	// TODO: Locking/Cache
+	/// Obtain the first sector of a cluster
+	fn cluster_to_sector(&self, cluster: u32) -> u64 {
+		if !is!(self.ty, Size::Fat32) && cluster >= FATL_ROOT_CLUSTER {
+			// Root directory (for FAT12/16, where it was not a normal file)
+			let rc = cluster - FATL_ROOT_CLUSTER;
+			assert!( (rc as u64 * self.spc as u64) < self.root_sector_count as u64);
+			(self.first_data_sector - self.root_sector_count as usize) as u64
+				+ (rc * self.spc as u32) as u64
+		}
+		else {
+			// Anything else
+			assert!(cluster >= 2);
+			assert!(cluster - 2 < self.cluster_count as u32);
+			self.first_data_sector as u64 + (cluster as u64 - 2) * self.spc as u64
+		}
+	}
+
+	// TODO: Locking
 	// - Should this function lock the cluster somehow to prevent accidental overlap?
-	// - Could also cache somehow (with a refcount) along with the 'writing' flag
+	/// Load a cluster via the block cache (used for directories and partial file reads)
 	fn load_cluster(&self, cluster: u32) -> Result<Cluster, storage::IoError>
 	{
-		self.metadata_block_cache.get(
-			cluster,
-			|_| {
-				log_debug!("load_cluster: miss {}", cluster);
-				let mut buf: Cluster = Arc::from_iter( (0..self.spc * self.vh.block_size()).map(|_| 0) );
-				try!(self.read_cluster( cluster, Arc::get_mut(&mut buf).unwrap() ));
-				Ok( buf )
-			})
+		let mut buf: Cluster = Arc::from_iter( (0..self.cluster_size).map(|_| 0) );
+		try!(self.vh.read_cached( self.cluster_to_sector(cluster), Arc::get_mut(&mut buf).unwrap() ));
+		Ok( buf )
 	}
 	
 	/// Obtain the next cluster in a chain
@@ -103,7 +103,8 @@ impl mount::Driver for Driver
 	
 	
 		let mut inner = InstanceInner {
-			vh: ::block_cache::CacheHandle::new(vol),
+			// - Cache in units of at least a logical block, so a sector is never split across entries
+			vh: ::block_cache::CacheHandle::with_entry_size(vol, lb_size as usize),
 			lb_size: lb_size as usize,
 			root_lba: root_lba,
 			root_size: root_size,
@@ -176,11 +177,11 @@ impl mount::Filesystem for Instance
 		}
 	}
 }
-struct Sector<'a>(::block_cache::CachedBlockHandle<'a>,u16,u16);
+struct Sector<'a>(::block_cache::CachedBlockHandle<'a>,usize,usize);
 impl<'a> ::core::ops::Deref for Sector<'a> {
 	type Target = [u8];
 	fn deref(&self) -> &[u8] {
-		&self.0.data()[self.1 as usize ..][.. self.2 as usize]
+		&self.0.data()[self.1 ..][.. self.2]
 	}
 }
 impl InstanceInner
@@ -199,9 +200,10 @@ impl InstanceInner
 	fn get_sector(&self, sector: u32) -> Result<Sector, storage::IoError> {
 		assert!(sector > 0);
 		
-		let blk = try!(self.vh.get_block(sector as u64));
-		let ofs = (sector as u64 - blk.index()) as usize * self.vh.block_size();
-		Ok( Sector(blk, ofs as u16, self.vh.block_size() as u16) )
+		let hwsector = sector as u64 * (self.lb_size / self.vh.block_size()) as u64;
+		let blk = try!(self.vh.get_block(hwsector));
+		let ofs = (hwsector - blk.index()) as usize * self.vh.block_size();
+		Ok( Sector(blk, ofs, self.lb_size) )
 	}
 }
 
@@ -19,7 +19,18 @@ impl Instance
 	pub fn release_key(&self, key: KeyCode) {
 		super::get_channel_by_index(0).handle_key(key, true);
 	}
+	/// Current lock key state, as a bitmap of `LED_*` values
+	///
+	/// Drivers should check this after passing on key events, and update the keyboard's LEDs if it changed
+	pub fn leds(&self) -> u8 {
+		super::get_channel_by_index(0).leds()
+	}
 }
 
+// Lock key LED bits (matching the HID boot keyboard output report)
+pub const LED_NUM_LOCK: u8 = 1 << 0;
+pub const LED_CAPS_LOCK: u8 = 1 << 1;
+pub const LED_SCROLL_LOCK: u8 = 1 << 2;
+
 include!("../../../../keycodes.inc.rs");
 
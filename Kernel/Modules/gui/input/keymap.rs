@@ -0,0 +1,381 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Core/gui/input/keymap.rs
+//! Keyboard layouts (translation of key codes into text)
+//!
+//! Each layout lists the keys that differ from the common Latin letter mapping, with up to four levels per key
+//! (base, shift, AltGr, shift+AltGr). Dead keys are combined with the next character typed.
+use super::keyboard::KeyCode;
+use self::Sym::{Char as C, Dead as D};
+
+/// Output of a key at a given level
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Sym
+{
+	None,
+	Char(char),
+	Dead(DeadKey),
+}
+const NO: Sym = Sym::None;
+
+/// Accent applied to the next character typed
+#[derive(Copy,Clone,Debug,PartialEq)]
+#[repr(u8)]
+pub enum DeadKey
+{
+	Grave = 1,
+	Acute,
+	Circumflex,
+	Tilde,
+	Diaeresis,
+}
+
+pub struct KeyEntry
+{
+	key: KeyCode,
+	/// Caps Lock swaps the base and shift levels
+	caps: bool,
+	/// Base, Shift, AltGr, Shift+AltGr
+	levels: [Sym; 4],
+}
+
+pub struct Keymap
+{
+	pub name: &'static str,
+	/// The right Alt key is AltGr (selecting the third and fourth levels) instead of Alt
+	pub has_altgr: bool,
+	/// Keys that differ from `LATIN_LETTERS`
+	keys: &'static [KeyEntry],
+}
+
+/// All available layouts, the first is the default
+pub static KEYMAPS: [&'static Keymap; 4] = [&US, &UK, &DE, &FR];
+
+impl Keymap
+{
+	fn lookup(&self, key: KeyCode) -> Option<&'static KeyEntry>
+	{
+		self.keys.iter().chain(LATIN_LETTERS.iter()).find(|e| e.key == key)
+	}
+	/// Translate a key press given the current modifier and lock state
+	pub fn translate(&self, key: KeyCode, shift: bool, altgr: bool, caps_lock: bool) -> Sym
+	{
+		match self.lookup(key)
+		{
+		Some(e) => {
+			let shift = if e.caps && caps_lock { !shift } else { shift };
+			e.levels[ if altgr { 2 } else { 0 } + if shift { 1 } else { 0 } ]
+			},
+		None => Sym::None,
+		}
+	}
+}
+
+impl DeadKey
+{
+	pub fn from_u8(v: u8) -> Option<DeadKey>
+	{
+		match v
+		{
+		1 => Some(DeadKey::Grave),
+		2 => Some(DeadKey::Acute),
+		3 => Some(DeadKey::Circumflex),
+		4 => Some(DeadKey::Tilde),
+		5 => Some(DeadKey::Diaeresis),
+		_ => None,
+		}
+	}
+	/// Character produced when the accent isn't combined with anything (e.g. followed by space)
+	pub fn spacing(&self) -> char
+	{
+		match *self
+		{
+		DeadKey::Grave => '`',
+		DeadKey::Acute => '´',
+		DeadKey::Circumflex => '^',
+		DeadKey::Tilde => '~',
+		DeadKey::Diaeresis => '¨',
+		}
+	}
+	/// Apply the accent to a character
+	pub fn compose(&self, c: char) -> Option<char>
+	{
+		let (bases, accented) = match *self
+			{
+			DeadKey::Grave      => ("aeiouAEIOU", "àèìòùÀÈÌÒÙ"),
+			DeadKey::Acute      => ("aeiouyAEIOUY", "áéíóúýÁÉÍÓÚÝ"),
+			DeadKey::Circumflex => ("aeiouAEIOU", "âêîôûÂÊÎÔÛ"),
+			DeadKey::Tilde      => ("anoANO", "ãñõÃÑÕ"),
+			DeadKey::Diaeresis  => ("aeiouyAEIOUY", "äëïöüÿÄËÏÖÜŸ"),
+			};
+		let i = bases.chars().position(|v| v == c)?;
+		accented.chars().nth(i)
+	}
+}
+
+/// Combine a translated key with the pending dead key
+///
+/// Returns the new pending dead key, and the characters (up to two) to emit
+pub fn combine(pending: Option<DeadKey>, sym: Sym) -> (Option<DeadKey>, [Option<char>; 2])
+{
+	match sym
+	{
+	Sym::None => (pending, [None, None]),
+	Sym::Dead(d) =>
+		match pending
+		{
+		// Pressing a dead key twice produces the accent itself
+		Some(p) if p == d => (None, [Some(d.spacing()), None]),
+		Some(p) => (Some(d), [Some(p.spacing()), None]),
+		None => (Some(d), [None, None]),
+		},
+	Sym::Char(c) =>
+		match pending
+		{
+		Some(p) =>
+			match p.compose(c)
+			{
+			Some(v) => (None, [Some(v), None]),
+			// - Space just produces the accent
+			None if c == ' ' => (None, [Some(p.spacing()), None]),
+			None => (None, [Some(p.spacing()), Some(c)]),
+			},
+		None => (None, [Some(c), None]),
+		},
+	}
+}
+
+/// Letter, affected by Caps Lock
+const fn alpha(key: KeyCode, lower: char, upper: char) -> KeyEntry {
+	KeyEntry { key: key, caps: true, levels: [C(lower), C(upper), NO, NO] }
+}
+/// Key with base and shifted symbols
+const fn sym(key: KeyCode, base: Sym, shift: Sym) -> KeyEntry {
+	KeyEntry { key: key, caps: false, levels: [base, shift, NO, NO] }
+}
+/// Key with AltGr symbols
+const fn full(key: KeyCode, caps: bool, levels: [Sym; 4]) -> KeyEntry {
+	KeyEntry { key: key, caps: caps, levels: levels }
+}
+
+static LATIN_LETTERS: [KeyEntry; 26] = [
+	alpha(KeyCode::A, 'a', 'A'), alpha(KeyCode::B, 'b', 'B'), alpha(KeyCode::C, 'c', 'C'), alpha(KeyCode::D, 'd', 'D'),
+	alpha(KeyCode::E, 'e', 'E'), alpha(KeyCode::F, 'f', 'F'), alpha(KeyCode::G, 'g', 'G'), alpha(KeyCode::H, 'h', 'H'),
+	alpha(KeyCode::I, 'i', 'I'), alpha(KeyCode::J, 'j', 'J'), alpha(KeyCode::K, 'k', 'K'), alpha(KeyCode::L, 'l', 'L'),
+	alpha(KeyCode::M, 'm', 'M'), alpha(KeyCode::N, 'n', 'N'), alpha(KeyCode::O, 'o', 'O'), alpha(KeyCode::P, 'p', 'P'),
+	alpha(KeyCode::Q, 'q', 'Q'), alpha(KeyCode::R, 'r', 'R'), alpha(KeyCode::S, 's', 'S'), alpha(KeyCode::T, 't', 'T'),
+	alpha(KeyCode::U, 'u', 'U'), alpha(KeyCode::V, 'v', 'V'), alpha(KeyCode::W, 'w', 'W'), alpha(KeyCode::X, 'x', 'X'),
+	alpha(KeyCode::Y, 'y', 'Y'), alpha(KeyCode::Z, 'z', 'Z'),
+	];
+
+/// United States (ANSI)
+static US: Keymap = Keymap {
+	name: "us",
+	has_altgr: false,
+	keys: &[
+		sym(KeyCode::Kb1, C('1'), C('!')),
+		sym(KeyCode::Kb2, C('2'), C('@')),
+		sym(KeyCode::Kb3, C('3'), C('#')),
+		sym(KeyCode::Kb4, C('4'), C('$')),
+		sym(KeyCode::Kb5, C('5'), C('%')),
+		sym(KeyCode::Kb6, C('6'), C('^')),
+		sym(KeyCode::Kb7, C('7'), C('&')),
+		sym(KeyCode::Kb8, C('8'), C('*')),
+		sym(KeyCode::Kb9, C('9'), C('(')),
+		sym(KeyCode::Kb0, C('0'), C(')')),
+		sym(KeyCode::Minus,  C('-'), C('_')),
+		sym(KeyCode::Equals, C('='), C('+')),
+		sym(KeyCode::SquareOpen,  C('['), C('{')),
+		sym(KeyCode::SquareClose, C(']'), C('}')),
+		sym(KeyCode::Backslash, C('\\'), C('|')),
+		sym(KeyCode::HashTilde, C('\\'), C('|')),
+		sym(KeyCode::Semicolon, C(';'), C(':')),
+		sym(KeyCode::Quote,     C('\''), C('"')),
+		sym(KeyCode::GraveTilde, C('`'), C('~')),
+		sym(KeyCode::Comma,  C(','), C('<')),
+		sym(KeyCode::Period, C('.'), C('>')),
+		sym(KeyCode::Slash,  C('/'), C('?')),
+		sym(KeyCode::NonUSBackslash, C('\\'), C('|')),
+		],
+	};
+
+/// United Kingdom
+static UK: Keymap = Keymap {
+	name: "uk",
+	has_altgr: true,
+	keys: &[
+		sym(KeyCode::Kb1, C('1'), C('!')),
+		sym(KeyCode::Kb2, C('2'), C('"')),
+		sym(KeyCode::Kb3, C('3'), C('£')),
+		full(KeyCode::Kb4, false, [C('4'), C('$'), C('€'), NO]),
+		sym(KeyCode::Kb5, C('5'), C('%')),
+		sym(KeyCode::Kb6, C('6'), C('^')),
+		sym(KeyCode::Kb7, C('7'), C('&')),
+		sym(KeyCode::Kb8, C('8'), C('*')),
+		sym(KeyCode::Kb9, C('9'), C('(')),
+		sym(KeyCode::Kb0, C('0'), C(')')),
+		sym(KeyCode::Minus,  C('-'), C('_')),
+		sym(KeyCode::Equals, C('='), C('+')),
+		sym(KeyCode::SquareOpen,  C('['), C('{')),
+		sym(KeyCode::SquareClose, C(']'), C('}')),
+		sym(KeyCode::Backslash, C('#'), C('~')),
+		sym(KeyCode::HashTilde, C('#'), C('~')),
+		sym(KeyCode::Semicolon, C(';'), C(':')),
+		sym(KeyCode::Quote,     C('\''), C('@')),
+		full(KeyCode::GraveTilde, false, [C('`'), C('¬'), C('¦'), NO]),
+		sym(KeyCode::Comma,  C(','), C('<')),
+		sym(KeyCode::Period, C('.'), C('>')),
+		sym(KeyCode::Slash,  C('/'), C('?')),
+		sym(KeyCode::NonUSBackslash, C('\\'), C('|')),
+		full(KeyCode::A, true, [C('a'), C('A'), C('á'), C('Á')]),
+		full(KeyCode::E, true, [C('e'), C('E'), C('é'), C('É')]),
+		full(KeyCode::I, true, [C('i'), C('I'), C('í'), C('Í')]),
+		full(KeyCode::O, true, [C('o'), C('O'), C('ó'), C('Ó')]),
+		full(KeyCode::U, true, [C('u'), C('U'), C('ú'), C('Ú')]),
+		],
+	};
+
+/// German (QWERTZ)
+static DE: Keymap = Keymap {
+	name: "de",
+	has_altgr: true,
+	keys: &[
+		sym(KeyCode::Kb1, C('1'), C('!')),
+		full(KeyCode::Kb2, false, [C('2'), C('"'), C('²'), NO]),
+		full(KeyCode::Kb3, false, [C('3'), C('§'), C('³'), NO]),
+		sym(KeyCode::Kb4, C('4'), C('$')),
+		sym(KeyCode::Kb5, C('5'), C('%')),
+		sym(KeyCode::Kb6, C('6'), C('&')),
+		full(KeyCode::Kb7, false, [C('7'), C('/'), C('{'), NO]),
+		full(KeyCode::Kb8, false, [C('8'), C('('), C('['), NO]),
+		full(KeyCode::Kb9, false, [C('9'), C(')'), C(']'), NO]),
+		full(KeyCode::Kb0, false, [C('0'), C('='), C('}'), NO]),
+		full(KeyCode::Minus, false, [C('ß'), C('?'), C('\\'), NO]),
+		sym(KeyCode::Equals, D(DeadKey::Acute), D(DeadKey::Grave)),
+		alpha(KeyCode::SquareOpen, 'ü', 'Ü'),
+		full(KeyCode::SquareClose, false, [C('+'), C('*'), C('~'), NO]),
+		sym(KeyCode::Backslash, C('#'), C('\'')),
+		sym(KeyCode::HashTilde, C('#'), C('\'')),
+		alpha(KeyCode::Semicolon, 'ö', 'Ö'),
+		alpha(KeyCode::Quote,     'ä', 'Ä'),
+		sym(KeyCode::GraveTilde, D(DeadKey::Circumflex), C('°')),
+		sym(KeyCode::Comma,  C(','), C(';')),
+		sym(KeyCode::Period, C('.'), C(':')),
+		sym(KeyCode::Slash,  C('-'), C('_')),
+		full(KeyCode::NonUSBackslash, false, [C('<'), C('>'), C('|'), NO]),
+		alpha(KeyCode::Y, 'z', 'Z'),
+		alpha(KeyCode::Z, 'y', 'Y'),
+		full(KeyCode::Q, true, [C('q'), C('Q'), C('@'), NO]),
+		full(KeyCode::E, true, [C('e'), C('E'), C('€'), NO]),
+		full(KeyCode::M, true, [C('m'), C('M'), C('µ'), NO]),
+		],
+	};
+
+/// French (AZERTY)
+static FR: Keymap = Keymap {
+	name: "fr",
+	has_altgr: true,
+	keys: &[
+		// - The number row is shifted (so Caps Lock selects the digits)
+		full(KeyCode::Kb1, true, [C('&'), C('1'), NO, NO]),
+		full(KeyCode::Kb2, true, [C('é'), C('2'), D(DeadKey::Tilde), NO]),
+		full(KeyCode::Kb3, true, [C('"'), C('3'), C('#'), NO]),
+		full(KeyCode::Kb4, true, [C('\''), C('4'), C('{'), NO]),
+		full(KeyCode::Kb5, true, [C('('), C('5'), C('['), NO]),
+		full(KeyCode::Kb6, true, [C('-'), C('6'), C('|'), NO]),
+		full(KeyCode::Kb7, true, [C('è'), C('7'), D(DeadKey::Grave), NO]),
+		full(KeyCode::Kb8, true, [C('_'), C('8'), C('\\'), NO]),
+		full(KeyCode::Kb9, true, [C('ç'), C('9'), C('^'), NO]),
+		full(KeyCode::Kb0, true, [C('à'), C('0'), C('@'), NO]),
+		full(KeyCode::Minus,  false, [C(')'), C('°'), C(']'), NO]),
+		full(KeyCode::Equals, false, [C('='), C('+'), C('}'), NO]),
+		sym(KeyCode::SquareOpen, D(DeadKey::Circumflex), D(DeadKey::Diaeresis)),
+		full(KeyCode::SquareClose, false, [C('$'), C('£'), C('¤'), NO]),
+		sym(KeyCode::Backslash, C('*'), C('µ')),
+		sym(KeyCode::HashTilde, C('*'), C('µ')),
+		alpha(KeyCode::Semicolon, 'm', 'M'),
+		sym(KeyCode::Quote, C('ù'), C('%')),
+		sym(KeyCode::GraveTilde, C('²'), NO),
+		sym(KeyCode::M,      C(','), C('?')),
+		sym(KeyCode::Comma,  C(';'), C('.')),
+		sym(KeyCode::Period, C(':'), C('/')),
+		sym(KeyCode::Slash,  C('!'), C('§')),
+		sym(KeyCode::NonUSBackslash, C('<'), C('>')),
+		alpha(KeyCode::A, 'q', 'Q'),
+		alpha(KeyCode::Q, 'a', 'A'),
+		alpha(KeyCode::W, 'z', 'Z'),
+		alpha(KeyCode::Z, 'w', 'W'),
+		full(KeyCode::E, true, [C('e'), C('E'), C('€'), NO]),
+		],
+	};
+
+#[test]
+fn translate_levels()
+{
+	// Letters follow Caps Lock, other keys ignore it
+	assert_eq!( US.translate(KeyCode::A, false, false, false), C('a') );
+	assert_eq!( US.translate(KeyCode::A, true , false, false), C('A') );
+	assert_eq!( US.translate(KeyCode::A, false, false, true ), C('A') );
+	assert_eq!( US.translate(KeyCode::A, true , false, true ), C('a') );
+	assert_eq!( US.translate(KeyCode::Kb1, false, false, true), C('1') );
+	assert_eq!( US.translate(KeyCode::Kb1, true , false, false), C('!') );
+	// Keys without a mapping
+	assert_eq!( US.translate(KeyCode::F1, false, false, false), Sym::None );
+	assert_eq!( US.translate(KeyCode::B, false, true, false), Sym::None );
+
+	// AltGr levels
+	assert_eq!( UK.translate(KeyCode::Kb4, false, true, false), C('€') );
+	assert_eq!( UK.translate(KeyCode::A, true, true, false), C('Á') );
+	assert_eq!( UK.translate(KeyCode::A, false, true, true), C('Á') );
+	assert_eq!( DE.translate(KeyCode::Q, false, true, false), C('@') );
+}
+#[test]
+fn translate_layouts()
+{
+	// Layout entries override the common letters
+	assert_eq!( DE.translate(KeyCode::Y, false, false, false), C('z') );
+	assert_eq!( DE.translate(KeyCode::Z, true, false, false), C('Y') );
+	assert_eq!( DE.translate(KeyCode::SquareOpen, false, false, true), C('Ü') );
+	assert_eq!( FR.translate(KeyCode::A, false, false, false), C('q') );
+	assert_eq!( FR.translate(KeyCode::M, false, false, false), C(',') );
+	// - The French number row has digits on the shift level, also selected by Caps Lock
+	assert_eq!( FR.translate(KeyCode::Kb1, false, false, false), C('&') );
+	assert_eq!( FR.translate(KeyCode::Kb1, false, false, true), C('1') );
+	assert_eq!( FR.translate(KeyCode::Kb1, true, false, true), C('&') );
+	// Dead keys
+	assert_eq!( DE.translate(KeyCode::Equals, false, false, false), D(DeadKey::Acute) );
+	assert_eq!( FR.translate(KeyCode::SquareOpen, true, false, false), D(DeadKey::Diaeresis) );
+}
+#[test]
+fn dead_key_compose()
+{
+	assert_eq!( DeadKey::Acute.compose('e'), Some('é') );
+	assert_eq!( DeadKey::Acute.compose('Y'), Some('Ý') );
+	assert_eq!( DeadKey::Circumflex.compose('A'), Some('Â') );
+	assert_eq!( DeadKey::Tilde.compose('n'), Some('ñ') );
+	assert_eq!( DeadKey::Diaeresis.compose('u'), Some('ü') );
+	assert_eq!( DeadKey::Grave.compose('y'), None );
+	assert_eq!( DeadKey::Tilde.compose('e'), None );
+	for &d in &[DeadKey::Grave, DeadKey::Acute, DeadKey::Circumflex, DeadKey::Tilde, DeadKey::Diaeresis] {
+		assert_eq!( DeadKey::from_u8(d as u8), Some(d) );
+	}
+	assert_eq!( DeadKey::from_u8(0), None );
+}
+#[test]
+fn dead_key_combine()
+{
+	let acute = Some(DeadKey::Acute);
+	// Dead key is held until the next character
+	assert_eq!( combine(None, D(DeadKey::Acute)), (acute, [None, None]) );
+	assert_eq!( combine(acute, Sym::None), (acute, [None, None]) );
+	assert_eq!( combine(acute, C('e')), (None, [Some('é'), None]) );
+	// Not composable, so both are emitted
+	assert_eq!( combine(acute, C('x')), (None, [Some('´'), Some('x')]) );
+	// Space and a repeated dead key give the bare accent
+	assert_eq!( combine(acute, C(' ')), (None, [Some('´'), None]) );
+	assert_eq!( combine(acute, D(DeadKey::Acute)), (None, [Some('´'), None]) );
+	// A different dead key replaces the pending one
+	assert_eq!( combine(acute, D(DeadKey::Grave)), (Some(DeadKey::Grave), [Some('´'), None]) );
+	assert_eq!( combine(None, C('q')), (None, [Some('q'), None]) );
+}
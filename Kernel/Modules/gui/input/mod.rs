@@ -6,12 +6,13 @@
 #[allow(unused_imports)]
 use kernel::prelude::*;
 use self::keyboard::KeyCode;
-use core::sync::atomic::{AtomicUsize,Ordering};
+use core::sync::atomic::{AtomicBool,AtomicUsize,Ordering};
 use kernel::sync::atomic::AtomicValue;
-use kernel::sync::Mutex;
+use kernel::sync::mutex::{LazyMutex,Mutex};
 
 pub mod keyboard;
 pub mod mouse;
+pub mod keymap;
 
 #[derive(Debug)]
 pub enum Event
@@ -25,6 +26,7 @@ pub enum Event
 	MouseDown(u32,u32,u8),
 	MouseUp(u32,u32,u8),
 	MouseClick(u32,u32, u8, u8),
+	MouseScroll(u32,u32, i16,i16),
 }
 
 struct ModKeyPair(AtomicUsize);
@@ -35,15 +37,24 @@ struct MouseCursor {
 
 struct InputChannel
 {
-	//caps_active: AtomicBool,	// Go DIAF capslock
+	caps_active: AtomicBool,	// Go DIAF capslock
+	num_active: AtomicBool,
+	scroll_active: AtomicBool,
 	shift_held: ModKeyPair,
 	ctrl_held: ModKeyPair,
 	alt_held: ModKeyPair,
-	//altgr: ModKeyPair,	// AltGr is usually just one... but meh
+	gui_held: ModKeyPair,
+
+	/// Index of the active layout in `keymap::KEYMAPS`
+	keymap: AtomicUsize,
+	/// Pending dead key (`keymap::DeadKey` as u8, zero for none)
+	dead_key: AtomicValue<u8>,
 	
 	last_key_pressed: AtomicValue<u8>,
-	//active_repeat: AtomicValue<u8>,
-	//repeat_start: Timestamp,
+	/// Key being auto-repeated (`KeyCode::None` if none)
+	repeat_key: AtomicValue<u8>,
+	/// Tick count at which the next repeat fires
+	repeat_next: AtomicValue<::kernel::time::TickCount>,
 	
 	cursor: MouseCursor,
 	// TODO: Mutex feels too heavy, but there may be multiple mice on one channel
@@ -65,31 +76,120 @@ struct MouseClickInfo
 //	ime_val: u32,
 //}
 
+/// Translated text from a key press (at most two characters, e.g. an uncombined accent and a letter)
+struct InputString
+{
+	data: [u8; 6],
+	len: usize,
+}
+
 /// Maximum time in kernel ticks between subsequent press/release events for a click/doubleclick
 const DOUBLE_CLICK_TIMEOUT: u64 = 500;	// 500ms
 /// Maximum distance along any axis between press/release before a click is not registered
 const MAX_CLICK_MOVE: u32 = 10;
 static MAIN_INPUT: InputChannel = InputChannel::new();
 
+/// Time in ticks between a key being pressed and it starting to repeat
+static S_REPEAT_DELAY: AtomicValue<u64> = AtomicValue::new(500);
+/// Time in ticks between repeats (zero disables repetition)
+static S_REPEAT_PERIOD: AtomicValue<u64> = AtomicValue::new(33);
+/// Poked when a repeatable key is pressed
+static S_REPEAT_EVENT: ::kernel::sync::EventChannel = ::kernel::sync::EventChannel::new();
+static S_REPEAT_THREAD: LazyMutex<::kernel::threads::WorkerThread> = lazymutex_init!();
+
 pub fn init() {
 	//MAIN_INPUT.cursor.
+	let name = ::kernel::config::get_string(::kernel::config::Value::Keymap);
+	if !set_keymap(name) {
+		log_warning!("Unknown keymap '{}', using '{}'", name, keymap::KEYMAPS[0].name);
+	}
+
+	// Key repeat is specified as `<delay_ms>,<rate_hz>`
+	let repeat = ::kernel::config::get_string(::kernel::config::Value::KeyRepeat);
+	let mut it = repeat.split(',').map(|v| v.trim().parse::<u32>());
+	match (it.next(), it.next(), it.next())
+	{
+	(Some(Ok(delay)), Some(Ok(rate)), None) => set_key_repeat(delay, rate),
+	_ => log_warning!("Malformed key repeat setting '{}', expected '<delay_ms>,<rate_hz>'", repeat),
+	}
+	S_REPEAT_THREAD.init( || ::kernel::threads::WorkerThread::new("GUI Key Repeat", repeat_thread) );
+}
+
+/// Set the key repeat delay (in milliseconds) and rate (in repeats per second, zero disables repetition)
+pub fn set_key_repeat(delay_ms: u32, rate_hz: u32) {
+	let period = if rate_hz == 0 { 0 } else { ::core::cmp::max(1, 1000 / rate_hz as u64) };
+	log_log!("Key repeat set to {}ms delay, {}ms period", delay_ms, period);
+	S_REPEAT_DELAY.store(delay_ms as u64, Ordering::Relaxed);
+	S_REPEAT_PERIOD.store(period, Ordering::Relaxed);
+	if period == 0 {
+		cancel_repeat();
+	}
+}
+/// Stop repeating the currently held key (e.g. when the focussed window changes)
+pub fn cancel_repeat() {
+	MAIN_INPUT.repeat_key.store(KeyCode::None as u8, Ordering::Relaxed);
+}
+
+/// Select the keyboard layout by name, returns false if there's no such layout
+pub fn set_keymap(name: &str) -> bool {
+	match keymap::KEYMAPS.iter().position(|m| m.name.eq_ignore_ascii_case(name))
+	{
+	Some(idx) => {
+		log_log!("Keymap set to '{}'", keymap::KEYMAPS[idx].name);
+		MAIN_INPUT.keymap.store(idx, Ordering::Relaxed);
+		MAIN_INPUT.dead_key.store(0, Ordering::Relaxed);
+		true
+		},
+	None => false,
+	}
+}
+/// Name of the current keyboard layout
+pub fn get_keymap() -> &'static str {
+	MAIN_INPUT.keymap().name
 }
 
 fn get_channel_by_index(_idx: usize) -> &'static InputChannel {
 	&MAIN_INPUT
 }
 
+/// Thread that generates events for held keys
+fn repeat_thread()
+{
+	loop
+	{
+		S_REPEAT_EVENT.sleep();
+		// Sleep until the next repeat is due (a new key press also wakes the thread, and resets the timer)
+		while let Some(next) = MAIN_INPUT.check_repeat()
+		{
+			if ::kernel::time::post_at(next, &S_REPEAT_EVENT) {
+				S_REPEAT_EVENT.sleep();
+			}
+			else {
+				// - Timed wakeup table is full (or there's no timer interrupt), fall back to polling
+				::kernel::threads::yield_time();
+			}
+		}
+	}
+}
+
 impl InputChannel
 {
 	const fn new() -> InputChannel {
 		InputChannel { 
+			caps_active: AtomicBool::new(false),
+			num_active: AtomicBool::new(false),
+			scroll_active: AtomicBool::new(false),
 			shift_held: ModKeyPair::new(),
 			ctrl_held: ModKeyPair::new(),
 			alt_held: ModKeyPair::new(),
-			//altgr: ModKeyPair::new(),
+			gui_held: ModKeyPair::new(),
+			keymap: AtomicUsize::new(0),
+			dead_key: AtomicValue::new(0),
 			cursor: MouseCursor::new(),
 			
 			last_key_pressed: AtomicValue::new(KeyCode::None as u8),
+			repeat_key: AtomicValue::new(KeyCode::None as u8),
+			repeat_next: AtomicValue::new(0),
 			double_click_info: Mutex::new(MouseClickInfo::new()),
 			}
 	}
@@ -105,12 +205,20 @@ impl InputChannel
 		(false, KeyCode::LeftCtrl)  => self.ctrl_held.set_l(),
 		(false, KeyCode::RightAlt) => self.alt_held.set_r(),
 		(false, KeyCode::LeftAlt)  => self.alt_held.set_l(),
+		(false, KeyCode::RightGui) => self.gui_held.set_r(),
+		(false, KeyCode::LeftGui)  => self.gui_held.set_l(),
 		(true, KeyCode::RightShift) => self.shift_held.clear_r(),
 		(true, KeyCode::LeftShift)  => self.shift_held.clear_l(),
 		(true, KeyCode::RightCtrl) => self.ctrl_held.clear_r(),
 		(true, KeyCode::LeftCtrl)  => self.ctrl_held.clear_l(),
 		(true, KeyCode::RightAlt) => self.alt_held.clear_r(),
 		(true, KeyCode::LeftAlt)  => self.alt_held.clear_l(),
+		(true, KeyCode::RightGui) => self.gui_held.clear_r(),
+		(true, KeyCode::LeftGui)  => self.gui_held.clear_l(),
+		// Lock keys toggle on press
+		(false, KeyCode::Caps) | (false, KeyCode::LockingCaps) => { self.caps_active.fetch_xor(true, Ordering::Relaxed); },
+		(false, KeyCode::Numlock) | (false, KeyCode::LogkingNum) => { self.num_active.fetch_xor(true, Ordering::Relaxed); },
+		(false, KeyCode::ScrollLock) | (false, KeyCode::LogkingScroll) => { self.scroll_active.fetch_xor(true, Ordering::Relaxed); },
 		// Check for session change commands, don't propagate if they fired
 		// - 'try_change_session' checks for the required modifier keys and permissions
 		// TODO: Should this be handled by the `windows` module?
@@ -148,20 +256,23 @@ impl InputChannel
 		}
 		else
 		{
-			// TODO: Support repetition (of the last non-modifier pressed)
 			if !release
 			{
-				super::windows::handle_input( Event::KeyFire(key) );
-
-				// TODO: Should only generate text if no non-shift modifiers are depressed
-				//if self.enable_input_translation {
-					let s = self.get_input_string(key);
-					if s.len() > 0 {
-						let mut buf = [0; 6];
-						buf[.. s.len()].clone_from_slice( s.as_bytes() );
-						super::windows::handle_input( Event::Text(buf) );
-					}
-				//}
+				// Only the last non-modifier pressed repeats (checked before firing, as firing changes the dead key state)
+				if self.is_repeatable(key) {
+					self.repeat_next.store(::kernel::time::ticks() + S_REPEAT_DELAY.load(Ordering::Relaxed), Ordering::Relaxed);
+					self.repeat_key.store(key as u8, Ordering::Relaxed);
+					S_REPEAT_EVENT.post();
+				}
+				else {
+					self.repeat_key.store(KeyCode::None as u8, Ordering::Relaxed);
+				}
+				self.fire_key(key);
+			}
+			else
+			{
+				// Releasing the repeating key stops the repeat, releasing an earlier key doesn't
+				let _ = self.repeat_key.compare_exchange(key as u8, KeyCode::None as u8, Ordering::Relaxed);
 			}
 		}
 
@@ -172,6 +283,63 @@ impl InputChannel
 		}
 	}
 	
+	/// Send the fire and text events for a (non-modifier) key press
+	fn fire_key(&self, key: KeyCode)
+	{
+		super::windows::handle_input( Event::KeyFire(key) );
+
+		// Only generate text if no non-shift modifiers (other than AltGr) are held
+		if !self.ctrl_held.get() && !self.alt() && !self.gui_held.get() {
+			let s = self.get_input_string(key);
+			if s.len > 0 {
+				super::windows::handle_input( Event::Text(s.data) );
+			}
+		}
+	}
+	/// Returns true if holding this key should generate repeated events
+	fn is_repeatable(&self, key: KeyCode) -> bool
+	{
+		if S_REPEAT_PERIOD.load(Ordering::Relaxed) == 0 {
+			return false;
+		}
+		match key
+		{
+		KeyCode::Caps | KeyCode::Numlock | KeyCode::ScrollLock | KeyCode::Pause => false,
+		KeyCode::LockingCaps | KeyCode::LogkingNum | KeyCode::LogkingScroll => false,
+		// Repeating a dead key would just emit the bare accent
+		_ => match self.keymap().translate(key, self.shift(), self.altgr(), self.caps_active.load(Ordering::Relaxed))
+			{
+			keymap::Sym::Dead(_) => false,
+			_ => true,
+			},
+		}
+	}
+	/// Fire the held key if its repeat is due, returns when the next repeat is due (`None` if no key is held)
+	fn check_repeat(&self) -> Option<::kernel::time::TickCount>
+	{
+		let key = self.repeat_key.load(Ordering::Relaxed);
+		if key == KeyCode::None as u8 {
+			return None;
+		}
+		let now = ::kernel::time::ticks();
+		let next = self.repeat_next.load(Ordering::Relaxed);
+		if now < next {
+			return Some(next);
+		}
+		// Claim this repeat, a new key press may have raced and reset the timer
+		let period = ::core::cmp::max(1, S_REPEAT_PERIOD.load(Ordering::Relaxed));
+		if !self.repeat_next.compare_exchange(next, ::core::cmp::max(next + period, now), Ordering::Relaxed).1 {
+			return Some(self.repeat_next.load(Ordering::Relaxed));
+		}
+		if self.repeat_key.load(Ordering::Relaxed) != key {
+			return Some(self.repeat_next.load(Ordering::Relaxed));
+		}
+		let key = KeyCode::from(key);
+		super::windows::handle_input( Event::KeyDown(key) );
+		self.fire_key(key);
+		Some(self.repeat_next.load(Ordering::Relaxed))
+	}
+
 	pub fn handle_mouse_move(&self, dx: i16, dy: i16)
 	{
 		// Mouse movement, update cursor
@@ -180,6 +348,11 @@ impl InputChannel
 		self.double_click_info.lock().clear();
 		super::windows::handle_input(/*self, */Event::MouseMove(x, y, dx, dy));
 	}
+	pub fn handle_mouse_scroll(&self, dx: i16, dy: i16)
+	{
+		let (x,y) = self.cursor.pos();
+		super::windows::handle_input(/*self, */Event::MouseScroll(x, y, dx, dy));
+	}
 	pub fn handle_mouse_btn(&self, btn: u8, release: bool)
 	{
 		let (x,y) = self.cursor.pos();
@@ -204,71 +377,65 @@ impl InputChannel
 		}
 	}
 
+	/// Lock key state (`keyboard::LED_*` bits)
+	fn leds(&self) -> u8 {
+		let mut rv = 0;
+		if self.num_active.load(Ordering::Relaxed) { rv |= keyboard::LED_NUM_LOCK; }
+		if self.caps_active.load(Ordering::Relaxed) { rv |= keyboard::LED_CAPS_LOCK; }
+		if self.scroll_active.load(Ordering::Relaxed) { rv |= keyboard::LED_SCROLL_LOCK; }
+		rv
+	}
+
+	fn keymap(&self) -> &'static keymap::Keymap {
+		keymap::KEYMAPS[self.keymap.load(Ordering::Relaxed)]
+	}
 	fn shift(&self) -> bool {
 		self.shift_held.get()
 	}
-	fn upper(&self) -> bool {
-		self.shift()
+	/// Alt (i.e. not AltGr) held
+	fn alt(&self) -> bool {
+		self.alt_held.get_l() || (self.alt_held.get_r() && !self.keymap().has_altgr)
+	}
+	fn altgr(&self) -> bool {
+		self.alt_held.get_r() && self.keymap().has_altgr
 	}
 	
-	fn get_input_string(&self, keycode: KeyCode) -> &str
+	fn get_input_string(&self, keycode: KeyCode) -> InputString
 	{
-		macro_rules! shift { ($s:ident: $lower:expr, $upper:expr) => { if $s.shift() { $upper } else {$lower} }; }
-		macro_rules! alpha { ($s:ident: $lower:expr, $upper:expr) => { if $s.upper() { $upper } else {$lower} }; }
-		match keycode
-		{
-		KeyCode::A => alpha!(self: "a", "A"),
-		KeyCode::B => alpha!(self: "b", "B"),
-		KeyCode::C => alpha!(self: "c", "C"),
-		KeyCode::D => alpha!(self: "d", "D"),
-		KeyCode::E => alpha!(self: "e", "E"),
-		KeyCode::F => alpha!(self: "f", "F"),
-		KeyCode::G => alpha!(self: "g", "G"),
-		KeyCode::H => alpha!(self: "h", "H"),
-		KeyCode::I => alpha!(self: "i", "I"),
-		KeyCode::J => alpha!(self: "j", "J"),
-		KeyCode::K => alpha!(self: "k", "K"),
-		KeyCode::L => alpha!(self: "l", "L"),
-		KeyCode::M => alpha!(self: "m", "M"),
-		KeyCode::N => alpha!(self: "n", "N"),
-		KeyCode::O => alpha!(self: "o", "O"),
-		KeyCode::P => alpha!(self: "p", "P"),
-		KeyCode::Q => alpha!(self: "q", "Q"),
-		KeyCode::R => alpha!(self: "r", "R"),
-		KeyCode::S => alpha!(self: "s", "S"),
-		KeyCode::T => alpha!(self: "t", "T"),
-		KeyCode::U => alpha!(self: "u", "U"),
-		KeyCode::V => alpha!(self: "v", "V"),
-		KeyCode::W => alpha!(self: "w", "W"),
-		KeyCode::X => alpha!(self: "x", "X"),
-		KeyCode::Y => alpha!(self: "y", "Y"),
-		KeyCode::Z => alpha!(self: "z", "Z"),
-
-		KeyCode::SquareOpen  => shift!(self: "[", "{"),
-		KeyCode::SquareClose => shift!(self: "[", "{"),
-		KeyCode::Backslash   => shift!(self: "\\","|"),
-		KeyCode::Semicolon => shift!(self: ";", ":"),
-		KeyCode::Quote     => shift!(self: "'", "\""),
-		KeyCode::Comma  => shift!(self: ",", "<"),
-		KeyCode::Period => shift!(self: ".", ">"),
-		KeyCode::Slash  => shift!(self: "/", "?"),
-
-		KeyCode::Kb1 => shift!(self: "1", "!"),
-		KeyCode::Kb2 => shift!(self: "2", "@"),
-		KeyCode::Kb3 => shift!(self: "3", "#"),
-		KeyCode::Kb4 => shift!(self: "4", "$"),
-		KeyCode::Kb5 => shift!(self: "5", "%"),
-		KeyCode::Kb6 => shift!(self: "6", "^"),
-		KeyCode::Kb7 => shift!(self: "7", "&"),
-		KeyCode::Kb8 => shift!(self: "8", "*"),
-		KeyCode::Kb9 => shift!(self: "9", "("),
-		KeyCode::Kb0 => shift!(self: "0", ")"),
-		KeyCode::Minus  => shift!(self: "-", "_"),
-		KeyCode::Equals => shift!(self: "=", "+"),
-
-		KeyCode::Space => " ",
-		_ => "",
+		use self::keymap::Sym;
+		let num = self.num_active.load(Ordering::Relaxed);
+		macro_rules! num { ($c:expr) => { if num { Sym::Char($c) } else { Sym::None } }; }
+		// Keys that are the same on all layouts
+		let sym = match keycode
+			{
+			KeyCode::Space => Sym::Char(' '),
+			KeyCode::KpSlash => Sym::Char('/'),
+			KeyCode::KpStar  => Sym::Char('*'),
+			KeyCode::KpMinus => Sym::Char('-'),
+			KeyCode::KpPlus  => Sym::Char('+'),
+			KeyCode::Kp1 => num!('1'),
+			KeyCode::Kp2 => num!('2'),
+			KeyCode::Kp3 => num!('3'),
+			KeyCode::Kp4 => num!('4'),
+			KeyCode::Kp5 => num!('5'),
+			KeyCode::Kp6 => num!('6'),
+			KeyCode::Kp7 => num!('7'),
+			KeyCode::Kp8 => num!('8'),
+			KeyCode::Kp9 => num!('9'),
+			KeyCode::Kp0 => num!('0'),
+			KeyCode::KpPeriod => num!('.'),
+			_ => self.keymap().translate(keycode, self.shift(), self.altgr(), self.caps_active.load(Ordering::Relaxed)),
+			};
+
+		let pending = keymap::DeadKey::from_u8( self.dead_key.load(Ordering::Relaxed) );
+		let (pending, chars) = keymap::combine(pending, sym);
+		self.dead_key.store(pending.map(|d| d as u8).unwrap_or(0), Ordering::Relaxed);
+
+		let mut rv = InputString::new();
+		for c in chars.iter().filter_map(|c| *c) {
+			rv.push(c);
 		}
+		rv
 	}
 	
 	fn try_change_session(&self, target: usize) -> bool {
@@ -295,6 +462,24 @@ impl ModKeyPair {
 	fn get(&self) -> bool {
 		self.0.load(Ordering::Relaxed) != 0
 	}
+	fn get_l(&self) -> bool {
+		self.0.load(Ordering::Relaxed) & 1 != 0
+	}
+	fn get_r(&self) -> bool {
+		self.0.load(Ordering::Relaxed) & 2 != 0
+	}
+}
+impl InputString {
+	fn new() -> InputString {
+		InputString { data: [0; 6], len: 0 }
+	}
+	fn push(&mut self, c: char) {
+		let l = c.len_utf8();
+		if self.len + l <= self.data.len() {
+			c.encode_utf8(&mut self.data[self.len..]);
+			self.len += l;
+		}
+	}
 }
 impl MouseCursor {
 	const fn new() -> MouseCursor {
@@ -22,5 +22,9 @@ impl Instance
 	pub fn release_button(&self, btn: u8) {
 		super::get_channel_by_index(0).handle_mouse_btn(btn, true);
 	}
+	/// Scroll wheel movement (positive is right/down)
+	pub fn scroll(&self, dx: i16, dy: i16) {
+		super::get_channel_by_index(0).handle_mouse_scroll(dx, dy);
+	}
 }
 
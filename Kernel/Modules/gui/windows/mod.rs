@@ -173,6 +173,8 @@ pub fn switch_active(new: usize)
 	// - Technically it shouldn't (reading the size is just racy, not unsafe), but representing that is nigh-on
 	//   impossible.
 	log_log!("Switching to group {}", new);
+	// Held keys shouldn't keep repeating into the new group
+	super::input::cancel_repeat();
 	S_CURRENT_GROUP.store(new as usize, atomic::Ordering::Relaxed);
 	S_RENDER_NEEDED.store(true, atomic::Ordering::Relaxed);
 	S_FULL_REDRAW.store(true, atomic::Ordering::Relaxed);
@@ -256,7 +258,7 @@ impl WindowGroup
 		self.refcount -= 1;
 		if self.refcount == 0 {
 			// Delete all windows
-			self.focussed_window = 0;
+			self.set_focus(0);
 			self.render_order.truncate(0);
 			// - Can't drop all the windows yet, their handles include ArefBorrow-s
 			//self.windows = Default::default();
@@ -331,7 +333,7 @@ impl WindowGroup
 				}
 			}
 			else {
-				self.focussed_window = 0;
+				self.set_focus(0);
 			}
 			},
 		Event::MouseMove(x,y, dx,dy) =>
@@ -361,6 +363,12 @@ impl WindowGroup
 				let Pos { x: bx, y: by } = newwin.0;
 				newwin.1.handle_input( Event::MouseClick(x - bx, y - by, btn, count) );
 			},
+		Event::MouseScroll(x,y, dx,dy) =>
+			if let Some(newwin) = self.get_win_at_pos(x,y)
+			{
+				let Pos { x: bx, y: by } = newwin.0;
+				newwin.1.handle_input( Event::MouseScroll(x - bx, y - by, dx, dy) );
+			},
 		Event::MouseUp(x,y, btn) =>
 			if let Some(newwin) = self.get_win_at_pos(x,y)
 			{
@@ -445,6 +453,14 @@ impl WindowGroup
 		vis
 	}
 	
+	/// Change the window receiving keyboard input
+	fn set_focus(&mut self, idx: WinId) {
+		if self.focussed_window != idx {
+			// - Stop any key repeat, so the new window doesn't get keys pressed in the old one
+			super::input::cancel_repeat();
+			self.focussed_window = idx;
+		}
+	}
 	fn show_window(&mut self, idx: WinId) {
 		if self.get_render_idx(idx).is_some() {
 			return ;
@@ -455,7 +471,7 @@ impl WindowGroup
 		self.recalc_vis_int(vis_idx);
 
 		// TODO: Have a better method than just switching focus on show
-		self.focussed_window = idx;
+		self.set_focus(idx);
 	}
 	fn hide_window(&mut self, idx: WinId) {
 		if let Some(pos) = self.get_render_idx(idx)
@@ -465,7 +481,8 @@ impl WindowGroup
 			// If this window was the focussed one, switch to the next lower down window
 			// - TODO: Have an alt-tab order and use that instead
 			if self.focussed_window == idx {
-				self.focussed_window = self.render_order.get( prev_pos ).map(|x| x.0).unwrap_or(0);
+				let new_focus = self.render_order.get( prev_pos ).map(|x| x.0).unwrap_or(0);
+				self.set_focus(new_focus);
 			}
 			// Recalculate visibility for lower window
 			self.recalc_vis_int(prev_pos);
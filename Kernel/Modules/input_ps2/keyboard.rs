@@ -3,7 +3,10 @@
 //
 // Modules/input_ps2/keyboard.rs
 //! PS2 Keyboard driver
+#[cfg(not(test))]
 use gui::input::keyboard as gui_keyboard;
+#[cfg(test)]
+use self::test_gui as gui_keyboard;
 use gui::input::keyboard::KeyCode;
 
 #[derive(Debug)]
@@ -11,6 +14,7 @@ pub enum Type
 {
 	//AT,
 	MF2,
+	/// MF2 with the controller translating to scancode set 1
 	MF2Emul,
 }
 
@@ -26,15 +30,37 @@ enum Layer
 	Base,
 	E0,
 	E1,
+	/// Second code of an E1 sequence (holding the first)
+	E1b(u8),
 }
 #[derive(Copy,Clone,Debug)]
-#[allow(dead_code)]	// SetLeds is unused... at the moment
 enum Init
 {
 	Disabled,
 	ReqScancodeSetAck,
 	ReqScancodeSetRsp,
-	SetLeds(u8),
+	/// Set 3 only - Waiting for ACK of "Set All Keys Make/Release"
+	SetMakeReleaseAck,
+}
+/// Progress of a "Set LEDs" (0xED) command, which can be interleaved with scancodes
+#[derive(Copy,Clone,Debug)]
+enum LedCmd
+{
+	Idle,
+	/// Waiting for the command's ACK, before sending the LED byte
+	CmdAck(u8),
+	/// Waiting for the LED byte's ACK
+	DataAck(u8),
+}
+#[derive(Copy,Clone,Debug)]
+enum ScancodeSet
+{
+	/// XT set, release flagged by bit 7
+	Set1,
+	/// AT set (most common), release prefixed with 0xF0
+	Set2,
+	/// PS/2 set, single-byte codes with 0xF0 release prefix
+	Set3,
 }
 
 #[derive(Debug)]
@@ -42,7 +68,13 @@ pub struct Dev
 {
 	ty: Type,
 	state: State,
+	set: ScancodeSet,
 	guidev: gui_keyboard::Instance,
+	/// Bitmap of held keys, used to drop typematic repeats (the GUI does its own repetition)
+	pressed: [u32; 8],
+	/// Lock state (`gui_keyboard::LED_*`) last sent to the keyboard
+	leds: u8,
+	led_cmd: LedCmd,
 }
 
 impl Dev
@@ -57,19 +89,19 @@ impl Dev
 		//	log_warning!("Unexpected AT keyboard");
 		//	return (None, Dev { ty: ty, state: State::Init(Init::Disabled), guidev: Default::default() });
 		//	},
-		Type::MF2Emul => {
-			log_warning!("Unexpected emulation enabled MF2");
-			return (None, Dev { ty: ty, state: State::Init(Init::Disabled), guidev: Default::default() });
-			},
-		Type::MF2 => {
-			// 1. Request scancode set
-			(Some(0xF0), Dev {
-				ty: ty,
-				state: State::Init(Init::ReqScancodeSetAck),
-				guidev: gui_keyboard::Instance::new(),
-				})
-			},
+		Type::MF2Emul => log_notice!("Emulation enabled MF2, expecting scancode set 1"),
+		Type::MF2 => {},
 		}
+		// 1. Request scancode set
+		(Some(0xF0), Dev {
+			ty: ty,
+			state: State::Init(Init::ReqScancodeSetAck),
+			set: ScancodeSet::Set2,
+			guidev: gui_keyboard::Instance::new(),
+			pressed: [0; 8],
+			leds: 0,
+			led_cmd: LedCmd::Idle,
+			})
 	}
 	
 	/// Handle a received byte
@@ -91,60 +123,87 @@ impl Dev
 				self.state = State::Init(Init::ReqScancodeSetRsp);
 				Some(0x00)
 				},
-			Init::ReqScancodeSetRsp =>
-				match byte
+			Init::ReqScancodeSetRsp => {
+				// - Second values are the response as translated by the controller
+				let set = match byte
+					{
+					1 | 0x43 => ScancodeSet::Set1,
+					2 | 0x41 => ScancodeSet::Set2,
+					3 | 0x3F => ScancodeSet::Set3,
+					0xFA => {
+						log_warning!("Received second ACK for ReqScancodeSetRsp {:#02x}", byte);
+						return None;
+						},
+					_ => {
+						log_warning!("Unkown scancode set reponse {:#02x}", byte);
+						self.state = State::Init(Init::Disabled);
+						return None;
+						},
+					};
+				// Translation converts everything to set 1
+				self.set = if is!(self.ty, Type::MF2Emul) { ScancodeSet::Set1 } else { set };
+				match self.set
 				{
-				// Scancode set 1
-				1 /*0x43*/ => {
-					log_warning!("TODO: Support scancode set 1");
-					self.state = State::Init(Init::Disabled);
-					None
-					},
-				// Scancode set 2 (most common)
-				2 /*0x41*/ => {
-					log_debug!("Keyboard ready, scancode set 2");
-					self.state = State::Idle(Layer::Base,false);
-					None
-					},
-				// Scancode set 3 (newest)
-				3 /*0x3F*/ => {
-					log_warning!("TODO: Support scancode set 3");
-					self.state = State::Init(Init::Disabled);
-					None
-					},
-				0xFA => {
-					log_warning!("Received second ACK for ReqScancodeSetRsp {:#02x}", byte);
-					None
+				// Set 3 defaults to make-only for some keys, so enable release codes for everything
+				ScancodeSet::Set3 => {
+					self.state = State::Init(Init::SetMakeReleaseAck);
+					Some(0xF8)
 					},
 				_ => {
-					log_warning!("Unkown scancode set reponse {:#02x}", byte);
-					self.state = State::Init(Init::Disabled);
-					None
+					log_debug!("Keyboard ready, scancode {:?}", self.set);
+					self.state = State::Idle(Layer::Base,false);
+					self.sync_leds()
 					},
+				}
 				},
-			Init::SetLeds(v) => {
+			Init::SetMakeReleaseAck => {
+				if byte != 0xFA {
+					log_warning!("Unexpected response to Set All Keys Make/Release {:#02x}", byte);
+				}
+				log_debug!("Keyboard ready, scancode {:?}", self.set);
 				self.state = State::Idle(Layer::Base,false);
-				Some(v)
+				self.sync_leds()
 				},
 			},
 		// Idle and ready to process keystrokes
-		State::Idle(layer,release) =>
+		State::Idle(layer,release) => {
+			// In set 1, several of the controller responses are also release codes
+			let set1 = is!(self.set, ScancodeSet::Set1);
 			match byte
 			{
 			// Error/Buffer Overrun
 			0x00 => None,
 			0xFF => None,
 			// Self-test passed
-			0xAA => None,
+			0xAA if !set1 => None,
 			// Self-test failed
-			0xFC => None,
-			0xFD => None,
+			0xFC if !set1 => None,
+			0xFD if !set1 => None,
 			// Echo reply
-			0xEE => None,
+			0xEE if !set1 => None,
 			// ACK
-			0xFA => { log_notice!("Unexpected ACK from keyboard"); None },
+			0xFA =>
+				match self.led_cmd
+				{
+				LedCmd::CmdAck(v) => {
+					self.led_cmd = LedCmd::DataAck(v);
+					Some(v)
+					},
+				LedCmd::DataAck(_) => {
+					// The lock state may have changed again while the command was in progress
+					self.led_cmd = LedCmd::Idle;
+					self.sync_leds()
+					},
+				LedCmd::Idle => { log_notice!("Unexpected ACK from keyboard"); None },
+				},
 			// Resend
-			0xFE => { log_notice!("Resend request from keyboard"); None },
+			0xFE =>
+				match self.led_cmd
+				{
+				LedCmd::CmdAck(_) => Some(0xED),
+				LedCmd::DataAck(v) => Some(v),
+				LedCmd::Idle => { log_notice!("Resend request from keyboard"); None },
+				},
 			// Extended scancodes
 			0xE0 => {
 				self.state = State::Idle(Layer::E0, false);
@@ -155,37 +214,96 @@ impl Dev
 				None
 				},
 			// Released key flag
-			0xF0 => {
+			0xF0 if !set1 => {
 				self.state = State::Idle(layer, true);
 				None
 				},
 			
-			v @ _ => {
-				// Translate to a HID scancode
-				let mapping: &[KeyCode] = match layer
-					{
-					Layer::Base => &keymaps::SC2_BASE,
-					Layer::E0 => &keymaps::SC2_E0,
-					_ => &[],
-					};
-				let key = *mapping.get(v as usize).unwrap_or(&KeyCode::None);
-				if key == KeyCode::None {
-					if ! release {
-						log_warning!("Scancode {:?} {:#02x} has no mapping", layer, v);
-					}
-				}
-				else {
-					if release {
-						self.guidev.release_key(key);
-					}
-					else {
-						self.guidev.press_key(key);
-					}
-				}
-				self.state = State::Idle(Layer::Base,false);
-				None
+			v @ _ => self.handle_code(layer, release, v),
+			}
+			},
+		}
+	}
+
+	/// Translate a scancode (with prefixes already handled) and pass it to the GUI
+	///
+	/// Returns a byte to send to the keyboard (if the key changed the lock state)
+	fn handle_code(&mut self, layer: Layer, release: bool, byte: u8) -> Option<u8>
+	{
+		let (release, v) = match self.set
+			{
+			ScancodeSet::Set1 => (byte & 0x80 != 0, byte & 0x7F),
+			_ => (release, byte),
+			};
+		// Translate to a HID scancode
+		let key = match (self.set, layer)
+			{
+			// E1 is only used for Pause, which is two codes long (and sends its release immediately)
+			(_, Layer::E1) => {
+				self.state = State::Idle(Layer::E1b(v), release);
+				return None;
 				},
+			(ScancodeSet::Set1, Layer::E1b(0x1D)) if v == 0x45 => KeyCode::Pause,
+			(ScancodeSet::Set2, Layer::E1b(0x14)) if v == 0x77 => KeyCode::Pause,
+			(_, Layer::E1b(_)) => KeyCode::None,
+			(ScancodeSet::Set1, Layer::Base) => keymaps::get(&keymaps::SC1_BASE, v),
+			(ScancodeSet::Set1, Layer::E0) => keymaps::get(&keymaps::SC1_E0, v),
+			(ScancodeSet::Set2, Layer::Base) => keymaps::get(&keymaps::SC2_BASE, v),
+			(ScancodeSet::Set2, Layer::E0) => keymaps::get(&keymaps::SC2_E0, v),
+			// Set 3 has no prefixes
+			(ScancodeSet::Set3, _) => keymaps::get(&keymaps::SC3_BASE, v),
+			};
+		self.state = State::Idle(Layer::Base,false);
+
+		if key == KeyCode::None {
+			if ! release && ! self.is_fake_shift(layer, v) {
+				log_warning!("Scancode {:?} {:?} {:#02x} has no mapping", self.set, layer, v);
+			}
+		}
+		else {
+			let (idx, mask) = (key as usize / 32, 1 << (key as usize % 32));
+			if release {
+				self.pressed[idx] &= !mask;
+				self.guidev.release_key(key);
+			}
+			else if self.pressed[idx] & mask == 0 {
+				self.pressed[idx] |= mask;
+				self.guidev.press_key(key);
+			}
+			else {
+				// Typematic repeat, ignored
+			}
+		}
+		self.sync_leds()
+	}
+
+	/// Start a "Set LEDs" command if the GUI's lock state differs from the keyboard's LEDs
+	fn sync_leds(&mut self) -> Option<u8>
+	{
+		let leds = self.guidev.leds();
+		match self.led_cmd
+		{
+		LedCmd::Idle if leds != self.leds => {
+			self.leds = leds;
+			// PS/2 bit order is Scroll, Num, Caps
+			let v = (if leds & gui_keyboard::LED_SCROLL_LOCK != 0 { 1 << 0 } else { 0 })
+				| (if leds & gui_keyboard::LED_NUM_LOCK != 0 { 1 << 1 } else { 0 })
+				| (if leds & gui_keyboard::LED_CAPS_LOCK != 0 { 1 << 2 } else { 0 })
+				;
+			self.led_cmd = LedCmd::CmdAck(v);
+			Some(0xED)
 			},
+		_ => None,
+		}
+	}
+
+	/// Check for the "fake shift" codes sent around some extended keys (e.g. Print Screen)
+	fn is_fake_shift(&self, layer: Layer, v: u8) -> bool {
+		match (self.set, layer, v)
+		{
+		(ScancodeSet::Set1, Layer::E0, 0x2A) | (ScancodeSet::Set1, Layer::E0, 0x36) => true,
+		(ScancodeSet::Set2, Layer::E0, 0x12) | (ScancodeSet::Set2, Layer::E0, 0x59) => true,
+		_ => false,
 		}
 	}
 }
@@ -194,10 +312,17 @@ impl Dev
 mod keymaps {
 	use gui::input::keyboard::KeyCode;
 	use gui::input::keyboard::KeyCode::*;
+
+	/// Look up a code in a table, returning `KeyCode::None` if it's out of range
+	pub fn get(map: &[KeyCode], v: u8) -> KeyCode {
+		*map.get(v as usize).unwrap_or(&None)
+	}
+
+	// Scancode set 2
 	pub static SC2_BASE: [KeyCode; 0x88] = [
 		None, F9,  None, F5, F3, F1 , F2, F12,
 		None, F10, F8,   F6, F4, Tab, GraveTilde, None,
-		None, LeftAlt, LeftShift, None, LeftCtrl, Q, Kb1, None,
+		None, LeftAlt, LeftShift, KbInt2, LeftCtrl, Q, Kb1, None,
 		None, None , Z, S, A, W  , Kb2, None,
 		None, C    , X, D, E, Kb4, Kb3, None,
 		None, Space, V, F, T, R  , Kb5, None,
@@ -205,33 +330,204 @@ mod keymaps {
 		None, None , M, J, U, Kb7, Kb8, None,
 		None, Comma, K, I, O, Kb0, Kb9, None,
 		None, Period, Slash, L, Semicolon, P, Minus, None,
-		None, None, Quote, None, SquareOpen, Equals, None, None,
+		None, KbInt1, Quote, None, SquareOpen, Equals, None, None,
 		Caps, RightShift, Return, SquareClose, None, Backslash, None, None,
-		None, None, None, None,  None, None, Backsp, None,
-		None, Kp1 , None, Kp4 ,  Kp7 , None, None, None,
+		None, NonUSBackslash, None, None,  KbInt4, None, Backsp, KbInt5,
+		None, Kp1 , KbInt3, Kp4 ,  Kp7 , None, None, None,
 		Kp0 , KpPeriod, Kp2, Kp5    , Kp6   , Kp8, Esc       , Numlock,
 		F11 , KpPlus  , Kp3, KpMinus, KpStar, Kp9, ScrollLock, None,
-		None, None, None, F7, None, None, None, None,
+		None, None, None, F7, SysRq, None, None, None,
 		];
-	// TODO: There's a chunk of multimedia/WWW keys in here that I don't know the HID codes for
 	pub static SC2_E0: [KeyCode; 0x80] = [
 		None, None, None, None, None, None, None, None,
 		None, None, None, None, None, None, None, None,
-		None, RightAlt, None, None, RightCtrl, None, None, None,
-		None, None, None, None, None, None, None, LeftGui,
-		None, None, None, None, None, None, None, RightGui,
-		None, None, None, None, None, None, None, Application,	// Application = Menu
+		WwwSearch, RightAlt, None, None, RightCtrl, MediaPrevTrack, None, None,
+		WwwFavourites, None, None, None, None, None, None, LeftGui,
+		WwwRefresh, VolDn, None, Mute, None, None, None, RightGui,
+		WwwStop, None, None, Calculator, None, None, None, Application,
+		WwwForward, None, VolUp, None, MediaPlayPause, None, None, Power,
+		WwwBack, None, WwwHome, MediaStop, None, None, None, Sleep,
+		MyComputer, None, None, None, None, None, None, None,
+		Mail, None, KpSlash, None, None, MediaNextTrack, None, None,
+		MediaSelect, None, None, None, None, None, None, None,
+		None, None, KpEnter, None, None, None, Wake, None,
 		None, None, None, None, None, None, None, None,
+		None, End, None, LeftArrow, Home, None, None, None,
+		Insert, Delete, DownArrow, None, RightArrow, UpArrow, None, None,
+		None, None, PgDn, None, PrintScreen, PgUp, Pause, None,
+		];
+	// Scancode set 1 (also what the controller produces when translating)
+	pub static SC1_BASE: [KeyCode; 0x80] = [
+		None, Esc, Kb1, Kb2, Kb3, Kb4, Kb5, Kb6,
+		Kb7, Kb8, Kb9, Kb0, Minus, Equals, Backsp, Tab,
+		Q, W, E, R, T, Y, U, I,
+		O, P, SquareOpen, SquareClose, Return, LeftCtrl, A, S,
+		D, F, G, H, J, K, L, Semicolon,
+		Quote, GraveTilde, LeftShift, Backslash, Z, X, C, V,
+		B, N, M, Comma, Period, Slash, RightShift, KpStar,
+		LeftAlt, Space, Caps, F1, F2, F3, F4, F5,
+		F6, F7, F8, F9, F10, Numlock, ScrollLock, Kp7,
+		Kp8, Kp9, KpMinus, Kp4, Kp5, Kp6, KpPlus, Kp1,
+		Kp2, Kp3, Kp0, KpPeriod, SysRq, None, NonUSBackslash, F11,
+		F12, KpEquals, None, None, None, None, None, None,
+		None, None, None, None, F13, F14, F15, F16,
+		F17, F18, F19, F20, F21, F22, F23, None,
+		KbInt2, None, None, KbInt1, None, None, F24, None,
+		None, KbInt4, None, KbInt5, None, KbInt3, None, None,
+		];
+	pub static SC1_E0: [KeyCode; 0x80] = [
 		None, None, None, None, None, None, None, None,
 		None, None, None, None, None, None, None, None,
-		None, None, KpSlash, None, None, None, None, None,
+		MediaPrevTrack, None, None, None, None, None, None, None,
+		None, MediaNextTrack, None, None, KpEnter, RightCtrl, None, None,
+		Mute, Calculator, MediaPlayPause, None, MediaStop, None, None, None,
+		None, None, None, None, None, None, VolDn, None,
+		VolUp, None, WwwHome, None, None, KpSlash, None, PrintScreen,
+		RightAlt, None, None, None, None, None, None, None,
+		None, None, None, None, None, None, Pause, Home,
+		UpArrow, PgUp, None, LeftArrow, None, RightArrow, None, End,
+		DownArrow, PgDn, Insert, Delete, None, None, None, None,
+		None, None, None, LeftGui, RightGui, Application, Power, Sleep,
+		None, None, None, Wake, None, WwwSearch, WwwFavourites, WwwRefresh,
+		WwwStop, WwwForward, WwwBack, MyComputer, Mail, MediaSelect, None, None,
 		None, None, None, None, None, None, None, None,
-		None, None, KpEnter, None, None, None, None, None,
 		None, None, None, None, None, None, None, None,
-		None, End , None, LeftArrow, Home, None, None, None,
-		Insert, Delete, DownArrow, None, RightArrow, UpArrow, None, None,
-		None, None, PgDn, None, None, PgUp, None, None,
 		];
-	// TODO: E1 (only contains pause/break)
+	// Scancode set 3 (no prefixed codes)
+	pub static SC3_BASE: [KeyCode; 0x90] = [
+		None, None, None, None, None, None, None, F1,
+		Esc, None, None, None, None, Tab, GraveTilde, F2,
+		None, LeftCtrl, LeftShift, NonUSBackslash, Caps, Q, Kb1, F3,
+		None, LeftAlt, Z, S, A, W, Kb2, F4,
+		None, C, X, D, E, Kb4, Kb3, F5,
+		None, Space, V, F, T, R, Kb5, F6,
+		None, N, B, H, G, Y, Kb6, F7,
+		None, RightAlt, M, J, U, Kb7, Kb8, F8,
+		None, Comma, K, I, O, Kb0, Kb9, F9,
+		None, Period, Slash, L, Semicolon, P, Minus, F10,
+		None, KbInt1, Quote, None, SquareOpen, Equals, F11, PrintScreen,
+		RightCtrl, RightShift, Return, SquareClose, Backslash, None, F12, ScrollLock,
+		DownArrow, LeftArrow, Pause, UpArrow, Delete, End, Backsp, Insert,
+		None, Kp1, RightArrow, Kp4, Kp7, PgDn, Home, PgUp,
+		Kp0, KpPeriod, Kp2, Kp5, Kp6, Kp8, Numlock, KpSlash,
+		None, KpEnter, Kp3, None, KpPlus, Kp9, KpStar, None,
+		None, None, None, None, KpMinus, None, None, None,
+		None, None, None, LeftGui, RightGui, Application, None, None,
+		];
+}
+
+/// Stand-in for the GUI keyboard handle, recording key events (and with a settable lock state)
+#[cfg(test)]
+mod test_gui {
+	use gui::input::keyboard::KeyCode;
+	pub use gui::input::keyboard::{LED_NUM_LOCK, LED_CAPS_LOCK, LED_SCROLL_LOCK};
+
+	#[derive(Default,Debug)]
+	pub struct Instance {
+		/// Key events, with `true` for a release
+		pub events: ::std::cell::RefCell<::std::vec::Vec<(KeyCode, bool)>>,
+		pub leds: ::std::cell::Cell<u8>,
+	}
+	impl Instance {
+		pub fn new() -> Instance {
+			Default::default()
+		}
+		pub fn press_key(&self, key: KeyCode) {
+			self.events.borrow_mut().push( (key, false) );
+		}
+		pub fn release_key(&self, key: KeyCode) {
+			self.events.borrow_mut().push( (key, true) );
+		}
+		pub fn leds(&self) -> u8 {
+			self.leds.get()
+		}
+	}
 }
 
+/// Create a keyboard and run the scancode set handshake, with the device reporting `set_rsp`
+#[cfg(test)]
+fn test_dev(ty: Type, set_rsp: u8) -> Dev
+{
+	let (b, mut dev) = Dev::new(ty);
+	assert_eq!(b, Some(0xF0));
+	assert_eq!(dev.recv_byte(0xFA), Some(0x00));
+	assert_eq!(dev.recv_byte(set_rsp), None);
+	dev
+}
+/// Feed bytes to the keyboard, returning the key events generated
+#[cfg(test)]
+fn test_feed(dev: &mut Dev, bytes: &[u8]) -> ::std::vec::Vec<(KeyCode, bool)>
+{
+	for &b in bytes {
+		assert_eq!(dev.recv_byte(b), None, "Unexpected response to {:#02x}", b);
+	}
+	::std::mem::replace(&mut *dev.guidev.events.borrow_mut(), ::std::vec::Vec::new())
+}
+
+#[test]
+fn pause_set1()
+{
+	let mut dev = test_dev(Type::MF2, 0x01);
+	assert_eq!( test_feed(&mut dev, &[0xE1, 0x1D, 0x45, 0xE1, 0x9D, 0xC5]), [(KeyCode::Pause, false), (KeyCode::Pause, true)] );
+	// Back to the base layer afterwards
+	assert_eq!( test_feed(&mut dev, &[0x1E, 0x9E]), [(KeyCode::A, false), (KeyCode::A, true)] );
+}
+#[test]
+fn pause_set2()
+{
+	let mut dev = test_dev(Type::MF2, 0x02);
+	assert_eq!( test_feed(&mut dev, &[0xE1, 0x14, 0x77, 0xE1, 0xF0, 0x14, 0xF0, 0x77]), [(KeyCode::Pause, false), (KeyCode::Pause, true)] );
+	// - Not Numlock (0x77) or LeftCtrl (0x14)
+	assert_eq!( test_feed(&mut dev, &[0x1C, 0xF0, 0x1C]), [(KeyCode::A, false), (KeyCode::A, true)] );
+}
+#[test]
+fn print_screen_fake_shifts()
+{
+	let mut dev = test_dev(Type::MF2Emul, 0x41);
+	assert_eq!( test_feed(&mut dev, &[0xE0, 0x2A, 0xE0, 0x37, 0xE0, 0xB7, 0xE0, 0xAA]), [(KeyCode::PrintScreen, false), (KeyCode::PrintScreen, true)] );
+
+	let mut dev = test_dev(Type::MF2, 0x02);
+	assert_eq!( test_feed(&mut dev, &[0xE0, 0x12, 0xE0, 0x7C, 0xE0, 0xF0, 0x7C, 0xE0, 0xF0, 0x12]), [(KeyCode::PrintScreen, false), (KeyCode::PrintScreen, true)] );
+}
+#[test]
+fn set1_release_codes_not_responses()
+{
+	let mut dev = test_dev(Type::MF2, 0x01);
+	// 0xAA = LeftShift (0x2A) release, not a self-test pass
+	assert_eq!( test_feed(&mut dev, &[0x2A, 0xAA]), [(KeyCode::LeftShift, false), (KeyCode::LeftShift, true)] );
+	// 0xFD = 0x7D release, not a self-test failure
+	assert_eq!( test_feed(&mut dev, &[0x7D, 0xFD]), [(KeyCode::KbInt3, false), (KeyCode::KbInt3, true)] );
+	// 0xEE = F23 (0x6E) release, not an echo reply
+	assert_eq!( test_feed(&mut dev, &[0x6E, 0xEE]), [(KeyCode::F23, false), (KeyCode::F23, true)] );
+	// 0xFC = release of the unmapped 0x7C, so nothing is generated (and the next code still decodes)
+	assert_eq!( test_feed(&mut dev, &[0xFC, 0x1E]), [(KeyCode::A, false)] );
+
+	// In set 2 they are controller responses
+	let mut dev = test_dev(Type::MF2, 0x02);
+	assert!( test_feed(&mut dev, &[0xAA, 0xFC, 0xFD, 0xEE]).is_empty() );
+}
+#[test]
+fn typematic_repeat_dropped()
+{
+	let mut dev = test_dev(Type::MF2, 0x02);
+	assert_eq!( test_feed(&mut dev, &[0x1C, 0x1C, 0x1C, 0xF0, 0x1C]), [(KeyCode::A, false), (KeyCode::A, true)] );
+}
+#[test]
+fn set_leds()
+{
+	let mut dev = test_dev(Type::MF2, 0x02);
+	// Caps Lock press changes the GUI's state, which is sent once the command is ACKed
+	dev.guidev.leds.set(gui_keyboard::LED_CAPS_LOCK);
+	assert_eq!( dev.recv_byte(0x58), Some(0xED) );
+	// - A key arriving before the ACK is still decoded
+	assert_eq!( dev.recv_byte(0xF0), None );
+	assert_eq!( dev.recv_byte(0x58), None );
+	assert_eq!( dev.recv_byte(0xFA), Some(1 << 2) );
+	// - Changed again before the data ACK, so another command follows
+	dev.guidev.leds.set(gui_keyboard::LED_CAPS_LOCK | gui_keyboard::LED_NUM_LOCK);
+	assert_eq!( dev.recv_byte(0xFA), Some(0xED) );
+	assert_eq!( dev.recv_byte(0xFE), Some(0xED) );
+	assert_eq!( dev.recv_byte(0xFA), Some((1 << 2) | (1 << 1)) );
+	assert_eq!( dev.recv_byte(0xFA), None );
+	assert_eq!( test_feed(&mut dev, &[]), [(KeyCode::Caps, false), (KeyCode::Caps, true)] );
+}
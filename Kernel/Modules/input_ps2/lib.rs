@@ -7,6 +7,8 @@
 #![feature(const_fn)]	// needed for lazystatic_init
 #![no_std]
 
+#[cfg(test)] #[macro_use] extern crate /**/ std;
+
 #[macro_use]
 extern crate kernel;
 
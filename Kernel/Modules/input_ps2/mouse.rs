@@ -3,7 +3,10 @@
 //
 // Modules/input_ps2/mouse.rs
 //! PS2 Mouse driver
+#[cfg(not(test))]
 use gui::input::mouse as gui_mouse;
+#[cfg(test)]
+use self::test_gui as gui_mouse;
 
 #[derive(Debug)]
 pub enum Type
@@ -13,15 +16,31 @@ pub enum Type
 	QuintBtn,	// 5 buttons
 }
 
+/// Stages of mouse initialisation, each is a sequence of command bytes (each ACKed by the mouse)
+#[derive(Copy,Clone,Debug)]
+enum InitStage
+{
+	/// Reset to a known configuration
+	Defaults,
+	/// IntelliMouse magic (enables the scroll wheel), followed by an identify
+	ScrollMagic,
+	/// IntelliMouse Explorer magic (enables buttons 4/5), followed by an identify
+	QuintMagic,
+	/// Restore the sample rate and enable reporting
+	Enable,
+}
+
 #[derive(Debug)]
 enum State
 {
-	Expect(usize),
+	/// Running an init stage, with the number of bytes sent
+	Init(InitStage, usize),
+	/// Waiting for the ID byte following the identify at the end of a stage
+	Ident(InitStage),
 	Idle,
-	// TODO: Initialise mouse to have a know config
-	// TODO: Support magic to switch types up to scroll / five-button
 	WaitByte2(u8),
 	WaitByte3(u8,u8),
+	WaitByte4(u8,u8,u8),
 }
 
 #[derive(Debug)]
@@ -33,27 +52,96 @@ pub struct Dev
 	btns: u8,
 }
 
+impl InitStage
+{
+	fn commands(&self) -> &'static [u8] {
+		match *self
+		{
+		// Set Defaults (100 samples/s, 4 counts/mm, stream mode, reporting disabled)
+		InitStage::Defaults => &[0xF6],
+		// Set Sample Rate 200, 100, 80 then Identify
+		InitStage::ScrollMagic => &[0xF3,200, 0xF3,100, 0xF3,80, 0xF2],
+		// Set Sample Rate 200, 200, 80 then Identify
+		InitStage::QuintMagic => &[0xF3,200, 0xF3,200, 0xF3,80, 0xF2],
+		// Set Sample Rate 100, Enable Reporting
+		InitStage::Enable => &[0xF3,100, 0xF4],
+		}
+	}
+}
+
 impl Dev
 {
 	pub fn new(ty: Type) -> (Option<u8>,Dev) {
-		// Enable scanning
-		(Some(0xF4), Dev {
+		let mut rv = Dev {
 			ty: ty,
-			state: State::Expect(0),
+			state: State::Idle,
 			guidev: gui_mouse::Instance::new(),
 			btns: 0x00,
-			})
+			};
+		(rv.start_stage(InitStage::Defaults), rv)
+	}
+
+	fn start_stage(&mut self, stage: InitStage) -> Option<u8> {
+		self.state = State::Init(stage, 1);
+		Some(stage.commands()[0])
 	}
 	
 	pub fn recv_byte(&mut self, byte: u8) -> Option<u8> {
 		let (rv, ns) = match self.state
 			{
-			State::Expect(extra) =>
-				if extra == 0 {
-					(None, State::Idle)
+			State::Init(stage, sent) => {
+				let cmds = stage.commands();
+				match byte
+				{
+				// ACK - Send the next byte, or move on once the stage is complete
+				0xFA =>
+					if sent < cmds.len() {
+						(Some(cmds[sent]), State::Init(stage, sent+1))
+					}
+					else if cmds[sent-1] == 0xF2 {
+						(None, State::Ident(stage))
+					}
+					else {
+						match stage
+						{
+						InitStage::Defaults => return self.start_stage(InitStage::ScrollMagic),
+						_ => {
+							log_debug!("Mouse ready, {:?}", self.ty);
+							(None, State::Idle)
+							},
+						}
+					},
+				// Resend
+				0xFE => (Some(cmds[sent-1]), State::Init(stage, sent)),
+				_ =>
+					match stage
+					{
+					InitStage::Enable => {
+						log_warning!("Unexpected byte {:#02x} enabling mouse", byte);
+						(None, State::Idle)
+						},
+					// Give up on the extensions, and just enable reporting
+					_ => {
+						log_notice!("Unexpected byte {:#02x} during mouse init {:?}, using {:?}", byte, stage, self.ty);
+						return self.start_stage(InitStage::Enable);
+						},
+					},
+				}
+				},
+			State::Ident(stage) => {
+				match byte
+				{
+				0x00 => {},
+				0x03 => self.ty = Type::Scroll,
+				0x04 => self.ty = Type::QuintBtn,
+				_ => log_notice!("Unexpected mouse ID {:#02x}", byte),
+				}
+				// Only a scroll mouse can be switched to five-button mode
+				match (stage, byte)
+				{
+				(InitStage::ScrollMagic, 0x03) => return self.start_stage(InitStage::QuintMagic),
+				_ => return self.start_stage(InitStage::Enable),
 				}
-				else {
-					(None, State::Expect(extra-1))
 				},
 			State::Idle =>
 				if byte & 0x08 != 0 {
@@ -64,31 +152,17 @@ impl Dev
 				},
 			State::WaitByte2(b1) =>
 				(None, State::WaitByte3(b1, byte)),
-			State::WaitByte3(b1, b2) => {
-				assert!(is!(self.ty, Type::Std));
-				let newbtns = b1 & 0b111;
-				let dx = Self::get_signed_9( ((b1 >> 6) & 1) != 0, ((b1 >> 4) & 1) != 0, b2 );
-				let dy = Self::get_signed_9( ((b1 >> 7) & 1) != 0, ((b1 >> 5) & 1) != 0, byte );
-				log_trace!("btns = {:#x}, (dx,dy) = ({},{})", newbtns, dx, dy);
-
-				if dx != 0 || dy != 0 {
-					self.guidev.move_cursor(dx, -dy);
-				}
-				let changed = newbtns ^ self.btns;
-				if changed != 0 {
-					for i in 0 .. 8 {
-						let mask = 1 << i;
-						if (changed & mask) != 0 {
-							if (newbtns & mask) != 0 {
-								self.guidev.press_button(i as u8);
-							}
-							else {
-								self.guidev.release_button(i as u8);
-							}
-						}
-					}
-				}
-				self.btns = newbtns;
+			State::WaitByte3(b1, b2) =>
+				match self.ty
+				{
+				Type::Std => {
+					self.handle_packet(b1, b2, byte, 0);
+					(None, State::Idle)
+					},
+				_ => (None, State::WaitByte4(b1, b2, byte)),
+				},
+			State::WaitByte4(b1, b2, b3) => {
+				self.handle_packet(b1, b2, b3, byte);
 				(None, State::Idle)
 				},
 			};
@@ -97,6 +171,44 @@ impl Dev
 		rv
 	}
 
+	fn handle_packet(&mut self, b1: u8, b2: u8, b3: u8, b4: u8)
+	{
+		// Byte 4 holds the wheel movement (and extra buttons on five-button mice)
+		let (dz, extra_btns) = match self.ty
+			{
+			Type::Std => (0, 0),
+			Type::Scroll => (b4 as i8 as i16, 0),
+			Type::QuintBtn => (((b4 << 4) as i8 >> 4) as i16, (b4 >> 4) & 0b11),
+			};
+		let newbtns = (b1 & 0b111) | (extra_btns << 3);
+		let dx = Self::get_signed_9( ((b1 >> 6) & 1) != 0, ((b1 >> 4) & 1) != 0, b2 );
+		let dy = Self::get_signed_9( ((b1 >> 7) & 1) != 0, ((b1 >> 5) & 1) != 0, b3 );
+		log_trace!("btns = {:#x}, (dx,dy,dz) = ({},{},{})", newbtns, dx, dy, dz);
+
+		if dx != 0 || dy != 0 {
+			self.guidev.move_cursor(dx, -dy);
+		}
+		// - Positive Z is the wheel rolled towards the user (scroll down)
+		if dz != 0 {
+			self.guidev.scroll(0, dz);
+		}
+		let changed = newbtns ^ self.btns;
+		if changed != 0 {
+			for i in 0 .. 8 {
+				let mask = 1 << i;
+				if (changed & mask) != 0 {
+					if (newbtns & mask) != 0 {
+						self.guidev.press_button(i as u8);
+					}
+					else {
+						self.guidev.release_button(i as u8);
+					}
+				}
+			}
+		}
+		self.btns = newbtns;
+	}
+
 
 	fn get_signed_9(overflow: bool, sign: bool, val: u8) -> i16 {
 		if sign {
@@ -118,4 +230,120 @@ impl Dev
 	}
 }
 
+/// Stand-in for the GUI mouse handle, recording events
+#[cfg(test)]
+mod test_gui {
+	#[derive(Debug,PartialEq)]
+	pub enum Event {
+		Move(i16, i16),
+		Scroll(i16, i16),
+		Press(u8),
+		Release(u8),
+	}
+	#[derive(Default,Debug)]
+	pub struct Instance {
+		pub events: ::std::cell::RefCell<::std::vec::Vec<Event>>,
+	}
+	impl Instance {
+		pub fn new() -> Instance {
+			Default::default()
+		}
+		pub fn move_cursor(&self, dx: i16, dy: i16) {
+			self.events.borrow_mut().push( Event::Move(dx, dy) );
+		}
+		pub fn press_button(&self, btn: u8) {
+			self.events.borrow_mut().push( Event::Press(btn) );
+		}
+		pub fn release_button(&self, btn: u8) {
+			self.events.borrow_mut().push( Event::Release(btn) );
+		}
+		pub fn scroll(&self, dx: i16, dy: i16) {
+			self.events.borrow_mut().push( Event::Scroll(dx, dy) );
+		}
+	}
+}
+
+/// Run the init sequence, with the mouse answering each identify with the next of `ids`
+#[cfg(test)]
+fn test_dev(ids: &[u8]) -> Dev
+{
+	let (mut b, mut dev) = Dev::new(Type::Std);
+	let mut ids_it = ids.iter();
+	let mut sent = ::std::vec::Vec::new();
+	while let Some(v) = b {
+		sent.push(v);
+		b = dev.recv_byte(0xFA);
+		if b.is_none() && v == 0xF2 {
+			b = dev.recv_byte(*ids_it.next().expect("Too many identifies"));
+		}
+	}
+	assert!(ids_it.next().is_none(), "Too few identifies");
+	assert!(is!(dev.state, State::Idle));
+	let mut exp = vec![0xF6];
+	exp.extend_from_slice(InitStage::ScrollMagic.commands());
+	// - Only a scroll mouse is asked to enable the extra buttons
+	if ids.len() > 1 {
+		exp.extend_from_slice(InitStage::QuintMagic.commands());
+	}
+	exp.extend_from_slice(InitStage::Enable.commands());
+	assert_eq!(sent, exp);
+	dev
+}
+/// Feed bytes to the mouse, returning the events generated
+#[cfg(test)]
+fn test_feed(dev: &mut Dev, bytes: &[u8]) -> ::std::vec::Vec<test_gui::Event>
+{
+	for &b in bytes {
+		assert_eq!(dev.recv_byte(b), None, "Unexpected response to {:#02x}", b);
+	}
+	::std::mem::replace(&mut *dev.guidev.events.borrow_mut(), ::std::vec::Vec::new())
+}
+
+#[test]
+fn init_types()
+{
+	assert!( is!(test_dev(&[0x00]).ty, Type::Std) );
+	assert!( is!(test_dev(&[0x03, 0x03]).ty, Type::Scroll) );
+	assert!( is!(test_dev(&[0x03, 0x04]).ty, Type::QuintBtn) );
+}
+#[test]
+fn init_failure()
+{
+	// An error response during the magic sequence skips straight to enabling reporting
+	let (_, mut dev) = Dev::new(Type::Std);
+	assert_eq!( dev.recv_byte(0xFA), Some(0xF3) );
+	assert_eq!( dev.recv_byte(0xFE), Some(0xF3) );
+	assert_eq!( dev.recv_byte(0xFC), Some(0xF3) );
+	assert_eq!( dev.recv_byte(0xFA), Some(100) );
+	assert_eq!( dev.recv_byte(0xFA), Some(0xF4) );
+	assert_eq!( dev.recv_byte(0xFA), None );
+	assert!( is!(dev.ty, Type::Std) );
+}
+#[test]
+fn std_packets()
+{
+	use self::test_gui::Event;
+	let mut dev = test_dev(&[0x00]);
+	// Left button, +X, -Y (reported as +Y to the GUI)
+	assert_eq!( test_feed(&mut dev, &[0x09 | 0x20, 5, 0xFE]), [Event::Move(5, 2), Event::Press(0)] );
+	// - Bytes without the always-set bit are dropped to resync
+	assert_eq!( test_feed(&mut dev, &[0x00, 0x08, 0, 0]), [Event::Release(0)] );
+	// Overflow saturates
+	assert_eq!( test_feed(&mut dev, &[0x08 | 0x40 | 0x10, 0x12, 0]), [Event::Move(-256, 0)] );
+	// Right and middle
+	assert_eq!( test_feed(&mut dev, &[0x0E, 0, 0, 0x08, 0, 0]), [Event::Press(1), Event::Press(2), Event::Release(1), Event::Release(2)] );
+}
+#[test]
+fn wheel_packets()
+{
+	use self::test_gui::Event;
+	let mut dev = test_dev(&[0x03, 0x03]);
+	assert_eq!( test_feed(&mut dev, &[0x08, 0, 0, 0x01]), [Event::Scroll(0, 1)] );
+	assert_eq!( test_feed(&mut dev, &[0x08, 0, 0, 0xFF]), [Event::Scroll(0, -1)] );
 
+	let mut dev = test_dev(&[0x03, 0x04]);
+	// Fourth byte is a 4-bit Z, with buttons 4/5 in the upper bits
+	assert_eq!( test_feed(&mut dev, &[0x08, 0, 0, 0x1F]), [Event::Scroll(0, -1), Event::Press(3)] );
+	assert_eq!( test_feed(&mut dev, &[0x08, 0, 0, 0x22]), [Event::Scroll(0, 2), Event::Release(3), Event::Press(4)] );
+	assert_eq!( test_feed(&mut dev, &[0x08, 0, 0, 0x00]), [Event::Release(4)] );
+}
@@ -21,6 +21,7 @@ pub struct ControllerInner
 	pub io_base: device_manager::IOBinding,
 	pub max_commands: u8,
 	pub supports_64bit: bool,
+	pub supports_ncq: bool,
 }
 
 impl Controller
@@ -31,7 +32,7 @@ impl Controller
 		// Enumerate implemented ports
 		let ports_implemented;
 		// SAFE: Enumerate access to hardware
-		let (n_ports, max_commands, supports_64bit) = unsafe {
+		let (n_ports, max_commands, supports_64bit, supports_ncq) = unsafe {
 			io.write_32(hw::REG_GHC, hw::GHC_AE);
 			ports_implemented = io.read_32(hw::REG_PI);
 			
@@ -55,9 +56,10 @@ impl Controller
 
 			let capabilities = io.read_32(hw::REG_CAP);
 			let supports_64bit = capabilities & hw::CAP_S64A != 0;
+			let supports_ncq = capabilities & hw::CAP_SNCQ != 0;
 			let max_commands = ((capabilities & hw::CAP_NCS) >> hw::CAP_NCS_ofs) + 1;
 			
-			(n_ports, max_commands, supports_64bit,)
+			(n_ports, max_commands, supports_64bit, supports_ncq,)
 			};
 		
 		// Construct controller structure
@@ -66,6 +68,7 @@ impl Controller
 			inner: unsafe {ArefInner::new(ControllerInner {
 				io_base: io,
 				supports_64bit: supports_64bit,
+				supports_ncq: supports_ncq,
 				max_commands: max_commands as u8,
 				}) },
 			ports: Vec::with_capacity(n_ports),
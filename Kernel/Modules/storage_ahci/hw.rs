@@ -94,6 +94,20 @@ pub const PxSSTS_SPD_ofs: usize = 4;
 pub const PxSSTS_DET: u32 = (15 << 0);	// Device Detection (0: None, 1: Present but no PHY yet, 3: Present and PHY, 4: offline)
 pub const PxSSTS_DET_ofs: usize = 0;
 
+pub const PxSCTL_DET: u32 = (15 << 0);	// Device Detection Initialisation (1 = Perform COMRESET)
+pub const PxSCTL_DET_ofs: usize = 0;
+
+// ATA commands that are translated to NCQ (FPDMA) equivalents
+pub const ATA_READ_DMA: u8 = 0xC8;
+pub const ATA_WRITE_DMA: u8 = 0xCA;
+pub const ATA_READ_DMA_EXT: u8 = 0x25;
+pub const ATA_WRITE_DMA_EXT: u8 = 0x35;
+pub const ATA_READ_FPDMA_QUEUED: u8 = 0x60;
+pub const ATA_WRITE_FPDMA_QUEUED: u8 = 0x61;
+// NCQ error recovery
+pub const ATA_READ_LOG_EXT: u8 = 0x2F;
+pub const LOG_NCQ_COMMAND_ERROR: u8 = 0x10;
+
 #[repr(C)]
 pub struct CmdHeader
 {
@@ -3,9 +3,9 @@
 //
 //! 
 use kernel::prelude::*;
-use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicBool, Ordering};
 use kernel::sync::atomic::AtomicU32;
-use kernel::sync::Mutex;
+use kernel::sync::{Mutex, RwLock, Spinlock};
 use kernel::metadevs::storage::{self, DataPtr};
 use kernel::memory::virt::AllocHandle;
 use kernel::lib::mem::aref::ArefBorrow;
@@ -17,6 +17,12 @@ enum Error
 	Ata { err: u8, sts: u8 },
 	Atapi { sense_key: ::storage_scsi::proto::SenseKey, eom: bool, ili: bool },
 	Bus,
+	/// Command was terminated by a port reset caused by another command's error
+	Aborted,
+	/// Unable to allocate a bounce buffer
+	NoMemory,
+	/// Command didn't complete in time (the port was reset)
+	Timeout,
 }
 impl_fmt! {
 	Debug(self,f) for Error {
@@ -27,6 +33,35 @@ impl_fmt! {
 			),
 		&Error::Atapi { sense_key, eom, ili } => write!(f, "Atapi(sense_key={:?},eom={},ili={})", sense_key, eom, ili),
 		&Error::Bus => write!(f, "Bus"),
+		&Error::Aborted => write!(f, "Aborted"),
+		&Error::NoMemory => write!(f, "NoMemory"),
+		&Error::Timeout => write!(f, "Timeout"),
+		}
+	}
+}
+
+impl_from! {
+	From<Error>(v) for storage::IoError {
+		match v
+		{
+		Error::Ata { err, .. } => ::storage_ata::volume::Error::from(err).into(),
+		Error::Atapi { sense_key: ::storage_scsi::proto::SenseKey::NotReady, .. } => storage::IoError::NoMedium,
+		Error::Atapi { sense_key: ::storage_scsi::proto::SenseKey::UnitAttention, .. } => storage::IoError::MediaChanged,
+		Error::Atapi { sense_key: ::storage_scsi::proto::SenseKey::MediumError, .. } => storage::IoError::BadBlock,
+		Error::Atapi { sense_key: ::storage_scsi::proto::SenseKey::IllegalRequest, .. } => storage::IoError::InvalidParameter,
+		Error::Atapi { sense_key: ::storage_scsi::proto::SenseKey::DataProtect, .. } => storage::IoError::ReadOnly,
+		Error::Atapi { .. } => storage::IoError::Unknown("ATAPI"),
+		Error::Bus => storage::IoError::Unknown("AHCI bus error"),
+		Error::Aborted => storage::IoError::Unknown("AHCI command aborted"),
+		Error::NoMemory => storage::IoError::Unknown("AHCI bounce buffer allocation failed"),
+		Error::Timeout => storage::IoError::Timeout,
+		}
+	}
+	From<Error>(v) for ::storage_ata::volume::Error {
+		match v
+		{
+		Error::Ata { err, .. } => ::storage_ata::volume::Error::Ata(err),
+		v @ _ => ::storage_ata::volume::Error::Io(v.into()),
 		}
 	}
 }
@@ -51,7 +86,53 @@ pub struct Port
 
 	used_commands_sem: ::kernel::sync::Semaphore,
 	used_commands: AtomicU32,
+
+	/// Per-slot completion status (CMD_*)
+	command_status: Vec<AtomicU32>,
+	/// Commands issued to the hardware that haven't been completed/failed yet
+	issued_commands: AtomicU32,
+	/// Protects `issued_commands` against the IRQ handler while a command is being issued
+	issue_lock: Spinlock<()>,
+	/// Task file data captured when the last error was raised
+	error_tfd: AtomicU32,
+
+	/// NCQ depth supported by the attached device (zero if NCQ isn't usable)
+	ncq_depth: AtomicU32,
+	/// Limits the number of outstanding NCQ commands to the device's queue depth
+	ncq_sem: ::kernel::sync::Semaphore,
+	/// Queued (NCQ) commands hold this shared, non-queued commands hold it exclusively
+	ncq_gate: RwLock<()>,
+
+	/// Set by the IRQ handler when the port has stopped due to an error
+	needs_recovery: AtomicU32,
+	/// Recovery must reset the link (a command timed out)
+	force_reset: AtomicBool,
+	/// A queued command failed, recovery must read the NCQ error log to find (and clear) it
+	ncq_error: AtomicBool,
+	/// Serialises port recovery against command issue
+	recovery_lock: Mutex<()>,
 }
+
+// Values for `command_status`
+const CMD_PENDING: u32 = 0;
+const CMD_DONE: u32 = 1;
+/// Device reported an error (see `error_tfd`)
+const CMD_FAILED: u32 = 2;
+/// Host bus/interface error
+const CMD_BUS_ERROR: u32 = 3;
+/// Terminated as part of recovery
+const CMD_ABORTED: u32 = 4;
+
+/// Interrupt status bits that stop the port's command engine
+const IS_ERROR_MASK: u32 = hw::PxIS_TFES|hw::PxIS_HBFS|hw::PxIS_HBDS|hw::PxIS_IFS;
+/// Timeout for the command engine to stop (from the AHCI spec)
+const ENGINE_STOP_TIMEOUT_MS: u64 = 500;
+/// Timeout for the link to come back after a COMRESET
+const COMRESET_TIMEOUT_MS: u64 = 1000;
+/// Time allowed for a command to complete before the port is reset
+const COMMAND_TIMEOUT_MS: u64 = 30*1000;
+/// Timeout for reading the NCQ error log during recovery
+const READ_LOG_TIMEOUT_MS: u64 = 1000;
 pub struct PortRegs<'a>
 {
 	idx: usize,
@@ -122,7 +203,7 @@ impl Port
 			// Interrupts on
 			regs.write(hw::REG_PxSERR, 0x3FF783);
 			regs.write(hw::REG_PxIS, !0);
-			regs.write(hw::REG_PxIE, hw::PxIS_CPDS|hw::PxIS_DSS|hw::PxIS_PSS|hw::PxIS_DHRS|hw::PxIS_TFES|hw::PxIS_IFS);
+			regs.write(hw::REG_PxIE, hw::PxIS_CPDS|hw::PxIS_DSS|hw::PxIS_PSS|hw::PxIS_DHRS|hw::PxIS_SDBS|IS_ERROR_MASK);
 			// Start command engine (Start, FIS Rx Enable)
 			let cmd = regs.read(hw::REG_PxCMD);
 			regs.write(hw::REG_PxCMD, cmd|hw::PxCMD_ST|hw::PxCMD_FRE);
@@ -142,6 +223,20 @@ impl Port
 			command_events: (0 .. max_commands).map(|_| ::kernel::sync::EventChannel::new()).collect(),
 			used_commands_sem: ::kernel::sync::Semaphore::new(max_commands as isize, max_commands as isize),
 			used_commands: AtomicU32::new(0),
+
+			command_status: (0 .. max_commands).map(|_| AtomicU32::new(CMD_DONE)).collect(),
+			issued_commands: AtomicU32::new(0),
+			issue_lock: Spinlock::new( () ),
+			error_tfd: AtomicU32::new(0),
+
+			ncq_depth: AtomicU32::new(0),
+			ncq_sem: ::kernel::sync::Semaphore::new(0, max_commands as isize),
+			ncq_gate: RwLock::new( () ),
+
+			needs_recovery: AtomicU32::new(0),
+			force_reset: AtomicBool::new(false),
+			ncq_error: AtomicBool::new(false),
+			recovery_lock: Mutex::new( () ),
 			})
 	}
 	
@@ -150,12 +245,13 @@ impl Port
 		use core::mem::size_of;
 		let max_commands = controller.max_commands as usize;
 		let cl_size = max_commands * size_of::<hw::CmdHeader>();
+		let bits = if controller.supports_64bit { 64 } else { 32 };
 
 		// Command list
 		// - Command list first (32 * max_commands)
 		// - Up to MAX_COMMANDS_FOR_SHARE in 1024 -- 4096-256
 		// - RcvdFis last
-		let cl_page = try!( ::kernel::memory::virt::alloc_dma(64, 1, "AHCI") );
+		let cl_page = try!( ::kernel::memory::virt::alloc_dma(bits, 1, "AHCI") );
 
 		// Allocate pages for the command table
 		// TODO: Delay allocating memory until a device is detected on this port
@@ -172,7 +268,7 @@ impl Port
 				assert!(n_pages < 4);
 				for i in 0 .. n_pages
 				{
-					tab_pages[i] = try!( ::kernel::memory::virt::alloc_dma(64, 1, "AHCI") );
+					tab_pages[i] = try!( ::kernel::memory::virt::alloc_dma(bits, 1, "AHCI") );
 				}
 				tab_pages
 			};
@@ -208,13 +304,6 @@ impl Port
 		}
 
 
-		// "Task File Error Status"
-		if int_status & hw::PxIS_TFES != 0
-		{
-			log_warning!("{} - Device pushed error: TFD={:#x}", self, tfd);
-			// TODO: This should terminate all outstanding transactions with an error.
-		}
-
 		// Device->Host Register Update
 		if int_status & hw::PxIS_DHRS != 0
 		{
@@ -226,40 +315,44 @@ impl Port
 			log_trace!("{} - PIO setup status update, PSFIS={:?}", self, self.get_rcvd_fis().PSFIS);
 		}
 
-		if int_status & hw::PxIS_IFS != 0
-		{
-		}
-
-		// Check commands
-		//if int_status & hw::PxIS_DPS != 0
-		//{
-		let issued_commands = regs.read(hw::REG_PxCI);
-		let active_commands = regs.read(hw::REG_PxSACT);
-		let used_commands = self.used_commands.load(Ordering::Relaxed);
-		//log_trace!("{} - used_commands = {:#x}, issued_commands={:#x}, active_commands={:#x}",
-		//	self, used_commands, issued_commands, active_commands);
-		for cmd in 0 .. self.ctrlr.max_commands as usize
+		let _lh = self.issue_lock.lock();
+		if int_status & IS_ERROR_MASK != 0
 		{
-			let mask = 1 << cmd;
-			if used_commands & mask != 0
-			{
-				if tfd & 0x01 != 0 {
-					self.command_events[cmd].post();
+			// The command engine has stopped, so terminate everything that was outstanding.
+			// - The port is restarted by `recover` (in thread context) before the next command is issued
+			let serr = regs.read(hw::REG_PxSERR);
+			log_warning!("{} - Port error: IS={:#x} TFD={:#x} SERR={:#x}", self, int_status, tfd, serr);
+			self.error_tfd.store(tfd, Ordering::Relaxed);
+			self.needs_recovery.store(1, Ordering::Release);
+
+			// If the device signalled an error for a non-queued command, it's the one that failed. With NCQ the failing
+			// command is found by `recover` (from the NCQ error log), the rest are aborted (and retried without queuing)
+			let ncq_active = regs.read(hw::REG_PxSACT) != 0;
+			let status = if int_status & hw::PxIS_TFES == 0 {
+					CMD_BUS_ERROR
 				}
-				else if issued_commands & mask == 0 || active_commands & mask == 0 {
-					self.command_events[cmd].post();
+				else if ncq_active {
+					self.ncq_error.store(true, Ordering::Relaxed);
+					CMD_ABORTED
 				}
 				else {
-					// Not yet complete
-				}
-			}
-			else if active_commands & mask != 0	{
-				log_warning!("{} - Command {} active, but not used", self, cmd);
-			}
-			else {
+					CMD_FAILED
+				};
+			let failed = self.issued_commands.swap(0, Ordering::Acquire);
+			self.complete_commands(failed, status);
+		}
+		else
+		{
+			// Commands are complete once they're no longer issued (CI) or active (SACT, for NCQ)
+			let outstanding = regs.read(hw::REG_PxCI) | regs.read(hw::REG_PxSACT);
+			let issued = self.issued_commands.load(Ordering::Acquire);
+			let complete = issued & !outstanding;
+			if complete != 0
+			{
+				self.issued_commands.store(issued & !complete, Ordering::Release);
+				self.complete_commands(complete, CMD_DONE);
 			}
 		}
-		//}
 	
 		// SAFE: Exclusive range, only written here
 		unsafe {
@@ -267,6 +360,175 @@ impl Port
 		}
 	}
 
+	/// Set the status of all commands in `mask` and wake their waiters
+	fn complete_commands(&self, mask: u32, status: u32)
+	{
+		for cmd in 0 .. self.ctrlr.max_commands as usize
+		{
+			if mask & (1 << cmd) != 0
+			{
+				self.command_status[cmd].store(status, Ordering::Release);
+				self.command_events[cmd].post();
+			}
+		}
+	}
+
+	/// Restart the port if it has stopped due to an error
+	fn recover(&self)
+	{
+		let _lh = self.recovery_lock.lock();
+		self.recover_locked();
+	}
+	fn recover_locked(&self)
+	{
+		if self.needs_recovery.load(Ordering::Acquire) == 0 {
+			return ;
+		}
+		log_notice!("{} - Recovering from error", self);
+		let regs = self.regs();
+
+		// SAFE: Recovery lock held, no commands can be issued
+		unsafe
+		{
+			// 1. Stop the command engine (clears PxCI and PxSACT)
+			let cmd = regs.read(hw::REG_PxCMD);
+			regs.write(hw::REG_PxCMD, cmd & !hw::PxCMD_ST);
+			if !self.poll_until(ENGINE_STOP_TIMEOUT_MS, || regs.read(hw::REG_PxCMD) & hw::PxCMD_CR == 0) {
+				log_error!("{} - Command engine didn't stop", self);
+			}
+
+			// 2. Fail anything issued after the error was raised
+			{
+				let _lh = self.issue_lock.lock_irqsafe();
+				let stale = self.issued_commands.swap(0, Ordering::Acquire);
+				self.complete_commands(stale, CMD_ABORTED);
+			}
+
+			// 3. Clear error state
+			regs.write(hw::REG_PxSERR, !0);
+			regs.write(hw::REG_PxIS, IS_ERROR_MASK);
+
+			// 4. If the device is still busy (or a command timed out), reset the link (COMRESET)
+			let ncq_error = self.ncq_error.swap(false, Ordering::Relaxed);
+			if self.force_reset.swap(false, Ordering::Relaxed) {
+				log_notice!("{} - Command timed out, issuing COMRESET", self);
+				self.comreset();
+			}
+			else if regs.read(hw::REG_PxTFD) & (hw::PxTFD_STS_BSY|hw::PxTFD_STS_DRQ) != 0 {
+				log_notice!("{} - Device busy after error, issuing COMRESET", self);
+				self.comreset();
+			}
+			// - After an NCQ error, the device won't accept commands until the error log has been read
+			else if ncq_error {
+				regs.write(hw::REG_PxCMD, regs.read(hw::REG_PxCMD) | hw::PxCMD_ST);
+				if !self.read_ncq_error_log() {
+					log_notice!("{} - Unable to read NCQ error log, issuing COMRESET", self);
+					let cmd = regs.read(hw::REG_PxCMD);
+					regs.write(hw::REG_PxCMD, cmd & !hw::PxCMD_ST);
+					self.poll_until(ENGINE_STOP_TIMEOUT_MS, || regs.read(hw::REG_PxCMD) & hw::PxCMD_CR == 0);
+					self.comreset();
+				}
+			}
+
+			// 5. Restart the command engine
+			let cmd = regs.read(hw::REG_PxCMD);
+			regs.write(hw::REG_PxCMD, cmd | hw::PxCMD_ST);
+		}
+
+		self.needs_recovery.store(0, Ordering::Release);
+	}
+
+	/// Reset the link to the device (COMRESET), command engine must be stopped
+	unsafe fn comreset(&self)
+	{
+		let regs = self.regs();
+		let sctl = regs.read(hw::REG_PxSCTL) & !hw::PxSCTL_DET;
+		regs.write(hw::REG_PxSCTL, sctl | (1 << hw::PxSCTL_DET_ofs));
+		// COMRESET must be asserted for at least 1ms
+		let end = ::kernel::time::ticks() + 2;
+		while ::kernel::time::ticks() < end {
+			::kernel::threads::yield_time();
+		}
+		regs.write(hw::REG_PxSCTL, sctl);
+		let linked = self.poll_until(COMRESET_TIMEOUT_MS, || (regs.read(hw::REG_PxSSTS) & hw::PxSSTS_DET) >> hw::PxSSTS_DET_ofs == 3);
+		let ready = linked && self.poll_until(COMRESET_TIMEOUT_MS, || regs.read(hw::REG_PxTFD) & (hw::PxTFD_STS_BSY|hw::PxTFD_STS_DRQ) == 0);
+		if !ready {
+			log_error!("{} - Device didn't return after COMRESET", self);
+		}
+		regs.write(hw::REG_PxSERR, !0);
+		regs.write(hw::REG_PxIS, IS_ERROR_MASK);
+	}
+
+	/// Read the NCQ Command Error log (READ LOG EXT, page 10h), marking the failed command. Returns false on failure
+	///
+	/// Reading the log clears the device's error state. Issued (polled) on the last command slot, which is reserved
+	/// for this as the NCQ depth is kept below the slot count (and only queued commands can be outstanding).
+	unsafe fn read_ncq_error_log(&self) -> bool
+	{
+		let regs = self.regs();
+		let max_commands = self.ctrlr.max_commands as usize;
+		let idx = max_commands - 1;
+
+		let bits = if self.ctrlr.supports_64bit { 64 } else { 32 };
+		let buf = match ::kernel::memory::virt::alloc_dma(bits, 1, "AHCI")
+			{
+			Ok(v) => v,
+			Err(_) => return false,
+			};
+		let fis = hw::sata::FisHost2DevReg {
+			ty: hw::sata::FisType::H2DRegister as u8,
+			flags: 0x80,
+			command: hw::ATA_READ_LOG_EXT,
+			sector_num: hw::LOG_NCQ_COMMAND_ERROR,
+			sector_count: 1,
+			dev_head: 0x40,
+			..Default::default()
+			};
+		let tab = &mut *self.get_cmdtab_ptr(idx);
+		let hdr = &mut self.command_list_alloc.as_int_mut_slice::<hw::CmdHeader>(0, max_commands)[idx];
+		tab.cmd_fis[..fis.as_ref().len()].clone_from_slice(fis.as_ref());
+		tab.prdt[0].dba = ::kernel::memory::virt::get_phys(buf.as_ref::<u8>(0)) as u64;
+		tab.prdt[0].dbc = 512 - 1;
+		hdr.prdtl = 1;
+		hdr.prdbc = 0;
+		hdr.flags = (fis.as_ref().len() / 4) as u16;
+
+		// - Not in `issued_commands`, so the IRQ handler ignores completion (errors set `needs_recovery`, checked below)
+		let mask = 1 << idx;
+		regs.write(hw::REG_PxCI, mask);
+		let done = self.poll_until(READ_LOG_TIMEOUT_MS, || regs.read(hw::REG_PxCI) & mask == 0 || regs.read(hw::REG_PxTFD) & hw::PxTFD_STS_ERR != 0);
+		if !done || regs.read(hw::REG_PxCI) & mask != 0 || regs.read(hw::REG_PxTFD) & hw::PxTFD_STS_ERR != 0 {
+			return false;
+		}
+
+		// Byte 0: [7] NQ (error was for a non-queued command), [4:0] tag. Bytes 2/3: Status/Error
+		let log = buf.as_slice::<u8>(0, 4);
+		if log[0] & 0x80 != 0 {
+			log_debug!("{} - NCQ error log: Non-queued command failed", self);
+			return true;
+		}
+		let tag = (log[0] & 0x1F) as usize;
+		log_debug!("{} - NCQ error log: Tag {} failed, status={:#x} error={:#x}", self, tag, log[2], log[3]);
+		if tag < max_commands && self.command_status[tag].compare_and_swap(CMD_ABORTED, CMD_FAILED, Ordering::Relaxed) == CMD_ABORTED {
+			self.error_tfd.store(log[2] as u32 | (log[3] as u32) << 8, Ordering::Relaxed);
+		}
+		true
+	}
+
+	/// Poll `cond` until it returns true, or the timeout expires (returning false)
+	fn poll_until<F: Fn()->bool>(&self, timeout_ms: u64, cond: F) -> bool
+	{
+		let end = ::kernel::time::ticks() + timeout_ms;
+		while !cond()
+		{
+			if ::kernel::time::ticks() > end {
+				return false;
+			}
+			::kernel::threads::yield_time();
+		}
+		true
+	}
+
 	fn get_rcvd_fis(&self) -> &hw::RcvdFis
 	{
 		self.command_list_alloc.as_ref::<hw::RcvdFis>( ::kernel::PAGE_SIZE - ::core::mem::size_of::<hw::RcvdFis>() )
@@ -322,17 +584,37 @@ impl Port
 			0x00000101 => {
 				// Request ATA Identify from the disk
 				const ATA_IDENTIFY: u8 = 0xEC;
-				let ident = self.request_identify(ATA_IDENTIFY).expect("Failure requesting ATA identify");
+				let ident = match self.request_identify(ATA_IDENTIFY)
+					{
+					Ok(v) => v,
+					Err(e) => {
+						log_error!("{}: Error requesting ATA identify: {:?}", self, e);
+						return ;
+						},
+					};
 
 				log_debug!("ATA `IDENTIFY` response data = {:?}", ident);
 				
 				let sectors = if ident.sector_count_48 == 0 { ident.sector_count_28 as u64 } else { ident.sector_count_48 };
 				log_log!("{}: Hard Disk, {} sectors, {}", self, sectors, storage::SizePrinter(sectors * 512));
 
+				// Native Command Queuing (needs both controller and device support)
+				// - The last command slot is reserved for error recovery (see `read_ncq_error_log`)
+				if self.ctrlr.supports_ncq && self.ctrlr.max_commands > 1 && ident.sata_capabilities & (1 << 8) != 0 && self.ncq_depth.load(Ordering::Relaxed) == 0
+				{
+					let depth = ::core::cmp::min( (ident.queue_depth & 0x1F) as usize + 1, self.ctrlr.max_commands as usize - 1 );
+					log_log!("{}: NCQ enabled, depth {}", self, depth);
+					for _ in 0 .. depth {
+						self.ncq_sem.release();
+					}
+					self.ncq_depth.store(depth as u32, Ordering::Release);
+				}
+
 				//*
 				match ::storage_ata::volume::AtaVolume::new_boxed( self.get_interface() )
 				{
-				Ok(vol) => Some(storage::register_pv(vol)),
+				// - Queued commands are issued in parallel (see `Interface::max_outstanding`)
+				Ok(vol) => Some(storage::register_concurrent_pv(vol)),
 				Err(e) => { log_error!("{}: Error while creating ATA device: {:?}", self, e); None },
 				}
 				// */
@@ -414,6 +696,42 @@ impl Port
 	{
 		log_trace!("request_ata_lba48(disk={}, cmd={:#02x}, n_sectors={}, lba={})", disk, cmd, n_sectors, lba);
 		assert!(lba < (1<<48));
+		let mut data = data;
+
+		// Use NCQ for DMA reads/writes if available
+		let ncq_cmd = match cmd
+			{
+			_ if !self.ncq_enabled() => None,
+			hw::ATA_READ_DMA_EXT => Some(hw::ATA_READ_FPDMA_QUEUED),
+			hw::ATA_WRITE_DMA_EXT => Some(hw::ATA_WRITE_FPDMA_QUEUED),
+			_ => None,
+			};
+		if let Some(ncq_cmd) = ncq_cmd
+		{
+			// The tag (in sector_count) is filled by `do_fis_int`, the count is in the features registers
+			let cmd_data = hw::sata::FisHost2DevReg {
+				ty: hw::sata::FisType::H2DRegister as u8,
+				flags: 0x80,
+				command: ncq_cmd,
+				features: n_sectors as u8,
+				features_exp: (n_sectors >> 8) as u8,
+				sector_num: lba as u8,
+				cyl_low: (lba >> 8) as u8,
+				cyl_high: (lba >> 16) as u8,
+				dev_head: 0x40 | (disk << 4),
+				sector_num_exp: (lba >> 24) as u8,
+				cyl_low_exp: (lba >> 32) as u8,
+				cyl_high_exp: (lba >> 40) as u8,
+				..Default::default()
+				};
+			match self.do_fis_int(cmd_data.as_ref(), &[], &mut data, true)
+			{
+			// Aborted because another queued command failed, retry un-queued to get the real result
+			Err(Error::Aborted) => log_debug!("{} - NCQ command aborted, retrying", self),
+			rv @ _ => return rv,
+			}
+		}
+
 		let cmd_data = hw::sata::FisHost2DevReg {
 			ty: hw::sata::FisType::H2DRegister as u8,
 			flags: 0x80,
@@ -429,7 +747,28 @@ impl Port
 			sector_count_exp: (n_sectors >> 8) as u8,
 			..Default::default()
 			};
-		self.do_fis(cmd_data.as_ref(), &[], data)
+		self.do_fis_int(cmd_data.as_ref(), &[], &mut data, false)
+	}
+	/// Issue a DMA read/write with a 28-bit address, using NCQ if the device supports it
+	fn request_ata_dma28(&self, disk: u8, cmd: u8,  n_sectors: u8, lba: u32, data: DataPtr) -> Result<usize, Error>
+	{
+		// NCQ commands take a 48-bit address, so translate to the equivalent LBA48 command (which will be queued)
+		let cmd48 = match cmd
+			{
+			_ if !self.ncq_enabled() => None,
+			hw::ATA_READ_DMA => Some(hw::ATA_READ_DMA_EXT),
+			hw::ATA_WRITE_DMA => Some(hw::ATA_WRITE_DMA_EXT),
+			_ => None,
+			};
+		match cmd48
+		{
+		// NOTE: A count of zero means 256 sectors for LBA28, but 65536 for LBA48
+		Some(cmd48) => self.request_ata_lba48(disk, cmd48, if n_sectors == 0 { 256 } else { n_sectors as u16 }, lba as u64, data),
+		None => self.request_ata_lba28(disk, cmd, n_sectors, lba, data),
+		}
+	}
+	fn ncq_enabled(&self) -> bool {
+		self.ncq_depth.load(Ordering::Relaxed) != 0
 	}
 	fn request_atapi(&self, disk: u8, cmd: &[u8], data: DataPtr) -> Result<(), Error>
 	{
@@ -449,22 +788,95 @@ impl Port
 		}
 	}
 
-	/// Create and dispatch a FIS, returns the number of bytes
+	/// Create and dispatch a (non-queued) FIS, returns the number of bytes
 	fn do_fis(&self, cmd: &[u8], pkt: &[u8], data: DataPtr) -> Result<usize, Error>
 	{
-		use kernel::memory::virt::get_phys;
-
+		let mut data = data;
+		self.do_fis_int(cmd, pkt, &mut data, false)
+	}
+	fn do_fis_int(&self, cmd: &[u8], pkt: &[u8], data: &mut DataPtr, queued: bool) -> Result<usize, Error>
+	{
 		//log_trace!("do_fis(self={}, cmd={:p}+{}, pkt={:p}+{}, data={:?})",
 		//	self, cmd.as_ptr(), cmd.len(), pkt.as_ptr(), pkt.len(), data);
 
-		let slot = self.get_command_slot();
+		// Queued and non-queued commands can't be mixed
+		let _gate = if queued { (Some(self.ncq_gate.read()), None) } else { (None, Some(self.ncq_gate.write())) };
+
+		let slot = self.get_command_slot(queued);
 
 		slot.data.cmd_fis[..cmd.len()].clone_from_slice(cmd);
 		slot.data.atapi_cmd[..pkt.len()].clone_from_slice(pkt);
+		if queued {
+			// NCQ tag goes in the sector count register
+			slot.data.cmd_fis[12] = slot.idx << 3;
+		}
+
+		// Generate the scatter-gather list, using a bounce buffer if the caller's buffer can't be used directly
+		let len = data.len();
+		let mut bounce = None;
+		let n_prdt_ents = match Self::fill_prdt(&mut slot.data.prdt, data.as_slice(), self.ctrlr.supports_64bit)
+			{
+			Some(n) => n,
+			None => {
+				// PRDT entries must be an even number of bytes, so round the bounce buffer up (the extra byte is ignored)
+				let bounce_len = (len + 1) & !1;
+				let bits = if self.ctrlr.supports_64bit { 64 } else { 32 };
+				let n_pages = (bounce_len + ::kernel::PAGE_SIZE - 1) / ::kernel::PAGE_SIZE;
+				let mut buf = match ::kernel::memory::virt::alloc_dma(bits, n_pages, "AHCI")
+					{
+					Ok(v) => v,
+					Err(_) => return Err(Error::NoMemory),
+					};
+				if let &mut DataPtr::Send(src) = data {
+					buf.as_mut_slice(0, len).clone_from_slice(src);
+				}
+				let n = match Self::fill_prdt(&mut slot.data.prdt, buf.as_slice(0, bounce_len), self.ctrlr.supports_64bit)
+					{
+					Some(n) => n,
+					None => {
+						log_error!("{} - Bounce buffer not usable for DMA ({} bytes)", self, bounce_len);
+						return Err(Error::NoMemory);
+						},
+					};
+				bounce = Some(buf);
+				n
+				},
+			};
+		if n_prdt_ents > 0 {
+			slot.data.prdt[n_prdt_ents-1].dbc |= 1 << 31;	// set IOC
+		}
+		slot.hdr.prdtl = n_prdt_ents as u16;
+		slot.hdr.prdbc = 0;
+		slot.hdr.flags = (cmd.len() / 4) as u16
+			//| (multiplier_port << 12)
+			| (if data.is_send() { 1 << 6 } else { 0 })	// Write
+			| (if pkt.len() > 0 { 1 << 5 } else { 0 })	// ATAPI
+			;
+
+		// SAFE: Wait ensures that memory stays valid
+		let rv = unsafe {
+			slot.start();
+			slot.wait(len)
+			};
+
+		if let Some(ref buf) = bounce {
+			if let &mut DataPtr::Recv(ref mut dst) = data {
+				dst.clone_from_slice(buf.as_slice(0, len));
+			}
+		}
+		rv
+	}
+
+	/// Populate a PRDT with the physical ranges making up `buf`
+	///
+	/// Returns `None` if the buffer doesn't meet the controller's requirements (alignment, addressable memory,
+	/// or too many fragments), and a bounce buffer is needed.
+	fn fill_prdt(prdt: &mut [hw::CmdEnt], buf: &[u8], supports_64bit: bool) -> Option<usize>
+	{
+		use kernel::memory::virt::get_phys;
 
-		// Generate the scatter-gather list
-		let mut va = data.as_slice().as_ptr() as usize;
-		let mut len = data.as_slice().len();
+		let mut va = buf.as_ptr() as usize;
+		let mut len = buf.len();
 		let mut n_prdt_ents = 0;
 		while len > 0
 		{
@@ -472,46 +884,42 @@ impl Port
 			let mut seglen = ::kernel::PAGE_SIZE - base_phys as usize % ::kernel::PAGE_SIZE;
 			const MAX_SEG_LEN: usize = (1 << 22);
 			// Each entry must be contigious, and not >4MB
-			while seglen < len && seglen <= MAX_SEG_LEN && get_phys( (va + seglen-1) as *const u8 ) == base_phys + (seglen-1) as ::kernel::memory::PAddr
+			while seglen < len && seglen <= MAX_SEG_LEN && get_phys( (va + seglen) as *const u8 ) == base_phys + seglen as ::kernel::memory::PAddr
 			{
 				seglen += ::kernel::PAGE_SIZE;
 			}
 			let seglen = ::core::cmp::min(len, seglen);
 			let seglen = ::core::cmp::min(MAX_SEG_LEN, seglen);
 			if base_phys % 4 != 0 || seglen % 2 != 0 {
-				todo!("AHCI Port::do_fis - Use a bounce buffer if alignment requirements are not met");
+				return None;
+			}
+			if !supports_64bit && (base_phys as u64 + seglen as u64 - 1) >> 32 != 0 {
+				return None;
+			}
+			if n_prdt_ents == prdt.len() {
+				return None;
 			}
-			assert!( n_prdt_ents < slot.data.prdt.len() );
-			slot.data.prdt[n_prdt_ents].dba = base_phys as u64;
-			slot.data.prdt[n_prdt_ents].dbc = (seglen - 1) as u32;
+			prdt[n_prdt_ents].dba = base_phys as u64;
+			prdt[n_prdt_ents].dbc = (seglen - 1) as u32;
 
 			va += seglen;
 			len -= seglen;
 
 			n_prdt_ents += 1;
 		}
-		slot.data.prdt[n_prdt_ents-1].dbc |= 1 << 31;	// set IOC
-		slot.hdr.prdtl = n_prdt_ents as u16;
-		slot.hdr.prdbc = 0;
-		slot.hdr.flags = (cmd.len() / 4) as u16
-			//| (multiplier_port << 12)
-			| (if data.is_send() { 1 << 6 } else { 0 })	// Write
-			| (if pkt.len() > 0 { 1 << 5 } else { 0 })	// ATAPI
-			;
-
-		slot.event.clear();
-		// SAFE: Wait ensures that memory stays valid
-		unsafe {
-			slot.start();
-			slot.wait()
-		}
+		Some(n_prdt_ents)
 	}
 
-	fn get_command_slot(&self) -> CommandSlot
+	fn get_command_slot(&self, queued: bool) -> CommandSlot
 	{
 		let max_commands = self.ctrlr.max_commands as usize;
 
 		// 0. Request slot from semaphore
+		// - Queued commands are also limited by the device's queue depth, as the slot index is the tag
+		//   (non-queued commands hold the gate exclusively, so queued commands will always get a slot below the depth)
+		if queued {
+			self.ncq_sem.acquire();
+		}
 		self.used_commands_sem.acquire();
 		
 		// 1. Load
@@ -545,6 +953,7 @@ impl Port
 					};
 				return CommandSlot {
 					idx: avail as u8,
+					queued: queued,
 					port: self,
 					data: tab,
 					hdr: hdr,
@@ -567,6 +976,7 @@ impl ::core::ops::Drop for Port
 
 struct CommandSlot<'a> {
 	idx: u8,
+	queued: bool,
 	port: &'a Port,
 	pub data: &'a mut hw::CmdTable,
 	pub hdr: &'a mut hw::CmdHeader,
@@ -579,25 +989,53 @@ impl<'a> CommandSlot<'a>
 	{
 		//log_trace!("{} - start(idx={})", self.port, self.idx);
 		let mask = 1 << self.idx as usize;
-		self.port.regs().write(hw::REG_PxSACT, mask);
+
+		// Ensure that the port isn't halted from a previous error
+		let _rlh = self.port.recovery_lock.lock();
+		self.port.recover_locked();
+
+		self.port.command_status[self.idx as usize].store(CMD_PENDING, Ordering::Relaxed);
+		self.event.clear();
+
+		let _lh = self.port.issue_lock.lock_irqsafe();
+		self.port.issued_commands.store(self.port.issued_commands.load(Ordering::Relaxed) | mask, Ordering::Release);
+		if self.queued {
+			self.port.regs().write(hw::REG_PxSACT, mask);
+		}
 		self.port.regs().write(hw::REG_PxCI, mask);
 	}
 
 	/// Wait for a command to complete and returns the number of bytes transferred
-	pub fn wait(&self) -> Result<usize, Error>
+	///
+	/// `len` is the requested transfer size, used for queued commands (which don't update the byte count)
+	pub fn wait(&self, len: usize) -> Result<usize, Error>
 	{
-		self.event.sleep();
+		let timed_out = !self.wait_timeout(COMMAND_TIMEOUT_MS);
 
-		let regs = self.port.regs();
-		let active = regs.read(hw::REG_PxCI);
-		let tfd = regs.read(hw::REG_PxTFD);
+		let status = self.port.command_status[self.idx as usize].load(Ordering::Acquire);
+		if status == CMD_DONE {
+			return Ok( if self.queued { len } else { self.hdr.prdbc as usize } );
+		}
 
-		let mask = 1 << self.idx;
-		if regs.read(hw::REG_PxSERR) != 0 {
-			Err( Error::Bus )
+		if timed_out {
+			log_error!("{} - Command {} timed out", self.port, self.idx);
+			self.port.force_reset.store(true, Ordering::Relaxed);
+			self.port.needs_recovery.store(1, Ordering::Release);
 		}
-		else if tfd & 0x01 != 0 {
-			// Errored (ATA)
+
+		// Error: Restart the port before returning (so that the slot is idle when released)
+		// - For NCQ errors, this also finds the failed command (updating its status)
+		self.port.recover();
+		if timed_out {
+			return Err( Error::Timeout );
+		}
+		match self.port.command_status[self.idx as usize].load(Ordering::Acquire)
+		{
+		CMD_BUS_ERROR => Err( Error::Bus ),
+		CMD_ABORTED => Err( Error::Aborted ),
+		_ => {
+			let tfd = self.port.error_tfd.load(Ordering::Relaxed);
+			// ATA error
 			if self.hdr.flags & (1 << 5) == 0 {
 				Err( Error::Ata {
 					sts: tfd as u8,
@@ -613,14 +1051,36 @@ impl<'a> CommandSlot<'a>
 					ili: err & 1 != 0,
 					})
 			}
+			},
 		}
-		else if active & mask == 0 {
-			// All good
-			Ok( self.hdr.prdbc as usize )
+	}
+}
+
+impl<'a> CommandSlot<'a>
+{
+	/// Sleep until the command is no longer pending, returns false if `timeout_ms` elapses first
+	fn wait_timeout(&self, timeout_ms: u64) -> bool
+	{
+		let is_pending = || self.port.command_status[self.idx as usize].load(Ordering::Acquire) == CMD_PENDING;
+		let deadline = ::kernel::time::ticks() + timeout_ms;
+
+		// SAFE: The timed wakeup is cancelled before returning (the event outlives this borrow)
+		let event: &'static ::kernel::sync::EventChannel = unsafe { &*(self.event as *const _) };
+		if ::kernel::time::post_at(deadline, event)
+		{
+			while is_pending() && ::kernel::time::ticks() < deadline {
+				self.event.sleep();
+			}
+			::kernel::time::cancel_post(self.event);
 		}
-		else {
-			panic!("{} - Command {} woken while still active", self.port, self.idx);
+		else
+		{
+			// No timer slot available, poll instead
+			while is_pending() && ::kernel::time::ticks() < deadline {
+				::kernel::threads::yield_time();
+			}
 		}
+		!is_pending()
 	}
 }
 
@@ -629,11 +1089,11 @@ impl<'a> ::core::ops::Drop for CommandSlot<'a>
 	fn drop(&mut self)
 	{
 		let mask = 1 << self.idx;
-		let regs = self.port.regs();
-		// SAFE: Reading has no effect
-		let cur_active = regs.read(hw::REG_PxCI) /* | regs.read(hw::REG_PxSACT) */;
-		if cur_active & mask != 0 {
-			todo!("CommandSlot::drop - Port {} cmd {} - Still active", self.port.index, self.idx);
+		if self.port.issued_commands.load(Ordering::Acquire) & mask != 0 {
+			// Command is still owned by the hardware, stop the port so the slot can be reused
+			log_error!("{} - Command {} dropped while still active, resetting port", self.port, self.idx);
+			self.port.needs_recovery.store(1, Ordering::Release);
+			self.port.recover();
 		}
 		
 		// Release into the pool
@@ -646,6 +1106,9 @@ impl<'a> ::core::ops::Drop for CommandSlot<'a>
 			}
 		}
 		self.port.used_commands_sem.release();
+		if self.queued {
+			self.port.ncq_sem.release();
+		}
 	}
 }
 
@@ -669,29 +1132,34 @@ impl ::storage_ata::volume::Interface for Interface
 	fn name(&self) -> &str { &self.port().name }
 
 	fn ata_identify(&self) -> Result<::storage_ata::AtaIdentifyData, ::storage_ata::volume::Error> {
-		match self.port().request_identify(0xEC)
-		{
-		Ok(v) => Ok(v),
-		Err(Error::Ata{err, ..}) => Err(From::from(err)),
-		Err(_) => Err(From::from(0)),
-		}
+		self.port().request_identify(0xEC).map_err(From::from)
 	}
 	fn dma_lba_28(&self, cmd: u8, count: u8 , addr: u32, data: DataPtr) -> Result<usize,::storage_ata::volume::Error> {
-		match self.port().request_ata_lba28(0, cmd, count, addr, data)
+		match self.port().request_ata_dma28(0, cmd, count, addr, data)
 		{
 		Ok(bc) => Ok( bc / 512 ),
-		Err(Error::Ata{err, ..}) => Err(From::from(err)),
-		Err(_) => Err(From::from(0)),
+		Err(e) => Err(From::from(e)),
 		}
 	}
 	fn dma_lba_48(&self, cmd: u8, count: u16, addr: u64, data: DataPtr) -> Result<usize,::storage_ata::volume::Error> {
 		match self.port().request_ata_lba48(0, cmd, count, addr, data)
 		{
 		Ok(bc) => Ok( bc / 512 ),
-		Err(Error::Ata{err, ..}) => Err(From::from(err)),
-		Err(_) => Err(From::from(0)),
+		Err(e) => Err(From::from(e)),
 		}
 	}
+	fn nodata_cmd(&self, cmd: u8) -> Result<(),::storage_ata::volume::Error> {
+		match self.port().request_ata_lba28(0, cmd, 0, 0, DataPtr::Send(&[]))
+		{
+		Ok(_) => Ok( () ),
+		Err(e) => Err(From::from(e)),
+		}
+	}
+
+	fn max_outstanding(&self) -> usize {
+		// Queued commands can be issued in parallel, everything else is serialised by the port
+		::core::cmp::max(1, self.port().ncq_depth.load(Ordering::Relaxed) as usize)
+	}
 }
 
 impl ::storage_scsi::ScsiInterface for Interface
@@ -701,24 +1169,26 @@ impl ::storage_scsi::ScsiInterface for Interface
 	}
 	fn send<'a>(&'a self, command: &[u8], data: &'a [u8]) -> storage::AsyncIoResult<'a,()>
 	{
-		use storage_scsi::proto::SenseKey;
 		use kernel::async::NullResultWaiter;
 		match self.port().request_atapi(0, command, DataPtr::Send(data))
 		{
 		Ok(_) => Box::new( NullResultWaiter::new(|| Ok( () )) ),
-		Err(Error::Atapi { sense_key: SenseKey::NotReady, .. }) => Box::new(NullResultWaiter::new(|| Err(storage::IoError::NoMedium))),
-		Err(_) => Box::new(NullResultWaiter::new(|| Err(storage::IoError::Unknown(""))))
+		Err(e) => {
+			let e = storage::IoError::from(e);
+			Box::new(NullResultWaiter::new(move || Err(e)))
+			},
 		}
 	}
 	fn recv<'a>(&'a self, command: &[u8], data: &'a mut [u8]) -> storage::AsyncIoResult<'a,()>
 	{
-		use storage_scsi::proto::SenseKey;
 		use kernel::async::NullResultWaiter;
 		match self.port().request_atapi(0, command, DataPtr::Recv(data))
 		{
 		Ok(_) => Box::new( NullResultWaiter::new(|| Ok( () )) ),
-		Err(Error::Atapi { sense_key: SenseKey::NotReady, .. }) => Box::new(NullResultWaiter::new(|| Err(storage::IoError::NoMedium))),
-		Err(_) => Box::new(NullResultWaiter::new(|| Err(storage::IoError::Unknown(""))))
+		Err(e) => {
+			let e = storage::IoError::from(e);
+			Box::new(NullResultWaiter::new(move || Err(e)))
+			},
 		}
 	}
 }
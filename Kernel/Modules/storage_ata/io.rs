@@ -24,6 +24,9 @@ const HDD_DMA_W28: u8 = 0xCA;
 const HDD_DMA_R48: u8 = 0x25;
 const HDD_DMA_W48: u8 = 0x35;
 
+const HDD_FLUSH_CACHE: u8 = 0xE7;
+const HDD_FLUSH_CACHE_EXT: u8 = 0xEA;
+
 pub struct DmaController
 {
 	pub name: String,
@@ -126,6 +129,18 @@ impl DmaController
 		let ub = ctrlr.do_atapi(disk, bm_regs, cmd, dst, is_write);
 		Box::new(ub)
 	}
+
+	/// Flush the disk's write cache (`lba48` selects FLUSH CACHE EXT)
+	pub fn do_flush<'a>(&'a self, disk: u8, lba48: bool) -> storage::AsyncIoResult<'a,()>
+	{
+		assert!(disk < 4);
+		
+		let bus = (disk >> 1) & 1;
+		let disk = disk & 1;
+		
+		let ctrlr = &self.ata_controllers[bus as usize];
+		Box::new( ctrlr.do_flush(disk, lba48) )
+	}
 }
 
 impl<'a> DmaRegBorrow<'a>
@@ -324,6 +339,17 @@ impl AtaRegs
 		}
 	}
 	
+	fn start_flush(&mut self, disk: u8, lba48: bool)
+	{
+		log_debug!("start_flush(disk={},lba48={})", disk, lba48);
+		// SAFE: Unique access and valid IO accesses
+		unsafe
+		{
+			self.out_8(6, 0xE0 | (disk << 4));
+			self.out_8(7, if lba48 { HDD_FLUSH_CACHE_EXT } else { HDD_FLUSH_CACHE });
+		}
+	}
+	
 	fn start_atapi(&mut self, bm: &DmaRegBorrow, disk: u8, is_write: bool, cmd: &[u16], dma_buffer: &DMABuffer)
 	{
 		log_debug!("start_atapi(...,disk={},is_write={},cmd={{len={}}},dma_buffer={{len={}}})",
@@ -475,6 +501,79 @@ impl<'a,'b> ::core::fmt::Debug for AtaWaiter<'a,'b> {
 		}
 	}
 }
+/// Waiter for a non-data command (FLUSH CACHE)
+struct AtaFlushWaiter<'dev>
+{
+	dev: &'dev AtaController,
+	disk: u8,
+	lba48: bool,
+	state: WaitState<'dev>,
+}
+impl<'a> async::ResultWaiter for AtaFlushWaiter<'a>
+{
+	type Result = Result<(), storage::IoError>;
+	
+	fn get_result(&mut self) -> Option<Self::Result> {
+		match self.state
+		{
+		WaitState::Done(r) => Some(r),
+		_ => None,
+		}
+	}
+	
+	fn as_waiter(&mut self) -> &mut dyn async::Waiter { self }
+}
+impl<'a> async::Waiter for AtaFlushWaiter<'a>
+{
+	fn is_complete(&self) -> bool {
+		if let WaitState::Done(..) = self.state { true } else { false }
+	}
+	
+	fn get_waiter(&mut self) -> &mut dyn async::PrimitiveWaiter
+	{
+		match self.state
+		{
+		WaitState::Acquire(ref mut waiter) => waiter,
+		WaitState::IoActive(_, ref mut waiter) => waiter,
+		WaitState::Done(..) => unreachable!(),
+		}
+	}
+	
+	fn complete(&mut self) -> bool
+	{
+		self.state = match self.state
+			{
+			WaitState::Acquire(ref mut waiter) => {
+				let mut lh = waiter.take_lock();
+				lh.start_flush( self.disk, self.lba48 );
+				WaitState::IoActive(lh, self.dev.interrupt.handle.get_event().wait())
+				},
+			WaitState::IoActive(ref mut lh, ref _waiter) => WaitState::Done(
+				// SAFE: Holding the register lock
+				unsafe {
+					let ata_status = AtaStatusVal(lh.in_8(7));	// Also acknowledges the interrupt
+					log_trace!("Flush complete, ATA Status = {:?}", ata_status);
+					lh.last_result(false)
+				}
+				),
+			WaitState::Done(..) => unreachable!(),
+			};
+		
+		self.is_complete()
+	}
+}
+impl<'a> ::core::fmt::Debug for AtaFlushWaiter<'a> {
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+		try!( write!(f, "AtaFlushWaiter") );
+		match self.state
+		{
+		WaitState::Acquire(..) => write!(f, "(Acquire)"),
+		WaitState::IoActive(..) => write!(f, "(IoActive)"),
+		WaitState::Done(..) => write!(f, "(Done)"),
+		}
+	}
+}
+
 struct AtapiWaiter<'dev,'buf>
 {
 	dev: &'dev AtaController,
@@ -621,6 +720,16 @@ impl AtaController
 		}
 	}
 	
+	fn do_flush<'a>(&'a self, disk: u8, lba48: bool) -> AtaFlushWaiter<'a>
+	{
+		AtaFlushWaiter {
+			dev: self,
+			disk: disk,
+			lba48: lba48,
+			state: WaitState::Acquire( self.regs.async_lock() ),
+		}
+	}
+	
 	/// Request an ATA IDENTIFY packet from the device
 	pub fn ata_identify<'a>(&'a self, disk: u8, data: &'a mut ::AtaIdentifyData, class: &'a mut ::AtaClass) -> async::poll::Waiter<'a>
 	{
@@ -77,7 +77,12 @@ pub struct AtaIdentifyData
 	pub size_of_rw_multiple: u16,
 	/// LBA 28 sector count (if zero, use 48)
 	pub sector_count_28: u32,
-	_unused6: [u16; 100-62],
+	_unused6: [u16; 75-62],
+	/// [0:4] Maximum queue depth - 1
+	pub queue_depth: u16,
+	/// Serial ATA capabilities ([8] = Native Command Queuing)
+	pub sata_capabilities: u16,
+	_unused6b: [u16; 100-77],
 	/// LBA 48 sector count
 	pub sector_count_48: u64,
 	_unused7: [u16; 2],
@@ -135,7 +140,7 @@ impl AtaVolume
 impl ::kernel::metadevs::storage::PhysicalVolume for AtaVolume
 {
 	fn name(&self) -> &str { &*self.name }
-	fn blocksize(&self) -> usize { io::SECTOR_SIZE }
+	fn blocksize(&self) -> Result<usize,::kernel::metadevs::storage::IoError> { Ok(io::SECTOR_SIZE) }
 	fn capacity(&self) -> Option<u64> { Some(self.size) }
 	
 	fn read<'a>(&'a self, _prio: u8, idx: u64, num: usize, dst: &'a mut [u8]) -> storage::AsyncIoResult<'a,usize>
@@ -150,6 +155,11 @@ impl ::kernel::metadevs::storage::PhysicalVolume for AtaVolume
 		ctrlr.do_dma_wr(idx, num, src, self.disk)
 	}
 	
+	fn flush<'a>(&'a self) -> storage::AsyncIoResult<'a,()>
+	{
+		self.controller.do_flush(self.disk, self.size >= (1 << 28))
+	}
+	
 	fn wipe<'a>(&'a self, _blockidx: u64, _count: usize) -> storage::AsyncIoResult<'a,()>
 	{
 		// Do nothing, no support for TRIM
@@ -7,17 +7,46 @@ use kernel::prelude::*;
 use kernel::metadevs::storage::{self, DataPtr};
 use kernel::async;
 
-pub struct Error(u8);
+pub enum Error
+{
+	/// Device reported an error (contents of the ATA error register)
+	Ata(u8),
+	/// Failure in the interface (bus error, allocation failure, ...)
+	Io(storage::IoError),
+}
 impl From<Error> for storage::IoError
 {
-	fn from(_v: Error) -> storage::IoError
+	fn from(v: Error) -> storage::IoError
 	{
-		storage::IoError::Unknown("ATA")
+		let v = match v
+			{
+			Error::Ata(v) => v,
+			Error::Io(e) => return e,
+			};
+		// ATA error register bits
+		if v & (1 << 6) != 0 {	// UNC - Uncorrectable data
+			storage::IoError::BadBlock
+		}
+		else if v & (1 << 4) != 0 {	// IDNF - Address not found
+			storage::IoError::BadAddr
+		}
+		else if v & (1 << 5) != 0 {	// MC - Media changed
+			storage::IoError::MediaChanged
+		}
+		else if v & (1 << 1) != 0 {	// NM - No media
+			storage::IoError::NoMedium
+		}
+		else {
+			storage::IoError::Unknown("ATA")
+		}
 	}
 }
 impl_from! {
 	From<u8>(v) for Error {
-		Error(v)
+		Error::Ata(v)
+	}
+	From<storage::IoError>(v) for Error {
+		Error::Io(v)
 	}
 }
 
@@ -27,6 +56,8 @@ const ATA_READ_DMA: u8 = 0xC8;
 const ATA_WRITE_DMA: u8 = 0xCA;
 const ATA_READ_DMA_EXT: u8 = 0x25;
 const ATA_WRITE_DMA_EXT: u8 = 0x35;
+pub const ATA_FLUSH_CACHE: u8 = 0xE7;
+pub const ATA_FLUSH_CACHE_EXT: u8 = 0xEA;
 
 pub trait Interface: 'static + Send
 {
@@ -35,6 +66,11 @@ pub trait Interface: 'static + Send
 	fn ata_identify(&self) -> Result<super::AtaIdentifyData, Error>;
 	fn dma_lba_28(&self, cmd: u8, count: u8 , addr: u32, data: DataPtr) -> Result<usize,Error>;
 	fn dma_lba_48(&self, cmd: u8, count: u16, addr: u64, data: DataPtr) -> Result<usize,Error>;
+	/// Issue a non-data command (e.g. ATA_FLUSH_CACHE)
+	fn nodata_cmd(&self, cmd: u8) -> Result<(),Error>;
+
+	/// Number of `dma_lba_*` calls that can be in progress at once (e.g. the NCQ depth)
+	fn max_outstanding(&self) -> usize { 1 }
 }
 
 pub struct AtaVolume<I: Interface>
@@ -64,11 +100,17 @@ impl<I: Interface> AtaVolume<I>
 }
 
 
+/// Interfaces that can be shared between threads (e.g. AHCI with NCQ) allow concurrent requests
+impl<I: Interface + Sync> storage::ConcurrentPhysicalVolume for AtaVolume<I>
+{
+}
+
 impl<I: Interface + Send + 'static> storage::PhysicalVolume for AtaVolume<I>
 {
 	fn name(&self) -> &str { self.int.name() }
-	fn blocksize(&self) -> usize { self.block_size as usize }
+	fn blocksize(&self) -> Result<usize,storage::IoError> { Ok(self.block_size as usize) }
 	fn capacity(&self) -> Option<u64> { Some(self.block_count) }
+	fn max_outstanding(&self) -> usize { self.int.max_outstanding() }
 	
 	fn read<'a>(&'a self, _prio: u8, idx: u64, num: usize, dst: &'a mut [u8]) -> storage::AsyncIoResult<'a,usize>
 	{
@@ -109,4 +151,10 @@ impl<I: Interface + Send + 'static> storage::PhysicalVolume for AtaVolume<I>
 		Box::new(async::NullResultWaiter::new( || Ok( () ) ))
 	}
 	
+	fn flush<'a>(&'a self) -> storage::AsyncIoResult<'a,()>
+	{
+		let cmd = if self.block_count >= (1 << 28) { ATA_FLUSH_CACHE_EXT } else { ATA_FLUSH_CACHE };
+		let ret = self.int.nodata_cmd(cmd).map_err(|e| e.into());
+		Box::new( ::kernel::async::NullResultWaiter::new( move || ret ) )
+	}
 }
@@ -0,0 +1,214 @@
+// "Tifflin" Kernel - Encrypted volumes
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_crypt/aes.rs
+//! AES-256 block cipher
+//!
+//! NOTE: Uses table lookups for the S-box, so is not constant-time.
+
+/// Forward substitution box
+const SBOX: [u8; 256] = [
+	0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+	0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+	0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+	0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+	0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+	0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+	0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+	0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+	0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+	0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+	0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+	0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+	0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+	0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+	0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+	0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+	];
+
+/// Inverse substitution box
+const INV_SBOX: [u8; 256] = [
+	0x52, 0x09, 0x6a, 0xd5, 0x30, 0x36, 0xa5, 0x38, 0xbf, 0x40, 0xa3, 0x9e, 0x81, 0xf3, 0xd7, 0xfb,
+	0x7c, 0xe3, 0x39, 0x82, 0x9b, 0x2f, 0xff, 0x87, 0x34, 0x8e, 0x43, 0x44, 0xc4, 0xde, 0xe9, 0xcb,
+	0x54, 0x7b, 0x94, 0x32, 0xa6, 0xc2, 0x23, 0x3d, 0xee, 0x4c, 0x95, 0x0b, 0x42, 0xfa, 0xc3, 0x4e,
+	0x08, 0x2e, 0xa1, 0x66, 0x28, 0xd9, 0x24, 0xb2, 0x76, 0x5b, 0xa2, 0x49, 0x6d, 0x8b, 0xd1, 0x25,
+	0x72, 0xf8, 0xf6, 0x64, 0x86, 0x68, 0x98, 0x16, 0xd4, 0xa4, 0x5c, 0xcc, 0x5d, 0x65, 0xb6, 0x92,
+	0x6c, 0x70, 0x48, 0x50, 0xfd, 0xed, 0xb9, 0xda, 0x5e, 0x15, 0x46, 0x57, 0xa7, 0x8d, 0x9d, 0x84,
+	0x90, 0xd8, 0xab, 0x00, 0x8c, 0xbc, 0xd3, 0x0a, 0xf7, 0xe4, 0x58, 0x05, 0xb8, 0xb3, 0x45, 0x06,
+	0xd0, 0x2c, 0x1e, 0x8f, 0xca, 0x3f, 0x0f, 0x02, 0xc1, 0xaf, 0xbd, 0x03, 0x01, 0x13, 0x8a, 0x6b,
+	0x3a, 0x91, 0x11, 0x41, 0x4f, 0x67, 0xdc, 0xea, 0x97, 0xf2, 0xcf, 0xce, 0xf0, 0xb4, 0xe6, 0x73,
+	0x96, 0xac, 0x74, 0x22, 0xe7, 0xad, 0x35, 0x85, 0xe2, 0xf9, 0x37, 0xe8, 0x1c, 0x75, 0xdf, 0x6e,
+	0x47, 0xf1, 0x1a, 0x71, 0x1d, 0x29, 0xc5, 0x89, 0x6f, 0xb7, 0x62, 0x0e, 0xaa, 0x18, 0xbe, 0x1b,
+	0xfc, 0x56, 0x3e, 0x4b, 0xc6, 0xd2, 0x79, 0x20, 0x9a, 0xdb, 0xc0, 0xfe, 0x78, 0xcd, 0x5a, 0xf4,
+	0x1f, 0xdd, 0xa8, 0x33, 0x88, 0x07, 0xc7, 0x31, 0xb1, 0x12, 0x10, 0x59, 0x27, 0x80, 0xec, 0x5f,
+	0x60, 0x51, 0x7f, 0xa9, 0x19, 0xb5, 0x4a, 0x0d, 0x2d, 0xe5, 0x7a, 0x9f, 0x93, 0xc9, 0x9c, 0xef,
+	0xa0, 0xe0, 0x3b, 0x4d, 0xae, 0x2a, 0xf5, 0xb0, 0xc8, 0xeb, 0xbb, 0x3c, 0x83, 0x53, 0x99, 0x61,
+	0x17, 0x2b, 0x04, 0x7e, 0xba, 0x77, 0xd6, 0x26, 0xe1, 0x69, 0x14, 0x63, 0x55, 0x21, 0x0c, 0x7d,
+	];
+
+
+/// Number of rounds for a 256-bit key
+const ROUNDS: usize = 14;
+
+/// Expanded AES-256 key
+pub struct Aes256
+{
+	round_keys: [[u8; 16]; ROUNDS+1],
+}
+
+impl Aes256
+{
+	pub fn new(key: &[u8; 32]) -> Aes256
+	{
+		let mut w = [[0u8; 4]; 4*(ROUNDS+1)];
+		for i in 0 .. 8 {
+			w[i].copy_from_slice(&key[i*4 ..][..4]);
+		}
+		let mut rcon = 1;
+		for i in 8 .. w.len()
+		{
+			let mut t = w[i-1];
+			if i % 8 == 0 {
+				t = [ SBOX[t[1] as usize] ^ rcon, SBOX[t[2] as usize], SBOX[t[3] as usize], SBOX[t[0] as usize] ];
+				rcon = xtime(rcon);
+			}
+			else if i % 8 == 4 {
+				for b in t.iter_mut() {
+					*b = SBOX[*b as usize];
+				}
+			}
+			for j in 0 .. 4 {
+				w[i][j] = w[i-8][j] ^ t[j];
+			}
+		}
+
+		let mut rv = Aes256 { round_keys: [[0; 16]; ROUNDS+1] };
+		for (i, word) in w.iter_mut().enumerate()
+		{
+			rv.round_keys[i / 4][(i % 4) * 4 ..][..4].copy_from_slice(word);
+			::zeroise(word);
+		}
+		rv
+	}
+
+	pub fn encrypt_block(&self, s: &mut [u8; 16])
+	{
+		add_round_key(s, &self.round_keys[0]);
+		for r in 1 .. ROUNDS
+		{
+			sub_bytes(s, &SBOX);
+			shift_rows(s);
+			mix_columns(s);
+			add_round_key(s, &self.round_keys[r]);
+		}
+		sub_bytes(s, &SBOX);
+		shift_rows(s);
+		add_round_key(s, &self.round_keys[ROUNDS]);
+	}
+
+	pub fn decrypt_block(&self, s: &mut [u8; 16])
+	{
+		add_round_key(s, &self.round_keys[ROUNDS]);
+		for r in (1 .. ROUNDS).rev()
+		{
+			inv_shift_rows(s);
+			sub_bytes(s, &INV_SBOX);
+			add_round_key(s, &self.round_keys[r]);
+			inv_mix_columns(s);
+		}
+		inv_shift_rows(s);
+		sub_bytes(s, &INV_SBOX);
+		add_round_key(s, &self.round_keys[0]);
+	}
+}
+impl ::core::ops::Drop for Aes256
+{
+	fn drop(&mut self)
+	{
+		for k in self.round_keys.iter_mut() {
+			::zeroise(k);
+		}
+	}
+}
+
+/// Multiply by x in GF(2^8)
+fn xtime(v: u8) -> u8
+{
+	(v << 1) ^ (if v & 0x80 != 0 { 0x1b } else { 0 })
+}
+
+fn add_round_key(s: &mut [u8; 16], k: &[u8; 16])
+{
+	for (d, k) in s.iter_mut().zip(k.iter()) {
+		*d ^= *k;
+	}
+}
+fn sub_bytes(s: &mut [u8; 16], table: &[u8; 256])
+{
+	for b in s.iter_mut() {
+		*b = table[*b as usize];
+	}
+}
+// State is column-major (byte `r + 4*c` is row `r` column `c`), row `r` rotates left by `r`
+fn shift_rows(s: &mut [u8; 16])
+{
+	let o = *s;
+	for r in 1 .. 4 {
+		for c in 0 .. 4 {
+			s[r + 4*c] = o[r + 4*((c + r) % 4)];
+		}
+	}
+}
+fn inv_shift_rows(s: &mut [u8; 16])
+{
+	let o = *s;
+	for r in 1 .. 4 {
+		for c in 0 .. 4 {
+			s[r + 4*c] = o[r + 4*((c + 4 - r) % 4)];
+		}
+	}
+}
+fn mix_columns(s: &mut [u8; 16])
+{
+	for c in s.chunks_mut(4)
+	{
+		let (a0, a1, a2, a3) = (c[0], c[1], c[2], c[3]);
+		let t = a0 ^ a1 ^ a2 ^ a3;
+		c[0] = a0 ^ t ^ xtime(a0 ^ a1);
+		c[1] = a1 ^ t ^ xtime(a1 ^ a2);
+		c[2] = a2 ^ t ^ xtime(a2 ^ a3);
+		c[3] = a3 ^ t ^ xtime(a3 ^ a0);
+	}
+}
+fn inv_mix_columns(s: &mut [u8; 16])
+{
+	// Pre-multiply so a forward mix yields the inverse
+	for c in s.chunks_mut(4)
+	{
+		let u = xtime(xtime(c[0] ^ c[2]));
+		let v = xtime(xtime(c[1] ^ c[3]));
+		c[0] ^= u;
+		c[1] ^= v;
+		c[2] ^= u;
+		c[3] ^= v;
+	}
+	mix_columns(s);
+}
+
+// FIPS-197 Appendix C.3 (AES-256)
+#[test]
+fn fips197_c3()
+{
+	let mut key = [0u8; 32];
+	for (i, b) in key.iter_mut().enumerate() {
+		*b = i as u8;
+	}
+	let plain = [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff];
+	let cipher = [0x8e, 0xa2, 0xb7, 0xca, 0x51, 0x67, 0x45, 0xbf, 0xea, 0xfc, 0x49, 0x90, 0x4b, 0x49, 0x60, 0x89];
+	let aes = Aes256::new(&key);
+	let mut s = plain;
+	aes.encrypt_block(&mut s);
+	assert_eq!(s, cipher);
+	aes.decrypt_block(&mut s);
+	assert_eq!(s, plain);
+}
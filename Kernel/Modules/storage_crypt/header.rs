@@ -0,0 +1,122 @@
+// "Tifflin" Kernel - Encrypted volumes
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_crypt/header.rs
+//! On-disk header (first block of the underlying volume)
+//!
+//! All fields are little-endian:
+//! ```text
+//!   0  [u8; 8]  Magic "TFCRYPT\0"
+//!   8  u16      Version (1)
+//!  10  u16      Cipher (1 = AES-256-XTS)
+//!  12  u32      PBKDF2-HMAC-SHA256 iteration count
+//!  16  u32      Offset of the first data block (in blocks of the underlying volume)
+//!  20  u32      Reserved (zero)
+//!  24  [u8; 32] KDF salt
+//!  56  [u8; 32] Key check (HMAC-SHA256 of a fixed string, keyed with the master key)
+//!  88  [u8; 64] Master key, encrypted with AES-256-XTS (sector 0) using the passphrase-derived key
+//! ```
+use xts;
+use Error;
+
+const MAGIC: &'static [u8; 8] = b"TFCRYPT\0";
+const VERSION: u16 = 1;
+const CIPHER_AES256_XTS: u16 = 1;
+
+pub const SALT_LEN: usize = 32;
+pub const CHECK_LEN: usize = ::sha256::DIGEST_LEN;
+/// Minimum size of the header block
+pub const HEADER_LEN: usize = 88 + xts::KEY_LEN;
+/// Largest accepted PBKDF2 iteration count (the header is untrusted, and key derivation runs in the caller's thread)
+pub const MAX_KDF_ITERATIONS: u32 = 1_000_000;
+
+pub struct Header
+{
+	pub kdf_iterations: u32,
+	pub data_offset: u32,
+	pub salt: [u8; SALT_LEN],
+	pub key_check: [u8; CHECK_LEN],
+	pub wrapped_key: [u8; xts::KEY_LEN],
+}
+
+fn get_u16(b: &[u8]) -> u16 {
+	b[0] as u16 | (b[1] as u16) << 8
+}
+fn get_u32(b: &[u8]) -> u32 {
+	get_u16(b) as u32 | (get_u16(&b[2..]) as u32) << 16
+}
+fn put_u16(b: &mut [u8], v: u16) {
+	b[0] = v as u8;
+	b[1] = (v >> 8) as u8;
+}
+fn put_u32(b: &mut [u8], v: u32) {
+	put_u16(b, v as u16);
+	put_u16(&mut b[2..], (v >> 16) as u16);
+}
+
+impl Header
+{
+	pub fn parse(b: &[u8]) -> Result<Header, Error>
+	{
+		if b.len() < HEADER_LEN || &b[..8] != &MAGIC[..] {
+			return Err( Error::BadHeader );
+		}
+		if get_u16(&b[8..]) != VERSION || get_u16(&b[10..]) != CIPHER_AES256_XTS {
+			log_notice!("Unsupported encrypted volume version/cipher ({}/{})", get_u16(&b[8..]), get_u16(&b[10..]));
+			return Err( Error::BadHeader );
+		}
+		let mut rv = Header {
+			kdf_iterations: get_u32(&b[12..]),
+			data_offset: get_u32(&b[16..]),
+			salt: [0; SALT_LEN],
+			key_check: [0; CHECK_LEN],
+			wrapped_key: [0; xts::KEY_LEN],
+			};
+		if rv.kdf_iterations == 0 || rv.data_offset == 0 {
+			return Err( Error::BadHeader );
+		}
+		if rv.kdf_iterations > MAX_KDF_ITERATIONS {
+			log_notice!("Encrypted volume KDF iteration count too large ({} > {})", rv.kdf_iterations, MAX_KDF_ITERATIONS);
+			return Err( Error::BadHeader );
+		}
+		rv.salt.copy_from_slice(&b[24..][..SALT_LEN]);
+		rv.key_check.copy_from_slice(&b[56..][..CHECK_LEN]);
+		rv.wrapped_key.copy_from_slice(&b[88..][..xts::KEY_LEN]);
+		Ok(rv)
+	}
+
+	/// Serialise into `b` (the rest of the block is zeroed)
+	pub fn write(&self, b: &mut [u8])
+	{
+		for v in b.iter_mut() {
+			*v = 0;
+		}
+		b[..8].copy_from_slice(MAGIC);
+		put_u16(&mut b[8..], VERSION);
+		put_u16(&mut b[10..], CIPHER_AES256_XTS);
+		put_u32(&mut b[12..], self.kdf_iterations);
+		put_u32(&mut b[16..], self.data_offset);
+		b[24..][..SALT_LEN].copy_from_slice(&self.salt);
+		b[56..][..CHECK_LEN].copy_from_slice(&self.key_check);
+		b[88..][..xts::KEY_LEN].copy_from_slice(&self.wrapped_key);
+	}
+}
+
+#[test]
+fn kdf_iteration_limit()
+{
+	let mut hdr = Header {
+		kdf_iterations: MAX_KDF_ITERATIONS,
+		data_offset: 1,
+		salt: [1; SALT_LEN],
+		key_check: [2; CHECK_LEN],
+		wrapped_key: [3; xts::KEY_LEN],
+		};
+	let mut buf = [0u8; 512];
+	hdr.write(&mut buf);
+	assert_eq!( Header::parse(&buf).ok().map(|h| h.kdf_iterations), Some(MAX_KDF_ITERATIONS) );
+
+	hdr.kdf_iterations = MAX_KDF_ITERATIONS + 1;
+	hdr.write(&mut buf);
+	assert!( Header::parse(&buf).is_err() );
+}
@@ -0,0 +1,201 @@
+// "Tifflin" Kernel - Encrypted volumes
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_crypt/lib.rs
+//! Encrypted (AES-256-XTS) volumes
+//!
+//! An encrypted volume is a logical volume with a small header (see `header`) in its first block,
+//! followed by the encrypted data. Once unlocked with its passphrase, the decrypted data is registered
+//! as a new physical volume named `<volume>.crypt` - so a partition table, or a filesystem (as
+//! `<volume>.cryptw`) can be used on top of it.
+//!
+//! Each volume has a random master key (supplied when formatting), stored in the header encrypted with a
+//! key derived from the passphrase using PBKDF2-HMAC-SHA256. The passphrase can be changed without
+//! re-encrypting the data.
+#![feature(linkage)]
+#![no_std]
+
+#[macro_use]
+extern crate kernel;
+
+use kernel::prelude::*;
+use kernel::sync::Mutex;
+use kernel::metadevs::storage::{self, VolumeHandle, VolOpenError, IoError};
+
+module_define!{Crypt, [Storage], init}
+
+mod aes;
+mod xts;
+mod sha256;
+mod header;
+mod volume;
+
+/// Default PBKDF2 iteration count for newly formatted volumes
+const DEFAULT_KDF_ITERATIONS: u32 = 20_000;
+/// Label used to create the header's key check value
+const KEY_CHECK_LABEL: &'static [u8] = b"Tifflin encrypted volume key check";
+
+/// Length of the key material passed to `format` (master key followed by KDF salt)
+pub const KEY_MATERIAL_LEN: usize = xts::KEY_LEN + header::SALT_LEN;
+
+/// Unlocked volumes (underlying volume name, decrypted volume)
+static S_VOLUMES: Mutex<Vec<(String, storage::PhysicalVolumeReg)>> = Mutex::new(Vec::new_const());
+
+#[derive(Debug)]
+pub enum Error
+{
+	/// The named volume doesn't exist
+	NotFound,
+	/// The volume is already open (e.g. already unlocked)
+	Locked,
+	/// Missing or unsupported header
+	BadHeader,
+	/// The passphrase doesn't match the header
+	BadPassphrase,
+	/// Invalid key material, or the volume can't hold an encrypted volume
+	BadParameter,
+	/// The decrypted volume is still open
+	InUse,
+	Io(IoError),
+}
+impl_from! {
+	From<VolOpenError>(v) for Error {
+		match v
+		{
+		VolOpenError::NotFound => Error::NotFound,
+		VolOpenError::Locked => Error::Locked,
+		}
+	}
+	From<IoError>(v) for Error {
+		Error::Io(v)
+	}
+}
+
+fn init()
+{
+}
+
+/// Overwrite a buffer holding key material
+fn zeroise(b: &mut [u8])
+{
+	for v in b.iter_mut() {
+		// SAFE: Valid pointer, volatile so the clear isn't elided
+		unsafe { ::core::ptr::write_volatile(v, 0); }
+	}
+}
+
+/// Compare two buffers without an early exit
+fn secure_eq(a: &[u8], b: &[u8]) -> bool
+{
+	a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (a,b)| acc | (a ^ b)) == 0
+}
+
+/// Open a volume and check that it can be used to hold an encrypted volume
+fn open_volume(volume: &str) -> Result<VolumeHandle, Error>
+{
+	let vh = try!( VolumeHandle::open_named(volume) );
+	if vh.block_size() % 16 != 0 || vh.block_size() < header::HEADER_LEN || vh.block_count() < 2 {
+		log_notice!("Crypt: Volume {} unsuitable ({} blocks of {} bytes)", volume, vh.block_count(), vh.block_size());
+		return Err( Error::BadParameter );
+	}
+	Ok(vh)
+}
+
+/// Derive the key-encrypting key for a header
+fn derive_kek(passphrase: &[u8], hdr: &header::Header) -> xts::Xts
+{
+	let mut kek = [0; xts::KEY_LEN];
+	sha256::pbkdf2(passphrase, &hdr.salt, hdr.kdf_iterations, &mut kek);
+	let rv = xts::Xts::new(&kek);
+	zeroise(&mut kek);
+	rv
+}
+
+/// Write a new encrypted volume header to `volume`
+///
+/// `key_material` is `KEY_MATERIAL_LEN` random bytes (the kernel has no entropy source of its own).
+/// Existing data is not overwritten, so will read as noise once unlocked.
+pub fn format(volume: &str, passphrase: &[u8], key_material: &[u8]) -> Result<(), Error>
+{
+	if key_material.len() != KEY_MATERIAL_LEN {
+		return Err( Error::BadParameter );
+	}
+	let vh = try!(open_volume(volume));
+
+	let mut master = [0; xts::KEY_LEN];
+	master.copy_from_slice(&key_material[..xts::KEY_LEN]);
+	let mut hdr = header::Header {
+		kdf_iterations: DEFAULT_KDF_ITERATIONS,
+		data_offset: 1,
+		salt: [0; header::SALT_LEN],
+		key_check: sha256::Hmac::new(&master).compute(&[KEY_CHECK_LABEL]),
+		wrapped_key: master,
+		};
+	hdr.salt.copy_from_slice(&key_material[xts::KEY_LEN..]);
+	zeroise(&mut master);
+	derive_kek(passphrase, &hdr).encrypt_sector(0, &mut hdr.wrapped_key);
+
+	let mut buf: Vec<u8> = vec![0; vh.block_size()];
+	hdr.write(&mut buf);
+	try!( vh.write_blocks(0, &buf) );
+	try!( vh.flush() );
+	log_log!("Crypt: Formatted {} ({} data blocks)", volume, vh.block_count() - 1);
+	Ok( () )
+}
+
+/// Unlock an encrypted volume, registering the decrypted contents as a new physical volume
+///
+/// Returns the name of the new physical volume.
+pub fn unlock(volume: &str, passphrase: &[u8]) -> Result<String, Error>
+{
+	let vh = try!(open_volume(volume));
+	let hdr = {
+		let mut buf: Vec<u8> = vec![0; vh.block_size()];
+		try!( vh.read_blocks(0, &mut buf) );
+		try!( header::Header::parse(&buf) )
+		};
+	if hdr.data_offset as u64 >= vh.block_count() {
+		return Err( Error::BadHeader );
+	}
+
+	let mut master = hdr.wrapped_key;
+	derive_kek(passphrase, &hdr).decrypt_sector(0, &mut master);
+	let ok = secure_eq( &sha256::Hmac::new(&master).compute(&[KEY_CHECK_LABEL]), &hdr.key_check );
+	let cipher = xts::Xts::new(&master);
+	zeroise(&mut master);
+	if !ok {
+		log_notice!("Crypt: Incorrect passphrase for {}", volume);
+		return Err( Error::BadPassphrase );
+	}
+
+	let vol = volume::CryptVolume::new(vh, cipher, hdr.data_offset as u64);
+	let name = String::from( storage::PhysicalVolume::name(&vol) );
+	log_log!("Crypt: Unlocked {} as {}", volume, name);
+	let reg = storage::register_pv( Box::new(vol) );
+	S_VOLUMES.lock().push( (String::from(volume), reg) );
+	Ok(name)
+}
+
+/// Lock an unlocked volume, removing the decrypted physical volume (and releasing the underlying volume)
+///
+/// Fails with `InUse` if any of the decrypted volume's logical volumes are open.
+pub fn lock(volume: &str) -> Result<(), Error>
+{
+	let reg = {
+		let mut lh = S_VOLUMES.lock();
+		let i = match lh.iter().position(|e| e.0 == volume)
+			{
+			Some(i) => i,
+			None => return Err( Error::NotFound ),
+			};
+		if lh[i].1.is_in_use() {
+			log_notice!("Crypt: Can't lock {}, decrypted volume in use", volume);
+			return Err( Error::InUse );
+		}
+		lh.remove(i).1
+		};
+	// - Dropped with the lock released (removing the PV takes the storage locks)
+	drop(reg);
+	log_log!("Crypt: Locked {}", volume);
+	Ok( () )
+}
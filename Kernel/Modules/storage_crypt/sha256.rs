@@ -0,0 +1,293 @@
+// "Tifflin" Kernel - Encrypted volumes
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_crypt/sha256.rs
+//! SHA-256, HMAC-SHA256 and PBKDF2 (for passphrase key derivation)
+
+const K: [u32; 64] = [
+	0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+	0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+	0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+	0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+	0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+	0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+	0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+	0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+	];
+const H0: [u32; 8] = [
+	0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+	];
+
+pub const DIGEST_LEN: usize = 32;
+const BLOCK_LEN: usize = 64;
+
+#[derive(Clone)]
+pub struct Sha256
+{
+	state: [u32; 8],
+	buf: [u8; BLOCK_LEN],
+	buf_len: usize,
+	total_len: u64,
+}
+
+impl Sha256
+{
+	pub fn new() -> Sha256
+	{
+		Sha256 {
+			state: H0,
+			buf: [0; BLOCK_LEN],
+			buf_len: 0,
+			total_len: 0,
+			}
+	}
+
+	pub fn update(&mut self, mut data: &[u8])
+	{
+		self.total_len += data.len() as u64;
+		if self.buf_len > 0
+		{
+			let n = ::core::cmp::min(BLOCK_LEN - self.buf_len, data.len());
+			self.buf[self.buf_len ..][..n].copy_from_slice(&data[..n]);
+			self.buf_len += n;
+			data = &data[n..];
+			if self.buf_len < BLOCK_LEN {
+				return ;
+			}
+			let b = self.buf;
+			self.compress(&b);
+			self.buf_len = 0;
+		}
+		while data.len() >= BLOCK_LEN
+		{
+			self.compress(&data[..BLOCK_LEN]);
+			data = &data[BLOCK_LEN..];
+		}
+		self.buf[..data.len()].copy_from_slice(data);
+		self.buf_len = data.len();
+	}
+
+	pub fn finish(mut self) -> [u8; DIGEST_LEN]
+	{
+		let bit_len = self.total_len * 8;
+		self.update(&[0x80]);
+		while self.buf_len != BLOCK_LEN - 8 {
+			self.update(&[0]);
+		}
+		let mut len_bytes = [0; 8];
+		for i in 0 .. 8 {
+			len_bytes[i] = (bit_len >> (56 - 8*i)) as u8;
+		}
+		self.update(&len_bytes);
+
+		let mut rv = [0; DIGEST_LEN];
+		for (i, w) in self.state.iter().enumerate() {
+			for j in 0 .. 4 {
+				rv[i*4 + j] = (w >> (24 - 8*j)) as u8;
+			}
+		}
+		rv
+	}
+
+	fn compress(&mut self, block: &[u8])
+	{
+		let mut w = [0u32; 64];
+		for i in 0 .. 16 {
+			w[i] = (block[i*4] as u32) << 24 | (block[i*4+1] as u32) << 16 | (block[i*4+2] as u32) << 8 | block[i*4+3] as u32;
+		}
+		for i in 16 .. 64 {
+			let s0 = w[i-15].rotate_right(7) ^ w[i-15].rotate_right(18) ^ (w[i-15] >> 3);
+			let s1 = w[i-2].rotate_right(17) ^ w[i-2].rotate_right(19) ^ (w[i-2] >> 10);
+			w[i] = w[i-16].wrapping_add(s0).wrapping_add(w[i-7]).wrapping_add(s1);
+		}
+
+		let mut v = self.state;
+		for i in 0 .. 64
+		{
+			let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+			let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+			let t1 = v[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+			let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+			let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+			let t2 = s0.wrapping_add(maj);
+			v = [t1.wrapping_add(t2), v[0], v[1], v[2], v[3].wrapping_add(t1), v[4], v[5], v[6]];
+		}
+		for (s, v) in self.state.iter_mut().zip(v.iter()) {
+			*s = s.wrapping_add(*v);
+		}
+	}
+}
+
+impl ::core::ops::Drop for Sha256
+{
+	fn drop(&mut self)
+	{
+		// State is derived from the data (which may be key material, e.g. the HMAC pads)
+		for w in self.state.iter_mut() {
+			// SAFE: Valid pointer, volatile so the clear isn't elided
+			unsafe { ::core::ptr::write_volatile(w, 0); }
+		}
+		::zeroise(&mut self.buf);
+	}
+}
+
+/// HMAC-SHA256 with a pre-hashed key
+#[derive(Clone)]
+pub struct Hmac
+{
+	inner: Sha256,
+	outer: Sha256,
+}
+impl Hmac
+{
+	pub fn new(key: &[u8]) -> Hmac
+	{
+		let mut k = [0u8; BLOCK_LEN];
+		if key.len() > BLOCK_LEN {
+			let mut h = Sha256::new();
+			h.update(key);
+			k[..DIGEST_LEN].copy_from_slice(&h.finish());
+		}
+		else {
+			k[..key.len()].copy_from_slice(key);
+		}
+
+		let mut pad = [0u8; BLOCK_LEN];
+		for (p, k) in pad.iter_mut().zip(k.iter()) {
+			*p = k ^ 0x36;
+		}
+		let mut inner = Sha256::new();
+		inner.update(&pad);
+		for (p, k) in pad.iter_mut().zip(k.iter()) {
+			*p = k ^ 0x5c;
+		}
+		let mut outer = Sha256::new();
+		outer.update(&pad);
+
+		::zeroise(&mut k);
+		::zeroise(&mut pad);
+		Hmac { inner: inner, outer: outer }
+	}
+
+	/// Compute the MAC of the concatenation of `parts`
+	pub fn compute(&self, parts: &[&[u8]]) -> [u8; DIGEST_LEN]
+	{
+		let mut inner = self.inner.clone();
+		for p in parts {
+			inner.update(p);
+		}
+		let mut outer = self.outer.clone();
+		outer.update(&inner.finish());
+		outer.finish()
+	}
+}
+
+/// PBKDF2-HMAC-SHA256, filling `out` with key material
+pub fn pbkdf2(password: &[u8], salt: &[u8], iterations: u32, out: &mut [u8])
+{
+	let mac = Hmac::new(password);
+	for (i, dst) in out.chunks_mut(DIGEST_LEN).enumerate()
+	{
+		let idx = i as u32 + 1;
+		let mut u = mac.compute(&[salt, &[(idx >> 24) as u8, (idx >> 16) as u8, (idx >> 8) as u8, idx as u8]]);
+		let mut t = u;
+		for _ in 1 .. iterations
+		{
+			u = mac.compute(&[&u]);
+			for (t, u) in t.iter_mut().zip(u.iter()) {
+				*t ^= *u;
+			}
+		}
+		let n = dst.len();
+		dst.copy_from_slice(&t[..n]);
+		::zeroise(&mut t);
+		::zeroise(&mut u);
+	}
+}
+
+#[cfg(test)]
+fn digest(data: &[u8]) -> [u8; DIGEST_LEN]
+{
+	let mut h = Sha256::new();
+	h.update(data);
+	h.finish()
+}
+
+// FIPS 180-2 examples
+#[test]
+fn sha256_abc()
+{
+	assert_eq!(digest(b""), [
+		0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f, 0xb9, 0x24,
+		0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b, 0x78, 0x52, 0xb8, 0x55,
+		]);
+	assert_eq!(digest(b"abc"), [
+		0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae, 0x22, 0x23,
+		0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61, 0xf2, 0x00, 0x15, 0xad,
+		]);
+}
+#[test]
+fn sha256_two_blocks()
+{
+	let data = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+	let expected = [
+		0x24, 0x8d, 0x6a, 0x61, 0xd2, 0x06, 0x38, 0xb8, 0xe5, 0xc0, 0x26, 0x93, 0x0c, 0x3e, 0x60, 0x39,
+		0xa3, 0x3c, 0xe4, 0x59, 0x64, 0xff, 0x21, 0x67, 0xf6, 0xec, 0xed, 0xd4, 0x19, 0xdb, 0x06, 0xc1,
+		];
+	assert_eq!(digest(data), expected);
+	// Same data, split across `update` calls
+	let mut h = Sha256::new();
+	for c in data.chunks(7) {
+		h.update(c);
+	}
+	assert_eq!(h.finish(), expected);
+}
+
+// RFC 4231 test cases 1, 2 and 6
+#[test]
+fn hmac_rfc4231()
+{
+	assert_eq!(Hmac::new(&[0x0b; 20]).compute(&[b"Hi There"]), [
+		0xb0, 0x34, 0x4c, 0x61, 0xd8, 0xdb, 0x38, 0x53, 0x5c, 0xa8, 0xaf, 0xce, 0xaf, 0x0b, 0xf1, 0x2b,
+		0x88, 0x1d, 0xc2, 0x00, 0xc9, 0x83, 0x3d, 0xa7, 0x26, 0xe9, 0x37, 0x6c, 0x2e, 0x32, 0xcf, 0xf7,
+		]);
+	assert_eq!(Hmac::new(b"Jefe").compute(&[b"what do ya want ", b"for nothing?"]), [
+		0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95, 0x75, 0xc7,
+		0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9, 0x64, 0xec, 0x38, 0x43,
+		]);
+	// Key longer than the block size
+	assert_eq!(Hmac::new(&[0xaa; 131]).compute(&[b"Test Using Larger Than Block-Size Key - Hash Key First"]), [
+		0x60, 0xe4, 0x31, 0x59, 0x1e, 0xe0, 0xb6, 0x7f, 0x0d, 0x8a, 0x26, 0xaa, 0xcb, 0xf5, 0xb7, 0x7f,
+		0x8e, 0x0b, 0xc6, 0x21, 0x37, 0x28, 0xc5, 0x14, 0x05, 0x46, 0x04, 0x0f, 0x0e, 0xe3, 0x7f, 0x54,
+		]);
+}
+
+// RFC 6070 inputs (which only lists PBKDF2-HMAC-SHA1 outputs), with the corresponding SHA-256 results
+#[test]
+fn pbkdf2_rfc6070()
+{
+	let mut out = [0; 32];
+	pbkdf2(b"password", b"salt", 1, &mut out);
+	assert_eq!(out, [
+		0x12, 0x0f, 0xb6, 0xcf, 0xfc, 0xf8, 0xb3, 0x2c, 0x43, 0xe7, 0x22, 0x52, 0x56, 0xc4, 0xf8, 0x37,
+		0xa8, 0x65, 0x48, 0xc9, 0x2c, 0xcc, 0x35, 0x48, 0x08, 0x05, 0x98, 0x7c, 0xb7, 0x0b, 0xe1, 0x7b,
+		]);
+	pbkdf2(b"password", b"salt", 2, &mut out);
+	assert_eq!(out, [
+		0xae, 0x4d, 0x0c, 0x95, 0xaf, 0x6b, 0x46, 0xd3, 0x2d, 0x0a, 0xdf, 0xf9, 0x28, 0xf0, 0x6d, 0xd0,
+		0x2a, 0x30, 0x3f, 0x8e, 0xf3, 0xc2, 0x51, 0xdf, 0xd6, 0xe2, 0xd8, 0x5a, 0x95, 0x47, 0x4c, 0x43,
+		]);
+	pbkdf2(b"password", b"salt", 4096, &mut out);
+	assert_eq!(out, [
+		0xc5, 0xe4, 0x78, 0xd5, 0x92, 0x88, 0xc8, 0x41, 0xaa, 0x53, 0x0d, 0xb6, 0x84, 0x5c, 0x4c, 0x8d,
+		0x96, 0x28, 0x93, 0xa0, 0x01, 0xce, 0x4e, 0x11, 0xa4, 0x96, 0x38, 0x73, 0xaa, 0x98, 0x13, 0x4a,
+		]);
+	// Multiple output blocks, with a partial final block
+	let mut out = [0; 40];
+	pbkdf2(b"passwordPASSWORDpassword", b"saltSALTsaltSALTsaltSALTsaltSALTsalt", 4096, &mut out);
+	assert_eq!(&out[..], &[
+		0x34, 0x8c, 0x89, 0xdb, 0xcb, 0xd3, 0x2b, 0x2f, 0x32, 0xd8, 0x14, 0xb8, 0x11, 0x6e, 0x84, 0xcf,
+		0x2b, 0x17, 0x34, 0x7e, 0xbc, 0x18, 0x00, 0x18, 0x1c, 0x4e, 0x2a, 0x1f, 0xb8, 0xdd, 0x53, 0xe1,
+		0xc6, 0x35, 0x51, 0x8c, 0x7d, 0xac, 0x47, 0xe9,
+		][..]);
+}
@@ -0,0 +1,99 @@
+// "Tifflin" Kernel - Encrypted volumes
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_crypt/volume.rs
+//! Decrypted view of a volume (registered as a physical volume)
+use kernel::prelude::*;
+use kernel::metadevs::storage::{self, VolumeHandle, IoError, IoPriority};
+use kernel::async;
+use xts::Xts;
+
+pub struct CryptVolume
+{
+	name: String,
+	inner: VolumeHandle,
+	cipher: Xts,
+	block_size: usize,
+	/// First block of encrypted data on `inner`
+	data_offset: u64,
+	block_count: u64,
+}
+
+impl CryptVolume
+{
+	pub fn new(inner: VolumeHandle, cipher: Xts, data_offset: u64) -> CryptVolume
+	{
+		CryptVolume {
+			name: format!("{}.crypt", inner.name()),
+			block_size: inner.block_size(),
+			block_count: inner.block_count() - data_offset,
+			inner: inner,
+			cipher: cipher,
+			data_offset: data_offset,
+			}
+	}
+
+	fn check_range(&self, idx: u64, count: usize) -> Result<(), IoError>
+	{
+		if idx >= self.block_count || count as u64 > self.block_count - idx {
+			Err( IoError::BadAddr )
+		}
+		else {
+			Ok( () )
+		}
+	}
+
+	fn read(&self, prio: IoPriority, idx: u64, dst: &mut [u8]) -> Result<(), IoError>
+	{
+		try!( self.inner.read_blocks_prio(prio, self.data_offset + idx, dst) );
+		for (i, blk) in dst.chunks_mut(self.block_size).enumerate()
+		{
+			self.cipher.decrypt_sector(idx + i as u64, blk);
+		}
+		Ok( () )
+	}
+
+	fn write(&self, prio: IoPriority, idx: u64, src: &[u8]) -> Result<(), IoError>
+	{
+		// Source is borrowed immutably, so encrypt into a bounce buffer
+		let mut buf: Vec<u8> = Vec::from(src);
+		for (i, blk) in buf.chunks_mut(self.block_size).enumerate()
+		{
+			self.cipher.encrypt_sector(idx + i as u64, blk);
+		}
+		self.inner.write_blocks_prio(prio, self.data_offset + idx, &buf)
+	}
+}
+
+impl storage::PhysicalVolume for CryptVolume
+{
+	fn name(&self) -> &str { &self.name }
+	fn blocksize(&self) -> Result<usize,storage::IoError> { Ok(self.block_size) }
+	fn capacity(&self) -> Option<u64> { Some(self.block_count) }
+
+	fn read<'a>(&'a self, prio: u8, idx: u64, num: usize, dst: &'a mut [u8]) -> storage::AsyncIoResult<'a,usize>
+	{
+		assert_eq!( dst.len(), num * self.block_size );
+		let rv = self.check_range(idx, num).and_then(|_| self.read(IoPriority::from_driver_prio(prio), idx, dst)).map(|_| num);
+		Box::new( async::NullResultWaiter::new( move || rv ) )
+	}
+	fn write<'a>(&'a self, prio: u8, idx: u64, num: usize, src: &'a [u8]) -> storage::AsyncIoResult<'a,usize>
+	{
+		assert_eq!( src.len(), num * self.block_size );
+		let rv = self.check_range(idx, num).and_then(|_| self.write(IoPriority::from_driver_prio(prio), idx, src)).map(|_| num);
+		Box::new( async::NullResultWaiter::new( move || rv ) )
+	}
+
+	fn wipe<'a>(&'a self, idx: u64, num: usize) -> storage::AsyncIoResult<'a,()>
+	{
+		// Not forwarded, as it would reveal which blocks are in use
+		let rv = self.check_range(idx, num);
+		Box::new( async::NullResultWaiter::new( move || rv ) )
+	}
+
+	fn flush<'a>(&'a self) -> storage::AsyncIoResult<'a,()>
+	{
+		let rv = self.inner.flush();
+		Box::new( async::NullResultWaiter::new( move || rv ) )
+	}
+}
@@ -0,0 +1,117 @@
+// "Tifflin" Kernel - Encrypted volumes
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_crypt/xts.rs
+//! XTS mode (IEEE 1619) using AES-256
+//!
+//! Sectors are always a multiple of the cipher block size, so ciphertext stealing isn't needed.
+use aes::Aes256;
+
+pub const KEY_LEN: usize = 64;
+
+pub struct Xts
+{
+	data: Aes256,
+	tweak: Aes256,
+}
+
+impl Xts
+{
+	/// Create from a 512-bit key (data key followed by tweak key)
+	pub fn new(key: &[u8; KEY_LEN]) -> Xts
+	{
+		let mut k1 = [0; 32];
+		let mut k2 = [0; 32];
+		k1.copy_from_slice(&key[..32]);
+		k2.copy_from_slice(&key[32..]);
+		let rv = Xts {
+			data: Aes256::new(&k1),
+			tweak: Aes256::new(&k2),
+			};
+		::zeroise(&mut k1);
+		::zeroise(&mut k2);
+		rv
+	}
+
+	pub fn encrypt_sector(&self, sector: u64, buf: &mut [u8])
+	{
+		self.process(sector, buf, false)
+	}
+	pub fn decrypt_sector(&self, sector: u64, buf: &mut [u8])
+	{
+		self.process(sector, buf, true)
+	}
+
+	fn process(&self, sector: u64, buf: &mut [u8], decrypt: bool)
+	{
+		assert!(buf.len() % 16 == 0, "XTS sector size {} not a multiple of 16", buf.len());
+		let mut t = [0u8; 16];
+		for i in 0 .. 8 {
+			t[i] = (sector >> (8*i)) as u8;
+		}
+		self.tweak.encrypt_block(&mut t);
+
+		for blk in buf.chunks_mut(16)
+		{
+			let mut b = [0u8; 16];
+			for i in 0 .. 16 {
+				b[i] = blk[i] ^ t[i];
+			}
+			if decrypt {
+				self.data.decrypt_block(&mut b);
+			}
+			else {
+				self.data.encrypt_block(&mut b);
+			}
+			for i in 0 .. 16 {
+				blk[i] = b[i] ^ t[i];
+			}
+			mul_alpha(&mut t);
+		}
+		::zeroise(&mut t);
+	}
+}
+
+/// Multiply the tweak by the primitive element of GF(2^128) (little-endian)
+fn mul_alpha(t: &mut [u8; 16])
+{
+	let mut carry = 0;
+	for b in t.iter_mut()
+	{
+		let next = *b >> 7;
+		*b = (*b << 1) | carry;
+		carry = next;
+	}
+	if carry != 0 {
+		t[0] ^= 0x87;
+	}
+}
+
+// IEEE 1619-2007 Vector 10 (AES-256, 512-byte data unit 0xFF)
+#[test]
+fn ieee1619_vector10()
+{
+	let key = [
+		0x27, 0x18, 0x28, 0x18, 0x28, 0x45, 0x90, 0x45, 0x23, 0x53, 0x60, 0x28, 0x74, 0x71, 0x35, 0x26,
+		0x62, 0x49, 0x77, 0x57, 0x24, 0x70, 0x93, 0x69, 0x99, 0x59, 0x57, 0x49, 0x66, 0x96, 0x76, 0x27,
+		0x31, 0x41, 0x59, 0x26, 0x53, 0x58, 0x97, 0x93, 0x23, 0x84, 0x62, 0x64, 0x33, 0x83, 0x27, 0x95,
+		0x02, 0x88, 0x41, 0x97, 0x16, 0x93, 0x99, 0x37, 0x51, 0x05, 0x82, 0x09, 0x74, 0x94, 0x45, 0x92,
+		];
+	let mut buf = [0u8; 512];
+	for (i, b) in buf.iter_mut().enumerate() {
+		*b = i as u8;
+	}
+	let xts = Xts::new(&key);
+	xts.encrypt_sector(0xFF, &mut buf);
+	assert_eq!(&buf[..32], &[
+		0x1c, 0x3b, 0x3a, 0x10, 0x2f, 0x77, 0x03, 0x86, 0xe4, 0x83, 0x6c, 0x99, 0xe3, 0x70, 0xcf, 0x9b,
+		0xea, 0x00, 0x80, 0x3f, 0x5e, 0x48, 0x23, 0x57, 0xa4, 0xae, 0x12, 0xd4, 0x14, 0xa3, 0xe6, 0x3b,
+		][..]);
+	assert_eq!(&buf[512-16..], &[
+		0xc4, 0xf3, 0x6f, 0xfd, 0xa9, 0xfc, 0xea, 0x70, 0xb9, 0xc6, 0xe6, 0x93, 0xe1, 0x48, 0xc1, 0x51,
+		][..]);
+	xts.decrypt_sector(0xFF, &mut buf);
+	for (i, b) in buf.iter().enumerate() {
+		assert_eq!(*b, i as u8);
+	}
+}
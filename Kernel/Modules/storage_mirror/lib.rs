@@ -0,0 +1,150 @@
+// "Tifflin" Kernel - Software mirror (RAID-1) volumes
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_mirror/lib.rs
+//! Software mirror (RAID-1) volumes
+//!
+//! A mirror combines two or more logical volumes into a new physical volume (so mappers see it like any
+//! other disk). Reads are spread round-robin over the in-sync members, writes go to all members. Members
+//! that return errors are dropped from the set (degraded operation), and regions written while a member is
+//! missing are tracked in a dirty bitmap so only those need copying when the member returns (either as a new
+//! volume, or when a periodic retry finds it responding again).
+//!
+//! Member volumes stay open (and hence can't be opened by anything else) for the lifetime of the mirror.
+//!
+//! Mirrors are assembled from the `MIRROR` boot option (e.g. `MIRROR=md0:VirtIO0w+VirtIO1w`) once all of
+//! their members have appeared, or by calling `create`.
+#![feature(linkage)]
+#![no_std]
+
+#[macro_use]
+extern crate kernel;
+
+use kernel::prelude::*;
+use kernel::lib::mem::Arc;
+use kernel::sync::{Mutex, EventChannel};
+use kernel::sync::mutex::LazyMutex;
+use kernel::metadevs::storage;
+
+module_define!{Mirror, [Storage], init}
+
+mod mirror;
+
+pub use mirror::Error;
+
+/// Mirrors waiting for their members to appear (name, member names)
+static S_PENDING: Mutex<Vec<(String, Vec<String>)>> = Mutex::new(Vec::new_const());
+/// Assembled mirrors
+static S_MIRRORS: Mutex<Vec<(Arc<mirror::Mirror>, storage::PhysicalVolumeReg)>> = Mutex::new(Vec::new_const());
+
+static S_RESYNC_THREAD: LazyMutex<::kernel::threads::WorkerThread> = LazyMutex::new();
+/// Posted when a member is waiting to be resynced
+static S_RESYNC_EVENT: EventChannel = EventChannel::new();
+/// Interval between attempts to recover faulty members (and to restart aborted resyncs)
+const RETRY_INTERVAL_MS: u64 = 10*1000;
+
+fn init()
+{
+	S_RESYNC_THREAD.init(|| ::kernel::threads::WorkerThread::new("Mirror Resync", resync_thread));
+	storage::register_lv_watcher(lv_added);
+
+	for ent in ::kernel::config::get_string(::kernel::config::Value::Mirrors).split(',').filter(|v| *v != "")
+	{
+		let mut it = ent.splitn(2, ':');
+		let name = it.next().unwrap();
+		let members: Vec<String> = match it.next()
+			{
+			Some(v) => v.split('+').filter(|v| *v != "").map(|v| String::from(v)).collect(),
+			None => Vec::new(),
+			};
+		if members.len() < 2 {
+			log_warning!("MIRROR: '{}' needs at least two members", ent);
+			continue ;
+		}
+		S_PENDING.lock().push( (String::from(name), members) );
+	}
+	try_assemble();
+}
+
+/// Create a mirror from the named logical volumes, registering it as a new physical volume
+///
+/// Members are assumed to already hold the same data (the dirty bitmap isn't persisted).
+pub fn create(name: &str, members: &[&str]) -> Result<(), Error>
+{
+	let m = Arc::new( try!(mirror::Mirror::new(name, members)) );
+	let reg = storage::register_pv( Box::new(mirror::MirrorVolume(m.clone())) );
+	S_MIRRORS.lock().push( (m, reg) );
+	Ok( () )
+}
+
+/// Assemble any pending mirrors that have all of their members present
+fn try_assemble()
+{
+	let present: Vec<String> = storage::enum_lvs().into_iter().map(|(_,name)| name).collect();
+	let ready = {
+		let mut lh = S_PENDING.lock();
+		let mut ready = Vec::new();
+		let mut i = 0;
+		while i < lh.len()
+		{
+			if lh[i].1.iter().all(|m| present.contains(m)) {
+				ready.push( lh.remove(i) );
+			}
+			else {
+				i += 1;
+			}
+		}
+		ready
+		};
+
+	for (name, members) in ready
+	{
+		let members: Vec<&str> = members.iter().map(|v| &v[..]).collect();
+		if let Err(e) = create(&name, &members) {
+			log_error!("Mirror {}: Assembly failed - {:?}", name, e);
+		}
+	}
+}
+
+/// Storage callback: A new logical volume has appeared
+fn lv_added(name: &str)
+{
+	// A failed member returning?
+	let mirrors: Vec<_> = S_MIRRORS.lock().iter().map(|e| e.0.clone()).collect();
+	for m in mirrors
+	{
+		if m.reattach(name) {
+			S_RESYNC_EVENT.post();
+		}
+	}
+
+	if S_PENDING.lock().iter().any(|e| e.1.iter().any(|m| m == name)) {
+		try_assemble();
+	}
+}
+
+fn resync_thread()
+{
+	loop
+	{
+		S_RESYNC_EVENT.sleep();
+		let mirrors: Vec<_> = S_MIRRORS.lock().iter().map(|e| e.0.clone()).collect();
+		let mut retry = false;
+		for m in mirrors
+		{
+			m.retry_faulty();
+			if ! m.resync() {
+				retry = true;
+			}
+			if m.is_degraded() {
+				retry = true;
+			}
+		}
+		// Members that failed (or resyncs that were aborted) are retried periodically
+		if retry {
+			if ! ::kernel::time::post_at(::kernel::time::ticks() + RETRY_INTERVAL_MS, &S_RESYNC_EVENT) {
+				log_warning!("Mirror: Unable to schedule a retry of faulty members");
+			}
+		}
+	}
+}
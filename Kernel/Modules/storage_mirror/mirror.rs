@@ -0,0 +1,402 @@
+// "Tifflin" Kernel - Software mirror (RAID-1) volumes
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_mirror/mirror.rs
+//! Mirror state and the physical volume implementation
+use kernel::prelude::*;
+use kernel::lib::mem::Arc;
+use kernel::sync::{Mutex, RwLock};
+use kernel::metadevs::storage::{self, VolumeHandle, VolOpenError, IoError, IoPriority};
+use kernel::async;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Number of blocks tracked by each bit of the dirty bitmap
+const REGION_BLOCKS: u64 = 128;
+
+/// Member is in sync and serving reads
+const STATE_ACTIVE: usize = 0;
+/// Member has failed (or is missing), it receives no I/O
+const STATE_FAULTY: usize = 1;
+/// Member has returned, and receives writes but not reads until the resync completes
+const STATE_RESYNC: usize = 2;
+
+#[derive(Debug)]
+pub enum Error
+{
+	TooFewMembers,
+	Open(String, VolOpenError),
+	BlockSizeMismatch(String),
+}
+
+struct Member
+{
+	name: String,
+	/// Kept open while the member is faulty, so nothing else can claim the volume (replaced if the volume returns)
+	handle: RwLock<Option<VolumeHandle>>,
+	state: AtomicUsize,
+	/// Member was active (holding a full copy) when it last failed
+	failed_active: AtomicBool,
+}
+
+pub struct Mirror
+{
+	name: String,
+	block_size: usize,
+	block_count: u64,
+	members: Vec<Member>,
+	next_read: AtomicUsize,
+	/// Regions (of `REGION_BLOCKS`) written while a member was not in sync
+	dirty: Mutex<Vec<u64>>,
+	/// Held for read by writes, for write by the resync of a region (stops writes racing the copy)
+	write_gate: RwLock<()>,
+}
+
+impl Mirror
+{
+	// NOTE: The dirty bitmap is not persisted, so members are assumed to be in sync at assembly
+	pub fn new(name: &str, member_names: &[&str]) -> Result<Mirror, Error>
+	{
+		if member_names.len() < 2 {
+			return Err( Error::TooFewMembers );
+		}
+
+		let mut members = Vec::with_capacity(member_names.len());
+		for &n in member_names
+		{
+			let h = match VolumeHandle::open_named(n)
+				{
+				Ok(h) => h,
+				Err(e) => return Err( Error::Open(String::from(n), e) ),
+				};
+			members.push( Member {
+				name: String::from(n),
+				handle: RwLock::new(Some(h)),
+				state: AtomicUsize::new(STATE_ACTIVE),
+				failed_active: AtomicBool::new(false),
+				} );
+		}
+
+		let (block_size, block_count) = {
+			let h0 = members[0].handle.read();
+			let h0 = h0.as_ref().unwrap();
+			(h0.block_size(), h0.block_count())
+			};
+		let mut count = block_count;
+		for m in &members[1..]
+		{
+			let h = m.handle.read();
+			let h = h.as_ref().unwrap();
+			if h.block_size() != block_size {
+				return Err( Error::BlockSizeMismatch(m.name.clone()) );
+			}
+			count = ::core::cmp::min(count, h.block_count());
+		}
+
+		let n_regions = (count + REGION_BLOCKS - 1) / REGION_BLOCKS;
+		log_notice!("Mirror {}: {} members, {} blocks of {} bytes", name, members.len(), count, block_size);
+		Ok(Mirror {
+			name: String::from(name),
+			block_size: block_size,
+			block_count: count,
+			members: members,
+			next_read: AtomicUsize::new(0),
+			dirty: Mutex::new( vec![0; ((n_regions + 63) / 64) as usize] ),
+			write_gate: RwLock::new( () ),
+			})
+	}
+
+	fn check_range(&self, idx: u64, count: usize) -> Result<(), IoError>
+	{
+		if idx >= self.block_count || count as u64 > self.block_count - idx {
+			Err( IoError::BadAddr )
+		}
+		else {
+			Ok( () )
+		}
+	}
+
+	/// Mark a member as failed (its volume stays claimed, and is retried by `retry_faulty`)
+	fn fail_member(&self, i: usize, err: IoError)
+	{
+		let m = &self.members[i];
+		let prev = m.state.swap(STATE_FAULTY, Ordering::SeqCst);
+		if prev != STATE_FAULTY {
+			m.failed_active.store(prev == STATE_ACTIVE, Ordering::SeqCst);
+			let n_active = self.members.iter().filter(|m| m.state.load(Ordering::SeqCst) == STATE_ACTIVE).count();
+			log_warning!("Mirror {}: Member {} failed ({:?}), running degraded ({} of {} active)",
+				self.name, m.name, err, n_active, self.members.len());
+		}
+	}
+
+	/// Returns true if any member is faulty
+	pub fn is_degraded(&self) -> bool
+	{
+		self.members.iter().any(|m| m.state.load(Ordering::SeqCst) == STATE_FAULTY)
+	}
+
+	/// Probe faulty members that are still attached, returning them to service if they respond (e.g. after a
+	/// transient error). Returns true if any member now needs a resync
+	pub fn retry_faulty(&self) -> bool
+	{
+		let mut rv = false;
+		let mut buf: Vec<u8> = vec![0; self.block_size];
+		for m in &self.members
+		{
+			if m.state.load(Ordering::SeqCst) != STATE_FAULTY {
+				continue ;
+			}
+			let ok = match *m.handle.read()
+				{
+				Some(ref h) => h.read_blocks_prio(IoPriority::Bulk, 0, &mut buf).is_ok(),
+				None => false,
+				};
+			if ok {
+				self.restore_member(m);
+				rv = true;
+			}
+		}
+		rv
+	}
+
+	/// Return a (re-opened or recovered) faulty member to service
+	fn restore_member(&self, m: &Member)
+	{
+		// With no active members there's no source for a resync. A member that held a full copy when it failed
+		// is the best available data, so it becomes active (writes it missed are lost)
+		let _gate = self.write_gate.write();
+		if m.failed_active.load(Ordering::SeqCst) && ! self.members.iter().any(|m| m.state.load(Ordering::SeqCst) == STATE_ACTIVE) {
+			log_warning!("Mirror {}: Member {} returned with no active members, restoring it as active", self.name, m.name);
+			m.state.store(STATE_ACTIVE, Ordering::SeqCst);
+		}
+		else {
+			log_notice!("Mirror {}: Member {} returned, resyncing", self.name, m.name);
+			m.state.store(STATE_RESYNC, Ordering::SeqCst);
+		}
+	}
+
+	fn mark_dirty(&self, idx: u64, count: usize)
+	{
+		let first = idx / REGION_BLOCKS;
+		let last = (idx + count as u64 - 1) / REGION_BLOCKS;
+		let mut lh = self.dirty.lock();
+		for r in first .. last + 1
+		{
+			lh[(r / 64) as usize] |= 1 << (r % 64);
+		}
+	}
+
+	fn read(&self, prio: IoPriority, idx: u64, dst: &mut [u8]) -> Result<(), IoError>
+	{
+		let n = self.members.len();
+		let start = self.next_read.fetch_add(1, Ordering::Relaxed);
+		let mut last_err = IoError::Unknown("Mirror: No active members");
+		for i in (0 .. n).map(|i| (start + i) % n)
+		{
+			if self.members[i].state.load(Ordering::SeqCst) != STATE_ACTIVE {
+				continue ;
+			}
+			let rv = match *self.members[i].handle.read()
+				{
+				Some(ref h) => h.read_blocks_prio(prio, idx, dst),
+				None => continue,
+				};
+			match rv
+			{
+			Ok(_) => return Ok( () ),
+			// Caller errors, another member won't do any better
+			Err(e @ IoError::BadAddr) | Err(e @ IoError::InvalidParameter) => return Err(e),
+			Err(e) => {
+				self.fail_member(i, e);
+				last_err = e;
+				},
+			}
+		}
+		Err( last_err )
+	}
+
+	fn write(&self, prio: IoPriority, idx: u64, src: &[u8]) -> Result<(), IoError>
+	{
+		let _gate = self.write_gate.read();
+		let mut n_ok = 0;
+		let mut missed = false;
+		let mut last_err = IoError::Unknown("Mirror: No active members");
+		for (i, m) in self.members.iter().enumerate()
+		{
+			let state = m.state.load(Ordering::SeqCst);
+			if state == STATE_FAULTY {
+				missed = true;
+				continue ;
+			}
+			let rv = match *m.handle.read()
+				{
+				Some(ref h) => h.write_blocks_prio(prio, idx, src),
+				None => { missed = true; continue },
+				};
+			match rv
+			{
+			Ok(_) => if state == STATE_ACTIVE { n_ok += 1 },
+			Err(e @ IoError::BadAddr) | Err(e @ IoError::InvalidParameter) => return Err(e),
+			Err(e) => {
+				self.fail_member(i, e);
+				missed = true;
+				last_err = e;
+				},
+			}
+		}
+		if missed {
+			self.mark_dirty(idx, src.len() / self.block_size);
+		}
+		if n_ok > 0 {
+			Ok( () )
+		}
+		else {
+			Err( last_err )
+		}
+	}
+
+	fn flush(&self) -> Result<(), IoError>
+	{
+		let mut rv = Err( IoError::Unknown("Mirror: No active members") );
+		for (i, m) in self.members.iter().enumerate()
+		{
+			if m.state.load(Ordering::SeqCst) == STATE_FAULTY {
+				continue ;
+			}
+			let r = match *m.handle.read()
+				{
+				Some(ref h) => h.flush(),
+				None => continue,
+				};
+			match r
+			{
+			Ok(_) => if rv.is_err() { rv = Ok( () ) },
+			Err(e) => {
+				self.fail_member(i, e);
+				if rv.is_err() { rv = Err(e) }
+				},
+			}
+		}
+		rv
+	}
+
+	/// Re-open a failed member that has re-appeared, returns true if it now needs a resync
+	pub fn reattach(&self, name: &str) -> bool
+	{
+		let m = match self.members.iter().find(|m| m.name == name)
+			{
+			Some(m) if m.state.load(Ordering::SeqCst) == STATE_FAULTY => m,
+			_ => return false,
+			};
+		let h = match VolumeHandle::open_named(name)
+			{
+			Ok(h) => h,
+			Err(e) => {
+				log_warning!("Mirror {}: Returning member {} can't be opened - {}", self.name, name, e);
+				return false;
+				},
+			};
+		if h.block_size() != self.block_size || h.block_count() < self.block_count {
+			log_warning!("Mirror {}: Returning member {} has a different geometry, ignoring", self.name, name);
+			return false;
+		}
+		*m.handle.write() = Some(h);
+		self.restore_member(m);
+		true
+	}
+
+	/// Copy dirty regions to members awaiting resync, returns false if the resync couldn't complete (and should be
+	/// retried later)
+	pub fn resync(&self) -> bool
+	{
+		if ! self.members.iter().any(|m| m.state.load(Ordering::SeqCst) == STATE_RESYNC) {
+			return true;
+		}
+		if ! self.members.iter().any(|m| m.state.load(Ordering::SeqCst) == STATE_ACTIVE) {
+			log_warning!("Mirror {}: No active members to resync from, waiting for a member to return", self.name);
+			return false;
+		}
+
+		let n_regions = (self.block_count + REGION_BLOCKS - 1) / REGION_BLOCKS;
+		let mut buf: Vec<u8> = vec![0; REGION_BLOCKS as usize * self.block_size];
+		let mut n_copied = 0;
+		for r in 0 .. n_regions
+		{
+			if self.dirty.lock()[(r / 64) as usize] & (1 << (r % 64)) == 0 {
+				continue ;
+			}
+
+			let _gate = self.write_gate.write();
+			let idx = r * REGION_BLOCKS;
+			let count = ::core::cmp::min(REGION_BLOCKS, self.block_count - idx) as usize;
+			let buf = &mut buf[.. count * self.block_size];
+			if let Err(e) = self.read(IoPriority::Bulk, idx, buf) {
+				// - Members stay in resync (still receiving writes), and the copy is restarted once a source returns
+				log_error!("Mirror {}: Resync aborted, no readable source for region {} - {:?}", self.name, r, e);
+				return false;
+			}
+			for (i, m) in self.members.iter().enumerate()
+			{
+				if m.state.load(Ordering::SeqCst) != STATE_RESYNC {
+					continue ;
+				}
+				let rv = match *m.handle.read()
+					{
+					Some(ref h) => h.write_blocks_prio(IoPriority::Bulk, idx, buf),
+					None => continue,
+					};
+				if let Err(e) = rv {
+					self.fail_member(i, e);
+				}
+			}
+			// Keep the region dirty while any member still needs it
+			if ! self.members.iter().any(|m| m.state.load(Ordering::SeqCst) == STATE_FAULTY) {
+				self.dirty.lock()[(r / 64) as usize] &= !(1 << (r % 64));
+			}
+			n_copied += 1;
+		}
+
+		for m in &self.members
+		{
+			if m.state.compare_and_swap(STATE_RESYNC, STATE_ACTIVE, Ordering::SeqCst) == STATE_RESYNC {
+				log_notice!("Mirror {}: Member {} in sync ({} regions copied)", self.name, m.name, n_copied);
+			}
+		}
+		true
+	}
+}
+
+/// Physical volume wrapper registered with the storage subsystem
+pub struct MirrorVolume(pub Arc<Mirror>);
+
+impl storage::PhysicalVolume for MirrorVolume
+{
+	fn name(&self) -> &str { &self.0.name }
+	fn blocksize(&self) -> Result<usize,storage::IoError> { Ok(self.0.block_size) }
+	fn capacity(&self) -> Option<u64> { Some(self.0.block_count) }
+
+	fn read<'a>(&'a self, prio: u8, idx: u64, num: usize, dst: &'a mut [u8]) -> storage::AsyncIoResult<'a,usize>
+	{
+		assert_eq!( dst.len(), num * self.0.block_size );
+		let rv = self.0.check_range(idx, num).and_then(|_| self.0.read(IoPriority::from_driver_prio(prio), idx, dst)).map(|_| num);
+		Box::new( async::NullResultWaiter::new( move || rv ) )
+	}
+	fn write<'a>(&'a self, prio: u8, idx: u64, num: usize, src: &'a [u8]) -> storage::AsyncIoResult<'a,usize>
+	{
+		assert_eq!( src.len(), num * self.0.block_size );
+		let rv = self.0.check_range(idx, num).and_then(|_| self.0.write(IoPriority::from_driver_prio(prio), idx, src)).map(|_| num);
+		Box::new( async::NullResultWaiter::new( move || rv ) )
+	}
+
+	fn wipe<'a>(&'a self, idx: u64, num: usize) -> storage::AsyncIoResult<'a,()>
+	{
+		// Wipe is only a hint, and forwarding it would need the members to agree on wiped contents
+		let rv = self.0.check_range(idx, num);
+		Box::new( async::NullResultWaiter::new( move || rv ) )
+	}
+
+	fn flush<'a>(&'a self) -> storage::AsyncIoResult<'a,()>
+	{
+		let rv = self.0.flush();
+		Box::new( async::NullResultWaiter::new( move || rv ) )
+	}
+}
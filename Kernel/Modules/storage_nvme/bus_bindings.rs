@@ -0,0 +1,148 @@
+// "Tifflin" Kernel - NVMe Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_nvme/bus_bindings.rs
+//! Bus drivers (e.g. PCI)
+use kernel::prelude::*;
+use kernel::device_manager;
+
+pub static S_PCI_DRIVER: PciDriver = PciDriver;
+
+/// Standard PCI bus binding (Class 1, Subclass 8, IF 2)
+pub struct PciDriver;
+
+impl device_manager::Driver for PciDriver
+{
+	fn name(&self) -> &str {
+		"nvme-pci"
+	}
+	fn bus_type(&self) -> &str {
+		"pci"
+	}
+	fn handles(&self, bus_dev: &dyn device_manager::BusDevice) -> u32
+	{
+		let classcode = bus_dev.get_attr("class").unwrap_u32();
+		// [class] [subclass] [IF] [ver]
+		if classcode & 0xFFFFFF00 == 0x01080200 {
+			1	// Handle as weakly as possible (vendor-provided drivers bind higher)
+		}
+		else {
+			0
+		}
+	}
+	fn bind(&self, bus_dev: &mut dyn device_manager::BusDevice) -> Box<dyn device_manager::DriverInstance+'static>
+	{
+		// Controller does DMA for both queues and data
+		bus_dev.set_attr("bus_master", device_manager::AttrValue::U32(1));
+		let irq = bus_dev.get_irq(0);
+		// BAR0/1 is the (64-bit) register block
+		let base = bus_dev.bind_io(0);
+
+		let rv = match find_msi(bus_dev)
+			{
+			Some(cap) => {
+				let mut set_msi = |address: u64, data: u32| program_msi(bus_dev, cap, address, data);
+				let msi = ::controller::MsiConfig {
+					is_msix: match cap { MsiCap::MsiX(_) => true, MsiCap::Msi(_) => false },
+					program: &mut set_msi,
+					};
+				::controller::Controller::new(irq, base, Some(msi))
+				},
+			None => ::controller::Controller::new(irq, base, None),
+			};
+		match rv
+		{
+		Ok(v) => v,
+		Err(e) => {
+			log_error!("Unable to initialise NVMe controller - {:?}", e);
+			Box::new(NullDevice)
+			},
+		}
+	}
+}
+
+/// Placeholder for a controller that failed to initialise
+struct NullDevice;
+impl device_manager::DriverInstance for NullDevice {
+}
+
+/// PCI capability IDs
+const CAP_ID_MSI: u8 = 0x05;
+const CAP_ID_MSIX: u8 = 0x11;
+
+#[derive(Copy,Clone)]
+enum MsiCap
+{
+	Msi(usize),
+	MsiX(usize),
+}
+
+/// Walk the PCI capability list for a MSI-X (preferred) or MSI capability
+fn find_msi(bus_dev: &dyn device_manager::BusDevice) -> Option<MsiCap>
+{
+	// Status register bit 4: Capability list present
+	if bus_dev.get_attr_idx("raw_config", 0x04).unwrap_u32() & (1 << (16+4)) == 0 {
+		return None;
+	}
+	let mut rv = None;
+	let mut ofs = (bus_dev.get_attr_idx("raw_config", 0x34).unwrap_u32() & 0xFC) as usize;
+	// - Limit the walk, in case the list loops
+	for _ in 0 .. 48
+	{
+		// Capabilities must be after the standard PCI header
+		if ofs < 0x40 {
+			break ;
+		}
+		let v = bus_dev.get_attr_idx("raw_config", ofs).unwrap_u32();
+		match (v & 0xFF) as u8
+		{
+		CAP_ID_MSIX => return Some(MsiCap::MsiX(ofs)),
+		CAP_ID_MSI => rv = Some(MsiCap::Msi(ofs)),
+		_ => {},
+		}
+		ofs = ((v >> 8) & 0xFC) as usize;
+	}
+	rv
+}
+
+/// Program and enable the device's MSI/MSI-X capability (single vector, vector 0)
+fn program_msi(bus_dev: &mut dyn device_manager::BusDevice, cap: MsiCap, address: u64, data: u32)
+{
+	use kernel::device_manager::AttrValue;
+	match cap
+	{
+	MsiCap::MsiX(ofs) => {
+		let table = bus_dev.get_attr_idx("raw_config", ofs + 4).unwrap_u32();
+		let (bir, table_ofs) = ((table & 7) as usize, (table & !7) as usize);
+		// Table entry 0: Address (low, high), Data, Vector Control
+		let io = bus_dev.bind_io_slice(bir, Some((table_ofs, 16)));
+		// SAFE: Vector 0's table entry, which is only used by this driver
+		unsafe {
+			io.write_32(0x0, address as u32);
+			io.write_32(0x4, (address >> 32) as u32);
+			io.write_32(0x8, data);
+			io.write_32(0xC, 0);	// Unmasked
+		}
+		// Message Control: Set MSI-X Enable (bit 15), clear Function Mask (bit 14)
+		let v = bus_dev.get_attr_idx("raw_config", ofs).unwrap_u32();
+		bus_dev.set_attr_idx("raw_config", ofs, AttrValue::U32( (v | (1 << 31)) & !(1 << 30) ));
+		},
+	MsiCap::Msi(ofs) => {
+		let v = bus_dev.get_attr_idx("raw_config", ofs).unwrap_u32();
+		bus_dev.set_attr_idx("raw_config", ofs + 4, AttrValue::U32(address as u32));
+		// Message Control bit 7: 64-bit address capable
+		let data_ofs = if v & (1 << (16+7)) != 0 {
+				bus_dev.set_attr_idx("raw_config", ofs + 8, AttrValue::U32((address >> 32) as u32));
+				ofs + 0xC
+			}
+			else {
+				ofs + 8
+			};
+		// - Data is the low 16 bits, the other half is reserved/extended data
+		let d = bus_dev.get_attr_idx("raw_config", data_ofs).unwrap_u32();
+		bus_dev.set_attr_idx("raw_config", data_ofs, AttrValue::U32( (d & 0xFFFF_0000) | (data & 0xFFFF) ));
+		// Message Control: Enable (bit 0), single message (Multiple Message Enable = 0)
+		bus_dev.set_attr_idx("raw_config", ofs, AttrValue::U32( (v | (1 << 16)) & !(7 << (16+4)) ));
+		},
+	}
+}
@@ -0,0 +1,358 @@
+// "Tifflin" Kernel - NVMe Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_nvme/controller.rs
+//! NVMe Controller root
+use kernel::prelude::*;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use kernel::device_manager;
+use kernel::metadevs::storage;
+use kernel::memory::virt::AllocHandle;
+use kernel::lib::mem::aref::ArefInner;
+use kernel::PAGE_SIZE;
+use queue::{self, QueuePair};
+use hw;
+
+/// Maximum number of I/O queue pairs to request
+const MAX_IO_QUEUES: usize = 4;
+/// Timeout for admin commands issued during initialisation
+const ADMIN_TIMEOUT_MS: u64 = 5000;
+
+static S_CONTROLLER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Register access
+pub struct Regs
+{
+	io: device_manager::IOBinding,
+	doorbell_stride: usize,
+}
+impl Regs
+{
+	pub fn read(&self, ofs: usize) -> u32 {
+		// SAFE: None of the NVMe registers have read side-effects
+		unsafe { self.io.read_32(ofs) }
+	}
+	pub unsafe fn write(&self, ofs: usize, val: u32) {
+		self.io.write_32(ofs, val)
+	}
+	pub unsafe fn write_64(&self, ofs: usize, val: u64) {
+		// Low dword first, as required by the spec for split accesses
+		self.io.write_32(ofs + 0, (val >>  0) as u32);
+		self.io.write_32(ofs + 4, (val >> 32) as u32);
+	}
+	/// Update a submission queue's tail doorbell
+	pub unsafe fn ring_sq(&self, qid: u16, tail: u16) {
+		self.io.write_32(hw::REG_DOORBELLS + (2 * qid as usize) * self.doorbell_stride, tail as u32)
+	}
+	/// Update a completion queue's head doorbell
+	pub unsafe fn ring_cq(&self, qid: u16, head: u16) {
+		self.io.write_32(hw::REG_DOORBELLS + (2 * qid as usize + 1) * self.doorbell_stride, head as u32)
+	}
+
+	/// Wait for the masked bits of CSTS to equal `val`
+	fn wait_status(&self, mask: u32, val: u32, timeout_ms: u64) -> Result<(), device_manager::DriverBindError>
+	{
+		let end = ::kernel::time::ticks() + timeout_ms;
+		loop
+		{
+			let csts = self.read(hw::REG_CSTS);
+			if csts & hw::CSTS_CFS != 0 {
+				return Err( device_manager::DriverBindError::Bug("NVMe controller fatal status") );
+			}
+			if csts & mask == val {
+				return Ok( () );
+			}
+			if ::kernel::time::ticks() > end {
+				return Err( device_manager::DriverBindError::Bug("NVMe controller timed out changing state") );
+			}
+			::kernel::threads::yield_time();
+		}
+	}
+}
+
+/// Message-signalled interrupt support, provided by the bus binding
+pub struct MsiConfig<'a>
+{
+	/// MSI-X is in use (INTMS/INTMC must not be accessed)
+	pub is_msix: bool,
+	/// Program the device with a message address and data value
+	pub program: &'a mut dyn FnMut(u64, u32),
+}
+
+enum IrqBinding
+{
+	Pin(::kernel::irqs::ObjectHandle),
+	Msi(::kernel::irqs::MsiHandle),
+}
+
+/// NVMe Controller
+pub struct Controller
+{
+	volumes: Vec<storage::PhysicalVolumeReg>,
+	irq_handle: Option<IrqBinding>,
+	is_msix: bool,
+	inner: ArefInner<ControllerInner>,
+}
+pub struct ControllerInner
+{
+	pub index: usize,
+	pub regs: Regs,
+	admin: QueuePair,
+	io_queues: Vec<QueuePair>,
+	next_queue: AtomicUsize,
+
+	/// Maximum number of bytes in a single read/write command
+	pub max_transfer: usize,
+	/// Controller has a volatile write cache (i.e. FLUSH is needed)
+	pub volatile_cache: bool,
+	/// Controller supports Dataset Management (deallocate)
+	pub supports_dsm: bool,
+}
+
+impl Controller
+{
+	pub fn new(irq: u32, io: device_manager::IOBinding, msi: Option<MsiConfig>) -> Result<Box<Controller>, device_manager::DriverBindError>
+	{
+		// SAFE: Reading has no side-effects
+		let (cap_lo, cap_hi, version) = unsafe { (io.read_32(hw::REG_CAP), io.read_32(hw::REG_CAP + 4), io.read_32(hw::REG_VS)) };
+		let max_queue_size = (cap_lo & hw::CAP_MQES) as usize + 1;
+		let ready_timeout = (cap_lo >> hw::CAP_TO_ofs & 0xFF) as u64 * 500;
+		if (cap_hi >> hw::CAPH_MPSMIN_ofs) & 0xF != 0 {
+			return Err( device_manager::DriverBindError::Bug("NVMe controller doesn't support 4KB pages") );
+		}
+		log_debug!("NVMe v{}.{}: CAP={:#x}:{:08x}", version >> 16, (version >> 8) & 0xFF, cap_hi, cap_lo);
+
+		let admin = try!( QueuePair::new(0, max_queue_size) );
+		let mut inner = ControllerInner {
+			index: S_CONTROLLER_COUNT.fetch_add(1, Ordering::Relaxed),
+			regs: Regs {
+				io: io,
+				doorbell_stride: 4 << (cap_hi & hw::CAPH_DSTRD),
+				},
+			admin: admin,
+			io_queues: Vec::new(),
+			next_queue: AtomicUsize::new(0),
+			max_transfer: 0,
+			volatile_cache: false,
+			supports_dsm: false,
+			};
+
+		// Reset the controller, and bring it back up with the admin queue configured
+		// SAFE: Exclusive access to the controller
+		unsafe {
+			inner.regs.write(hw::REG_CC, 0);
+			try!( inner.regs.wait_status(hw::CSTS_RDY, 0, ready_timeout) );
+
+			// Interrupts are masked until the handler is bound, admin commands are polled
+			inner.regs.write(hw::REG_INTMS, !0);
+			let qs = inner.admin.size() as u32 - 1;
+			inner.regs.write(hw::REG_AQA, (qs << 16) | qs);
+			inner.regs.write_64(hw::REG_ASQ, inner.admin.sq_phys());
+			inner.regs.write_64(hw::REG_ACQ, inner.admin.cq_phys());
+			inner.regs.write(hw::REG_CC, hw::CC_EN | (6 << hw::CC_IOSQES_ofs) | (4 << hw::CC_IOCQES_ofs));
+			try!( inner.regs.wait_status(hw::CSTS_RDY, hw::CSTS_RDY, ready_timeout) );
+		}
+
+		let ident = try!( ::kernel::memory::virt::alloc_dma(64, 1, "NVMe") );
+
+		// Identify the controller
+		try!( inner.identify(hw::IDENTIFY_CONTROLLER, 0, &ident) );
+		let mdts = *ident.as_ref::<u8>(hw::IDC_MDTS);
+		let n_namespaces = *ident.as_ref::<u32>(hw::IDC_NN);
+		inner.max_transfer = if mdts == 0 || mdts >= 16 {
+				queue::PRPS_PER_SLOT * PAGE_SIZE
+			}
+			else {
+				::core::cmp::min(queue::PRPS_PER_SLOT * PAGE_SIZE, PAGE_SIZE << mdts)
+			};
+		inner.volatile_cache = *ident.as_ref::<u8>(hw::IDC_VWC) & 1 != 0;
+		inner.supports_dsm = *ident.as_ref::<u16>(hw::IDC_ONCS) & hw::ONCS_DSM != 0;
+
+		// Create I/O queues
+		{
+			let want = MAX_IO_QUEUES as u32 - 1;
+			let mut ent = hw::SubmissionEntry::new(hw::ADMIN_SET_FEATURES, 0);
+			ent.cdw10 = hw::FEATURE_NUM_QUEUES;
+			ent.cdw11 = (want << 16) | want;
+			let alloc = try!( inner.admin_command(ent, None) );
+			let n_queues = ::core::cmp::min( MAX_IO_QUEUES, 1 + ::core::cmp::min(alloc & 0xFFFF, alloc >> 16) as usize );
+
+			for qid in 1 .. n_queues as u16 + 1
+			{
+				let q = try!( QueuePair::new(qid, max_queue_size) );
+				let qs = q.size() as u32 - 1;
+
+				// All queues share a single interrupt (vector 0)
+				let mut ent = hw::SubmissionEntry::new(hw::ADMIN_CREATE_IO_CQ, 0);
+				ent.prp1 = q.cq_phys();
+				ent.cdw10 = (qs << 16) | qid as u32;
+				ent.cdw11 = (0 << 16) | (1 << 1) | (1 << 0);	// IV=0, IEN, PC
+				try!( inner.admin_command(ent, None) );
+
+				let mut ent = hw::SubmissionEntry::new(hw::ADMIN_CREATE_IO_SQ, 0);
+				ent.prp1 = q.sq_phys();
+				ent.cdw10 = (qs << 16) | qid as u32;
+				ent.cdw11 = ((qid as u32) << 16) | (1 << 0);	// CQID, PC
+				try!( inner.admin_command(ent, None) );
+
+				inner.io_queues.push(q);
+			}
+		}
+
+		// Enumerate namespaces
+		let nsids: Vec<u32> = if version >= 0x0001_0100 {
+				try!( inner.identify(hw::IDENTIFY_ACTIVE_NS_LIST, 0, &ident) );
+				ident.as_slice::<u32>(0, PAGE_SIZE / 4).iter().cloned().take_while(|&v| v != 0).collect()
+			}
+			else {
+				(1 .. n_namespaces + 1).collect()
+			};
+		let mut namespaces = Vec::new();
+		for nsid in nsids
+		{
+			try!( inner.identify(hw::IDENTIFY_NAMESPACE, nsid, &ident) );
+			let size = *ident.as_ref::<u64>(hw::IDN_NSZE);
+			if size == 0 {
+				// Inactive namespace
+				continue ;
+			}
+			let format = (*ident.as_ref::<u8>(hw::IDN_FLBAS) & 0xF) as usize;
+			let lbads = (*ident.as_ref::<u32>(hw::IDN_LBAF + format * 4) >> 16) & 0xFF;
+			if lbads < 9 || lbads > 12 {
+				log_warning!("NVMe{} NS{}: Unsupported LBA size 2^{}", inner.index, nsid, lbads);
+				continue ;
+			}
+			namespaces.push( (nsid, 1usize << lbads, size) );
+		}
+		log_notice!("NVMe{}: {} I/O queue(s), {} namespace(s), max transfer {}KB",
+			inner.index, inner.io_queues.len(), namespaces.len(), inner.max_transfer / 1024);
+
+		let mut ret = Box::new( Controller {
+			volumes: Vec::with_capacity(namespaces.len()),
+			irq_handle: None,
+			is_msix: false,
+			// SAFE: The inner is boxed (and hence gets a fixed address) before it's borrowed
+			inner: unsafe { ArefInner::new(inner) },
+			});
+
+		// Bind interrupt, preferring MSI/MSI-X over the pin interrupt
+		let mut is_msix = false;
+		if let Some(msi) = msi
+		{
+			match ::kernel::irqs::bind_msi_object(ret.irq_handler())
+			{
+			Ok(h) => {
+				log_debug!("{}: Using {} (address={:#x}, data={:#x})", *ret.inner, if msi.is_msix { "MSI-X" } else { "MSI" }, h.address(), h.data());
+				(msi.program)(h.address(), h.data());
+				is_msix = msi.is_msix;
+				ret.irq_handle = Some(IrqBinding::Msi(h));
+				},
+			Err(e) => log_notice!("{}: Unable to bind MSI ({:?}), using IRQ {}", *ret.inner, e, irq),
+			}
+		}
+		if ret.irq_handle.is_none() {
+			ret.irq_handle = Some(IrqBinding::Pin( ::kernel::irqs::bind_object(irq, ret.irq_handler()) ));
+		}
+		ret.is_msix = is_msix;
+		if !is_msix {
+			// SAFE: Exclusive access to this register, handler is now bound
+			unsafe {
+				ret.inner.regs.write(hw::REG_INTMC, 1 << 0);
+			}
+		}
+
+		for (nsid, block_size, count) in namespaces
+		{
+			let vol = ::volume::Volume::new(ret.inner.borrow(), nsid, block_size, count);
+			ret.volumes.push( storage::register_concurrent_pv( Box::new(vol) ) );
+		}
+
+		Ok( ret )
+	}
+
+	/// Create the interrupt handler closure for this (boxed) controller
+	fn irq_handler(&self) -> Box<dyn FnMut()->bool + Send + 'static>
+	{
+		struct RawSend<T: Send>(*const T);
+		unsafe impl<T: Send> Send for RawSend<T> {}
+		let raw = RawSend(self);
+		// SAFE: Pointer _should_ be valid as long as the IRQ binding exists (it's dropped before the controller)
+		Box::new(move || unsafe { (*raw.0).handle_irq() })
+	}
+
+	fn handle_irq(&self) -> bool
+	{
+		let mut rv = false;
+		for q in &self.inner.io_queues
+		{
+			rv |= q.handle_completions(&self.inner.regs);
+		}
+		rv
+	}
+}
+impl ::core::ops::Drop for Controller
+{
+	fn drop(&mut self)
+	{
+		self.volumes.clear();
+		self.irq_handle = None;
+		// SAFE: All users are gone, disable the controller
+		unsafe {
+			if !self.is_msix {
+				self.inner.regs.write(hw::REG_INTMS, !0);
+			}
+			self.inner.regs.write(hw::REG_CC, 0);
+		}
+	}
+}
+impl device_manager::DriverInstance for Controller
+{
+}
+
+impl ControllerInner
+{
+	/// Number of I/O queues in use
+	pub fn io_queue_count(&self) -> usize
+	{
+		self.io_queues.len()
+	}
+	/// Select an I/O queue for a new command
+	pub fn io_queue(&self) -> &QueuePair
+	{
+		let i = self.next_queue.fetch_add(1, Ordering::Relaxed);
+		&self.io_queues[i % self.io_queues.len()]
+	}
+
+	/// Issue an admin command and poll for its completion
+	fn admin_command(&self, mut ent: hw::SubmissionEntry, data: Option<&AllocHandle>) -> Result<u32, device_manager::DriverBindError>
+	{
+		let cmd = self.admin.get_slot();
+		if let Some(buf) = data {
+			cmd.set_prps(&mut ent, buf.as_ref::<u8>(0), PAGE_SIZE);
+		}
+		let opcode = ent.opcode;
+		// SAFE: The data buffer (if any) outlives the command, which is waited on below
+		unsafe { cmd.start(&self.regs, ent); }
+		match cmd.poll(&self.regs, ADMIN_TIMEOUT_MS)
+		{
+		Some(Ok(v)) => Ok(v),
+		Some(Err(e)) => {
+			log_error!("NVMe{}: Admin command {:#x} failed - {:?}", self.index, opcode, e);
+			Err( device_manager::DriverBindError::Bug("NVMe admin command failed") )
+			},
+		None => Err( device_manager::DriverBindError::Bug("NVMe admin command timed out") ),
+		}
+	}
+	fn identify(&self, cns: u32, nsid: u32, buf: &AllocHandle) -> Result<(), device_manager::DriverBindError>
+	{
+		let mut ent = hw::SubmissionEntry::new(hw::ADMIN_IDENTIFY, nsid);
+		ent.cdw10 = cns;
+		try!( self.admin_command(ent, Some(buf)) );
+		Ok( () )
+	}
+}
+impl_fmt! {
+	Display(self, f) for ControllerInner {
+		write!(f, "NVMe{}", self.index)
+	}
+}
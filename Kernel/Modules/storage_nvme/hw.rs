@@ -0,0 +1,121 @@
+// "Tifflin" Kernel - NVMe Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_nvme/hw.rs
+//! Hardware definitions (registers, queue entries, opcodes)
+#![allow(dead_code)]
+
+// Controller registers (offsets into BAR0)
+pub const REG_CAP: usize = 0x00;	// Controller Capabilities (64-bit)
+pub const REG_VS: usize = 0x08;	// Version
+pub const REG_INTMS: usize = 0x0C;	// Interrupt Mask Set
+pub const REG_INTMC: usize = 0x10;	// Interrupt Mask Clear
+pub const REG_CC: usize = 0x14;	// Controller Configuration
+pub const REG_CSTS: usize = 0x1C;	// Controller Status
+pub const REG_AQA: usize = 0x24;	// Admin Queue Attributes
+pub const REG_ASQ: usize = 0x28;	// Admin Submission Queue Base Address (64-bit)
+pub const REG_ACQ: usize = 0x30;	// Admin Completion Queue Base Address (64-bit)
+pub const REG_DOORBELLS: usize = 0x1000;
+
+// CAP (low word)
+pub const CAP_MQES: u32 = 0xFFFF;	// Maximum Queue Entries Supported (0-based)
+pub const CAP_TO_ofs: usize = 24;	// Timeout (500ms units)
+// CAP (high word)
+pub const CAPH_DSTRD: u32 = 0xF;	// Doorbell Stride (stride is 4 << DSTRD)
+pub const CAPH_MPSMIN_ofs: usize = 16;	// Memory Page Size Minimum (4096 << MPSMIN)
+
+pub const CC_EN: u32 = (1 << 0);	// Enable
+pub const CC_IOSQES_ofs: usize = 16;	// I/O Submission Queue Entry Size (log2)
+pub const CC_IOCQES_ofs: usize = 20;	// I/O Completion Queue Entry Size (log2)
+
+pub const CSTS_RDY: u32 = (1 << 0);	// Ready
+pub const CSTS_CFS: u32 = (1 << 1);	// Controller Fatal Status
+
+// Admin command set
+pub const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+pub const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+pub const ADMIN_IDENTIFY: u8 = 0x06;
+pub const ADMIN_SET_FEATURES: u8 = 0x09;
+
+pub const IDENTIFY_NAMESPACE: u32 = 0;
+pub const IDENTIFY_CONTROLLER: u32 = 1;
+pub const IDENTIFY_ACTIVE_NS_LIST: u32 = 2;
+
+pub const FEATURE_NUM_QUEUES: u32 = 0x07;
+
+// NVM command set
+pub const NVM_FLUSH: u8 = 0x00;
+pub const NVM_WRITE: u8 = 0x01;
+pub const NVM_READ: u8 = 0x02;
+pub const NVM_DATASET_MGMT: u8 = 0x09;
+
+pub const DSM_AD: u32 = (1 << 2);	// Attribute - Deallocate
+
+// Identify Controller fields
+pub const IDC_MDTS: usize = 77;	// u8 - Maximum Data Transfer Size (log2 of min page size units)
+pub const IDC_NN: usize = 516;	// u32 - Number of Namespaces
+pub const IDC_ONCS: usize = 520;	// u16 - Optional NVM Command Support
+pub const IDC_VWC: usize = 525;	// u8 - Volatile Write Cache
+pub const ONCS_DSM: u16 = (1 << 2);
+
+// Identify Namespace fields
+pub const IDN_NSZE: usize = 0;	// u64 - Namespace Size (in LBAs)
+pub const IDN_FLBAS: usize = 26;	// u8 - Formatted LBA Size (low 4 bits index LBAF)
+pub const IDN_LBAF: usize = 128;	// [u32; 16] - LBA Formats (bits 23:16 = LBADS)
+
+/// Submission queue entry (64 bytes)
+#[repr(C)]
+#[derive(Default)]
+pub struct SubmissionEntry
+{
+	pub opcode: u8,
+	pub flags: u8,
+	pub cid: u16,
+	pub nsid: u32,
+	_rsvd: u64,
+	pub mptr: u64,
+	pub prp1: u64,
+	pub prp2: u64,
+	pub cdw10: u32,
+	pub cdw11: u32,
+	pub cdw12: u32,
+	pub cdw13: u32,
+	pub cdw14: u32,
+	pub cdw15: u32,
+}
+unsafe impl ::kernel::lib::POD for SubmissionEntry {}
+impl SubmissionEntry
+{
+	pub fn new(opcode: u8, nsid: u32) -> SubmissionEntry {
+		SubmissionEntry {
+			opcode: opcode,
+			nsid: nsid,
+			.. Default::default()
+			}
+	}
+}
+
+/// Completion queue entry (16 bytes)
+#[repr(C)]
+#[derive(Copy,Clone)]
+pub struct CompletionEntry
+{
+	pub dw0: u32,
+	_rsvd: u32,
+	pub sq_head: u16,
+	pub sq_id: u16,
+	pub cid: u16,
+	/// Bit 0: Phase Tag, 8:1 Status Code, 11:9 Status Code Type
+	pub status: u16,
+}
+unsafe impl ::kernel::lib::POD for CompletionEntry {}
+
+/// An entry in a Dataset Management range list
+#[repr(C)]
+pub struct DsmRange
+{
+	pub context: u32,
+	pub length: u32,
+	pub lba: u64,
+}
+unsafe impl ::kernel::lib::POD for DsmRange {}
@@ -0,0 +1,315 @@
+// "Tifflin" Kernel - NVMe Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_nvme/queue.rs
+//! Submission/Completion queue pairs
+use kernel::prelude::*;
+use core::sync::atomic::Ordering;
+use kernel::sync::atomic::AtomicU32;
+use kernel::sync::{Mutex, Spinlock, Semaphore, EventChannel};
+use kernel::memory::virt::{AllocHandle, MapError};
+use kernel::metadevs::storage;
+use kernel::PAGE_SIZE;
+use controller::Regs;
+use hw;
+
+/// Maximum number of entries in a queue (a single page of submission entries)
+const QUEUE_SIZE: usize = PAGE_SIZE / 64;
+/// Maximum number of commands that can be in-flight on one queue (limited further by the queue size)
+const MAX_SLOTS: usize = 16;
+/// Number of PRP list entries available to each command slot (slot lists share one page)
+pub const PRPS_PER_SLOT: usize = PAGE_SIZE / 8 / MAX_SLOTS;
+
+/// Set in `slot_status` once the controller has completed the command
+const SLOT_DONE: u32 = 1 << 16;
+
+/// Failed command status (status field of the completion entry, without the phase bit)
+#[derive(Copy,Clone)]
+pub struct Status(u16);
+impl Status
+{
+	pub fn code(&self) -> u8 {
+		self.0 as u8
+	}
+	pub fn code_type(&self) -> u8 {
+		((self.0 >> 8) & 7) as u8
+	}
+}
+impl_fmt! {
+	Debug(self,f) for Status {
+		write!(f, "Status(sct={},sc={:#x}{})", self.code_type(), self.code(),
+			if self.0 & (1 << 14) != 0 { " DNR" } else { "" }
+			)
+	}
+}
+impl_from! {
+	From<Status>(v) for storage::IoError {
+		match (v.code_type(), v.code())
+		{
+		(0, 0x02) => storage::IoError::InvalidParameter,	// Invalid Field in Command
+		(0, 0x80) => storage::IoError::BadAddr,	// LBA Out of Range
+		(2, 0x80) | (2, 0x81) => storage::IoError::BadBlock,	// Write Fault / Unrecovered Read Error
+		(2, 0x82) => storage::IoError::ReadOnly,	// End-to-end Guard Check Error (treated as a write protect)
+		_ => {
+			log_warning!("NVMe command failed: {:?}", v);
+			storage::IoError::Unknown("NVMe error")
+			},
+		}
+	}
+}
+
+pub struct QueuePair
+{
+	qid: u16,
+	size: u16,
+
+	// Hardware allocations:
+	// - One page of submission entries
+	// - One page of completion entries
+	// - One page split into per-slot PRP lists
+	sq: AllocHandle,
+	cq: AllocHandle,
+	prp_lists: AllocHandle,
+
+	sq_tail: Mutex<u16>,
+	/// Completion queue head, and the phase tag expected on new entries
+	cq_state: Spinlock<(u16, bool)>,
+
+	used_slots_sem: Semaphore,
+	used_slots: AtomicU32,
+	slot_events: Vec<EventChannel>,
+	slot_status: Vec<AtomicU32>,
+	slot_dw0: Vec<AtomicU32>,
+}
+
+impl QueuePair
+{
+	/// Allocate memory for a queue pair, `max_size` is the controller's maximum queue size
+	pub fn new(qid: u16, max_size: usize) -> Result<QueuePair, MapError>
+	{
+		let size = ::core::cmp::min(QUEUE_SIZE, max_size);
+		// A full submission queue holds one less than its size
+		let n_slots = ::core::cmp::min(MAX_SLOTS, size - 1);
+		Ok(QueuePair {
+			qid: qid,
+			size: size as u16,
+			sq: try!( ::kernel::memory::virt::alloc_dma(64, 1, "NVMe") ),
+			cq: try!( ::kernel::memory::virt::alloc_dma(64, 1, "NVMe") ),
+			prp_lists: try!( ::kernel::memory::virt::alloc_dma(64, 1, "NVMe") ),
+			sq_tail: Mutex::new(0),
+			cq_state: Spinlock::new( (0, true) ),
+			used_slots_sem: Semaphore::new(n_slots as isize, n_slots as isize),
+			used_slots: AtomicU32::new(0),
+			slot_events: (0 .. n_slots).map(|_| EventChannel::new()).collect(),
+			slot_status: (0 .. n_slots).map(|_| AtomicU32::new(SLOT_DONE)).collect(),
+			slot_dw0: (0 .. n_slots).map(|_| AtomicU32::new(0)).collect(),
+			})
+	}
+
+	pub fn size(&self) -> usize {
+		self.size as usize
+	}
+	pub fn sq_phys(&self) -> u64 {
+		::kernel::memory::virt::get_phys( self.sq.as_ref::<hw::SubmissionEntry>(0) ) as u64
+	}
+	pub fn cq_phys(&self) -> u64 {
+		::kernel::memory::virt::get_phys( self.cq.as_ref::<hw::CompletionEntry>(0) ) as u64
+	}
+
+	/// Process new completion queue entries, returns true if any were found
+	pub fn handle_completions(&self, regs: &Regs) -> bool
+	{
+		let mut lh = self.cq_state.lock();
+		let mut rv = false;
+		loop
+		{
+			let ent_ptr: *const hw::CompletionEntry = self.cq.as_ref(lh.0 as usize * ::core::mem::size_of::<hw::CompletionEntry>());
+			// SAFE: Pointer is valid, volatile read as the controller updates this memory
+			let ent = unsafe { ::core::ptr::read_volatile(ent_ptr) };
+			if (ent.status & 1 != 0) != lh.1 {
+				break ;
+			}
+			rv = true;
+
+			let idx = ent.cid as usize;
+			if idx >= self.slot_status.len() {
+				log_error!("NVMe Q{}: Completion for invalid command ID {}", self.qid, ent.cid);
+			}
+			else {
+				self.slot_dw0[idx].store(ent.dw0, Ordering::Relaxed);
+				self.slot_status[idx].store(SLOT_DONE | (ent.status >> 1) as u32, Ordering::Release);
+				self.slot_events[idx].post();
+			}
+
+			lh.0 += 1;
+			if lh.0 == self.size {
+				lh.0 = 0;
+				lh.1 = !lh.1;
+			}
+		}
+		if rv {
+			// SAFE: This queue's doorbell, protected by the lock
+			unsafe { regs.ring_cq(self.qid, lh.0); }
+		}
+		rv
+	}
+
+	/// Obtain a free command slot (blocking until one is available)
+	pub fn get_slot(&self) -> Command
+	{
+		self.used_slots_sem.acquire();
+
+		let mut cur = self.used_slots.load(Ordering::Relaxed);
+		loop
+		{
+			let avail = (0 .. self.slot_status.len()).find(|i| cur & (1 << i) == 0).expect("NVMe: No free slots after semaphore acquire");
+			let newval = self.used_slots.compare_and_swap(cur, cur | (1 << avail), Ordering::Acquire);
+			if newval == cur
+			{
+				return Command {
+					queue: self,
+					idx: avail,
+					};
+			}
+			cur = newval;
+		}
+	}
+}
+
+/// An allocated command slot
+pub struct Command<'a>
+{
+	queue: &'a QueuePair,
+	idx: usize,
+}
+impl<'a> Command<'a>
+{
+	/// Populate the data pointers in `ent` to refer to (up to) `len` bytes at `buf`
+	///
+	/// Returns the number of bytes covered, which is less than `len` if the slot's PRP list is too small.
+	/// `buf` must be dword aligned (callers bounce unaligned buffers)
+	pub fn set_prps(&self, ent: &mut hw::SubmissionEntry, buf: *const u8, len: usize) -> usize
+	{
+		use kernel::memory::virt::get_phys;
+		assert!(buf as usize % 4 == 0, "NVMe buffers must be dword aligned");
+		assert!(len > 0);
+
+		ent.prp1 = get_phys(buf) as u64;
+		let first_len = PAGE_SIZE - buf as usize % PAGE_SIZE;
+		if len <= first_len {
+			ent.prp2 = 0;
+			len
+		}
+		else if len <= first_len + PAGE_SIZE {
+			ent.prp2 = get_phys( (buf as usize + first_len) as *const u8 ) as u64;
+			len
+		}
+		else {
+			let n_pages = ::core::cmp::min( (len - first_len + PAGE_SIZE - 1) / PAGE_SIZE, PRPS_PER_SLOT );
+			ent.prp2 = {
+				// SAFE: This slot's region of the PRP list page is exclusively owned by this command
+				let list = unsafe { self.queue.prp_lists.as_int_mut_slice::<u64>(self.idx * PRPS_PER_SLOT * 8, PRPS_PER_SLOT) };
+				for i in 0 .. n_pages {
+					list[i] = get_phys( (buf as usize + first_len + i * PAGE_SIZE) as *const u8 ) as u64;
+				}
+				get_phys(&list[0]) as u64
+				};
+			::core::cmp::min(len, first_len + n_pages * PAGE_SIZE)
+		}
+	}
+
+	/// Place a single Dataset Management range in this slot's scratch space, and point `ent` at it
+	pub fn set_dsm_range(&self, ent: &mut hw::SubmissionEntry, lba: u64, count: u32)
+	{
+		// SAFE: This slot's region of the PRP list page is exclusively owned by this command
+		let range = unsafe { self.queue.prp_lists.as_int_mut::<hw::DsmRange>(self.idx * PRPS_PER_SLOT * 8) };
+		*range = hw::DsmRange {
+			context: 0,
+			length: count,
+			lba: lba,
+			};
+		ent.prp1 = ::kernel::memory::virt::get_phys(range) as u64;
+		ent.prp2 = 0;
+	}
+
+	/// Submit the command to the controller
+	///
+	/// UNSAFE: Caller must ensure that memory referenced by the command stays valid until it completes
+	pub unsafe fn start(&self, regs: &Regs, mut ent: hw::SubmissionEntry)
+	{
+		ent.cid = self.idx as u16;
+		self.queue.slot_status[self.idx].store(0, Ordering::Relaxed);
+		self.queue.slot_events[self.idx].clear();
+
+		let mut tail = self.queue.sq_tail.lock();
+		let dst: *mut hw::SubmissionEntry = &mut self.queue.sq.as_int_mut_slice(0, self.queue.size())[*tail as usize];
+		::core::ptr::write_volatile(dst, ent);
+		*tail = (*tail + 1) % self.queue.size;
+		regs.ring_sq(self.queue.qid, *tail);
+	}
+
+	fn result(&self) -> Option<Result<u32, Status>>
+	{
+		let s = self.queue.slot_status[self.idx].load(Ordering::Acquire);
+		if s & SLOT_DONE == 0 {
+			None
+		}
+		else if s & 0x7FFF != 0 {
+			Some( Err(Status(s as u16 & 0x7FFF)) )
+		}
+		else {
+			Some( Ok(self.queue.slot_dw0[self.idx].load(Ordering::Relaxed)) )
+		}
+	}
+
+	/// Wait for the command to complete (via the interrupt handler), returning the result dword
+	pub fn wait(&self) -> Result<u32, Status>
+	{
+		loop
+		{
+			if let Some(rv) = self.result() {
+				return rv;
+			}
+			self.queue.slot_events[self.idx].sleep();
+		}
+	}
+
+	/// Poll the completion queue until the command completes, or `timeout_ms` elapses
+	pub fn poll(&self, regs: &Regs, timeout_ms: u64) -> Option<Result<u32, Status>>
+	{
+		let end = ::kernel::time::ticks() + timeout_ms;
+		loop
+		{
+			self.queue.handle_completions(regs);
+			if let Some(rv) = self.result() {
+				return Some(rv);
+			}
+			if ::kernel::time::ticks() > end {
+				return None;
+			}
+			::kernel::threads::yield_time();
+		}
+	}
+}
+impl<'a> ::core::ops::Drop for Command<'a>
+{
+	fn drop(&mut self)
+	{
+		if self.queue.slot_status[self.idx].load(Ordering::Acquire) & SLOT_DONE == 0 {
+			// The controller may still write to memory owned by this slot, so it can't be reused
+			log_error!("NVMe Q{}: Command {} dropped while still active, leaking slot", self.queue.qid, self.idx);
+			return ;
+		}
+
+		let mask = 1 << self.idx;
+		loop
+		{
+			let cur = self.queue.used_slots.load(Ordering::Relaxed);
+			let new = self.queue.used_slots.compare_and_swap(cur, cur & !mask, Ordering::Release);
+			if new == cur {
+				break ;
+			}
+		}
+		self.queue.used_slots_sem.release();
+	}
+}
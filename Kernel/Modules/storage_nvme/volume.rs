@@ -0,0 +1,213 @@
+// "Tifflin" Kernel - NVMe Driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/storage_nvme/volume.rs
+//! Namespace (physical volume) handling
+use kernel::prelude::*;
+use kernel::metadevs::storage;
+use kernel::lib::mem::aref::ArefBorrow;
+use kernel::async;
+use kernel::PAGE_SIZE;
+use controller::ControllerInner;
+use hw;
+
+/// A single NVMe namespace
+pub struct Volume
+{
+	name: String,
+	ctrlr: ArefBorrow<ControllerInner>,
+	nsid: u32,
+	block_size: usize,
+	block_count: u64,
+}
+
+impl Volume
+{
+	pub fn new(ctrlr: ArefBorrow<ControllerInner>, nsid: u32, block_size: usize, block_count: u64) -> Volume
+	{
+		log_log!("{} NS{}: {} blocks of {} bytes", *ctrlr, nsid, block_count, block_size);
+		Volume {
+			name: format!("nvme{}n{}", ctrlr.index, nsid),
+			ctrlr: ctrlr,
+			nsid: nsid,
+			block_size: block_size,
+			block_count: block_count,
+		}
+	}
+
+	fn check_range(&self, idx: u64, count: usize) -> Result<(), storage::IoError>
+	{
+		if idx >= self.block_count || count as u64 > self.block_count - idx {
+			Err( storage::IoError::BadAddr )
+		}
+		else {
+			Ok( () )
+		}
+	}
+
+	/// Run a single (data-less) command on an I/O queue and wait for it to complete
+	fn command(&self, ent: hw::SubmissionEntry) -> Result<u32, storage::IoError>
+	{
+		let q = self.ctrlr.io_queue();
+		let cmd = q.get_slot();
+		// SAFE: No memory is referenced by the command
+		unsafe { cmd.start(&self.ctrlr.regs, ent); }
+		Ok( try!(cmd.wait()) )
+	}
+
+	/// Read or write `count` blocks, split into commands no larger than the controller's limit
+	fn transfer(&self, idx: u64, count: usize, mut data: DataPtr) -> Result<usize, storage::IoError>
+	{
+		try!( self.check_range(idx, count) );
+
+		let max_blocks = ::core::cmp::min(self.ctrlr.max_transfer / self.block_size, 1 << 16);
+		// PRPs must be dword aligned, so unaligned buffers are copied through a DMA buffer
+		let mut bounce = if data.as_ptr() as usize % 4 != 0 {
+				let n_pages = (::core::cmp::min(count, max_blocks) * self.block_size + PAGE_SIZE - 1) / PAGE_SIZE;
+				match ::kernel::memory::virt::alloc_dma(64, n_pages, "NVMe")
+				{
+				Ok(v) => Some(v),
+				Err(_) => return Err( storage::IoError::Unknown("NVMe bounce buffer allocation failed") ),
+				}
+			}
+			else {
+				None
+			};
+
+		let mut done = 0;
+		while done < count
+		{
+			let ofs = done * self.block_size;
+			let len = ::core::cmp::min(count - done, max_blocks) * self.block_size;
+
+			let q = self.ctrlr.io_queue();
+			let cmd = q.get_slot();
+			let mut ent = hw::SubmissionEntry::new(data.opcode(), self.nsid);
+			let buf = match bounce
+				{
+				Some(ref mut b) => {
+					if let DataPtr::Send(src) = data {
+						b.as_mut_slice::<u8>(0, len).clone_from_slice(&src[ofs .. ofs + len]);
+					}
+					b.as_ref::<u8>(0) as *const u8
+					},
+				None => (data.as_ptr() as usize + ofs) as *const u8,
+				};
+			// The PRP list may not cover the whole range (e.g. if the buffer isn't page aligned), limit to whole blocks
+			let n = cmd.set_prps(&mut ent, buf, len) / self.block_size;
+			let lba = idx + done as u64;
+			ent.cdw10 = (lba >>  0) as u32;
+			ent.cdw11 = (lba >> 32) as u32;
+			ent.cdw12 = (n - 1) as u32;
+			// SAFE: The buffer is borrowed by the caller (or is the bounce buffer), and the command is waited on below
+			unsafe { cmd.start(&self.ctrlr.regs, ent); }
+			try!(cmd.wait());
+
+			if let (&Some(ref b), &mut DataPtr::Recv(ref mut dst)) = (&bounce, &mut data) {
+				let n_bytes = n * self.block_size;
+				dst[ofs .. ofs + n_bytes].clone_from_slice(b.as_slice::<u8>(0, n_bytes));
+			}
+			done += n;
+		}
+		Ok( done )
+	}
+}
+
+/// Data buffer for a read/write
+enum DataPtr<'a>
+{
+	Send(&'a [u8]),
+	Recv(&'a mut [u8]),
+}
+impl<'a> DataPtr<'a>
+{
+	fn as_ptr(&self) -> *const u8 {
+		match *self
+		{
+		DataPtr::Send(ref v) => v.as_ptr(),
+		DataPtr::Recv(ref v) => v.as_ptr(),
+		}
+	}
+	fn opcode(&self) -> u8 {
+		match *self
+		{
+		DataPtr::Send(_) => hw::NVM_WRITE,
+		DataPtr::Recv(_) => hw::NVM_READ,
+		}
+	}
+}
+
+/// Commands are spread over the I/O queues, which have their own locking
+impl storage::ConcurrentPhysicalVolume for Volume
+{
+}
+
+impl storage::PhysicalVolume for Volume
+{
+	fn name(&self) -> &str { &self.name }
+	fn blocksize(&self) -> Result<usize,storage::IoError> { Ok(self.block_size) }
+	fn capacity(&self) -> Option<u64> { Some(self.block_count) }
+
+	fn read<'a>(&'a self, _prio: u8, idx: u64, num: usize, dst: &'a mut [u8]) -> storage::AsyncIoResult<'a,usize>
+	{
+		assert_eq!( dst.len(), num * self.block_size );
+		let rv = self.transfer(idx, num, DataPtr::Recv(dst));
+		Box::new( async::NullResultWaiter::new( move || rv ) )
+	}
+	fn write<'a>(&'a self, _prio: u8, idx: u64, num: usize, src: &'a [u8]) -> storage::AsyncIoResult<'a,usize>
+	{
+		assert_eq!( src.len(), num * self.block_size );
+		let rv = self.transfer(idx, num, DataPtr::Send(src));
+		Box::new( async::NullResultWaiter::new( move || rv ) )
+	}
+
+	fn wipe<'a>(&'a self, idx: u64, num: usize) -> storage::AsyncIoResult<'a,()>
+	{
+		let rv = if let Err(e) = self.check_range(idx, num) {
+				Err(e)
+			}
+			else if !self.ctrlr.supports_dsm {
+				// Deallocate is only a hint
+				Ok( () )
+			}
+			else {
+				let mut rv = Ok( () );
+				let mut done = 0;
+				while done < num
+				{
+					let n = ::core::cmp::min(num - done, 0xFFFF_FFFF);
+					let q = self.ctrlr.io_queue();
+					let cmd = q.get_slot();
+					let mut ent = hw::SubmissionEntry::new(hw::NVM_DATASET_MGMT, self.nsid);
+					ent.cdw10 = 0;	// One range
+					ent.cdw11 = hw::DSM_AD;
+					cmd.set_dsm_range(&mut ent, idx + done as u64, n as u32);
+					// SAFE: Range is stored in the slot's own memory
+					unsafe { cmd.start(&self.ctrlr.regs, ent); }
+					if let Err(e) = cmd.wait() {
+						rv = Err( e.into() );
+						break ;
+					}
+					done += n;
+				}
+				rv
+			};
+		Box::new( async::NullResultWaiter::new( move || rv ) )
+	}
+
+	fn flush<'a>(&'a self) -> storage::AsyncIoResult<'a,()>
+	{
+		let rv = if self.ctrlr.volatile_cache {
+				self.command(hw::SubmissionEntry::new(hw::NVM_FLUSH, self.nsid)).map(|_| ())
+			}
+			else {
+				Ok( () )
+			};
+		Box::new( async::NullResultWaiter::new( move || rv ) )
+	}
+
+	fn max_outstanding(&self) -> usize {
+		// Commands are spread over the I/O queues, which have their own locking
+		self.ctrlr.io_queue_count() * 4
+	}
+}
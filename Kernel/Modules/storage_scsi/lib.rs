@@ -9,6 +9,7 @@
 use kernel::prelude::*;
 
 use kernel::async;
+use kernel::sync::Mutex;
 use kernel::metadevs::storage;
 
 pub mod proto;
@@ -33,8 +34,17 @@ pub struct Volume<I: ScsiInterface>
 {
 	int: I,
 	class: VolumeClass,
-	// block size, number of blocks
-	size: Option< (usize, u64) >,
+	removable: bool,
+	// block size, number of blocks (updated when the medium changes)
+	size: Mutex<Option< (usize, u64) >>,
+}
+
+/// Waiter for a read/write, that decodes the sense data if the command fails
+struct IoWaiter<'a, I: 'a + ScsiInterface>
+{
+	vol: &'a Volume<I>,
+	inner: storage::AsyncIoResult<'a,()>,
+	count: usize,
 }
 
 impl<I: ScsiInterface> Volume<I>
@@ -60,6 +70,138 @@ impl<I: ScsiInterface> Volume<I>
 			};
 		Ok( () )
 	}
+	fn send_cmd(int: &I, cmd: &[u8], data: &[u8]) -> Result<(), storage::IoError> {
+		log_debug!("- cmd=[{:?}] (send {})", cmd, data.len());
+		let mut v = int.send(cmd, data);
+		while !v.is_complete() {
+			::kernel::async::wait_on_list(&mut [v.as_waiter()], None);
+		}
+		v.get_result().unwrap()
+	}
+
+	/// Read the medium's block size and block count (None if there's no medium in a removable drive)
+	fn read_capacity(int: &I, removable: bool) -> Result<Option<(usize, u64)>, storage::IoError> {
+		let mut data = proto::ReadCapacity10Rsp::new();
+		match Self::recv_cmd(int, proto::ReadCapacity10::new().as_ref(), data.as_mut())
+		{
+		Ok(_) => {
+			::kernel::logging::hex_dump("SCSI Volume size", data.as_ref());
+			let blksz = data.block_length();
+			let max = data.maxlba();
+			Ok( Some( (blksz as usize, (max as u64 + 1)) ) )
+			},
+		Err(storage::IoError::NoMedium) if removable => {
+			log_debug!("No medium");
+			Ok( None )
+			},
+		Err(e) => Err(e),
+		}
+	}
+
+	/// Convert a failed command's error into a more specific error using the device's sense data
+	///
+	/// Also handles media changes (re-reading the capacity)
+	fn decode_error(&self, err: storage::IoError) -> storage::IoError {
+		use proto::SenseKey;
+		let mut sense = proto::RequestSenseRsp::new();
+		match Self::recv_cmd(&self.int, proto::RequestSense::new(sense.len() as u8).as_ref(), sense.as_mut())
+		{
+		Ok(_) if sense.is_valid() => {},
+		_ => return err,
+		}
+		log_debug!("{}: Sense {:?} ASC={:#x} ASCQ={:#x}", self.int.name(), sense.sense_key(), sense.asc(), sense.ascq());
+		match sense.sense_key()
+		{
+		SenseKey::NoSense | SenseKey::RecoveredError => err,
+		SenseKey::NotReady => match sense.asc()
+			{
+			// MEDIUM NOT PRESENT
+			0x3A => {
+				*self.size.lock() = None;
+				storage::IoError::NoMedium
+				},
+			_ => storage::IoError::Timeout,
+			},
+		SenseKey::MediumError => storage::IoError::BadBlock,
+		SenseKey::HardwareError => storage::IoError::Unknown("SCSI hardware error"),
+		SenseKey::IllegalRequest => match sense.asc()
+			{
+			// LOGICAL BLOCK ADDRESS OUT OF RANGE
+			0x21 => storage::IoError::BadAddr,
+			_ => storage::IoError::InvalidParameter,
+			},
+		// NOT READY TO READY CHANGE (medium may have changed), or a reset/power-on
+		SenseKey::UnitAttention => {
+			self.refresh_size();
+			storage::IoError::MediaChanged
+			},
+		SenseKey::DataProtect => storage::IoError::ReadOnly,
+		SenseKey::BlankCheck | SenseKey::VolumeOverflow => storage::IoError::BadAddr,
+		SenseKey::AbortedCommand => storage::IoError::Timeout,
+		_ => storage::IoError::Unknown("SCSI error"),
+		}
+	}
+
+	/// Re-read the capacity after a medium change
+	fn refresh_size(&self) {
+		let size = match Self::read_capacity(&self.int, self.removable)
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_notice!("{}: Unable to read capacity after media change - {:?}", self.int.name(), e);
+				None
+				},
+			};
+		log_log!("SCSI Volume {} - Medium changed, size={:?}", self.int.name(), size);
+		*self.size.lock() = size;
+	}
+
+	/// Check for a medium change (using TEST UNIT READY)
+	///
+	/// Returns `Err(MediaChanged)` if the medium was changed since the last request, and `Err(NoMedium)` if there's
+	/// no medium. Removable drives should be polled with this to detect ejection/insertion.
+	pub fn check_media(&self) -> Result<(), storage::IoError> {
+		match Self::recv_cmd(&self.int, proto::TestUnitReady::new().as_ref(), &mut [])
+		{
+		Ok(_) => {
+			if self.size.lock().is_none() {
+				// - A medium has been inserted without a UNIT ATTENTION
+				self.refresh_size();
+				if self.size.lock().is_some() {
+					return Err(storage::IoError::MediaChanged);
+				}
+			}
+			Ok( () )
+			},
+		Err(e) => Err(self.decode_error(e)),
+		}
+	}
+
+	/// Returns the block size and count, or an error if there's no medium present
+	///
+	/// If there was no medium, the drive is checked again (so an inserted medium is picked up without waiting for a
+	/// UNIT ATTENTION), returning `Err(MediaChanged)` if one has appeared.
+	fn medium_size(&self) -> Result<(usize, u64), storage::IoError> {
+		if let Some(size) = *self.size.lock() {
+			return Ok(size);
+		}
+		if self.removable {
+			try!(self.check_media());
+		}
+		match *self.size.lock()
+		{
+		Some(_) => Err(storage::IoError::MediaChanged),
+		None => Err(storage::IoError::NoMedium),
+		}
+	}
+	/// Wrap a command's waiter, to decode errors and return the block count
+	fn io_waiter<'a>(&'a self, inner: storage::AsyncIoResult<'a,()>, count: usize) -> storage::AsyncIoResult<'a,usize> {
+		Box::new(IoWaiter {
+			vol: self,
+			inner: inner,
+			count: count,
+			})
+	}
 	pub fn new_boxed(int: I) -> Result<Box<Self>,storage::IoError> {
 		// 1. Request device type (INQUIRY)
 		let (class, removable) = {
@@ -83,29 +225,14 @@ impl<I: ScsiInterface> Volume<I>
 			};
 		
 		// 2. Check the size (and check for a disk too)
-		let size = {
-			let mut data = proto::ReadCapacity10Rsp::new();
-			match Self::recv_cmd(&int, proto::ReadCapacity10::new().as_ref(), data.as_mut())
-			{
-			Ok(_) => {
-				::kernel::logging::hex_dump("SCSI Volume size", data.as_ref());
-				let blksz = data.block_length();
-				let max = data.maxlba();
-				Some( (blksz as usize, (max as u64 + 1)) )
-				},
-			Err(storage::IoError::NoMedium) if removable => {
-				log_debug!("No medium");
-				None
-				},
-			Err(e) => return Err(From::from(e)),
-			}
-			};
+		let size = try!(Self::read_capacity(&int, removable));
 		log_log!("SCSI Volume {} - class={:?} size={:?}", int.name(), class, size);
 		
 		Ok(Box::new( Volume {
 			int: int,
 			class: class,
-			size: size,
+			removable: removable,
+			size: Mutex::new(size),
 			} ))
 	}
 }
@@ -119,14 +246,49 @@ fn fits_in_bits(v: usize, bits: usize) -> bool {
 	}
 }
 
+impl<'a, I: 'a + ScsiInterface> ::core::fmt::Debug for IoWaiter<'a, I> {
+	fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+		write!(f, "IoWaiter({:?}, {})", self.inner, self.count)
+	}
+}
+impl<'a, I: 'a + ScsiInterface> async::Waiter for IoWaiter<'a, I> {
+	fn is_complete(&self) -> bool { self.inner.is_complete() }
+	fn get_waiter(&mut self) -> &mut dyn async::PrimitiveWaiter { self.inner.get_waiter() }
+	fn complete(&mut self) -> bool { self.inner.complete() }
+}
+impl<'a, I: 'a + ScsiInterface> async::ResultWaiter for IoWaiter<'a, I> {
+	type Result = Result<usize, storage::IoError>;
+	fn get_result(&mut self) -> Option<Self::Result> {
+		match self.inner.get_result()
+		{
+		Some(Ok(_)) => Some(Ok(self.count)),
+		Some(Err(e)) => Some(Err(self.vol.decode_error(e))),
+		None => None,
+		}
+	}
+	fn as_waiter(&mut self) -> &mut dyn async::Waiter { self.inner.as_waiter() }
+}
+
 impl<I: ScsiInterface> storage::PhysicalVolume for Volume<I>
 {
 	fn name(&self) -> &str { self.int.name() }
-	fn blocksize(&self) -> usize { self.size.expect("Calling blocksize on no-media volume").0 }
-	fn capacity(&self) -> Option<u64> { self.size.map(|x| x.1) }
+	fn blocksize(&self) -> Result<usize,storage::IoError> {
+		match *self.size.lock()
+		{
+		Some( (size, _) ) => Ok(size),
+		None => Err(storage::IoError::NoMedium),
+		}
+	}
+	fn capacity(&self) -> Option<u64> { self.size.lock().map(|x| x.1) }
 	
 	fn read<'a>(&'a self, _prio: u8, idx: u64, num: usize, dst: &'a mut [u8]) -> storage::AsyncIoResult<'a,usize>
 	{
+		let bs = match self.medium_size()
+			{
+			Ok( (bs, count) ) if idx < count && num as u64 <= count - idx => bs,
+			Ok(_) => return Box::new(async::NullResultWaiter::new( || Err(storage::IoError::BadAddr) )),
+			Err(e) => return Box::new(async::NullResultWaiter::new( move || Err(e) )),
+			};
 		// NOTE: Read6 commented out, as qemu's CD code doesn't support it
 		let rv = /*if idx < (1<<24) && num < (1 << 8) {
 				log_trace!("SCSI Read6");
@@ -141,40 +303,89 @@ impl<I: ScsiInterface> storage::PhysicalVolume for Volume<I>
 				self.int.recv(proto::Read16::new(idx, num as u32).as_ref(), dst)
 			}
 			else {
-				todo!("SCSI read out of range");
+				// - Larger than a single command can express, read the first part (the caller handles short reads)
+				let num: usize = 0xFFFF_FFFF;
+				log_trace!("SCSI Read16 (partial)");
+				return self.io_waiter(self.int.recv(proto::Read16::new(idx, num as u32).as_ref(), &mut dst[..num * bs]), num);
 			};
-		
-		#[derive(Debug)]
-		struct Wrapper<'a>(storage::AsyncIoResult<'a,()>, usize);
-		impl<'a> async::Waiter for Wrapper<'a> {
-			fn is_complete(&self) -> bool { self.0.is_complete() }
-			fn get_waiter(&mut self) -> &mut dyn async::PrimitiveWaiter { self.0.get_waiter() }
-			fn complete(&mut self) -> bool { self.0.complete() }
-		}
-		impl<'a> async::ResultWaiter for Wrapper<'a> {
-			type Result = Result<usize, ::kernel::metadevs::storage::IoError>;
-			fn get_result(&mut self) -> Option<Self::Result> { self.0.get_result().map(|v| v.map(|_| self.1)) }
-			fn as_waiter(&mut self) -> &mut dyn async::Waiter { self.0.as_waiter() }
-		}
-		Box::new( Wrapper(rv, num) )
-
-		//rv
+		self.io_waiter(rv, num)
 	}
 	fn write<'s>(&'s self, _prio: u8, idx: u64, num: usize, src: &'s [u8]) -> storage::AsyncIoResult<'s,usize> {
 		match self.class
 		{
 		VolumeClass::CdDvd => Box::new(async::NullResultWaiter::new( || Err(storage::IoError::ReadOnly) )),
 		VolumeClass::DirectAccessBlock => {
-			todo!("Volume::write(idx={},num={},len={})", idx, num, src.len());
+			let bs = match self.medium_size()
+				{
+				Ok( (bs, count) ) if idx < count && num as u64 <= count - idx => bs,
+				Ok(_) => return Box::new(async::NullResultWaiter::new( || Err(storage::IoError::BadAddr) )),
+				Err(e) => return Box::new(async::NullResultWaiter::new( move || Err(e) )),
+				};
+			let rv = if idx < (1<<32) && num < (1 << 16) {
+					log_trace!("SCSI Write10");
+					self.int.send(proto::Write10::new(idx as u32, num as u16).as_ref(), src)
+				}
+				else if fits_in_bits(num, 32) {
+					log_trace!("SCSI Write16");
+					self.int.send(proto::Write16::new(idx, num as u32).as_ref(), src)
+				}
+				else {
+					// - Write as much as a single command can express (the storage layer limits writes anyway)
+					let num: usize = 0xFFFF_FFFF;
+					log_trace!("SCSI Write16 (partial)");
+						return self.io_waiter(self.int.send(proto::Write16::new(idx, num as u32).as_ref(), &src[..num * bs]), num);
+				};
+			self.io_waiter(rv, num)
 			},
 		_ => Box::new(async::NullResultWaiter::new( || Err(storage::IoError::Unknown("TODO: Write support")) )),
 		}
 	}
 	
-	fn wipe<'a>(&'a self, _blockidx: u64, _count: usize) -> storage::AsyncIoResult<'a,()>
+	fn wipe<'a>(&'a self, blockidx: u64, count: usize) -> storage::AsyncIoResult<'a,()>
 	{
-		todo!("Volume::wipe");
+		let rv = match self.class
+			{
+			VolumeClass::DirectAccessBlock => {
+				// - Split into chunks that fit in a single block descriptor
+				let mut rv = Ok( () );
+				let mut idx = blockidx;
+				let mut rem = count as u64;
+				while rem > 0
+				{
+					let n = ::core::cmp::min(rem, 0xFFFF_FFFF);
+					let params = proto::UnmapParams::new(idx, n as u32);
+					rv = match Self::send_cmd(&self.int, proto::Unmap::new(params.len() as u16).as_ref(), params.as_ref())
+						{
+						Ok(_) => Ok( () ),
+						Err(e) => match self.decode_error(e)
+							{
+							// - UNMAP isn't supported, wiping is only advisory so that's not an error
+							storage::IoError::InvalidParameter => break,
+							e @ _ => Err(e),
+							},
+						};
+					if rv.is_err() {
+						break;
+					}
+					idx += n;
+					rem -= n;
+				}
+				rv
+				},
+			VolumeClass::CdDvd => Err(storage::IoError::ReadOnly),
+			_ => Ok( () ),
+			};
+		Box::new(async::NullResultWaiter::new( move || rv ))
+	}
+	
+	fn flush<'a>(&'a self) -> storage::AsyncIoResult<'a,()>
+	{
+		match self.class
+		{
+		VolumeClass::DirectAccessBlock => self.int.send(proto::SynchronizeCache10::new().as_ref(), &[]),
+		// Read-only/unsupported classes have nothing to flush
+		_ => Box::new(async::NullResultWaiter::new( || Ok( () ) )),
+		}
 	}
 	
 }
-
@@ -210,3 +210,105 @@ impl GetConfiguration
 	}
 }
 
+
+def_cmd!{ SynchronizeCache10[10] 0x35,
+	() => [
+		0,	// 1: flags (IMMED=0, wait for completion)
+		0,0,0,0,	// LBA (0 with count 0 = entire medium)
+		0,	// 6: group number
+		0,0,	// count
+		0	// 9: control
+	] }
+
+def_cmd!{ Write10[10] 0x2A,
+	(lba: u32, count: u16) => [
+		0,	// 1: flags
+		((lba >> 24) & 0xFF) as u8,
+		((lba >> 16) & 0xFF) as u8,
+		((lba >>  8) & 0xFF) as u8,
+		((lba >>  0) & 0xFF) as u8,
+		0,	// 6: group number
+		((count >> 8) & 0xFF) as u8,
+		((count >> 0) & 0xFF) as u8,
+		0	// 9: control
+	] }
+
+def_cmd!{ Write16[16] 0x8A,
+	(lba: u64, count: u32) => [
+		0,	// 1: flags
+		((lba >> 56) & 0xFF) as u8,
+		((lba >> 48) & 0xFF) as u8,
+		((lba >> 40) & 0xFF) as u8,
+		((lba >> 32) & 0xFF) as u8,
+		((lba >> 24) & 0xFF) as u8,
+		((lba >> 16) & 0xFF) as u8,
+		((lba >>  8) & 0xFF) as u8,
+		((lba >>  0) & 0xFF) as u8,
+		((count >> 24) & 0xFF) as u8,
+		((count >> 16) & 0xFF) as u8,
+		((count >>  8) & 0xFF) as u8,
+		((count >>  0) & 0xFF) as u8,
+		0,	// 14: group number
+		0	// 15: control
+	] }
+
+def_cmd!{ TestUnitReady[6] 0x00,
+	() => [
+		0,0,0,0,	// reserved
+		0	// 5: control
+	] }
+
+def_cmd!{ RequestSense[6] 0x03,
+	(alloc: u8) => [
+		0,	// 1: DESC=0 (fixed format)
+		0,0,	// reserved
+		alloc,
+		0	// 5: control
+	] }
+/// Fixed-format sense data
+def_rsp!{ RequestSenseRsp[18] }
+impl RequestSenseRsp
+{
+	/// Returns false if the data isn't fixed-format sense data (e.g. the request failed)
+	pub fn is_valid(&self) -> bool {
+		self.0[0] & 0x7E == 0x70
+	}
+	pub fn sense_key(&self) -> SenseKey {
+		SenseKey::from(self.0[2] & 0xF)
+	}
+	/// Additional Sense Code
+	pub fn asc(&self) -> u8 {
+		self.0[12]
+	}
+	/// Additional Sense Code Qualifier
+	pub fn ascq(&self) -> u8 {
+		self.0[13]
+	}
+}
+
+def_cmd!{ Unmap[10] 0x42,
+	(param_len: u16) => [
+		0,	// 1: anchor
+		0,0,0,0,	// reserved
+		0,	// 6: group number
+		((param_len >> 8) & 0xFF) as u8,
+		((param_len >> 0) & 0xFF) as u8,
+		0	// 9: control
+	] }
+/// UNMAP parameter list, with a single block descriptor
+pub struct UnmapParams([u8; 8+16]);
+impl AsRef<[u8]> for UnmapParams { fn as_ref(&self) -> &[u8] { &self.0 } }
+impl UnmapParams
+{
+	pub fn new(lba: u64, count: u32) -> UnmapParams {
+		let mut rv = UnmapParams([0; 8+16]);
+		BigEndian::write_u16(&mut rv.0[0..], 6 + 16);	// Data length (excluding this field)
+		BigEndian::write_u16(&mut rv.0[2..], 16);	// Block descriptor data length
+		BigEndian::write_u64(&mut rv.0[8..], lba);
+		BigEndian::write_u32(&mut rv.0[16..], count);
+		rv
+	}
+	pub fn len(&self) -> usize {
+		self.0.len()
+	}
+}
@@ -29,6 +29,7 @@ impl ::core::convert::Into<values::GuiEvent> for ::gui::input::Event {
 		Event::MouseClick(x,y,btn,2) => values::GuiEvent::MouseDblClick(x,y,btn),
 		Event::MouseClick(x,y,btn,3) => values::GuiEvent::MouseTriClick(x,y,btn),
 		Event::MouseClick(x,y,btn,_) => values::GuiEvent::MouseClick(x,y,btn),
+		Event::MouseScroll(x,y, dx,dy) => values::GuiEvent::MouseScroll(x,y, dx,dy),
 		}
 	}
 }
@@ -67,6 +68,16 @@ pub fn get_group() -> Result<ObjectHandle,u32>
 	wgh.with(|h| objects::new_object(Group( h.clone() )))
 }
 
+/// Select the keyboard layout (any process can, as there's only the one keyboard user)
+pub fn set_keymap(name: &str) -> Result<u32,u32> {
+	if ::gui::input::set_keymap(name) {
+		Ok(0)
+	}
+	else {
+		Err(0)
+	}
+}
+
 /// Window group, aka Session
 struct Group(::gui::WindowGroupHandle);
 impl objects::Object for Group
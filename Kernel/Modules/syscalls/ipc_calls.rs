@@ -5,8 +5,10 @@
 //! Userland interface to IPC channels
 use args::Args;
 use kernel::memory::freeze::{Freeze,FreezeMut};
-use core::sync::atomic::{AtomicU8,Ordering};
-use values::RpcMessage;
+use core::sync::atomic::{AtomicU8,AtomicBool,Ordering};
+use kernel::prelude::*;
+use kernel::lib::mem::Arc;
+use values::{RpcMessage,MemoryError};
 
 struct SyncChannel {
 	// TODO: NonZero?
@@ -31,14 +33,54 @@ impl ::objects::Object for SyncChannel
 		::values::IPC_RPC_SEND => {
 			let data: Freeze<::values::RpcMessage> = try!(args.get());
 			let obj: u32 = try!(args.get());
-			todo!("IPC_RPC_SEND({:p}, {})", &*data, obj);
+			log_debug!("IPC_RPC_SEND({:p}, {})", &*data, obj);
+			if self.is_peer_closed() {
+				return Ok( 2 );
+			}
+			let mut lh = self.get_peer().message.lock();
+			if lh.is_some() {
+				// Peer hasn't yet received the previous message
+				return Ok( 1 );
+			}
+			// NOTE: Object 0 is "this process", which can't be sent - so is used to indicate no object
+			let obj = if obj != 0 {
+					Some( try!(::objects::take_object_any(obj)) )
+				}
+				else {
+					None
+				};
+			*lh = Some( (*data, obj) );
+			drop(lh);
+			self.get_peer().queue.wake_one();
+			Ok( 0 )
 			},
 		::values::IPC_RPC_RECV => {
-			let _data: FreezeMut<::values::RpcMessage> = try!(args.get());
+			let mut data: FreezeMut<::values::RpcMessage> = try!(args.get());
 
-			if let Some(msg) = self.take_message()
+			let mut lh = self.get_side().message.lock();
+			if let Some((msg, obj)) = lh.take()
 			{
-				todo!("IPC_RPC_RECV - Message present - {:?}", msg);
+				log_debug!("IPC_RPC_RECV - Message present (obj={})", obj.is_some());
+				let rv = match obj
+					{
+					None => 0,
+					Some(obj) => match ::objects::new_object_any(obj)
+						{
+						Ok(v) => v as u64,
+						Err( (e, obj) ) => {
+							// Leave the message queued, so it can be received once a handle is freed
+							log_notice!("IPC_RPC_RECV - Unable to store received object: {:?}", e);
+							*lh = Some( (msg, Some(obj)) );
+							return Ok( 0x1002 );
+							},
+						},
+					};
+				*data = msg;
+				Ok( rv )
+			}
+			else if self.is_peer_closed()
+			{
+				Ok( 0x1001 )
 			}
 			else
 			{
@@ -65,7 +107,7 @@ impl ::objects::Object for SyncChannel
 		let mut ret = 0;
 		if flags & ::values::EV_IPC_RPC_RECV != 0 {
 			self.clear_wait(obj);
-			if self.has_message() {
+			if self.has_message() || self.is_peer_closed() {
 				ret += 1;
 			}
 		}
@@ -101,7 +143,8 @@ struct SyncChannelBack
 #[derive(Default)]
 struct SyncChannelSide
 {
-	message: ::kernel::sync::Spinlock<Option<RpcMessage>>,
+	/// Message waiting to be received by this side (with an optional attached object)
+	message: ::kernel::sync::Mutex<Option<(RpcMessage, Option<::objects::UserObject>)>>,
 	queue: ::kernel::async::queue::Source,
 }
 
@@ -120,6 +163,18 @@ impl SyncChannel
 			&(*self.ptr).sides[self.side_idx as usize]
 		}
 	}
+	fn get_peer(&self) -> &SyncChannelSide {
+		// SAFE: Destructor ensures that pointer is valid until both are dead
+		unsafe {
+			&(*self.ptr).sides[1 - self.side_idx as usize]
+		}
+	}
+	fn is_peer_closed(&self) -> bool {
+		// SAFE: Destructor ensures that pointer is valid until both are dead
+		unsafe {
+			(*self.ptr).dying_refs.load(Ordering::SeqCst) & (1 << (1 - self.side_idx)) != 0
+		}
+	}
 
 	pub fn wait_upon(&self, waiter: &mut ::kernel::threads::SleepObject) {
 		self.get_side().queue.wait_upon(waiter);
@@ -131,9 +186,6 @@ impl SyncChannel
 	pub fn has_message(&self) -> bool {
 		self.get_side().message.lock().is_some()
 	}
-	pub fn take_message(&self) -> Option<RpcMessage> {
-		self.get_side().message.lock().take()
-	}
 }
 
 impl ::core::ops::Drop for SyncChannel {
@@ -144,13 +196,167 @@ impl ::core::ops::Drop for SyncChannel {
 				// Other side is in shutdown or dead.
 			}
 			else {
+				// Wake the other side, so it can see that the connection closed
+				self.get_peer().queue.wake_all();
 			}
 
 			(*self.ptr).dead_refs.fetch_or(1 << self.side_idx, Ordering::SeqCst) != 0
 			};
 		if should_free {
-			todo!("Deallocate SyncChannel");
+			// SAFE: Both sides are dead, nothing else can reference the allocation
+			unsafe {
+				::core::ptr::drop_in_place(self.ptr as *mut SyncChannelBack);
+				::kernel::memory::heap::dealloc(self.ptr as *mut SyncChannelBack);
+			}
+		}
+	}
+}
+
+
+// --------------------------------------------------------------------
+// Shared memory buffers
+// --------------------------------------------------------------------
+
+/// Handle to a shared memory buffer (cloneable, each clone refers to the same memory)
+struct SharedBuffer
+{
+	inner: Arc<SharedBufferInner>,
+	/// Handle lacks `OBJECT_RIGHT_WRITE`, so may only be mapped read-only
+	read_only: bool,
+}
+struct SharedBufferInner
+{
+	frames: Vec<::kernel::memory::phys::FrameHandle>,
+	consumed: AtomicBool,
+	waiters: ::kernel::async::queue::Source,
+}
+
+/// Upper limit on the size of a single buffer (4MB with 4KB pages)
+const MAX_BUFFER_PAGES: usize = 1024;
+
+pub fn new_buffer(page_count: usize) -> Result<u32, ()>
+{
+	if page_count == 0 || page_count > MAX_BUFFER_PAGES {
+		log_log!("IPC_NEWBUFFER - Bad page count {}", page_count);
+		return Err( () );
+	}
+
+	let mut frames = Vec::with_capacity(page_count);
+	for _ in 0 .. page_count
+	{
+		let mut page = match ::kernel::memory::virt::alloc_free()
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_notice!("IPC_NEWBUFFER - Allocation failure: {:?}", e);
+				return Err( () );
+				},
+			};
+		for b in page.iter_mut() {
+			*b = 0;
 		}
+		frames.push( page.into_frame() );
+	}
+
+	let rv = ::objects::new_object(SharedBuffer {
+		inner: Arc::new(SharedBufferInner {
+			frames: frames,
+			consumed: AtomicBool::new(false),
+			waiters: ::kernel::async::queue::Source::new(),
+			}),
+		read_only: false,
+		});
+	if rv == !0 {
+		Err( () )
+	}
+	else {
+		Ok( rv )
 	}
 }
 
+impl ::objects::Object for SharedBuffer
+{
+	fn class(&self) -> u16 { ::values::CLASS_IPC_BUFFER }
+	fn as_any(&self) -> &dyn core::any::Any { self }
+	fn try_clone(&self) -> Option<u32> {
+		let new = SharedBuffer { inner: self.inner.clone(), read_only: self.read_only };
+		let rv = if self.read_only {
+				::objects::new_object_restricted(new, ::values::OBJECT_RIGHTS_ALL & !::values::OBJECT_RIGHT_WRITE)
+			}
+			else {
+				::objects::new_object(new)
+			};
+		if rv == !0 {
+			None
+		}
+		else {
+			Some(rv)
+		}
+	}
+	fn restrict(&mut self, rights: u32) {
+		self.read_only |= rights & ::values::OBJECT_RIGHT_WRITE == 0;
+	}
+	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,::Error> {
+		match call
+		{
+		::values::IPC_BUFFER_GETSIZE => {
+			Ok( self.inner.frames.len() as u64 )
+			},
+		::values::IPC_BUFFER_MAP => {
+			let addr: usize = try!(args.get());
+			let writable: bool = try!(args.get());
+			log_debug!("IPC_BUFFER_MAP({:#x}, writable={})", addr, writable);
+			let pages = self.inner.frames.len();
+			if addr % ::kernel::PAGE_SIZE != 0 || addr >= ::kernel::arch::memory::addresses::USER_END || pages > (::kernel::arch::memory::addresses::USER_END - addr) / ::kernel::PAGE_SIZE {
+				return Ok( super::from_result::<u32,_>(Err(MemoryError::BadAddress)) );
+			}
+			if writable && self.read_only {
+				log_log!("IPC_BUFFER_MAP - Writable mapping requested without write rights");
+				return Ok( super::from_result::<u32,_>(Err(MemoryError::PermissionDenied)) );
+			}
+			let mode = if writable {
+					::kernel::memory::virt::ProtectionMode::UserRW
+				}
+				else {
+					::kernel::memory::virt::ProtectionMode::UserRO
+				};
+			let rv = match ::kernel::memory::virt::map_user_frames(addr as *mut (), &self.inner.frames, mode)
+				{
+				Ok( () ) => Ok( 0u32 ),
+				Err(e) => {
+					log_log!("IPC_BUFFER_MAP - Failed to map at {:#x}: {:?}", addr, e);
+					Err( MemoryError::from(e) )
+					},
+				};
+			Ok( super::from_result(rv) )
+			},
+		::values::IPC_BUFFER_SIGNAL => {
+			self.inner.consumed.store(true, Ordering::SeqCst);
+			self.inner.waiters.wake_all();
+			Ok( 0 )
+			},
+		_ => ::objects::object_has_no_such_method_ref("ipc_calls::SharedBuffer", call),
+		}
+	}
+	fn bind_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & ::values::EV_IPC_BUFFER_CONSUMED != 0 {
+			self.inner.waiters.wait_upon(obj);
+			if self.inner.consumed.load(Ordering::SeqCst) {
+				obj.signal();
+			}
+			ret += 1;
+		}
+		ret
+	}
+	fn clear_wait(&self, flags: u32, obj: &mut ::kernel::threads::SleepObject) -> u32 {
+		let mut ret = 0;
+		if flags & ::values::EV_IPC_BUFFER_CONSUMED != 0 {
+			self.inner.waiters.clear_wait(obj);
+			if self.inner.consumed.swap(false, Ordering::SeqCst) {
+				ret |= ::values::EV_IPC_BUFFER_CONSUMED;
+			}
+		}
+		ret
+	}
+}
@@ -14,6 +14,8 @@ use args::Args;
 #[macro_use]
 extern crate kernel;
 extern crate gui;
+extern crate block_cache;
+extern crate storage_crypt;
 extern crate stack_dst;
 
 mod objects;
@@ -24,7 +26,9 @@ mod threads;
 mod gui_calls;
 mod vfs;
 mod ipc_calls;
+mod memory_calls;
 mod network_calls;
+mod storage_calls;
 
 pub type ObjectHandle = u32;
 
@@ -39,6 +43,7 @@ pub enum Error
 	InvalidBuffer(*const (), usize),
 	BorrowFailure,
 	MoveContention,
+	PermissionDenied,
 	InvalidUnicode(::core::str::Utf8Error),
 }
 impl From<::core::str::Utf8Error> for Error {
@@ -59,6 +64,7 @@ impl ::core::fmt::Display for Error {
 		Error::InvalidBuffer(p,s) => write!(f, "Buffer {:p}+{} wasn't valid", p, s),
 		Error::BorrowFailure => f.write_str("Contention on memory accesses"),
 		Error::MoveContention => f.write_str("Contention on object transfer"),
+		Error::PermissionDenied => f.write_str("Handle lacks the required rights"),
 		Error::InvalidUnicode(_) => f.write_str("Passed string wasn't valid unicode"),
 		}
 	}
@@ -181,6 +187,11 @@ fn invoke_int(call_id: u32, args: &mut Args) -> Result<u64,Error>
 		CORE_FUTEX_WAKE => {
 			todo!("FUTEX_SLEEP");
 			},
+		CORE_PROCESSINFO => {
+			let min_pid: u32 = try!(args.get());
+			let mut info: FreezeMut<::values::ProcessInfo> = try!(args.get());
+			threads::process_info(min_pid, &mut info) as u64
+			},
 		// === 1: Window Manager / GUI
 		// - 1/0: New group (requires permission, has other restrictions)
 		GUI_NEWGROUP => {
@@ -206,44 +217,48 @@ fn invoke_int(call_id: u32, args: &mut Args) -> Result<u64,Error>
 			let name: Freeze<str> = try!(args.get());
 			from_result(gui_calls::newwindow(&name))
 			},
+		// - 1/4: Set keyboard layout
+		GUI_SETKEYMAP => {
+			let name: Freeze<str> = try!(args.get());
+			from_result(gui_calls::set_keymap(&name))
+			},
 		// === 2: Memory Mangement
 		MEM_ALLOCATE => {
 			let addr: usize = try!(args.get());
 			let count: usize = try!(args.get());
 			log_debug!("MEM_ALLOCATE({:#x},{})", addr, count);
-			match ::kernel::memory::virt::allocate_user(addr as *mut (), count)
-			{
-			Ok(_) => 0,
-			Err(e) => todo!("MEM_ALLOCATE - error {:?}", e),
-			}
+			from_result(memory_calls::allocate(addr, count))
 			},
 		MEM_REPROTECT => {
 			let addr: usize = try!(args.get());
+			let count: usize = try!(args.get());
 			let mode: u8 = try!(args.get());
-			log_debug!("MEM_REPROTECT({:#x},{})", addr, mode);
+			log_debug!("MEM_REPROTECT({:#x},{},{})", addr, count, mode);
 			let mode = match mode
 				{
 				0 => ::kernel::memory::virt::ProtectionMode::UserRO,
 				1 => ::kernel::memory::virt::ProtectionMode::UserRW,
 				2 => ::kernel::memory::virt::ProtectionMode::UserRX,
-				3 => ::kernel::memory::virt::ProtectionMode::UserRWX,	// TODO: Should this be disallowed?
+				// RWX is not allowed (W^X)
 				_ => return Err( Error::BadValue ),
 				};
-			// SAFE: This internally does checks, but is marked as unsafe as a signal
-			match unsafe { ::kernel::memory::virt::reprotect_user(addr as *mut (), mode) }
-			{
-			Ok( () ) => 0,
-			Err( () ) => error_code(0) as u64,
-			}
+			from_result(memory_calls::reprotect(addr, count, mode))
 			},
 		MEM_DEALLOCATE => {
 			let addr: usize = try!(args.get());
-			// SAFE: This internally does checks, but is marked as unsafe as a signal
-			match unsafe { ::kernel::memory::virt::reprotect_user(addr as *mut (), ::kernel::memory::virt::ProtectionMode::Unmapped) }
-			{
-			Ok( () ) => 0,
-			Err( () ) => error_code(0) as u64,
-			}
+			let count: usize = try!(args.get());
+			from_result(memory_calls::deallocate(addr, count))
+			},
+		MEM_QUERY => {
+			let addr: usize = try!(args.get());
+			let max_pages: usize = try!(args.get());
+			from_result(memory_calls::query(addr, max_pages))
+			},
+		MEM_GUARD => {
+			let addr: usize = try!(args.get());
+			let count: usize = try!(args.get());
+			log_debug!("MEM_GUARD({:#x},{})", addr, count);
+			from_result(memory_calls::guard(addr, count))
 			},
 		// === 3: IPC
 		IPC_NEWPAIR => {
@@ -253,6 +268,14 @@ fn invoke_int(call_id: u32, args: &mut Args) -> Result<u64,Error>
 			Err( () ) => !0
 			}
 			},
+		IPC_NEWBUFFER => {
+			let page_count: usize = try!(args.get());
+			match ipc_calls::new_buffer(page_count)
+			{
+			Ok(oh) => oh as u64,
+			Err( () ) => !0
+			}
+			},
 		// === 4: Networking
 		NET_CONNECT => {
 			todo!("NET_CONNECT");
@@ -274,6 +297,22 @@ fn invoke_int(call_id: u32, args: &mut Args) -> Result<u64,Error>
 			Err(e) => e as u8 as u64,
 			}
 			},
+		// === 5: Storage
+		STORAGE_CRYPT_UNLOCK => {
+			let volume: Freeze<str> = try!(args.get());
+			let passphrase: Freeze<[u8]> = try!(args.get());
+			from_result(storage_calls::crypt_unlock(&volume, &passphrase))
+			},
+		STORAGE_CRYPT_FORMAT => {
+			let volume: Freeze<str> = try!(args.get());
+			let passphrase: Freeze<[u8]> = try!(args.get());
+			let key_material: Freeze<[u8]> = try!(args.get());
+			from_result(storage_calls::crypt_format(&volume, &passphrase, &key_material))
+			},
+		STORAGE_CRYPT_LOCK => {
+			let volume: Freeze<str> = try!(args.get());
+			from_result(storage_calls::crypt_lock(&volume))
+			},
 		// === *: Default
 		_ => {
 			log_error!("Unknown syscall {:05x}", call_id);
@@ -292,9 +331,13 @@ fn invoke_int(call_id: u32, args: &mut Args) -> Result<u64,Error>
 		// - Call method
 		match call_id as u16
 		{
-		0 ..= 0x3FD => {
+		0 ..= 0x3FC => {
 			objects::call_object_ref(handle_id, call_id as u16, args)
 			},
+		::values::OBJECT_RESTRICT => {
+			let rights: u32 = try!(args.get());
+			objects::derive_object(handle_id, rights)
+			},
 		::values::OBJECT_CLONE => {
 			objects::clone_object(handle_id)
 			},
@@ -334,15 +377,36 @@ fn syscall_core_dbgvalue(msg: &[u8], val: usize) {
 #[inline(never)]
 fn syscall_core_textinfo(group: u32, id: usize, buf: &mut [u8]) -> usize
 {
+	// Copy as much of the string as fits, returning the length copied
+	fn copy_str(buf: &mut [u8], s: &str) -> usize {
+		let mut len = ::core::cmp::min(buf.len(), s.len());
+		while !s.is_char_boundary(len) {
+			len -= 1;
+		}
+		buf[..len].clone_from_slice( &s.as_bytes()[..len] );
+		len
+	}
 	match group
 	{
 	::values::TEXTINFO_KERNEL =>
 		match id
 		{
-		0 => { buf.clone_from_slice( ::kernel::VERSION_STRING.as_bytes() ); ::kernel::VERSION_STRING.len() },
-		1 => { buf.clone_from_slice( ::kernel::BUILD_STRING.as_bytes() ); ::kernel::BUILD_STRING.len() },
+		0 => copy_str(buf, ::kernel::VERSION_STRING),
+		1 => copy_str(buf, ::kernel::BUILD_STRING),
 		_ => 0,
 		},
+	::values::TEXTINFO_PROCESS =>
+		match ::kernel::threads::get_process_info(id as u32)
+		{
+		Some(ref p) if p.pid == id as u32 => copy_str(buf, &p.name),
+		_ => 0,
+		},
+	::values::TEXTINFO_BLOCKCACHE =>
+		match ::block_cache::get_stats(id)
+		{
+		Some((name, s)) => copy_str(buf, &format!("{}: {} hits, {} misses, {} evictions", name, s.hits, s.misses, s.evictions)),
+		None => 0,
+		},
 	_ => 0,
 	}
 }
@@ -0,0 +1,102 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Modules/syscalls/memory_calls.rs
+//! Userland address space management
+use kernel::memory::virt::{self,ProtectionMode,MapError,ProtectError};
+use kernel::arch::memory::addresses::USER_END;
+use kernel::PAGE_SIZE;
+use values::{MemoryError,MemoryState};
+
+/// Upper limit on the page count returned by `MEM_QUERY` (must fit below the state field)
+const MAX_QUERY_PAGES: usize = (1 << ::values::MEM_QUERY_STATE_SHIFT) - 1;
+
+impl_from! {
+	From<MapError>(v) for MemoryError {
+		match v
+		{
+		MapError::OutOfMemory => MemoryError::OutOfMemory,
+		MapError::RangeInUse => MemoryError::AlreadyMapped,
+		}
+	}
+	From<ProtectError>(v) for MemoryError {
+		match v
+		{
+		ProtectError::BadAddress => MemoryError::BadAddress,
+		ProtectError::ReadOnlyBacking => MemoryError::PermissionDenied,
+		ProtectError::GuardPage => MemoryError::PermissionDenied,
+		}
+	}
+}
+
+/// Check that a range is page aligned and entirely within user memory
+fn check_range(addr: usize, count: usize) -> Result<*mut (), MemoryError>
+{
+	if addr % PAGE_SIZE != 0 || count == 0 {
+		Err( MemoryError::BadAddress )
+	}
+	else if count > (USER_END - addr) / PAGE_SIZE || addr >= USER_END {
+		Err( MemoryError::BadAddress )
+	}
+	else {
+		Ok( addr as *mut () )
+	}
+}
+/// Iterate the pages in a (checked) range
+fn pages(addr: *mut (), count: usize) -> impl Iterator<Item=*mut ()> {
+	(0 .. count).map(move |i| (addr as usize + i * PAGE_SIZE) as *mut ())
+}
+
+#[inline(never)]
+pub fn allocate(addr: usize, count: usize) -> Result<u32, MemoryError>
+{
+	let addr = try!(check_range(addr, count));
+	try!( virt::allocate_user(addr, count) );
+	Ok( 0 )
+}
+
+#[inline(never)]
+pub fn guard(addr: usize, count: usize) -> Result<u32, MemoryError>
+{
+	let addr = try!(check_range(addr, count));
+	try!( virt::allocate_user_guard(addr, count) );
+	Ok( 0 )
+}
+
+#[inline(never)]
+pub fn reprotect(addr: usize, count: usize, mode: ProtectionMode) -> Result<u32, MemoryError>
+{
+	let addr = try!(check_range(addr, count));
+	// SAFE: This internally does checks (of the entire range, before changing anything), but is marked as unsafe as a signal
+	try!( unsafe { virt::reprotect_user(addr, count, mode) } );
+	Ok( 0 )
+}
+
+#[inline(never)]
+pub fn deallocate(addr: usize, count: usize) -> Result<u32, MemoryError>
+{
+	reprotect(addr, count, ProtectionMode::Unmapped)
+}
+
+/// Returns the number of pages (starting at `addr`) with the same state, and that state
+#[inline(never)]
+pub fn query(addr: usize, max_pages: usize) -> Result<u32, MemoryError>
+{
+	let max_pages = ::core::cmp::min(max_pages, MAX_QUERY_PAGES);
+	let addr = try!(check_range(addr, max_pages));
+
+	let get_state = |p: *mut ()| match virt::get_info(p)
+		{
+		None => MemoryState::Unmapped,
+		Some( (_, ProtectionMode::UserRO) ) => MemoryState::ReadOnly,
+		// NOTE: COW pages become RW on write, so are reported as RW
+		Some( (_, ProtectionMode::UserRW) ) => MemoryState::ReadWrite,
+		Some( (_, ProtectionMode::UserCOW) ) => MemoryState::ReadWrite,
+		Some( (_, ProtectionMode::UserRX) ) => MemoryState::Executable,
+		Some( (_, ProtectionMode::UserRWX) ) => MemoryState::ReadWriteExecute,
+		Some( (_, _) ) => MemoryState::Guard,
+		};
+	let state: u8 = get_state(addr).into();
+	let count = pages(addr, max_pages).take_while(|&p| Into::<u8>::into(get_state(p)) == state).count();
+	Ok( count as u32 | (state as u32) << ::values::MEM_QUERY_STATE_SHIFT )
+}
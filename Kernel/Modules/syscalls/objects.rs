@@ -21,6 +21,11 @@ pub trait Object: Send + Sync + ::core::any::Any
 
 	fn try_clone(&self) -> Option<u32>;
 
+	/// Rights (`values::OBJECT_RIGHT_*`) a handle must hold to invoke the given method
+	fn required_rights(&self, _call: u16) -> u32 { 0 }
+	/// Called when a handle to this object has its rights reduced (allows class-specific enforcement)
+	fn restrict(&mut self, _rights: u32) {}
+
 	/// Return: Return value or argument error
 	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,super::Error>;
 	/// NOTE: Implementors should always move out of `self` and drop the contents (the caller will forget)
@@ -42,6 +47,12 @@ impl<T: Object> Object for Box<T> {
 	fn try_clone(&self) -> Option<u32> {
 		(**self).try_clone()
 	}
+	fn required_rights(&self, call: u16) -> u32 {
+		(**self).required_rights(call)
+	}
+	fn restrict(&mut self, rights: u32) {
+		(**self).restrict(rights)
+	}
 	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,super::Error> {
 		(**self).handle_syscall_ref(call, args)
 	}
@@ -61,14 +72,18 @@ impl<T: Object> Object for Box<T> {
 }
 pub type ObjectAlloc = ::stack_dst::ValueA<dyn Object, [usize; 8]>;
 
-struct UserObject
+/// An object handle (object, and the rights the handle grants)
+pub struct UserObject
 {
 	data: ObjectAlloc,
+	/// Rights granted by this handle (`values::OBJECT_RIGHT_*`)
+	rights: u32,
 }
 
 impl UserObject {
 	fn new<T: Object+'static>(v: T) -> Self {
 		UserObject {
+			rights: ::values::OBJECT_RIGHTS_ALL,
 			data: match ::stack_dst::ValueA::new(v)
 				{
 				Ok(v) => v,
@@ -121,12 +136,18 @@ impl ProcessObjects {
 	fn with_object<O, F>(&self, handle: u32, fcn: F) -> Result< O, super::Error >
 	where
 		F: FnOnce(&dyn Object)->Result<O,super::Error> 
+	{
+		self.with_handle(handle, |obj| fcn(&*obj.data))
+	}
+	fn with_handle<O, F>(&self, handle: u32, fcn: F) -> Result< O, super::Error >
+	where
+		F: FnOnce(&UserObject)->Result<O,super::Error> 
 	{
 		if let Some(h) = self.get(handle)
 		{
 			// Call method
 			if let Some(ref obj) = *h.read() {
-				fcn(&*obj.data)
+				fcn(obj)
 			}
 			else {
 				Err( super::Error::NoSuchObject(handle) )
@@ -136,16 +157,25 @@ impl ProcessObjects {
 			Err( super::Error::NoSuchObject(handle) )
 		}
 	}
-	fn with_object_val<O, F>(&self, handle: u32, fcn: F) -> Result<O, super::Error>
+	fn with_object_val<O, F>(&self, handle: u32, call: u16, fcn: F) -> Result<O, super::Error>
 	where
 		F: FnOnce(&mut dyn Object) -> Result<O, super::Error>
 	{
 		if let Some(h) = self.get(handle)
 		{
-			// Call method
+			// Check rights before the object is taken, so a failed check leaves the handle intact
 			// NOTE: Move out of the collection before calling, to allow reusing the slot
-			let v = h.write().take();
+			let v = {
+				let mut lh = h.write();
+				match *lh
+				{
+				Some(ref obj) => try!(check_rights(&*obj.data, obj.rights, call)),
+				None => return Err( super::Error::NoSuchObject(handle) ),
+				}
+				lh.take()
+				};
 			if let Some(mut obj) = v {
+				account_handles(-1);
 				let rv = fcn(&mut *obj.data);
 				::core::mem::forget(obj);
 				rv
@@ -158,7 +188,7 @@ impl ProcessObjects {
 			Err( super::Error::NoSuchObject(handle) )
 		}
 	}
-	fn take_object(&self, handle: u32) -> Result<ObjectAlloc, super::Error>
+	fn take_object(&self, handle: u32) -> Result<UserObject, super::Error>
 	{
 		if let Some(h) = self.get(handle)
 		{
@@ -166,7 +196,8 @@ impl ProcessObjects {
 			if let Some(mut lh) = h.try_write()
 			{
 				if let Some(obj) = lh.take() {
-					Ok( obj.data )
+					account_handles(-1);
+					Ok( obj )
 				}
 				else {
 					Err( super::Error::NoSuchObject(handle) )
@@ -201,6 +232,21 @@ impl ProcessObjects {
 		Err(super::Error::TooManyObjects)
 	}
 
+	/// Reduce the rights of a handle (notifying the object if rights were removed)
+	fn restrict(&self, handle: u32, rights: u32) {
+		if let Some(h) = self.get(handle)
+		{
+			if let Some(ref mut obj) = *h.write()
+			{
+				let new_rights = obj.rights & rights;
+				if new_rights != obj.rights {
+					obj.rights = new_rights;
+					obj.data.restrict(new_rights);
+				}
+			}
+		}
+	}
+
 	fn push_given(&self, handle: u32, tag: &str)
 	{
 		let mut lh = self.given.lock();
@@ -223,11 +269,29 @@ impl Drop for ProcessObjects {
 	}
 }
 
+/// Update the current process's handle count (for resource accounting)
+fn account_handles(delta: isize) {
+	::kernel::threads::with_process_stats(|s| s.adjust_handles(delta));
+}
+
 //pub fn new_object<T: Object+'static>(val: T) -> Result<u32, super::Error>
 pub fn new_object<T: Object+'static>(val: T) -> u32
 {
 	log_debug!("new_object() - size_of {} = {}", type_name!(T), ::core::mem::size_of::<T>());
-	get_process_local::<ProcessObjects>().find_and_fill_slot(|| UserObject::new(val)).unwrap_or(!0)
+	match get_process_local::<ProcessObjects>().find_and_fill_slot(|| UserObject::new(val))
+	{
+	Ok(v) => { account_handles(1); v },
+	Err(_) => !0,
+	}
+}
+/// Create a new object with a restricted set of rights (see `values::OBJECT_RIGHT_*`)
+pub fn new_object_restricted<T: Object+'static>(val: T, rights: u32) -> u32
+{
+	let rv = new_object(val);
+	if rv != !0 {
+		get_process_local::<ProcessObjects>().restrict(rv, rights);
+	}
+	rv
 }
 
 /// Startup: Pushes the specified index as an unclaimed object
@@ -282,20 +346,34 @@ fn get_unclaimed_int(class: u16, tag: &str) -> Result<u32,u32>
 	}
 }
 
+/// Check that a handle has the rights required for a method call
+fn check_rights(obj: &dyn Object, rights: u32, call: u16) -> Result<(),super::Error> {
+	let req = obj.required_rights(call);
+	if rights & req != req {
+		log_notice!("User called method {:#x} on {} without rights ({:#x} has {:#x})", call, obj.type_name(), req, rights);
+		Err( super::Error::PermissionDenied )
+	}
+	else {
+		Ok( () )
+	}
+}
+
 #[inline(never)]
 pub fn call_object_ref(handle: u32, call: u16, args: &mut Args) -> Result<u64,super::Error>
 {
 	// Obtain reference/borrow to object (individually locked), and call the syscall on it
-	get_process_local::<ProcessObjects>().with_object(handle, |obj| {
-		//log_trace!("#{} {} Call Ref {} - args={:?}", handle, obj.type_name(), call, args);
-		obj.handle_syscall_ref(call, args)
+	get_process_local::<ProcessObjects>().with_handle(handle, |obj| {
+		//log_trace!("#{} {} Call Ref {} - args={:?}", handle, obj.data.type_name(), call, args);
+		try!(check_rights(&*obj.data, obj.rights, call));
+		obj.data.handle_syscall_ref(call, args)
 		})
 }
 #[inline(never)]
 pub fn call_object_val(handle: u32, call: u16, args: &mut Args) -> Result<u64,super::Error>
 {
 	// Obtain reference/borrow to object (individually locked), and call the syscall on it
-	get_process_local::<ProcessObjects>().with_object_val(handle, |obj| {
+	// NOTE: The rights check is done before the handle is consumed
+	get_process_local::<ProcessObjects>().with_object_val(handle, call, |obj| {
 		//log_trace!("#{} {} Call Val {} - args={:?}", handle, obj.type_name(), call-0x400, args);
 		obj.handle_syscall_val(call, args)
 		})
@@ -305,15 +383,47 @@ pub fn get_class(handle: u32) -> Result<u64, super::Error>
 {
 	get_process_local::<ProcessObjects>().with_object(handle, |obj| Ok(obj.class() as u64))
 }
-pub fn clone_object(handle: u32) -> Result<u64, super::Error> {
+/// Borrow an object of a known type (e.g. one passed as a method argument)
+///
+/// Passing an object of the wrong type is treated as a bad argument
+pub fn with_object_ref<T, O, F>(handle: u32, fcn: F) -> Result<O, super::Error>
+where
+	T: Object + 'static,
+	F: FnOnce(&T) -> O
+{
 	get_process_local::<ProcessObjects>().with_object(handle, |obj| {
-		match obj.try_clone()
+		match obj.as_any().downcast_ref::<T>()
 		{
-		Some(v) => Ok(v as u64),
-		None => Ok(!0),
+		Some(v) => Ok( fcn(v) ),
+		None => {
+			log_notice!("Object #{} is {}, expected {}", handle, obj.type_name(), type_name!(T));
+			Err( super::Error::BadValue )
+			},
 		}
 		})
 }
+pub fn clone_object(handle: u32) -> Result<u64, super::Error> {
+	derive_object(handle, ::values::OBJECT_RIGHTS_ALL)
+}
+/// Create a new handle to an object, with rights restricted to `rights`
+pub fn derive_object(handle: u32, rights: u32) -> Result<u64, super::Error> {
+	let objs = get_process_local::<ProcessObjects>();
+	let (new_handle, rights) = try!(objs.with_handle(handle, |obj| {
+		if obj.rights & ::values::OBJECT_RIGHT_CLONE == 0 {
+			log_notice!("Attempting to clone non-clonable handle #{} ({})", handle, obj.data.type_name());
+			return Ok( (None, 0) );
+		}
+		Ok( (obj.data.try_clone(), obj.rights & rights) )
+		}));
+	match new_handle
+	{
+	Some(v) => {
+		objs.restrict(v, rights);
+		Ok(v as u64)
+		},
+	None => Ok(!0),
+	}
+}
 
 pub fn wait_on_object(handle: u32, mask: u32, sleeper: &mut ::kernel::threads::SleepObject) -> Result<u32,super::Error> {
 	get_process_local::<ProcessObjects>().with_object(handle, |obj| {
@@ -330,9 +440,10 @@ pub fn clear_wait(handle: u32, mask: u32, sleeper: &mut ::kernel::threads::Sleep
 pub fn give_object(target: &::kernel::threads::ProcessHandle, tag: &str, handle: u32) -> Result<(),super::Error> {
 	log_debug!("give_object(target={:?}, handle={:?})", target, handle);
 	let target_list = target.get_process_local_alloc::<ProcessObjects>();
-	let obj = try!(get_process_local::<ProcessObjects>().take_object(handle));
-	let class_id = obj.class();
-	let id = try!( target_list.find_and_fill_slot(|| UserObject { data: obj }) );
+	let obj = try!(take_object_any(handle));
+	let class_id = obj.data.class();
+	let id = try!( target_list.find_and_fill_slot(|| obj) );
+	target.stats().adjust_handles(1);
 	
 	log_trace!("- Giving object {} ({} {}) as '{}' (handle {})",
 		handle, class_id, ::values::get_class_name(class_id),
@@ -343,8 +454,38 @@ pub fn give_object(target: &::kernel::threads::ProcessHandle, tag: &str, handle:
 	Ok( () )
 }
 
+/// Remove an object from the current process (without knowing its type), e.g. to pass it over IPC
+///
+/// Requires that the handle have the `OBJECT_RIGHT_TRANSFER` right
+pub fn take_object_any(handle: u32) -> Result<UserObject,super::Error> {
+	if handle == 0 {
+		// The "this process" object can't be moved
+		return Err( super::Error::NoSuchObject(handle) );
+	}
+	let objs = get_process_local::<ProcessObjects>();
+	if try!(objs.with_handle(handle, |obj| Ok(obj.rights & ::values::OBJECT_RIGHT_TRANSFER == 0))) {
+		log_notice!("Attempting to transfer non-transferable handle #{}", handle);
+		return Err( super::Error::PermissionDenied );
+	}
+	objs.take_object(handle)
+}
+/// Insert a previously taken object into the current process
+///
+/// On failure, the object is returned to the caller
+pub fn new_object_any(obj: UserObject) -> Result<u32,(super::Error, UserObject)> {
+	let mut obj = Some(obj);
+	match get_process_local::<ProcessObjects>().find_and_fill_slot(|| obj.take().unwrap())
+	{
+	Ok(rv) => {
+		account_handles(1);
+		Ok(rv)
+		},
+	Err(e) => Err( (e, obj.take().unwrap()) ),
+	}
+}
+
 pub fn take_object<T: Object+'static>(handle: u32) -> Result<T,super::Error> {
-	let obj = try!(get_process_local::<ProcessObjects>().take_object(handle));
+	let obj = try!(get_process_local::<ProcessObjects>().take_object(handle)).data;
 	// SAFE: ptr::read is called on a pointer to a value that is subsequently forgotten
 	unsafe {
 		let rv = {
@@ -366,7 +507,7 @@ pub fn drop_object(handle: u32)
 		match get_process_local::<ProcessObjects>().take_object(handle)
 		{
 		Ok(v) => {
-			log_debug!("Object dropped #{}: {}", handle, v.type_name());
+			log_debug!("Object dropped #{}: {}", handle, v.data.type_name());
 			::core::mem::drop( v );
 			},
 		Err(_) => {}
@@ -0,0 +1,58 @@
+// "Tifflin" Kernel
+// - By John Hodge (thePowersGang)
+//
+// Modules/syscalls/storage_calls.rs
+//! Storage management calls
+use values::CryptError;
+
+impl_from! {
+	From<::storage_crypt::Error>(v) for CryptError {
+		match v
+		{
+		::storage_crypt::Error::NotFound => CryptError::NoSuchVolume,
+		::storage_crypt::Error::Locked => CryptError::VolumeLocked,
+		::storage_crypt::Error::BadHeader => CryptError::BadHeader,
+		::storage_crypt::Error::BadPassphrase => CryptError::BadPassphrase,
+		::storage_crypt::Error::BadParameter => CryptError::BadParameter,
+		::storage_crypt::Error::InUse => CryptError::VolumeInUse,
+		::storage_crypt::Error::Io(_) => CryptError::IoError,
+		}
+	}
+}
+
+/// Storage management is only available to init
+// TODO: Use a capability system instead of hardcoding to only PID0
+fn check_permission(call: &str, volume: &str) -> Result<(), CryptError>
+{
+	if ::kernel::threads::get_process_id() != 0 {
+		log_notice!("{}({}) - Denied for PID {}", call, volume, ::kernel::threads::get_process_id());
+		Err(CryptError::PermissionDenied)
+	}
+	else {
+		Ok( () )
+	}
+}
+
+pub fn crypt_unlock(volume: &str, passphrase: &[u8]) -> Result<u32, CryptError>
+{
+	// Unlocking exposes the decrypted contents (and allows guessing passphrases)
+	try!( check_permission("crypt_unlock", volume) );
+	let name = try!( ::storage_crypt::unlock(volume, passphrase) );
+	log_debug!("crypt_unlock: {} unlocked as {}", volume, name);
+	Ok(0)
+}
+
+pub fn crypt_format(volume: &str, passphrase: &[u8], key_material: &[u8]) -> Result<u32, CryptError>
+{
+	// Formatting destroys the volume's contents
+	try!( check_permission("crypt_format", volume) );
+	try!( ::storage_crypt::format(volume, passphrase, key_material) );
+	Ok(0)
+}
+
+pub fn crypt_lock(volume: &str) -> Result<u32, CryptError>
+{
+	try!( check_permission("crypt_lock", volume) );
+	try!( ::storage_crypt::lock(volume) );
+	Ok(0)
+}
@@ -64,6 +64,29 @@ pub fn newprocess(name: &str,  clone_start: usize, clone_end: usize) -> ObjectHa
 	::objects::new_object( ProtoProcess(process) )
 }
 
+/// Fill `info` with the state of the first process with a PID of at least `min_pid`
+///
+/// Returns false if there are no more processes
+#[inline(never)]
+pub fn process_info(min_pid: u32, info: &mut values::ProcessInfo) -> bool
+{
+	match ::kernel::threads::get_process_info(min_pid)
+	{
+	Some(p) => {
+		*info = values::ProcessInfo {
+			pid: p.pid,
+			thread_count: p.thread_count as u32,
+			handle_count: p.handle_count as u32,
+			exited: p.exit_status.is_some() as u32,
+			resident_pages: p.resident_pages as u64,
+			cpu_time_ms: ::kernel::time::ticks_to_ms(p.cpu_ticks),
+			};
+		true
+		},
+	None => false,
+	}
+}
+
 // ret: number of events triggered
 #[inline(never)]
 pub fn wait(events: &mut [values::WaitItem], wake_time_mono: u64) -> Result<u32,Error>
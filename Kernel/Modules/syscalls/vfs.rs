@@ -37,6 +37,9 @@ impl_from! {
 		Error::PermissionDenied => VFSError::PermissionDenied,
 		Error::Locked => VFSError::FileLocked,
 		Error::MalformedPath => VFSError::MalformedPath,
+		Error::AlreadyExists => VFSError::AlreadyExists,
+		Error::OutOfSpace => VFSError::OutOfSpace,
+		Error::ReadOnlyFilesystem => VFSError::PermissionDenied,
 		Error::Unknown(reason) => todo!("VFS Error Unknown - '{}'", reason),
 		_ => todo!("VFS Error - {:?}", v),
 		}
@@ -51,6 +54,32 @@ impl_from! {
 		}
 	}
 
+	From<::kernel::vfs::mount::MountError>(v) for ::values::VFSMountError {{
+		use kernel::vfs::mount::MountError;
+		use values::VFSMountError;
+		map_enums!(
+			(MountError, VFSMountError)
+			match (v) {
+				(UnknownFilesystem),
+				(NoHandler),
+				(InvalidMountpoint),
+				(MountpointUsed),
+				(CallFailed),
+			}
+		)
+	}}
+	From<::kernel::metadevs::storage::VolOpenError>(v) for ::values::VFSMountError {{
+		use kernel::metadevs::storage::VolOpenError;
+		use values::VFSMountError;
+		map_enums!(
+			(VolOpenError, VFSMountError)
+			match (v) {
+				(NotFound => @NoSuchVolume),
+				(Locked => @VolumeLocked),
+			}
+		)
+	}}
+
 	From<::values::VFSFileOpenMode>(v) for handle::FileOpenMode {{
 		use values::VFSFileOpenMode;
 		use kernel::vfs::handle::FileOpenMode;
@@ -68,6 +97,16 @@ impl_from! {
 	}}
 }
 
+/// Create a new VFS object, with the write right removed if it is from a read-only subtree
+fn new_vfs_object<T: objects::Object+'static>(v: T, read_only: bool) -> u32 {
+	if read_only {
+		objects::new_object_restricted(v, values::OBJECT_RIGHTS_ALL & !values::OBJECT_RIGHT_WRITE)
+	}
+	else {
+		objects::new_object(v)
+	}
+}
+
 /// Convert a VFS result into an encoded syscall result
 fn to_result<T>(r: Result<T, ::kernel::vfs::Error>) -> Result<T, u32> {
 	r.map_err( |e| Into::into( <::values::VFSError as From<_>>::from(e) ) )
@@ -78,13 +117,9 @@ pub fn init_handles(loader_handle: ::kernel::vfs::handle::File, init_handle: ::k
 	::core::mem::forget(loader_handle);
 
 	// #1: Initial file handle
-	::objects::new_object( File(init_handle) );
+	new_vfs_object( File { handle: init_handle, read_only: true }, true );
 	// #2: Read-only root
-	::objects::new_object(Dir::new( {
-		let root = handle::Dir::open(Path::new("/")).unwrap();
-		//root.set_permissions( handle::Perms::readonly() );
-		root
-		}));
+	new_vfs_object(Dir::new_ro( handle::Dir::open(Path::new("/")).unwrap() ), true);
 
 	// - Read-write handle to /
 	//::objects::push_as_unclaimed( ::objects::new_object( Dir::new( handle::Dir::open(Path::new("/")).unwrap() ) ) );
@@ -96,20 +131,27 @@ pub fn init_handles(loader_handle: ::kernel::vfs::handle::File, init_handle: ::k
 //
 // --------------------------------------------------------------------
 
-struct Node( handle::Any );
+struct Node {
+	handle: handle::Any,
+	/// Node is within a read-only subtree (can only be opened for reading)
+	read_only: bool,
+}
 impl objects::Object for Node
 {
 	fn class(&self) -> u16 { values::CLASS_VFS_NODE }
 	fn as_any(&self) -> &dyn Any { self }
 	fn try_clone(&self) -> Option<u32> {
-		Some( ::objects::new_object( Node(self.0.clone()) ) )
+		Some( new_vfs_object( Node { handle: self.handle.clone(), read_only: self.read_only }, self.read_only ) )
+	}
+	fn restrict(&mut self, rights: u32) {
+		self.read_only |= rights & values::OBJECT_RIGHT_WRITE == 0;
 	}
 	fn handle_syscall_ref(&self, call: u16, _args: &mut Args) -> Result<u64,Error> {
 		match call
 		{
 		values::VFS_NODE_GETTYPE => {
 			log_debug!("VFS_NODE_GETTYPE()");
-			let v32: u32 = ::values::VFSNodeType::from( self.0.get_class() ).into();
+			let v32: u32 = ::values::VFSNodeType::from( self.handle.get_class() ).into();
 			Ok( v32 as u64 )
 			},
 		_ => ::objects::object_has_no_such_method_ref("vfs::Node", call),
@@ -118,7 +160,8 @@ impl objects::Object for Node
 	fn handle_syscall_val(&mut self, call: u16, args: &mut Args) -> Result<u64,Error> {
 		// SAFE: Raw pointer coerced from &mut, caller forgets us
 		let this = unsafe { ::core::ptr::read(self) };
-		let inner = this.0;
+		let inner = this.handle;
+		let read_only = this.read_only;
 		match call
 		{
 		values::VFS_NODE_TOFILE => {
@@ -130,14 +173,26 @@ impl objects::Object for Node
 				Err(_) => return Err( Error::BadValue ),
 				};
 			log_debug!("VFS_NODE_TOFILE({:?})", mode);
+			if read_only {
+				match mode
+				{
+				::values::VFSFileOpenMode::ReadOnly => {},
+				::values::VFSFileOpenMode::Execute => {},
+				_ => {
+					log_debug!("- Write access denied (read-only subtree)");
+					let e: u32 = ::values::VFSError::PermissionDenied.into();
+					return Ok( super::from_result::<u32,_>(Err(e)) );
+					},
+				}
+			}
 
 			let objres = to_result(inner.to_file(mode.into()))
-				.map( |h| objects::new_object(File(h)) );
+				.map( |h| new_vfs_object(File { handle: h, read_only: read_only }, read_only) );
 			Ok( super::from_result(objres) )
 			},
 		values::VFS_NODE_TODIR => {
 			let objres = to_result(inner.to_dir())
-				.map( |h| objects::new_object(Dir::new(h)) );
+				.map( |h| new_vfs_object(Dir { handle: h, read_only: read_only }, read_only) );
 			Ok( super::from_result(objres) )
 			},
 		values::VFS_NODE_TOLINK => {
@@ -157,25 +212,39 @@ impl objects::Object for Node
 //
 // --------------------------------------------------------------------
 
-struct File(::kernel::vfs::handle::File);
+struct File {
+	handle: ::kernel::vfs::handle::File,
+	/// Handle cannot be used to modify the file
+	read_only: bool,
+}
 impl objects::Object for File
 {
 	fn class(&self) -> u16 { values::CLASS_VFS_FILE }
 	fn as_any(&self) -> &dyn Any { self }
 	fn try_clone(&self) -> Option<u32> {
-		Some( ::objects::new_object( File(self.0.clone()) ) )
+		Some( new_vfs_object( File { handle: self.handle.clone(), read_only: self.read_only }, self.read_only ) )
+	}
+	fn required_rights(&self, call: u16) -> u32 {
+		match call
+		{
+		values::VFS_FILE_WRITEAT => values::OBJECT_RIGHT_WRITE,
+		_ => 0,
+		}
+	}
+	fn restrict(&mut self, rights: u32) {
+		self.read_only |= rights & values::OBJECT_RIGHT_WRITE == 0;
 	}
 	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,Error> {
 		match call
 		{
 		values::VFS_FILE_GETSIZE => {
-			Ok( self.0.size() )
+			Ok( self.handle.size() )
 			},
 		values::VFS_FILE_READAT => {
 			let ofs: u64 = try!(args.get());
 			let mut dest: FreezeMut<[u8]> = try!(args.get());
 			log_debug!("File::readat({}, {:p}+{} bytes)", ofs, dest.as_ptr(), dest.len());
-			match self.0.read(ofs, &mut dest)
+			match self.handle.read(ofs, &mut dest)
 			{
 			Ok(count) => Ok(count as u64),
 			Err(e) => todo!("File::handle_syscall READAT Error {:?}", e),
@@ -185,7 +254,7 @@ impl objects::Object for File
 			let ofs: u64 = try!(args.get());
 			let src: Freeze<[u8]> = try!(args.get());
 			log_debug!("File::writeat({}, {:p}+{} bytes)", ofs, src.as_ptr(), src.len());
-			match self.0.write(ofs, &src)
+			match self.handle.write(ofs, &src)
 			{
 			Ok(count) => Ok(count as u64),
 			Err(e) => todo!("File::handle_syscall WRITEAT Error {:?}", e),
@@ -207,8 +276,12 @@ impl objects::Object for File
 					},
 				};
 			log_debug!("VFS_FILE_MEMMAP({:#x}, {:#x}+{}, {:?})", ofs, addr, size, mode);
+			if self.read_only && is!(mode, ::kernel::vfs::handle::MemoryMapMode::WriteBack) {
+				log_log!("VFS_FILE_MEMMAP - Write-back mapping of read-only handle");
+				return Err( Error::PermissionDenied );
+			}
 			
-			match self.0.memory_map(addr, ofs, size, mode)
+			match self.handle.memory_map(addr, ofs, size, mode)
 			{
 			Ok(h) => {
 				// TODO: I would like the map handle to be avaliable, but I'd like the user to be able to "forget" it
@@ -221,6 +294,11 @@ impl objects::Object for File
 			Err(e) => todo!("File::handle_syscall MEMMAP Error {:?}", e),
 			}
 			},
+		values::VFS_FILE_ATTACHLOOP => {
+			log_debug!("VFS_FILE_ATTACHLOOP()");
+			let l = ::kernel::vfs::loopback::attach(self.handle.clone(), self.read_only);
+			Ok( objects::new_object(Loop(l)) as u64 )
+			},
 		_ => ::objects::object_has_no_such_method_ref("vfs::File", call),
 		}
 	}
@@ -235,11 +313,20 @@ impl objects::Object for File
 
 struct Dir {
 	handle: ::kernel::vfs::handle::Dir,
+	/// Directory is the root of (or within) a read-only subtree
+	read_only: bool,
 }
 impl Dir {
 	fn new(handle: ::kernel::vfs::handle::Dir) -> Dir {
 		Dir {
 			handle: handle,
+			read_only: false,
+		}
+	}
+	fn new_ro(handle: ::kernel::vfs::handle::Dir) -> Dir {
+		Dir {
+			handle: handle,
+			read_only: true,
 		}
 	}
 }
@@ -249,7 +336,18 @@ impl objects::Object for Dir
 	fn class(&self) -> u16 { values::CLASS_VFS_DIR }
 	fn as_any(&self) -> &dyn Any { self }
 	fn try_clone(&self) -> Option<u32> {
-		Some( ::objects::new_object( Dir { handle: self.handle.clone() } ) )
+		Some( new_vfs_object( Dir { handle: self.handle.clone(), read_only: self.read_only }, self.read_only ) )
+	}
+	fn required_rights(&self, call: u16) -> u32 {
+		match call
+		{
+		values::VFS_DIR_MOUNT => values::OBJECT_RIGHT_WRITE,
+		values::VFS_DIR_CREATEFILE => values::OBJECT_RIGHT_WRITE,
+		_ => 0,
+		}
+	}
+	fn restrict(&mut self, rights: u32) {
+		self.read_only |= rights & values::OBJECT_RIGHT_WRITE == 0;
 	}
 	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,Error> {
 		Ok(match call
@@ -262,7 +360,7 @@ impl objects::Object for Dir
 
 			super::from_result(
 				to_result( self.handle.open_child(name) )
-					.map( |h| objects::new_object(Node(h)) )
+					.map( |h| new_vfs_object(Node { handle: h, read_only: self.read_only }, self.read_only) )
 				)
 			},
 		values::VFS_DIR_OPENPATH => {
@@ -272,12 +370,42 @@ impl objects::Object for Dir
 			log_debug!("VFS_DIR_OPENPATH({:?})", path);
 			super::from_result(
 				to_result( self.handle.open_child_path(path) )
-					.map( |h| objects::new_object(Node(h)) )
+					.map( |h| new_vfs_object(Node { handle: h, read_only: self.read_only }, self.read_only) )
 				)
 			},
 		values::VFS_DIR_ENUMERATE => {
 			objects::new_object( DirIter::new( self.handle.clone() ) ) as u64
 			},
+		values::VFS_DIR_MOUNT => {
+			let loop_handle: u32 = try!(args.get());
+			let volume: Freeze<str> = try!(args.get());
+			let fs: Freeze<str> = try!(args.get());
+			log_debug!("VFS_DIR_MOUNT({}, {:?}, {:?})", loop_handle, &*volume, &*fs);
+
+			let vh = try!( objects::with_object_ref(loop_handle, |l: &Loop| l.0.open_volume(&volume)) );
+			let res = match vh
+				{
+				Ok(vh) => self.handle.mount(vh, &fs).map_err(|e| ::values::VFSMountError::from(e)),
+				Err(e) => Err( ::values::VFSMountError::from(e) ),
+				};
+			super::from_result( res.map(|_| 0u32).map_err(|e| Into::<u32>::into(e)) )
+			},
+		values::VFS_DIR_CREATEFILE => {
+			let name: Freeze<[u8]> = try!(args.get());
+			let mode: u8 = try!(args.get());
+
+			let mode = match ::values::VFSFileOpenMode::try_from(mode)
+				{
+				Ok(v) => v,
+				Err(_) => return Err( Error::BadValue ),
+				};
+			let name = ::kernel::lib::byte_str::ByteStr::new(&*name);
+			log_debug!("VFS_DIR_CREATEFILE({:?}, {:?})", name, mode);
+			super::from_result(
+				to_result( self.handle.create_file(name, mode.into()) )
+					.map( |h| new_vfs_object(File { handle: h, read_only: false }, false) )
+				)
+			},
 		_ => return ::objects::object_has_no_such_method_ref("vfs::Dir", call),
 		})
 	}
@@ -431,6 +559,51 @@ impl objects::Object for Link
 	fn clear_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 { 0 }
 }
 
+// --------------------------------------------------------------------
+//
+// --------------------------------------------------------------------
+
+/// Copy a (possibly truncated) name into a user buffer, returning the full length
+fn copy_name(buf: &mut [u8], name: &str) -> u64 {
+	let len = ::core::cmp::min(buf.len(), name.len());
+	buf[..len].clone_from_slice( &name.as_bytes()[..len] );
+	name.len() as u64
+}
+
+struct Loop(::kernel::vfs::loopback::Loop);
+impl objects::Object for Loop
+{
+	fn class(&self) -> u16 { values::CLASS_VFS_LOOP }
+	fn as_any(&self) -> &dyn Any { self }
+	fn try_clone(&self) -> Option<u32> {
+		// Attachments are unique (dropping the handle detaches)
+		None
+	}
+	fn handle_syscall_ref(&self, call: u16, args: &mut Args) -> Result<u64,Error> {
+		match call
+		{
+		values::VFS_LOOP_GETNAME => {
+			let mut buf: FreezeMut<[u8]> = try!(args.get());
+			log_debug!("VFS_LOOP_GETNAME({:p}+{})", buf.as_ptr(), buf.len());
+			Ok( copy_name(&mut buf, self.0.name()) )
+			},
+		values::VFS_LOOP_GETVOLUME => {
+			let idx: usize = try!(args.get());
+			let mut buf: FreezeMut<[u8]> = try!(args.get());
+			log_debug!("VFS_LOOP_GETVOLUME({}, {:p}+{})", idx, buf.as_ptr(), buf.len());
+			match self.0.volumes().get(idx)
+			{
+			Some(name) => Ok( copy_name(&mut buf, name) ),
+			None => Ok( !0 ),
+			}
+			},
+		_ => ::objects::object_has_no_such_method_ref("vfs::Loop", call),
+		}
+	}
+	fn bind_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 { 0 }
+	fn clear_wait(&self, _flags: u32, _obj: &mut ::kernel::threads::SleepObject) -> u32 { 0 }
+}
+
 
 
 // -
@@ -16,14 +16,28 @@ pub enum MatchLevel
 	Precise,	// Matched on VID/DID
 }
 
-pub type Instance = Box<dyn ::core::future::Future<Output=()> + Send + Sync>;
+pub type Instance = Box<dyn ::core::future::Future<Output=()> + Send>;
 
 /// Driver for an interface
 pub trait Driver: Sync
 {
 	fn name(&self) -> &str;
 	fn matches(&self, vendor_id: u16, device_id: u16, class_code: u32) -> MatchLevel;
-	fn start_device(&self, endpoints: Vec<super::Endpoint>, descriptors: &[u8]) -> Instance;
+	/// Start driving an interface
+	///
+	/// `ep0` is the device's default control endpoint (for class requests), and `interface_num` is the
+	/// interface number to use in the index field of interface-directed requests.
+	fn start_device(&self, ep0: super::ControlEndpoint, interface_num: u8, endpoints: Vec<super::Endpoint>, descriptors: &[u8]) -> Instance;
+}
+
+/// Driver instance for an interface that the driver can't use (never completes, so the interface stays claimed)
+pub struct Idle;
+impl ::core::future::Future for Idle
+{
+	type Output = ();
+	fn poll(self: ::core::pin::Pin<&mut Self>, _cx: &mut ::core::task::Context<'_>) -> ::core::task::Poll<()> {
+		::core::task::Poll::Pending
+	}
 }
 
 static S_DRIVERS: Mutex<Vec<&'static dyn Driver>> = Mutex::new(Vec::new_const());
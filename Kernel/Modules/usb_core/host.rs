@@ -1,7 +1,5 @@
 /*
  */
-use kernel::_async3 as kasync;
-
 //use handle::{Handle,RemoteFree};
 
 pub use crate::hub::PortFeature;
@@ -31,6 +29,36 @@ impl ::core::fmt::Debug for EndpointAddr
 	}
 }
 
+/// Bus speed of a device
+#[derive(Debug,Copy,Clone,PartialEq)]
+pub enum Speed
+{
+	Low,
+	Full,
+	High,
+	/// SuperSpeed (USB 3), only reported for xHCI root ports
+	Super,
+}
+/// A port on an external hub
+#[derive(Debug,Copy,Clone)]
+pub struct HubPort
+{
+	/// Address of the hub
+	pub addr: u8,
+	/// Port number on the hub (1-based)
+	pub port: u8,
+}
+/// Information about a newly attached device, needed to communicate with it
+#[derive(Debug,Copy,Clone)]
+pub struct DeviceInfo
+{
+	pub speed: Speed,
+	/// The hub port that the device is attached to (`None` for a root hub port)
+	pub hub: Option<HubPort>,
+	/// Transaction translator used by a low/full-speed device behind a high-speed hub (for split transactions)
+	pub tt: Option<HubPort>,
+}
+
 pub type AsyncWaitIo<'a> = stack_dst::ValueA<dyn core::future::Future<Output=usize> + Send + 'a, [usize; 3]>;
 pub trait InterruptEndpoint: Send + Sync
 {
@@ -43,20 +71,38 @@ pub trait ControlEndpoint: Send + Sync
 	fn out_only<'a>(&'a self, setup_data: &'a [u8], out_data: &'a [u8]) -> AsyncWaitIo<'a>;
 	fn in_only<'a>(&'a self, setup_data: &'a [u8], out_data: &'a mut [u8]) -> AsyncWaitIo<'a>;
 }
+pub trait InterruptOutEndpoint: Send + Sync
+{
+	/// Send data, at the next polling period (returns the number of bytes sent)
+	fn send<'a>(&'a self, buffer: &'a [u8]) -> AsyncWaitIo<'a>;
+}
+/// Isochronous endpoint
+///
+/// Frame numbers are in 1ms frames, and wrap at 2048 (only the low 11 bits are significant to the controller).
+/// Each call covers one or more consecutive service intervals, each taking up to the endpoint's per-interval payload
+/// (packet size multiplied by the high-bandwidth multiplier).
 pub trait IsochEndpoint: Send + Sync
 {
 	/// Returns the current controller frame number (for timing) and the matching system time
 	fn get_current_frame_and_time(&self) -> (u32, ::kernel::time::TickCount);
-	/// Start a send to be sent at the specified frame (relative to controller's arbtiary basis)
-	fn send_at<'a, 's>(&'s self, async_obj: kasync::ObjectHandle, stack: kasync::StackPush<'a, 's>, buffer: kasync::WriteBufferHandle<'a, '_>, abs_frame: u32);
-	/// Prepare a receive to complete in the specified frame.
-	fn recv_at<'a, 's>(&'s self, async_obj: kasync::ObjectHandle, stack: kasync::StackPush<'a, 's>, buffer: &'a mut [u8], abs_frame: u32);
+	/// Send data starting in the specified frame (relative to controller's arbtiary basis), returning the number of bytes sent
+	fn send_at<'a>(&'a self, buffer: &'a [u8], abs_frame: u32) -> AsyncWaitIo<'a>;
+	/// Receive data starting in the specified frame, returning the number of bytes received
+	///
+	/// Interval `n`'s data is placed at offset `n` times the per-interval payload
+	fn recv_at<'a>(&'a self, buffer: &'a mut [u8], abs_frame: u32) -> AsyncWaitIo<'a>;
 }
 pub trait BulkEndpoint: Send + Sync
 {
 	// Start a send operation of the passed buffers
 	fn send<'a>(&'a self, buffer: &'a [u8]) -> AsyncWaitIo<'a>;
 	fn recv<'a>(&'a self, buffer: &'a mut [u8]) -> AsyncWaitIo<'a>;
+	/// Returns true if the most recent transfer ended with a STALL from the device
+	fn is_halted(&self) -> bool;
+	/// Clear the host's halt state and reset the data toggle to DATA0
+	///
+	/// Called once the device's halt has been cleared with `CLEAR_FEATURE(ENDPOINT_HALT)`
+	fn reset(&self);
 }
 
 pub type AsyncWaitRoot = stack_dst::ValueA<dyn core::future::Future<Output=usize>, [usize; 3]>;
@@ -65,13 +111,30 @@ pub trait HostController: Send + Sync
 	///// Obtain a handle to endpoint zero
 	//fn get_control_zero(&self) -> Handle<dyn ControlEndpoint>;
 	/// Begin polling an endpoint at the given rate (buffer used is allocated by the driver to be the interrupt endpoint's size)
-	fn init_interrupt(&self, endpoint: EndpointAddr, max_packet_size: usize, period_ms: usize) -> Handle<dyn InterruptEndpoint>;
-	/// Initialise an ichronous endpoint
-	fn init_isoch(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<dyn IsochEndpoint>;
-	/// Initialise a control endpoint
-	fn init_control(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<dyn ControlEndpoint>;
-	/// Initialise a bulk endpoint
-	fn init_bulk(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<dyn BulkEndpoint>;
+	///
+	/// Returns `None` if the endpoint's resources can't be allocated
+	fn init_interrupt(&self, endpoint: EndpointAddr, max_packet_size: usize, period_ms: usize) -> Option<Handle<dyn InterruptEndpoint>>;
+	/// Initialise an interrupt OUT endpoint (returns `None` if the controller can't support it)
+	fn init_interrupt_out(&self, endpoint: EndpointAddr, max_packet_size: usize, period_ms: usize) -> Option<Handle<dyn InterruptOutEndpoint>>;
+	/// Initialise an isochronous endpoint (returns `None` if the controller can't support it)
+	///
+	/// `max_packet_size` is the raw `wMaxPacketSize`, with the high-bandwidth multiplier in bits 11-12.
+	/// `period` is the endpoint's `bInterval`
+	fn init_isoch(&self, endpoint: EndpointAddr, max_packet_size: usize, period: usize) -> Option<Handle<dyn IsochEndpoint>>;
+	/// Initialise a control endpoint (returns `None` if the endpoint can't be allocated)
+	fn init_control(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Option<Handle<dyn ControlEndpoint>>;
+	/// Initialise a bulk endpoint (returns `None` if the endpoint can't be allocated)
+	fn init_bulk(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Option<Handle<dyn BulkEndpoint>>;
+	/// Describe the device now answering on address zero (called once its port is reset, before `SET_ADDRESS`)
+	///
+	/// The information carries over to the device's new address when `SET_ADDRESS` is sent.
+	fn set_address_zero_info(&self, info: DeviceInfo);
+	/// Describe a hub (called once its descriptor is read, before any downstream devices are addressed)
+	///
+	/// `tt_think_time` is the "TT Think Time" field of `wHubCharacteristics` (in units of 8 full-speed bit times, minus one)
+	fn set_hub_info(&self, addr: u8, num_ports: u8, tt_think_time: u8);
+	/// A device has been removed, release any state held for its address (which may be reused afterwards)
+	fn device_removed(&self, addr: u8);
 
 
 	// Root hub maintainence
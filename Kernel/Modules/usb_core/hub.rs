@@ -1,4 +1,39 @@
+// "Tifflin" Kernel - USB Stack
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_core/hub.rs
+//! USB hub class (external hubs)
+//!
+//! Hubs are handled within usb_core (instead of being a separate driver), as devices attached to a hub are
+//! enumerated using the same `PortDev` logic as root hub ports.
+use kernel::prelude::*;
+use kernel::lib::mem::aref::Aref;
+use crate::{HostRef, HubRef, PortState, ControlEndpoint, InterruptEndpoint, Endpoint};
+use crate::host;
 
+/// Interface class code for hubs
+pub const CLASS_HUB: u8 = 9;
+
+const DESCRIPTOR_TYPE_HUB: u16 = 0x29;
+/// Maximum size of a hub descriptor (7 fixed bytes, and two bitmaps of up to 255 ports + 1)
+const HUB_DESCRIPTOR_MAX: usize = 7 + 2*32;
+
+const REQTYPE_CLASS_DEVICE_IN: u8 = 0xA0;
+const REQTYPE_CLASS_DEVICE_OUT: u8 = 0x20;
+const REQTYPE_CLASS_PORT_IN: u8 = 0xA3;
+const REQTYPE_CLASS_PORT_OUT: u8 = 0x23;
+const REQ_GET_STATUS: u8 = 0;
+const REQ_CLEAR_FEATURE: u8 = 1;
+const REQ_SET_FEATURE: u8 = 3;
+const REQ_GET_DESCRIPTOR: u8 = 6;
+
+// Hub (not port) features
+const FEAT_C_HUB_LOCAL_POWER: u16 = 0;
+const FEAT_C_HUB_OVER_CURRENT: u16 = 1;
+
+/// Port features, as used for the hub class `SET_FEATURE`/`CLEAR_FEATURE` requests
+///
+/// The value is also the bit number in the combined port status/change word returned by `GET_STATUS`
 #[repr(C)]
 #[derive(Debug)]
 pub enum PortFeature
@@ -8,8 +43,9 @@ pub enum PortFeature
 	Suspend,
 	OverCurrent,
 	Reset,
-	Power,
+	Power = 8,
 	LowSpeed,
+	HighSpeed,
 	CConnection = 16,
 	CEnable,
 	CSuspend,
@@ -17,5 +53,276 @@ pub enum PortFeature
 	CReset,
 	Test,
 	Indicator,
+	/// Not a hub feature, used to query if a root port's device is SuperSpeed (always false for external hubs)
+	SuperSpeed,
+}
+
+/// Number of status polls to wait for a port reset to complete
+const RESET_POLL_LIMIT: usize = 50;
+
+/// Parsed hub class descriptor
+#[derive(Debug)]
+struct HubDescriptor
+{
+	num_ports: u8,
+	characteristics: u16,
+	/// Time from power-on until the port is usable
+	power_good_delay_ms: usize,
+}
+impl HubDescriptor
+{
+	fn from_bytes(b: &[u8]) -> Option<HubDescriptor>
+	{
+		if b.len() < 7 || b[1] as u16 != DESCRIPTOR_TYPE_HUB {
+			return None;
+		}
+		Some(HubDescriptor {
+			num_ports: b[2],
+			characteristics: b[3] as u16 | (b[4] as u16) << 8,
+			power_good_delay_ms: b[5] as usize * 2,
+			})
+	}
+	/// "TT Think Time" field of the characteristics (bits 5-6)
+	fn tt_think_time(&self) -> u8 {
+		((self.characteristics >> 5) & 3) as u8
+	}
+}
+
+/// Shared state for an external hub (borrowed by the workers for downstream devices)
+pub(crate) struct HubDevice
+{
+	pub(crate) host: HostRef,
+	/// Address of the hub, and its speed/transaction translator (used for downstream devices)
+	pub(crate) addr: u8,
+	pub(crate) info: host::DeviceInfo,
+	ep0: ControlEndpoint,
+	ports: Vec<PortState>,
+}
+
+impl HubDevice
+{
+	/// Get the combined port status (low 16 bits) and change (high 16 bits) for a port
+	async fn get_port_status(&self, port_idx: usize) -> u32
+	{
+		let mut v = [0; 4];
+		match self.ep0.recv_request(REQTYPE_CLASS_PORT_IN, REQ_GET_STATUS, 0, port_idx as u16 + 1, &mut v).await
+		{
+		4 => v[0] as u32 | (v[1] as u32) << 8 | (v[2] as u32) << 16 | (v[3] as u32) << 24,
+		l => {
+			log_warning!("Hub port {}: GET_STATUS returned {} bytes", port_idx+1, l);
+			0
+			},
+		}
+	}
+
+	pub(crate) async fn set_port_feature(&self, port_idx: usize, feat: PortFeature)
+	{
+		match feat
+		{
+		// Hubs enable a port themselves once reset completes
+		PortFeature::Enable => {},
+		_ => {
+			let feat = feat as u16;
+			if let Err(e) = self.ep0.send_request(REQTYPE_CLASS_PORT_OUT, REQ_SET_FEATURE, feat, port_idx as u16 + 1, &[]).await {
+				log_warning!("Hub port {}: SET_FEATURE({}) failed - {}", port_idx+1, feat, e);
+			}
+			},
+		}
+	}
+	pub(crate) async fn clear_port_feature(&self, port_idx: usize, feat: PortFeature)
+	{
+		match feat
+		{
+		// Reset is ended by the hub, so wait for that instead
+		PortFeature::Reset => {
+			for _ in 0 .. RESET_POLL_LIMIT
+			{
+				if self.get_port_status(port_idx).await & (1 << PortFeature::Reset as u32) == 0 {
+					return ;
+				}
+				kernel::futures::msleep(10).await;
+			}
+			log_warning!("Hub port {}: Reset didn't complete", port_idx+1);
+			},
+		_ => {
+			let feat = feat as u16;
+			if let Err(e) = self.ep0.send_request(REQTYPE_CLASS_PORT_OUT, REQ_CLEAR_FEATURE, feat, port_idx as u16 + 1, &[]).await {
+				log_warning!("Hub port {}: CLEAR_FEATURE({}) failed - {}", port_idx+1, feat, e);
+			}
+			},
+		}
+	}
+	pub(crate) async fn get_port_feature(&self, port_idx: usize, feat: PortFeature) -> bool
+	{
+		match feat
+		{
+		// USB 2 hubs only have low/full/high-speed ports
+		PortFeature::SuperSpeed => false,
+		_ => self.get_port_status(port_idx).await & (1 << feat as u32) != 0,
+		}
+	}
+
+	/// Handle a change in the status of the hub itself
+	async fn handle_hub_event(&self)
+	{
+		let mut v = [0; 4];
+		let _ = self.ep0.recv_request(REQTYPE_CLASS_DEVICE_IN, REQ_GET_STATUS, 0, 0, &mut v).await;
+		let status = v[0] as u16 | (v[1] as u16) << 8;
+		let change = v[2] as u16 | (v[3] as u16) << 8;
+		log_notice!("Hub status change: status={:#x} change={:#x}", status, change);
+		if change & (1 << FEAT_C_HUB_LOCAL_POWER) != 0 {
+			let _ = self.ep0.send_request(REQTYPE_CLASS_DEVICE_OUT, REQ_CLEAR_FEATURE, FEAT_C_HUB_LOCAL_POWER, 0, &[]).await;
+		}
+		if change & (1 << FEAT_C_HUB_OVER_CURRENT) != 0 {
+			let _ = self.ep0.send_request(REQTYPE_CLASS_DEVICE_OUT, REQ_CLEAR_FEATURE, FEAT_C_HUB_OVER_CURRENT, 0, &[]).await;
+		}
+	}
+}
+
+/// Owner of the hub state, removes downstream devices when the hub is removed
+struct Hub
+{
+	dev: Aref<HubDevice>,
+}
+impl ::core::ops::Drop for Hub
+{
+	fn drop(&mut self)
+	{
+		// Downstream workers borrow `dev`, so must be dropped first
+		for p in self.dev.ports.iter()
+		{
+			p.signal_disconnected(&self.dev.host);
+		}
+	}
+}
+
+impl Hub
+{
+	/// Handle a status change on a port (reported via the status change endpoint)
+	async fn handle_port_event(&self, port_idx: usize)
+	{
+		let status = self.dev.get_port_status(port_idx).await;
+		log_debug!("Hub port {}: status={:#x}", port_idx+1, status);
+		// Acknowledge all changes
+		for bit in 16 .. 21
+		{
+			if status & (1 << bit) != 0 {
+				if let Err(e) = self.dev.ep0.send_request(REQTYPE_CLASS_PORT_OUT, REQ_CLEAR_FEATURE, bit, port_idx as u16 + 1, &[]).await {
+					log_warning!("Hub port {}: Unable to acknowledge change {} - {}", port_idx+1, bit, e);
+				}
+			}
+		}
+
+		if status & (1 << PortFeature::COverCurrent as u32) != 0 && status & (1 << PortFeature::OverCurrent as u32) != 0 {
+			log_warning!("Hub port {}: Over-current", port_idx+1);
+		}
+		if status & (1 << PortFeature::CConnection as u32) != 0
+		{
+			let port = &self.dev.ports[port_idx];
+			// Any existing device has gone (even if something is connected again)
+			port.signal_disconnected(&self.dev.host);
+			if status & (1 << PortFeature::Connection as u32) != 0 {
+				port.signal_connected(HubRef::Device(self.dev.borrow()), port_idx as u8);
+			}
+		}
+	}
+}
+
+/// Start the driver for a hub interface
+pub(crate) fn start(host: HostRef, addr: u8, info: host::DeviceInfo, ep0: ControlEndpoint, endpoints: Vec<Endpoint>) -> crate::device::Instance
+{
+	let int_ep = endpoints.into_iter()
+		.filter_map(|ep| match ep { Endpoint::Interrupt(v) => Some(v), _ => None })
+		.next();
+	Box::new(async move {
+		let int_ep = match int_ep
+			{
+			Some(v) => v,
+			None => {
+				log_error!("Hub has no status change endpoint");
+				return ;
+				},
+			};
+		let hub = match init(host, addr, info, ep0).await
+			{
+			Some(v) => v,
+			None => return,
+			};
+		run(hub, int_ep).await
+		})
+}
+
+async fn init(host: HostRef, addr: u8, info: host::DeviceInfo, ep0: ControlEndpoint) -> Option<Hub>
+{
+	let mut desc_buf = [0; HUB_DESCRIPTOR_MAX];
+	let len = ep0.recv_request(REQTYPE_CLASS_DEVICE_IN, REQ_GET_DESCRIPTOR, DESCRIPTOR_TYPE_HUB << 8, 0, &mut desc_buf).await;
+	let desc = match HubDescriptor::from_bytes(&desc_buf[..len])
+		{
+		Some(v) => v,
+		None => {
+			log_error!("Hub descriptor invalid - {:?}", ::kernel::logging::HexDump(&desc_buf[..len]));
+			return None;
+			},
+		};
+	log_log!("Hub: {} ports ({:?})", desc.num_ports, desc);
+	host.driver.set_hub_info(addr, desc.num_ports, desc.tt_think_time());
+
+	let hub = Hub {
+		dev: Aref::new(HubDevice {
+			host: host,
+			addr: addr,
+			info: info,
+			ep0: ep0,
+			ports: {
+				let mut v = Vec::new();
+				v.resize_with(desc.num_ports as usize, || PortState::new());
+				v
+				},
+			}),
+		};
+
+	// Power all ports (a no-op for hubs without per-port switching), then wait for power to be stable
+	for port_idx in 0 .. hub.dev.ports.len()
+	{
+		hub.dev.set_port_feature(port_idx, PortFeature::Power).await;
+	}
+	kernel::futures::msleep(desc.power_good_delay_ms).await;
+
+	// Start devices already connected
+	for port_idx in 0 .. hub.dev.ports.len()
+	{
+		if hub.dev.get_port_feature(port_idx, PortFeature::Connection).await
+		{
+			hub.dev.clear_port_feature(port_idx, PortFeature::CConnection).await;
+			hub.dev.ports[port_idx].signal_connected(HubRef::Device(hub.dev.borrow()), port_idx as u8);
+		}
+	}
+	Some(hub)
 }
 
+async fn run(hub: Hub, int_ep: InterruptEndpoint)
+{
+	loop
+	{
+		let len = int_ep.wait().await;
+		// Status change bitmap: bit 0 is the hub, bit N is port N
+		let mut changes = [0u8; 32];
+		{
+			let data_handle = int_ep.get_data();
+			let data = data_handle.get();
+			let len = ::core::cmp::min(len, ::core::cmp::min(data.len(), changes.len()));
+			changes[..len].copy_from_slice(&data[..len]);
+		}
+
+		if changes[0] & 1 != 0 {
+			hub.dev.handle_hub_event().await;
+		}
+		for port_idx in 0 .. hub.dev.ports.len()
+		{
+			let bit = port_idx + 1;
+			if changes[bit / 8] & (1 << (bit % 8)) != 0 {
+				hub.handle_port_event(port_idx).await;
+			}
+		}
+	}
+}
@@ -5,7 +5,9 @@
 #![feature(try_blocks)]
 use kernel::prelude::*;
 use kernel::lib::mem::aref::{Aref,ArefBorrow};
-use kernel::sync::Mutex;
+use kernel::lib::mem::Arc;
+use kernel::sync::{Mutex,Spinlock};
+use core::sync::atomic::{AtomicBool,AtomicU8,Ordering};
 
 #[macro_use]
 extern crate kernel;
@@ -30,7 +32,7 @@ enum HubRef
 {
 	//Root(ArefBorrow<Host>),
 	Root(HostRef),
-	Device(ArefBorrow<HubDevice>),
+	Device(ArefBorrow<hub::HubDevice>),
 }
 
 #[derive(Clone)]
@@ -47,6 +49,9 @@ impl core::ops::Deref for HostRef {
 	}
 }
 
+/// Highest assignable device address
+const MAX_ADDRESS: u8 = 127;
+
 #[derive(Default)]
 struct AddressPool
 {
@@ -60,8 +65,8 @@ struct Host
 	driver: Box<dyn host::HostController>,
 	addresses: Mutex<AddressPool>,
 
-	//// If true, EP0 is currently being enumerated
-	//endpoint_zero_state: bool,
+	/// Held while a device is being assigned an address (i.e. is using address zero)
+	endpoint_zero_lock: AsyncLock,
 	endpoint_zero_handle: ControlEndpoint,
 	
 	//root_ports: OnceCell<Vec<Port>>,
@@ -69,11 +74,8 @@ struct Host
 	
 	//device_workers: [Mutex<Option<core::pin::Pin<Box<dyn core::future::Future<Output=()> + Send>>>>; 255],
 	device_workers: Vec< Mutex<Option<core::pin::Pin<Box<dyn core::future::Future<Output=()> + Send>>>> >,
-}
-struct HubDevice
-{
-	host: ArefBorrow<Host>,
-	int_ep: host::Handle<dyn host::InterruptEndpoint>,
+	/// Set when a worker is added, so the host worker re-polls the list
+	workers_added: AtomicBool,
 }
 struct HostEnt
 {
@@ -86,14 +88,22 @@ static HOST_LIST: Mutex<Vec<HostEnt>> = ::kernel::sync::Mutex::new(Vec::new_cons
 /// Add a new host controller/bus to the system
 pub fn register_host(mut driver: Box<dyn host::HostController>, nports: u8)
 {
+	// NOTE: 8 bytes is the only packet size all devices support, and SET_ADDRESS has no data stage anyway
+	let endpoint_zero_handle = match driver.init_control(crate::host::EndpointAddr::new(0, 0), 8)
+		{
+		Some(v) => ControlEndpoint::from_handle(v),
+		None => {
+			log_error!("Unable to allocate the address zero endpoint, host not registered");
+			return ;
+			},
+		};
 	let host = Aref::new(Host {
 		addresses: ::kernel::sync::Mutex::new(AddressPool {
 			next_id: 1,
 			used_ids: [0; 128/8],
 			}),
-		endpoint_zero_handle: ControlEndpoint {
-			inner: driver.init_control(crate::host::EndpointAddr::new(0, 0), 64),
-			},
+		endpoint_zero_lock: AsyncLock::new(),
+		endpoint_zero_handle: endpoint_zero_handle,
 		root_ports: {
 			let mut v = Vec::new();
 			v.resize_with(nports as usize, || PortState::new());
@@ -102,9 +112,10 @@ pub fn register_host(mut driver: Box<dyn host::HostController>, nports: u8)
 		//device_workers: Default::default(),
 		device_workers: {
 			let mut v = Vec::new();
-			v.resize_with(255, Default::default);
+			v.resize_with(MAX_ADDRESS as usize + 1, Default::default);
 			v
 			},
+		workers_added: AtomicBool::new(false),
 
 		driver: driver,
 		});
@@ -130,21 +141,28 @@ fn host_worker(host: ArefBorrow<Host>)
 		core::task::Poll::Pending => {},
 		}
 		// Have a list of port workers
-		// TODO: A hub updating might lead t a new entry being added here
-		for p in host.device_workers.iter()
+		// - A hub (or the root task) may add new workers while this runs, so repeat until there are no additions
+		loop
 		{
-			let mut p = p.lock();
-			let done = if let Some(ref mut p) = *p
-				{
-					p.as_mut().poll(context).is_ready()
+			host.workers_added.store(false, Ordering::SeqCst);
+			for p in host.device_workers.iter()
+			{
+				let mut p = p.lock();
+				let done = if let Some(ref mut p) = *p
+					{
+						p.as_mut().poll(context).is_ready()
+					}
+					else
+					{
+						false
+					};
+				if done {
+					log_debug!("Device worker complete");
+					*p = None;
 				}
-				else
-				{
-					false
-				};
-			if done {
-				log_debug!("Device worker complete");
-				*p = None;
+			}
+			if !host.workers_added.load(Ordering::SeqCst) {
+				break;
 			}
 		}
 	});
@@ -156,7 +174,14 @@ impl HubRef
 		match self
 		{
 		&HubRef::Root  (ref h) => h,
-		&HubRef::Device(ref h) => todo!("get host - hub"),
+		&HubRef::Device(ref h) => &h.host,
+		}
+	}
+	fn host_ref(&self) -> HostRef {
+		match self
+		{
+		&HubRef::Root  (ref h) => h.clone(),
+		&HubRef::Device(ref h) => h.host.clone(),
 		}
 	}
 
@@ -188,17 +213,33 @@ impl HubRef
 
 struct PortState
 {
+	/// Address of the device attached to this port (zero if none)
+	addr: AtomicU8,
 }
 impl PortState
 {
 	fn new() -> Self {
 		PortState {
+			addr: AtomicU8::new(0),
 		}
 	}
 
 	fn signal_connected(&self, hub: HubRef, port_idx: u8)
 	{
-		hub.clone().host().add_device(move |addr| PortDev::new(hub, port_idx, addr).worker());
+		// Remove any previous device (if the disconnect wasn't seen)
+		self.signal_disconnected(hub.host());
+		if let Some(addr) = hub.clone().host().add_device(move |addr| PortDev::new(hub, port_idx, addr).worker())
+		{
+			self.addr.store(addr, Ordering::SeqCst);
+		}
+	}
+	/// Remove the device attached to this port (if any)
+	fn signal_disconnected(&self, host: &Host)
+	{
+		let addr = self.addr.swap(0, Ordering::SeqCst);
+		if addr != 0 {
+			host.remove_device(addr);
+		}
 	}
 }
 struct PortDev
@@ -206,6 +247,8 @@ struct PortDev
 	hub: HubRef,
 	port_idx: u8,
 	addr: u8,
+	/// Speed and transaction translator, determined once the port is reset
+	info: Option<host::DeviceInfo>,
 }
 impl PortDev
 {
@@ -214,6 +257,7 @@ impl PortDev
 			hub,
 			port_idx,
 			addr,
+			info: None,
 			}
 	}
 	fn host(&self) -> &Host {
@@ -237,7 +281,7 @@ impl PortDev
 	//	rv
 	//}
 
-	async fn initialise_port(&self, address: u8)
+	async fn initialise_port(&self, address: u8) -> host::DeviceInfo
 	{
 		let addr0_handle = self.host().get_address_zero().await;
 		if ! self.get_port_feature(host::PortFeature::Power).await
@@ -249,7 +293,71 @@ impl PortDev
 		self.clear_port_feature(host::PortFeature::Reset).await;
 		kernel::futures::msleep(2).await;
 		self.set_port_feature(host::PortFeature::Enable).await;
-		addr0_handle.send_setup_address(address).await;
+
+		// The port's speed is valid once the reset is complete
+		let speed = if self.get_port_feature(host::PortFeature::SuperSpeed).await {
+				host::Speed::Super
+			}
+			else if self.get_port_feature(host::PortFeature::LowSpeed).await {
+				host::Speed::Low
+			}
+			else if self.get_port_feature(host::PortFeature::HighSpeed).await {
+				host::Speed::High
+			}
+			else {
+				host::Speed::Full
+			};
+		let info = match self.hub
+			{
+			HubRef::Root(_) => host::DeviceInfo { speed: speed, hub: None, tt: None },
+			HubRef::Device(ref h) => {
+				let port = host::HubPort { addr: h.addr, port: self.port_idx + 1 };
+				// Low/full-speed devices use the nearest high-speed hub's transaction translator
+				let tt = match (speed, h.info.speed)
+					{
+					(host::Speed::High, _) | (host::Speed::Super, _) => None,
+					(_, host::Speed::High) => Some(port),
+					_ => h.info.tt,
+					};
+				host::DeviceInfo { speed: speed, hub: Some(port), tt: tt }
+				},
+			};
+		log_debug!("Device {}: {:?}", address, info);
+		self.host().driver.set_address_zero_info(info);
+		if let Err(e) = addr0_handle.send_setup_address(address).await {
+			// - Reading the device descriptor will fail, ending enumeration
+			log_error!("Device {}: SET_ADDRESS failed - {}", address, e);
+		}
+		info
+	}
+
+	/// Create the device's endpoint zero handle, using the packet size from the device descriptor
+	async fn open_endpoint_zero(&self) -> Result<ControlEndpoint, &'static str>
+	{
+		let speed = self.info.map(|v| v.speed).unwrap_or(host::Speed::Full);
+		// Low-speed devices only support 8 byte packets, so use that to read the start of the device descriptor
+		// (which contains the real packet size)
+		let ep0 = ControlEndpoint::new(self.host(), self.addr, /*ep_num=*/0, /*max_packet_size=*/8)?;
+		let mut buf = [0u8; 8];
+		let len = ep0.read_descriptor_raw(<hw_decls::Descriptor_Device as hw_decls::Descriptor>::TYPE, 0, &mut buf).await?;
+		if len < 8 {
+			return Err("Short device descriptor");
+		}
+		match (speed, buf[7])
+		{
+		// SuperSpeed devices report the packet size as a power of two (always 2^9 = 512)
+		(host::Speed::Super, 9) => {
+			drop(ep0);
+			ControlEndpoint::new(self.host(), self.addr, /*ep_num=*/0, 512)
+			},
+		(host::Speed::Super, _) => Err("Invalid endpoint zero packet size"),
+		(_, 8) => Ok(ep0),
+		(_, mps @ 16) | (_, mps @ 32) | (_, mps @ 64) => {
+			drop(ep0);
+			ControlEndpoint::new(self.host(), self.addr, /*ep_num=*/0, mps as usize)
+			},
+		_ => Err("Invalid endpoint zero packet size"),
+		}
 	}
 
 	async fn enumerate(&self, ep0: ControlEndpoint) -> Result<Vec<Interface>, &'static str>
@@ -291,6 +399,9 @@ impl PortDev
 		ep0.read_descriptor_raw(<hw_decls::Descriptor_Configuration as hw_decls::Descriptor>::TYPE, idx, &mut cfg_buf).await?;
 		let other_descriptors = &cfg_buf[base_cfg.length as usize..];
 
+		// Select the configuration (SET_CONFIGURATION), the device's endpoints aren't usable until this is done
+		ep0.send_request(0x00, 9, base_cfg.configuration_value as u16, 0, &[]).await?;
+
 		// Count the number of interfaces and pre-allocate the return list
 		let n_ints = hw_decls::IterDescriptors(other_descriptors)
 			.filter(|v| is!(v, Ok(hw_decls::DescriptorAny::Interface(..))))
@@ -305,13 +416,16 @@ impl PortDev
 		{
 			if let Ok(hw_decls::DescriptorAny::Interface(v)) = desc
 			{
-				let s = ep0.read_string(v.interface_str).await?;
-				log_debug!("Interface string '{}'", s);
+				match ep0.read_string(v.interface_str).await
+				{
+				Ok(s) => log_debug!("Interface string '{}'", s),
+				Err(e) => log_notice!("Interface {}: Unable to read string - {}", v.interface_num, e),
+				}
 				if let Some( (v,start) ) = last_int.take()
 				{
 					// Note: minus 9 so it excludes the current iteration's interface
 					let endpoint_list = &start[..start.len() - it.0.len() - 9];
-					interfaces.push( self.spawn_interface(&ep0, &v, endpoint_list) );
+					interfaces.push( self.spawn_interface_logged(&ep0, &v, endpoint_list) );
 				}
 				last_int = Some( (v, it.0) );
 			}
@@ -319,12 +433,25 @@ impl PortDev
 		if let Some( (v,start) ) = last_int.take()
 		{
 			let endpoint_list = &start[..start.len() - it.0.len()];
-			interfaces.push( self.spawn_interface(&ep0, &v, endpoint_list) );
+			interfaces.push( self.spawn_interface_logged(&ep0, &v, endpoint_list) );
 		}
 		Ok(interfaces)
 	}
 
-	fn spawn_interface(&self, endpoint_0: &ControlEndpoint, int_desc: &hw_decls::Descriptor_Interface, descriptors: &[u8]) -> Interface
+	/// Start an interface, logging (and ignoring) failures so the device's other interfaces still work
+	fn spawn_interface_logged(&self, endpoint_0: &ControlEndpoint, int_desc: &hw_decls::Descriptor_Interface, descriptors: &[u8]) -> Interface
+	{
+		match self.spawn_interface(endpoint_0, int_desc, descriptors)
+		{
+		Ok(v) => v,
+		Err(e) => {
+			log_error!("Device {} interface {}: {}", self.addr, int_desc.interface_num, e);
+			Interface::Stopped
+			},
+		}
+	}
+
+	fn spawn_interface(&self, endpoint_0: &ControlEndpoint, int_desc: &hw_decls::Descriptor_Interface, descriptors: &[u8]) -> Result<Interface, &'static str>
 	{
 		let full_class
 			= (int_desc.interface_class as u32) << 16
@@ -357,46 +484,66 @@ impl PortDev
 					);
 				endpts.push(match ep_type
 					{
-					0 => Endpoint::Control(ControlEndpoint::new(self.host(), self.addr, ep_num, max_packet_size as usize)),
-					1 => todo!("Isoch endpoint"),//Endpoint::Isoch(IsochEndpoint::new(self.host(), self.addr, ep_num, max_packet_size, ep_dir_in, ...)),
-					2 => Endpoint::Bulk(BulkEndpoint::new(self.host(), self.addr, ep_num, ep_dir_in, max_packet_size as usize)),
+					0 => Endpoint::Control(ControlEndpoint::new(self.host(), self.addr, ep_num, max_packet_size as usize)?),
+					1 => {
+						// Keep the high-bandwidth multiplier (bits 11-12) for isochronous endpoints
+						let raw_mps = (ep_desc.max_packet_size.0 as u16) | (ep_desc.max_packet_size.1 as u16 & 0x1F) << 8;
+						Endpoint::Isoch(IsochEndpoint::new(self.host(), self.addr, ep_num, ep_dir_in, raw_mps as usize, poll_period as usize)?)
+						},
+					2 => Endpoint::Bulk(BulkEndpoint::new(self.host(), self.addr, ep_num, ep_dir_in, max_packet_size as usize)?),
 					3 => if ep_dir_in {
-							Endpoint::Interrupt(InterruptEndpoint::new(self.host(), self.addr, ep_num, max_packet_size as usize, poll_period as usize))
+							Endpoint::Interrupt(InterruptEndpoint::new(self.host(), self.addr, ep_num, max_packet_size as usize, poll_period as usize)?)
 						}
 						else {
-							todo!("Out interrupt endpoint?");
+							Endpoint::InterruptOut(InterruptOutEndpoint::new(self.host(), self.addr, ep_num, max_packet_size as usize, poll_period as usize)?)
 						},
 					_ => unreachable!("endpoint type"),
 					});
 			}
 		}
 
+		// Hubs are handled internally, as they need access to the host
+		if int_desc.interface_class == hub::CLASS_HUB {
+			let info = self.info.ok_or("Hub device not initialised")?;
+			return Ok(Interface::Bound(hub::start(self.hub.host_ref(), self.addr, info, endpoint_0.clone(), endpts).into()));
+		}
+
 		// Locate a suitable driver
 		match crate::device::find_driver(0,0, full_class)
 		{
 		Some(d) => {
-			// Start the device
-			Interface::Bound(d.start_device(endpts, descriptors).into())
+			// Start the device (all interfaces share the device's endpoint zero)
+			Ok(Interface::Bound(d.start_device(endpoint_0.clone(), int_desc.interface_num, endpts, descriptors).into()))
 			},
 		None => {
 			use ::kernel::lib::borrow::ToOwned;;
 			log_notice!("No driver for class={:06x}", full_class);
 			// If a driver can't be found, save the endpoints for later (and the descriptor data)
-			Interface::Unknown(endpts, descriptors.to_owned())
+			Ok(Interface::Unknown(endpts, descriptors.to_owned()))
 			},
 		}
 	}
 
-	async fn worker(self)
+	async fn worker(mut self)
 	{
-		self.initialise_port(self.addr).await;
+		self.info = Some(self.initialise_port(self.addr).await);
 		
-		let ep0 = ControlEndpoint::new(self.host(), self.addr, /*ep_num=*/0, /*max_packet_size=*/64);
+		let ep0 = match self.open_endpoint_zero().await
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_error!("Device {}: Unable to read device descriptor - {}", self.addr, e);
+				return ;
+				},
+			};
 		// Enumerate device
 		let interfaces = match self.enumerate(ep0).await
 			{
 			Ok(v) => v,
-			Err(e) => panic!("{}", e),
+			Err(e) => {
+				log_error!("Device {}: Enumeration failed - {}", self.addr, e);
+				return ;
+				},
 			};
 
 		log_debug!("{} interfaces", interfaces.len());
@@ -416,8 +563,12 @@ impl PortDev
 						match inst.as_mut().poll(cx)
 						{
 						::core::task::Poll::Pending => {},
-						::core::task::Poll::Ready( () ) => todo!("Handle device future completing"),
+						::core::task::Poll::Ready( () ) => {
+							log_debug!("interface {} driver stopped", i);
+							*v = Interface::Stopped;
+							},
 						},
+					Interface::Stopped => {},
 					}
 				}
 				::core::task::Poll::Pending
@@ -434,13 +585,17 @@ enum Interface
 	Unknown(Vec<Endpoint>, Vec<u8>),
 	/// Started driver
 	Bound(::core::pin::Pin<crate::device::Instance>),
+	/// The driver has stopped, or the interface couldn't be started
+	Stopped,
 }
 
 pub enum Endpoint
 {
 	Control(ControlEndpoint),
 	Interrupt(InterruptEndpoint),
+	InterruptOut(InterruptOutEndpoint),
 	Bulk(BulkEndpoint),
+	Isoch(IsochEndpoint),
 }
 
 pub struct InterruptEndpoint
@@ -449,24 +604,149 @@ pub struct InterruptEndpoint
 }
 impl InterruptEndpoint
 {
-	fn new(host: &Host, addr: u8, ep_num: u8, max_packet_size: usize, polling_interval: usize) -> Self {
-		Self {
-			inner: host.driver.init_interrupt(crate::host::EndpointAddr::new(addr, ep_num), max_packet_size, polling_interval),
-			}
+	fn new(host: &Host, addr: u8, ep_num: u8, max_packet_size: usize, polling_interval: usize) -> Result<Self, &'static str> {
+		match host.driver.init_interrupt(crate::host::EndpointAddr::new(addr, ep_num), max_packet_size, polling_interval)
+		{
+		Some(v) => Ok(Self { inner: v }),
+		None => Err("Unable to initialise interrupt endpoint"),
+		}
+	}
+
+	/// Wait for the next poll to complete, returning the number of bytes received
+	pub async fn wait(&self) -> usize
+	{
+		self.inner.wait().await
+	}
+	/// Obtain the data received by the most recent poll
+	pub fn get_data(&self) -> crate::host::Handle<dyn crate::handle::RemoteBuffer>
+	{
+		self.inner.get_data()
+	}
+}
+
+pub struct InterruptOutEndpoint
+{
+	inner: crate::host::Handle<dyn crate::host::InterruptOutEndpoint>,
+}
+impl InterruptOutEndpoint
+{
+	fn new(host: &Host, addr: u8, ep_num: u8, max_packet_size: usize, polling_interval: usize) -> Result<Self, &'static str> {
+		match host.driver.init_interrupt_out(crate::host::EndpointAddr::new(addr, ep_num), max_packet_size, polling_interval)
+		{
+		Some(v) => Ok(Self { inner: v }),
+		None => Err("Host controller doesn't support interrupt OUT endpoints"),
+		}
+	}
+
+	/// Send data (at the next polling period), returning the number of bytes sent
+	pub async fn send(&self, buffer: &[u8]) -> usize
+	{
+		self.inner.send(buffer).await
 	}
 }
 
+pub struct IsochEndpoint
+{
+	inner: crate::host::Handle<dyn crate::host::IsochEndpoint>,
+	ep_num: u8,
+	dir_is_in: bool,
+	/// Bytes per service interval (packet size multiplied by the number of transactions per microframe)
+	interval_bytes: usize,
+}
+impl IsochEndpoint
+{
+	/// `max_packet_size` is the raw `wMaxPacketSize` (including the high-bandwidth multiplier)
+	fn new(host: &Host, addr: u8, ep_num: u8, dir_is_in: bool, max_packet_size: usize, period: usize) -> Result<Self, &'static str> {
+		match host.driver.init_isoch(crate::host::EndpointAddr::new(addr, ep_num), max_packet_size, period)
+		{
+		Some(v) => Ok(Self {
+			inner: v,
+			ep_num: ep_num,
+			dir_is_in: dir_is_in,
+			interval_bytes: (max_packet_size & 0x7FF) * (1 + ((max_packet_size >> 11) & 3)),
+			}),
+		None => Err("Host controller doesn't support isochronous endpoints"),
+		}
+	}
+
+	/// Returns true if this is an IN (device-to-host) endpoint
+	pub fn is_in(&self) -> bool {
+		self.dir_is_in
+	}
+	/// Endpoint address, as used in descriptors and endpoint-directed requests (number, with bit 7 set for IN)
+	pub fn address(&self) -> u8 {
+		self.ep_num | if self.dir_is_in { 0x80 } else { 0 }
+	}
+	/// Largest amount of data that can be sent/received in one service interval
+	///
+	/// For high-bandwidth endpoints this includes all of the interval's transactions
+	pub fn max_packet_size(&self) -> usize {
+		self.interval_bytes
+	}
+
+	/// Get the controller's current frame number, and the system time it was sampled at
+	pub fn get_current_frame_and_time(&self) -> (u32, ::kernel::time::TickCount) {
+		self.inner.get_current_frame_and_time()
+	}
+	/// Send data starting at frame `abs_frame`, returning the number of bytes sent (zero if the frame was missed)
+	///
+	/// Buffers larger than `max_packet_size` are split over consecutive service intervals, if the controller supports it
+	pub async fn send_at(&self, buffer: &[u8], abs_frame: u32) -> usize
+	{
+		assert!( !self.dir_is_in, "IsochEndpoint::send_at on an IN endpoint" );
+		self.inner.send_at(buffer, abs_frame).await
+	}
+	/// Receive data starting at frame `abs_frame`, returning the number of bytes received
+	///
+	/// Buffers larger than `max_packet_size` span consecutive service intervals, with interval `n`'s data at offset
+	/// `n * max_packet_size` (short packets leave gaps)
+	pub async fn recv_at(&self, buffer: &mut [u8], abs_frame: u32) -> usize
+	{
+		assert!( self.dir_is_in, "IsochEndpoint::recv_at on an OUT endpoint" );
+		self.inner.recv_at(buffer, abs_frame).await
+	}
+}
+
+/// Handle to a control endpoint
+///
+/// Clones refer to the same endpoint (e.g. endpoint zero is shared by all of a device's interfaces), and requests
+/// are serialised.
+#[derive(Clone)]
 pub struct ControlEndpoint
 {
-	inner: crate::host::Handle<dyn crate::host::ControlEndpoint>,
+	inner: Arc<ControlEndpointInner>,
+}
+struct ControlEndpointInner
+{
+	handle: crate::host::Handle<dyn crate::host::ControlEndpoint>,
+	/// Held for the duration of a request
+	lock: AsyncLock,
 }
 impl ControlEndpoint
 {
-	fn new(host: &Host, addr: u8, ep_num: u8, max_packet_size: usize) -> ControlEndpoint {
+	fn new(host: &Host, addr: u8, ep_num: u8, max_packet_size: usize) -> Result<ControlEndpoint, &'static str> {
+		match host.driver.init_control(crate::host::EndpointAddr::new(addr, ep_num), max_packet_size)
+		{
+		Some(v) => Ok(Self::from_handle(v)),
+		None => Err("Unable to initialise control endpoint"),
+		}
+	}
+	fn from_handle(handle: crate::host::Handle<dyn crate::host::ControlEndpoint>) -> ControlEndpoint {
 		ControlEndpoint {
-			inner: host.driver.init_control(crate::host::EndpointAddr::new(addr, ep_num), max_packet_size),
+			inner: Arc::new(ControlEndpointInner {
+				handle: handle,
+				lock: AsyncLock::new(),
+				}),
 			}
 	}
+	async fn in_only(&self, setup_data: &[u8], data: &mut [u8]) -> usize {
+		let _lh = self.inner.lock.lock().await;
+		self.inner.handle.in_only(setup_data, data).await
+	}
+	async fn out_only(&self, setup_data: &[u8], data: &[u8]) -> usize {
+		let _lh = self.inner.lock.lock().await;
+		self.inner.handle.out_only(setup_data, data).await
+	}
 	pub async fn read_descriptor_raw(&self, ty: u16, index: u8, buf: &mut [u8]) -> Result<usize,&'static str>
 	{
 		//log_trace!("read_descriptor_raw: (ty={:#x}, index={}, buf={}b)", ty, index, buf.len());
@@ -480,7 +760,7 @@ impl ControlEndpoint
 			length: exp_length as u16,
 			};
 		let hdr = hdr.to_bytes();
-		let res_len = self.inner.in_only(&hdr, buf).await;
+		let res_len = self.in_only(&hdr, buf).await;
 
 		Ok(res_len)
 	}
@@ -514,7 +794,22 @@ impl ControlEndpoint
 		}
 	}
 
-	pub async fn send_request(&self,  request_type: u8, request_num: u8, value: u16, index: u16, data: &[u8])
+	/// Send a request with an IN data stage, returning the number of bytes received
+	pub async fn recv_request(&self, request_type: u8, request_num: u8, value: u16, index: u16, data: &mut [u8]) -> usize
+	{
+		let hdr = hw_decls::DeviceRequest {
+			req_type: request_type,
+			req_num: request_num,
+			value: value,
+			index: index,
+			length: data.len() as u16,
+			};
+		let hdr = hdr.to_bytes();
+		self.in_only(&hdr, data).await
+	}
+
+	/// Send a request with an (optional) OUT data stage, fails if the device didn't accept all of the data
+	pub async fn send_request(&self,  request_type: u8, request_num: u8, value: u16, index: u16, data: &[u8]) -> Result<(), &'static str>
 	{
 		let hdr = hw_decls::DeviceRequest {
 			req_type: request_type,
@@ -524,29 +819,75 @@ impl ControlEndpoint
 			length: data.len() as u16,
 			};
 		let hdr = hdr.to_bytes();
-		let sent_len = self.inner.out_only(&hdr, data).await;
-		assert_eq!(sent_len, data.len());
+		let sent_len = self.out_only(&hdr, data).await;
+		if sent_len != data.len() {
+			log_debug!("send_request({:#x},{}): Sent {} of {} bytes", request_type, request_num, sent_len, data.len());
+			return Err("Short control transfer");
+		}
+		Ok( () )
 	}
 }
 
 pub struct BulkEndpoint
 {
 	inner: crate::host::Handle<dyn crate::host::BulkEndpoint>,
+	ep_num: u8,
 	dir_is_in: bool,
 }
 impl BulkEndpoint
 {
-	fn new(host: &Host, addr: u8, ep_num: u8, dir_is_in: bool, max_packet_size: usize) -> Self {
-		Self {
+	fn new(host: &Host, addr: u8, ep_num: u8, dir_is_in: bool, max_packet_size: usize) -> Result<Self, &'static str> {
+		match host.driver.init_bulk(crate::host::EndpointAddr::new(addr, ep_num), max_packet_size)
+		{
+		Some(v) => Ok(Self {
+			ep_num: ep_num,
 			dir_is_in: dir_is_in,
-			inner: host.driver.init_bulk(crate::host::EndpointAddr::new(addr, ep_num), max_packet_size),
-			}
+			inner: v,
+			}),
+		None => Err("Unable to initialise bulk endpoint"),
+		}
+	}
+
+	/// Returns true if this is an IN (device-to-host) endpoint
+	pub fn is_in(&self) -> bool {
+		self.dir_is_in
+	}
+	/// Endpoint address, as used in descriptors and endpoint-directed requests (number, with bit 7 set for IN)
+	pub fn address(&self) -> u8 {
+		self.ep_num | if self.dir_is_in { 0x80 } else { 0 }
+	}
+
+	/// Send data to an OUT endpoint, returning the number of bytes sent
+	pub async fn send(&self, buffer: &[u8]) -> usize
+	{
+		assert!( !self.dir_is_in, "BulkEndpoint::send on an IN endpoint" );
+		self.inner.send(buffer).await
+	}
+	/// Receive data from an IN endpoint, returning the number of bytes received
+	pub async fn recv(&self, buffer: &mut [u8]) -> usize
+	{
+		assert!( self.dir_is_in, "BulkEndpoint::recv on an OUT endpoint" );
+		self.inner.recv(buffer).await
+	}
+
+	/// Returns true if the last transfer was stalled by the device (and the halt needs clearing)
+	pub fn is_halted(&self) -> bool {
+		self.inner.is_halted()
+	}
+	/// Clear a halt on both the device (using the passed control endpoint) and the host, resetting the data toggle
+	pub async fn clear_halt(&self, ep0: &ControlEndpoint) -> Result<(), &'static str>
+	{
+		// CLEAR_FEATURE(ENDPOINT_HALT) to the endpoint
+		ep0.send_request(0x02, 1, 0, self.address() as u16, &[]).await?;
+		self.inner.reset();
+		Ok( () )
 	}
 }
 
 impl Host
 {
-	fn add_device<F,A>(&self, make_worker: F)
+	/// Allocate an address for a new device, and start its worker (returning the address)
+	fn add_device<F,A>(&self, make_worker: F) -> Option<u8>
 	where
 		F: FnOnce(u8) -> A,
 		A: ::core::future::Future<Output=()> + Send + 'static,
@@ -562,15 +903,33 @@ impl Host
 			let mut lh = self.device_workers[v as usize].lock();
 			assert!( lh.is_none(), "Address already allocated?" );
 			*lh = Some(cb);
+			self.workers_added.store(true, Ordering::SeqCst);
+			Some(v)
+			},
+		None => {
+			log_error!("Out of USB addresses, device ignored");
+			None
 			},
-		None => {},
 		}
 	}
 
-	async fn get_address_zero<'a>(&'a self) -> AddressZeroHandle<'a>
+	/// Stop a device's worker (and drivers), and release its address
+	fn remove_device(&self, addr: u8)
+	{
+		log_notice!("Device {} disconnected", addr);
+		// Take the worker out before dropping it, as a hub's worker removes its downstream devices when dropped
+		let worker = self.device_workers[addr as usize].lock().take();
+		drop(worker);
+		self.driver.device_removed(addr);
+		self.addresses.lock().release(addr);
+	}
+
+	/// Obtain exclusive use of address zero (waiting until no other device is being addressed)
+	async fn get_address_zero(&self) -> AddressZeroHandle<'_>
 	{
 		AddressZeroHandle {
 			host: self,
+			_lock: self.endpoint_zero_lock.lock().await,
 			}
 	}
 
@@ -598,11 +957,8 @@ impl Host
 			}
 			else
 			{
-				// Was disconnected, need to eliminate all downstream devices
-				// - Requires knowing what devices are on this port.
-				// - And need to signal to the devices that they've been disconnected
-				todo!("Handle port disconnection");
-				//self.ports[port_idx].signal_disconnected();
+				// Was disconnected, stop the device (and anything downstream of it)
+				self.root_ports[port_idx].signal_disconnected(self);
 			}
 		}
 		/*
@@ -637,18 +993,63 @@ impl Host
 
 struct AddressZeroHandle<'a> {
 	host: &'a Host,
+	_lock: AsyncLockHandle<'a>,
 }
 impl<'a> AddressZeroHandle<'a>
 {
-	async fn send_setup_address(&self, addr: u8) {
+	async fn send_setup_address(&self, addr: u8) -> Result<(), &'static str> {
 		// Send a request with type=0x00, request=5,  value=addr, index=0, and no data
 		self.host.endpoint_zero_handle.send_request(0x00, 5, addr as u16, 0, &[]).await
 	}
 }
-impl<'a> ::core::ops::Drop for AddressZeroHandle<'a>
+
+/// Asynchronous exclusive lock (for serialising use of a shared endpoint)
+struct AsyncLock
+{
+	/// Set while held, and the tasks waiting for it
+	state: Spinlock<(bool, Vec<core::task::Waker>)>,
+}
+struct AsyncLockHandle<'a>(&'a AsyncLock);
+impl AsyncLock
+{
+	fn new() -> AsyncLock {
+		AsyncLock {
+			state: Spinlock::new( (false, Vec::new()) ),
+			}
+	}
+	fn lock(&self) -> impl core::future::Future<Output=AsyncLockHandle<'_>> + '_
+	{
+		struct Acquire<'a>(&'a AsyncLock);
+		impl<'a> core::future::Future for Acquire<'a>
+		{
+			type Output = AsyncLockHandle<'a>;
+			fn poll(self: core::pin::Pin<&mut Self>, cx: &mut core::task::Context<'_>) -> core::task::Poll<Self::Output> {
+				let mut lh = self.0.state.lock();
+				if lh.0 {
+					lh.1.push(cx.waker().clone());
+					core::task::Poll::Pending
+				}
+				else {
+					lh.0 = true;
+					core::task::Poll::Ready(AsyncLockHandle(self.0))
+				}
+			}
+		}
+		Acquire(self)
+	}
+}
+impl<'a> ::core::ops::Drop for AsyncLockHandle<'a>
 {
 	fn drop(&mut self)
 	{
+		let wakers = {
+			let mut lh = self.0.state.lock();
+			lh.0 = false;
+			::core::mem::replace(&mut lh.1, Vec::new())
+			};
+		for w in wakers {
+			w.wake();
+		}
 	}
 }
 
@@ -656,12 +1057,12 @@ impl AddressPool
 {
 	fn allocate(&mut self) -> Option<u8>
 	{
-		for i in self.next_id ..= 255 {
+		for i in self.next_id ..= MAX_ADDRESS {
 			let byte = &mut self.used_ids[i as usize / 8];
 			let bitmask = 1 << (i%8);
 			if 0 == *byte & bitmask {
 				*byte |= bitmask;
-				self.next_id = i.checked_add(1).unwrap_or(1);
+				self.next_id = if i == MAX_ADDRESS { 1 } else { i + 1 };
 				return Some(i);
 			}
 		}
@@ -671,34 +1072,17 @@ impl AddressPool
 			let bitmask = 1 << (i%8);
 			if 0 == *byte & bitmask {
 				*byte |= bitmask;
-				self.next_id = i.checked_add(1).unwrap_or(1);
+				self.next_id = if i == MAX_ADDRESS { 1 } else { i + 1 };
 				return Some(i);
 			}
 		}
 		// Exhausted
 		None
 	}
-}
-
-impl HubDevice
-{
-	fn handle_int(&self, _size: usize)
+	/// Return an address to the pool
+	fn release(&mut self, addr: u8)
 	{
-		let data_handle = self.int_ep.get_data();
-		let data = data_handle.get();
-		todo!("Process interrupt bytes from host - {:?}", ::kernel::logging::HexDump(data));
-	}
-
-	async fn set_port_feature(&self, port_idx: usize, feat: host::PortFeature) {
-		todo!("HubDevice::set_port_feature({}, {:?})", port_idx, feat)
+		assert!(addr != 0 && addr <= MAX_ADDRESS);
+		self.used_ids[addr as usize / 8] &= !(1 << (addr % 8));
 	}
-	async fn clear_port_feature(&self, port_idx: usize, feat: host::PortFeature) {
-		todo!("HubDevice::clear_port_feature({}, {:?})", port_idx, feat)
-	}
-	async fn get_port_feature(&self, port_idx: usize, feat: host::PortFeature) -> bool {
-		todo!("HubDevice::get_port_feature({}, {:?})", port_idx, feat)
-	}
-
 }
-
-
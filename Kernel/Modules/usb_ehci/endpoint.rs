@@ -0,0 +1,470 @@
+// "Tifflin" Kernel - EHCI USB driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_ehci/endpoint.rs
+//! Endpoint queue heads and transfer descriptor chains
+use kernel::prelude::*;
+use kernel::sync::Spinlock;
+use kernel::memory::virt::AllocHandle;
+use kernel::PAGE_SIZE;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::ptr::{read_volatile, write_volatile};
+use crate::hw;
+
+/// Offset of the first qTD slot (the queue head occupies the start of the page)
+const TD_BASE: usize = 128;
+/// qTD slot size (the 64-bit layout is 52 bytes, and qTDs must be 32-byte aligned)
+const TD_STRIDE: usize = 64;
+/// Offset of the data buffer in pages that have one (interrupt endpoints)
+const DATA_OFS: usize = PAGE_SIZE / 2;
+/// Maximum size of the data buffer
+pub const MAX_DATA_LEN: usize = PAGE_SIZE - DATA_OFS;
+
+/// Data buffer for a transfer stage
+pub enum Data<'a>
+{
+	None,
+	In(&'a mut [u8]),
+	Out(&'a [u8]),
+}
+impl<'a> Data<'a>
+{
+	pub fn len(&self) -> usize {
+		match self
+		{
+		Data::None => 0,
+		Data::In(b) => b.len(),
+		Data::Out(b) => b.len(),
+		}
+	}
+	pub fn is_in(&self) -> bool {
+		match self
+		{
+		Data::In(_) => true,
+		_ => false,
+		}
+	}
+	fn as_ptr(&self) -> *const u8 {
+		match self
+		{
+		Data::None => ::core::ptr::null(),
+		Data::In(b) => b.as_ptr(),
+		Data::Out(b) => b.as_ptr(),
+		}
+	}
+}
+
+/// A transfer buffer prepared for DMA
+///
+/// The qTD buffer pointers are 32-bit (`CTRLDSSEGMENT` is zero), so memory above 4GiB is copied through a bounce
+/// buffer.
+pub struct DmaBuffer<'a>
+{
+	data: Data<'a>,
+	bounce: Option<AllocHandle>,
+}
+impl<'a> DmaBuffer<'a>
+{
+	pub fn new(data: Data<'a>) -> Result<DmaBuffer<'a>, &'static str>
+	{
+		let len = data.len();
+		let needs_bounce = if len == 0 {
+				false
+			}
+			else {
+				let base = data.as_ptr() as usize;
+				let first_page = base & !(PAGE_SIZE - 1);
+				(first_page .. base + len).step_by(PAGE_SIZE)
+					.any(|page| ::kernel::memory::virt::get_phys(::core::cmp::max(page, base) as *const u8) > 0xFFFF_FFFF)
+			};
+		let bounce = if needs_bounce {
+				let mut h = ::kernel::memory::virt::alloc_dma(32, ::kernel::lib::num::div_up(len, PAGE_SIZE), "usb_ehci")?;
+				// Copy in both directions, so the unused tail of an IN buffer is unchanged when copied back
+				// SAFE: The pointer and length come from a valid slice
+				h.as_mut_slice::<u8>(0, len).copy_from_slice(unsafe { ::core::slice::from_raw_parts(data.as_ptr(), len) });
+				Some(h)
+			}
+			else {
+				None
+			};
+		Ok(DmaBuffer {
+			data: data,
+			bounce: bounce,
+			})
+	}
+	pub fn empty() -> DmaBuffer<'static> {
+		DmaBuffer {
+			data: Data::None,
+			bounce: None,
+			}
+	}
+
+	pub fn len(&self) -> usize {
+		self.data.len()
+	}
+	/// Physical address of the byte at `ofs`
+	fn phys(&self, ofs: usize) -> u32 {
+		let p = match self.bounce
+			{
+			Some(ref h) => h.as_ref::<u8>(ofs) as *const u8,
+			None => (self.data.as_ptr() as usize + ofs) as *const u8,
+			};
+		::kernel::memory::virt::get_phys(p) as u32
+	}
+
+	/// Complete the transfer, copying received data out of the bounce buffer
+	pub fn finish(self)
+	{
+		if let (Data::In(buf), Some(h)) = (self.data, self.bounce) {
+			let len = buf.len();
+			buf.copy_from_slice(h.as_slice::<u8>(0, len));
+		}
+	}
+}
+
+/// A stage of a transfer (one or more qTDs with the same PID)
+pub struct Stage<'a>
+{
+	pub pid: u32,
+	/// Initial data toggle (only used by control endpoints)
+	pub toggle: bool,
+	/// Counted in the transfer's returned length (i.e. not a SETUP or status stage)
+	pub is_data: bool,
+	pub buf: DmaBuffer<'a>,
+}
+
+/// Information about a queued qTD, for checking completion
+pub struct TdInfo
+{
+	len: usize,
+	is_data: bool,
+	is_in: bool,
+}
+
+/// An endpoint's queue head, its qTDs, and (optionally) a data buffer, all in one DMA page
+pub struct EndpointPage
+{
+	page: AllocHandle,
+	/// Number of qTD slots (slot 0 is the inactive "stop" qTD that short IN packets branch to)
+	n_tds: usize,
+	max_packet_size: usize,
+	busy: AtomicBool,
+	/// The most recent transfer ended with a STALL handshake
+	stalled: AtomicBool,
+}
+impl EndpointPage
+{
+	pub fn new(characteristics: u32, capabilities: u32, with_buffer: bool) -> Result<EndpointPage, &'static str>
+	{
+		let mut page = crate::alloc_dma_zeroed()?;
+		{
+			let qh = page.as_mut::<hw::QueueHead>(0);
+			qh.link = hw::LINK_TERMINATE;
+			qh.characteristics = characteristics;
+			qh.capabilities = capabilities;
+			qh.overlay.next = hw::LINK_TERMINATE;
+			qh.overlay.alt_next = hw::LINK_TERMINATE;
+		}
+		{
+			let stop = page.as_mut::<hw::TransferDescriptor>(TD_BASE);
+			stop.next = hw::LINK_TERMINATE;
+			stop.alt_next = hw::LINK_TERMINATE;
+		}
+		let end = if with_buffer { DATA_OFS } else { PAGE_SIZE };
+		Ok(EndpointPage {
+			page: page,
+			n_tds: (end - TD_BASE) / TD_STRIDE,
+			max_packet_size: ((characteristics >> hw::QH_MPS_ofs) & 0x7FF) as usize,
+			busy: AtomicBool::new(false),
+			stalled: AtomicBool::new(false),
+			})
+	}
+
+	fn qh(&self) -> *mut hw::QueueHead {
+		// SAFE: Only accessed via raw pointers (volatile), as the controller also writes
+		unsafe { self.page.as_int_mut::<hw::QueueHead>(0) as *mut _ }
+	}
+	fn td(&self, idx: usize) -> *mut hw::TransferDescriptor {
+		assert!(idx < self.n_tds);
+		// SAFE: Only accessed via raw pointers (volatile), as the controller also writes
+		unsafe { self.page.as_int_mut::<hw::TransferDescriptor>(TD_BASE + idx * TD_STRIDE) as *mut _ }
+	}
+	pub fn qh_phys(&self) -> u32 {
+		::kernel::memory::virt::get_phys(self.page.as_ref::<u8>(0)) as u32
+	}
+	fn td_phys(&self, idx: usize) -> u32 {
+		self.qh_phys() + (TD_BASE + idx * TD_STRIDE) as u32
+	}
+
+	/// Virtual address of the queue head (which starts with the horizontal link pointer)
+	pub fn qh_addr(&self) -> usize {
+		self.qh() as usize
+	}
+	/// Horizontal link pointer
+	pub fn link(&self) -> u32 {
+		// SAFE: Volatile read of an aligned field
+		unsafe { read_volatile(&(*self.qh()).link) }
+	}
+	pub fn set_link(&self, v: u32) {
+		// SAFE: Volatile write of an aligned field, schedule changes are serialised by the caller
+		unsafe { write_volatile(&mut (*self.qh()).link, v) }
+	}
+	pub fn max_packet_size(&self) -> usize {
+		self.max_packet_size
+	}
+	/// Replace the static fields of an idle queue head (used when address zero is reused by a new device)
+	pub fn set_characteristics(&self, characteristics: u32, capabilities: u32) {
+		// SAFE: Volatile writes of aligned fields, the queue head has no active qTDs
+		unsafe {
+			write_volatile(&mut (*self.qh()).characteristics, characteristics);
+			write_volatile(&mut (*self.qh()).capabilities, capabilities);
+		}
+	}
+	pub fn dev_addr(&self) -> u8 {
+		// SAFE: Volatile read of an aligned field
+		(unsafe { read_volatile(&(*self.qh()).characteristics) } & 0x7F) as u8
+	}
+
+	/// Received data (for endpoints created `with_buffer`)
+	pub fn data(&self, len: usize) -> &[u8] {
+		self.page.as_slice(DATA_OFS, ::core::cmp::min(len, MAX_DATA_LEN))
+	}
+	/// Mutable view of the data buffer
+	///
+	/// UNSAFE: Caller must ensure that there's only one active user of the buffer (i.e. the endpoint is claimed)
+	pub unsafe fn data_mut(&self, len: usize) -> &mut [u8] {
+		self.page.as_int_mut_slice(DATA_OFS, ::core::cmp::min(len, MAX_DATA_LEN))
+	}
+
+	/// Mark the endpoint as having a transfer in progress (returns None if it already does)
+	pub fn claim(&self) -> Option<BusyGuard> {
+		if self.busy.swap(true, Ordering::SeqCst) {
+			None
+		}
+		else {
+			Some(BusyGuard(&self.busy))
+		}
+	}
+
+	/// Returns true if the most recent transfer was stalled by the device
+	pub fn is_stalled(&self) -> bool {
+		self.stalled.load(Ordering::SeqCst)
+	}
+	pub fn set_stalled(&self, v: bool) {
+		self.stalled.store(v, Ordering::SeqCst)
+	}
+
+	/// Populate the qTD chain for a transfer (the endpoint must be claimed and idle)
+	pub fn fill(&self, stages: &[Stage], control: bool) -> Result<Vec<TdInfo>, &'static str>
+	{
+		let mps = ::core::cmp::max(self.max_packet_size, 1);
+		let mut infos: Vec<TdInfo> = Vec::new();
+		for st in stages
+		{
+			let len = st.buf.len();
+			let mut toggle = st.toggle;
+			let mut ofs = 0;
+			loop
+			{
+				let slot = infos.len() + 1;
+				if slot >= self.n_tds {
+					return Err("Transfer too large");
+				}
+				let page_ofs = if len == 0 { 0 } else { (st.buf.phys(ofs) & 0xFFF) as usize };
+				// A qTD covers five pages, and only the last one can end with a short packet
+				let space = 5 * PAGE_SIZE - page_ofs;
+				let chunk = if len - ofs > space { space - space % mps } else { len - ofs };
+
+				let mut buffers = [0; 5];
+				if chunk > 0 {
+					buffers[0] = st.buf.phys(ofs);
+					for (i, b) in buffers.iter_mut().enumerate().skip(1)
+					{
+						let o = i * PAGE_SIZE - page_ofs;
+						if o >= chunk {
+							break;
+						}
+						*b = st.buf.phys(ofs + o);
+					}
+				}
+				let td = self.td(slot);
+				// SAFE: The qTD isn't linked into the queue yet
+				unsafe {
+					write_volatile(&mut (*td).next, hw::LINK_TERMINATE);
+					write_volatile(&mut (*td).alt_next, hw::LINK_TERMINATE);
+					write_volatile(&mut (*td).buffers, buffers);
+					write_volatile(&mut (*td).buffers_hi, [0; 5]);
+					write_volatile(&mut (*td).token, st.pid
+						| 3 << hw::TOKEN_CERR_ofs
+						| (chunk as u32) << hw::TOKEN_LEN_ofs
+						| if control && toggle { hw::TOKEN_DT } else { 0 }
+						);
+				}
+				infos.push(TdInfo {
+					len: chunk,
+					is_data: st.is_data,
+					is_in: st.pid == hw::TOKEN_PID_IN,
+					});
+
+				if ::kernel::lib::num::div_up(chunk, mps) % 2 == 1 {
+					toggle = !toggle;
+				}
+				ofs += chunk;
+				if ofs >= len {
+					break;
+				}
+			}
+		}
+
+		// Link the chain, and make it active (the last qTD raises an interrupt on completion)
+		let n = infos.len();
+		for (i, info) in infos.iter().enumerate()
+		{
+			let td = self.td(i + 1);
+			let next = if i + 1 < n { self.td_phys(i + 2) } else { hw::LINK_TERMINATE };
+			// A short packet skips the rest of the data (to the status stage, or the stop qTD)
+			let alt_next = match (info.is_data && info.is_in, control)
+				{
+				(false, _) => hw::LINK_TERMINATE,
+				(true, true) => self.td_phys(n),
+				(true, false) => self.td_phys(0),
+				};
+			let flags = hw::TOKEN_ACTIVE | if i + 1 == n { hw::TOKEN_IOC } else { 0 };
+			// SAFE: The qTD isn't linked into the queue yet
+			unsafe {
+				write_volatile(&mut (*td).next, next);
+				write_volatile(&mut (*td).alt_next, alt_next);
+				write_volatile(&mut (*td).token, read_volatile(&(*td).token) | flags);
+			}
+		}
+		Ok(infos)
+	}
+
+	/// Point the queue head at a filled qTD chain
+	pub fn start(&self)
+	{
+		let qh = self.qh();
+		// SAFE: The overlay is inactive (the endpoint is idle), so the controller only reads `next` once it's set
+		unsafe {
+			let toggle = read_volatile(&(*qh).overlay.token) & hw::TOKEN_DT;
+			write_volatile(&mut (*qh).overlay.alt_next, hw::LINK_TERMINATE);
+			write_volatile(&mut (*qh).overlay.token, toggle);
+			::core::sync::atomic::fence(Ordering::SeqCst);
+			write_volatile(&mut (*qh).overlay.next, self.td_phys(1));
+		}
+	}
+
+	/// Check for completion, returning the number of data bytes transferred or the token of a failed qTD
+	pub fn check(&self, tds: &[TdInfo], control: bool) -> Option<Result<usize, u32>>
+	{
+		let mut total = 0;
+		for (i, info) in tds.iter().enumerate()
+		{
+			// SAFE: Volatile read of an aligned field
+			let token = unsafe { read_volatile(&(*self.td(i + 1)).token) };
+			if token & hw::TOKEN_ACTIVE != 0 {
+				return None;
+			}
+			if token & hw::TOKEN_ERROR_MASK != 0 {
+				return Some(Err(token));
+			}
+			let remaining = ((token >> hw::TOKEN_LEN_ofs) & hw::TOKEN_LEN_mask) as usize;
+			if info.is_data {
+				total += info.len - remaining;
+			}
+			if remaining > 0 && info.is_data && info.is_in
+			{
+				// Short packet, the controller moved on to the status stage (control) or stopped
+				if !control {
+					return Some(Ok(total));
+				}
+				// SAFE: Volatile read of an aligned field
+				let token = unsafe { read_volatile(&(*self.td(tds.len())).token) };
+				return if token & hw::TOKEN_ACTIVE != 0 {
+						None
+					}
+					else if token & hw::TOKEN_ERROR_MASK != 0 {
+						Some(Err(token))
+					}
+					else {
+						Some(Ok(total))
+					};
+			}
+		}
+		Some(Ok(total))
+	}
+
+	/// Clear a halted queue head (the data toggle is reset, matching a `CLEAR_FEATURE(ENDPOINT_HALT)`)
+	pub fn reset(&self)
+	{
+		let qh = self.qh();
+		// SAFE: A halted queue head isn't processed by the controller
+		unsafe {
+			write_volatile(&mut (*qh).overlay.next, hw::LINK_TERMINATE);
+			write_volatile(&mut (*qh).overlay.alt_next, hw::LINK_TERMINATE);
+			write_volatile(&mut (*qh).overlay.token, 0);
+		}
+	}
+	/// Abandon a transfer in progress (deactivates the qTDs, so the controller stops at the next qTD)
+	pub fn cancel(&self, n_tds: usize)
+	{
+		for i in 1 ..= n_tds
+		{
+			let td = self.td(i);
+			// SAFE: Clearing the active bit only stops the controller from using the qTD
+			unsafe { write_volatile(&mut (*td).token, read_volatile(&(*td).token) & !hw::TOKEN_ACTIVE); }
+		}
+		let qh = self.qh();
+		// SAFE: As above
+		unsafe {
+			let token = read_volatile(&(*qh).overlay.token);
+			write_volatile(&mut (*qh).overlay.token, token & hw::TOKEN_DT);
+			write_volatile(&mut (*qh).overlay.next, hw::LINK_TERMINATE);
+		}
+	}
+}
+
+/// Releases an endpoint's "busy" flag when dropped
+pub struct BusyGuard<'a>(&'a AtomicBool);
+impl<'a> Drop for BusyGuard<'a>
+{
+	fn drop(&mut self) {
+		self.0.store(false, Ordering::SeqCst);
+	}
+}
+
+/// Future that waits for a transfer to complete (cancelling the transfer if dropped before then)
+pub struct WaitTransfer<'a>
+{
+	pub wakers: &'a Spinlock<Vec<::core::task::Waker>>,
+	pub ep: &'a EndpointPage,
+	pub tds: &'a [TdInfo],
+	pub control: bool,
+	pub complete: bool,
+}
+impl<'a> ::core::future::Future for WaitTransfer<'a>
+{
+	type Output = Result<usize, u32>;
+	fn poll(mut self: ::core::pin::Pin<&mut Self>, cx: &mut ::core::task::Context) -> ::core::task::Poll<Self::Output>
+	{
+		// Register before checking, so a completion between the two isn't missed
+		self.wakers.lock().push(cx.waker().clone());
+		match self.ep.check(self.tds, self.control)
+		{
+		Some(rv) => {
+			self.complete = true;
+			::core::task::Poll::Ready(rv)
+			},
+		None => ::core::task::Poll::Pending,
+		}
+	}
+}
+impl<'a> Drop for WaitTransfer<'a>
+{
+	fn drop(&mut self)
+	{
+		if !self.complete {
+			self.ep.cancel(self.tds.len());
+		}
+	}
+}
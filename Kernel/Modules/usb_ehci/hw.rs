@@ -0,0 +1,141 @@
+// "Tifflin" Kernel - EHCI USB driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_ehci/hw.rs
+//! EHCI hardware definitions (registers, queue heads and transfer descriptors)
+
+// --- Capability registers (offsets from BAR0)
+pub const CAP_CAPLENGTH: usize = 0x00;	// u8 (with HCIVERSION in the upper 16 bits)
+pub const CAP_HCSPARAMS: usize = 0x04;
+pub const CAP_HCCPARAMS: usize = 0x08;
+
+pub const HCSPARAMS_NPORTS_mask: u32 = 0xF;
+/// HCSPARAMS - Port Power Control (ports have power switches)
+pub const HCSPARAMS_PPC: u32 = 1 << 4;
+pub const HCSPARAMS_NCC_ofs: u32 = 12;
+
+/// HCCPARAMS - 64-bit addressing capable (data structures use the 64-bit layout)
+pub const HCCPARAMS_64BIT: u32 = 1 << 0;
+/// HCCPARAMS - Extended capabilities pointer (an offset in PCI config space)
+pub const HCCPARAMS_EECP_ofs: u32 = 8;
+
+// --- Extended capabilities (in PCI config space)
+pub const EECP_LEGACY: u8 = 1;
+pub const LEGSUP_BIOS_OWNED: u32 = 1 << 16;
+pub const LEGSUP_OS_OWNED: u32 = 1 << 24;
+
+// --- Operational registers (offsets from CAPLENGTH)
+pub const OP_USBCMD: usize = 0x00;
+pub const OP_USBSTS: usize = 0x04;
+pub const OP_USBINTR: usize = 0x08;
+pub const OP_CTRLDSSEGMENT: usize = 0x10;
+pub const OP_PERIODICLISTBASE: usize = 0x14;
+pub const OP_ASYNCLISTADDR: usize = 0x18;
+pub const OP_CONFIGFLAG: usize = 0x40;
+pub const OP_PORTSC_BASE: usize = 0x44;
+
+pub const USBCMD_RS: u32 = 1 << 0;
+pub const USBCMD_HCRESET: u32 = 1 << 1;
+pub const USBCMD_PSE: u32 = 1 << 4;
+pub const USBCMD_ASE: u32 = 1 << 5;
+/// USBCMD - Interrupt on Async Advance Doorbell
+pub const USBCMD_IAAD: u32 = 1 << 6;
+/// USBCMD - Interrupt Threshold Control (in microframes)
+pub const USBCMD_ITC_ofs: u32 = 16;
+
+// USBSTS (and USBINTR) bits
+pub const USBSTS_USBINT: u32 = 1 << 0;
+pub const USBSTS_USBERRINT: u32 = 1 << 1;
+pub const USBSTS_PCD: u32 = 1 << 2;
+pub const USBSTS_HSE: u32 = 1 << 4;
+pub const USBSTS_IAA: u32 = 1 << 5;
+pub const USBSTS_HCHALTED: u32 = 1 << 12;
+/// Status bits that are handled by the interrupt handler
+pub const USBSTS_INT_MASK: u32 = USBSTS_USBINT | USBSTS_USBERRINT | USBSTS_PCD | USBSTS_HSE | USBSTS_IAA;
+
+// PORTSC bits
+pub const PORTSC_CCS: u32 = 1 << 0;
+pub const PORTSC_CSC: u32 = 1 << 1;
+pub const PORTSC_PE: u32 = 1 << 2;
+pub const PORTSC_PEC: u32 = 1 << 3;
+pub const PORTSC_OCA: u32 = 1 << 4;
+pub const PORTSC_OCC: u32 = 1 << 5;
+/// PORTSC - Force Port Resume
+pub const PORTSC_FPR: u32 = 1 << 6;
+pub const PORTSC_SUSPEND: u32 = 1 << 7;
+pub const PORTSC_PR: u32 = 1 << 8;
+pub const PORTSC_LS_ofs: u32 = 10;
+pub const PORTSC_PP: u32 = 1 << 12;
+/// PORTSC - Port Owner (set to hand the port to the companion controller)
+pub const PORTSC_PO: u32 = 1 << 13;
+/// Write-1-to-clear bits, masked out when updating other bits
+pub const PORTSC_W1C_MASK: u32 = PORTSC_CSC | PORTSC_PEC | PORTSC_OCC;
+/// Line status value for a low-speed device (K-state)
+pub const LINE_STATUS_K: u32 = 1;
+
+// --- Link pointers (frame list, QH horizontal links and qTD next pointers)
+pub const LINK_TERMINATE: u32 = 1 << 0;
+pub const LINK_TYPE_QH: u32 = 1 << 1;
+
+/// Transfer descriptor (qTD), in the 64-bit layout (a 32-bit controller ignores the upper words)
+#[repr(C)]
+pub struct TransferDescriptor
+{
+	pub next: u32,
+	pub alt_next: u32,
+	pub token: u32,
+	pub buffers: [u32; 5],
+	pub buffers_hi: [u32; 5],
+}
+unsafe impl ::kernel::lib::POD for TransferDescriptor {}
+
+/// Queue head
+#[repr(C)]
+pub struct QueueHead
+{
+	pub link: u32,
+	pub characteristics: u32,
+	pub capabilities: u32,
+	pub current_qtd: u32,
+	/// Transfer overlay (state of the current qTD)
+	pub overlay: TransferDescriptor,
+}
+unsafe impl ::kernel::lib::POD for QueueHead {}
+
+// QH endpoint characteristics
+pub const QH_ENDPT_ofs: u32 = 8;
+pub const QH_EPS_FULL: u32 = 0 << 12;
+pub const QH_EPS_LOW: u32 = 1 << 12;
+pub const QH_EPS_HIGH: u32 = 2 << 12;
+/// Data toggle comes from the qTD (instead of being tracked in the overlay)
+pub const QH_DTC: u32 = 1 << 14;
+/// Head of the reclamation list
+pub const QH_HEAD: u32 = 1 << 15;
+pub const QH_MPS_ofs: u32 = 16;
+/// Control endpoint of a low/full-speed device (split transactions only)
+pub const QH_C: u32 = 1 << 27;
+
+// QH endpoint capabilities
+pub const QH_SMASK_ofs: u32 = 0;
+/// Split completion mask (microframes to issue complete-splits in)
+pub const QH_CMASK_ofs: u32 = 8;
+/// Transaction translator - hub address and port
+pub const QH_HUB_ofs: u32 = 16;
+pub const QH_PORT_ofs: u32 = 23;
+pub const QH_MULT_ofs: u32 = 30;
+
+// qTD token
+pub const TOKEN_ACTIVE: u32 = 1 << 7;
+pub const TOKEN_HALTED: u32 = 1 << 6;
+pub const TOKEN_BUFERR: u32 = 1 << 5;
+pub const TOKEN_BABBLE: u32 = 1 << 4;
+pub const TOKEN_XACTERR: u32 = 1 << 3;
+pub const TOKEN_ERROR_MASK: u32 = TOKEN_HALTED | TOKEN_BUFERR | TOKEN_BABBLE | TOKEN_XACTERR;
+pub const TOKEN_PID_OUT: u32 = 0 << 8;
+pub const TOKEN_PID_IN: u32 = 1 << 8;
+pub const TOKEN_PID_SETUP: u32 = 2 << 8;
+pub const TOKEN_CERR_ofs: u32 = 10;
+pub const TOKEN_IOC: u32 = 1 << 15;
+pub const TOKEN_LEN_ofs: u32 = 16;
+pub const TOKEN_LEN_mask: u32 = 0x7FFF;
+pub const TOKEN_DT: u32 = 1 << 31;
@@ -0,0 +1,877 @@
+// "Tifflin" Kernel - EHCI USB driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_ehci/lib.rs
+//! Enhanced Host Controller Interface (EHCI) driver, for USB 2.0 high-speed devices
+//!
+//! Each endpoint handle owns a queue head (and its qTDs), which is linked into the async schedule (control and
+//! bulk) or the periodic schedule (interrupt) while the handle exists.
+//!
+//! Limitations:
+//! - Full/low-speed devices on root ports are handed to the companion controller (e.g. OHCI), if there is one
+//! - Full/low-speed devices behind high-speed hubs use split transactions (only a single start-split per periodic
+//!   frame, so large full-speed interrupt packets may not fit)
+//! - Isochronous endpoints (iTDs/siTDs) aren't supported, `init_isoch` always fails (such devices need to be on
+//!   an xHCI controller)
+//! - Only one transfer can be in progress on each endpoint handle
+#![no_std]
+#![feature(linkage)]	// for module_define!
+use kernel::prelude::*;
+use kernel::lib::mem::aref::{Aref,ArefBorrow};
+use kernel::sync::Spinlock;
+use kernel::memory::virt::AllocHandle;
+use core::sync::atomic::{AtomicU8,AtomicU32,AtomicUsize,Ordering};
+use crate::endpoint::{EndpointPage, Data, DmaBuffer, Stage};
+use crate::schedule::{Schedule, Position};
+
+#[macro_use]
+extern crate kernel;
+extern crate usb_core;
+
+mod hw;
+mod pci;
+mod endpoint;
+mod schedule;
+
+module_define!{usb_ehci, [usb_core], init}
+
+fn init()
+{
+	static PCI_DRIVER: pci::PciDriver = pci::PciDriver;
+	::kernel::device_manager::register_driver(&PCI_DRIVER);
+}
+
+/// Allocate a 32-bit addressable page of DMA memory, cleared to zero
+fn alloc_dma_zeroed() -> Result<AllocHandle, &'static str>
+{
+	let mut h = ::kernel::memory::virt::alloc_dma(32, 1, "usb_ehci")?;
+	for b in h.as_mut_slice::<u8>(0, ::kernel::PAGE_SIZE) {
+		*b = 0;
+	}
+	Ok(h)
+}
+
+/// Wait (yielding the CPU) until `cond` returns true, returns false on timeout
+fn wait_for<F: FnMut()->bool>(timeout_ms: u64, mut cond: F) -> bool
+{
+	let end = ::kernel::time::ticks() + timeout_ms;
+	while !cond()
+	{
+		if ::kernel::time::ticks() > end {
+			return false;
+		}
+		::kernel::threads::yield_time();
+	}
+	true
+}
+
+/// Box a future (if it doesn't fit inline) into an `AsyncWaitIo`
+macro_rules! make_io {
+	($fut:expr) => {
+		::usb_core::host::AsyncWaitIo::new($fut)
+			.or_else(|v| ::usb_core::host::AsyncWaitIo::new(Box::pin(v)))
+			.ok().expect("Boxed future doesn't fit")
+	};
+}
+
+struct BusDev
+{
+	// Just holds the handle
+	_host: Aref<HostInner>,
+}
+struct UsbHost
+{
+	host: ArefBorrow<HostInner>,
+}
+
+/// Minimum time to hold a port in reset (USB 2.0 TDRSTR)
+const PORT_RESET_TIME_MS: u64 = 50;
+
+struct HostInner
+{
+	regs: Regs,
+	irq_handle: Option<::kernel::irqs::ObjectHandle>,
+	nports: u8,
+	/// Number of companion controllers (for full/low-speed devices)
+	n_companions: u8,
+	/// Ports have power switches
+	port_power_control: bool,
+
+	schedule: Schedule,
+
+	/// Tasks waiting on transfer completion (woken on any completion interrupt)
+	transfer_wakers: Spinlock<Vec<core::task::Waker>>,
+
+	/// Ports that have been handed to the companion controller
+	companion_ports: AtomicU32,
+	/// Ports that have completed a reset (for `CReset`, as EHCI has no reset change bit)
+	reset_change: AtomicU32,
+	/// Port most recently reset (1-based, 0 for none), which address-zero requests go to
+	last_reset_port: AtomicU8,
+	/// Time (in ticks) that the most recent port reset started
+	reset_start: Spinlock<u64>,
+	/// Speed and transaction translator for each address (`None` for a high-speed device on a root port)
+	devices: Spinlock<[Option<host::DeviceInfo>; 128]>,
+
+	// - Async support
+	waker: Spinlock<core::task::Waker>,
+	port_update: AtomicU32,
+}
+
+/// Register access
+struct Regs
+{
+	io: ::kernel::device_manager::IOBinding,
+	/// Offset of the operational registers
+	op: usize,
+}
+impl Regs
+{
+	fn read_cap(&self, ofs: usize) -> u32 {
+		// SAFE: Reads have no side-effects
+		unsafe { self.io.read_32(ofs) }
+	}
+	fn read_op(&self, ofs: usize) -> u32 {
+		// SAFE: Reads have no side-effects
+		unsafe { self.io.read_32(self.op + ofs) }
+	}
+	unsafe fn write_op(&self, ofs: usize, v: u32) {
+		self.io.write_32(self.op + ofs, v);
+	}
+
+	fn read_portsc(&self, port_idx: usize) -> u32 {
+		self.read_op(hw::OP_PORTSC_BASE + port_idx * 4)
+	}
+	fn write_portsc(&self, port_idx: usize, v: u32) {
+		// SAFE: Port registers don't reference memory
+		unsafe { self.write_op(hw::OP_PORTSC_BASE + port_idx * 4, v) }
+	}
+	/// Update a port's register, without acknowledging any change bits
+	fn modify_portsc(&self, port_idx: usize, clear: u32, set: u32) {
+		let v = self.read_portsc(port_idx) & !hw::PORTSC_W1C_MASK;
+		self.write_portsc(port_idx, (v & !clear) | set);
+	}
+
+	/// Request an interrupt once the controller has moved past the current async schedule state
+	fn ring_iaa_doorbell(&self) {
+		// SAFE: Doorbell doesn't reference memory
+		unsafe { self.write_op(hw::OP_USBCMD, self.read_op(hw::OP_USBCMD) | hw::USBCMD_IAAD) }
+	}
+}
+
+impl BusDev
+{
+	fn new_boxed(irq: u32, io: ::kernel::device_manager::IOBinding) -> Result<Box<BusDev>, &'static str>
+	{
+		Ok(Box::new(BusDev {
+			_host: HostInner::new_aref(irq, io)?
+			}))
+	}
+}
+impl HostInner
+{
+	fn new_aref(irq: u32, io: ::kernel::device_manager::IOBinding) -> Result<Aref<HostInner>, &'static str>
+	{
+		// SAFE: Reads have no side-effects
+		let cap0 = unsafe { io.read_32(hw::CAP_CAPLENGTH) };
+		let regs = Regs {
+			io: io,
+			op: (cap0 & 0xFF) as usize,
+			};
+		let hcsparams = regs.read_cap(hw::CAP_HCSPARAMS);
+		let hccparams = regs.read_cap(hw::CAP_HCCPARAMS);
+		let nports = (hcsparams & hw::HCSPARAMS_NPORTS_mask) as u8;
+		let n_companions = ((hcsparams >> hw::HCSPARAMS_NCC_ofs) & 0xF) as u8;
+		log_notice!("Card {:?} version is {:x}.{:02x}: {} ports, {} companion controllers",
+			regs.io, cap0 >> 24, (cap0 >> 16) & 0xFF, nports, n_companions);
+
+		// Halt and reset the controller
+		// SAFE: No memory addresses involved
+		unsafe {
+			regs.write_op(hw::OP_USBCMD, regs.read_op(hw::OP_USBCMD) & !hw::USBCMD_RS);
+		}
+		if !wait_for(100, || regs.read_op(hw::OP_USBSTS) & hw::USBSTS_HCHALTED != 0) {
+			return Err("Controller didn't halt");
+		}
+		// SAFE: No memory addresses involved
+		unsafe {
+			regs.write_op(hw::OP_USBCMD, hw::USBCMD_HCRESET);
+		}
+		if !wait_for(100, || regs.read_op(hw::OP_USBCMD) & hw::USBCMD_HCRESET == 0) {
+			return Err("Controller reset timed out");
+		}
+
+		let schedule = Schedule::new()?;
+		// SAFE: All memory referenced is owned by the returned structure
+		unsafe {
+			// All structures are allocated below 4GiB
+			if hccparams & hw::HCCPARAMS_64BIT != 0 {
+				regs.write_op(hw::OP_CTRLDSSEGMENT, 0);
+			}
+			regs.write_op(hw::OP_PERIODICLISTBASE, schedule.frame_list_phys());
+			regs.write_op(hw::OP_ASYNCLISTADDR, schedule.async_head_phys());
+			regs.write_op(hw::OP_USBINTR, hw::USBSTS_INT_MASK);
+		}
+
+		let mut inner_aref = Aref::new(HostInner {
+			regs: regs,
+			irq_handle: None,	// Filled below, once the allocation is made
+			nports: nports,
+			n_companions: n_companions,
+			port_power_control: hcsparams & hw::HCSPARAMS_PPC != 0,
+
+			schedule: schedule,
+			transfer_wakers: Spinlock::new(Vec::new()),
+
+			companion_ports: AtomicU32::new(0),
+			reset_change: AtomicU32::new(0),
+			last_reset_port: AtomicU8::new(0),
+			reset_start: Spinlock::new(0),
+			devices: Spinlock::new([None; 128]),
+
+			waker: Spinlock::new(kernel::futures::null_waker()),
+			port_update: AtomicU32::new(0),
+			});
+
+		// Bind interrupt
+		{
+			struct RawSend<T: Send>(*const T);
+			unsafe impl<T: Send> Send for RawSend<T> {}
+			let ret_raw = RawSend(&*inner_aref);
+			// SAFE: Pointer _should_ be valid as long as this IRQ binding exists
+			Aref::get_mut(&mut inner_aref).unwrap().irq_handle = Some(::kernel::irqs::bind_object(irq, Box::new(move || unsafe { (*ret_raw.0).handle_irq() } )));
+		}
+
+		// Start the controller (with an interrupt threshold of one frame), and route all ports to it
+		// SAFE: Structures were all initialised above
+		unsafe {
+			inner_aref.regs.write_op(hw::OP_USBCMD, hw::USBCMD_RS | hw::USBCMD_PSE | hw::USBCMD_ASE | 8 << hw::USBCMD_ITC_ofs);
+			inner_aref.regs.write_op(hw::OP_CONFIGFLAG, 1);
+		}
+		if !wait_for(100, || inner_aref.regs.read_op(hw::OP_USBSTS) & hw::USBSTS_HCHALTED == 0) {
+			log_error!("Controller didn't start (USBSTS={:#x})", inner_aref.regs.read_op(hw::OP_USBSTS));
+		}
+
+		// Power ports, and populate `port_update` (could duplicate work from the interrupt, but won't miss anything)
+		if inner_aref.port_power_control
+		{
+			for i in 0 .. nports as usize
+			{
+				inner_aref.regs.modify_portsc(i, 0, hw::PORTSC_PP);
+			}
+			// Wait for power to stabilise (and connections to be detected)
+			wait_for(20, || false);
+		}
+		for i in 0 .. nports as usize
+		{
+			if inner_aref.regs.read_portsc(i) & hw::PORTSC_CCS != 0 {
+				inner_aref.port_update.fetch_or(1 << i, Ordering::SeqCst);
+			}
+		}
+
+		::usb_core::register_host(Box::new(UsbHost { host: inner_aref.borrow() }), nports);
+		Ok(inner_aref)
+	}
+
+	fn handle_irq(&self) -> bool
+	{
+		let sts = self.regs.read_op(hw::OP_USBSTS) & hw::USBSTS_INT_MASK;
+		if sts == 0 {
+			return false;
+		}
+		// SAFE: Acknowledging (write-1-to-clear) bits
+		unsafe {
+			self.regs.write_op(hw::OP_USBSTS, sts);
+		}
+
+		if sts & hw::USBSTS_HSE != 0 {
+			log_error!("Host system error (USBSTS={:#x})", self.regs.read_op(hw::OP_USBSTS));
+		}
+		if sts & (hw::USBSTS_USBINT | hw::USBSTS_USBERRINT) != 0 {
+			// Transfer completion isn't tied to a particular endpoint, so wake everything that's waiting
+			for w in self.transfer_wakers.lock().drain(..) {
+				w.wake();
+			}
+		}
+		if sts & hw::USBSTS_PCD != 0 {
+			let mut updated = 0;
+			for i in 0 .. self.nports as usize
+			{
+				if self.regs.read_portsc(i) & hw::PORTSC_W1C_MASK != 0 {
+					updated |= 1 << i;
+				}
+			}
+			if updated != 0 {
+				self.port_update.fetch_or(updated, Ordering::SeqCst);
+				self.waker.lock().wake_by_ref();
+			}
+		}
+		if sts & hw::USBSTS_IAA != 0 {
+			if self.schedule.handle_iaa() {
+				self.regs.ring_iaa_doorbell();
+			}
+		}
+		true
+	}
+
+	/// Hand a port to the companion controller (for a full/low-speed device)
+	fn release_to_companion(&self, port: usize)
+	{
+		if self.n_companions == 0 {
+			log_warning!("Port {}: Full/low-speed device, but no companion controller to handle it", port+1);
+			return ;
+		}
+		log_log!("Port {}: Full/low-speed device, passing to companion controller", port+1);
+		self.companion_ports.fetch_or(1 << port, Ordering::SeqCst);
+		self.regs.modify_portsc(port, 0, hw::PORTSC_PO);
+	}
+	/// Check if an address-zero request is for a device that's been handed to the companion controller
+	fn is_released_device(&self, addr: u8) -> bool
+	{
+		if addr != 0 {
+			return false;
+		}
+		match self.last_reset_port.load(Ordering::SeqCst)
+		{
+		0 => false,
+		p => self.companion_ports.load(Ordering::SeqCst) & (1 << (p - 1)) != 0,
+		}
+	}
+
+	/// Speed and transaction translator of a device
+	fn device_info(&self, addr: u8) -> host::DeviceInfo
+	{
+		match self.devices.lock()[addr as usize & 0x7F]
+		{
+		Some(v) => v,
+		None => host::DeviceInfo { speed: host::Speed::High, hub: None, tt: None },
+		}
+	}
+
+	/// Run a transfer on an endpoint, returning the number of data bytes transferred
+	async fn run_transfer(&self, ep: &EndpointPage, control: bool, stages: Vec<Stage<'_>>) -> Option<usize>
+	{
+		let _busy = match ep.claim()
+			{
+			Some(v) => v,
+			None => {
+				log_error!("Endpoint of device {} already has a transfer in progress", ep.dev_addr());
+				return None;
+				},
+			};
+		let tds = match ep.fill(&stages, control)
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_error!("Unable to queue transfer for device {}: {}", ep.dev_addr(), e);
+				return None;
+				},
+			};
+		ep.set_stalled(false);
+		ep.start();
+		let rv = endpoint::WaitTransfer {
+			wakers: &self.transfer_wakers,
+			ep: ep,
+			tds: &tds,
+			control: control,
+			complete: false,
+			}.await;
+		match rv
+		{
+		Ok(len) => {
+			for s in stages {
+				s.buf.finish();
+			}
+			Some(len)
+			},
+		Err(token) => {
+			log_warning!("Transfer to device {} failed: token={:#x}", ep.dev_addr(), token);
+			// A halt without any other error bits is a STALL handshake from the device
+			ep.set_stalled(token & hw::TOKEN_ERROR_MASK == hw::TOKEN_HALTED);
+			ep.reset();
+			None
+			},
+		}
+	}
+
+	async fn control(&self, ep: &EndpointPage, setup: &[u8], data: Data<'_>) -> usize
+	{
+		if self.is_released_device(ep.dev_addr()) {
+			log_debug!("Ignoring request to device on port {}, now owned by the companion controller",
+				self.last_reset_port.load(Ordering::SeqCst));
+			return 0;
+		}
+		// Address zero is shared by all new devices, so use the speed/TT of the device being addressed
+		let set_address = if ep.dev_addr() == 0 {
+				let (c, s) = self.qh_fields(&EndpointAddr::new(0, 0), ep.max_packet_size(), true, 0);
+				ep.set_characteristics(c, s);
+				// - SET_ADDRESS carries that information over to the new address
+				if setup.len() >= 8 && setup[0] == 0 && setup[1] == 5 { Some(setup[2] & 0x7F) } else { None }
+			}
+			else {
+				None
+			};
+		let has_data = data.len() > 0;
+		let is_in = data.is_in();
+		let (setup, data) = match (DmaBuffer::new(Data::Out(setup)), DmaBuffer::new(data))
+			{
+			(Ok(s), Ok(d)) => (s, d),
+			(Err(e), _) | (_, Err(e)) => {
+				log_error!("Unable to allocate bounce buffer: {}", e);
+				return 0;
+				},
+			};
+		let mut stages = Vec::with_capacity(3);
+		stages.push(Stage { pid: hw::TOKEN_PID_SETUP, toggle: false, is_data: false, buf: setup });
+		if has_data {
+			stages.push(Stage { pid: if is_in { hw::TOKEN_PID_IN } else { hw::TOKEN_PID_OUT }, toggle: true, is_data: true, buf: data });
+		}
+		// Status stage is in the opposite direction to the data (IN if there's no data)
+		let status_pid = if has_data && is_in { hw::TOKEN_PID_OUT } else { hw::TOKEN_PID_IN };
+		stages.push(Stage { pid: status_pid, toggle: true, is_data: false, buf: DmaBuffer::empty() });
+		let rv = self.run_transfer(ep, true, stages).await;
+		if let (Some(new_addr), Some(_)) = (set_address, rv) {
+			let mut devices = self.devices.lock();
+			devices[new_addr as usize] = devices[0];
+		}
+		rv.unwrap_or(0)
+	}
+	/// Bulk transfer, or a single interrupt OUT transfer (the queue head's schedule determines the timing)
+	async fn bulk(&self, ep: &EndpointPage, data: Data<'_>) -> usize
+	{
+		let pid = if data.is_in() { hw::TOKEN_PID_IN } else { hw::TOKEN_PID_OUT };
+		let buf = match DmaBuffer::new(data)
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_error!("Unable to allocate bounce buffer: {}", e);
+				return 0;
+				},
+			};
+		self.run_transfer(ep, false, vec![Stage { pid: pid, toggle: false, is_data: true, buf: buf }]).await.unwrap_or(0)
+	}
+	/// Run a single poll of an interrupt IN endpoint
+	async fn interrupt_in(&self, state: &InterruptState) -> usize
+	{
+		let ep = state.ep.as_ref().unwrap();
+		// SAFE: The buffer is only written by this transfer (`run_transfer` only allows one at a time)
+		let buf = unsafe { ep.data_mut(state.max_packet_size) };
+		let buf = match DmaBuffer::new(Data::In(buf))
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_error!("Unable to use interrupt buffer: {}", e);
+				return 0;
+				},
+			};
+		let stage = Stage { pid: hw::TOKEN_PID_IN, toggle: false, is_data: true, buf: buf };
+		let len = self.run_transfer(ep, false, vec![stage]).await.unwrap_or(0);
+		state.last_len.store(len, Ordering::SeqCst);
+		len
+	}
+
+	/// Queue head characteristics and capabilities for an endpoint
+	///
+	/// Low/full-speed devices (behind a high-speed hub) are accessed using split transactions to the hub's transaction translator.
+	fn qh_fields(&self, endpoint: &EndpointAddr, max_packet_size: usize, control: bool, smask: u8) -> (u32, u32)
+	{
+		let info = self.device_info(endpoint.dev_addr());
+		let eps = match info.speed
+			{
+			host::Speed::Low  => hw::QH_EPS_LOW,
+			host::Speed::Full => hw::QH_EPS_FULL,
+			host::Speed::High | host::Speed::Super => hw::QH_EPS_HIGH,
+			};
+		let characteristics = endpoint.dev_addr() as u32
+			| (endpoint.endpt() as u32) << hw::QH_ENDPT_ofs
+			| eps
+			| if control { hw::QH_DTC } else { 0 }
+			| if control && info.speed != host::Speed::High { hw::QH_C } else { 0 }
+			| (max_packet_size as u32 & 0x7FF) << hw::QH_MPS_ofs
+			;
+		let mut capabilities = (smask as u32) << hw::QH_SMASK_ofs | 1 << hw::QH_MULT_ofs;
+		if let Some(tt) = info.tt {
+			capabilities |= (tt.addr as u32) << hw::QH_HUB_ofs | (tt.port as u32) << hw::QH_PORT_ofs;
+			// Periodic start-splits are in microframe 0, so complete-splits are in microframes 2 to 4
+			if smask != 0 {
+				capabilities |= 0x1C << hw::QH_CMASK_ofs;
+			}
+		}
+		(characteristics, capabilities)
+	}
+	/// Convert an interrupt endpoint's `bInterval` into a S-mask and a period in frames (depends on the device's speed)
+	fn periodic_schedule(&self, endpoint: &EndpointAddr, b_interval: usize) -> (u8, usize)
+	{
+		match self.device_info(endpoint.dev_addr()).speed
+		{
+		// Low/full-speed intervals are in frames, with a single start-split per period
+		host::Speed::Low | host::Speed::Full => (0x01, ::core::cmp::max(b_interval, 1)),
+		host::Speed::High | host::Speed::Super => interrupt_schedule(b_interval),
+		}
+	}
+
+	/// Create a queue head for an endpoint
+	fn make_endpoint(&self, endpoint: EndpointAddr, max_packet_size: usize, control: bool, smask: u8, with_buffer: bool) -> Option<Box<EndpointPage>>
+	{
+		let (characteristics, capabilities) = self.qh_fields(&endpoint, max_packet_size, control, smask);
+		match EndpointPage::new(characteristics, capabilities, with_buffer)
+		{
+		Ok(v) => Some(Box::new(v)),
+		Err(e) => {
+			log_error!("Unable to allocate queue head for {:?}: {}", endpoint, e);
+			None
+			},
+		}
+	}
+}
+
+/// Convert a high-speed interrupt endpoint's `bInterval` (2^(n-1) microframes) into a S-mask and a period in frames
+fn interrupt_schedule(b_interval: usize) -> (u8, usize)
+{
+	let microframes = 1 << (::core::cmp::min(::core::cmp::max(b_interval, 1), 16) - 1);
+	match microframes
+	{
+	1 => (0xFF, 1),
+	2 => (0x55, 1),
+	4 => (0x11, 1),
+	_ => (0x01, microframes / 8),
+	}
+}
+
+use ::usb_core::host::{self, EndpointAddr, PortFeature, Handle};
+use ::usb_core::host::{InterruptEndpoint, InterruptOutEndpoint, IsochEndpoint, ControlEndpoint, BulkEndpoint};
+impl ::usb_core::host::HostController for UsbHost
+{
+	fn init_interrupt(&self, endpoint: EndpointAddr, max_packet_size: usize, period_ms: usize) -> Option<Handle<dyn InterruptEndpoint>> {
+		let max_packet_size = ::core::cmp::min(max_packet_size, endpoint::MAX_DATA_LEN);
+		let (smask, period) = self.host.periodic_schedule(&endpoint, period_ms);
+		let ep = self.host.make_endpoint(endpoint, max_packet_size, false, smask, true)?;
+		let anchor = self.host.schedule.add_periodic(&ep, period);
+		Some(Handle::new(InterruptEndpointHandle {
+			host: self.host.reborrow(),
+			state: Box::new(InterruptState {
+				ep: Some(ep),
+				anchor: anchor,
+				max_packet_size: max_packet_size,
+				last_len: AtomicUsize::new(0),
+				}),
+			}).ok().unwrap())
+	}
+	fn init_interrupt_out(&self, endpoint: EndpointAddr, max_packet_size: usize, period_ms: usize) -> Option<Handle<dyn InterruptOutEndpoint>> {
+		let (smask, period) = self.host.periodic_schedule(&endpoint, period_ms);
+		let ep = self.host.make_endpoint(endpoint, max_packet_size, false, smask, false)?;
+		let anchor = self.host.schedule.add_periodic(&ep, period);
+		Some(Handle::new(InterruptOutEndpointHandle {
+			host: self.host.reborrow(),
+			ep: Some(ep),
+			anchor: anchor,
+			}).ok().unwrap())
+	}
+	fn init_isoch(&self, endpoint: EndpointAddr, max_packet_size: usize, period: usize) -> Option<Handle<dyn IsochEndpoint>> {
+		// Isochronous transfers need iTDs (or siTDs for split transactions) in the periodic frame list, which aren't implemented
+		log_notice!("init_isoch({:?}, max_packet_size={}, period={}): Isochronous endpoints aren't supported by this driver",
+			endpoint, max_packet_size, period);
+		None
+	}
+	fn init_control(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Option<Handle<dyn ControlEndpoint>> {
+		let ep = self.host.make_endpoint(endpoint, max_packet_size, true, 0, false)?;
+		self.host.schedule.add_async(&ep);
+		Some(Handle::new(ControlEndpointHandle {
+			host: self.host.reborrow(),
+			ep: Some(ep),
+			}).ok().unwrap())
+	}
+	fn init_bulk(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Option<Handle<dyn BulkEndpoint>> {
+		let ep = self.host.make_endpoint(endpoint, max_packet_size, false, 0, false)?;
+		self.host.schedule.add_async(&ep);
+		Some(Handle::new(BulkEndpointHandle {
+			host: self.host.reborrow(),
+			ep: Some(ep),
+			}).ok().unwrap())
+	}
+	fn set_address_zero_info(&self, info: host::DeviceInfo) {
+		// Devices behind a hub aren't on the most recently reset root port
+		if info.hub.is_some() {
+			self.host.last_reset_port.store(0, Ordering::SeqCst);
+		}
+		self.host.devices.lock()[0] = Some(info);
+	}
+	fn set_hub_info(&self, _addr: u8, _num_ports: u8, _tt_think_time: u8) {
+		// Split transactions are addressed using the hub/port from `DeviceInfo`, nothing else is needed
+	}
+	fn device_removed(&self, addr: u8) {
+		self.host.devices.lock()[addr as usize & 0x7F] = None;
+	}
+
+
+	// Root hub maintainence
+	fn set_port_feature(&self, port: usize, feature: PortFeature) {
+		log_trace!("set_port_feature({}, {:?})", port, feature);
+		let regs = &self.host.regs;
+		match feature
+		{
+		// Ports are enabled by the controller once reset completes
+		PortFeature::Enable => {},
+		PortFeature::Suspend => regs.modify_portsc(port, 0, hw::PORTSC_SUSPEND),
+		PortFeature::Reset => {
+			// Requests to address zero now go to the device on this port
+			self.host.last_reset_port.store(port as u8 + 1, Ordering::SeqCst);
+			// A low-speed device is visible before reset (the line is in the K-state)
+			if (regs.read_portsc(port) >> hw::PORTSC_LS_ofs) & 3 == hw::LINE_STATUS_K {
+				self.host.release_to_companion(port);
+				return ;
+			}
+			*self.host.reset_start.lock() = ::kernel::time::ticks();
+			regs.modify_portsc(port, hw::PORTSC_PE, hw::PORTSC_PR);
+			},
+		PortFeature::Power => regs.modify_portsc(port, 0, hw::PORTSC_PP),
+		_ => {},
+		}
+	}
+	fn clear_port_feature(&self, port: usize, feature: PortFeature) {
+		log_trace!("clear_port_feature({}, {:?})", port, feature);
+		let regs = &self.host.regs;
+		let bit = 1 << port;
+		match feature
+		{
+		PortFeature::Enable  => regs.modify_portsc(port, hw::PORTSC_PE, 0),
+		PortFeature::Suspend => regs.modify_portsc(port, 0, hw::PORTSC_FPR),
+		PortFeature::Power   => regs.modify_portsc(port, hw::PORTSC_PP, 0),
+		PortFeature::Reset   => {
+			if self.host.companion_ports.load(Ordering::SeqCst) & bit != 0 {
+				return ;
+			}
+			// Software ends the reset, so ensure that it was held for long enough
+			let end = *self.host.reset_start.lock() + PORT_RESET_TIME_MS;
+			wait_for(PORT_RESET_TIME_MS, || ::kernel::time::ticks() >= end);
+			regs.modify_portsc(port, hw::PORTSC_PR, 0);
+			if !wait_for(2, || regs.read_portsc(port) & hw::PORTSC_PR == 0) {
+				log_warning!("Port {}: Reset didn't complete", port+1);
+			}
+			// The port is only enabled if a high-speed device completed the chirp handshake
+			if regs.read_portsc(port) & hw::PORTSC_PE == 0 {
+				self.host.release_to_companion(port);
+			}
+			self.host.reset_change.fetch_or(bit, Ordering::SeqCst);
+			},
+		PortFeature::CConnection => {
+			let v = regs.read_portsc(port);
+			regs.write_portsc(port, (v & !hw::PORTSC_W1C_MASK) | hw::PORTSC_CSC);
+			// Ownership returns to this controller when the device is removed
+			if v & hw::PORTSC_PO == 0 {
+				self.host.companion_ports.fetch_and(!bit, Ordering::SeqCst);
+			}
+			},
+		PortFeature::CEnable     => regs.modify_portsc(port, 0, hw::PORTSC_PEC),
+		PortFeature::COverCurrent=> regs.modify_portsc(port, 0, hw::PORTSC_OCC),
+		PortFeature::CReset      => { self.host.reset_change.fetch_and(!bit, Ordering::SeqCst); },
+		_ => {},
+		}
+	}
+	fn get_port_feature(&self, port: usize, feature: PortFeature) -> bool {
+		log_trace!("get_port_feature({}, {:?})", port, feature);
+		let v = self.host.regs.read_portsc(port);
+		let mask = match feature
+			{
+			PortFeature::Connection  => hw::PORTSC_CCS,
+			PortFeature::Enable      => hw::PORTSC_PE,
+			PortFeature::Suspend     => hw::PORTSC_SUSPEND,
+			PortFeature::OverCurrent => hw::PORTSC_OCA,
+			PortFeature::Reset       => hw::PORTSC_PR,
+			// Without port power control, ports are always powered
+			PortFeature::Power       => if self.host.port_power_control { hw::PORTSC_PP } else { return true },
+			// Line status is only valid before the port is enabled, and enabled ports are high-speed
+			PortFeature::LowSpeed    => return v & hw::PORTSC_PE == 0 && (v >> hw::PORTSC_LS_ofs) & 3 == hw::LINE_STATUS_K,
+			PortFeature::HighSpeed   => hw::PORTSC_PE,
+			PortFeature::CConnection => hw::PORTSC_CSC,
+			PortFeature::CEnable     => hw::PORTSC_PEC,
+			PortFeature::CSuspend    => return false,
+			PortFeature::COverCurrent=> hw::PORTSC_OCC,
+			PortFeature::CReset      => return self.host.reset_change.load(Ordering::SeqCst) & (1 << port) != 0,
+			PortFeature::Test        => return false,
+			PortFeature::Indicator   => return false,
+			PortFeature::SuperSpeed  => return false,
+			};
+		v & mask != 0
+	}
+
+	fn async_wait_root(&self) -> usb_core::host::AsyncWaitRoot {
+		struct AsyncWaitRoot {
+			host: ArefBorrow<HostInner>,
+		}
+		impl core::future::Future for AsyncWaitRoot {
+			type Output = usize;
+			fn poll(self: core::pin::Pin<&mut Self>, cx: &mut core::task::Context) -> core::task::Poll<Self::Output> {
+				let v = self.host.port_update.load(Ordering::SeqCst);
+				if v != 0
+				{
+					let idx = v.trailing_zeros();
+					self.host.port_update.fetch_and(!(1 << idx), Ordering::SeqCst);
+					return core::task::Poll::Ready(idx as usize);
+				}
+				*self.host.waker.lock() = cx.waker().clone();
+				// Check again, in case an update happened before the waker was registered
+				let v = self.host.port_update.load(Ordering::SeqCst);
+				if v != 0
+				{
+					let idx = v.trailing_zeros();
+					self.host.port_update.fetch_and(!(1 << idx), Ordering::SeqCst);
+					return core::task::Poll::Ready(idx as usize);
+				}
+				core::task::Poll::Pending
+			}
+		}
+		usb_core::host::AsyncWaitRoot::new(AsyncWaitRoot {
+			host: self.host.reborrow(),
+			}).ok().expect("Over-size task in")
+	}
+}
+
+struct ControlEndpointHandle {
+	host: ArefBorrow<HostInner>,
+	/// Queue head (only `None` during drop)
+	ep: Option<Box<EndpointPage>>,
+}
+impl host::ControlEndpoint for ControlEndpointHandle
+{
+	fn out_only<'a>(&'a self, setup_data: &'a [u8], out_data: &'a [u8]) -> host::AsyncWaitIo<'a>
+	{
+		let data = if out_data.len() == 0 { Data::None } else { Data::Out(out_data) };
+		make_io!(self.host.control(self.ep.as_ref().unwrap(), setup_data, data))
+	}
+	fn in_only<'a>(&'a self, setup_data: &'a [u8], in_data: &'a mut [u8]) -> host::AsyncWaitIo<'a>
+	{
+		make_io!(self.host.control(self.ep.as_ref().unwrap(), setup_data, Data::In(in_data)))
+	}
+}
+impl Drop for ControlEndpointHandle
+{
+	fn drop(&mut self)
+	{
+		if let Some(ep) = self.ep.take() {
+			self.host.schedule.remove(&self.host.regs, ep, Position::Async);
+		}
+	}
+}
+
+struct BulkEndpointHandle {
+	host: ArefBorrow<HostInner>,
+	/// Queue head (only `None` during drop)
+	ep: Option<Box<EndpointPage>>,
+}
+impl host::BulkEndpoint for BulkEndpointHandle
+{
+	fn send<'a>(&'a self, buffer: &'a [u8]) -> host::AsyncWaitIo<'a>
+	{
+		make_io!(self.host.bulk(self.ep.as_ref().unwrap(), Data::Out(buffer)))
+	}
+	fn recv<'a>(&'a self, buffer: &'a mut [u8]) -> host::AsyncWaitIo<'a>
+	{
+		make_io!(self.host.bulk(self.ep.as_ref().unwrap(), Data::In(buffer)))
+	}
+	fn is_halted(&self) -> bool
+	{
+		self.ep.as_ref().unwrap().is_stalled()
+	}
+	fn reset(&self)
+	{
+		// The queue head was un-halted when the transfer failed, so just reset the toggle
+		let ep = self.ep.as_ref().unwrap();
+		if let Some(_busy) = ep.claim() {
+			ep.reset();
+			ep.set_stalled(false);
+		}
+		else {
+			log_error!("Endpoint of device {} reset with a transfer in progress", ep.dev_addr());
+		}
+	}
+}
+impl Drop for BulkEndpointHandle
+{
+	fn drop(&mut self)
+	{
+		if let Some(ep) = self.ep.take() {
+			self.host.schedule.remove(&self.host.regs, ep, Position::Async);
+		}
+	}
+}
+
+struct InterruptOutEndpointHandle {
+	host: ArefBorrow<HostInner>,
+	/// Queue head (only `None` during drop)
+	ep: Option<Box<EndpointPage>>,
+	/// Periodic schedule anchor that the queue head is linked after
+	anchor: usize,
+}
+impl host::InterruptOutEndpoint for InterruptOutEndpointHandle
+{
+	fn send<'a>(&'a self, buffer: &'a [u8]) -> host::AsyncWaitIo<'a>
+	{
+		make_io!(self.host.bulk(self.ep.as_ref().unwrap(), Data::Out(buffer)))
+	}
+}
+impl Drop for InterruptOutEndpointHandle
+{
+	fn drop(&mut self)
+	{
+		if let Some(ep) = self.ep.take() {
+			self.host.schedule.remove(&self.host.regs, ep, Position::Periodic(self.anchor));
+		}
+	}
+}
+
+struct InterruptState
+{
+	/// Queue head (with the receive buffer), only `None` during drop
+	ep: Option<Box<EndpointPage>>,
+	/// Periodic schedule anchor that the queue head is linked after
+	anchor: usize,
+	max_packet_size: usize,
+	/// Length of the most recently received data
+	last_len: AtomicUsize,
+}
+struct InterruptEndpointHandle {
+	host: ArefBorrow<HostInner>,
+	state: Box<InterruptState>,
+}
+impl host::InterruptEndpoint for InterruptEndpointHandle
+{
+	fn get_data(&self) -> Handle<dyn usb_core::handle::RemoteBuffer>
+	{
+		let len = self.state.last_len.load(Ordering::SeqCst);
+		let data = self.state.ep.as_ref().unwrap().data(len).to_vec().into_boxed_slice();
+		Handle::new(DataCopy(data)).ok().expect("Over-size data handle")
+	}
+	fn wait<'a>(&'a self) -> host::AsyncWaitIo<'a>
+	{
+		make_io!(self.host.interrupt_in(&self.state))
+	}
+}
+impl Drop for InterruptEndpointHandle
+{
+	fn drop(&mut self)
+	{
+		if let Some(ep) = self.state.ep.take() {
+			self.host.schedule.remove(&self.host.regs, ep, Position::Periodic(self.state.anchor));
+		}
+	}
+}
+
+/// Copy of received interrupt data
+struct DataCopy(Box<[u8]>);
+impl usb_core::handle::RemoteFree for DataCopy
+{
+	unsafe fn free_self(&mut self) {
+		// Freed when the handle is dropped
+	}
+}
+impl usb_core::handle::RemoteBuffer for DataCopy
+{
+	fn get(&self) -> &[u8] {
+		&self.0
+	}
+}
+
+impl ::kernel::device_manager::DriverInstance for BusDev
+{
+}
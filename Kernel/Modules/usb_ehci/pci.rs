@@ -0,0 +1,76 @@
+// "Tifflin" Kernel - EHCI USB driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_ehci/pci.rs
+//! PCI bindings
+use kernel::prelude::*;
+use kernel::device_manager;
+use crate::hw;
+
+pub struct PciDriver;
+
+impl device_manager::Driver for PciDriver {
+	fn name(&self) -> &str {
+		"ehci-pci"
+	}
+	fn bus_type(&self) -> &str {
+		"pci"
+	}
+	fn handles(&self, bus_dev: &dyn device_manager::BusDevice) -> u32
+	{
+		let class = bus_dev.get_attr("class").unwrap_u32();
+		if class & 0xFF_FF_FF_00 == 0x0C0320_00 {
+			1
+		}
+		else {
+			0
+		}
+	}
+	fn bind(&self, bus_dev: &mut dyn device_manager::BusDevice) -> Box<dyn device_manager::DriverInstance+'static>
+	{
+		bus_dev.set_attr("bus_master", device_manager::AttrValue::U32(1));
+		let irq = bus_dev.get_irq(0);
+		let base = bus_dev.bind_io(0);
+
+		// SAFE: Reads have no side-effects
+		let hccparams = unsafe { base.read_32(hw::CAP_HCCPARAMS) };
+		take_ownership(bus_dev, ((hccparams >> hw::HCCPARAMS_EECP_ofs) & 0xFF) as usize);
+
+		match crate::BusDev::new_boxed(irq, base)
+		{
+		Ok(v) => v,
+		Err(e) => {
+			log_error!("Unable to initialise EHCI controller - {}", e);
+			Box::new(NullDevice)
+			},
+		}
+	}
+}
+
+/// Placeholder for a controller that failed to initialise
+struct NullDevice;
+impl device_manager::DriverInstance for NullDevice {
+}
+
+/// Walk the extended capabilities (which live in PCI config space), taking the controller from the BIOS
+fn take_ownership(bus_dev: &mut dyn device_manager::BusDevice, mut ofs: usize)
+{
+	// Capabilities must be after the standard PCI header
+	while ofs >= 0x40
+	{
+		let v = bus_dev.get_attr_idx("raw_config", ofs).unwrap_u32();
+		if (v & 0xFF) as u8 == hw::EECP_LEGACY
+		{
+			if v & hw::LEGSUP_BIOS_OWNED != 0
+			{
+				bus_dev.set_attr_idx("raw_config", ofs, device_manager::AttrValue::U32(v | hw::LEGSUP_OS_OWNED));
+				if !crate::wait_for(1000, || bus_dev.get_attr_idx("raw_config", ofs).unwrap_u32() & hw::LEGSUP_BIOS_OWNED == 0) {
+					log_warning!("BIOS didn't release the controller");
+				}
+			}
+			// Disable SMIs
+			bus_dev.set_attr_idx("raw_config", ofs + 4, device_manager::AttrValue::U32(0));
+		}
+		ofs = ((v >> 8) & 0xFF) as usize;
+	}
+}
@@ -0,0 +1,226 @@
+// "Tifflin" Kernel - EHCI USB driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_ehci/schedule.rs
+//! Async and periodic schedules
+//!
+//! The async schedule is a circular list of queue heads, starting at a permanent (inactive) head. The periodic
+//! schedule is a tree of inactive "anchor" queue heads - one per slot for each power-of-two period up to
+//! `MAX_PERIOD` frames - with interrupt endpoints linked after the anchor for their period.
+use kernel::prelude::*;
+use kernel::sync::Spinlock;
+use kernel::memory::virt::AllocHandle;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::hw;
+use crate::endpoint::EndpointPage;
+
+/// Number of entries in the periodic frame list
+const FRAME_LIST_LEN: usize = 1024;
+/// Longest supported interrupt period (in frames)
+pub const MAX_PERIOD: usize = 16;
+/// Number of anchors (MAX_PERIOD + MAX_PERIOD/2 + ... + 1)
+const N_ANCHORS: usize = MAX_PERIOD * 2 - 1;
+/// Spacing of anchor queue heads in their page
+const ANCHOR_STRIDE: usize = 128;
+
+/// Location of an endpoint in the schedule (for removal)
+pub enum Position
+{
+	Async,
+	Periodic(usize),
+}
+
+pub struct Schedule
+{
+	/// Head of the async schedule (the reclamation list head)
+	async_head: EndpointPage,
+	/// Periodic frame list
+	frame_list: AllocHandle,
+	/// Periodic anchor queue heads
+	anchors: AllocHandle,
+
+	/// Queue heads in the async schedule (virtual addresses, in list order)
+	async_members: Spinlock<Vec<usize>>,
+	/// Queue heads after each anchor (virtual addresses, in list order)
+	periodic_members: Spinlock<Vec<Vec<usize>>>,
+
+	/// Endpoints removed from the schedule, waiting until the controller can't be using them
+	/// (with the doorbell count and timestamp when they were removed)
+	retired: Spinlock<Vec<(Box<EndpointPage>, usize, u64)>>,
+	/// Number of "Interrupt on Async Advance" events seen
+	iaa_count: AtomicUsize,
+}
+
+/// Index of the first anchor for `period` (a power of two, at most MAX_PERIOD)
+fn anchor_base(period: usize) -> usize {
+	// Anchors are ordered longest period first: [16 x 16], [8 x 8], ..., [1 x 1]
+	N_ANCHORS + 1 - period * 2
+}
+
+impl Schedule
+{
+	pub fn new() -> Result<Schedule, &'static str>
+	{
+		let async_head = EndpointPage::new(hw::QH_HEAD | hw::QH_EPS_HIGH, 1 << hw::QH_MULT_ofs, false)?;
+		async_head.set_link(async_head.qh_phys() | hw::LINK_TYPE_QH);
+
+		let mut anchors = crate::alloc_dma_zeroed()?;
+		let anchors_phys = ::kernel::memory::virt::get_phys(anchors.as_ref::<u8>(0)) as u32;
+		for i in 0 .. N_ANCHORS
+		{
+			// Link to the anchor for the next shorter period (the single 1-frame anchor terminates the tree)
+			let period = Self::anchor_period(i);
+			let link = if period == 1 {
+					hw::LINK_TERMINATE
+				}
+				else {
+					let next = anchor_base(period / 2) + (i - anchor_base(period)) % (period / 2);
+					(anchors_phys + (next * ANCHOR_STRIDE) as u32) | hw::LINK_TYPE_QH
+				};
+			let qh = anchors.as_mut::<hw::QueueHead>(i * ANCHOR_STRIDE);
+			qh.link = link;
+			qh.characteristics = hw::QH_EPS_HIGH;
+			// A zero S-mask is undefined in the periodic schedule, the inactive overlay means nothing is sent
+			qh.capabilities = 1 << hw::QH_SMASK_ofs | 1 << hw::QH_MULT_ofs;
+			qh.overlay.next = hw::LINK_TERMINATE;
+			qh.overlay.alt_next = hw::LINK_TERMINATE;
+		}
+
+		let mut frame_list = crate::alloc_dma_zeroed()?;
+		for (i, e) in frame_list.as_mut_slice::<u32>(0, FRAME_LIST_LEN).iter_mut().enumerate()
+		{
+			*e = (anchors_phys + ((anchor_base(MAX_PERIOD) + i % MAX_PERIOD) * ANCHOR_STRIDE) as u32) | hw::LINK_TYPE_QH;
+		}
+
+		Ok(Schedule {
+			async_head: async_head,
+			frame_list: frame_list,
+			anchors: anchors,
+			async_members: Spinlock::new(Vec::new()),
+			periodic_members: Spinlock::new((0 .. N_ANCHORS).map(|_| Vec::new()).collect()),
+			retired: Spinlock::new(Vec::new()),
+			iaa_count: AtomicUsize::new(0),
+			})
+	}
+
+	/// Period (in frames) of the anchor at `idx`
+	fn anchor_period(idx: usize) -> usize {
+		let mut period = MAX_PERIOD;
+		while idx >= anchor_base(period) + period {
+			period /= 2;
+		}
+		period
+	}
+
+	pub fn frame_list_phys(&self) -> u32 {
+		::kernel::memory::virt::get_phys(self.frame_list.as_ref::<u8>(0)) as u32
+	}
+	pub fn async_head_phys(&self) -> u32 {
+		self.async_head.qh_phys()
+	}
+
+	fn anchor_link(&self, idx: usize) -> *mut u32 {
+		// SAFE: Only accessed via raw pointers (volatile), serialised by the `periodic_members` lock
+		unsafe { &mut self.anchors.as_int_mut::<hw::QueueHead>(idx * ANCHOR_STRIDE).link as *mut u32 }
+	}
+
+	/// Add an endpoint to the async schedule
+	pub fn add_async(&self, ep: &EndpointPage)
+	{
+		let mut members = self.async_members.lock();
+		// Insert after the head
+		ep.set_link(self.async_head.link());
+		::core::sync::atomic::fence(Ordering::SeqCst);
+		self.async_head.set_link(ep.qh_phys() | hw::LINK_TYPE_QH);
+		members.insert(0, ep.qh_addr());
+	}
+	/// Add an endpoint to the periodic schedule, using the least-loaded slot for the period (in frames)
+	///
+	/// Returns the anchor index, which is needed for removal.
+	pub fn add_periodic(&self, ep: &EndpointPage, period_frames: usize) -> usize
+	{
+		// Round down to a supported power of two
+		let mut period = MAX_PERIOD;
+		while period > 1 && period > period_frames {
+			period /= 2;
+		}
+		let mut members = self.periodic_members.lock();
+		let base = anchor_base(period);
+		let idx = (base .. base + period).min_by_key(|&i| members[i].len()).unwrap();
+		let anchor = self.anchor_link(idx);
+		// SAFE: Link fields are valid, and changes are serialised by the lock
+		unsafe {
+			ep.set_link(::core::ptr::read_volatile(anchor));
+			::core::sync::atomic::fence(Ordering::SeqCst);
+			::core::ptr::write_volatile(anchor, ep.qh_phys() | hw::LINK_TYPE_QH);
+		}
+		members[idx].insert(0, ep.qh_addr());
+		idx
+	}
+
+	/// Remove an endpoint from the schedule, and free it once the controller can no longer be accessing it
+	pub fn remove(&self, regs: &crate::Regs, ep: Box<EndpointPage>, pos: Position)
+	{
+		match pos
+		{
+		Position::Async => {
+			let mut members = self.async_members.lock();
+			Self::unlink(&mut members, self.async_head.qh_addr() as *mut u32, &ep);
+			},
+		Position::Periodic(idx) => {
+			let mut members = self.periodic_members.lock();
+			Self::unlink(&mut members[idx], self.anchor_link(idx), &ep);
+			},
+		}
+
+		// Free any endpoints that have become idle, then queue this one
+		self.reap();
+		let mut retired = self.retired.lock();
+		retired.push( (ep, self.iaa_count.load(Ordering::SeqCst), ::kernel::time::ticks()) );
+		regs.ring_iaa_doorbell();
+	}
+	fn unlink(members: &mut Vec<usize>, head: *mut u32, ep: &EndpointPage)
+	{
+		let addr = ep.qh_addr();
+		if let Some(i) = members.iter().position(|&v| v == addr)
+		{
+			let prev = if i == 0 { head } else { members[i-1] as *mut u32 };
+			// SAFE: The previous link field is valid, and changes are serialised by the caller's lock
+			unsafe { ::core::ptr::write_volatile(prev, ep.link()); }
+			members.remove(i);
+		}
+	}
+
+	/// Handle an "Interrupt on Async Advance", returns true if the doorbell should be rung again
+	pub fn handle_iaa(&self) -> bool
+	{
+		self.iaa_count.fetch_add(1, Ordering::SeqCst);
+		self.retired.lock().len() > 0
+	}
+	/// Free retired endpoints that the controller is no longer accessing
+	///
+	/// The async schedule is safe after an async advance that started after removal (so two doorbell events), and
+	/// the periodic schedule after the current frame ends (so at least one millisecond tick).
+	fn reap(&self)
+	{
+		let iaa = self.iaa_count.load(Ordering::SeqCst);
+		let now = ::kernel::time::ticks();
+		let done: Vec<_> = {
+			let mut retired = self.retired.lock();
+			let mut done = Vec::new();
+			let mut i = 0;
+			while i < retired.len()
+			{
+				if iaa >= retired[i].1 + 2 && now >= retired[i].2 + 2 {
+					done.push(retired.remove(i));
+				}
+				else {
+					i += 1;
+				}
+			}
+			done
+			};
+		// Freed with the lock released
+		drop(done);
+	}
+}
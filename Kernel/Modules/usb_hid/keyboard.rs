@@ -0,0 +1,133 @@
+// "Tifflin" Kernel - USB HID driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_hid/keyboard.rs
+//! Boot protocol keyboard
+use usb_core::{ControlEndpoint, InterruptEndpoint};
+use gui::input::keyboard::KeyCode;
+
+/// Size of a boot protocol keyboard report (modifiers, reserved, six key slots)
+const REPORT_LEN: usize = 8;
+
+pub struct Keyboard
+{
+	ep0: ControlEndpoint,
+	interface_num: u8,
+	int_ep: InterruptEndpoint,
+	guidev: gui::input::keyboard::Instance,
+
+	last_report: [u8; REPORT_LEN],
+	/// Lock state last sent to the keyboard (the GUI's `LED_*` bits match the output report)
+	leds: u8,
+}
+
+impl Keyboard
+{
+	pub fn new(ep0: ControlEndpoint, interface_num: u8, int_ep: InterruptEndpoint) -> Keyboard
+	{
+		Keyboard {
+			ep0,
+			interface_num,
+			int_ep,
+			guidev: gui::input::keyboard::Instance::new(),
+			last_report: [0; REPORT_LEN],
+			leds: 0,
+			}
+	}
+
+	pub async fn run(mut self)
+	{
+		crate::set_boot_protocol(&self.ep0, self.interface_num).await;
+		// Idle rate of zero - only report on change (key repeat is the host's job)
+		// - Optional for keyboards, so failure is ignored
+		let _ = self.ep0.send_request(crate::REQTYPE_CLASS_INTERFACE_OUT, crate::REQ_SET_IDLE, 0, self.interface_num as u16, &[]).await;
+		self.leds = self.guidev.leds();
+		self.update_leds().await;
+
+		loop
+		{
+			let len = self.int_ep.wait().await;
+			let mut report = [0; REPORT_LEN];
+			if crate::read_report(&self.int_ep, len, &mut report) < 3 {
+				log_notice!("Short keyboard report ({} bytes)", len);
+				continue ;
+			}
+			self.handle_report(&report);
+			// Lock keys (on this or another keyboard) change the GUI's state, which the LEDs follow
+			let leds = self.guidev.leds();
+			if leds != self.leds {
+				self.leds = leds;
+				self.update_leds().await;
+			}
+		}
+	}
+
+	/// Send the lock key state to the keyboard
+	async fn update_leds(&self)
+	{
+		// Report ID zero (boot keyboards only have the one output report)
+		let value = crate::REPORT_TYPE_OUTPUT << 8;
+		// - LEDs are cosmetic, so a failure is only logged
+		if let Err(e) = self.ep0.send_request(crate::REQTYPE_CLASS_INTERFACE_OUT, crate::REQ_SET_REPORT, value, self.interface_num as u16, &[self.leds]).await {
+			log_debug!("Keyboard: Unable to update LEDs - {}", e);
+		}
+	}
+
+	/// Generate key events from a report
+	fn handle_report(&mut self, report: &[u8; REPORT_LEN])
+	{
+		// Too many keys held, the report doesn't say which
+		if report[2..].iter().all(|&k| k == KeyCode::ErrorRollover as u8) {
+			return ;
+		}
+		let last = self.last_report;
+
+		// Modifiers (bitmap of LeftCtrl...RightGui)
+		let mod_changes = report[0] ^ last[0];
+		for bit in 0 .. 8
+		{
+			if mod_changes & (1 << bit) != 0
+			{
+				let key = usage_to_keycode(0xE0 + bit).unwrap();
+				if report[0] & (1 << bit) != 0 {
+					self.guidev.press_key(key);
+				}
+				else {
+					self.guidev.release_key(key);
+				}
+			}
+		}
+
+		// Released keys
+		for &usage in last[2..].iter().filter(|&&k| !report[2..].contains(&k))
+		{
+			if let Some(key) = usage_to_keycode(usage) {
+				self.guidev.release_key(key);
+			}
+		}
+		// Newly pressed keys
+		for &usage in report[2..].iter().filter(|&&k| !last[2..].contains(&k))
+		{
+			if let Some(key) = usage_to_keycode(usage)
+			{
+				self.guidev.press_key(key);
+			}
+		}
+
+		self.last_report = *report;
+	}
+}
+
+/// Convert a HID keyboard usage into a key code (None for empty slots and undefined usages)
+fn usage_to_keycode(usage: u8) -> Option<KeyCode>
+{
+	if (usage >= KeyCode::A as u8 && usage <= KeyCode::Oper as u8) || (usage >= KeyCode::LeftCtrl as u8 && usage <= KeyCode::RightGui as u8)
+	{
+		// SAFE: KeyCode is repr(u8) with the same values as HID usages, and this range is fully defined
+		Some( unsafe { ::core::mem::transmute(usage) } )
+	}
+	else
+	{
+		None
+	}
+}
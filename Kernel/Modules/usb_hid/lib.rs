@@ -0,0 +1,116 @@
+// "Tifflin" Kernel - USB HID driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_hid/lib.rs
+//! USB Human Interface Device (HID) class driver
+//!
+//! Only the boot protocol is supported (keyboards and mice), so report descriptors aren't parsed.
+#![no_std]
+#![feature(linkage)]	// for module_define!
+use kernel::prelude::*;
+use usb_core::device::{Driver, MatchLevel, Instance, Idle};
+use usb_core::{Endpoint, ControlEndpoint, InterruptEndpoint};
+
+#[macro_use]
+extern crate kernel;
+extern crate usb_core;
+extern crate gui;
+
+mod keyboard;
+mod mouse;
+
+module_define!{usb_hid, [usb_core, GUI], init}
+
+fn init()
+{
+	static KEYBOARD_DRIVER: KeyboardDriver = KeyboardDriver;
+	static MOUSE_DRIVER: MouseDriver = MouseDriver;
+	::usb_core::device::register_driver(&KEYBOARD_DRIVER);
+	::usb_core::device::register_driver(&MOUSE_DRIVER);
+}
+
+/// Class 3 (HID), subclass 1 (boot interface), protocol 1 (keyboard)
+const CLASS_BOOT_KEYBOARD: u32 = 0x03_01_01;
+/// Class 3 (HID), subclass 1 (boot interface), protocol 2 (mouse)
+const CLASS_BOOT_MOUSE: u32 = 0x03_01_02;
+
+/// Request type: Host-to-device, class request, to the interface
+const REQTYPE_CLASS_INTERFACE_OUT: u8 = 0x21;
+// HID class requests
+const REQ_SET_REPORT: u8 = 0x09;
+const REQ_SET_IDLE: u8 = 0x0A;
+const REQ_SET_PROTOCOL: u8 = 0x0B;
+
+/// Report type for SET_REPORT (upper byte of value)
+const REPORT_TYPE_OUTPUT: u16 = 2;
+/// Protocol value for SET_PROTOCOL
+const PROTOCOL_BOOT: u16 = 0;
+
+struct KeyboardDriver;
+impl Driver for KeyboardDriver
+{
+	fn name(&self) -> &str {
+		"hid-keyboard"
+	}
+	fn matches(&self, _vendor_id: u16, _device_id: u16, class_code: u32) -> MatchLevel {
+		if class_code == CLASS_BOOT_KEYBOARD { MatchLevel::Generic } else { MatchLevel::None }
+	}
+	fn start_device(&self, ep0: ControlEndpoint, interface_num: u8, endpoints: Vec<Endpoint>, _descriptors: &[u8]) -> Instance {
+		match take_interrupt_endpoint(endpoints)
+		{
+		Some(int_ep) => Box::new(keyboard::Keyboard::new(ep0, interface_num, int_ep).run()),
+		None => {
+			log_error!("USB keyboard (interface {}) has no interrupt IN endpoint", interface_num);
+			Box::new(Idle)
+			},
+		}
+	}
+}
+
+struct MouseDriver;
+impl Driver for MouseDriver
+{
+	fn name(&self) -> &str {
+		"hid-mouse"
+	}
+	fn matches(&self, _vendor_id: u16, _device_id: u16, class_code: u32) -> MatchLevel {
+		if class_code == CLASS_BOOT_MOUSE { MatchLevel::Generic } else { MatchLevel::None }
+	}
+	fn start_device(&self, ep0: ControlEndpoint, interface_num: u8, endpoints: Vec<Endpoint>, _descriptors: &[u8]) -> Instance {
+		match take_interrupt_endpoint(endpoints)
+		{
+		Some(int_ep) => Box::new(mouse::Mouse::new(ep0, interface_num, int_ep).run()),
+		None => {
+			log_error!("USB mouse (interface {}) has no interrupt IN endpoint", interface_num);
+			Box::new(Idle)
+			},
+		}
+	}
+}
+
+/// Get the (first) interrupt IN endpoint from the interface's list
+fn take_interrupt_endpoint(endpoints: Vec<Endpoint>) -> Option<InterruptEndpoint>
+{
+	endpoints.into_iter()
+		.filter_map(|ep| match ep { Endpoint::Interrupt(v) => Some(v), _ => None })
+		.next()
+}
+
+/// Copy the most recent report from an interrupt endpoint into `dst`, returning the length copied
+fn read_report(ep: &InterruptEndpoint, len: usize, dst: &mut [u8]) -> usize
+{
+	let handle = ep.get_data();
+	let data = handle.get();
+	let len = ::core::cmp::min( ::core::cmp::min(len, data.len()), dst.len() );
+	dst[..len].copy_from_slice(&data[..len]);
+	len
+}
+
+/// Set the interface to use the boot protocol (instead of the report descriptor's format)
+async fn set_boot_protocol(ep0: &ControlEndpoint, interface_num: u8)
+{
+	// - Boot devices start in the boot protocol after reset anyway, so a failure isn't fatal
+	if let Err(e) = ep0.send_request(REQTYPE_CLASS_INTERFACE_OUT, REQ_SET_PROTOCOL, PROTOCOL_BOOT, interface_num as u16, &[]).await {
+		log_notice!("HID interface {}: SET_PROTOCOL failed - {}", interface_num, e);
+	}
+}
@@ -0,0 +1,76 @@
+// "Tifflin" Kernel - USB HID driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_hid/mouse.rs
+//! Boot protocol mouse
+use usb_core::{ControlEndpoint, InterruptEndpoint};
+
+/// Boot protocol mouse report (buttons, X, Y), devices may append extra bytes
+const REPORT_LEN: usize = 3;
+const NUM_BUTTONS: u8 = 3;
+
+pub struct Mouse
+{
+	ep0: ControlEndpoint,
+	interface_num: u8,
+	int_ep: InterruptEndpoint,
+	guidev: gui::input::mouse::Instance,
+
+	buttons: u8,
+}
+
+impl Mouse
+{
+	pub fn new(ep0: ControlEndpoint, interface_num: u8, int_ep: InterruptEndpoint) -> Mouse
+	{
+		Mouse {
+			ep0,
+			interface_num,
+			int_ep,
+			guidev: gui::input::mouse::Instance::new(),
+			buttons: 0,
+			}
+	}
+
+	pub async fn run(mut self)
+	{
+		crate::set_boot_protocol(&self.ep0, self.interface_num).await;
+
+		loop
+		{
+			let len = self.int_ep.wait().await;
+			let mut report = [0; REPORT_LEN];
+			if crate::read_report(&self.int_ep, len, &mut report) < REPORT_LEN {
+				log_notice!("Short mouse report ({} bytes)", len);
+				continue ;
+			}
+			self.handle_report(&report);
+		}
+	}
+
+	fn handle_report(&mut self, report: &[u8; REPORT_LEN])
+	{
+		// Y is positive downwards (same as the GUI)
+		let dx = report[1] as i8 as i16;
+		let dy = report[2] as i8 as i16;
+		if dx != 0 || dy != 0 {
+			self.guidev.move_cursor(dx, dy);
+		}
+
+		let buttons = report[0] & ((1 << NUM_BUTTONS) - 1);
+		let changes = buttons ^ self.buttons;
+		for i in 0 .. NUM_BUTTONS
+		{
+			if changes & (1 << i) != 0
+			{
+				if buttons & (1 << i) != 0 {
+					self.guidev.press_button(i);
+				}
+				else {
+					self.guidev.release_button(i);
+				}
+			}
+		}
+		self.buttons = buttons;
+	}
+}
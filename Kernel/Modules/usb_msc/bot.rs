@@ -0,0 +1,176 @@
+// "Tifflin" Kernel - USB Mass Storage driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_msc/bot.rs
+//! Bulk-Only Transport (command/data/status wrappers)
+use kernel::metadevs::storage::IoError;
+use usb_core::{ControlEndpoint, BulkEndpoint};
+
+const CBW_SIGNATURE: u32 = 0x4342_5355;	// "USBC"
+const CSW_SIGNATURE: u32 = 0x5342_5355;	// "USBS"
+const CBW_LEN: usize = 31;
+const CSW_LEN: usize = 13;
+/// CBW flags - Data stage is device-to-host
+const CBW_FLAG_IN: u8 = 0x80;
+
+const CSW_STATUS_PASSED: u8 = 0;
+const CSW_STATUS_FAILED: u8 = 1;
+const CSW_STATUS_PHASE_ERROR: u8 = 2;
+
+// Class requests (to the interface)
+const REQ_BOT_RESET: u8 = 0xFF;
+const REQ_GET_MAX_LUN: u8 = 0xFE;
+
+/// Data stage of a command
+pub enum Data<'a>
+{
+	None,
+	In(&'a mut [u8]),
+	Out(&'a [u8]),
+}
+
+pub struct Transport
+{
+	ep0: ControlEndpoint,
+	interface_num: u8,
+	bulk_in: BulkEndpoint,
+	bulk_out: BulkEndpoint,
+	next_tag: u32,
+}
+
+impl Transport
+{
+	pub fn new(ep0: ControlEndpoint, interface_num: u8, bulk_in: BulkEndpoint, bulk_out: BulkEndpoint) -> Transport
+	{
+		Transport {
+			ep0,
+			interface_num,
+			bulk_in,
+			bulk_out,
+			next_tag: 1,
+			}
+	}
+
+	/// Query the highest LUN number (zero if the device doesn't support multiple LUNs)
+	pub async fn get_max_lun(&self) -> u8
+	{
+		let mut v = [0];
+		// - Single-LUN devices may stall this request, which is seen as a zero-length response
+		match self.ep0.recv_request(0xA1, REQ_GET_MAX_LUN, 0, self.interface_num as u16, &mut v).await
+		{
+		1 => ::core::cmp::min(v[0], 15),
+		_ => 0,
+		}
+	}
+
+	/// Run a single SCSI command
+	pub async fn command(&mut self, lun: u8, cdb: &[u8], data: Data<'_>) -> Result<(), IoError>
+	{
+		assert!(cdb.len() <= 16);
+		let tag = self.next_tag;
+		self.next_tag = self.next_tag.wrapping_add(1);
+
+		// - Command
+		let (flags, data_len) = match data
+			{
+			Data::None => (0, 0),
+			Data::In(ref b) => (CBW_FLAG_IN, b.len()),
+			Data::Out(ref b) => (0, b.len()),
+			};
+		let mut cbw = [0u8; CBW_LEN];
+		put_u32(&mut cbw[0..], CBW_SIGNATURE);
+		put_u32(&mut cbw[4..], tag);
+		put_u32(&mut cbw[8..], data_len as u32);
+		cbw[12] = flags;
+		cbw[13] = lun;
+		cbw[14] = cdb.len() as u8;
+		cbw[15 .. 15 + cdb.len()].copy_from_slice(cdb);
+		if self.bulk_out.send(&cbw).await != CBW_LEN {
+			log_warning!("USB MSC: CBW not accepted, resetting");
+			self.reset_recovery().await;
+			return Err( IoError::Timeout );
+		}
+
+		// - Data
+		// The device can stall the data stage (e.g. if it rejected the command), which must be cleared before the
+		// status can be read. A short read without a stall is possible, the residue is reported in the CSW.
+		match data
+		{
+		Data::None => {},
+		Data::In(buf) => {
+			self.bulk_in.recv(buf).await;
+			if self.bulk_in.is_halted() {
+				let _ = self.bulk_in.clear_halt(&self.ep0).await;
+			}
+			},
+		Data::Out(buf) => {
+			self.bulk_out.send(buf).await;
+			if self.bulk_out.is_halted() {
+				let _ = self.bulk_out.clear_halt(&self.ep0).await;
+			}
+			},
+		}
+
+		// - Status (retried once if the IN endpoint stalled)
+		let mut csw = [0u8; CSW_LEN];
+		let mut len = self.bulk_in.recv(&mut csw).await;
+		if len != CSW_LEN && self.bulk_in.is_halted() {
+			let _ = self.bulk_in.clear_halt(&self.ep0).await;
+			len = self.bulk_in.recv(&mut csw).await;
+		}
+		if len != CSW_LEN || get_u32(&csw[0..]) != CSW_SIGNATURE || get_u32(&csw[4..]) != tag {
+			log_warning!("USB MSC: Invalid CSW (len={}, sig={:#x}, tag={:#x} != {:#x}), resetting",
+				len, get_u32(&csw[0..]), get_u32(&csw[4..]), tag);
+			self.reset_recovery().await;
+			return Err( IoError::Timeout );
+		}
+
+		let residue = get_u32(&csw[8..]);
+		match csw[12]
+		{
+		CSW_STATUS_PASSED => {
+			// - A residue means the device transferred less than requested, so part of the buffer is stale
+			if residue != 0 {
+				log_warning!("USB MSC: Command {:#x} passed with {} bytes residue", cdb[0], residue);
+				Err( IoError::Unknown("USB MSC short transfer") )
+			}
+			else {
+				Ok( () )
+			}
+			},
+		// - The SCSI layer requests sense data to find out why
+		CSW_STATUS_FAILED => Err( IoError::Unknown("USB MSC command failed") ),
+		CSW_STATUS_PHASE_ERROR => {
+			log_warning!("USB MSC: Phase error on command {:#x}, resetting", cdb[0]);
+			self.reset_recovery().await;
+			Err( IoError::Timeout )
+			},
+		v => {
+			log_warning!("USB MSC: Unknown CSW status {:#x}, resetting", v);
+			self.reset_recovery().await;
+			Err( IoError::Timeout )
+			},
+		}
+	}
+
+	/// Reset the device's transport state after a protocol error
+	async fn reset_recovery(&self)
+	{
+		if let Err(e) = self.ep0.send_request(0x21, REQ_BOT_RESET, 0, self.interface_num as u16, &[]).await {
+			log_warning!("USB MSC: Bulk-Only Mass Storage Reset failed - {}", e);
+		}
+		// - Failures here show up on the next command
+		let _ = self.bulk_in.clear_halt(&self.ep0).await;
+		let _ = self.bulk_out.clear_halt(&self.ep0).await;
+	}
+}
+
+fn get_u32(b: &[u8]) -> u32 {
+	b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24
+}
+fn put_u32(b: &mut [u8], v: u32) {
+	b[0] = v as u8;
+	b[1] = (v >> 8) as u8;
+	b[2] = (v >> 16) as u8;
+	b[3] = (v >> 24) as u8;
+}
@@ -0,0 +1,292 @@
+// "Tifflin" Kernel - USB Mass Storage driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_msc/lib.rs
+//! USB Mass Storage Class driver (SCSI over Bulk-Only Transport)
+//!
+//! Transfers run in the driver instance (polled by the USB host worker), while the SCSI layer's calls block the
+//! calling thread. Commands are handed over through a single-entry slot in `Shared`, and each LUN is registered
+//! with the storage subsystem from a separate thread (as probing a LUN issues commands).
+//!
+//! When the device is removed the instance is dropped, which fails outstanding (and later) commands with
+//! `NoMedium` and unregisters the device's volumes.
+#![no_std]
+#![feature(linkage)]	// for module_define!
+use kernel::prelude::*;
+use kernel::lib::mem::Arc;
+use kernel::sync::{Mutex, Spinlock, EventChannel};
+use kernel::metadevs::storage;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task;
+use usb_core::device::{Driver, MatchLevel, Instance, Idle};
+use usb_core::{Endpoint, ControlEndpoint};
+
+#[macro_use]
+extern crate kernel;
+extern crate usb_core;
+extern crate storage_scsi;
+
+mod bot;
+
+module_define!{usb_msc, [usb_core, Storage], init}
+
+fn init()
+{
+	static DRIVER: MscDriver = MscDriver;
+	::usb_core::device::register_driver(&DRIVER);
+}
+
+/// Class 8 (Mass Storage), subclass 6 (SCSI transparent command set), protocol 0x50 (Bulk-Only Transport)
+const CLASS_MSC_SCSI_BOT: u32 = 0x08_06_50;
+
+/// Index used to name devices
+static S_NEXT_DEVICE: AtomicUsize = AtomicUsize::new(0);
+/// Registered LUNs, tagged with the owning device's index (so they can be dropped when it's removed)
+static S_VOLUMES: Mutex<Vec<(usize, storage::PhysicalVolumeReg)>> = Mutex::new(Vec::new_const());
+
+struct MscDriver;
+impl Driver for MscDriver
+{
+	fn name(&self) -> &str {
+		"msc-bot"
+	}
+	fn matches(&self, _vendor_id: u16, _device_id: u16, class_code: u32) -> MatchLevel {
+		if class_code == CLASS_MSC_SCSI_BOT { MatchLevel::Generic } else { MatchLevel::None }
+	}
+	fn start_device(&self, ep0: ControlEndpoint, interface_num: u8, endpoints: Vec<Endpoint>, _descriptors: &[u8]) -> Instance {
+		let mut bulk_in = None;
+		let mut bulk_out = None;
+		for ep in endpoints
+		{
+			match ep
+			{
+			Endpoint::Bulk(ep) => if ep.is_in() {
+					if bulk_in.is_none() { bulk_in = Some(ep) }
+				}
+				else {
+					if bulk_out.is_none() { bulk_out = Some(ep) }
+				},
+			_ => {},
+			}
+		}
+		match (bulk_in, bulk_out)
+		{
+		(Some(i), Some(o)) => Box::new(run_device(bot::Transport::new(ep0, interface_num, i, o))),
+		_ => {
+			log_error!("USB MSC interface {} lacks bulk IN/OUT endpoints", interface_num);
+			Box::new(Idle)
+			},
+		}
+	}
+}
+
+/// A command, with its data buffer borrowed from the (blocked) submitting thread
+struct Request
+{
+	lun: u8,
+	cdb: [u8; 16],
+	cdb_len: usize,
+	data: RequestData,
+}
+enum RequestData
+{
+	None,
+	In(*mut u8, usize),
+	Out(*const u8, usize),
+}
+// SAFE: The submitter blocks until the request completes, so the buffer stays valid and unaliased
+unsafe impl Send for Request {}
+unsafe impl Sync for Request {}
+
+/// State shared between the driver instance and the SCSI interfaces
+struct Shared
+{
+	name_idx: usize,
+	/// Serialises commands (BOT only allows one outstanding command)
+	cmd_lock: Mutex<()>,
+	/// Pending request, and the instance's waker (if it's waiting for a request)
+	slot: Spinlock<(Option<Request>, Option<task::Waker>)>,
+	result: Spinlock<Option<Result<(), storage::IoError>>>,
+	complete: EventChannel,
+	/// Set once the device has been removed
+	gone: AtomicBool,
+}
+
+impl Shared
+{
+	/// Submit a command and wait for it to complete
+	fn execute(&self, lun: u8, cdb: &[u8], data: RequestData) -> Result<(), storage::IoError>
+	{
+		if cdb.len() > 16 {
+			return Err( storage::IoError::InvalidParameter );
+		}
+		let _lh = self.cmd_lock.lock();
+		if self.gone.load(Ordering::SeqCst) {
+			return Err( storage::IoError::NoMedium );
+		}
+		let mut req = Request {
+			lun: lun,
+			cdb: [0; 16],
+			cdb_len: cdb.len(),
+			data: data,
+			};
+		req.cdb[..cdb.len()].copy_from_slice(cdb);
+
+		let waker = {
+			let mut lh = self.slot.lock();
+			assert!(lh.0.is_none());
+			lh.0 = Some(req);
+			lh.1.take()
+			};
+		if let Some(w) = waker {
+			w.wake();
+		}
+
+		loop
+		{
+			if let Some(rv) = self.result.lock().take() {
+				return rv;
+			}
+			if self.gone.load(Ordering::SeqCst) {
+				// Clear the request (if the instance never picked it up) so the buffer isn't left referenced
+				self.slot.lock().0 = None;
+				return Err( storage::IoError::NoMedium );
+			}
+			self.complete.sleep();
+		}
+	}
+}
+
+/// Future that yields the next submitted request
+struct NextRequest<'a>(&'a Shared);
+impl<'a> ::core::future::Future for NextRequest<'a>
+{
+	type Output = Request;
+	fn poll(self: ::core::pin::Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Request> {
+		let mut lh = self.0.slot.lock();
+		match lh.0.take()
+		{
+		Some(r) => task::Poll::Ready(r),
+		None => {
+			lh.1 = Some(cx.waker().clone());
+			task::Poll::Pending
+			},
+		}
+	}
+}
+
+/// Marks the device as removed when the instance is dropped
+struct RemovalGuard(Arc<Shared>);
+impl Drop for RemovalGuard
+{
+	fn drop(&mut self)
+	{
+		log_log!("USB MSC {}: Device removed", self.0.name_idx);
+		self.0.gone.store(true, Ordering::SeqCst);
+		self.0.complete.post();
+
+		// Drop the registrations with the lock released (dropping a PV can block on the storage subsystem)
+		let name_idx = self.0.name_idx;
+		let removed: Vec<_> = {
+			let mut lh = S_VOLUMES.lock();
+			let mut removed = Vec::new();
+			let mut i = 0;
+			while i < lh.len()
+			{
+				if lh[i].0 == name_idx {
+					removed.push( lh.remove(i) );
+				}
+				else {
+					i += 1;
+				}
+			}
+			removed
+			};
+		drop(removed);
+	}
+}
+
+/// Driver instance for a device
+async fn run_device(mut transport: bot::Transport)
+{
+	let max_lun = transport.get_max_lun().await;
+	let shared = Arc::new(Shared {
+		name_idx: S_NEXT_DEVICE.fetch_add(1, Ordering::Relaxed),
+		cmd_lock: Mutex::new( () ),
+		slot: Spinlock::new( (None, None) ),
+		result: Spinlock::new(None),
+		complete: EventChannel::new(),
+		gone: AtomicBool::new(false),
+		});
+	log_log!("USB MSC {}: {} LUN(s)", shared.name_idx, max_lun as usize + 1);
+
+	// Probing a LUN sends commands, which are processed below, so has to happen on another thread
+	let _probe_thread = {
+		let shared = shared.clone();
+		::kernel::threads::WorkerThread::new("USB MSC Probe", move || {
+			for lun in 0 ..= max_lun
+			{
+				let int = LunInterface {
+					name: format!("usbmsc{}l{}", shared.name_idx, lun),
+					dev: shared.clone(),
+					lun: lun,
+					};
+				match ::storage_scsi::Volume::new_boxed(int)
+				{
+				Ok(vol) => {
+					let reg = storage::register_pv(vol);
+					let mut lh = S_VOLUMES.lock();
+					// Checked with the lock held, so the removal guard can't miss this registration
+					if shared.gone.load(Ordering::SeqCst) {
+						drop(lh);
+						drop(reg);
+						break ;
+					}
+					lh.push( (shared.name_idx, reg) );
+					},
+				Err(e) => log_warning!("USB MSC {} LUN {}: Unable to probe - {:?}", shared.name_idx, lun, e),
+				}
+			}
+			})
+		};
+	let _removal_guard = RemovalGuard(shared.clone());
+
+	loop
+	{
+		let req = NextRequest(&shared).await;
+		let cdb = &req.cdb[..req.cdb_len];
+		// SAFE: The submitting thread keeps the buffer borrowed until the result is posted
+		let rv = match req.data
+			{
+			RequestData::None => transport.command(req.lun, cdb, bot::Data::None).await,
+			RequestData::In(p, len) => transport.command(req.lun, cdb, bot::Data::In(unsafe { ::core::slice::from_raw_parts_mut(p, len) })).await,
+			RequestData::Out(p, len) => transport.command(req.lun, cdb, bot::Data::Out(unsafe { ::core::slice::from_raw_parts(p, len) })).await,
+			};
+		*shared.result.lock() = Some(rv);
+		shared.complete.post();
+	}
+}
+
+/// SCSI interface for a single LUN
+struct LunInterface
+{
+	name: String,
+	dev: Arc<Shared>,
+	lun: u8,
+}
+impl ::storage_scsi::ScsiInterface for LunInterface
+{
+	fn name(&self) -> &str {
+		&self.name
+	}
+	fn send<'a>(&'a self, command: &[u8], data: &'a [u8]) -> storage::AsyncIoResult<'a,()> {
+		let d = if data.len() == 0 { RequestData::None } else { RequestData::Out(data.as_ptr(), data.len()) };
+		let rv = self.dev.execute(self.lun, command, d);
+		Box::new( ::kernel::r#async::NullResultWaiter::new( move || rv ) )
+	}
+	fn recv<'a>(&'a self, command: &[u8], data: &'a mut [u8]) -> storage::AsyncIoResult<'a,()> {
+		let d = if data.len() == 0 { RequestData::None } else { RequestData::In(data.as_mut_ptr(), data.len()) };
+		let rv = self.dev.execute(self.lun, command, d);
+		Box::new( ::kernel::r#async::NullResultWaiter::new( move || rv ) )
+	}
+}
@@ -52,8 +52,14 @@ pub enum Regs
 
 pub const HCCMDSTATUS_HCR: u32 = 1 << 0;	// "HostControllerReset"
 pub const HCCMDSTATUS_CLF: u32 = 1 << 1;	// "ControlListFilled"
-pub const HCCMDSTATUS_BLF: u32 = 1 << 1;	// "BulkListFilled"
-pub const HCCMDSTATUS_OCR: u32 = 1 << 1;	// "OwnershipChangeRequest"
+pub const HCCMDSTATUS_BLF: u32 = 1 << 2;	// "BulkListFilled"
+pub const HCCMDSTATUS_OCR: u32 = 1 << 3;	// "OwnershipChangeRequest"
+
+pub const HCCONTROL_HCFS_MASK: u32 = 3 << 6;	// "HostControllerFunctionalState"
+pub const HCCONTROL_HCFS_RESET: u32 = 0 << 6;	// - UsbReset
+pub const HCCONTROL_HCFS_RESUME: u32 = 1 << 6;	// - UsbResume
+pub const HCCONTROL_HCFS_OPERATIONAL: u32 = 2 << 6;	// - UsbOperational
+pub const HCCONTROL_IR: u32 = 1 << 8;	// "InterruptRouting" (set when SMM owns the controller)
 
 // Host Controller Communication Area
 // 256 bytes total
@@ -101,6 +107,15 @@ impl Endpoint
 	pub const FLAG_LOCKED: u32 = (1 << 31);
 	/// (AVAIL) Allocated bit
 	pub const FLAG_ALLOC: u32 = (1 << 30);
+	/// Skip entry (controller moves on to the next endpoint)
+	pub const FLAG_SKIP: u32 = (1 << 14);
+	/// Low-speed device
+	pub const FLAG_LOWSPEED: u32 = (1 << 13);
+
+	/// (head_ptr) Halted
+	pub const HEAD_HALTED: u32 = (1 << 0);
+	/// (head_ptr) Data toggle carry
+	pub const HEAD_TOGGLE: u32 = (1 << 1);
 
 	pub fn atomic_flags(s: *const Self) -> *const core::sync::atomic::AtomicU32 {
 		// NOTE: flags is the first field
@@ -108,6 +123,11 @@ impl Endpoint
 	}
 }
 
+// TD condition codes
+pub const CC_NOERROR: u32 = 0;
+pub const CC_STALL: u32 = 4;
+pub const CC_DATAUNDERRUN: u32 = 9;
+
 /// A general (non-isochronous) transfer descriptor
 #[repr(C)]
 pub struct GeneralTD
@@ -146,7 +166,7 @@ impl GeneralTD
 	pub const FLAG_AUTOFREE: u32 = 1 << 2;
 	pub const FLAG_COMPLETE: u32 = 1 << 3;
 	pub const FLAG_LOCKED: u32 = 1 << 4;
-	//pub const FLAG_ROUNDING: u32 = 1 << 18;
+	pub const FLAG_ROUNDING: u32 = 1 << 18;
 
 	pub fn maybe_alloc(&self) -> bool
 	{
@@ -175,10 +195,28 @@ impl GeneralTD
 		assert!(self.flags.load(Ordering::SeqCst) & Self::FLAG_INIT != 0);
 		self.flags.fetch_or(Self::FLAG_COMPLETE, Ordering::SeqCst) & Self::FLAG_AUTOFREE != 0
 	}
+	/// Request that the TD be released once complete, returns true if it's already complete (and should be freed now)
+	pub fn mark_autofree(&self) -> bool
+	{
+		assert!(self.flags.load(Ordering::SeqCst) & Self::FLAG_INIT != 0);
+		self.flags.fetch_or(Self::FLAG_AUTOFREE, Ordering::SeqCst) & Self::FLAG_COMPLETE != 0
+	}
 	pub fn get_next(&self) -> u32
 	{
 		assert!(self.flags.load(Ordering::Acquire) & Self::FLAG_INIT != 0);
-		self.next_td
+		// NOTE: Volatile, as the controller updates this when the TD is retired
+		// SAFE: Valid pointer
+		unsafe { ::core::ptr::read_volatile(&self.next_td) }
+	}
+	/// UNSAFE: `next_td` must be a valid TD address, and the TD must not be in use by the controller
+	pub unsafe fn set_next(s: *mut Self, next_td: u32)
+	{
+		::core::ptr::write_volatile(&mut (*s).next_td, next_td);
+	}
+	/// Condition code (valid once complete, 0 = NoError)
+	pub fn condition_code(&self) -> u32
+	{
+		self.flags.load(Ordering::SeqCst) >> 28
 	}
 	/// Returns `Some(unused_space)`
 	pub fn is_complete(&self) -> Option<usize>
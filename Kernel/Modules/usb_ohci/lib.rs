@@ -2,12 +2,16 @@
 //
 //
 //! Open Host Controller Interface (OHCI) driver
+//!
+//! Limitations:
+//! - Isochronous endpoints (isochronous TDs) aren't supported, `init_isoch` always fails
 #![no_std]
 #![feature(linkage)]	// for module_define!
 use kernel::prelude::*;
 use kernel::_async3 as async;
 use kernel::lib::mem::aref::{Aref,ArefBorrow};
-use core::sync::atomic::{AtomicU32,AtomicPtr,AtomicUsize,Ordering};
+use core::sync::atomic::{AtomicBool,AtomicU32,AtomicPtr,AtomicUsize,Ordering};
+use kernel::memory::virt::AllocHandle;
 use core::mem::size_of;
 
 #[macro_use]
@@ -35,6 +39,8 @@ struct UsbHost
 	host: ArefBorrow<HostInner>,
 }
 const MAX_INT_PERIOD_MS: usize = 16;
+/// Maximum number of additional pages for endpoint descriptors (and for transfer descriptors)
+const MAX_EXTRA_POOLS: usize = 16;
 struct HostInner
 {
 	io: IoWrapper,
@@ -43,6 +49,7 @@ struct HostInner
 	nports: u8,
 
 	control_list_lock: ::kernel::sync::Spinlock<()>,
+	bulk_list_lock: ::kernel::sync::Spinlock<()>,
 
 	// - Async support
 	waker: kernel::sync::Spinlock<core::task::Waker>,
@@ -51,6 +58,12 @@ struct HostInner
 	int_table_meta: [InterruptSlotMeta; MAX_INT_PERIOD_MS*2 - 1],
 	/// Table of TD metadata (for group 0)
 	endpoint_metadata_group0: Vec<EndpointMetadata>,
+	/// Endpoint descriptor pages used once the HCCA page is full (group N is at index N-1)
+	ed_pools: Vec<AtomicPtr<EndpointPool>>,
+	/// Transfer descriptor pages used once the HCCA page is full (group N is at index N-1)
+	td_pools: Vec<AtomicPtr<AllocHandle>>,
+	/// Bitmap of low-speed device addresses (address 0 is the device on the most recently reset port)
+	low_speed_devices: [AtomicU32; 128 / 32],
 }
 struct IoWrapper(::kernel::device_manager::IOBinding);
 #[derive(Default)]
@@ -72,6 +85,7 @@ impl_fmt! {
 	}
 }
 /// Index into a pool of transfer descriptors
+#[derive(PartialEq)]
 struct TransferDescriptorId {
 	// Group 0 is in the tail end of the HCCA
 	group: u8,
@@ -97,6 +111,74 @@ impl TransferDescriptorId {
 struct EndpointMetadata {
 	tail_td: core::sync::atomic::AtomicU16,
 }
+/// An additional page of endpoint descriptors
+struct EndpointPool {
+	page: AllocHandle,
+	metadata: Vec<EndpointMetadata>,
+}
+impl EndpointPool {
+	fn new() -> Result<EndpointPool, &'static str> {
+		Ok(EndpointPool {
+			page: alloc_pool_page()?,
+			metadata: Vec::from_fn(0x1000 / size_of::<hw::Endpoint>(), |_| Default::default()),
+			})
+	}
+}
+
+/// Allocate a zeroed page for descriptors
+fn alloc_pool_page() -> Result<AllocHandle, &'static str>
+{
+	let mut h = ::kernel::memory::virt::alloc_dma(32, 1, "usb_ohci")?;
+	for v in h.as_mut_slice::<u32>(0, 0x1000 / 4) {
+		*v = 0;
+	}
+	Ok(h)
+}
+/// Get an additional descriptor pool, allocating it if `new` is passed
+fn get_extra_pool<T>(pools: &[AtomicPtr<T>], group: u8, new: Option<fn()->Result<T,&'static str>>) -> Option<&T>
+{
+	let slot = pools.get(group as usize - 1)?;
+	let p = slot.load(Ordering::Acquire);
+	if !p.is_null() {
+		// SAFE: Pools are only freed when the host is dropped
+		return Some(unsafe { &*p });
+	}
+	let new = new?;
+	let v = match new()
+		{
+		Ok(v) => Box::into_raw(Box::new(v)),
+		Err(e) => {
+			log_error!("Unable to allocate descriptor pool {}: {}", group, e);
+			return None;
+			},
+		};
+	match slot.compare_exchange(::core::ptr::null_mut(), v, Ordering::AcqRel, Ordering::Acquire)
+	{
+	// SAFE: Pools are only freed when the host is dropped
+	Ok(_) => Some(unsafe { &*v }),
+	Err(existing) => {
+		// Another thread allocated this pool first
+		// SAFE: `v` came from `Box::into_raw` and wasn't published
+		drop(unsafe { Box::from_raw(v) });
+		// SAFE: Pools are only freed when the host is dropped
+		Some(unsafe { &*existing })
+		},
+	}
+}
+
+/// Wait (yielding the CPU) until `cond` returns true, returns false on timeout
+fn wait_for<F: FnMut()->bool>(timeout_ms: u64, mut cond: F) -> bool
+{
+	let end = ::kernel::time::ticks() + timeout_ms;
+	while !cond()
+	{
+		if ::kernel::time::ticks() > end {
+			return false;
+		}
+		::kernel::threads::yield_time();
+	}
+	true
+}
 
 
 impl BusDev
@@ -133,26 +215,40 @@ impl HostInner
 		// Perform a hardware reset (and get controller from the firmware)
 		// SAFE: Read is safe
 		let hc_control = io.read_reg(hw::Regs::HcControl);
-		if hc_control & 0x100 != 0
+		if hc_control & hw::HCCONTROL_IR != 0
 		{
-			// SMM emulation
-			todo!("Request device from SMM");
+			// SMM emulation - request ownership, the SMM driver clears InterruptRouting once it has let go
+			log_notice!("Requesting controller from SMM");
+			// SAFE: No memory addresses in this one.
+			unsafe { io.write_reg(hw::Regs::HcCommandStatus, hw::HCCMDSTATUS_OCR); }
+			if !wait_for(500, || io.read_reg(hw::Regs::HcControl) & hw::HCCONTROL_IR == 0)
+			{
+				log_warning!("SMM didn't release the controller, taking it anyway");
+				// SAFE: No memory addresses in this one.
+				unsafe { io.write_reg(hw::Regs::HcControl, io.read_reg(hw::Regs::HcControl) & !hw::HCCONTROL_IR); }
+			}
 		}
 		else
 		{
-			if hc_control & 0xC0 == 0
+			if hc_control & hw::HCCONTROL_HCFS_MASK == hw::HCCONTROL_HCFS_RESET
 			{
 				// Bus is in UsbReset, wait a bit then switch to UsbOperational
 				// TODO: Wait for a period, or just assume that the wait has already happened.
 			}
-			else if hc_control & 0xC0 == 0x80
+			else if hc_control & hw::HCCONTROL_HCFS_MASK == hw::HCCONTROL_HCFS_OPERATIONAL
 			{
 				// Device is ready for operation
 			}
 			else
 			{
-				// The bus is in UsbSuspend or UsbResume
-				todo!("Reset device, not ready yet");
+				// The bus is in UsbSuspend or UsbResume (left there by a BIOS driver), resume before resetting
+				// SAFE: No memory addresses in this one.
+				unsafe {
+					io.write_reg(hw::Regs::HcControl, (hc_control & !hw::HCCONTROL_HCFS_MASK) | hw::HCCONTROL_HCFS_RESUME);
+				}
+				// - Minimum resume time is 20ms
+				let end = ::kernel::time::ticks() + 20;
+				wait_for(20, || ::kernel::time::ticks() > end);
 			}
 		}
 
@@ -160,8 +256,10 @@ impl HostInner
 		// SAFE: No memory addresses in this one.
 		unsafe {
 			io.write_reg(hw::Regs::HcCommandStatus, hw::HCCMDSTATUS_HCR);
-			// TODO: Wait for 10us
-			//::kernel::time::wait_busy_microseconds(10);
+			// - Reset takes at most 10us, and the bit clears once done
+			if !wait_for(2, || io.read_reg(hw::Regs::HcCommandStatus) & hw::HCCMDSTATUS_HCR == 0) {
+				log_warning!("Controller reset didn't complete");
+			}
 			// - Restore the HcFmInterval value
 			io.write_reg(hw::Regs::HcFmInterval, fm_interval_val);
 			// - Set the bus back to UsbOperational
@@ -280,12 +378,16 @@ impl HostInner
 			irq_handle: None,	// Filled below, once the allocation is made
 
 			control_list_lock: Default::default(),
+			bulk_list_lock: Default::default(),
 
 			port_update: AtomicU32::new(0),
 			waker: kernel::sync::Spinlock::new(kernel::futures::null_waker()),
 
 			int_table_meta: Default::default(),
-			endpoint_metadata_group0: Vec::from_fn((1024+256) / 16, |_| Default::default()),
+			endpoint_metadata_group0: Vec::from_fn(2048 / 16, |_| Default::default()),
+			ed_pools: Vec::from_fn(MAX_EXTRA_POOLS, |_| AtomicPtr::new(::core::ptr::null_mut())),
+			td_pools: Vec::from_fn(MAX_EXTRA_POOLS, |_| AtomicPtr::new(::core::ptr::null_mut())),
+			low_speed_devices: Default::default(),
 			});
 		
 		// Bind interrupt
@@ -398,32 +500,62 @@ impl HostInner
 		}
 	}
 
-	/// Allocate a new endpoint
-	fn allocate_endpoint(&self, flags: u32) -> EndpointId
+	/// Allocate a new endpoint (returns `None` if there are no free endpoint descriptors)
+	fn allocate_endpoint(&self, flags: u32) -> Option<EndpointId>
 	{
+		let try_claim = |ep_id: &EndpointId| {
+			let ptr = self.get_ed_pointer(ep_id);
+			// SAFE: Pointer is valid (we just got it from get_ed_pointer)
+			let flags_atomic = unsafe { &*hw::Endpoint::atomic_flags(ptr) };
+			let fv = flags_atomic.load(Ordering::SeqCst);
+			fv & hw::Endpoint::FLAG_ALLOC == 0 && flags_atomic.compare_and_swap(fv, flags | hw::Endpoint::FLAG_ALLOC, Ordering::SeqCst) == fv
+			};
 		let ep_id = (|| {
 			// 1. Iterate all group 0 endpoints and look for one not marked as allocated
 			// - Free pool starts at 256 + 512 (HCCA + interrupts)
 			for i in (256 + 512) / 16 .. 2048 / 16
 			{
 				let ep_id = EndpointId { group: 0, idx: i as u8 };
-				let ptr = self.get_ed_pointer(&ep_id);
-				// SAFE: Pointer is valid (we just got it from get_ed_pointer)
-				let flags_atomic = unsafe { &*hw::Endpoint::atomic_flags(ptr) };
-				let fv = flags_atomic.load(Ordering::SeqCst);
-				if fv & hw::Endpoint::FLAG_ALLOC == 0 {
-					if flags_atomic.compare_and_swap(fv, flags | hw::Endpoint::FLAG_ALLOC, Ordering::SeqCst) == fv {
-						return ep_id;
+				if try_claim(&ep_id) {
+					return Some(ep_id);
+				}
+			}
+			// 2. Look through the additional endpoint pages (allocating them as needed)
+			for group in 1 .. MAX_EXTRA_POOLS as u8 + 1
+			{
+				if get_extra_pool(&self.ed_pools, group, Some(EndpointPool::new)).is_none() {
+					break;
+				}
+				for i in 0 .. 0x1000 / size_of::<hw::Endpoint>()
+				{
+					let ep_id = EndpointId { group: group, idx: i as u8 };
+					if try_claim(&ep_id) {
+						return Some(ep_id);
 					}
 				}
 			}
-			// 2. Look through already-existing endpoint pages
-			todo!("allocate_endpoint: flags={:#x} (hcca page full)", flags)
+			None
 			})();
+		let ep_id = match ep_id
+			{
+			Some(v) => v,
+			None => {
+				log_error!("allocate_endpoint: flags={:#x} - out of endpoint descriptors", flags);
+				return None;
+				},
+			};
 		log_debug!("allocate_endpoint(flags={:#x}): ptr={:#x}", flags, kernel::memory::virt::get_phys(self.get_ed_pointer(&ep_id)));
 		// - Populate metadata and initialise them
 		let meta = self.get_endpoint_meta(&ep_id);
-		let new_tail = self.allocate_td();
+		let new_tail = match self.allocate_td()
+			{
+			Some(v) => v,
+			None => {
+				// SAFE: Pointer is valid, and the endpoint was only just claimed
+				unsafe { (*hw::Endpoint::atomic_flags(self.get_ed_pointer(&ep_id))).store(0, Ordering::SeqCst); }
+				return None;
+				},
+			};
 		let mut h = self.get_ed_locked(&ep_id);
 		// SAFE: Locked
 		unsafe {
@@ -434,7 +566,7 @@ impl HostInner
 		}
 		meta.tail_td.store( new_tail.to_u16(), Ordering::SeqCst );
 
-		ep_id
+		Some(ep_id)
 	}
 	/// Obtain a pointer to the specified endpoint descriptor
 	// NOTE: Returns a raw pointer because it's possibly being mutated
@@ -446,7 +578,8 @@ impl HostInner
 			self.hcca_handle.as_ref(ofs)
 		}
 		else {
-			todo!("get_ed_pointer: Alternate pools for endpoint descriptors");
+			let pool = get_extra_pool(&self.ed_pools, id.group, None).expect("get_ed_pointer: Pool not allocated");
+			pool.page.as_ref::<hw::Endpoint>((id.idx as usize) * size_of::<hw::Endpoint>())
 		}
 	}
 
@@ -467,6 +600,20 @@ impl HostInner
 			assert!(ofs % size_of::<hw::GeneralTD>() == 0);
 			return Some(TransferDescriptorId { group: 0, idx: (ofs / size_of::<hw::GeneralTD>()) as u8 });
 		}
+		for group in 1 .. MAX_EXTRA_POOLS as u8 + 1
+		{
+			let pool = match get_extra_pool(&self.td_pools, group, None)
+				{
+				Some(v) => v,
+				None => break,
+				};
+			let page = ::kernel::memory::virt::get_phys(pool.as_ref::<u8>(0)) as u32;
+			if addr & !0xFFF == page {
+				let ofs = (addr - page) as usize;
+				assert!(ofs % size_of::<hw::GeneralTD>() == 0);
+				return Some(TransferDescriptorId { group: group, idx: (ofs / size_of::<hw::GeneralTD>()) as u8 });
+			}
+		}
 		None
 	}
 	fn get_general_td_pointer(&self, id: &TransferDescriptorId) -> &hw::GeneralTD {
@@ -478,23 +625,25 @@ impl HostInner
 			unsafe { &*(self.hcca_handle.as_ref::<u8>(ofs) as *const _ as *const hw::GeneralTD) }
 		}
 		else {
-			todo!("get_general_td_pointer: Alternate pools for transfer descriptors");
+			let pool = get_extra_pool(&self.td_pools, id.group, None).expect("get_general_td_pointer: Pool not allocated");
+			// SAFE: Aligned, in range, and the pool only contains TDs
+			unsafe { &*(pool.as_ref::<u8>((id.idx as usize) * size_of::<hw::GeneralTD>()) as *const _ as *const hw::GeneralTD) }
 		}
 	}
 
 	/// Obtain metadata for the specified endpoint
 	fn get_endpoint_meta(&self, id: &EndpointId) -> &EndpointMetadata {
-		// TODO: Find a place where the metadata can be stored
 		if id.group == 0 {
 			&self.endpoint_metadata_group0[id.idx as usize]
 		}
 		else {
-			todo!("get_endpoint_meta({:?})", id);
+			let pool = get_extra_pool(&self.ed_pools, id.group, None).expect("get_endpoint_meta: Pool not allocated");
+			&pool.metadata[id.idx as usize]
 		}
 	}
 
 	/// Register an interrupt endpoint
-	fn register_interrupt_ed(&self, period_pow_2: usize, flags: u32) -> EndpointId
+	fn register_interrupt_ed(&self, period_pow_2: usize, flags: u32) -> Option<EndpointId>
 	{
 		// 1. Find a low-load slot of this period
 		let (start,len) = 
@@ -523,7 +672,17 @@ impl HostInner
 		// 2. Check if the placeholder is in use
 		if placeholder_ed.flags() & (1 << 14) == 0 {
 			// - If it is, allocate a new endpoint descriptor and put it after the placeholder
-			let new_ed_id = self.allocate_endpoint(flags);
+			let new_ed_id = match self.allocate_endpoint(flags)
+				{
+				Some(v) => v,
+				None => {
+					for idx in UpstreamIntSlots(start + min_slot_idx)
+					{
+						self.int_table_meta[idx].loading.fetch_sub(1, Ordering::SeqCst);
+					}
+					return None;
+					},
+				};
 
 			// SAFE: Ordering ensures consistency, writing valid addreses
 			unsafe {
@@ -532,18 +691,18 @@ impl HostInner
 				placeholder_ed.set_next_ed( new_ed.get_phys() );
 			}
 
-			new_ed_id
+			Some(new_ed_id)
 		}
 		else {
 			// - Otherwise use the placeholder
 			placeholder_ed.set_flags(flags);
-			placeholder_ed_id
+			Some(placeholder_ed_id)
 		}
 	}
 	/// Register a general-purpose endpoint descriptor and add it to the control queue
-	fn register_control_ed(&self, flags: u32) -> EndpointId
+	fn register_control_ed(&self, flags: u32) -> Option<EndpointId>
 	{
-		let ep = self.allocate_endpoint(flags);
+		let ep = self.allocate_endpoint(flags)?;
 
 		// SAFE: Pointer valid, register access controlled
 		unsafe {
@@ -557,17 +716,30 @@ impl HostInner
 			(*ptr).next_ed = existing;
 			self.io.write_reg(hw::Regs::HcControlHeadED, paddr);
 		}
-		ep
+		Some(ep)
 	}
 	/// Register a general-purpose endpoint descriptor and add it to the bulk queue
-	fn register_bulk_ed(&self, flags: u32) -> EndpointId
+	fn register_bulk_ed(&self, flags: u32) -> Option<EndpointId>
 	{
-		let ep = self.allocate_endpoint(flags);
-		todo!("register_bulk_ed: {:?}", ep);
+		let ep = self.allocate_endpoint(flags)?;
+
+		// SAFE: Pointer valid, register access controlled
+		unsafe {
+			let ptr = self.get_ed_pointer(&ep) as *mut hw::Endpoint;
+			let paddr = ::kernel::memory::virt::get_phys(ptr) as u32;
+
+			// Lock list
+			let _lh = self.bulk_list_lock.lock();
+			// Get existing head pointer, store in newly created ED, update register
+			let existing = self.io.read_reg(hw::Regs::HcBulkHeadED);
+			(*ptr).next_ed = existing;
+			self.io.write_reg(hw::Regs::HcBulkHeadED, paddr);
+		}
+		Some(ep)
 	}
 
-	/// Allocate a new TD
-	fn allocate_td(&self) -> TransferDescriptorId
+	/// Allocate a new TD (returns `None` if all pools are exhausted)
+	fn allocate_td(&self) -> Option<TransferDescriptorId>
 	{
 		// Iterate over all avaliable pools
 		const SIZE: usize = size_of::<hw::GeneralTD>();
@@ -577,16 +749,33 @@ impl HostInner
 			if self.get_general_td_pointer(&rv).maybe_alloc()
 			{
 				//log_debug!("allocate_td: group 0, idx {}", i);
-				return rv;
+				return Some(rv);
+			}
+		}
+		// Main pool is exhausted, look through the additional pools (allocating them as needed)
+		for group in 1 .. MAX_EXTRA_POOLS as u8 + 1
+		{
+			if get_extra_pool(&self.td_pools, group, Some(alloc_pool_page)).is_none() {
+				break;
+			}
+			for i in 0 .. 0x1000 / SIZE
+			{
+				let rv = TransferDescriptorId { group: group, idx: i as u8 };
+				if self.get_general_td_pointer(&rv).maybe_alloc()
+				{
+					return Some(rv);
+				}
 			}
 		}
-		todo!("allocate_td - alternate pools, main is exhausted");
+		log_error!("allocate_td: Out of transfer descriptors");
+		None
 	}
-	unsafe fn push_td(&self, ep: &EndpointId, flags: u32, first_byte: u32, last_byte: u32, waker: ::core::task::Waker) -> TransferDescriptorId
+	/// Queue a TD on an endpoint (returns `None` if a TD couldn't be allocated)
+	unsafe fn push_td(&self, ep: &EndpointId, flags: u32, first_byte: u32, last_byte: u32, waker: ::core::task::Waker) -> Option<TransferDescriptorId>
 	{
 		log_debug!("push_td({:?}, {:#x}, {:#x}-{:#x})", ep, flags, first_byte, last_byte);
 		// 1. Allocate a new transfer descriptor (to be used as the new tail)
-		let new_tail_td = self.allocate_td();
+		let new_tail_td = self.allocate_td()?;
 		let new_tail_phys = ::kernel::memory::virt::get_phys( self.get_general_td_pointer(&new_tail_td) ) as u32;
 		// 2. Lock the endpoint (makes sure that there's no contention software-side)
 		// TODO: Could the metadata be locked to the endpoint handle?
@@ -602,11 +791,80 @@ impl HostInner
 		// - Update the tail pointer
 		ed.set_tail_ptr( new_tail_phys );
 
-		td_handle
+		Some(td_handle)
 	}
-	pub fn stop_td(&self, td: &TransferDescriptorId)
+	/// Stop and release transfer descriptors queued on an endpoint (also clears a halt)
+	///
+	/// TDs still on the endpoint's queue are removed, and any that the controller has already retired are released
+	/// once they're seen on the done queue. If `reset_toggle` is set, the data toggle is reset to DATA0 (e.g. after
+	/// a STALL, as the device resets its toggle when the halt is cleared).
+	pub fn stop_tds(&self, ep: &EndpointId, tds: &[TransferDescriptorId], reset_toggle: bool)
 	{
-		todo!("stop_td({:?})", td);
+		// Skip the endpoint and wait for the next frame, after which the controller won't be accessing it
+		{
+			let mut ed = self.get_ed_locked(ep);
+			let flags = ed.flags();
+			ed.set_flags(flags | hw::Endpoint::FLAG_SKIP);
+		}
+		let frame = self.io.read_reg(hw::Regs::HcFmNumber) & 0xFFFF;
+		if !wait_for(2, || self.io.read_reg(hw::Regs::HcFmNumber) & 0xFFFF != frame) {
+			log_warning!("stop_tds({:?}): Frame number didn't advance", ep);
+		}
+
+		let mut unlinked = Vec::new();
+		{
+			let mut ed = self.get_ed_locked(ep);
+			let tail = ed.tail_ptr() & !0xF;
+			let mut prev: Option<*mut hw::GeneralTD> = None;
+			let mut cur = ed.head_ptr() & !0xF;
+			while cur != tail
+			{
+				let cur_id = self.get_general_td_from_phys(cur).expect("stop_tds: Bad TD address");
+				let cur_ptr = self.get_general_td_pointer(&cur_id);
+				let next = cur_ptr.get_next();
+				match tds.iter().position(|td| *td == cur_id)
+				{
+				Some(i) => {
+					// SAFE: The endpoint is skipped and locked, and `next` was a valid TD address
+					unsafe {
+						match prev
+						{
+						Some(p) => hw::GeneralTD::set_next(p, next),
+						None => {
+							let head = ed.head_ptr();
+							ed.set_head_ptr(next | (head & (hw::Endpoint::HEAD_HALTED|hw::Endpoint::HEAD_TOGGLE)));
+							},
+						}
+					}
+					cur_ptr.mark_free();
+					unlinked.push(i);
+					},
+				None => prev = Some(cur_ptr as *const _ as *mut _),
+				}
+				cur = next;
+			}
+
+			// Clear the halt (and maybe the toggle), then resume processing
+			let mut head = ed.head_ptr() & !hw::Endpoint::HEAD_HALTED;
+			if reset_toggle {
+				head &= !hw::Endpoint::HEAD_TOGGLE;
+			}
+			// SAFE: Address unchanged
+			unsafe { ed.set_head_ptr(head); }
+			let flags = ed.flags();
+			ed.set_flags(flags & !hw::Endpoint::FLAG_SKIP);
+		}
+
+		// Anything not on the queue has been retired by the controller, release once complete
+		for (i,td) in tds.iter().enumerate()
+		{
+			if !unlinked.contains(&i) {
+				let ptr = self.get_general_td_pointer(td);
+				if ptr.mark_autofree() {
+					ptr.mark_free();
+				}
+			}
+		}
 	}
 	pub fn release_td(&self, td: TransferDescriptorId)
 	{
@@ -629,44 +887,171 @@ impl HostInner
 	fn td_complete(&self, td: &TransferDescriptorId) -> Option<usize> {
 		self.get_general_td_pointer(td).is_complete()
 	}
+	/// Returns the condition code of a TD that completed with an error
+	fn td_error(&self, td: &TransferDescriptorId) -> Option<u32> {
+		let ptr = self.get_general_td_pointer(td);
+		match ptr.is_complete()
+		{
+		Some(_) if ptr.condition_code() != hw::CC_NOERROR => Some(ptr.condition_code()),
+		_ => None,
+		}
+	}
 
-	// Get a handle for a DMA output
-	fn get_dma_todev(&self, p: &[u8]) -> (Option<::kernel::memory::virt::AllocHandle>, u32, u32)
+	// Kick the controller and make it run the bulk list
+	fn kick_bulk(&self)
 	{
-		log_debug!("get_dma_todev({:p})", p);
-		if p.len() == 0 {
-			return (None, 0 as u32, 0 as u32);
+		// SAFE: No memory impact
+		unsafe {
+			self.io.write_reg(hw::Regs::HcCommandStatus, hw::HCCMDSTATUS_BLF);
 		}
-		let start_phys = ::kernel::memory::virt::get_phys(p.as_ptr());
-		let last_phys = ::kernel::memory::virt::get_phys(&p[p.len()-1]);
-		if start_phys > 0xFFFF_FFFF || last_phys > 0xFFFF_FFFF {
-			// An address is more than 32-bits, bounce
+	}
+
+	/// Get the ED speed flag for a device
+	fn speed_flag(&self, dev_addr: u8) -> u32 {
+		if self.low_speed_devices[dev_addr as usize / 32].load(Ordering::SeqCst) & (1 << (dev_addr % 32)) != 0 {
+			hw::Endpoint::FLAG_LOWSPEED
 		}
-		else if start_phys & !0xFFF != last_phys & !0xFFF && (0x1000 - (start_phys & 0xFFF) + last_phys & 0xFFF) as usize != p.len() {
-			// The buffer spans more than two pages, bounce
+		else {
+			0
+		}
+	}
+	fn set_low_speed(&self, dev_addr: u8, is_low_speed: bool) {
+		let bit = 1 << (dev_addr % 32);
+		if is_low_speed {
+			self.low_speed_devices[dev_addr as usize / 32].fetch_or(bit, Ordering::SeqCst);
 		}
 		else {
-			// Good
-			return (None, start_phys as u32, last_phys as u32);
+			self.low_speed_devices[dev_addr as usize / 32].fetch_and(!bit, Ordering::SeqCst);
 		}
-		todo!("Bounce buffer - long lifetime");
 	}
-	// Get a handle for a DMA input
-	fn get_dma_fromdev<'a>(&self, p: &'a mut [u8]) -> (Option<::kernel::memory::virt::AllocHandle>, u32, u32)
+	/// Update the speed flag on an (idle) endpoint, used for the address zero endpoint
+	fn update_ed_speed(&self, ep: &EndpointId, dev_addr: u8) {
+		let mut ed = self.get_ed_locked(ep);
+		let flags = ed.flags() & !hw::Endpoint::FLAG_LOWSPEED;
+		ed.set_flags(flags | self.speed_flag(dev_addr));
+	}
+
+	/// Get the first and last physical addresses of a buffer, if a single TD can access it directly
+	fn get_dma_range(p: &[u8]) -> Option<(u32, u32)>
 	{
 		let start_phys = ::kernel::memory::virt::get_phys(p.as_ptr());
 		let last_phys = ::kernel::memory::virt::get_phys(&p[p.len()-1]);
 		if start_phys > 0xFFFF_FFFF || last_phys > 0xFFFF_FFFF {
 			// An address is more than 32-bits, bounce
+			None
 		}
-		else if start_phys & !0xFFF != last_phys & !0xFFF && (0x1000 - (start_phys & 0xFFF) + last_phys & 0xFFF) as usize != p.len() {
+		else if (start_phys & 0xFFF) as usize + p.len() > 0x2000 {
 			// The buffer spans more than two pages, bounce
+			None
 		}
 		else {
 			// Good
-			return (None, start_phys as u32, last_phys as u32);
+			Some( (start_phys as u32, last_phys as u32) )
+		}
+	}
+	/// Returns true if all pages of the buffer are 32-bit addressable
+	fn is_dma32(p: &[u8]) -> bool
+	{
+		let mut ofs = 0;
+		while ofs < p.len()
+		{
+			if ::kernel::memory::virt::get_phys(&p[ofs]) > 0xFFFF_FFFF {
+				return false;
+			}
+			ofs += 0x1000 - (&p[ofs] as *const u8 as usize & 0xFFF);
+		}
+		true
+	}
+	/// Allocate a (32-bit addressable) bounce buffer
+	fn alloc_bounce(len: usize) -> Option<AllocHandle>
+	{
+		match ::kernel::memory::virt::alloc_dma(32, (len + 0xFFF) / 0x1000, "usb_ohci")
+		{
+		Ok(v) => Some(v),
+		Err(e) => {
+			log_error!("Unable to allocate {} byte bounce buffer - {:?}", len, e);
+			None
+			},
+		}
+	}
+	/// Get the physical range of a single-TD bounce buffer
+	fn bounce_range(h: &AllocHandle, len: usize) -> (u32, u32)
+	{
+		assert!(len <= 0x2000, "Transfer of {} bytes is too large for a single TD", len);
+		(::kernel::memory::virt::get_phys(h.as_ref::<u8>(0)) as u32, ::kernel::memory::virt::get_phys(h.as_ref::<u8>(len-1)) as u32)
+	}
+
+	// Get a handle for a DMA output
+	// - The returned bounce buffer must be kept until the transfer completes
+	// - Returns `None` if a bounce buffer was needed but couldn't be allocated
+	fn get_dma_todev(&self, p: &[u8]) -> Option<(Option<AllocHandle>, u32, u32)>
+	{
+		log_debug!("get_dma_todev({:p})", p);
+		if p.len() == 0 {
+			return Some( (None, 0 as u32, 0 as u32) );
+		}
+		if let Some((start, last)) = Self::get_dma_range(p) {
+			return Some( (None, start, last) );
+		}
+		let mut h = Self::alloc_bounce(p.len())?;
+		h.as_mut_slice::<u8>(0, p.len()).copy_from_slice(p);
+		let (start, last) = Self::bounce_range(&h, p.len());
+		Some( (Some(h), start, last) )
+	}
+	// Get a handle for a DMA input
+	// - If a bounce buffer is returned, the caller copies the data out once the transfer is complete
+	// - Returns `None` if a bounce buffer was needed but couldn't be allocated
+	fn get_dma_fromdev<'a>(&self, p: &'a mut [u8]) -> Option<(Option<AllocHandle>, u32, u32)>
+	{
+		if p.len() == 0 {
+			return Some( (None, 0 as u32, 0 as u32) );
+		}
+		if let Some((start, last)) = Self::get_dma_range(p) {
+			return Some( (None, start, last) );
 		}
-		todo!("Bounce buffer for read");
+		let h = Self::alloc_bounce(p.len())?;
+		let (start, last) = Self::bounce_range(&h, p.len());
+		Some( (Some(h), start, last) )
+	}
+
+	/// Queue TDs for a buffer (split so each covers at most two pages, as that's all a TD can address)
+	///
+	/// All but the final TD are a multiple of the packet size, and only the final TD may be short.
+	/// Returns `None` (with nothing queued) if the TDs couldn't be allocated.
+	unsafe fn push_buffer_tds(&self, ep: &EndpointId, flags: u32, buf: &[u8], max_packet_size: usize, waker: &::core::task::Waker) -> Option<(Vec<TransferDescriptorId>, Vec<usize>)>
+	{
+		let mut tds = Vec::new();
+		let mut lens = Vec::new();
+		if buf.len() == 0 {
+			tds.push( self.push_td(ep, flags | (0 << 21) /*immediate int*/, 0, 0, waker.clone())? );
+			lens.push(0);
+		}
+		let max_packet_size = ::core::cmp::max(max_packet_size, 1);
+		let mut ofs = 0;
+		while ofs < buf.len()
+		{
+			let page_ofs = &buf[ofs] as *const u8 as usize & 0xFFF;
+			let len = ::core::cmp::min(buf.len() - ofs, (0x2000 - page_ofs) / max_packet_size * max_packet_size);
+			let td_flags = if ofs + len == buf.len() {
+					flags | hw::GeneralTD::FLAG_ROUNDING | (0 << 21) /*immediate int*/
+				}
+				else {
+					flags | (7 << 21) /*no int*/
+				};
+			let first_phys = ::kernel::memory::virt::get_phys(&buf[ofs]) as u32;
+			let last_phys = ::kernel::memory::virt::get_phys(&buf[ofs + len - 1]) as u32;
+			match self.push_td(ep, td_flags, first_phys, last_phys, waker.clone())
+			{
+			Some(td) => tds.push(td),
+			None => {
+				self.stop_tds(ep, &tds, false);
+				return None;
+				},
+			}
+			lens.push(len);
+			ofs += len;
+		}
+		Some( (tds, lens) )
 	}
 
 	fn get_port_reg(&self, port: usize) -> hw::Regs
@@ -678,6 +1063,28 @@ impl HostInner
 	}
 }
 
+impl ::core::ops::Drop for HostInner
+{
+	fn drop(&mut self)
+	{
+		for p in &self.ed_pools
+		{
+			let p = p.load(Ordering::SeqCst);
+			if !p.is_null() {
+				// SAFE: Came from `Box::into_raw`, and nothing can be using it now
+				drop(unsafe { Box::from_raw(p) });
+			}
+		}
+		for p in &self.td_pools
+		{
+			let p = p.load(Ordering::SeqCst);
+			if !p.is_null() {
+				// SAFE: Came from `Box::into_raw`, and nothing can be using it now
+				drop(unsafe { Box::from_raw(p) });
+			}
+		}
+	}
+}
 
 /// Lock handle on a `hw::Endpoint`
 struct LockedEndpoint<'a> {
@@ -716,10 +1123,10 @@ impl<'a> LockedEndpoint<'a>
 	// SAFE: Read-only, locked
 	pub fn flags   (&self) -> u32 { unsafe { (*self.ptr).flags    } }
 	// SAFE: Read-only, locked
-	//pub fn tail_ptr(&self) -> u32 { unsafe { (*self.ptr).tail_ptr } }
+	pub fn tail_ptr(&self) -> u32 { unsafe { (*self.ptr).tail_ptr } }
 	// NOTE: The controller can write to this value, so use read_volatile
 	// SAFE: Read-only, locked
-	//pub fn head_ptr(&self) -> u32 { unsafe { core::ptr::read_volatile(&(*self.ptr).head_ptr) } }
+	pub fn head_ptr(&self) -> u32 { unsafe { core::ptr::read_volatile(&(*self.ptr).head_ptr) } }
 	// SAFE: Read-only, locked
 	pub fn next_ed (&self) -> u32 { unsafe { (*self.ptr).next_ed  } }
 
@@ -790,59 +1197,102 @@ impl Iterator for UpstreamIntSlots
 }
 
 use ::usb_core::host::{self, EndpointAddr, PortFeature, Handle};
-use ::usb_core::host::{InterruptEndpoint, IsochEndpoint, ControlEndpoint, BulkEndpoint};
+use ::usb_core::host::{InterruptEndpoint, InterruptOutEndpoint, IsochEndpoint, ControlEndpoint, BulkEndpoint};
 impl ::usb_core::host::HostController for UsbHost
 {
-	fn init_interrupt(&self, endpoint: EndpointAddr, period_ms: usize, max_packet_size: usize) -> Handle<dyn InterruptEndpoint> {
+	fn init_interrupt(&self, endpoint: EndpointAddr, max_packet_size: usize, period_ms: usize) -> Option<Handle<dyn InterruptEndpoint>> {
+		// Allocate the buffer first, as endpoint descriptors can't be returned to the schedule
+		let buffer = HostInner::alloc_bounce(max_packet_size)?;
 		// NOTE: This rounds down (so 3 = 2^1)
 		let period_pow_2 = if period_ms == 0 { 0 } else { 32-1 - (period_ms as u32).leading_zeros()};
 		let ptr = self.host.register_interrupt_ed(period_pow_2 as usize,
 			  (endpoint.dev_addr() & 0x7F) as u32
 			| ((endpoint.endpt() & 0xF) << 7) as u32
 			| (0b00 << 11)	// Direction - Use TD
-			| (0b0 << 13)	// Speed (TODO)
+			| self.host.speed_flag(endpoint.dev_addr())	// Speed
 			| (0b0 << 14)	// Skip - clear
 			| (0b0 << 15)	// Format - 0=control/bulk/int
-			// TODO: max packet size?
-			);
-		// TODO: Allocate a pair of buffers (in DMA memory) of `max_packet_size`, use double buffering for them
+			| ((max_packet_size & 0xFFFF) << 16) as u32
+			)?;
 		// NOTE: Don't add TDs until `wait` call
-		Handle::new(InterruptEndpointHandle {
+		Some(Handle::new(InterruptEndpointHandle {
 			controller: self.host.reborrow(),
 			id: ptr,
-			}).ok().unwrap()
+			max_packet_size: max_packet_size,
+			buffer: buffer,
+			last_len: AtomicUsize::new(0),
+			}).ok().unwrap())
 	}
-	fn init_isoch(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<dyn IsochEndpoint> {
-		todo!("init_isoch({:?}, max_packet_size={})", endpoint, max_packet_size);
+	fn init_interrupt_out(&self, endpoint: EndpointAddr, max_packet_size: usize, period_ms: usize) -> Option<Handle<dyn InterruptOutEndpoint>> {
+		let buffer = HostInner::alloc_bounce(::kernel::PAGE_SIZE)?;
+		// NOTE: This rounds down (so 3 = 2^1)
+		let period_pow_2 = if period_ms == 0 { 0 } else { 32-1 - (period_ms as u32).leading_zeros()};
+		let ptr = self.host.register_interrupt_ed(period_pow_2 as usize,
+			  (endpoint.dev_addr() & 0x7F) as u32
+			| ((endpoint.endpt() & 0xF) << 7) as u32
+			| (0b00 << 11)	// Direction - Use TD
+			| self.host.speed_flag(endpoint.dev_addr())	// Speed
+			| (0b0 << 14)	// Skip - clear
+			| (0b0 << 15)	// Format - 0=control/bulk/int
+			| ((max_packet_size & 0xFFFF) << 16) as u32
+			)?;
+		Some(Handle::new(InterruptOutEndpointHandle {
+			controller: self.host.reborrow(),
+			id: ptr,
+			buffer: buffer,
+			busy: AtomicBool::new(false),
+			}).ok().unwrap())
 	}
-	fn init_control(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<dyn ControlEndpoint> {
+	fn init_isoch(&self, endpoint: EndpointAddr, max_packet_size: usize, period: usize) -> Option<Handle<dyn IsochEndpoint>> {
+		// Isochronous TDs (with per-frame offsets) aren't implemented, so isochronous devices need to be on another controller
+		log_notice!("init_isoch({:?}, max_packet_size={}, period={}): Isochronous endpoints aren't supported by this driver",
+			endpoint, max_packet_size, period);
+		None
+	}
+	fn init_control(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Option<Handle<dyn ControlEndpoint>> {
 		// Allocate an endpoint
 		let ptr = self.host.register_control_ed(
 			  (endpoint.dev_addr() & 0x7F) as u32
 			| ((endpoint.endpt() & 0xF) << 7) as u32
 			| (0b00 << 11)	// Direction - Use TD
-			| (0b0 << 13)	// Speed (TODO)
+			| self.host.speed_flag(endpoint.dev_addr())	// Speed
 			| (0b0 << 14)	// Skip - clear
 			| (0b0 << 15)	// Format - 0=control/bulk/int
 			| ((max_packet_size & 0xFFFF) << 16) as u32
-			);
+			)?;
 
-		Handle::new(ControlEndpointHandle {
+		Some(Handle::new(ControlEndpointHandle {
 			controller: self.host.reborrow(),
 			id: ptr,
-			}).ok().unwrap()
+			dev_addr: endpoint.dev_addr(),
+			}).ok().unwrap())
 	}
-	fn init_bulk(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Handle<dyn BulkEndpoint> {
+	fn init_bulk(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Option<Handle<dyn BulkEndpoint>> {
 		let ptr = self.host.register_bulk_ed(
 			  (endpoint.dev_addr() & 0x7F) as u32
 			| ((endpoint.endpt() & 0xF) << 7) as u32
 			| (0b00 << 11)	// Direction - Use TD
-			| (0b0 << 13)	// Speed (TODO)
+			| self.host.speed_flag(endpoint.dev_addr())	// Speed
 			| (0b0 << 14)	// Skip - clear
 			| (0b0 << 15)	// Format - 0=control/bulk/int
 			| ((max_packet_size & 0xFFFF) << 16) as u32
-			);
-		todo!("init_bulk({:?}, max_packet_size={}): {:?}", endpoint, max_packet_size, ptr);
+			)?;
+
+		Some(Handle::new(BulkEndpointHandle {
+			controller: self.host.reborrow(),
+			id: ptr,
+			max_packet_size: max_packet_size,
+			halted: AtomicBool::new(false),
+			}).ok().unwrap())
+	}
+	fn set_address_zero_info(&self, info: host::DeviceInfo) {
+		// A full-speed controller, so only the low-speed flag matters (hubs handle the rest)
+		self.host.set_low_speed(0, info.speed == host::Speed::Low);
+	}
+	fn set_hub_info(&self, _addr: u8, _num_ports: u8, _tt_think_time: u8) {
+	}
+	fn device_removed(&self, addr: u8) {
+		self.host.set_low_speed(addr, false);
 	}
 
 
@@ -901,6 +1351,8 @@ impl ::usb_core::host::HostController for UsbHost
 			PortFeature::Reset       => 0x0010,
 			PortFeature::Power       => 0x0100,
 			PortFeature::LowSpeed    => 0x0200,
+			// Full-speed controller
+			PortFeature::HighSpeed   => return false,
 			PortFeature::CConnection => 0x01_0000,
 			PortFeature::CEnable     => 0x02_0000,
 			PortFeature::CSuspend    => 0x04_0000,
@@ -908,6 +1360,7 @@ impl ::usb_core::host::HostController for UsbHost
 			PortFeature::CReset      => 0x10_0000,
 			PortFeature::Test        => return false,
 			PortFeature::Indicator   => return false,
+			PortFeature::SuperSpeed  => return false,
 			};
 		v & mask != 0
 	}
@@ -944,6 +1397,95 @@ impl ::usb_core::host::HostController for UsbHost
 struct ControlEndpointHandle {
 	controller: ArefBorrow<HostInner>,
 	id: EndpointId,
+	dev_addr: u8,
+}
+/// Transfer descriptors for a queued control transfer (setup, optional data, then status)
+struct ControlTds {
+	tds: Vec<TransferDescriptorId>,
+	has_data: bool,
+}
+impl ControlEndpointHandle
+{
+	/// Queue a control transfer, `data` is the direction and physical range of the data stage
+	///
+	/// Returns `None` (with nothing queued) if the TDs couldn't be allocated
+	// UNSAFE: The buffers must be valid until the transfer completes or is stopped
+	unsafe fn start(&self, setup_data: &[u8], setup: (u32, u32), data: Option<(u32, u32, u32)>, waker: &::core::task::Waker) -> Option<ControlTds>
+	{
+		// Address zero is shared by all new devices, so use the speed of the device being enumerated
+		if self.dev_addr == 0 {
+			self.controller.update_ed_speed(&self.id, 0);
+			// - SET_ADDRESS carries that speed over to the new address
+			if setup_data.len() >= 8 && setup_data[0] == 0 && setup_data[1] == 5 {
+				self.controller.set_low_speed(setup_data[2] & 0x7F, self.controller.speed_flag(0) != 0);
+			}
+		}
+
+		let mut stages = Vec::with_capacity(3);
+		stages.push( ((0b00 << 19) /*setup*/ | (0b10 << 24) /*DATA0*/ | (7 << 21) /*no int*/, setup.0, setup.1) );
+		if let Some((dir, first_phys, last_phys)) = data {
+			stages.push( (dir | (0b11 << 24) /*DATA1*/ | (7 << 21) /*no int*/ | hw::GeneralTD::FLAG_ROUNDING, first_phys, last_phys) );
+		}
+		// Status stage is in the opposite direction to the data (IN if there's no data)
+		let status_dir = match data
+			{
+			Some((dir, _, _)) if dir == 0b10 << 19 => 0b01 << 19,
+			_ => 0b10 << 19,
+			};
+		stages.push( (status_dir | (0b11 << 24) /*DATA1*/ | (0 << 21) /*immediate int*/, 0, 0) );
+
+		let mut tds = Vec::with_capacity(stages.len());
+		for (flags, first_phys, last_phys) in stages
+		{
+			match self.controller.push_td(&self.id, flags, first_phys, last_phys, waker.clone())
+			{
+			Some(td) => tds.push(td),
+			None => {
+				self.controller.stop_tds(&self.id, &tds, false);
+				return None;
+				},
+			}
+		}
+		self.controller.kick_control();
+		Some(ControlTds {
+			tds: tds,
+			has_data: data.is_some(),
+			})
+	}
+	/// Check for completion, returning the unused data space (or `Err` with the condition code if a stage failed)
+	fn check(&self, t: &ControlTds, waker: &::core::task::Waker) -> Option<Result<usize, u32>>
+	{
+		// A failed stage halts the endpoint, so the later stages would never complete
+		for td in &t.tds
+		{
+			if let Some(cc) = self.controller.td_error(td) {
+				return Some(Err(cc));
+			}
+		}
+		if self.controller.td_complete(t.tds.last().unwrap()).is_some()
+		{
+			Some(Ok( if t.has_data { self.controller.td_complete(&t.tds[1]).unwrap() } else { 0 } ))
+		}
+		else
+		{
+			for td in &t.tds
+			{
+				self.controller.td_update_waker(td, waker);
+			}
+			None
+		}
+	}
+	fn release(&self, t: ControlTds)
+	{
+		for td in t.tds
+		{
+			self.controller.release_td(td);
+		}
+	}
+	fn stop(&self, t: &ControlTds)
+	{
+		self.controller.stop_tds(&self.id, &t.tds, false);
+	}
 }
 impl host::ControlEndpoint for ControlEndpointHandle
 {
@@ -955,12 +1497,10 @@ impl host::ControlEndpoint for ControlEndpointHandle
 				out_data: &'a [u8],
 				},
 			Started {
-				_bb_setup: Option<::kernel::memory::virt::AllocHandle>,
-				_bb_data: Option<::kernel::memory::virt::AllocHandle>,
+				_bb_setup: Option<AllocHandle>,
+				_bb_data: Option<AllocHandle>,
 				out_data_len: usize,
-				td_setup: TransferDescriptorId,
-				td_data: TransferDescriptorId,
-				td_status: TransferDescriptorId,
+				tds: ControlTds,
 				},
 			Complete,
 		}
@@ -972,54 +1512,57 @@ impl host::ControlEndpoint for ControlEndpointHandle
 			type Output = usize;
 			fn poll(mut self: core::pin::Pin<&mut Self>, cx: &mut core::task::Context) -> core::task::Poll<Self::Output> {
 				let parent = self.self_;
-				match self.state
+				// NOTE: The state is taken while polling, and put back if the transfer is still pending
+				match core::mem::replace(&mut self.state, FutureState::Complete)
 				{
 				FutureState::Init { setup_data, out_data } => {
 					log_debug!("out_only - init");
 					// Get (potentially bounced) data handles
-					let (setup_buf, setup_first_phys, setup_last_phys) = parent.controller.get_dma_todev(setup_data);
-					let (out_buf, out_first_phys, out_last_phys) = parent.controller.get_dma_todev(out_data);
+					let (setup_buf, setup_first_phys, setup_last_phys) = match parent.controller.get_dma_todev(setup_data)
+						{
+						Some(v) => v,
+						None => return core::task::Poll::Ready(0),
+						};
+					let (out_buf, out_first_phys, out_last_phys) = match parent.controller.get_dma_todev(out_data)
+						{
+						Some(v) => v,
+						None => return core::task::Poll::Ready(0),
+						};
+					let data = if out_data.len() > 0 { Some( (0b01 << 19 /*out*/, out_first_phys, out_last_phys) ) } else { None };
 
 					// TODO: This isn't 100% safe, as the future _could_ be leaked before completion
 					// SAFE: Requires that the future isn't leaked
-					unsafe {
-						use kernel::futures::null_waker;
-						let td_setup = parent.controller.push_td( &parent.id, (0b00 << 19) /*setup*/ | hw::GeneralTD::FLAG_AUTOFREE | (7 << 21) /*no int*/, setup_first_phys, setup_last_phys, null_waker() );
-						let td_data  = parent.controller.push_td( &parent.id, (0b01 << 19) /*out*/ | (7 << 21) /*no int*/, out_first_phys, out_last_phys, null_waker() );
-						let td_status= parent.controller.push_td( &parent.id, (0b10 << 19) /*in*/ | (0 << 21) /*immediate int*/, 0, 0, cx.waker().clone() );
-						parent.controller.kick_control();
-						self.state = FutureState::Started {
-							_bb_setup: setup_buf,
-							_bb_data: out_buf,
-							out_data_len: out_data.len(),
-							td_setup,
-							td_data,
-							td_status,
-							};
-					}
+					let tds = match unsafe { parent.start(setup_data, (setup_first_phys, setup_last_phys), data, cx.waker()) }
+						{
+						Some(v) => v,
+						None => return core::task::Poll::Ready(0),
+						};
+					self.state = FutureState::Started {
+						_bb_setup: setup_buf,
+						_bb_data: out_buf,
+						out_data_len: out_data.len(),
+						tds,
+						};
 					core::task::Poll::Pending
 					},
-				FutureState::Started { ref td_status, .. } =>
-					if parent.controller.td_complete(td_status).is_some()
-					{
-						log_debug!("out_only - Started -> complete");
-						match core::mem::replace(&mut self.state, FutureState::Complete)
-						{
-						FutureState::Started { td_data, td_status, out_data_len, .. } => {
-							let spare_size = parent.controller.td_complete(&td_data).unwrap();
-							log_debug!("out_only - out_data_len={}, spare_size={}", out_data_len, spare_size);
-							parent.controller.release_td(td_data);
-							parent.controller.release_td(td_status);
-							core::task::Poll::Ready(out_data_len)
-							},
-						_ => panic!(),
-						}
-					}
-					else
+				FutureState::Started { _bb_setup, _bb_data, out_data_len, tds } =>
+					match parent.check(&tds, cx.waker())
 					{
+					Some(Ok(spare_size)) => {
+						log_debug!("out_only - out_data_len={}, spare_size={}", out_data_len, spare_size);
+						parent.release(tds);
+						core::task::Poll::Ready(out_data_len)
+						},
+					Some(Err(cc)) => {
+						log_notice!("out_only - Transfer to device {} failed: condition code {}", parent.dev_addr, cc);
+						parent.stop(&tds);
+						core::task::Poll::Ready(0)
+						},
+					None => {
 						log_debug!("out_only - Started -> pending");
-						parent.controller.td_update_waker(td_status, cx.waker());
+						self.state = FutureState::Started { _bb_setup, _bb_data, out_data_len, tds };
 						core::task::Poll::Pending
+						},
 					},
 				FutureState::Complete => panic!("Completed future polled"),
 				}
@@ -1031,11 +1574,7 @@ impl host::ControlEndpoint for ControlEndpointHandle
 				match self.state
 				{
 				FutureState::Init { .. } => {},
-				FutureState::Started { ref td_setup, ref td_data, .. } => {
-					// TODO: Force termination of the transfers
-					self.self_.controller.stop_td(td_setup);
-					self.self_.controller.stop_td(td_data);
-					}
+				FutureState::Started { ref tds, .. } => self.self_.stop(tds),
 				FutureState::Complete => {},
 				}
 			}
@@ -1058,12 +1597,10 @@ impl host::ControlEndpoint for ControlEndpointHandle
 				in_data: &'a mut [u8],
 				},
 			Started {
-				_bb_setup: Option<::kernel::memory::virt::AllocHandle>,
-				bb_data: Option<::kernel::memory::virt::AllocHandle>,
+				_bb_setup: Option<AllocHandle>,
+				bb_data: Option<AllocHandle>,
 				in_data: &'a mut [u8],
-				td_setup: TransferDescriptorId,
-				td_data: TransferDescriptorId,
-				td_status: TransferDescriptorId,
+				tds: ControlTds,
 				},
 			Complete,
 		}
@@ -1075,62 +1612,65 @@ impl host::ControlEndpoint for ControlEndpointHandle
 			type Output = usize;
 			fn poll(mut self: core::pin::Pin<&mut Self>, cx: &mut core::task::Context) -> core::task::Poll<usize> {
 				let parent = self.self_;
-				match self.state
+				// NOTE: The state is taken while polling, and put back if the transfer is still pending
+				match core::mem::replace(&mut self.state, FutureState::Complete)
 				{
-				FutureState::Init { setup_data, ref mut in_data } => {
+				FutureState::Init { setup_data, in_data } => {
 					log_debug!("in_only - init");
-					let in_data = ::core::mem::replace(in_data, &mut []);
 					// Get (potentially bounced) data handles
-					let (setup_buf, setup_first_phys, setup_last_phys) = parent.controller.get_dma_todev(setup_data);
-					let (in_buf, in_first_phys, in_last_phys) = parent.controller.get_dma_fromdev(in_data);
+					let (setup_buf, setup_first_phys, setup_last_phys) = match parent.controller.get_dma_todev(setup_data)
+						{
+						Some(v) => v,
+						None => return core::task::Poll::Ready(0),
+						};
+					let (in_buf, in_first_phys, in_last_phys) = match parent.controller.get_dma_fromdev(in_data)
+						{
+						Some(v) => v,
+						None => return core::task::Poll::Ready(0),
+						};
+					let data = if in_data.len() > 0 { Some( (0b10 << 19 /*in*/, in_first_phys, in_last_phys) ) } else { None };
 
 					// TODO: This isn't 100% safe, as the future _could_ be leaked before completion
 					// SAFE: Requires that the future isn't leaked
-					unsafe {
-						use kernel::futures::null_waker;
-						let td_setup = parent.controller.push_td( &parent.id, (0b00 << 19) /*setup*/ | hw::GeneralTD::FLAG_AUTOFREE | (7 << 21) /*no int*/, setup_first_phys, setup_last_phys, null_waker() );
-						let td_data  = parent.controller.push_td( &parent.id, (0b10 << 19) /*in*/ | (7 << 21) /*no int*/ | (1 << 18), in_first_phys, in_last_phys, null_waker() );
-						let td_status= parent.controller.push_td( &parent.id, (0b01 << 19) /*out*/ | (0 << 21) /*immediate int*/, 0, 0, cx.waker().clone() );
-						parent.controller.kick_control();
-						self.state = FutureState::Started {
-							_bb_setup: setup_buf,
-							bb_data: in_buf,
-							in_data: in_data,
-							td_setup,
-							td_data,
-							td_status,
-							};
-					}
+					let tds = match unsafe { parent.start(setup_data, (setup_first_phys, setup_last_phys), data, cx.waker()) }
+						{
+						Some(v) => v,
+						None => return core::task::Poll::Ready(0),
+						};
+					self.state = FutureState::Started {
+						_bb_setup: setup_buf,
+						bb_data: in_buf,
+						in_data: in_data,
+						tds,
+						};
 					core::task::Poll::Pending
 					},
-				FutureState::Started { ref td_status, .. } =>
-					if parent.controller.td_complete(td_status).is_some()
+				FutureState::Started { _bb_setup, bb_data, in_data, tds } =>
+					match parent.check(&tds, cx.waker())
 					{
-						match core::mem::replace(&mut self.state, FutureState::Complete)
-						{
-						FutureState::Started { td_data, td_status, bb_data, in_data, .. } => {
-							let rem_size = parent.controller.td_complete(&td_data).unwrap();
-							assert!(rem_size <= in_data.len(), "{} <= {}", rem_size, in_data.len());
-							let read_len = if rem_size == in_data.len() { in_data.len() } else { in_data.len() - rem_size };
-							log_debug!("in_only - completed {} (read {})", rem_size, read_len);
-							if let Some(r) = bb_data {
-								in_data.copy_from_slice( r.as_slice(0, in_data.len()) );
-							}
-
-							parent.controller.release_td(td_data);
-							parent.controller.release_td(td_status);
-							core::task::Poll::Ready(read_len)
-							},
-						_ => panic!(""),
+					Some(Ok(rem_size)) => {
+						assert!(rem_size <= in_data.len(), "{} <= {}", rem_size, in_data.len());
+						let read_len = in_data.len() - rem_size;
+						log_debug!("in_only - completed {} (read {})", rem_size, read_len);
+						if let Some(r) = bb_data {
+							in_data[..read_len].copy_from_slice( r.as_slice(0, read_len) );
 						}
-					}
-					else
-					{
+
+						parent.release(tds);
+						core::task::Poll::Ready(read_len)
+						},
+					Some(Err(cc)) => {
+						log_notice!("in_only - Transfer from device {} failed: condition code {}", parent.dev_addr, cc);
+						parent.stop(&tds);
+						core::task::Poll::Ready(0)
+						},
+					None => {
 						log_debug!("in_only - pending");
-						parent.controller.td_update_waker(td_status, cx.waker());
+						self.state = FutureState::Started { _bb_setup, bb_data, in_data, tds };
 						core::task::Poll::Pending
+						},
 					},
-				FutureState::Complete => panic!("Complete"),
+				FutureState::Complete => panic!("Completed future polled"),
 				}
 			}
 		}
@@ -1140,11 +1680,7 @@ impl host::ControlEndpoint for ControlEndpointHandle
 				match self.state
 				{
 				FutureState::Init { .. } => {},
-				FutureState::Started { ref td_setup, ref td_data, .. } => {
-					// TODO: Force termination of the transfers
-					self.self_.controller.stop_td(td_setup);
-					self.self_.controller.stop_td(td_data);
-					}
+				FutureState::Started { ref tds, .. } => self.self_.stop(tds),
 				FutureState::Complete => {},
 				}
 			}
@@ -1161,19 +1697,402 @@ impl host::ControlEndpoint for ControlEndpointHandle
 	}
 }
 
+struct BulkEndpointHandle {
+	controller: ArefBorrow<HostInner>,
+	id: EndpointId,
+	max_packet_size: usize,
+	/// The most recent transfer was stalled by the device
+	halted: AtomicBool,
+}
+enum BulkData<'a> {
+	Out(&'a [u8]),
+	In(&'a mut [u8]),
+}
+enum BulkState<'a> {
+	Init(BulkData<'a>),
+	Started {
+		/// Bounce buffer (used if any of the buffer isn't 32-bit addressable)
+		bounce: Option<AllocHandle>,
+		/// Destination for a bounced read
+		in_data: Option<&'a mut [u8]>,
+		tds: Vec<TransferDescriptorId>,
+		lens: Vec<usize>,
+		},
+	Complete,
+}
+struct BulkFuture<'a> {
+	self_: &'a BulkEndpointHandle,
+	state: BulkState<'a>,
+}
+impl<'a> BulkFuture<'a>
+{
+	fn new(self_: &'a BulkEndpointHandle, data: BulkData<'a>) -> host::AsyncWaitIo<'a>
+	{
+		host::AsyncWaitIo::new(BulkFuture {
+			self_: self_,
+			state: BulkState::Init(data),
+			})
+			.or_else(|v| host::AsyncWaitIo::new(Box::new(v)))
+			.ok().expect("Box doesn't fit in alloc")
+	}
+}
+impl<'a> core::future::Future for BulkFuture<'a>
+{
+	type Output = usize;
+	fn poll(mut self: core::pin::Pin<&mut Self>, cx: &mut core::task::Context) -> core::task::Poll<usize> {
+		let parent = self.self_;
+		// NOTE: The state is taken while polling, and put back if the transfer is still pending
+		match ::core::mem::replace(&mut self.state, BulkState::Complete)
+		{
+		BulkState::Init(data) => {
+			parent.halted.store(false, Ordering::SeqCst);
+			// TODO: This isn't 100% safe, as the future _could_ be leaked before completion
+			// SAFE: Requires that the future isn't leaked
+			let state = unsafe {
+				match data
+				{
+				BulkData::Out(buf) => {
+					let bounce = if HostInner::is_dma32(buf) {
+							None
+						}
+						else {
+							let mut h = match HostInner::alloc_bounce(buf.len())
+								{
+								Some(v) => v,
+								None => return core::task::Poll::Ready(0),
+								};
+							h.as_mut_slice::<u8>(0, buf.len()).copy_from_slice(buf);
+							Some(h)
+						};
+					let tds = {
+						let buf = match bounce { Some(ref h) => h.as_slice(0, buf.len()), None => buf };
+						parent.controller.push_buffer_tds(&parent.id, 0b01 << 19 /*out*/, buf, parent.max_packet_size, cx.waker())
+						};
+					let (tds, lens) = match tds
+						{
+						Some(v) => v,
+						None => return core::task::Poll::Ready(0),
+						};
+					BulkState::Started { bounce: bounce, in_data: None, tds: tds, lens: lens }
+					},
+				BulkData::In(buf) => {
+					let bounce = if HostInner::is_dma32(buf) {
+							None
+						}
+						else {
+							match HostInner::alloc_bounce(buf.len())
+							{
+							Some(v) => Some(v),
+							None => return core::task::Poll::Ready(0),
+							}
+						};
+					let tds = {
+						let dma_buf: &[u8] = match bounce { Some(ref h) => h.as_slice(0, buf.len()), None => &*buf };
+						parent.controller.push_buffer_tds(&parent.id, 0b10 << 19 /*in*/, dma_buf, parent.max_packet_size, cx.waker())
+						};
+					let (tds, lens) = match tds
+						{
+						Some(v) => v,
+						None => return core::task::Poll::Ready(0),
+						};
+					let in_data = if bounce.is_some() { Some(buf) } else { None };
+					BulkState::Started { bounce: bounce, in_data: in_data, tds: tds, lens: lens }
+					},
+				}
+				};
+			parent.controller.kick_bulk();
+			self.state = state;
+			core::task::Poll::Pending
+			},
+		BulkState::Started { bounce, in_data, mut tds, lens } => {
+			// Sum the transferred length, stopping at the first incomplete or failed TD
+			let mut total = 0;
+			let mut error = None;
+			let mut n_done = 0;
+			for (td, &len) in Iterator::zip(tds.iter(), lens.iter())
+			{
+				match parent.controller.td_complete(td)
+				{
+				Some(rem) => {
+					total += len - ::core::cmp::min(rem, len);
+					n_done += 1;
+					if let Some(cc) = parent.controller.td_error(td) {
+						error = Some(cc);
+						break;
+					}
+				},
+				None => break,
+				}
+			}
+			if error.is_none() && n_done < tds.len()
+			{
+				for td in &tds[n_done..]
+				{
+					parent.controller.td_update_waker(td, cx.waker());
+				}
+				self.state = BulkState::Started { bounce, in_data, tds, lens };
+				return core::task::Poll::Pending;
+			}
+
+			let rest = tds.split_off(n_done);
+			for td in tds
+			{
+				parent.controller.release_td(td);
+			}
+			// An error (or a short packet before the final TD) halts the endpoint with the rest still queued
+			match error
+			{
+			None => {},
+			Some(hw::CC_DATAUNDERRUN) => parent.controller.stop_tds(&parent.id, &rest, false),
+			Some(cc) => {
+				log_notice!("Bulk transfer on {:?} failed: condition code {}", parent.id, cc);
+				parent.controller.stop_tds(&parent.id, &rest, cc == hw::CC_STALL);
+				if cc == hw::CC_STALL {
+					parent.halted.store(true, Ordering::SeqCst);
+				}
+				},
+			}
+			if let (Some(h), Some(dst)) = (bounce, in_data) {
+				dst[..total].copy_from_slice( h.as_slice(0, total) );
+			}
+			core::task::Poll::Ready(total)
+			},
+		BulkState::Complete => panic!("Completed future polled"),
+		}
+	}
+}
+impl<'a> core::ops::Drop for BulkFuture<'a>
+{
+	fn drop(&mut self)
+	{
+		match self.state
+		{
+		BulkState::Init(..) => {},
+		BulkState::Started { ref tds, .. } => self.self_.controller.stop_tds(&self.self_.id, tds, false),
+		BulkState::Complete => {},
+		}
+	}
+}
+impl host::BulkEndpoint for BulkEndpointHandle
+{
+	fn send<'a>(&'a self, buffer: &'a [u8]) -> host::AsyncWaitIo<'a>
+	{
+		BulkFuture::new(self, BulkData::Out(buffer))
+	}
+	fn recv<'a>(&'a self, buffer: &'a mut [u8]) -> host::AsyncWaitIo<'a>
+	{
+		BulkFuture::new(self, BulkData::In(buffer))
+	}
+	fn is_halted(&self) -> bool
+	{
+		self.halted.load(Ordering::SeqCst)
+	}
+	fn reset(&self)
+	{
+		// Clear the ED's halt bit and reset the toggle carry (no TDs to remove)
+		self.controller.stop_tds(&self.id, &[], true);
+		self.halted.store(false, Ordering::SeqCst);
+	}
+}
+
 struct InterruptEndpointHandle {
 	controller: ArefBorrow<HostInner>,
 	id: EndpointId,
+	max_packet_size: usize,
+	/// Receive buffer (32-bit addressable), filled by each poll
+	buffer: AllocHandle,
+	/// Length of the most recently received data
+	last_len: AtomicUsize,
+}
+/// Future for a single poll of an interrupt IN endpoint
+struct InterruptFuture<'a> {
+	self_: &'a InterruptEndpointHandle,
+	/// Queued TD (`None` before the first poll and after completion)
+	td: Option<TransferDescriptorId>,
+}
+impl<'a> core::future::Future for InterruptFuture<'a>
+{
+	type Output = usize;
+	fn poll(mut self: core::pin::Pin<&mut Self>, cx: &mut core::task::Context) -> core::task::Poll<usize> {
+		let parent = self.self_;
+		if self.td.is_none()
+		{
+			let (first, last) = HostInner::bounce_range(&parent.buffer, parent.max_packet_size);
+			// NOTE: The controller polls interrupt EDs itself, so no kick is needed
+			// SAFE: The buffer is owned by the endpoint, which outlives this future (and the TD is stopped on drop)
+			let td = unsafe { parent.controller.push_td(&parent.id, (0b10 << 19) /*in*/ | hw::GeneralTD::FLAG_ROUNDING | (0 << 21) /*immediate int*/, first, last, cx.waker().clone()) };
+			if td.is_none() {
+				return core::task::Poll::Ready(0);
+			}
+			self.td = td;
+			return core::task::Poll::Pending;
+		}
+		let td = self.td.as_ref().unwrap();
+
+		if let Some(cc) = parent.controller.td_error(td)
+		{
+			// The TD has been retired, so release it and clear the halt (resetting the toggle on a STALL)
+			log_notice!("Interrupt transfer on {:?} failed: condition code {}", parent.id, cc);
+			let td = self.td.take().unwrap();
+			parent.controller.release_td(td);
+			parent.controller.stop_tds(&parent.id, &[], cc == hw::CC_STALL);
+			core::task::Poll::Ready(0)
+		}
+		else if let Some(rem) = parent.controller.td_complete(td)
+		{
+			let len = parent.max_packet_size - ::core::cmp::min(rem, parent.max_packet_size);
+			parent.last_len.store(len, Ordering::SeqCst);
+			let td = self.td.take().unwrap();
+			parent.controller.release_td(td);
+			core::task::Poll::Ready(len)
+		}
+		else
+		{
+			parent.controller.td_update_waker(td, cx.waker());
+			core::task::Poll::Pending
+		}
+	}
+}
+impl<'a> core::ops::Drop for InterruptFuture<'a>
+{
+	fn drop(&mut self)
+	{
+		if let Some(td) = self.td.take() {
+			self.self_.controller.stop_tds(&self.self_.id, &[td], false);
+		}
+	}
 }
 impl host::InterruptEndpoint for InterruptEndpointHandle
 {
 	fn get_data(&self) -> Handle<dyn usb_core::handle::RemoteBuffer>
 	{
-		todo!("");
+		let len = self.last_len.load(Ordering::SeqCst);
+		let data = self.buffer.as_slice::<u8>(0, len).to_vec().into_boxed_slice();
+		Handle::new(DataCopy(data)).ok().expect("Over-size data handle")
 	}
 	fn wait<'a>(&'a self) -> ::usb_core::host::AsyncWaitIo<'a>
 	{
-		todo!("");
+		host::AsyncWaitIo::new(InterruptFuture {
+			self_: self,
+			td: None,
+			})
+			.or_else(|v| host::AsyncWaitIo::new(Box::new(v)))
+			.ok().expect("Box doesn't fit in alloc")
+	}
+}
+
+struct InterruptOutEndpointHandle {
+	controller: ArefBorrow<HostInner>,
+	id: EndpointId,
+	/// Transmit buffer (32-bit addressable, one page)
+	buffer: AllocHandle,
+	/// Set while a transfer is using the buffer
+	busy: AtomicBool,
+}
+/// Future for a single interrupt OUT transfer
+struct InterruptOutFuture<'a> {
+	self_: &'a InterruptOutEndpointHandle,
+	data: &'a [u8],
+	/// Queued TD (`None` before the first poll and after completion)
+	td: Option<TransferDescriptorId>,
+	/// Set once this future owns the endpoint's buffer
+	claimed: bool,
+}
+impl<'a> core::future::Future for InterruptOutFuture<'a>
+{
+	type Output = usize;
+	fn poll(mut self: core::pin::Pin<&mut Self>, cx: &mut core::task::Context) -> core::task::Poll<usize> {
+		let parent = self.self_;
+		let len = self.data.len();
+		if self.td.is_none()
+		{
+			if len > ::kernel::PAGE_SIZE {
+				log_error!("Interrupt OUT transfer on {:?} too large ({} bytes)", parent.id, len);
+				return core::task::Poll::Ready(0);
+			}
+			if parent.busy.swap(true, Ordering::SeqCst) {
+				log_error!("Interrupt OUT endpoint {:?} already has a transfer in progress", parent.id);
+				return core::task::Poll::Ready(0);
+			}
+			self.claimed = true;
+			// SAFE: The buffer is only accessed by the future that claimed it
+			unsafe { parent.buffer.as_int_mut_slice::<u8>(0, len).copy_from_slice(self.data); }
+			let (first, last) = if len == 0 { (0, 0) } else { HostInner::bounce_range(&parent.buffer, len) };
+			// SAFE: The buffer is owned by the endpoint, which outlives this future (and the TD is stopped on drop)
+			let td = unsafe { parent.controller.push_td(&parent.id, (0b01 << 19) /*out*/ | (0 << 21) /*immediate int*/, first, last, cx.waker().clone()) };
+			if td.is_none() {
+				self.claimed = false;
+				parent.busy.store(false, Ordering::SeqCst);
+				return core::task::Poll::Ready(0);
+			}
+			self.td = td;
+			return core::task::Poll::Pending;
+		}
+		let td = self.td.as_ref().unwrap();
+
+		let rv = if let Some(cc) = parent.controller.td_error(td)
+			{
+				log_notice!("Interrupt OUT transfer on {:?} failed: condition code {}", parent.id, cc);
+				let td = self.td.take().unwrap();
+				parent.controller.release_td(td);
+				parent.controller.stop_tds(&parent.id, &[], cc == hw::CC_STALL);
+				0
+			}
+			else if let Some(rem) = parent.controller.td_complete(td)
+			{
+				let td = self.td.take().unwrap();
+				parent.controller.release_td(td);
+				len - ::core::cmp::min(rem, len)
+			}
+			else
+			{
+				parent.controller.td_update_waker(td, cx.waker());
+				return core::task::Poll::Pending;
+			};
+		self.claimed = false;
+		parent.busy.store(false, Ordering::SeqCst);
+		core::task::Poll::Ready(rv)
+	}
+}
+impl<'a> core::ops::Drop for InterruptOutFuture<'a>
+{
+	fn drop(&mut self)
+	{
+		if let Some(td) = self.td.take() {
+			self.self_.controller.stop_tds(&self.self_.id, &[td], false);
+		}
+		if self.claimed {
+			self.self_.busy.store(false, Ordering::SeqCst);
+		}
+	}
+}
+impl host::InterruptOutEndpoint for InterruptOutEndpointHandle
+{
+	fn send<'a>(&'a self, buffer: &'a [u8]) -> host::AsyncWaitIo<'a>
+	{
+		host::AsyncWaitIo::new(InterruptOutFuture {
+			self_: self,
+			data: buffer,
+			td: None,
+			claimed: false,
+			})
+			.or_else(|v| host::AsyncWaitIo::new(Box::new(v)))
+			.ok().expect("Box doesn't fit in alloc")
+	}
+}
+
+/// Copy of received interrupt data
+struct DataCopy(Box<[u8]>);
+impl usb_core::handle::RemoteFree for DataCopy
+{
+	unsafe fn free_self(&mut self) {
+		// Freed when the handle is dropped
+	}
+}
+impl usb_core::handle::RemoteBuffer for DataCopy
+{
+	fn get(&self) -> &[u8] {
+		&self.0
 	}
 }
 
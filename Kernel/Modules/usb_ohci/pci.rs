@@ -25,7 +25,19 @@ impl device_manager::Driver for PciDriver {
 		let irq = bus_dev.get_irq(0);
 		let base = bus_dev.bind_io(0);
 
-		::BusDev::new_boxed(irq, base).expect("ohci")
+		match ::BusDev::new_boxed(irq, base)
+		{
+		Ok(v) => v,
+		Err(e) => {
+			log_error!("Unable to initialise OHCI controller - {}", e);
+			Box::new(NullDevice)
+			},
+		}
 	}
 }
 
+/// Placeholder for a controller that failed to initialise
+struct NullDevice;
+impl device_manager::DriverInstance for NullDevice {
+}
+
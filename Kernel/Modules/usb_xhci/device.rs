@@ -0,0 +1,164 @@
+// "Tifflin" Kernel - xHCI USB driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_xhci/device.rs
+//! Device slots and contexts
+use kernel::prelude::*;
+use kernel::lib::mem::Arc;
+use kernel::sync::Spinlock;
+use kernel::memory::virt::AllocHandle;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering};
+use crate::hw;
+use crate::ring::ProducerRing;
+
+/// Number of device context indexes (DCI 0 is the slot context, 1 is the control endpoint)
+const MAX_DCI: usize = 32;
+/// Set in `Slot::hub` once the device is known to be a hub
+const HUB_VALID: u32 = 1 << 16;
+
+/// State for an enabled device slot
+pub struct Slot
+{
+	pub id: u8,
+	pub speed: hw::Speed,
+	/// Root hub port number (1-based)
+	pub root_port: u8,
+	/// Route string (port numbers on each hub tier below the root port, zero for a root port device)
+	pub route: u32,
+	/// Hub fields for the slot context (zero if not a hub), see `set_hub`
+	hub: AtomicU32,
+	/// The hub fields have been passed to the controller
+	pub hub_configured: AtomicBool,
+	/// Device context (written by the controller)
+	output_ctx: AllocHandle,
+	/// Transfer rings, indexed by DCI
+	rings: Vec<Spinlock<Option<Arc<ProducerRing>>>>,
+	/// Highest DCI configured (for the slot context's "Context Entries" field)
+	max_dci: AtomicU8,
+}
+
+impl Slot
+{
+	/// Create a slot, along with the transfer ring for the control endpoint
+	pub fn new(dma_bits: u8, id: u8, speed: hw::Speed, root_port: u8, route: u32) -> Result<Slot, &'static str>
+	{
+		let rv = Slot {
+			id: id,
+			speed: speed,
+			root_port: root_port,
+			route: route,
+			hub: AtomicU32::new(0),
+			hub_configured: AtomicBool::new(false),
+			output_ctx: crate::alloc_dma_zeroed(dma_bits)?,
+			rings: (0 .. MAX_DCI).map(|_| Spinlock::new(None)).collect(),
+			max_dci: AtomicU8::new(1),
+			};
+		rv.set_ring(1, Arc::new(ProducerRing::new(dma_bits)?));
+		Ok(rv)
+	}
+
+	/// Physical address of the output device context (for the DCBAA)
+	pub fn output_phys(&self) -> u64 {
+		::kernel::memory::virt::get_phys(self.output_ctx.as_ref::<u32>(0)) as u64
+	}
+	/// Read a dword from the output device context
+	pub fn read_output(&self, ofs: usize) -> u32 {
+		// SAFE: Volatile read of DMA memory
+		unsafe { ::core::ptr::read_volatile(self.output_ctx.as_ref::<u32>(ofs)) }
+	}
+
+	pub fn ring(&self, dci: u8) -> Option<Arc<ProducerRing>> {
+		self.rings.get(dci as usize)?.lock().clone()
+	}
+	pub fn set_ring(&self, dci: u8, ring: Arc<ProducerRing>) {
+		*self.rings[dci as usize].lock() = Some(ring);
+	}
+	/// Mark the device as a hub (applied to the slot context by `write_hub_fields`)
+	pub fn set_hub(&self, num_ports: u8, tt_think_time: u8) {
+		self.hub.store(HUB_VALID | (tt_think_time as u32 & 3) << 8 | num_ports as u32, Ordering::SeqCst);
+	}
+	pub fn is_hub(&self) -> bool {
+		self.hub.load(Ordering::SeqCst) != 0
+	}
+	/// Set the hub fields ("Hub", "Number of Ports" and "TT Think Time") in a copy of the slot context
+	pub fn write_hub_fields(&self, s: &mut [u32]) {
+		let v = self.hub.load(Ordering::SeqCst);
+		if v == 0 {
+			return ;
+		}
+		s[0] |= hw::SLOT_DW0_HUB;
+		s[1] = (s[1] & !(0xFF << hw::SLOT_DW1_NUM_PORTS_ofs)) | (v & 0xFF) << hw::SLOT_DW1_NUM_PORTS_ofs;
+		// - Only meaningful for high-speed hubs
+		if self.speed == hw::Speed::High {
+			s[2] = (s[2] & !(3 << hw::SLOT_DW2_TTT_ofs)) | ((v >> 8) & 3) << hw::SLOT_DW2_TTT_ofs;
+		}
+	}
+	/// Record that `dci` is in use, returning the new number of context entries
+	pub fn add_dci(&self, dci: u8) -> u8 {
+		let mut cur = self.max_dci.load(Ordering::SeqCst);
+		while cur < dci
+		{
+			match self.max_dci.compare_exchange(cur, dci, Ordering::SeqCst, Ordering::SeqCst)
+			{
+			Ok(_) => return dci,
+			Err(v) => cur = v,
+			}
+		}
+		cur
+	}
+}
+
+/// Input context, as passed to the "Address Device", "Configure Endpoint" and "Evaluate Context" commands
+pub struct InputContext
+{
+	page: AllocHandle,
+	context_size: usize,
+}
+impl InputContext
+{
+	pub fn new(dma_bits: u8, context_size: usize) -> Result<InputContext, &'static str>
+	{
+		Ok(InputContext {
+			page: crate::alloc_dma_zeroed(dma_bits)?,
+			context_size: context_size,
+			})
+	}
+	pub fn phys(&self) -> u64 {
+		::kernel::memory::virt::get_phys(self.page.as_ref::<u32>(0)) as u64
+	}
+
+	/// Set the "Add Context" flags (bit N is DCI N, bit 0 is the slot context)
+	pub fn set_add_flags(&mut self, flags: u32) {
+		*self.page.as_mut::<u32>(4) = flags;
+	}
+	pub fn slot_mut(&mut self) -> &mut [u32] {
+		let csz = self.context_size;
+		self.page.as_mut_slice(csz, csz / 4)
+	}
+	pub fn endpoint_mut(&mut self, dci: u8) -> &mut [u32] {
+		let csz = self.context_size;
+		self.page.as_mut_slice((dci as usize + 1) * csz, csz / 4)
+	}
+}
+
+/// Populate an endpoint context
+///
+/// `dequeue` is the transfer ring's dequeue pointer, with the cycle state in bit 0
+pub fn fill_endpoint(ctx: &mut [u32], ep_type: u32, max_packet_size: usize, max_burst: u8, interval: u8, dequeue: u64, avg_trb_length: usize, max_esit_payload: usize)
+{
+	// Isochronous endpoints aren't retried, so must have an error count of zero
+	let cerr = match ep_type
+		{
+		hw::EP_TYPE_ISOCH_IN | hw::EP_TYPE_ISOCH_OUT => 0,
+		_ => 3,
+		};
+	ctx[0] = (interval as u32) << hw::EP_DW0_INTERVAL_ofs;
+	ctx[1] = cerr << hw::EP_DW1_CERR_ofs
+		| ep_type << hw::EP_DW1_TYPE_ofs
+		| (max_burst as u32) << hw::EP_DW1_MAX_BURST_ofs
+		| (max_packet_size as u32 & 0xFFFF) << hw::EP_DW1_MPS_ofs
+		;
+	ctx[2] = dequeue as u32;
+	ctx[3] = (dequeue >> 32) as u32;
+	ctx[4] = (avg_trb_length as u32 & 0xFFFF) | (max_esit_payload as u32 & 0xFFFF) << hw::EP_DW4_ESIT_ofs;
+}
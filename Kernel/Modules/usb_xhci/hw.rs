@@ -0,0 +1,260 @@
+// "Tifflin" Kernel - xHCI USB driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_xhci/hw.rs
+//! xHCI hardware definitions (registers, TRBs and contexts)
+
+// --- Capability registers (offsets from BAR0)
+pub const CAP_CAPLENGTH: usize = 0x00;	// u8 (with HCIVERSION in the upper 16 bits)
+pub const CAP_HCSPARAMS1: usize = 0x04;
+pub const CAP_HCSPARAMS2: usize = 0x08;
+pub const CAP_HCCPARAMS1: usize = 0x10;
+pub const CAP_DBOFF: usize = 0x14;
+pub const CAP_RTSOFF: usize = 0x18;
+
+/// HCCPARAMS1 - 64-bit addressing capable
+pub const HCCPARAMS1_AC64: u32 = 1 << 0;
+/// HCCPARAMS1 - Contexts are 64 bytes (instead of 32)
+pub const HCCPARAMS1_CSZ: u32 = 1 << 2;
+pub const HCCPARAMS1_XECP_ofs: u32 = 16;
+
+// --- Operational registers (offsets from CAPLENGTH)
+pub const OP_USBCMD: usize = 0x00;
+pub const OP_USBSTS: usize = 0x04;
+pub const OP_PAGESIZE: usize = 0x08;
+pub const OP_CRCR: usize = 0x18;
+pub const OP_DCBAAP: usize = 0x30;
+pub const OP_CONFIG: usize = 0x38;
+pub const OP_PORTSC_BASE: usize = 0x400;
+pub const OP_PORT_STRIDE: usize = 0x10;
+
+pub const USBCMD_RS: u32 = 1 << 0;
+pub const USBCMD_HCRST: u32 = 1 << 1;
+pub const USBCMD_INTE: u32 = 1 << 2;
+pub const USBCMD_HSEE: u32 = 1 << 3;
+
+pub const USBSTS_HCH: u32 = 1 << 0;
+pub const USBSTS_HSE: u32 = 1 << 2;
+pub const USBSTS_EINT: u32 = 1 << 3;
+pub const USBSTS_PCD: u32 = 1 << 4;
+pub const USBSTS_CNR: u32 = 1 << 11;
+pub const USBSTS_HCE: u32 = 1 << 12;
+
+/// CRCR - Ring Cycle State
+pub const CRCR_RCS: u64 = 1 << 0;
+
+// PORTSC bits
+pub const PORTSC_CCS: u32 = 1 << 0;
+pub const PORTSC_PED: u32 = 1 << 1;
+pub const PORTSC_OCA: u32 = 1 << 3;
+pub const PORTSC_PR: u32 = 1 << 4;
+pub const PORTSC_PLS_ofs: u32 = 5;
+pub const PORTSC_PP: u32 = 1 << 9;
+pub const PORTSC_SPEED_ofs: u32 = 10;
+pub const PORTSC_CSC: u32 = 1 << 17;
+pub const PORTSC_PEC: u32 = 1 << 18;
+pub const PORTSC_WRC: u32 = 1 << 19;
+pub const PORTSC_OCC: u32 = 1 << 20;
+pub const PORTSC_PRC: u32 = 1 << 21;
+pub const PORTSC_PLC: u32 = 1 << 22;
+pub const PORTSC_LWS: u32 = 1 << 16;
+pub const PORTSC_CEC: u32 = 1 << 23;
+/// All of the write-1-to-clear change bits
+pub const PORTSC_CHANGE_MASK: u32 = PORTSC_CSC | PORTSC_PEC | PORTSC_WRC | PORTSC_OCC | PORTSC_PRC | PORTSC_PLC | PORTSC_CEC;
+/// Bits that must be written back unchanged (read/write, and not write-1-to-clear/set)
+pub const PORTSC_PRESERVE_MASK: u32 = PORTSC_PP | (3 << 14) | (7 << 25);
+/// Port link state "U3" (suspended)
+pub const PLS_U3: u32 = 3;
+
+// --- Runtime registers (offsets from RTSOFF)
+/// Microframe index (14 bits)
+pub const RT_MFINDEX: usize = 0x00;
+pub const RT_IR0: usize = 0x20;
+pub const IR_IMAN: usize = 0x00;
+pub const IR_IMOD: usize = 0x04;
+pub const IR_ERSTSZ: usize = 0x08;
+pub const IR_ERSTBA: usize = 0x10;
+pub const IR_ERDP: usize = 0x18;
+
+pub const IMAN_IP: u32 = 1 << 0;
+pub const IMAN_IE: u32 = 1 << 1;
+/// ERDP - Event Handler Busy (write 1 to clear)
+pub const ERDP_EHB: u64 = 1 << 3;
+
+// --- Extended capabilities
+pub const XCAP_LEGACY: u8 = 1;
+pub const XCAP_PROTOCOL: u8 = 2;
+pub const LEGSUP_BIOS_OWNED: u32 = 1 << 16;
+pub const LEGSUP_OS_OWNED: u32 = 1 << 24;
+
+/// Port speed IDs (default mapping, as used by PORTSC and the slot context)
+#[derive(Copy,Clone,Debug,PartialEq)]
+pub enum Speed
+{
+	Full = 1,
+	Low = 2,
+	High = 3,
+	Super = 4,
+	SuperPlus = 5,
+}
+impl Speed
+{
+	pub fn from_portsc(v: u32) -> Option<Speed>
+	{
+		Some(match (v >> PORTSC_SPEED_ofs) & 0xF
+		{
+		1 => Speed::Full,
+		2 => Speed::Low,
+		3 => Speed::High,
+		4 => Speed::Super,
+		5 => Speed::SuperPlus,
+		_ => return None,
+		})
+	}
+	/// Default max packet size for the control endpoint
+	pub fn default_ep0_mps(&self) -> u16
+	{
+		match *self
+		{
+		Speed::Low | Speed::Full => 8,
+		Speed::High => 64,
+		Speed::Super | Speed::SuperPlus => 512,
+		}
+	}
+}
+
+/// Transfer Request Block
+#[repr(C)]
+#[derive(Copy,Clone,Default,Debug)]
+pub struct Trb
+{
+	pub param: u64,
+	pub status: u32,
+	pub control: u32,
+}
+unsafe impl ::kernel::lib::POD for Trb {}
+impl Trb
+{
+	pub fn trb_type(&self) -> u8 {
+		((self.control >> 10) & 0x3F) as u8
+	}
+	pub fn cycle(&self) -> bool {
+		self.control & TRB_CYCLE != 0
+	}
+	/// Completion code (event TRBs)
+	pub fn completion_code(&self) -> u8 {
+		(self.status >> 24) as u8
+	}
+	/// Slot ID (event and command TRBs)
+	pub fn slot_id(&self) -> u8 {
+		(self.control >> 24) as u8
+	}
+	/// Endpoint ID/DCI (transfer events)
+	pub fn endpoint_id(&self) -> u8 {
+		((self.control >> 16) & 0x1F) as u8
+	}
+}
+
+// TRB control word fields
+pub const TRB_CYCLE: u32 = 1 << 0;
+/// Link TRB - Toggle Cycle
+pub const TRB_TC: u32 = 1 << 1;
+/// Interrupt on Short Packet
+pub const TRB_ISP: u32 = 1 << 2;
+/// Chain bit (TRB is part of a multi-TRB TD)
+pub const TRB_CH: u32 = 1 << 4;
+/// Interrupt On Completion
+pub const TRB_IOC: u32 = 1 << 5;
+/// Immediate Data
+pub const TRB_IDT: u32 = 1 << 6;
+/// Data/Status stage direction
+pub const TRB_DIR_IN: u32 = 1 << 16;
+pub const TRB_TYPE_ofs: u32 = 10;
+pub const TRB_TRT_ofs: u32 = 16;
+/// Isoch TRB - Transfer Burst Count (number of bursts in the TD, minus one)
+pub const TRB_TBC_ofs: u32 = 7;
+/// Isoch TRB - Transfer Last Burst Packet Count (packets in the last burst, minus one)
+pub const TRB_TLBPC_ofs: u32 = 16;
+/// Isoch TRB - Frame ID (11 bits)
+pub const TRB_FRAME_ID_ofs: u32 = 20;
+/// Isoch TRB - Start Isoch ASAP (schedule in the interval after the previous TD)
+pub const TRB_SIA: u32 = 1 << 31;
+pub const TRB_SLOT_ofs: u32 = 24;
+pub const TRB_EP_ofs: u32 = 16;
+
+pub const TRT_NO_DATA: u32 = 0;
+pub const TRT_OUT: u32 = 2;
+pub const TRT_IN: u32 = 3;
+
+// TRB types
+pub const TRB_NORMAL: u8 = 1;
+pub const TRB_SETUP: u8 = 2;
+pub const TRB_DATA: u8 = 3;
+pub const TRB_STATUS: u8 = 4;
+pub const TRB_ISOCH: u8 = 5;
+pub const TRB_LINK: u8 = 6;
+pub const TRB_ENABLE_SLOT: u8 = 9;
+pub const TRB_DISABLE_SLOT: u8 = 10;
+pub const TRB_ADDRESS_DEVICE: u8 = 11;
+pub const TRB_CONFIGURE_ENDPOINT: u8 = 12;
+pub const TRB_EVALUATE_CONTEXT: u8 = 13;
+pub const TRB_RESET_ENDPOINT: u8 = 14;
+pub const TRB_SET_TR_DEQUEUE: u8 = 16;
+pub const TRB_EV_TRANSFER: u8 = 32;
+pub const TRB_EV_COMMAND_COMPLETION: u8 = 33;
+pub const TRB_EV_PORT_STATUS_CHANGE: u8 = 34;
+pub const TRB_EV_HOST_CONTROLLER: u8 = 37;
+
+// Completion codes
+pub const CC_SUCCESS: u8 = 1;
+pub const CC_STALL: u8 = 6;
+pub const CC_SHORT_PACKET: u8 = 13;
+
+/// Event Ring Segment Table entry
+#[repr(C)]
+#[derive(Copy,Clone,Default)]
+pub struct ErstEntry
+{
+	pub base: u64,
+	pub size: u32,
+	_rsvd: u32,
+}
+unsafe impl ::kernel::lib::POD for ErstEntry {}
+impl ErstEntry
+{
+	pub fn new(base: u64, size: u32) -> ErstEntry {
+		ErstEntry { base: base, size: size, _rsvd: 0 }
+	}
+}
+
+// --- Contexts
+// Contexts are accessed as arrays of u32 (with the context size determined at runtime)
+// Slot context
+pub const SLOT_DW0_ROUTE_MASK: u32 = 0xF_FFFF;
+pub const SLOT_DW0_SPEED_ofs: u32 = 20;
+pub const SLOT_DW0_HUB: u32 = 1 << 26;
+pub const SLOT_DW0_ENTRIES_ofs: u32 = 27;
+pub const SLOT_DW1_ROOT_PORT_ofs: u32 = 16;
+pub const SLOT_DW1_NUM_PORTS_ofs: u32 = 24;
+/// Parent hub slot ID (for a low/full-speed device behind a high-speed hub) is in bits 0-7
+pub const SLOT_DW2_TT_PORT_ofs: u32 = 8;
+pub const SLOT_DW2_TTT_ofs: u32 = 16;
+pub const SLOT_DW3_ADDRESS_MASK: u32 = 0xFF;
+// Endpoint context
+pub const EP_DW1_CERR_ofs: u32 = 1;
+pub const EP_DW1_TYPE_ofs: u32 = 3;
+/// Max Burst Size (for high-speed periodic endpoints, the number of additional transactions per microframe)
+pub const EP_DW1_MAX_BURST_ofs: u32 = 8;
+pub const EP_DW1_MPS_ofs: u32 = 16;
+pub const EP_DW0_INTERVAL_ofs: u32 = 16;
+pub const EP_DW2_DCS: u32 = 1 << 0;
+pub const EP_DW4_ESIT_ofs: u32 = 16;
+
+// Endpoint types (endpoint context)
+pub const EP_TYPE_ISOCH_OUT: u32 = 1;
+pub const EP_TYPE_BULK_OUT: u32 = 2;
+pub const EP_TYPE_INTERRUPT_OUT: u32 = 3;
+pub const EP_TYPE_CONTROL: u32 = 4;
+pub const EP_TYPE_ISOCH_IN: u32 = 5;
+pub const EP_TYPE_BULK_IN: u32 = 6;
+pub const EP_TYPE_INTERRUPT_IN: u32 = 7;
@@ -0,0 +1,1475 @@
+// "Tifflin" Kernel - xHCI USB driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_xhci/lib.rs
+//! eXtensible Host Controller Interface (xHCI) driver
+//!
+//! The controller assigns device addresses itself, so the `SET_ADDRESS` request from usb_core is replaced by
+//! the "Address Device" command, and usb_core's addresses are mapped to device slots. Endpoint rings are
+//! created (and the endpoint configured) on first use.
+//!
+//! Devices behind external hubs are addressed using a route string built from the hub ports in usb_core's
+//! `DeviceInfo`, and hubs are marked as such in their slot context before their downstream devices are addressed.
+//!
+//! Limitations:
+//! - Each endpoint should only be used by a single handle at a time
+#![no_std]
+#![feature(linkage)]	// for module_define!
+use kernel::prelude::*;
+use kernel::lib::mem::Arc;
+use kernel::lib::mem::aref::{Aref,ArefBorrow};
+use kernel::sync::Spinlock;
+use kernel::memory::virt::AllocHandle;
+use core::sync::atomic::{AtomicBool,AtomicU8,AtomicU32,AtomicUsize,Ordering};
+use crate::hw::Trb;
+use crate::ring::{ProducerRing, EventRing, Completion};
+use crate::device::{Slot, InputContext};
+
+#[macro_use]
+extern crate kernel;
+extern crate usb_core;
+
+mod hw;
+mod pci;
+mod ring;
+mod device;
+
+module_define!{usb_xhci, [usb_core], init}
+
+fn init()
+{
+	static PCI_DRIVER: pci::PciDriver = pci::PciDriver;
+	::kernel::device_manager::register_driver(&PCI_DRIVER);
+}
+
+/// Allocate a page of DMA memory, cleared to zero
+fn alloc_dma_zeroed(bits: u8) -> Result<AllocHandle, &'static str>
+{
+	let mut h = ::kernel::memory::virt::alloc_dma(bits, 1, "usb_xhci")?;
+	for b in h.as_mut_slice::<u8>(0, ::kernel::PAGE_SIZE) {
+		*b = 0;
+	}
+	Ok(h)
+}
+
+/// Wait (yielding the CPU) until `cond` returns true, returns false on timeout
+fn wait_for<F: FnMut()->bool>(timeout_ms: u64, mut cond: F) -> bool
+{
+	let end = ::kernel::time::ticks() + timeout_ms;
+	while !cond()
+	{
+		if ::kernel::time::ticks() > end {
+			return false;
+		}
+		::kernel::threads::yield_time();
+	}
+	true
+}
+
+/// Wrap a future in an `AsyncWaitIo` (boxing it if it's too large to store inline)
+macro_rules! make_io {
+	($fut:expr) => {
+		::usb_core::host::AsyncWaitIo::new($fut)
+			.or_else(|v| ::usb_core::host::AsyncWaitIo::new(Box::pin(v)))
+			.ok().expect("Boxed future doesn't fit")
+	};
+}
+
+struct BusDev
+{
+	// Just holds the handle
+	_host: Aref<HostInner>,
+}
+struct UsbHost
+{
+	host: ArefBorrow<HostInner>,
+}
+
+/// Maximum number of root hub ports (size of the `port_update` bitmap)
+const MAX_PORTS: usize = 8 * 32;
+
+struct HostInner
+{
+	regs: Regs,
+	irq_handle: Option<::kernel::irqs::ObjectHandle>,
+	/// Address size for DMA allocations
+	dma_bits: u8,
+	/// Size of a context structure (32 or 64 bytes)
+	context_size: usize,
+	nports: u8,
+
+	/// Device Context Base Address Array
+	dcbaa: AllocHandle,
+	/// Scratchpad buffers (and the array referencing them)
+	_scratchpad: Vec<AllocHandle>,
+	command_ring: ProducerRing,
+	event_ring: EventRing,
+	/// Event Ring Segment Table
+	_erst: AllocHandle,
+
+	/// Device slots, indexed by slot ID (kept until the ID is reused, as the controller may still access them)
+	slots: Vec<Spinlock<Option<Arc<Slot>>>>,
+	/// Slot ID for each usb_core device address
+	addr_map: Vec<AtomicU8>,
+	/// Slot ID for the device on each root port
+	port_slot: Vec<AtomicU8>,
+	/// Port most recently reset (1-based, 0 for none), which the next `SET_ADDRESS` applies to
+	pending_reset_port: AtomicU8,
+	/// Information for the device being addressed (from `set_address_zero_info`)
+	pending_info: Spinlock<Option<host::DeviceInfo>>,
+
+	// - Async support
+	waker: Spinlock<core::task::Waker>,
+	port_update: [AtomicU32; MAX_PORTS / 32],
+}
+
+/// Register access
+struct Regs
+{
+	io: ::kernel::device_manager::IOBinding,
+	/// Offset of the operational registers
+	op: usize,
+	/// Offset of interrupter 0's registers
+	ir0: usize,
+	/// Offset of the doorbell array
+	db: usize,
+}
+impl Regs
+{
+	fn read_cap(&self, ofs: usize) -> u32 {
+		// SAFE: Reads have no side-effects
+		unsafe { self.io.read_32(ofs) }
+	}
+	fn read_op(&self, ofs: usize) -> u32 {
+		// SAFE: Reads have no side-effects
+		unsafe { self.io.read_32(self.op + ofs) }
+	}
+	unsafe fn write_op(&self, ofs: usize, v: u32) {
+		self.io.write_32(self.op + ofs, v);
+	}
+	unsafe fn write_op64(&self, ofs: usize, v: u64) {
+		self.io.write_32(self.op + ofs, v as u32);
+		self.io.write_32(self.op + ofs + 4, (v >> 32) as u32);
+	}
+	/// Current frame number (1ms units, wraps at 2048)
+	fn read_frame(&self) -> u32 {
+		// SAFE: Reads have no side-effects
+		let mfindex = unsafe { self.io.read_32(self.ir0 - hw::RT_IR0 + hw::RT_MFINDEX) };
+		(mfindex >> 3) & 0x7FF
+	}
+	fn read_ir(&self, ofs: usize) -> u32 {
+		// SAFE: Reads have no side-effects
+		unsafe { self.io.read_32(self.ir0 + ofs) }
+	}
+	unsafe fn write_ir(&self, ofs: usize, v: u32) {
+		self.io.write_32(self.ir0 + ofs, v);
+	}
+	unsafe fn write_ir64(&self, ofs: usize, v: u64) {
+		self.io.write_32(self.ir0 + ofs, v as u32);
+		self.io.write_32(self.ir0 + ofs + 4, (v >> 32) as u32);
+	}
+
+	fn read_portsc(&self, port_idx: usize) -> u32 {
+		self.read_op(hw::OP_PORTSC_BASE + port_idx * hw::OP_PORT_STRIDE)
+	}
+	fn write_portsc(&self, port_idx: usize, v: u32) {
+		// SAFE: Port registers don't reference memory
+		unsafe { self.write_op(hw::OP_PORTSC_BASE + port_idx * hw::OP_PORT_STRIDE, v) }
+	}
+
+	/// Ring a doorbell (slot 0 is the command ring, otherwise `target` is the endpoint's DCI)
+	fn ring_doorbell(&self, slot: u8, target: u8) {
+		// SAFE: Rings are fully initialised before being referenced, and the controller only processes owned TRBs
+		unsafe { self.io.write_32(self.db + slot as usize * 4, target as u32) }
+	}
+}
+
+/// Build a command TRB
+fn command_trb(ty: u8, slot_id: u8, dci: u8, param: u64) -> Trb
+{
+	Trb {
+		param: param,
+		status: 0,
+		control: (ty as u32) << hw::TRB_TYPE_ofs | (slot_id as u32) << hw::TRB_SLOT_ofs | (dci as u32) << hw::TRB_EP_ofs,
+		}
+}
+/// Length field of a transfer TRB
+fn trb_length(trb: &Trb) -> usize {
+	(trb.status & 0x1_FFFF) as usize
+}
+
+/// Data buffer for a transfer
+enum Buffer<'a>
+{
+	None,
+	In(&'a mut [u8]),
+	Out(&'a [u8]),
+}
+impl<'a> Buffer<'a>
+{
+	fn len(&self) -> usize {
+		match self
+		{
+		Buffer::None => 0,
+		Buffer::In(b) => b.len(),
+		Buffer::Out(b) => b.len(),
+		}
+	}
+	fn is_in(&self) -> bool {
+		match self
+		{
+		Buffer::In(_) => true,
+		_ => false,
+		}
+	}
+	fn as_ptr(&self) -> *const u8 {
+		match self
+		{
+		Buffer::None => ::core::ptr::null(),
+		Buffer::In(b) => b.as_ptr(),
+		Buffer::Out(b) => b.as_ptr(),
+		}
+	}
+}
+
+impl BusDev
+{
+	fn new_boxed(irq: u32, io: ::kernel::device_manager::IOBinding) -> Result<Box<BusDev>, &'static str>
+	{
+		Ok(Box::new(BusDev {
+			_host: HostInner::new_aref(irq, io)?
+			}))
+	}
+}
+impl HostInner
+{
+	fn new_aref(irq: u32, io: ::kernel::device_manager::IOBinding) -> Result<Aref<HostInner>, &'static str>
+	{
+		// SAFE: Reads have no side-effects
+		let (cap0, rtsoff, dboff) = unsafe { (io.read_32(hw::CAP_CAPLENGTH), io.read_32(hw::CAP_RTSOFF), io.read_32(hw::CAP_DBOFF)) };
+		let regs = Regs {
+			io: io,
+			op: (cap0 & 0xFF) as usize,
+			ir0: (rtsoff & !0x1F) as usize + hw::RT_IR0,
+			db: (dboff & !0x3) as usize,
+			};
+		let hcsparams1 = regs.read_cap(hw::CAP_HCSPARAMS1);
+		let hcsparams2 = regs.read_cap(hw::CAP_HCSPARAMS2);
+		let hccparams1 = regs.read_cap(hw::CAP_HCCPARAMS1);
+		let max_slots = (hcsparams1 & 0xFF) as u8;
+		let nports = (hcsparams1 >> 24) as u8;
+		let n_scratchpad = ((hcsparams2 >> 27) & 0x1F) as usize | (((hcsparams2 >> 21) & 0x1F) as usize) << 5;
+		let dma_bits = if hccparams1 & hw::HCCPARAMS1_AC64 != 0 { 64 } else { 32 };
+		let context_size = if hccparams1 & hw::HCCPARAMS1_CSZ != 0 { 64 } else { 32 };
+		log_notice!("Card {:?} version is {:x}.{:02x}: {} ports, {} slots, {} scratchpad buffers, {}-bit DMA",
+			regs.io, cap0 >> 24, (cap0 >> 16) & 0xFF, nports, max_slots, n_scratchpad, dma_bits);
+		if nports as usize > MAX_PORTS {
+			return Err("Too many ports");
+		}
+
+		Self::take_ownership(&regs, hccparams1);
+
+		// Halt and reset the controller
+		// SAFE: No memory addresses involved
+		unsafe {
+			regs.write_op(hw::OP_USBCMD, regs.read_op(hw::OP_USBCMD) & !hw::USBCMD_RS);
+		}
+		if !wait_for(100, || regs.read_op(hw::OP_USBSTS) & hw::USBSTS_HCH != 0) {
+			return Err("Controller didn't halt");
+		}
+		// SAFE: No memory addresses involved
+		unsafe {
+			regs.write_op(hw::OP_USBCMD, hw::USBCMD_HCRST);
+		}
+		if !wait_for(1000, || regs.read_op(hw::OP_USBCMD) & hw::USBCMD_HCRST == 0 && regs.read_op(hw::OP_USBSTS) & hw::USBSTS_CNR == 0) {
+			return Err("Controller reset timed out");
+		}
+		if regs.read_op(hw::OP_PAGESIZE) & 1 == 0 {
+			return Err("Controller doesn't support 4KiB pages");
+		}
+
+		// Allocate the controller's structures
+		// - The DCBAA has (max_slots+1) 8-byte entries, so fits in a page
+		let mut dcbaa = alloc_dma_zeroed(dma_bits)?;
+		let mut scratchpad = Vec::new();
+		if n_scratchpad > 0
+		{
+			let mut array = alloc_dma_zeroed(dma_bits)?;
+			for i in 0 .. n_scratchpad
+			{
+				let buf = alloc_dma_zeroed(dma_bits)?;
+				*array.as_mut::<u64>(i * 8) = ::kernel::memory::virt::get_phys(buf.as_ref::<u8>(0)) as u64;
+				scratchpad.push(buf);
+			}
+			*dcbaa.as_mut::<u64>(0) = ::kernel::memory::virt::get_phys(array.as_ref::<u8>(0)) as u64;
+			scratchpad.push(array);
+		}
+		let command_ring = ProducerRing::new(dma_bits)?;
+		let event_ring = EventRing::new(dma_bits)?;
+		let mut erst = alloc_dma_zeroed(dma_bits)?;
+		*erst.as_mut::<hw::ErstEntry>(0) = hw::ErstEntry::new(event_ring.base_phys(), ring::RING_SIZE as u32);
+
+		// SAFE: All memory referenced is owned by the returned structure
+		unsafe {
+			regs.write_op(hw::OP_CONFIG, max_slots as u32);
+			regs.write_op64(hw::OP_DCBAAP, ::kernel::memory::virt::get_phys(dcbaa.as_ref::<u8>(0)) as u64);
+			regs.write_op64(hw::OP_CRCR, command_ring.base_phys() | hw::CRCR_RCS);
+			regs.write_ir(hw::IR_ERSTSZ, 1);
+			regs.write_ir64(hw::IR_ERDP, event_ring.base_phys());
+			regs.write_ir64(hw::IR_ERSTBA, ::kernel::memory::virt::get_phys(erst.as_ref::<u8>(0)) as u64);
+			// Moderate interrupts to at most one per 1ms (in 250ns units)
+			regs.write_ir(hw::IR_IMOD, 4000);
+			regs.write_ir(hw::IR_IMAN, hw::IMAN_IE | hw::IMAN_IP);
+		}
+
+		let mut inner_aref = Aref::new(HostInner {
+			regs: regs,
+			irq_handle: None,	// Filled below, once the allocation is made
+			dma_bits: dma_bits,
+			context_size: context_size,
+			nports: nports,
+
+			dcbaa: dcbaa,
+			_scratchpad: scratchpad,
+			command_ring: command_ring,
+			event_ring: event_ring,
+			_erst: erst,
+
+			slots: (0 ..= max_slots).map(|_| Spinlock::new(None)).collect(),
+			addr_map: (0 .. 128).map(|_| AtomicU8::new(0)).collect(),
+			port_slot: (0 .. nports).map(|_| AtomicU8::new(0)).collect(),
+			pending_reset_port: AtomicU8::new(0),
+			pending_info: Spinlock::new(None),
+
+			waker: Spinlock::new(kernel::futures::null_waker()),
+			port_update: Default::default(),
+			});
+
+		// Bind interrupt
+		{
+			struct RawSend<T: Send>(*const T);
+			unsafe impl<T: Send> Send for RawSend<T> {}
+			let ret_raw = RawSend(&*inner_aref);
+			// SAFE: Pointer _should_ be valid as long as this IRQ binding exists
+			Aref::get_mut(&mut inner_aref).unwrap().irq_handle = Some(::kernel::irqs::bind_object(irq, Box::new(move || unsafe { (*ret_raw.0).handle_irq() } )));
+		}
+
+		// Start the controller
+		// SAFE: Structures were all initialised above
+		unsafe {
+			inner_aref.regs.write_op(hw::OP_USBCMD, hw::USBCMD_RS | hw::USBCMD_INTE | hw::USBCMD_HSEE);
+		}
+		if !wait_for(100, || inner_aref.regs.read_op(hw::OP_USBSTS) & hw::USBSTS_HCH == 0) {
+			log_error!("Controller didn't start (USBSTS={:#x})", inner_aref.regs.read_op(hw::OP_USBSTS));
+		}
+
+		// Power ports, and populate `port_update` (could duplicate work from the interrupt, but won't miss anything)
+		for i in 0 .. nports as usize
+		{
+			let v = inner_aref.regs.read_portsc(i);
+			if v & hw::PORTSC_PP == 0 {
+				inner_aref.regs.write_portsc(i, (v & hw::PORTSC_PRESERVE_MASK) | hw::PORTSC_PP);
+			}
+			if v & hw::PORTSC_CCS != 0 {
+				inner_aref.port_update[i / 32].fetch_or(1 << (i % 32), Ordering::SeqCst);
+			}
+		}
+
+		::usb_core::register_host(Box::new(UsbHost { host: inner_aref.borrow() }), nports);
+		Ok(inner_aref)
+	}
+
+	/// Walk the extended capabilities, taking the controller from the BIOS and logging the supported protocols
+	fn take_ownership(regs: &Regs, hccparams1: u32)
+	{
+		let mut ofs = ((hccparams1 >> hw::HCCPARAMS1_XECP_ofs) as usize) << 2;
+		while ofs != 0
+		{
+			let v = regs.read_cap(ofs);
+			match (v & 0xFF) as u8
+			{
+			hw::XCAP_LEGACY => {
+				if v & hw::LEGSUP_BIOS_OWNED != 0
+				{
+					// SAFE: Ownership semaphore, no memory access
+					unsafe { regs.io.write_32(ofs, v | hw::LEGSUP_OS_OWNED); }
+					if !wait_for(1000, || regs.read_cap(ofs) & hw::LEGSUP_BIOS_OWNED == 0) {
+						log_warning!("BIOS didn't release the controller");
+					}
+				}
+				// Disable SMIs (and clear any pending SMI events)
+				// SAFE: No memory access
+				unsafe { regs.io.write_32(ofs + 4, regs.read_cap(ofs + 4) & 0xE000_0000); }
+				},
+			hw::XCAP_PROTOCOL => {
+				let ports = regs.read_cap(ofs + 8);
+				let first = ports & 0xFF;
+				let count = (ports >> 8) & 0xFF;
+				if count > 0 {
+					log_log!("USB {:x}.{:x} ports {}-{}", v >> 24, (v >> 20) & 0xF, first, first + count - 1);
+				}
+				},
+			_ => {},
+			}
+			let next = ((v >> 8) & 0xFF) as usize;
+			if next == 0 {
+				break;
+			}
+			ofs += next * 4;
+		}
+	}
+
+	fn handle_irq(&self) -> bool
+	{
+		let sts = self.regs.read_op(hw::OP_USBSTS);
+		let iman = self.regs.read_ir(hw::IR_IMAN);
+		if sts & (hw::USBSTS_EINT | hw::USBSTS_PCD | hw::USBSTS_HSE) == 0 && iman & hw::IMAN_IP == 0 {
+			return false;
+		}
+		// SAFE: Acknowledging (write-1-to-clear) bits
+		unsafe {
+			self.regs.write_op(hw::OP_USBSTS, sts & (hw::USBSTS_EINT | hw::USBSTS_PCD | hw::USBSTS_HSE));
+			self.regs.write_ir(hw::IR_IMAN, iman | hw::IMAN_IP);
+		}
+		if sts & hw::USBSTS_HSE != 0 {
+			log_error!("Host system error");
+		}
+		if sts & hw::USBSTS_HCE != 0 {
+			log_error!("Host controller error");
+		}
+
+		let mut any = false;
+		while let Some(ev) = self.event_ring.pop()
+		{
+			self.handle_event(ev);
+			any = true;
+		}
+		if any
+		{
+			// SAFE: Pointer is within the event ring
+			unsafe {
+				self.regs.write_ir64(hw::IR_ERDP, self.event_ring.dequeue_phys() | hw::ERDP_EHB);
+			}
+		}
+		true
+	}
+
+	fn handle_event(&self, ev: Trb)
+	{
+		let completion = Completion {
+			code: ev.completion_code(),
+			trb_idx: 0,
+			residual: ev.status & 0xFF_FFFF,
+			slot_id: ev.slot_id(),
+			};
+		match ev.trb_type()
+		{
+		hw::TRB_EV_TRANSFER => {
+			let dci = ev.endpoint_id();
+			match self.get_slot(ev.slot_id()).and_then(|s| s.ring(dci))
+			{
+			Some(ring) => match ring.index_of(ev.param)
+				{
+				Some(idx) => ring.handle_event(idx, Completion { trb_idx: idx, ..completion }),
+				None => log_warning!("Transfer event for slot {} DCI {} outside ring ({:#x})", ev.slot_id(), dci, ev.param),
+				},
+			None => log_warning!("Transfer event for unknown endpoint (slot {} DCI {})", ev.slot_id(), dci),
+			}
+			},
+		hw::TRB_EV_COMMAND_COMPLETION => {
+			match self.command_ring.index_of(ev.param)
+			{
+			Some(idx) => self.command_ring.handle_event(idx, Completion { trb_idx: idx, ..completion }),
+			None => log_warning!("Command completion outside ring ({:#x})", ev.param),
+			}
+			},
+		hw::TRB_EV_PORT_STATUS_CHANGE => {
+			let port = ((ev.param >> 24) & 0xFF) as usize;
+			if port == 0 || port > self.nports as usize {
+				log_warning!("Port status change for invalid port {}", port);
+				return ;
+			}
+			let port_idx = port - 1;
+			let v = self.regs.read_portsc(port_idx);
+			log_trace!("Port {} status change: {:#x}", port, v);
+			// Acknowledge everything but the connection change (which usb_core clears)
+			self.regs.write_portsc(port_idx, (v & hw::PORTSC_PRESERVE_MASK) | (v & hw::PORTSC_CHANGE_MASK & !hw::PORTSC_CSC));
+			if v & hw::PORTSC_CSC != 0
+			{
+				self.port_update[port_idx / 32].fetch_or(1 << (port_idx % 32), Ordering::SeqCst);
+				self.waker.lock().wake_by_ref();
+			}
+			},
+		hw::TRB_EV_HOST_CONTROLLER => log_error!("Host controller event: code {}", completion.code),
+		ty => log_debug!("Unhandled event type {} ({:?})", ty, ev),
+		}
+	}
+
+	fn get_slot(&self, slot_id: u8) -> Option<Arc<Slot>> {
+		self.slots.get(slot_id as usize)?.lock().clone()
+	}
+	fn slot_for_addr(&self, addr: u8) -> Option<Arc<Slot>> {
+		match self.addr_map[addr as usize].load(Ordering::SeqCst)
+		{
+		0 => None,
+		id => self.get_slot(id),
+		}
+	}
+
+	/// Submit a command without waiting for it to complete, returning the TRB index
+	fn submit_command(&self, trb: Trb) -> Option<usize> {
+		let (_, idx) = self.command_ring.push(&[trb], false)?;
+		self.regs.ring_doorbell(0, 0);
+		Some(idx)
+	}
+	/// Run a command and wait for its completion
+	async fn command(&self, trb: Trb) -> Completion {
+		match self.submit_command(trb)
+		{
+		Some(idx) => self.command_ring.wait(idx).await,
+		None => Completion { code: 0, trb_idx: 0, residual: 0, slot_id: 0 },
+		}
+	}
+
+	/// Handle usb_core's `SET_ADDRESS` for the device on the most recently reset port, by enabling and addressing a slot
+	async fn address_device(&self, addr: u8) -> bool
+	{
+		let reset_port = self.pending_reset_port.swap(0, Ordering::SeqCst);
+		let info = self.pending_info.lock().take();
+		// Speed, root port, route string, and transaction translator fields (slot context DW2)
+		let (speed, root_port, route, tt) = match info
+			{
+			Some(host::DeviceInfo { speed, hub: Some(hub), tt }) => match self.hub_device_location(addr, speed, hub, tt).await
+				{
+				Some(v) => v,
+				None => return false,
+				},
+			_ => {
+				let port_idx = match reset_port
+					{
+					0 => {
+						log_error!("Device {}: SET_ADDRESS without a port reset", addr);
+						return false;
+						},
+					v => v as usize - 1,
+					};
+				match hw::Speed::from_portsc(self.regs.read_portsc(port_idx))
+				{
+				Some(v) => (v, port_idx as u8 + 1, 0, 0),
+				None => {
+					log_error!("Port {}: Unknown speed (PORTSC={:#x})", port_idx+1, self.regs.read_portsc(port_idx));
+					return false;
+					},
+				}
+				},
+			};
+
+		let c = self.command(command_trb(hw::TRB_ENABLE_SLOT, 0, 0, 0)).await;
+		if c.code != hw::CC_SUCCESS {
+			log_error!("Device {}: Enable Slot failed (code {})", addr, c.code);
+			return false;
+		}
+		let slot_id = c.slot_id;
+		let slot = match Slot::new(self.dma_bits, slot_id, speed, root_port, route)
+			{
+			Ok(v) => Arc::new(v),
+			Err(e) => {
+				log_error!("Device {}: Unable to allocate slot {} - {}", addr, slot_id, e);
+				self.submit_command(command_trb(hw::TRB_DISABLE_SLOT, slot_id, 0, 0));
+				return false;
+				},
+			};
+		// SAFE: The entry is only used by the controller (and the context is kept until the slot is reused)
+		unsafe {
+			::core::ptr::write_volatile(self.dcbaa.as_int_mut::<u64>(slot_id as usize * 8), slot.output_phys());
+		}
+		*self.slots[slot_id as usize].lock() = Some(slot.clone());
+
+		let mps = speed.default_ep0_mps() as usize;
+		let c = match InputContext::new(self.dma_bits, self.context_size)
+			{
+			Ok(mut input) => {
+				input.set_add_flags(0b11);
+				{
+					let s = input.slot_mut();
+					s[0] = route | (speed as u32) << hw::SLOT_DW0_SPEED_ofs | 1 << hw::SLOT_DW0_ENTRIES_ofs;
+					s[1] = (root_port as u32) << hw::SLOT_DW1_ROOT_PORT_ofs;
+					s[2] = tt;
+				}
+				match slot.ring(1)
+				{
+				Some(ring) => {
+					device::fill_endpoint(input.endpoint_mut(1), hw::EP_TYPE_CONTROL, mps, 0, 0, ring.base_phys() | hw::EP_DW2_DCS as u64, 8, 0);
+					self.command(command_trb(hw::TRB_ADDRESS_DEVICE, slot_id, 0, input.phys())).await
+					},
+				None => {
+					log_error!("Device {}: Slot {} has no control endpoint ring", addr, slot_id);
+					Completion { code: 0, trb_idx: 0, residual: 0, slot_id: 0 }
+					},
+				}
+				},
+			Err(e) => {
+				log_error!("Device {}: Unable to allocate input context - {}", addr, e);
+				Completion { code: 0, trb_idx: 0, residual: 0, slot_id: 0 }
+				},
+			};
+		if c.code != hw::CC_SUCCESS {
+			log_error!("Device {}: Address Device failed (code {})", addr, c.code);
+			self.submit_command(command_trb(hw::TRB_DISABLE_SLOT, slot_id, 0, 0));
+			return false;
+		}
+		self.addr_map[addr as usize].store(slot_id, Ordering::SeqCst);
+		if route == 0 {
+			self.port_slot[root_port as usize - 1].store(slot_id, Ordering::SeqCst);
+		}
+		log_log!("Port {} (route {:#x}): {:?} speed device, slot {}, address {} (bus address {})",
+			slot.root_port, route, speed, slot_id, addr, slot.read_output(3*4) & hw::SLOT_DW3_ADDRESS_MASK);
+
+		// Full speed devices can have a larger control endpoint than the default
+		if speed == hw::Speed::Full
+		{
+			let mut desc = [0u8; 8];
+			let setup = [0x80, 6, 0, 1, 0, 0, 8, 0];	// GET_DESCRIPTOR(Device, 8 bytes)
+			let len = self.control_transfer(&slot, 1, mps, &setup, Buffer::In(&mut desc)).await;
+			match (len, desc[7])
+			{
+			(Some(8), 16) | (Some(8), 32) | (Some(8), 64) => self.update_ep0_mps(&slot, desc[7] as usize).await,
+			_ => {},
+			}
+		}
+		true
+	}
+	/// Locate a device behind an external hub, returning its speed, root port, route string and TT fields
+	async fn hub_device_location(&self, addr: u8, speed: host::Speed, hub: host::HubPort, tt: Option<host::HubPort>) -> Option<(hw::Speed, u8, u32, u32)>
+	{
+		let parent = match self.slot_for_addr(hub.addr)
+			{
+			Some(v) => v,
+			None => {
+				log_error!("Device {}: No slot for parent hub {}", addr, hub.addr);
+				return None;
+				},
+			};
+		// Each tier takes a nibble of the route string (with ports above 15 clamped to 15)
+		let depth = (0 .. 5).take_while(|i| (parent.route >> (i * 4)) & 0xF != 0).count();
+		if depth >= 5 {
+			log_error!("Device {}: Too many hub tiers", addr);
+			return None;
+		}
+		let route = parent.route | (::core::cmp::min(hub.port, 15) as u32) << (depth * 4);
+		let speed = match speed
+			{
+			host::Speed::Low => hw::Speed::Low,
+			host::Speed::Full => hw::Speed::Full,
+			host::Speed::High => hw::Speed::High,
+			host::Speed::Super => hw::Speed::Super,
+			};
+		let tt = match tt
+			{
+			Some(tt) => match self.slot_for_addr(tt.addr)
+				{
+				Some(tt_slot) => tt_slot.id as u32 | (tt.port as u32) << hw::SLOT_DW2_TT_PORT_ofs,
+				None => {
+					log_error!("Device {}: No slot for transaction translator hub {}", addr, tt.addr);
+					return None;
+					},
+				},
+			None => 0,
+			};
+		// The controller needs to know the parent is a hub before scheduling transactions through it
+		self.configure_hub_slot(&parent).await;
+		Some( (speed, parent.root_port, route & hw::SLOT_DW0_ROUTE_MASK, tt) )
+	}
+	/// Pass a hub's fields (set by `set_hub_info`) to the controller, if not already done
+	async fn configure_hub_slot(&self, slot: &Slot)
+	{
+		if !slot.is_hub() || slot.hub_configured.swap(true, Ordering::SeqCst) {
+			return ;
+		}
+		let mut input = match InputContext::new(self.dma_bits, self.context_size)
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_error!("Slot {}: Unable to allocate input context - {}", slot.id, e);
+				slot.hub_configured.store(false, Ordering::SeqCst);
+				return ;
+				},
+			};
+		input.set_add_flags(1);
+		{
+			let s = input.slot_mut();
+			for i in 0 .. 4 {
+				s[i] = slot.read_output(i * 4);
+			}
+			s[3] = 0;
+			slot.write_hub_fields(s);
+		}
+		let c = self.command(command_trb(hw::TRB_CONFIGURE_ENDPOINT, slot.id, 0, input.phys())).await;
+		if c.code != hw::CC_SUCCESS {
+			log_warning!("Slot {}: Configure Endpoint (hub) failed (code {})", slot.id, c.code);
+			slot.hub_configured.store(false, Ordering::SeqCst);
+		}
+	}
+	/// Update the max packet size of a slot's control endpoint
+	async fn update_ep0_mps(&self, slot: &Slot, mps: usize)
+	{
+		let mut input = match InputContext::new(self.dma_bits, self.context_size)
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_error!("Slot {}: Unable to allocate input context - {}", slot.id, e);
+				return ;
+				},
+			};
+		input.set_add_flags(1 << 1);
+		let ring = match slot.ring(1)
+			{
+			Some(v) => v,
+			None => {
+				log_error!("Slot {}: No control endpoint ring", slot.id);
+				return ;
+				},
+			};
+		device::fill_endpoint(input.endpoint_mut(1), hw::EP_TYPE_CONTROL, mps, 0, 0, ring.enqueue_ptr(), 8, 0);
+		let c = self.command(command_trb(hw::TRB_EVALUATE_CONTEXT, slot.id, 0, input.phys())).await;
+		if c.code != hw::CC_SUCCESS {
+			log_warning!("Slot {}: Evaluate Context failed (code {})", slot.id, c.code);
+		}
+	}
+
+	/// Release the slot used by the device on a port (after it's disconnected)
+	fn release_port(&self, port_idx: usize)
+	{
+		let slot_id = self.port_slot[port_idx].swap(0, Ordering::SeqCst);
+		if slot_id == 0 {
+			return ;
+		}
+		for a in self.addr_map.iter()
+		{
+			let _ = a.compare_exchange(slot_id, 0, Ordering::SeqCst, Ordering::SeqCst);
+		}
+		self.disable_slot(slot_id);
+	}
+	/// Release the slot used by a device (after usb_core has removed it)
+	fn release_device(&self, addr: u8)
+	{
+		let slot_id = self.addr_map[addr as usize & 0x7F].swap(0, Ordering::SeqCst);
+		if slot_id == 0 {
+			return ;
+		}
+		for p in self.port_slot.iter()
+		{
+			let _ = p.compare_exchange(slot_id, 0, Ordering::SeqCst, Ordering::SeqCst);
+		}
+		self.disable_slot(slot_id);
+	}
+	fn disable_slot(&self, slot_id: u8)
+	{
+		log_debug!("Disabling slot {}", slot_id);
+		// Nothing waits for the completion, the slot's state is kept until the ID is handed out again
+		if self.submit_command(command_trb(hw::TRB_DISABLE_SLOT, slot_id, 0, 0)).is_none() {
+			log_warning!("Unable to disable slot {}", slot_id);
+		}
+	}
+
+	/// Get the slot and transfer ring for an endpoint, configuring the endpoint on first use
+	///
+	/// For isochronous endpoints, `max_packet_size` is the raw `wMaxPacketSize` (with the multiplier in bits 11-12)
+	async fn get_endpoint(&self, addr: u8, dci: u8, ep_type: u32, max_packet_size: usize, period: usize) -> Option<(Arc<Slot>, Arc<ProducerRing>)>
+	{
+		let slot = match self.slot_for_addr(addr)
+			{
+			Some(v) => v,
+			None => {
+				log_warning!("No slot for device {}", addr);
+				return None;
+				},
+			};
+		if let Some(r) = slot.ring(dci) {
+			return Some( (slot, r) );
+		}
+
+		let ring = match ProducerRing::new(self.dma_bits)
+			{
+			Ok(v) => Arc::new(v),
+			Err(e) => {
+				log_error!("Slot {} DCI {}: Unable to allocate ring - {}", slot.id, dci, e);
+				return None;
+				},
+			};
+		let mut input = match InputContext::new(self.dma_bits, self.context_size)
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_error!("Slot {} DCI {}: Unable to allocate input context - {}", slot.id, dci, e);
+				return None;
+				},
+			};
+		let entries = slot.add_dci(dci);
+		input.set_add_flags(1 | 1 << dci);
+		{
+			// Copy the current slot context, updating the number of entries
+			let s = input.slot_mut();
+			for i in 0 .. 4 {
+				s[i] = slot.read_output(i * 4);
+			}
+			s[0] = (s[0] & !(0x1F << hw::SLOT_DW0_ENTRIES_ofs)) | (entries as u32) << hw::SLOT_DW0_ENTRIES_ofs;
+			s[3] = 0;
+			slot.write_hub_fields(s);
+		}
+		let (max_packet_size, max_burst) = match ep_type
+			{
+			hw::EP_TYPE_ISOCH_IN | hw::EP_TYPE_ISOCH_OUT => isoch_packet_size(max_packet_size),
+			_ => (max_packet_size, 0),
+			};
+		let (interval, avg_len, esit) = match ep_type
+			{
+			hw::EP_TYPE_INTERRUPT_IN | hw::EP_TYPE_INTERRUPT_OUT => (interrupt_interval(slot.speed, period), max_packet_size, max_packet_size),
+			hw::EP_TYPE_ISOCH_IN | hw::EP_TYPE_ISOCH_OUT => {
+				let interval_bytes = max_packet_size * (max_burst as usize + 1);
+				(isoch_interval(slot.speed, period), interval_bytes, interval_bytes)
+				},
+			hw::EP_TYPE_CONTROL => (0, 8, 0),
+			_ => (0, 3*1024, 0),
+			};
+		device::fill_endpoint(input.endpoint_mut(dci), ep_type, max_packet_size, max_burst, interval, ring.base_phys() | hw::EP_DW2_DCS as u64, avg_len, esit);
+		let c = self.command(command_trb(hw::TRB_CONFIGURE_ENDPOINT, slot.id, 0, input.phys())).await;
+		if c.code != hw::CC_SUCCESS {
+			log_error!("Slot {} DCI {}: Configure Endpoint failed (code {})", slot.id, dci, c.code);
+			return None;
+		}
+		slot.set_ring(dci, ring.clone());
+		Some( (slot, ring) )
+	}
+
+	/// Build TRBs for a data buffer, splitting it at page boundaries
+	///
+	/// The first TRB has type `first_type` and all have `flags` (with `first_flags`, e.g. the direction, only applied to the first)
+	fn buffer_trbs(&self, dst: &mut Vec<Trb>, first_type: u8, flags: u32, first_flags: u32, buf: &Buffer, max_packet_size: usize) -> bool
+	{
+		let max_packet_size = ::core::cmp::max(max_packet_size, 1);
+		let base = buf.as_ptr() as usize;
+		let len = buf.len();
+		let mut ofs = 0;
+		let mut first = true;
+		while ofs < len
+		{
+			let addr = base + ofs;
+			let chunk = ::core::cmp::min(len - ofs, ::kernel::PAGE_SIZE - addr % ::kernel::PAGE_SIZE);
+			let phys = ::kernel::memory::virt::get_phys(addr as *const u8) as u64;
+			if self.dma_bits < 64 && phys + chunk as u64 > (1 << self.dma_bits) {
+				log_error!("Buffer {:#x} not addressable by the controller ({:#x})", addr, phys);
+				return false;
+			}
+			// TD Size - number of packets remaining after this TRB
+			let rem_packets = ::kernel::lib::num::div_up(len - ofs - chunk, max_packet_size);
+			let (ty, extra) = if first { (first_type, first_flags) } else { (hw::TRB_NORMAL, 0) };
+			dst.push(Trb {
+				param: phys,
+				status: chunk as u32 | (::core::cmp::min(rem_packets, 31) as u32) << 17,
+				control: (ty as u32) << hw::TRB_TYPE_ofs | flags | extra,
+				});
+			ofs += chunk;
+			first = false;
+		}
+		true
+	}
+
+	/// Issue a control transfer on an endpoint, returning the number of bytes transferred in the data stage
+	async fn control_transfer(&self, slot: &Slot, dci: u8, max_packet_size: usize, setup: &[u8], data: Buffer<'_>) -> Option<usize>
+	{
+		let ring = slot.ring(dci)?;
+		let data_len = data.len();
+		let trbs = {
+			let mut setup_bytes = [0u8; 8];
+			let l = ::core::cmp::min(setup.len(), 8);
+			setup_bytes[..l].copy_from_slice(&setup[..l]);
+			let (trt, dir) = match (data_len, data.is_in())
+				{
+				(0, _) => (hw::TRT_NO_DATA, 0),
+				(_, true) => (hw::TRT_IN, hw::TRB_DIR_IN),
+				(_, false) => (hw::TRT_OUT, 0),
+				};
+			let mut trbs = vec![Trb {
+				param: u64::from_le_bytes(setup_bytes),
+				status: 8,
+				control: (hw::TRB_SETUP as u32) << hw::TRB_TYPE_ofs | hw::TRB_IDT | trt << hw::TRB_TRT_ofs,
+				}];
+			if !self.buffer_trbs(&mut trbs, hw::TRB_DATA, hw::TRB_ISP, dir, &data, max_packet_size) {
+				return None;
+			}
+			// Status stage is in the opposite direction to the data stage (IN if there's no data)
+			let status_dir = if dir != 0 { 0 } else { hw::TRB_DIR_IN };
+			trbs.push(Trb {
+				param: 0,
+				status: 0,
+				control: (hw::TRB_STATUS as u32) << hw::TRB_TYPE_ofs | hw::TRB_IOC | status_dir,
+				});
+			trbs
+			};
+
+		let (first, last) = ring.push(&trbs, false)?;
+		self.regs.ring_doorbell(slot.id, dci);
+		let res = ring.wait(last).await;
+		if res.code != hw::CC_SUCCESS {
+			log_warning!("Slot {} DCI {}: Control transfer failed (code {})", slot.id, dci, res.code);
+			self.reset_endpoint(slot, dci, &ring).await;
+			return None;
+		}
+		// Data stage: a short packet stops the data stage early (the event reports the residual)
+		let mut total = 0;
+		for i in 1 .. trbs.len() - 1
+		{
+			let l = trb_length(&trbs[i]);
+			match ring.get_event(ProducerRing::td_index(first, i))
+			{
+			Some(ev) if ev.code == hw::CC_SHORT_PACKET => {
+				total += l.saturating_sub(ev.residual as usize);
+				break;
+				},
+			_ => total += l,
+			}
+		}
+		Some( ::core::cmp::min(total, data_len) )
+	}
+
+	/// Build the TRBs for a single bulk/interrupt/isochronous TD (with IOC set on the last TRB)
+	fn td_trbs(&self, first_type: u8, first_flags: u32, data: &Buffer, max_packet_size: usize) -> Option<Vec<Trb>>
+	{
+		let mut trbs = Vec::new();
+		if data.len() == 0 {
+			trbs.push(Trb { param: 0, status: 0, control: (first_type as u32) << hw::TRB_TYPE_ofs | first_flags });
+		}
+		else if !self.buffer_trbs(&mut trbs, first_type, hw::TRB_ISP, first_flags, data, max_packet_size) {
+			return None;
+		}
+		trbs.last_mut().unwrap().control |= hw::TRB_IOC;
+		Some(trbs)
+	}
+
+	/// Issue a bulk/interrupt transfer, returning the number of bytes transferred
+	///
+	/// On failure, returns the completion code (zero if the transfer couldn't be queued)
+	async fn normal_transfer(&self, slot: &Slot, dci: u8, ring: &ProducerRing, max_packet_size: usize, data: Buffer<'_>) -> Result<usize, u8>
+	{
+		let trbs = self.td_trbs(hw::TRB_NORMAL, 0, &data, max_packet_size).ok_or(0)?;
+		let (first, last) = ring.push(&trbs, true).ok_or(0)?;
+		self.regs.ring_doorbell(slot.id, dci);
+		let res = ring.wait(last).await;
+		match res.code
+		{
+		hw::CC_SUCCESS | hw::CC_SHORT_PACKET => Ok( td_transferred(&trbs, first, &res) ),
+		code => {
+			log_warning!("Slot {} DCI {}: Transfer failed (code {})", slot.id, dci, code);
+			self.reset_endpoint(slot, dci, ring).await;
+			Err(code)
+			},
+		}
+	}
+
+	/// Recover an endpoint after an error (e.g. a stall), discarding any queued TDs
+	async fn reset_endpoint(&self, slot: &Slot, dci: u8, ring: &ProducerRing)
+	{
+		let c = self.command(command_trb(hw::TRB_RESET_ENDPOINT, slot.id, dci, 0)).await;
+		if c.code != hw::CC_SUCCESS {
+			log_warning!("Slot {} DCI {}: Reset Endpoint failed (code {})", slot.id, dci, c.code);
+		}
+		let c = self.command(command_trb(hw::TRB_SET_TR_DEQUEUE, slot.id, dci, ring.enqueue_ptr())).await;
+		if c.code != hw::CC_SUCCESS {
+			log_warning!("Slot {} DCI {}: Set TR Dequeue Pointer failed (code {})", slot.id, dci, c.code);
+		}
+	}
+
+	/// Control transfer for a control endpoint handle
+	async fn control(&self, addr: u8, ep: u8, max_packet_size: usize, setup: &[u8], data: Buffer<'_>) -> usize
+	{
+		if addr == 0
+		{
+			// The only request usb_core sends to address zero is SET_ADDRESS
+			if setup.len() >= 8 && setup[0] == 0 && setup[1] == 5 {
+				self.address_device(setup[2] & 0x7F).await;
+			}
+			else {
+				log_warning!("Unexpected request to address 0 - {:?}", ::kernel::logging::HexDump(setup));
+			}
+			return 0;
+		}
+		let dci = ep * 2 + 1;
+		match self.get_endpoint(addr, dci, hw::EP_TYPE_CONTROL, max_packet_size, 0).await
+		{
+		Some((slot, _)) => self.control_transfer(&slot, dci, max_packet_size, setup, data).await.unwrap_or(0),
+		None => 0,
+		}
+	}
+	/// Bulk transfer for a bulk endpoint handle
+	///
+	/// `halted` is set if the device stalls the transfer (the endpoint's host state is reset before returning)
+	async fn bulk(&self, addr: u8, ep: u8, max_packet_size: usize, data: Buffer<'_>, halted: &AtomicBool) -> usize
+	{
+		let (dci, ep_type) = if data.is_in() { (ep * 2 + 1, hw::EP_TYPE_BULK_IN) } else { (ep * 2, hw::EP_TYPE_BULK_OUT) };
+		halted.store(false, Ordering::SeqCst);
+		match self.get_endpoint(addr, dci, ep_type, max_packet_size, 0).await
+		{
+		Some((slot, ring)) => match self.normal_transfer(&slot, dci, &ring, max_packet_size, data).await
+			{
+			Ok(len) => len,
+			Err(code) => {
+				halted.store(code == hw::CC_STALL, Ordering::SeqCst);
+				0
+				},
+			},
+		None => 0,
+		}
+	}
+	/// Send data on an interrupt OUT endpoint
+	async fn interrupt_out(&self, addr: u8, ep: u8, max_packet_size: usize, period: usize, data: &[u8]) -> usize
+	{
+		let dci = ep * 2;
+		match self.get_endpoint(addr, dci, hw::EP_TYPE_INTERRUPT_OUT, max_packet_size, period).await
+		{
+		Some((slot, ring)) => self.normal_transfer(&slot, dci, &ring, max_packet_size, Buffer::Out(data)).await.unwrap_or(0),
+		None => 0,
+		}
+	}
+	/// Transfer data on an isochronous endpoint, starting in the given frame
+	///
+	/// The buffer is split into one TD per service interval (each of up to the packet size times the multiplier), which
+	/// are queued together so the controller runs them in consecutive intervals. Returns the total transferred.
+	async fn isoch(&self, addr: u8, ep: u8, max_packet_size: usize, period: usize, data: Buffer<'_>, frame: u32) -> usize
+	{
+		let (dci, ep_type) = if data.is_in() { (ep * 2 + 1, hw::EP_TYPE_ISOCH_IN) } else { (ep * 2, hw::EP_TYPE_ISOCH_OUT) };
+		let (slot, ring) = match self.get_endpoint(addr, dci, ep_type, max_packet_size, period).await
+			{
+			Some(v) => v,
+			None => return 0,
+			};
+		let (packet_size, max_burst) = isoch_packet_size(max_packet_size);
+		let packet_size = ::core::cmp::max(packet_size, 1);
+		let burst_packets = max_burst as usize + 1;
+		let interval_bytes = packet_size * burst_packets;
+
+		let chunks: Vec<Buffer> = match data
+			{
+			Buffer::In(b) if b.len() > 0 => b.chunks_mut(interval_bytes).map(Buffer::In).collect(),
+			Buffer::Out(b) if b.len() > 0 => b.chunks(interval_bytes).map(Buffer::Out).collect(),
+			_ => vec![Buffer::None],
+			};
+		// Queue a TD for each interval (only the first has a frame number, the rest follow on)
+		let mut tds = Vec::with_capacity(chunks.len());
+		for (i, chunk) in chunks.iter().enumerate()
+		{
+			let packets = ::core::cmp::max(::kernel::lib::num::div_up(chunk.len(), packet_size), 1);
+			let flags = (if i == 0 { (frame & 0x7FF) << hw::TRB_FRAME_ID_ofs } else { hw::TRB_SIA })
+				| (((packets - 1) / burst_packets) as u32) << hw::TRB_TBC_ofs
+				| (((packets - 1) % burst_packets) as u32) << hw::TRB_TLBPC_ofs
+				;
+			let trbs = match self.td_trbs(hw::TRB_ISOCH, flags, chunk, packet_size)
+				{
+				Some(v) => v,
+				None => break,
+				};
+			match ring.push(&trbs, true)
+			{
+			Some((first, last)) => tds.push( (trbs, first, last) ),
+			None => break,
+			}
+		}
+		if tds.is_empty() {
+			return 0;
+		}
+		if tds.len() < chunks.len() {
+			log_warning!("Slot {} DCI {}: Only {} of {} isochronous intervals queued", slot.id, dci, tds.len(), chunks.len());
+		}
+		self.regs.ring_doorbell(slot.id, dci);
+
+		let mut total = 0;
+		for (trbs, first, last) in tds
+		{
+			let res = ring.wait(last).await;
+			match res.code
+			{
+			hw::CC_SUCCESS | hw::CC_SHORT_PACKET => total += td_transferred(&trbs, first, &res),
+			// Isochronous endpoints don't halt, the interval's data is just lost (e.g. the frame was missed)
+			code => log_debug!("Slot {} DCI {}: Isochronous transfer failed (code {})", slot.id, dci, code),
+			}
+		}
+		total
+	}
+	/// Run a single poll of an interrupt IN endpoint
+	async fn interrupt_in(&self, state: &InterruptState) -> usize
+	{
+		let dci = state.ep * 2 + 1;
+		let len = match self.get_endpoint(state.addr, dci, hw::EP_TYPE_INTERRUPT_IN, state.max_packet_size, state.period).await
+			{
+			Some((slot, ring)) => {
+				// SAFE: The buffer is only written by this transfer (one outstanding poll per endpoint)
+				let buf = unsafe { state.buffer.as_int_mut_slice::<u8>(0, state.max_packet_size) };
+				self.normal_transfer(&slot, dci, &ring, state.max_packet_size, Buffer::In(buf)).await.unwrap_or(0)
+				},
+			None => 0,
+			};
+		state.last_len.store(len, Ordering::SeqCst);
+		len
+	}
+}
+
+/// Convert an interrupt endpoint's polling interval into the endpoint context value (2^n * 125us)
+fn interrupt_interval(speed: hw::Speed, b_interval: usize) -> u8
+{
+	match speed
+	{
+	// Interval is in frames (1ms, 8 microframes)
+	hw::Speed::Low | hw::Speed::Full => {
+		let microframes = ::core::cmp::max(b_interval, 1) as u32 * 8;
+		::core::cmp::min(31 - microframes.leading_zeros(), 10) as u8
+		},
+	// Interval is an exponent (2^(n-1) microframes)
+	_ => (::core::cmp::min(::core::cmp::max(b_interval, 1), 16) - 1) as u8,
+	}
+}
+/// Bytes transferred by a completed TD, from its TRBs and the completion event
+fn td_transferred(trbs: &[Trb], first: usize, res: &Completion) -> usize
+{
+	let pos = ::core::cmp::min(ProducerRing::td_offset(first, res.trb_idx), trbs.len() - 1);
+	let before: usize = trbs[..pos].iter().map(trb_length).sum();
+	before + trb_length(&trbs[pos]).saturating_sub(res.residual as usize)
+}
+/// Split an isochronous `wMaxPacketSize` into the packet size and the number of additional transactions per microframe
+fn isoch_packet_size(raw: usize) -> (usize, u8)
+{
+	(raw & 0x7FF, ::core::cmp::min((raw >> 11) & 3, 2) as u8)
+}
+/// Convert an isochronous endpoint's polling interval into the endpoint context value (2^n * 125us)
+fn isoch_interval(speed: hw::Speed, b_interval: usize) -> u8
+{
+	// Interval is always an exponent, in frames for full-speed and microframes for high-speed
+	let exp = (::core::cmp::min(::core::cmp::max(b_interval, 1), 16) - 1) as u8;
+	match speed
+	{
+	hw::Speed::Low | hw::Speed::Full => exp + 3,
+	_ => exp,
+	}
+}
+
+use ::usb_core::host::{self, EndpointAddr, PortFeature, Handle};
+use ::usb_core::host::{InterruptEndpoint, InterruptOutEndpoint, IsochEndpoint, ControlEndpoint, BulkEndpoint};
+impl ::usb_core::host::HostController for UsbHost
+{
+	fn init_interrupt(&self, endpoint: EndpointAddr, max_packet_size: usize, period_ms: usize) -> Option<Handle<dyn InterruptEndpoint>> {
+		let max_packet_size = ::core::cmp::min(max_packet_size, ::kernel::PAGE_SIZE);
+		let buffer = match alloc_dma_zeroed(self.host.dma_bits)
+			{
+			Ok(v) => v,
+			Err(e) => {
+				log_error!("init_interrupt({:?}): Unable to allocate buffer - {}", endpoint, e);
+				return None;
+				},
+			};
+		Some(Handle::new(InterruptEndpointHandle {
+			host: self.host.reborrow(),
+			state: Box::new(InterruptState {
+				addr: endpoint.dev_addr(),
+				ep: endpoint.endpt(),
+				max_packet_size: max_packet_size,
+				period: period_ms,
+				buffer: buffer,
+				last_len: AtomicUsize::new(0),
+				}),
+			}).ok().unwrap())
+	}
+	fn init_interrupt_out(&self, endpoint: EndpointAddr, max_packet_size: usize, period_ms: usize) -> Option<Handle<dyn InterruptOutEndpoint>> {
+		Some(Handle::new(InterruptOutEndpointHandle {
+			host: self.host.reborrow(),
+			addr: endpoint.dev_addr(),
+			ep: endpoint.endpt(),
+			max_packet_size: max_packet_size as u16,
+			period: period_ms as u16,
+			}).ok().unwrap())
+	}
+	fn init_isoch(&self, endpoint: EndpointAddr, max_packet_size: usize, period: usize) -> Option<Handle<dyn IsochEndpoint>> {
+		Some(Handle::new(IsochEndpointHandle {
+			host: self.host.reborrow(),
+			addr: endpoint.dev_addr(),
+			ep: endpoint.endpt(),
+			max_packet_size: max_packet_size as u16,
+			period: period as u16,
+			}).ok().unwrap())
+	}
+	fn init_control(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Option<Handle<dyn ControlEndpoint>> {
+		Some(Handle::new(ControlEndpointHandle {
+			host: self.host.reborrow(),
+			addr: endpoint.dev_addr(),
+			ep: endpoint.endpt(),
+			max_packet_size: max_packet_size as u16,
+			}).ok().unwrap())
+	}
+	fn init_bulk(&self, endpoint: EndpointAddr, max_packet_size: usize) -> Option<Handle<dyn BulkEndpoint>> {
+		Some(Handle::new(BulkEndpointHandle {
+			host: self.host.reborrow(),
+			addr: endpoint.dev_addr(),
+			ep: endpoint.endpt(),
+			max_packet_size: max_packet_size as u16,
+			halted: AtomicBool::new(false),
+			}).ok().unwrap())
+	}
+	fn set_address_zero_info(&self, info: host::DeviceInfo) {
+		// Root port devices use the speed from PORTSC (which also reports SuperSpeed), devices behind a hub use
+		// this information to build the route string.
+		*self.host.pending_info.lock() = Some(info);
+	}
+	fn set_hub_info(&self, addr: u8, num_ports: u8, tt_think_time: u8) {
+		// Applied before the first downstream device is addressed (see `configure_hub_slot`)
+		match self.host.slot_for_addr(addr)
+		{
+		Some(slot) => slot.set_hub(num_ports, tt_think_time),
+		None => log_warning!("set_hub_info: No slot for device {}", addr),
+		}
+	}
+	fn device_removed(&self, addr: u8) {
+		self.host.release_device(addr);
+	}
+
+
+	// Root hub maintainence
+	fn set_port_feature(&self, port: usize, feature: PortFeature) {
+		log_trace!("set_port_feature({}, {:?})", port, feature);
+		let v = self.host.regs.read_portsc(port) & hw::PORTSC_PRESERVE_MASK;
+		let v = match feature
+			{
+			// Ports are enabled by the controller once reset completes
+			PortFeature::Enable => return,
+			PortFeature::Suspend => v | hw::PLS_U3 << hw::PORTSC_PLS_ofs | hw::PORTSC_LWS,
+			PortFeature::Reset => {
+				// The next SET_ADDRESS is for the device on this port
+				self.host.pending_reset_port.store(port as u8 + 1, Ordering::SeqCst);
+				v | hw::PORTSC_PR
+				},
+			PortFeature::Power => v | hw::PORTSC_PP,
+			_ => return,
+			};
+		self.host.regs.write_portsc(port, v);
+	}
+	fn clear_port_feature(&self, port: usize, feature: PortFeature) {
+		log_trace!("clear_port_feature({}, {:?})", port, feature);
+		let v = self.host.regs.read_portsc(port) & hw::PORTSC_PRESERVE_MASK;
+		let v = match feature
+			{
+			PortFeature::Enable  => v | hw::PORTSC_PED,	// Write-1-to-disable
+			PortFeature::Suspend => v | 0 << hw::PORTSC_PLS_ofs | hw::PORTSC_LWS,	// Move to U0
+			PortFeature::Power   => v & !hw::PORTSC_PP,
+			// Reset completes by itself, wait for that
+			PortFeature::Reset   => {
+				let regs = &self.host.regs;
+				if !wait_for(500, || regs.read_portsc(port) & hw::PORTSC_PR == 0) {
+					log_warning!("Port {}: Reset didn't complete", port+1);
+				}
+				return ;
+				},
+			PortFeature::CConnection => {
+				self.host.regs.write_portsc(port, v | hw::PORTSC_CSC);
+				// Whatever was attached has gone (even if there's a new connection)
+				self.host.release_port(port);
+				return ;
+				},
+			PortFeature::CEnable     => v | hw::PORTSC_PEC,
+			PortFeature::CSuspend    => v | hw::PORTSC_PLC,
+			PortFeature::COverCurrent=> v | hw::PORTSC_OCC,
+			PortFeature::CReset      => v | hw::PORTSC_PRC | hw::PORTSC_WRC,
+			_ => return,
+			};
+		self.host.regs.write_portsc(port, v);
+	}
+	fn get_port_feature(&self, port: usize, feature: PortFeature) -> bool {
+		log_trace!("get_port_feature({}, {:?})", port, feature);
+		let v = self.host.regs.read_portsc(port);
+		let mask = match feature
+			{
+			PortFeature::Connection  => hw::PORTSC_CCS,
+			PortFeature::Enable      => hw::PORTSC_PED,
+			PortFeature::Suspend     => return (v >> hw::PORTSC_PLS_ofs) & 0xF == hw::PLS_U3,
+			PortFeature::OverCurrent => hw::PORTSC_OCA,
+			PortFeature::Reset       => hw::PORTSC_PR,
+			PortFeature::Power       => hw::PORTSC_PP,
+			PortFeature::LowSpeed    => return hw::Speed::from_portsc(v) == Some(hw::Speed::Low),
+			PortFeature::HighSpeed   => return hw::Speed::from_portsc(v) == Some(hw::Speed::High),
+			PortFeature::CConnection => hw::PORTSC_CSC,
+			PortFeature::CEnable     => hw::PORTSC_PEC,
+			PortFeature::CSuspend    => hw::PORTSC_PLC,
+			PortFeature::COverCurrent=> hw::PORTSC_OCC,
+			PortFeature::CReset      => hw::PORTSC_PRC | hw::PORTSC_WRC,
+			PortFeature::Test        => return false,
+			PortFeature::Indicator   => return false,
+			PortFeature::SuperSpeed  => return match hw::Speed::from_portsc(v)
+				{
+				Some(hw::Speed::Super) | Some(hw::Speed::SuperPlus) => true,
+				_ => false,
+				},
+			};
+		v & mask != 0
+	}
+
+	fn async_wait_root(&self) -> usb_core::host::AsyncWaitRoot {
+		struct AsyncWaitRoot {
+			host: ArefBorrow<HostInner>,
+		}
+		impl core::future::Future for AsyncWaitRoot {
+			type Output = usize;
+			fn poll(self: core::pin::Pin<&mut Self>, cx: &mut core::task::Context) -> core::task::Poll<Self::Output> {
+				for i in 0 .. self.host.nports as usize
+				{
+					let bit = 1 << (i % 32);
+					if self.host.port_update[i / 32].fetch_and(!bit, Ordering::SeqCst) & bit != 0 {
+						return core::task::Poll::Ready(i);
+					}
+				}
+				*self.host.waker.lock() = cx.waker().clone();
+				// Check again, in case an update happened before the waker was registered
+				for i in 0 .. self.host.nports as usize
+				{
+					let bit = 1 << (i % 32);
+					if self.host.port_update[i / 32].fetch_and(!bit, Ordering::SeqCst) & bit != 0 {
+						return core::task::Poll::Ready(i);
+					}
+				}
+				core::task::Poll::Pending
+			}
+		}
+		usb_core::host::AsyncWaitRoot::new(AsyncWaitRoot {
+			host: self.host.reborrow(),
+			}).ok().expect("Over-size task in")
+	}
+}
+
+struct ControlEndpointHandle {
+	host: ArefBorrow<HostInner>,
+	addr: u8,
+	ep: u8,
+	max_packet_size: u16,
+}
+impl host::ControlEndpoint for ControlEndpointHandle
+{
+	fn out_only<'a>(&'a self, setup_data: &'a [u8], out_data: &'a [u8]) -> host::AsyncWaitIo<'a>
+	{
+		let data = if out_data.len() == 0 { Buffer::None } else { Buffer::Out(out_data) };
+		make_io!(self.host.control(self.addr, self.ep, self.max_packet_size as usize, setup_data, data))
+	}
+	fn in_only<'a>(&'a self, setup_data: &'a [u8], in_data: &'a mut [u8]) -> host::AsyncWaitIo<'a>
+	{
+		make_io!(self.host.control(self.addr, self.ep, self.max_packet_size as usize, setup_data, Buffer::In(in_data)))
+	}
+}
+
+struct BulkEndpointHandle {
+	host: ArefBorrow<HostInner>,
+	addr: u8,
+	ep: u8,
+	max_packet_size: u16,
+	/// The most recent transfer was stalled by the device
+	halted: AtomicBool,
+}
+impl host::BulkEndpoint for BulkEndpointHandle
+{
+	fn send<'a>(&'a self, buffer: &'a [u8]) -> host::AsyncWaitIo<'a>
+	{
+		make_io!(self.host.bulk(self.addr, self.ep, self.max_packet_size as usize, Buffer::Out(buffer), &self.halted))
+	}
+	fn recv<'a>(&'a self, buffer: &'a mut [u8]) -> host::AsyncWaitIo<'a>
+	{
+		make_io!(self.host.bulk(self.addr, self.ep, self.max_packet_size as usize, Buffer::In(buffer), &self.halted))
+	}
+	fn is_halted(&self) -> bool
+	{
+		self.halted.load(Ordering::SeqCst)
+	}
+	fn reset(&self)
+	{
+		// The failed transfer already issued a Reset Endpoint command (which resets the sequence number)
+		self.halted.store(false, Ordering::SeqCst);
+	}
+}
+
+struct InterruptOutEndpointHandle {
+	host: ArefBorrow<HostInner>,
+	addr: u8,
+	ep: u8,
+	max_packet_size: u16,
+	period: u16,
+}
+impl host::InterruptOutEndpoint for InterruptOutEndpointHandle
+{
+	fn send<'a>(&'a self, buffer: &'a [u8]) -> host::AsyncWaitIo<'a>
+	{
+		make_io!(self.host.interrupt_out(self.addr, self.ep, self.max_packet_size as usize, self.period as usize, buffer))
+	}
+}
+
+struct IsochEndpointHandle {
+	host: ArefBorrow<HostInner>,
+	addr: u8,
+	ep: u8,
+	max_packet_size: u16,
+	period: u16,
+}
+impl host::IsochEndpoint for IsochEndpointHandle
+{
+	fn get_current_frame_and_time(&self) -> (u32, ::kernel::time::TickCount)
+	{
+		(self.host.regs.read_frame(), ::kernel::time::ticks())
+	}
+	fn send_at<'a>(&'a self, buffer: &'a [u8], abs_frame: u32) -> host::AsyncWaitIo<'a>
+	{
+		make_io!(self.host.isoch(self.addr, self.ep, self.max_packet_size as usize, self.period as usize, Buffer::Out(buffer), abs_frame))
+	}
+	fn recv_at<'a>(&'a self, buffer: &'a mut [u8], abs_frame: u32) -> host::AsyncWaitIo<'a>
+	{
+		make_io!(self.host.isoch(self.addr, self.ep, self.max_packet_size as usize, self.period as usize, Buffer::In(buffer), abs_frame))
+	}
+}
+
+struct InterruptState
+{
+	addr: u8,
+	ep: u8,
+	max_packet_size: usize,
+	period: usize,
+	/// DMA buffer for received data
+	buffer: AllocHandle,
+	/// Length of the most recently received data
+	last_len: AtomicUsize,
+}
+struct InterruptEndpointHandle {
+	host: ArefBorrow<HostInner>,
+	state: Box<InterruptState>,
+}
+impl host::InterruptEndpoint for InterruptEndpointHandle
+{
+	fn get_data(&self) -> Handle<dyn usb_core::handle::RemoteBuffer>
+	{
+		let len = self.state.last_len.load(Ordering::SeqCst);
+		let data = self.state.buffer.as_slice::<u8>(0, len).to_vec().into_boxed_slice();
+		Handle::new(DataCopy(data)).ok().expect("Over-size data handle")
+	}
+	fn wait<'a>(&'a self) -> host::AsyncWaitIo<'a>
+	{
+		make_io!(self.host.interrupt_in(&self.state))
+	}
+}
+
+/// Copy of received interrupt data
+struct DataCopy(Box<[u8]>);
+impl usb_core::handle::RemoteFree for DataCopy
+{
+	unsafe fn free_self(&mut self) {
+		// Freed when the handle is dropped
+	}
+}
+impl usb_core::handle::RemoteBuffer for DataCopy
+{
+	fn get(&self) -> &[u8] {
+		&self.0
+	}
+}
+
+impl ::kernel::device_manager::DriverInstance for BusDev
+{
+}
@@ -0,0 +1,48 @@
+// "Tifflin" Kernel - xHCI USB driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_xhci/pci.rs
+//! PCI bindings
+use kernel::prelude::*;
+use kernel::device_manager;
+
+pub struct PciDriver;
+
+impl device_manager::Driver for PciDriver {
+	fn name(&self) -> &str {
+		"xhci-pci"
+	}
+	fn bus_type(&self) -> &str {
+		"pci"
+	}
+	fn handles(&self, bus_dev: &dyn device_manager::BusDevice) -> u32
+	{
+		let class = bus_dev.get_attr("class").unwrap_u32();
+		if class & 0xFF_FF_FF_00 == 0x0C0330_00 {
+			1
+		}
+		else {
+			0
+		}
+	}
+	fn bind(&self, bus_dev: &mut dyn device_manager::BusDevice) -> Box<dyn device_manager::DriverInstance+'static>
+	{
+		bus_dev.set_attr("bus_master", device_manager::AttrValue::U32(1));
+		let irq = bus_dev.get_irq(0);
+		let base = bus_dev.bind_io(0);
+
+		match crate::BusDev::new_boxed(irq, base)
+		{
+		Ok(v) => v,
+		Err(e) => {
+			log_error!("Unable to initialise xHCI controller - {}", e);
+			Box::new(NullDevice)
+			},
+		}
+	}
+}
+
+/// Placeholder for a controller that failed to initialise
+struct NullDevice;
+impl device_manager::DriverInstance for NullDevice {
+}
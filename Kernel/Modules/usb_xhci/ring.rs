@@ -0,0 +1,402 @@
+// "Tifflin" Kernel - xHCI USB driver
+// - By John Hodge (thePowersGang)
+//
+// Modules/usb_xhci/ring.rs
+//! TRB rings (command/transfer rings, and the event ring)
+use kernel::prelude::*;
+use kernel::sync::Spinlock;
+use kernel::memory::virt::AllocHandle;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task;
+use crate::hw::{self, Trb};
+
+/// Number of TRBs in a ring (one page)
+pub const RING_SIZE: usize = ::kernel::PAGE_SIZE / ::core::mem::size_of::<Trb>();
+/// Index of the link TRB in a producer ring
+const LINK_IDX: usize = RING_SIZE - 1;
+/// Control word of the link TRB (excluding the chain and cycle bits)
+const LINK_CONTROL: u32 = (hw::TRB_LINK as u32) << hw::TRB_TYPE_ofs | hw::TRB_TC;
+
+/// Completion status of a TD (or command)
+#[derive(Copy,Clone,Debug)]
+pub struct Completion
+{
+	pub code: u8,
+	/// Index of the TRB that generated the event
+	pub trb_idx: usize,
+	/// Residual byte count (transfer events)
+	pub residual: u32,
+	/// Slot ID (command completion events)
+	pub slot_id: u8,
+}
+
+#[derive(Default)]
+struct TrbMeta
+{
+	/// Index of the last TRB in the TD containing this TRB
+	td_last: usize,
+	/// If set, a short packet on this TRB ends the TD (false for control data stages)
+	short_ends_td: bool,
+	/// (last TRB only) Number of ring entries used by the TD
+	td_slots: usize,
+	/// Event for this specific TRB (if one was generated)
+	event: Option<Completion>,
+	/// (last TRB only) Final status of the TD
+	result: Option<Completion>,
+	waker: Option<task::Waker>,
+}
+
+/// A ring written by software (command ring or a transfer ring)
+pub struct ProducerRing
+{
+	page: AllocHandle,
+	/// Enqueue index, and producer cycle state
+	state: Spinlock<(usize, bool)>,
+	/// Number of entries in use (submitted and not yet completed)
+	used: AtomicUsize,
+	meta: Vec<Spinlock<TrbMeta>>,
+}
+
+impl ProducerRing
+{
+	pub fn new(bits: u8) -> Result<ProducerRing, &'static str>
+	{
+		let mut page = crate::alloc_dma_zeroed(bits)?;
+		let base = ::kernel::memory::virt::get_phys(page.as_ref::<Trb>(0)) as u64;
+		// The last entry links back to the start (toggling the cycle state)
+		*page.as_mut::<Trb>(LINK_IDX * 16) = Trb {
+			param: base,
+			status: 0,
+			control: LINK_CONTROL,
+			};
+		Ok(ProducerRing {
+			page: page,
+			state: Spinlock::new( (0, true) ),
+			used: AtomicUsize::new(0),
+			meta: (0 .. RING_SIZE).map(|_| Default::default()).collect(),
+			})
+	}
+
+	/// Physical address of the first entry
+	pub fn base_phys(&self) -> u64 {
+		::kernel::memory::virt::get_phys(self.page.as_ref::<Trb>(0)) as u64
+	}
+	/// Convert a TRB pointer (from an event) into an index
+	pub fn index_of(&self, phys: u64) -> Option<usize> {
+		let base = self.base_phys();
+		if base <= phys && phys < base + (LINK_IDX * 16) as u64 {
+			Some( ((phys - base) / 16) as usize )
+		}
+		else {
+			None
+		}
+	}
+	/// Current enqueue pointer, with the cycle state in bit 0 (as used by "Set TR Dequeue Pointer")
+	pub fn enqueue_ptr(&self) -> u64 {
+		let lh = self.state.lock();
+		self.base_phys() + (lh.0 * 16) as u64 | lh.1 as u64
+	}
+
+	/// Add a TD to the ring, returning the indexes of its first and last TRBs
+	///
+	/// The cycle bit of each TRB is set by this function, and TRBs other than the last are chained. If
+	/// `short_ends_td` is false, short packets only end the TD if they occur on the last TRB.
+	pub fn push(&self, trbs: &[Trb], short_ends_td: bool) -> Option<(usize, usize)>
+	{
+		assert!(trbs.len() > 0);
+		let mut lh = self.state.lock();
+		// Worst case: one extra entry for the link TRB
+		let needed = trbs.len() + 1;
+		if self.used.load(Ordering::SeqCst) + needed > LINK_IDX {
+			log_warning!("TRB ring full ({} used, {} needed)", self.used.load(Ordering::SeqCst), needed);
+			return None;
+		}
+
+		let (first_idx, first_cycle) = *lh;
+		let (last_idx, slots) = layout_td(&mut *lh, trbs, |idx, trb, control| {
+			// SAFE: The controller doesn't own entries with the wrong cycle bit
+			unsafe {
+				match trb
+				{
+				Some(trb) => self.write_trb(idx, Trb { param: trb.param, status: trb.status, control: control }),
+				None => ::core::ptr::write_volatile(&mut (*self.trb_ptr(idx)).control, control),
+				}
+			}
+			});
+
+		// Prepare the completion metadata before the controller can see the TD
+		let mut idx = first_idx;
+		loop
+		{
+			let mut m = self.meta[idx].lock();
+			m.td_last = last_idx;
+			m.short_ends_td = short_ends_td || idx == last_idx;
+			m.td_slots = if idx == last_idx { slots } else { 0 };
+			m.event = None;
+			m.result = None;
+			m.waker = None;
+			if idx == last_idx {
+				break;
+			}
+			idx = if idx + 1 == LINK_IDX { 0 } else { idx + 1 };
+		}
+		self.used.fetch_add(slots, Ordering::SeqCst);
+
+		// Release the TD to the controller
+		::core::sync::atomic::fence(Ordering::SeqCst);
+		// SAFE: Only the cycle bit changes
+		unsafe {
+			let p = self.trb_ptr(first_idx);
+			let v = ::core::ptr::read_volatile(&(*p).control) & !hw::TRB_CYCLE;
+			::core::ptr::write_volatile(&mut (*p).control, if first_cycle { v | hw::TRB_CYCLE } else { v });
+		}
+		Some( (first_idx, last_idx) )
+	}
+
+	/// Handle an event referencing a TRB on this ring
+	pub fn handle_event(&self, idx: usize, c: Completion)
+	{
+		let (td_last, ends_td) = {
+			let mut m = self.meta[idx].lock();
+			m.event = Some(c);
+			(m.td_last, completes_td(c.code, idx == m.td_last, m.short_ends_td))
+			};
+		if ends_td
+		{
+			let waker = {
+				let mut m = self.meta[td_last].lock();
+				if m.result.is_some() {
+					// Already completed (e.g. by an earlier short packet)
+					return ;
+				}
+				m.result = Some(c);
+				self.used.fetch_sub(m.td_slots, Ordering::SeqCst);
+				m.waker.take()
+				};
+			if let Some(w) = waker {
+				w.wake();
+			}
+		}
+	}
+
+	/// Position of a TRB within a TD starting at `first_idx` (skipping the link TRB)
+	pub fn td_offset(first_idx: usize, idx: usize) -> usize {
+		(idx + LINK_IDX - first_idx) % LINK_IDX
+	}
+	/// Index of the TRB `ofs` entries after `first_idx`
+	pub fn td_index(first_idx: usize, ofs: usize) -> usize {
+		(first_idx + ofs) % LINK_IDX
+	}
+
+	/// Event reported for a TRB (e.g. to get the residual of a control data stage)
+	pub fn get_event(&self, idx: usize) -> Option<Completion> {
+		self.meta[idx].lock().event
+	}
+
+	/// Wait for the TD ending at `last_idx` to complete
+	pub fn wait(&self, last_idx: usize) -> WaitTd
+	{
+		WaitTd { ring: self, idx: last_idx }
+	}
+
+	fn trb_ptr(&self, idx: usize) -> *mut Trb {
+		// SAFE: Only used for volatile accesses
+		unsafe { self.page.as_int_mut::<Trb>(idx * 16) as *mut Trb }
+	}
+	unsafe fn write_trb(&self, idx: usize, trb: Trb) {
+		let p = self.trb_ptr(idx);
+		::core::ptr::write_volatile(&mut (*p).param, trb.param);
+		::core::ptr::write_volatile(&mut (*p).status, trb.status);
+		::core::sync::atomic::fence(Ordering::SeqCst);
+		::core::ptr::write_volatile(&mut (*p).control, trb.control);
+	}
+}
+
+/// Write a TD starting at the enqueue position `pos` (index and cycle state), advancing it
+///
+/// `write` is called with each entry's index, TRB (`None` for the link TRB, which only has its control word written)
+/// and control word. Returns the index of the last TRB, and the number of ring entries used.
+fn layout_td<F>(pos: &mut (usize, bool), trbs: &[Trb], mut write: F) -> (usize, usize)
+where
+	F: FnMut(usize, Option<&Trb>, u32)
+{
+	let mut slots = 0;
+	let mut last_idx = 0;
+	for (i, trb) in trbs.iter().enumerate()
+	{
+		let is_last = i == trbs.len() - 1;
+		let (idx, cycle) = *pos;
+		let mut control = trb.control & !(hw::TRB_CYCLE | hw::TRB_CH);
+		if !is_last {
+			control |= hw::TRB_CH;
+		}
+		// The first TRB is written with the wrong cycle bit, and fixed once the rest of the TD is written
+		if cycle != (i == 0) {
+			control |= hw::TRB_CYCLE;
+		}
+		write(idx, Some(trb), control);
+		last_idx = idx;
+		slots += 1;
+
+		pos.0 += 1;
+		if pos.0 == LINK_IDX {
+			// Hand the link TRB to the controller (chained if the TD continues past it)
+			let mut control = LINK_CONTROL;
+			if !is_last {
+				control |= hw::TRB_CH;
+			}
+			if cycle {
+				control |= hw::TRB_CYCLE;
+			}
+			write(LINK_IDX, None, control);
+			slots += 1;
+			pos.0 = 0;
+			pos.1 = !pos.1;
+		}
+	}
+	(last_idx, slots)
+}
+
+/// Check if an event with completion code `code` ends its TD
+fn completes_td(code: u8, is_last: bool, short_ends_td: bool) -> bool
+{
+	match code
+	{
+	hw::CC_SUCCESS => is_last,
+	hw::CC_SHORT_PACKET => short_ends_td,
+	_ => true,
+	}
+}
+
+pub struct WaitTd<'a>
+{
+	ring: &'a ProducerRing,
+	idx: usize,
+}
+impl<'a> ::core::future::Future for WaitTd<'a>
+{
+	type Output = Completion;
+	fn poll(self: ::core::pin::Pin<&mut Self>, cx: &mut task::Context<'_>) -> task::Poll<Completion> {
+		let mut m = self.ring.meta[self.idx].lock();
+		match m.result
+		{
+		Some(v) => task::Poll::Ready(v),
+		None => {
+			m.waker = Some(cx.waker().clone());
+			task::Poll::Pending
+			},
+		}
+	}
+}
+
+/// The (single segment) event ring, consumed by the interrupt handler
+pub struct EventRing
+{
+	page: AllocHandle,
+	/// Dequeue index, and consumer cycle state
+	state: Spinlock<(usize, bool)>,
+}
+impl EventRing
+{
+	pub fn new(bits: u8) -> Result<EventRing, &'static str>
+	{
+		Ok(EventRing {
+			page: crate::alloc_dma_zeroed(bits)?,
+			state: Spinlock::new( (0, true) ),
+			})
+	}
+	pub fn base_phys(&self) -> u64 {
+		::kernel::memory::virt::get_phys(self.page.as_ref::<Trb>(0)) as u64
+	}
+	/// Current dequeue pointer (for ERDP)
+	pub fn dequeue_phys(&self) -> u64 {
+		self.base_phys() + (self.state.lock().0 * 16) as u64
+	}
+
+	/// Take the next event (if the controller has written one)
+	pub fn pop(&self) -> Option<Trb>
+	{
+		let mut lh = self.state.lock();
+		// SAFE: Volatile read of DMA memory
+		let trb = unsafe { ::core::ptr::read_volatile(self.page.as_ref::<Trb>(lh.0 * 16)) };
+		if trb.cycle() != lh.1 {
+			return None;
+		}
+		lh.0 += 1;
+		if lh.0 == RING_SIZE {
+			lh.0 = 0;
+			lh.1 = !lh.1;
+		}
+		Some(trb)
+	}
+}
+
+/// Lay out `n` TRBs (with IOC set and `param` numbering them), returning the writes made
+#[cfg(test)]
+fn test_layout(pos: &mut (usize, bool), n: usize) -> (usize, usize, Vec<(usize, Option<u64>, u32)>)
+{
+	let trbs: Vec<Trb> = (0 .. n).map(|i| Trb { param: i as u64, status: 0, control: hw::TRB_IOC }).collect();
+	let mut writes = Vec::new();
+	let (last, slots) = layout_td(pos, &trbs, |idx, trb, control| writes.push( (idx, trb.map(|t| t.param), control) ));
+	(last, slots, writes)
+}
+
+#[test]
+fn layout_simple()
+{
+	const CH: u32 = hw::TRB_CH;
+	const C: u32 = hw::TRB_CYCLE;
+	const IOC: u32 = hw::TRB_IOC;
+	// First TRB has the inverted cycle bit, all but the last are chained
+	let mut pos = (0, true);
+	assert_eq!( test_layout(&mut pos, 3), (2, 3, vec![(0, Some(0), IOC|CH), (1, Some(1), IOC|CH|C), (2, Some(2), IOC|C)]) );
+	assert_eq!( pos, (3, true) );
+	// - Same on the second lap (with cycle clear)
+	let mut pos = (3, false);
+	assert_eq!( test_layout(&mut pos, 2), (4, 2, vec![(3, Some(0), IOC|CH|C), (4, Some(1), IOC)]) );
+	assert_eq!( pos, (5, false) );
+}
+#[test]
+fn layout_wrap()
+{
+	const CH: u32 = hw::TRB_CH;
+	const C: u32 = hw::TRB_CYCLE;
+	const IOC: u32 = hw::TRB_IOC;
+	// TD crossing the link is chained through it, with the cycle toggling after it
+	let mut pos = (LINK_IDX - 1, true);
+	assert_eq!( test_layout(&mut pos, 3), (1, 4, vec![
+		(LINK_IDX-1, Some(0), IOC|CH),
+		(LINK_IDX, None, LINK_CONTROL|CH|C),
+		(0, Some(1), IOC|CH),
+		(1, Some(2), IOC),
+		]) );
+	assert_eq!( pos, (2, false) );
+	// TD ending just before the link, the link isn't chained
+	let mut pos = (LINK_IDX - 1, false);
+	assert_eq!( test_layout(&mut pos, 1), (LINK_IDX-1, 2, vec![(LINK_IDX-1, Some(0), IOC|C), (LINK_IDX, None, LINK_CONTROL)]) );
+	assert_eq!( pos, (0, true) );
+}
+#[test]
+fn td_indexes()
+{
+	assert_eq!( ProducerRing::td_offset(5, 5), 0 );
+	assert_eq!( ProducerRing::td_offset(5, 7), 2 );
+	// - The link TRB isn't counted
+	assert_eq!( ProducerRing::td_offset(LINK_IDX-1, 0), 1 );
+	assert_eq!( ProducerRing::td_offset(LINK_IDX-2, 1), 3 );
+	assert_eq!( ProducerRing::td_index(5, 2), 7 );
+	assert_eq!( ProducerRing::td_index(LINK_IDX-1, 1), 0 );
+	assert_eq!( ProducerRing::td_index(LINK_IDX-2, 3), 1 );
+}
+#[test]
+fn td_completion()
+{
+	assert!( !completes_td(hw::CC_SUCCESS, false, true) );
+	assert!(  completes_td(hw::CC_SUCCESS, true, false) );
+	// Short packets end the TD unless they're on a control data stage
+	assert!(  completes_td(hw::CC_SHORT_PACKET, false, true) );
+	assert!( !completes_td(hw::CC_SHORT_PACKET, false, false) );
+	assert!(  completes_td(hw::CC_SHORT_PACKET, true, true) );
+	// Errors always end it
+	assert!(  completes_td(hw::CC_STALL, false, false) );
+}
@@ -8,6 +8,7 @@ use queue::{Queue,Buffer};
 #[allow(dead_code)]
 mod defs {
 pub const VIRTIO_BLK_F_RO	: u32 = 1 << 5;
+pub const VIRTIO_BLK_F_FLUSH	: u32 = 1 << 9;
 // TODO: Other feature flags
 
 pub const VIRTIO_BLK_T_IN    	: u32 = 0;
@@ -30,6 +31,8 @@ struct Volume<I: Interface>
 	interface: I,
 	capacity: u64,
 	requestq: Queue,
+	/// Device has a volatile write cache (and supports VIRTIO_BLK_T_FLUSH)
+	has_flush: bool,
 }
 
 impl BlockDevice
@@ -41,7 +44,7 @@ impl BlockDevice
 
 		let requestq = int.get_queue(0, 0).expect("Queue #0 'requestq' missing on virtio block device");
 	
-		let features = int.negotiate_features( VIRTIO_BLK_F_RO );
+		let features = int.negotiate_features( VIRTIO_BLK_F_RO | VIRTIO_BLK_F_FLUSH );
 		if features & VIRTIO_BLK_F_RO != 0 {
 			// TODO: Need a way of indicating to the upper layers that a volume is read-only
 		}
@@ -50,6 +53,7 @@ impl BlockDevice
 		let mut vol = Box::new(Volume {
 			requestq: requestq,
 			capacity: capacity,
+			has_flush: features & VIRTIO_BLK_F_FLUSH != 0,
 			interface: int,
 			});
 
@@ -80,7 +84,7 @@ const BLOCK_SIZE: usize = 512;
 impl<I: Interface+Send+'static> storage::PhysicalVolume for Volume<I>
 {
 	fn name(&self) -> &str { "virtio0" }
-	fn blocksize(&self) -> usize { BLOCK_SIZE }
+	fn blocksize(&self) -> Result<usize,storage::IoError> { Ok(BLOCK_SIZE) }
 	fn capacity(&self) -> Option<u64> { Some(self.capacity) }
 	
 	fn read<'a>(&'a self, prio: u8, idx: u64, num: usize, dst: &'a mut [u8]) -> storage::AsyncIoResult<'a,usize>
@@ -141,6 +145,35 @@ impl<I: Interface+Send+'static> storage::PhysicalVolume for Volume<I>
 		// Do nothing, no support for TRIM
 		Box::new(async::NullResultWaiter::new( || Ok( () ) ))
 	}
+	
+	fn flush<'a>(&'a self) -> storage::AsyncIoResult<'a,()>
+	{
+		// Without VIRTIO_BLK_F_FLUSH, the device is write-through
+		if !self.has_flush {
+			return Box::new(async::NullResultWaiter::new( || Ok( () ) ));
+		}
+		let cmd = VirtioBlockReq {
+			type_: VIRTIO_BLK_T_FLUSH,
+			ioprio: 0,
+			sector: 0,
+			};
+		let mut status = 0u8;
+
+		let rv = {
+			let h = self.requestq.send_buffers(&self.interface, &mut[
+				Buffer::Read( ::kernel::lib::as_byte_slice(&cmd) ),
+				Buffer::Write( ::kernel::lib::as_byte_slice_mut(&mut status) )
+				]);
+			match h.wait_for_completion()
+				{
+				Ok(_) => Ok( () ),
+				Err( () ) => Err( storage::IoError::Unknown("VirtIO") ),
+				}
+			};
+		let rv = if rv.is_ok() && status != 0 { Err( storage::IoError::Unknown("VirtIO flush") ) } else { rv };
+
+		Box::new(async::NullResultWaiter::new( move || rv ))
+	}
 
 }
 
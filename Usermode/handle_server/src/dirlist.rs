@@ -0,0 +1,201 @@
+// Tifflin OS - handle_server
+// - By John Hodge (thePowersGang)
+//
+// handle_server/src/dirlist.rs
+//! Directory browser element for the file picker
+use std::cell::{Cell,RefCell};
+use wtk::geom::Rect;
+use wtk::Colour;
+use subtree;
+
+const ROW_HEIGHT: u32 = 16;
+
+struct Entry
+{
+	name: Vec<u8>,
+	is_dir: bool,
+}
+
+/// List of the entries in a directory (within the connection's root)
+///
+/// Up/Down select an entry, Return enters a directory or picks a file
+pub struct DirList<'a>
+{
+	root: &'a ::syscalls::vfs::Dir,
+	/// Path of the displayed directory (relative to the root)
+	cur_path: RefCell<Vec<u8>>,
+	entries: RefCell<Vec<Entry>>,
+	selected: Cell<usize>,
+	/// First visible entry
+	view_offset: Cell<usize>,
+	dirty: Cell<bool>,
+	on_pick: Box<dyn Fn(&mut dyn ::wtk::WindowTrait, &[u8]) + 'a>,
+}
+
+impl<'a> DirList<'a>
+{
+	pub fn new(root: &'a ::syscalls::vfs::Dir) -> DirList<'a>
+	{
+		let rv = DirList {
+			root: root,
+			cur_path: Default::default(),
+			entries: Default::default(),
+			selected: Cell::new(0),
+			view_offset: Cell::new(0),
+			dirty: Cell::new(true),
+			on_pick: Box::new(|_,_| ()),
+			};
+		rv.populate();
+		rv
+	}
+
+	/// Bind to a file being picked (select+enter), with the file's path
+	pub fn on_pick<F: 'a>(&mut self, f: F)
+	where
+		F: Fn(&mut dyn ::wtk::WindowTrait, &[u8])
+	{
+		self.on_pick = Box::new(f);
+	}
+
+	/// Path of the current directory (relative to the root, empty for the root)
+	pub fn cur_path(&self) -> ::std::cell::Ref<Vec<u8>> {
+		self.cur_path.borrow()
+	}
+	/// Path of the selected entry, if it's a file
+	pub fn selected_file(&self) -> Option<Vec<u8>> {
+		match self.entries.borrow().get(self.selected.get())
+		{
+		Some(e) if !e.is_dir => Some(self.child_path(&e.name)),
+		_ => None,
+		}
+	}
+
+	fn child_path(&self, name: &[u8]) -> Vec<u8> {
+		let mut rv = self.cur_path.borrow().clone();
+		if rv.len() > 0 {
+			rv.push(b'/');
+		}
+		rv.extend_from_slice(name);
+		rv
+	}
+
+	/// Re-read the current directory
+	fn populate(&self)
+	{
+		let mut entries = Vec::new();
+		if self.cur_path.borrow().len() > 0 {
+			entries.push(Entry { name: b"..".to_vec(), is_dir: true });
+		}
+		let dir_iter = subtree::open_dir(self.root, &self.cur_path.borrow()).and_then(|d| d.enumerate().map(|i| (d, i)));
+		if let Ok( (dir, mut iter) ) = dir_iter
+		{
+			let mut namebuf = [0; 256];
+			let mut names = Vec::new();
+			while let Ok(Some(name)) = iter.read_ent(&mut namebuf)
+			{
+				if name == b"." || name == b".." {
+					continue ;
+				}
+				// Only list what can be opened (symbolic links are refused)
+				let is_dir = match subtree::open_child(&dir, name).map(|n| n.class())
+					{
+					Ok(::syscalls::vfs::NodeType::Dir) => true,
+					Ok(::syscalls::vfs::NodeType::File) => false,
+					_ => continue,
+					};
+				names.push( Entry { name: name.to_vec(), is_dir: is_dir } );
+			}
+			// Directories first, then by name
+			names.sort_by(|a, b| (!a.is_dir, &a.name).cmp(&(!b.is_dir, &b.name)));
+			entries.extend(names);
+		}
+		*self.entries.borrow_mut() = entries;
+		self.selected.set(0);
+		self.view_offset.set(0);
+		self.dirty.set(true);
+	}
+
+	/// Act on the selected entry (returns true if the list changed)
+	fn activate(&self, win: &mut dyn ::wtk::WindowTrait) -> bool
+	{
+		let (name, is_dir) = match self.entries.borrow().get(self.selected.get())
+			{
+			Some(e) => (e.name.clone(), e.is_dir),
+			None => return false,
+			};
+		if !is_dir {
+			(self.on_pick)(win, &self.child_path(&name));
+			false
+		}
+		else {
+			let new_path = if name == b".." {
+					subtree::split_last(&self.cur_path.borrow()).0.to_vec()
+				}
+				else {
+					self.child_path(&name)
+				};
+			*self.cur_path.borrow_mut() = new_path;
+			self.populate();
+			true
+		}
+	}
+}
+
+impl<'a> ::wtk::Element for DirList<'a>
+{
+	fn handle_event(&self, ev: ::wtk::InputEvent, win: &mut dyn ::wtk::WindowTrait) -> bool {
+		let count = self.entries.borrow().len();
+		match ev
+		{
+		::wtk::InputEvent::KeyUp(::wtk::KeyCode::UpArrow) if self.selected.get() > 0 => {
+			self.selected.set( self.selected.get() - 1 );
+			self.dirty.set(true);
+			true
+			},
+		::wtk::InputEvent::KeyUp(::wtk::KeyCode::DownArrow) if self.selected.get() + 1 < count => {
+			self.selected.set( self.selected.get() + 1 );
+			self.dirty.set(true);
+			true
+			},
+		::wtk::InputEvent::KeyUp(::wtk::KeyCode::Return) => self.activate(win),
+		_ => false,
+		}
+	}
+	fn render(&self, surface: ::wtk::surface::SurfaceView, force: bool) {
+		if !force && !self.dirty.get() {
+			return ;
+		}
+		let rows = ::std::cmp::max(1, surface.height() / ROW_HEIGHT) as usize;
+		// Keep the selection visible
+		let sel = self.selected.get();
+		if sel < self.view_offset.get() {
+			self.view_offset.set(sel);
+		}
+		else if sel >= self.view_offset.get() + rows {
+			self.view_offset.set(sel + 1 - rows);
+		}
+
+		surface.fill_rect(Rect::new_full(), Colour::theme_text_bg());
+		for (i, ent) in self.entries.borrow().iter().enumerate().skip(self.view_offset.get()).take(rows)
+		{
+			let y = (i - self.view_offset.get()) as u32 * ROW_HEIGHT;
+			let fg = if i == sel {
+					surface.fill_rect(Rect::new(0, y, !0, ROW_HEIGHT), Colour::theme_text());
+					Colour::theme_text_bg()
+				}
+				else {
+					Colour::theme_text()
+				};
+			let name = String::from_utf8_lossy(&ent.name);
+			let suffix = if ent.is_dir { "/" } else { "" };
+			surface.draw_text(Rect::new(2, y, !0, ROW_HEIGHT), name.chars().chain(suffix.chars()), fg);
+		}
+		self.dirty.set(false);
+	}
+	fn resize(&self, _w: u32, _h: u32) {
+		self.dirty.set(true);
+	}
+	fn with_element_at_pos(&self, pos: ::wtk::geom::PxPos, _dims: ::wtk::geom::PxDims, f: ::wtk::WithEleAtPosCb) -> bool {
+		f(self, pos)
+	}
+}
@@ -9,53 +9,109 @@ extern crate syscalls;
 
 extern crate loader;
 extern crate handle_server;
+extern crate wtk;
+extern crate async;
 
 use handle_server::protocol;
 
+mod picker;
+mod dirlist;
+mod subtree;
+
 struct Connection
 {
 	name: String,
 	channel: ::syscalls::ipc::RpcChannel,
+	/// Root of the directory tree this connection can access (picked paths are relative to this)
+	root: ::syscalls::vfs::Dir,
+	/// Connection can only be granted read-only handles
+	read_only: bool,
+}
+
+/// Log a granted handle (the audit trail of which connection was given access to what)
+macro_rules! audit_log {
+	($conn:expr, $($t:tt)*) => {
+		kernel_log!("AUDIT: '{}'{} - {}", $conn.name, if $conn.read_only { " (ro)" } else { "" }, format_args!($($t)*))
+	};
 }
 
 fn main()
 {
 	// handle_server gets the read-write root handle for the session user
 	let filesystem_root: ::syscalls::vfs::Dir = ::syscalls::threads::S_THIS_PROCESS.receive_object("RwRoot").expect("Failed to receive FS root");
+	// - And a GUI group handle, used for the file picker
+	::wtk::initialise();
 
 	// Active handle set - pre-populated with connection to session leader
 	let mut handles = vec![
 		Connection {
 			name: String::from("Leader"),
 			channel: ::syscalls::threads::S_THIS_PROCESS.receive_object("HsChan").expect("Failed to receive leader channel"),
+			root: filesystem_root.clone(),
+			read_only: false,
 		}
 		];
 
-	let mut waits: Vec<_> = handles.iter().map(|x| x.channel.wait_rx()).collect();
-
-	loop
+	let mut waits: Vec<_> = Vec::new();
+	while handles.len() > 0
 	{
+		waits.clear();
+		waits.extend( handles.iter().map(|x| x.channel.wait_rx()) );
 		::syscalls::threads::wait(&mut waits, !0);
-		let mut idx = !0;
+
+		let mut new_handles = Vec::new();
+		let mut idx = 0;
 		while idx < handles.len()
 		{
-			idx += 1;
 			let (buffer, _obj) = match handles[idx].channel.try_receive()
 				{
 				Ok(v) => v,
-				Err(::syscalls::ipc::RxError::NoMessage) => continue,
+				Err(::syscalls::ipc::RxError::NoMessage) => {
+					idx += 1;
+					continue
+					},
 				Err(::syscalls::ipc::RxError::ConnectionClosed) => {
 					kernel_log!("Connection '{}' dropped", handles[idx].name);
 					handles.swap_remove(idx);
-					idx -= 1;
+					continue
+					},
+				// - The message would stay queued (and keep waking the server), so drop the client
+				Err(::syscalls::ipc::RxError::NoFreeHandles) => {
+					kernel_log!("Connection '{}' sent an object with no free handles, dropping", handles[idx].name);
+					handles.swap_remove(idx);
 					continue
 					},
 				};
 			let conn = &handles[idx];
+			idx += 1;
 			match protocol::Request::try_from(buffer)
 			{
+			// Request for a new connection (for a child process)
 			Ok(protocol::Request::CreateChild(req)) => {
-				panic!("TODO: Create new connection name={:?}", req.name());
+				let name = format!("{}/{}", conn.name, String::from_utf8_lossy(req.name()));
+				// Children can only be more restricted than their parent
+				let read_only = conn.read_only || req.flags() & protocol::CHILD_FLAG_READONLY != 0;
+				// - And can only see a subtree of their parent's root
+				let root = match subtree::open_dir(&conn.root, req.root())
+					{
+					Ok(v) => v,
+					Err(_) => {
+						conn.channel.send( protocol::RspError::new(protocol::ERR_NOT_FOUND, "Child root not found").into() );
+						continue
+						},
+					};
+				match ::syscalls::ipc::RpcChannel::new_pair()
+				{
+				Ok( (svr_chan, clt_chan) ) => {
+					let child = Connection { name: name, channel: svr_chan, root: root, read_only: read_only };
+					audit_log!(child, "New connection (root {:?})", String::from_utf8_lossy(req.root()));
+					conn.channel.send_obj( protocol::RspNewChannel::new().into(), clt_chan );
+					new_handles.push(child);
+					},
+				Err(_) => {
+					conn.channel.send( protocol::RspError::new(protocol::ERR_GENERAL, "Cannot create channel").into() );
+					},
+				}
 				},
 			// Request to open an executable
 			Ok(protocol::Request::OpenExecutable(req)) => {
@@ -64,35 +120,110 @@ fn main()
 					{
 					b"fileviewer" => b"/system/bin/fileviewer",
 					_ => {
-						conn.channel.send( protocol::RspError::new(0, "Unknown name").into() );
+						conn.channel.send( protocol::RspError::new(protocol::ERR_NOT_FOUND, "Unknown name").into() );
 						continue
 						},
 					};
 				match filesystem_root.open_child_path(path).and_then(|x| x.into_file(::syscalls::vfs::FileOpenMode::Execute))
 				{
 				Ok(fh) => {
+					audit_log!(conn, "Executable {:?}", ::std::str::from_utf8(path));
 					conn.channel.send_obj( protocol::RspOpenedFile::new(path).into(), fh );
 					},
 				Err(_) => {
-					conn.channel.send( protocol::RspError::new(0, "Could not open executable file").into() );
+					conn.channel.send( protocol::RspError::new(protocol::ERR_NOT_FOUND, "Could not open executable file").into() );
 					continue
 					},
 				}
 				},
 			// Request the user pick a file to open
 			Ok(protocol::Request::PickFile(req)) => {
-				// TODO: Spawn a "open file" dialog linked to the calling process
-				panic!("TODO: PickFile(mode={:?}, reason={:?})", req.mode(), req.description_raw());
+				let mode = req.mode();
+				if conn.read_only {
+					match mode
+					{
+					protocol::PickFileMode::ReadWrite | protocol::PickFileMode::Create => {
+						conn.channel.send( protocol::RspError::new(protocol::ERR_PERMISSION_DENIED, "Connection is read-only").into() );
+						continue
+						},
+					_ => {},
+					}
+				}
+				let title = format!("{} - Select a file", conn.name);
+				let path = match picker::run(&conn.root, &title, &String::from_utf8_lossy(req.description_raw()))
+					{
+					picker::Selection::Cancelled => {
+						conn.channel.send( protocol::RspError::new(protocol::ERR_CANCELLED, "Cancelled by user").into() );
+						continue
+						},
+					picker::Selection::Path(p) => p,
+					};
+				match open_picked_file(&conn.root, &path, mode, conn.read_only)
+				{
+				Ok( (fh, mode_str) ) => {
+					audit_log!(conn, "File {:?} ({})", String::from_utf8_lossy(&path), mode_str);
+					conn.channel.send_obj( protocol::RspOpenedFile::new(&path).into(), fh );
+					},
+				Err(e) => {
+					let (code, msg) = match e
+						{
+						::syscalls::vfs::Error::FileNotFound => (protocol::ERR_NOT_FOUND, "File not found"),
+						::syscalls::vfs::Error::PermissionDenied => (protocol::ERR_PERMISSION_DENIED, "Permission denied"),
+						::syscalls::vfs::Error::MalformedPath => (protocol::ERR_NOT_FOUND, "Invalid path"),
+						_ => (protocol::ERR_GENERAL, "Could not open file"),
+						};
+					conn.channel.send( protocol::RspError::new(code, msg).into() );
+					},
+				}
 				},
 			Err(protocol::UnmarshalError::BadValue) => {
 				kernel_log!("NOTICE: Malformed request from '{}' - {}", conn.name, buffer[0]);
-				conn.channel.send( protocol::RspError::new(0, "Bad request").into() );
+				conn.channel.send( protocol::RspError::new(protocol::ERR_GENERAL, "Bad request").into() );
 				},
 			Err(protocol::UnmarshalError::UnknownRequest) => {
 				kernel_log!("NOTICE: Unknown request from '{}' - {}", conn.name, buffer[0]);
-				conn.channel.send( protocol::RspError::new(0, "Unknown request").into() );
+				conn.channel.send( protocol::RspError::new(protocol::ERR_GENERAL, "Unknown request").into() );
 				},
 			}
 		}
+		handles.extend(new_handles);
+	}
+	kernel_log!("All connections closed, exiting");
+}
+
+/// Open a file selected using the picker, with a file mode derived from the requested mode
+fn open_picked_file(root: &::syscalls::vfs::Dir, path: &[u8], mode: protocol::PickFileMode, read_only: bool) -> Result<(::syscalls::vfs::File, &'static str), ::syscalls::vfs::Error>
+{
+	use syscalls::vfs::FileOpenMode;
+	// NOTE: Lookups are confined to the connection's root
+	let open = |mode| subtree::open_node(root, path).and_then(|n| n.into_file(mode));
+	match mode
+	{
+	protocol::PickFileMode::ReadOnly => open(FileOpenMode::ReadOnly).map(|h| (h, "ro")),
+	protocol::PickFileMode::ReadWrite => open(FileOpenMode::ExclRW).map(|h| (h, "rw")),
+	// Create the file, or (as the target is to be over-written) open it for writing if it already exists
+	protocol::PickFileMode::Create => {
+		let (parent, name) = subtree::split_last(path);
+		if name == b"" || name == b"." || name == b".." {
+			return Err(::syscalls::vfs::Error::MalformedPath);
+		}
+		match subtree::open_dir(root, parent)?.create_file(name, FileOpenMode::ExclRW)
+		{
+		Ok(h) => Ok( (h, "new") ),
+		Err(::syscalls::vfs::Error::AlreadyExists) => open(FileOpenMode::ExclRW).map(|h| (h, "rw")),
+		Err(e) => Err(e),
+		}
+		},
+	protocol::PickFileMode::OptionalWrite =>
+		if read_only {
+			open(FileOpenMode::ReadOnly).map(|h| (h, "ro"))
+		}
+		else {
+			match open(FileOpenMode::ExclRW)
+			{
+			Ok(h) => Ok( (h, "rw") ),
+			Err(_) => open(FileOpenMode::ReadOnly).map(|h| (h, "ro")),
+			}
+		},
 	}
 }
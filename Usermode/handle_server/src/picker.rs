@@ -0,0 +1,113 @@
+// Tifflin OS - handle_server
+// - By John Hodge (thePowersGang)
+//
+// handle_server/src/picker.rs
+//! Interactive file picker window
+use std::cell::RefCell;
+
+/// Result of a file picker interaction
+pub enum Selection
+{
+	/// User cancelled the request
+	Cancelled,
+	/// User selected a path (relative to the connection's root)
+	Path(Vec<u8>),
+}
+
+/// Show a modal file picker window, returning once the user confirms or cancels
+///
+/// The user can browse directories within `root`, and either pick a listed file or enter a file name.
+///
+/// NOTE: While the picker is open, other requests to the handle server are not serviced
+pub fn run(root: &::syscalls::vfs::Dir, title: &str, description: &str) -> Selection
+{
+	const BUTTON_WIDTH: u32 = 60;
+	const LINE_HEIGHT: u32 = 16;
+
+	let result: RefCell<Option<Selection>> = RefCell::new(None);
+
+	let mut dir_list = ::dirlist::DirList::new(root);
+	dir_list.on_pick(|_win, path| *result.borrow_mut() = Some( Selection::Path(path.to_owned()) ));
+
+	let mut path_input = ::wtk::TextInput::new();
+	path_input.set_shadow("File name (or select above)");
+	path_input.bind_submit(|input, _win| {
+		if let Some(sel) = get_selection(&dir_list, &input.get_content()) {
+			*result.borrow_mut() = Some(sel);
+		}
+		});
+
+	let description = ::wtk::Label::new(description, ::wtk::Colour::theme_text());
+	let open_button = ::wtk::Button::new(
+		::wtk::Label::new("Open", ::wtk::Colour::theme_text()),
+		|_btn, _win| if let Some(sel) = get_selection(&dir_list, &path_input.get_content()) {
+			*result.borrow_mut() = Some(sel);
+			}
+		);
+	let cancel_button = ::wtk::Button::new(
+		::wtk::Label::new("Cancel", ::wtk::Colour::theme_text()),
+		|_btn, _win| *result.borrow_mut() = Some(Selection::Cancelled)
+		);
+
+	let buttons = ::wtk::StaticBox::new_horiz((
+		::wtk::BoxEle::expand( () ),
+		::wtk::BoxEle::fixed( BUTTON_WIDTH, &open_button ),
+		::wtk::BoxEle::fixed( 4, () ),	// <-- Padding
+		::wtk::BoxEle::fixed( BUTTON_WIDTH, &cancel_button ),
+		));
+	let vbox = ::wtk::Frame::new_fat( ::wtk::StaticBox::new_vert((
+		::wtk::BoxEle::fixed( LINE_HEIGHT, &description ),
+		::wtk::BoxEle::fixed( 4, () ),	// <-- Padding
+		::wtk::BoxEle::expand( &dir_list ),
+		::wtk::BoxEle::fixed( 4, () ),	// <-- Padding
+		::wtk::BoxEle::fixed( LINE_HEIGHT, &path_input ),
+		::wtk::BoxEle::fixed( 4, () ),	// <-- Padding
+		::wtk::BoxEle::fixed( LINE_HEIGHT, &buttons ),
+		)) );
+
+	let mut win = ::wtk::Window::new_def("File Picker", &vbox).expect("Cannot create file picker window");
+	win.set_title(title);
+	win.set_dims(300, 240);
+	win.set_pos(100, 100);
+	win.taborder_add( 1, &dir_list );
+	win.taborder_add( 2, &path_input );
+	win.taborder_add( 3, &open_button );
+	win.taborder_add( 4, &cancel_button );
+	win.add_shortcut_1( ::syscalls::gui::KeyCode::Esc, || *result.borrow_mut() = Some(Selection::Cancelled) );
+	win.focus( &dir_list );
+	win.show();
+
+	// Modal loop - only services this window until a result is available
+	let mut waits = Vec::new();
+	while result.borrow().is_none()
+	{
+		waits.clear();
+		::async::WaitController::populate(&win, &mut |wi| waits.push(wi));
+		::syscalls::threads::wait(&mut waits, !0);
+		::async::WaitController::handle(&mut win, &waits);
+	}
+	win.hide();
+
+	let rv = result.borrow_mut().take().unwrap();
+	rv
+}
+
+/// Get the selected path: the entered name (relative to the listed directory, or to the root if it starts with `/`),
+/// or the selected file if no name was entered. `None` if nothing is selected.
+fn get_selection(dir_list: &::dirlist::DirList, input: &str) -> Option<Selection>
+{
+	if input == "" {
+		dir_list.selected_file().map(Selection::Path)
+	}
+	else if input.starts_with("/") {
+		Some( Selection::Path( input.as_bytes().to_owned() ) )
+	}
+	else {
+		let mut path = dir_list.cur_path().clone();
+		if path.len() > 0 {
+			path.push(b'/');
+		}
+		path.extend_from_slice(input.as_bytes());
+		Some( Selection::Path(path) )
+	}
+}
@@ -0,0 +1,56 @@
+// Tifflin OS - handle_server
+// - By John Hodge (thePowersGang)
+//
+// handle_server/src/subtree.rs
+//! Path lookup confined to a connection's root directory
+//!
+//! Paths are walked one component at a time, rejecting `.`/`..` and symbolic links (both of which could resolve to
+//! a node outside of the root).
+use syscalls::vfs::{Dir, Node, NodeType, Error};
+
+/// Iterate the non-empty components of a path
+pub fn components(path: &[u8]) -> impl Iterator<Item=&[u8]> {
+	path.split(|&b| b == b'/').filter(|c| !c.is_empty())
+}
+
+/// Split a path into its parent directory and final component
+pub fn split_last(path: &[u8]) -> (&[u8], &[u8]) {
+	let path = match path.iter().rposition(|&b| b != b'/')
+		{
+		Some(end) => &path[..end+1],
+		None => return (b"", b""),
+		};
+	match path.iter().rposition(|&b| b == b'/')
+	{
+	Some(pos) => (&path[..pos], &path[pos+1..]),
+	None => (b"", path),
+	}
+}
+
+/// Open a child of `dir`, refusing anything that could leave the root
+pub fn open_child(dir: &Dir, name: &[u8]) -> Result<Node, Error> {
+	if name == b"" || name == b"." || name == b".." {
+		return Err(Error::MalformedPath);
+	}
+	let node = dir.open_child(name)?;
+	match node.class()
+	{
+	NodeType::Symlink => Err(Error::PermissionDenied),
+	_ => Ok(node),
+	}
+}
+
+/// Open a directory within `root` (an empty path is the root itself)
+pub fn open_dir(root: &Dir, path: &[u8]) -> Result<Dir, Error> {
+	let mut dir = root.clone();
+	for name in components(path) {
+		dir = open_child(&dir, name)?.into_dir()?;
+	}
+	Ok(dir)
+}
+
+/// Open a node within `root`
+pub fn open_node(root: &Dir, path: &[u8]) -> Result<Node, Error> {
+	let (parent, name) = split_last(path);
+	open_child(&open_dir(root, parent)?, name)
+}
@@ -29,7 +29,10 @@ pub fn allocate(size: usize, align: usize) -> *mut u8
 	match S_GLOBAL_HEAP.lock().allocate(size, align)
 	{
 	Ok(x) => x as *mut u8,
-	Err(_) => panic!("alloc_raw({}, {}) out of memory", size, align),
+	Err(_) => {
+		kernel_log!("allocate({}, {}) out of memory", size, align);
+		::core::ptr::null_mut()
+		},
 	}
 }
 pub unsafe fn deallocate(ptr: *mut u8, _size: usize, align: usize)
@@ -106,7 +109,7 @@ impl AllocState
 			return Ok( EMPTY as *mut () );
 		}
 		if self.start == self.past_end {
-			self.extend_reservation(size);
+			self.extend_reservation(size)?;
 
 			let block = self.last_block();
 			let rv = block.allocate(size, align);
@@ -126,7 +129,7 @@ impl AllocState
 		}
 
 		let current_extra = self.last_block().self_free().map(|blk| blk.capacity(align)).unwrap_or(0);
-		self.extend_reservation(size - current_extra);
+		self.extend_reservation(size - current_extra)?;
 
 		let block = self.last_block();
 		let rv = block.allocate(size, align);
@@ -208,19 +211,26 @@ impl AllocState
 		// SAFE: Mutable borrow prevents any form of aliasing
 		unsafe { &mut *(*self.past_end).prev() }
 	}
-	fn extend_reservation(&mut self, required_space: usize) {
+	/// Extend the heap to fit at least `required_space` bytes, fails if the heap is full or memory is exhausted
+	fn extend_reservation(&mut self, required_space: usize) -> Result<(), ()> {
 		let npages = (required_space + size_of::<Block>() + size_of::<BlockTail>() + PAGE_SIZE-1) / PAGE_SIZE;
 		assert!(npages > 0);
-		assert!(self.past_end != HEAP_LIMITS.1 as *mut Block);
-		assert!(self.past_end as usize + (npages * PAGE_SIZE) <= HEAP_LIMITS.1);	// TODO: This isn't an assert conditon, it's an OOM
 		if self.start.is_null() {
 			self.start = HEAP_LIMITS.0 as *mut Block;
 			self.past_end = HEAP_LIMITS.0 as *mut Block;
 		}
 
+		if npages > (HEAP_LIMITS.1 - self.past_end as usize) / PAGE_SIZE {
+			kernel_log!("Heap limit reached ({} pages requested)", npages);
+			return Err( () );
+		}
+
 		// SAFE: Allocates only in controlled region.
 		let cb = unsafe {
-			::syscalls::memory::allocate(self.past_end as usize, npages).expect("Heap allocation failure");
+			if let Err(e) = ::syscalls::memory::allocate(self.past_end as usize, npages) {
+				kernel_log!("Heap allocation failure - {:?}", e);
+				return Err( () );
+			}
 			(*self.past_end).initialise(npages * PAGE_SIZE);
 			&mut *self.past_end
 			};
@@ -232,6 +242,7 @@ impl AllocState
 				cb.try_merge_left();
 			}
 		}
+		Ok( () )
 	}
 	fn free_blocks(&mut self) -> FreeBlocks {
 		FreeBlocks { cur: self.start, state: self, }
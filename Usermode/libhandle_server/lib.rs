@@ -15,6 +15,7 @@ pub struct Connection
 	channel: ::syscalls::ipc::RpcChannel,
 }
 
+#[derive(Debug)]
 pub enum OpenError
 {
 	/// The user cancelled the file open
@@ -23,6 +24,24 @@ pub enum OpenError
 	NotFound,
 	/// The application requested a file, but permission was denied
 	PermissionDenied,
+	/// The handle server could not complete the request
+	Failed,
+	/// The connection to the handle server was closed
+	ConnectionClosed,
+	/// The handle server sent a malformed or unexpected response
+	BadResponse,
+}
+impl OpenError
+{
+	fn from_rsp(e: &protocol::RspError) -> OpenError {
+		match e.error_id()
+		{
+		protocol::ERR_CANCELLED => OpenError::Cancelled,
+		protocol::ERR_NOT_FOUND => OpenError::NotFound,
+		protocol::ERR_PERMISSION_DENIED => OpenError::PermissionDenied,
+		_ => OpenError::Failed,
+		}
+	}
 }
 
 impl Connection
@@ -57,20 +76,7 @@ impl Connection
 	/// Open a named executable
 	pub fn open_executable(&self, name: &str) -> Result< ::syscalls::vfs::File, OpenError > {
 		self.channel.send( protocol::ReqOpenExecutable::new(name).into() );
-		::syscalls::threads::wait(&mut [ self.channel.wait_rx() ], !0);
-		let (rsp, obj) = self.channel.try_receive().unwrap();
-		match protocol::Response::try_from(rsp)
-		{
-		Ok(protocol::Response::OpenedFile(_v)) => {
-			Ok( obj.expect("No handle returned with OpenFile response").downcast_panic() )
-			},
-		Ok(protocol::Response::Error(e)) => {
-			panic!("TODO: ERROR: {:?}", e);
-			//Err( OpenError::NotFound )
-			},
-		Ok(_) => panic!("Unexpected response from handle server"),
-		Err(_) => panic!("Error receiving response from handle server"),
-		}
+		self.wait_opened_file()
 	}
 
 	//pub fn open_file(&self, _name: &str, mode: OpenMode) -> Result< ::syscalls::vfs::File, OpenError > {
@@ -96,8 +102,72 @@ impl Connection
 	}
 
 	/// Helper: Abstracts the select_file_* functions
-	fn select_file(&self, _reason: &str, _mode: OpenMode) -> Result< ::syscalls::vfs::File, OpenError > {
-		unimplemented!()
+	fn select_file(&self, reason: &str, mode: OpenMode) -> Result< ::syscalls::vfs::File, OpenError > {
+		let mode = match mode
+			{
+			OpenMode::ReadOnly => protocol::PickFileMode::ReadOnly,
+			OpenMode::ReadWrite => protocol::PickFileMode::ReadWrite,
+			OpenMode::OptionalWrite => protocol::PickFileMode::OptionalWrite,
+			OpenMode::Create => protocol::PickFileMode::Create,
+			};
+		self.channel.send( protocol::ReqPickFile::new(mode, reason).into() );
+		self.wait_opened_file()
+	}
+
+	/// Create a new named connection to the handle server (e.g. to hand to a child process)
+	///
+	/// The new connection is confined to `root` (a path relative to this connection's root, empty for the same root).
+	/// If `read_only` is set, the new connection can only be used to obtain read-only handles
+	pub fn create_child(&self, name: &str, root: &str, read_only: bool) -> Result<Connection, OpenError> {
+		let flags = if read_only { protocol::CHILD_FLAG_READONLY } else { 0 };
+		self.channel.send( protocol::ReqCreateChild::new(flags, name, root).into() );
+		let (rsp, obj) = self.wait_response()?;
+		match protocol::Response::try_from(rsp)
+		{
+		Ok(protocol::Response::NewChannel(_v)) => match obj.map(|o| o.downcast())
+			{
+			Some(Ok(ch)) => Ok( Connection::new(ch) ),
+			_ => Err( OpenError::BadResponse ),
+			},
+		Ok(protocol::Response::Error(e)) => Err( OpenError::from_rsp(&e) ),
+		Ok(_) | Err(_) => Err( OpenError::BadResponse ),
+		}
+	}
+
+	/// Obtain the underlying channel (e.g. to send it to a child process)
+	pub fn into_channel(self) -> ::syscalls::ipc::RpcChannel {
+		self.channel
+	}
+
+	/// Helper: Wait for a `RspOpenedFile` response, and return the attached file
+	fn wait_opened_file(&self) -> Result< ::syscalls::vfs::File, OpenError > {
+		let (rsp, obj) = self.wait_response()?;
+		match protocol::Response::try_from(rsp)
+		{
+		Ok(protocol::Response::OpenedFile(_v)) => match obj.map(|o| o.downcast())
+			{
+			Some(Ok(fh)) => Ok(fh),
+			_ => Err( OpenError::BadResponse ),
+			},
+		Ok(protocol::Response::Error(e)) => Err( OpenError::from_rsp(&e) ),
+		Ok(_) | Err(_) => Err( OpenError::BadResponse ),
+		}
+	}
+
+	/// Helper: Block until a response is received
+	fn wait_response(&self) -> Result<(::syscalls::ipc::RpcMessage, Option<::syscalls::AnyObject>), OpenError> {
+		loop
+		{
+			::syscalls::threads::wait(&mut [ self.channel.wait_rx() ], !0);
+			match self.channel.try_receive()
+			{
+			Ok(v) => return Ok(v),
+			Err(::syscalls::ipc::RxError::NoMessage) => {},
+			Err(::syscalls::ipc::RxError::ConnectionClosed) => return Err( OpenError::ConnectionClosed ),
+			// - The response stays queued, so there's no way to make progress
+			Err(::syscalls::ipc::RxError::NoFreeHandles) => return Err( OpenError::Failed ),
+			}
+		}
 	}
 }
 
@@ -105,24 +105,40 @@ impl RequestId
 	}
 }
 
+/// `ReqCreateChild` flag: The child connection can only obtain read-only handles
+pub const CHILD_FLAG_READONLY: u8 = 1 << 0;
+
 def_proto_type! {
 	RequestId::CreateChild => ReqCreateChild
 	struct {
-		name_buf: [u8; 31],
+		flags: u8,
+		name_buf: [u8; 12],
+		root_buf: [u8; 18],
 	}
-	new(name: &str) {
+	new(flags: u8, name: &str, root: &str) {
+		flags: flags,
 		name_buf: zero_pad_bytes_into(name.as_bytes()),
+		root_buf: zero_pad_bytes_into(root.as_bytes()),
 	}
 	try_from(v) {
-		name_buf: zero_pad_bytes_into(&v[1..]),
+		flags: v[1],
+		name_buf: zero_pad_bytes_into(&v[2..14]),
+		root_buf: zero_pad_bytes_into(&v[14..]),
 	}
 }
 def_message_transmute! { ReqCreateChild }
 impl ReqCreateChild
 {
+	pub fn flags(&self) -> u8 {
+		self.flags
+	}
 	pub fn name(&self) -> &[u8] {
 		get_zero_terminated_slice(&self.name_buf)
 	}
+	/// Root directory of the child, relative to the parent's root (empty for the parent's root)
+	pub fn root(&self) -> &[u8] {
+		get_zero_terminated_slice(&self.root_buf)
+	}
 }
 
 def_proto_type! {
@@ -164,6 +180,7 @@ def_proto_type! {
 		description: zero_pad_bytes_into(&v[2..]),
 	}
 }
+def_message_transmute! { ReqPickFile }
 impl ReqPickFile
 {
 	pub fn mode(&self) -> PickFileMode {
@@ -233,7 +250,7 @@ enum ResponseId
 impl ResponseId
 {
 	pub fn try_from(v: u8) -> Option<Self> {
-		if v <= ResponseId::OpenedFile as u8 {
+		if v <= ResponseId::NewChannel as u8 {
 			// SAFE: Range checked
 			Some(unsafe { ::core::mem::transmute(v) })
 		}
@@ -243,6 +260,15 @@ impl ResponseId
 	}
 }
 
+/// `RspError` code: Unspecified/general failure
+pub const ERR_GENERAL: u8 = 0;
+/// `RspError` code: The user cancelled the request
+pub const ERR_CANCELLED: u8 = 1;
+/// `RspError` code: The requested file wasn't found
+pub const ERR_NOT_FOUND: u8 = 2;
+/// `RspError` code: The request was denied (e.g. write access on a read-only connection)
+pub const ERR_PERMISSION_DENIED: u8 = 3;
+
 def_proto_type! {
 	ResponseId::Error => RspError
 	struct {
@@ -265,7 +291,7 @@ impl RspError
 		self.error_id
 	}
 	pub fn message(&self) -> &str {
-		::core::str::from_utf8( get_zero_terminated_slice(&self.message) ).expect("Invalid UTF-8 from handle server")
+		::core::str::from_utf8( get_zero_terminated_slice(&self.message) ).unwrap_or("(invalid UTF-8)")
 	}
 }
 impl ::core::fmt::Debug for RspError
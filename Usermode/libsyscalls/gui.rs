@@ -80,6 +80,16 @@ pub fn set_group(grp: Group)
 	// SAFE: Syscall
 	unsafe { syscall!(GUI_BINDGROUP, grp.into_handle().into_raw() as usize); }
 }
+/// Select the keyboard layout by name (e.g. "us", "uk", "de", "fr"), errors if the layout isn't known
+pub fn set_keymap(name: &str) -> Result<(),()>
+{
+	// SAFE: Syscall
+	match super::to_result( unsafe { syscall!(GUI_SETKEYMAP, name.as_ptr() as usize, name.len()) } as usize )
+	{
+	Ok(_) => Ok( () ),
+	Err(_) => Err( () ),
+	}
+}
 pub fn clone_group_handle() -> Group
 {
 	// SAFE: Syscall with no arguments (... I feel dirty)
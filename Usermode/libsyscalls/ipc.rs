@@ -21,12 +21,6 @@ impl ::Object for RpcChannel
 	}
 
 	type Waits = RpcChannelWaits;
-	fn get_wait(&self, waits: Self::Waits) -> ::values::WaitItem {
-		::values::WaitItem { object: 0, flags: waits.0 }
-	}
-	fn check_wait(&self, wi: &::values::WaitItem) -> Self::Waits {
-		RpcChannelWaits(wi.flags)
-	}
 }
 define_waits!{ RpcChannelWaits => (
 	rx:has_rx = ::values::EV_IPC_RPC_RECV,
@@ -59,11 +53,14 @@ impl RpcChannel
 		let mut msg: RpcMessage = Default::default();
 		// SAFE: Syscall
 		let rv = unsafe { self.0.call_1(::values::IPC_RPC_RECV, &mut msg as *mut _ as usize) };
-		if rv < 0x1000 {
-			Ok( (msg, if rv > 0 { Some(::AnyObject(::ObjectHandle(rv as u32))) } else { None }) )
-		}
-		else {
-			unimplemented!()
+		match rv
+		{
+		0 => Ok( (msg, None) ),
+		1 ..= 0xFFF => Ok( (msg, Some(::AnyObject(::ObjectHandle(rv as u32)))) ),
+		0x1000 => Err( RxError::NoMessage ),
+		0x1001 => Err( RxError::ConnectionClosed ),
+		0x1002 => Err( RxError::NoFreeHandles ),
+		_ => panic!("RpcChannel::try_receive - Unexpected return value {:#x}", rv),
 		}
 	}
 
@@ -77,8 +74,80 @@ pub enum RxError
 {
 	NoMessage,
 	ConnectionClosed,
+	/// The message has an attached object, but the process has no free handles (the message stays queued)
+	NoFreeHandles,
 }
 
 #[derive(Debug)]
 pub struct NewError( () );
 
+
+/// Shared memory buffer
+///
+/// Can be cloned and sent to other processes (e.g. using `RpcChannel::send_obj`), each handle refers to the same memory.
+pub struct SharedBuffer(::ObjectHandle);
+
+impl ::Object for SharedBuffer
+{
+	const CLASS: u16 = ::values::CLASS_IPC_BUFFER;
+	fn class() -> u16 { Self::CLASS }
+	fn from_handle(handle: ::ObjectHandle) -> Self {
+		SharedBuffer(handle)
+	}
+	fn into_handle(self) -> ::ObjectHandle {
+		self.0
+	}
+	fn handle(&self) -> &::ObjectHandle {
+		&self.0
+	}
+
+	type Waits = SharedBufferWaits;
+}
+define_waits!{ SharedBufferWaits => (
+	consumed:has_consumed = ::values::EV_IPC_BUFFER_CONSUMED,
+)}
+impl SharedBuffer
+{
+	/// Allocate a new (zeroed) buffer of `page_count` pages
+	pub fn new(page_count: usize) -> Result<SharedBuffer, NewError> {
+		// SAFE: Syscall
+		let rv = unsafe { syscall!(IPC_NEWBUFFER, page_count) };
+		if rv == !0 {
+			Err( NewError(()) )
+		}
+		else {
+			Ok( SharedBuffer( super::ObjectHandle::new(rv as usize).expect("SharedBuffer::new - bad handle") ) )
+		}
+	}
+	/// Obtain another handle to the same buffer
+	pub fn try_clone(&self) -> Result<SharedBuffer, ()> {
+		self.0.try_clone().map(SharedBuffer)
+	}
+
+	/// Size of the buffer in pages
+	pub fn page_count(&self) -> usize {
+		// SAFE: Syscall with no side-effects
+		unsafe { self.0.call_0(::values::IPC_BUFFER_GETSIZE) as usize }
+	}
+
+	/// Map the buffer into the address space at `addr`
+	///
+	/// The mapping can be removed using `::syscalls::memory::deallocate`
+	pub unsafe fn map(&self, addr: usize, writable: bool) -> Result<(), MapError> {
+		super::to_result( self.0.call_2(::values::IPC_BUFFER_MAP, addr, writable as usize) as usize )
+			.map(|_| ())
+			.map_err(|code| MapError::try_from(code).expect("Bad IPC_BUFFER_MAP error"))
+	}
+
+	/// Mark the buffer as consumed (waking any process waiting on `wait_consumed`)
+	pub fn signal(&self) {
+		// SAFE: Syscall
+		unsafe { self.0.call_0(::values::IPC_BUFFER_SIGNAL); }
+	}
+	pub fn wait_consumed(&self) -> ::WaitItem {
+		self.0.get_wait(::values::EV_IPC_BUFFER_CONSUMED)
+	}
+}
+
+/// Error returned when a buffer cannot be mapped (address range in use, or invalid)
+pub use ::values::MemoryError as MapError;
@@ -84,6 +84,7 @@ pub mod threads;
 pub mod sync;
 pub mod ipc;
 pub mod net;
+pub mod storage;
 
 pub use values::WaitItem;
 
@@ -154,6 +155,17 @@ impl ObjectHandle
 		}
 	}
 
+	fn try_restrict(&self, rights: u32) -> Result<Self,()> {
+		// SAFE: Standard method
+		let v = unsafe { ::raw::syscall_1( self.call_value(::values::OBJECT_RESTRICT), rights as usize ) };
+		if v >= (1<<20) {
+			Err( () )
+		}
+		else {
+			Ok( ObjectHandle(v as u32) )
+		}
+	}
+
 	def_call!{ call_0,call_0_v => syscall_0() }
 	def_call!{ call_1,call_1_v => syscall_1(a1) }
 	def_call!{ call_2,call_2_v => syscall_2(a1, a2) }
@@ -255,8 +267,17 @@ pub trait Object
 	fn from_raw(handle: u32) -> Result<Self, FromRawError> where Self: Sized {
 		object_from_raw(handle)
 	}
+
+	/// Create a new handle to this object, with only the passed subset of rights (`OBJECT_RIGHT_*`)
+	///
+	/// Fails if this handle cannot be cloned
+	fn restricted(&self, rights: u32) -> Result<Self,()> where Self: Sized {
+		self.handle().try_restrict(rights).map(Self::from_handle)
+	}
 }
 
+pub use values::{OBJECT_RIGHT_CLONE,OBJECT_RIGHT_TRANSFER,OBJECT_RIGHT_WRITE,OBJECT_RIGHTS_ALL};
+
 #[derive(Debug)]
 pub enum FromRawError
 {
@@ -318,7 +339,7 @@ pub fn debug_value<S: ?Sized+AsRef<[u8]>>(msg: &S, v: usize) {
 }
 
 
-pub use values::TEXTINFO_KERNEL;
+pub use values::{TEXTINFO_KERNEL,TEXTINFO_PROCESS,TEXTINFO_BLOCKCACHE};
 
 #[inline]
 /// Obtain a string from the kernel
@@ -1,7 +1,9 @@
 //
 //
 //
+//! Process address space management
 
+/// Protection mode for user memory
 #[repr(u8)]
 #[derive(Debug,PartialEq)]
 pub enum ProtectionMode
@@ -9,28 +11,43 @@ pub enum ProtectionMode
 	ReadOnly   = 0,
 	ReadWrite  = 1,
 	Executable = 2,
-	ReadWriteExecute = 3,
+	// NOTE: ReadWriteExecute (3) is rejected by the kernel
 }
 
-#[derive(Debug)]
-pub struct Error;
+pub use ::values::MemoryError as Error;
+pub use ::values::MemoryState as State;
 
+#[inline]
+fn to_result(val: usize) -> Result<u32, Error> {
+	super::to_result(val).map_err(|code| Error::try_from(code).expect("Bad memory Error"))
+}
+
+/// Allocate `count` zeroed read-write pages at `addr`
 #[inline]
 pub unsafe fn allocate(addr: usize, count: usize) -> Result<(), Error> {
-	super::to_result( syscall!(MEM_ALLOCATE, addr, count) as usize )
-		.map(|_| ())
-		.map_err(|_| Error)
+	to_result( syscall!(MEM_ALLOCATE, addr, count) as usize ).map(|_| ())
 }
+/// Change the protection of `count` pages starting at `addr` (all pages must be mapped)
 #[inline]
-pub unsafe fn reprotect(addr: usize, protection: ProtectionMode) -> Result<(), Error> {
-	super::to_result( syscall!(MEM_REPROTECT, addr, protection as u8 as usize) as usize )
-		.map(|_| ())
-		.map_err(|_| Error)
+pub unsafe fn reprotect(addr: usize, count: usize, protection: ProtectionMode) -> Result<(), Error> {
+	to_result( syscall!(MEM_REPROTECT, addr, count, protection as u8 as usize) as usize ).map(|_| ())
 }
+/// Release `count` pages starting at `addr` (all pages must be mapped)
 #[inline]
-pub unsafe fn deallocate(addr: usize) -> Result<(), Error> {
-	super::to_result( syscall!(MEM_DEALLOCATE, addr) as usize )
-		.map(|_| ())
-		.map_err(|_| Error)
+pub unsafe fn deallocate(addr: usize, count: usize) -> Result<(), Error> {
+	to_result( syscall!(MEM_DEALLOCATE, addr, count) as usize ).map(|_| ())
+}
+/// Reserve `count` inaccessible guard pages at `addr`
+#[inline]
+pub unsafe fn guard(addr: usize, count: usize) -> Result<(), Error> {
+	to_result( syscall!(MEM_GUARD, addr, count) as usize ).map(|_| ())
+}
+/// Query the state of memory at `addr`, returning the number of pages (up to `max_pages`) with the same state
+#[inline]
+pub fn query(addr: usize, max_pages: usize) -> Result<(usize, State), Error> {
+	// SAFE: Query has no side-effects
+	let v = to_result( unsafe { syscall!(MEM_QUERY, addr, max_pages) } as usize )?;
+	let count = v & ((1 << ::values::MEM_QUERY_STATE_SHIFT) - 1);
+	let state = State::try_from( (v >> ::values::MEM_QUERY_STATE_SHIFT) as u8 ).expect("Bad memory state");
+	Ok( (count as usize, state) )
 }
-
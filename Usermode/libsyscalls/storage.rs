@@ -0,0 +1,44 @@
+// Tifflin OS - System Calls
+// - By John Hodge (thePowersGang)
+//
+// storage.rs
+/// Storage management (encrypted volumes)
+
+pub use ::values::CryptError;
+pub use ::values::CRYPT_KEY_MATERIAL_LEN;
+
+#[inline]
+fn to_result(val: usize) -> Result<u32, CryptError> {
+	super::to_result(val).map_err(|code| CryptError::try_from(code).expect("Bad crypt Error"))
+}
+
+/// Unlock an encrypted volume
+///
+/// The decrypted contents appear as a new physical volume named `<volume>.crypt`. Only init (PID0) may unlock volumes.
+#[inline]
+pub fn crypt_unlock(volume: &str, passphrase: &[u8]) -> Result<(), CryptError> {
+	// SAFE: Syscall
+	to_result( unsafe { syscall!(STORAGE_CRYPT_UNLOCK, volume.as_ptr() as usize, volume.len(), passphrase.as_ptr() as usize, passphrase.len()) } as usize ).map(|_| ())
+}
+
+/// Write a new encryption header to a volume
+///
+/// `key_material` must be `CRYPT_KEY_MATERIAL_LEN` random bytes. Only init (PID0) may format volumes.
+#[inline]
+pub fn crypt_format(volume: &str, passphrase: &[u8], key_material: &[u8]) -> Result<(), CryptError> {
+	// SAFE: Syscall
+	to_result( unsafe { syscall!(STORAGE_CRYPT_FORMAT,
+		volume.as_ptr() as usize, volume.len(),
+		passphrase.as_ptr() as usize, passphrase.len(),
+		key_material.as_ptr() as usize, key_material.len()
+		) } as usize ).map(|_| ())
+}
+
+/// Lock an unlocked encrypted volume, removing the decrypted volume (which must not be in use)
+///
+/// Only init (PID0) may lock volumes.
+#[inline]
+pub fn crypt_lock(volume: &str) -> Result<(), CryptError> {
+	// SAFE: Syscall
+	to_result( unsafe { syscall!(STORAGE_CRYPT_LOCK, volume.as_ptr() as usize, volume.len()) } as usize ).map(|_| ())
+}
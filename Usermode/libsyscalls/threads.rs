@@ -186,3 +186,36 @@ pub fn wait(items: &mut [WaitItem], wake_time_mono: u64) -> u32 {
 	}
 }
 
+
+pub use values::ProcessInfo;
+
+/// Obtain resource usage information for the first process with a PID of at least `min_pid`
+#[inline]
+pub fn get_process_info(min_pid: u32) -> Option<ProcessInfo> {
+	let mut info = ProcessInfo::default();
+	// SAFE: Syscall, passed a valid pointer
+	match unsafe { syscall!(CORE_PROCESSINFO, min_pid as usize, &mut info as *mut _ as usize) }
+	{
+	0 => None,
+	_ => Some(info),
+	}
+}
+
+/// Iterate over all running processes (see `get_process_info`)
+pub fn processes() -> ProcessIter {
+	ProcessIter { next_pid: 0 }
+}
+/// Iterator over running processes
+pub struct ProcessIter {
+	next_pid: u32,
+}
+impl ::core::iter::Iterator for ProcessIter {
+	type Item = ProcessInfo;
+	fn next(&mut self) -> Option<ProcessInfo> {
+		let rv = get_process_info(self.next_pid);
+		if let Some(ref p) = rv {
+			self.next_pid = p.pid + 1;
+		}
+		rv
+	}
+}
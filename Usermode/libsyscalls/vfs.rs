@@ -13,11 +13,14 @@ pub struct Dir(super::ObjectHandle);
 pub struct DirIter(::ObjectHandle);
 /// Symbolic link
 pub struct Symlink(super::ObjectHandle);
+/// Loopback volume (a file exposed as a block device), detached when dropped unless mounted
+pub struct LoopDevice(super::ObjectHandle);
 
 pub use ::values::VFSError as Error;
 pub use ::values::VFSNodeType as NodeType;
 pub use ::values::VFSFileOpenMode as FileOpenMode;
 pub use ::values::VFSMemoryMapMode as MemoryMapMode;
+pub use ::values::VFSMountError as MountError;
 
 pub static ROOT: Dir = Dir( ::ObjectHandle(2) );
 
@@ -121,6 +124,14 @@ impl File
 		to_result( unsafe { self.0.call_4l(::values::VFS_FILE_MEMMAP, ofs, read_size, mem_addr as usize, mode as u8 as usize) } as usize )
 			.map( |_| () )
 	}
+
+	/// Attach this file as a loopback volume (read-only unless the file is writable)
+	#[inline]
+	pub fn attach_loop(&self) -> Result<LoopDevice, Error> {
+		// SAFE: Syscall
+		to_obj( unsafe { self.0.call_0(::values::VFS_FILE_ATTACHLOOP) } as usize )
+			.map(|h| LoopDevice(h))
+	}
 }
 impl ::Object for File {
 	const CLASS: u16 = ::values::CLASS_VFS_FILE;
@@ -170,6 +181,27 @@ impl Dir
 		Err(code) => Err( Error::try_from(code).expect("Bad VFS Error") ),
 		}
 	}
+
+	/// Create a new file in this directory, and open it with the provided mode
+	#[inline]
+	pub fn create_file<P: ?Sized+AsRef<[u8]>>(&self, name: &P, mode: FileOpenMode) -> Result<File, Error> {
+		let name = name.as_ref();
+		// SAFE: Syscall
+		to_obj( unsafe { self.0.call_3(::values::VFS_DIR_CREATEFILE, name.as_ptr() as usize, name.len(), mode as u8 as usize) } as usize )
+			.map(|h| File(h, 0))
+	}
+
+	/// Mount a volume from a loopback device on this directory
+	///
+	/// An empty `volume` selects the first volume on the device, and an empty `fs` auto-detects the filesystem
+	#[inline]
+	pub fn mount(&self, dev: &LoopDevice, volume: &str, fs: &str) -> Result<(), MountError> {
+		// SAFE: Syscall
+		let rv = unsafe { self.0.call_5(::values::VFS_DIR_MOUNT, (dev.0).0 as usize, volume.as_ptr() as usize, volume.len(), fs.as_ptr() as usize, fs.len()) };
+		super::to_result(rv as usize)
+			.map( |_| () )
+			.map_err( |code| MountError::try_from(code).expect("Bad VFS mount error") )
+	}
 }
 impl ::Object for Dir {
 	const CLASS: u16 = ::values::CLASS_VFS_DIR;
@@ -240,3 +272,39 @@ impl ::Object for Symlink {
 
 	type Waits = ();
 }
+
+impl LoopDevice
+{
+	/// Obtain the device (physical volume) name
+	///
+	/// If the buffer is not long enough, the return value is truncated.
+	#[inline]
+	pub fn name<'a>(&self, buf: &'a mut [u8]) -> &'a [u8] {
+		// SAFE: Syscall with correct args
+		let len = unsafe { self.0.call_2(::values::VFS_LOOP_GETNAME, buf.as_mut_ptr() as usize, buf.len()) } as usize;
+		&buf[ .. ::core::cmp::min(len, buf.len())]
+	}
+	/// Obtain the name of the `idx`th volume found on the device (`None` if out of range)
+	#[inline]
+	pub fn volume<'a>(&self, idx: usize, buf: &'a mut [u8]) -> Option<&'a [u8]> {
+		// SAFE: Syscall with correct args
+		let len = unsafe { self.0.call_3(::values::VFS_LOOP_GETVOLUME, idx, buf.as_mut_ptr() as usize, buf.len()) } as usize;
+		if len == !0 {
+			None
+		}
+		else {
+			Some( &buf[ .. ::core::cmp::min(len, buf.len())] )
+		}
+	}
+}
+impl ::Object for LoopDevice {
+	const CLASS: u16 = ::values::CLASS_VFS_LOOP;
+	fn class() -> u16 { Self::CLASS }
+	fn from_handle(handle: ::ObjectHandle) -> Self {
+		LoopDevice(handle)
+	}
+	fn into_handle(self) -> ::ObjectHandle { self.0 }
+	fn handle(&self) -> &::ObjectHandle { &self.0 }
+
+	type Waits = ();
+}
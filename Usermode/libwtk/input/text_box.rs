@@ -135,13 +135,14 @@ impl<'a> ::Element for TextInput<'a>
 			state.is_dirty = true;
 			true
 			},
-		::InputEvent::KeyUp(::syscalls::gui::KeyCode::Backsp) => {
+		// Editing keys act on fire, so they auto-repeat while held
+		::InputEvent::KeyFire(::syscalls::gui::KeyCode::Backsp) => {
 			let mut state = self.state.borrow_mut();
 			state.value.pop();	// TODO: Should really pop a grapheme
 			state.is_dirty = true;
 			true
 			},
-		::InputEvent::KeyUp(::syscalls::gui::KeyCode::Return) =>
+		::InputEvent::KeyFire(::syscalls::gui::KeyCode::Return) =>
 			if let Some(ref cb) = self.submit_cb
 			{
 				cb(self, win);
@@ -349,6 +349,16 @@ impl<'a, D: Decorator> Window<'a, D>
 					)
 			}
 			},
+		::InputEvent::MouseScroll(x,y,dx,dy) => {
+			if ! self.client_rect().contains( Pos::new(x,y) ) {
+				false
+			}
+			else {
+				self.root.with_element_at_pos( Pos::new(x,y), self.surface.rect().dims(),
+					&mut |ele, p| ele.handle_event( ::InputEvent::MouseScroll(p.x.0, p.y.0, dx, dy), self )
+					)
+			}
+			},
 		ev @ _ => {
 			let ::decorator::EventHandled { capture, rerender } = self.decorator.handle_event(ev, self);
 			if capture
@@ -49,7 +49,7 @@ pub extern "C" fn loader_main(cmdline: *mut u8, cmdline_len: usize) -> !
 	//	extern "C" {
 	//		static init_stack_base: [u8; 0];
 	//	}
-	//	let _ = ::syscalls::memory::deallocate( (init_stack_base.as_ptr() as usize) - ::PAGE_SIZE );
+	//	let _ = ::syscalls::memory::deallocate( (init_stack_base.as_ptr() as usize) - ::PAGE_SIZE, 1 );
 	//}
 	
 	// 1. Print the INIT parameter from the kernel
@@ -182,7 +182,7 @@ fn load_binary(path: &::std::ffi::OsStr, fh: ::syscalls::vfs::File) -> usize
 					fp.read_at(segment.file_addr + aligned as u64, destslice).expect("Failure reading file data for end of .segment");
 					// - Reprotect to the real mode, not bothering if the desired is Read-Write
 					if alloc_mode != ProtectionMode::ReadWrite {
-						::syscalls::memory::reprotect(destslice.as_ptr() as usize, alloc_mode).expect("reprotect");
+						::syscalls::memory::reprotect(destslice.as_ptr() as usize, 1, alloc_mode).expect("reprotect");
 					}
 				}
 			}
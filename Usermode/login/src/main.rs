@@ -155,6 +155,7 @@ fn spawn_console_and_wait(path: &str)
 		let fh = open_exe(path).unwrap_or_else(|e| panic!("Couldn't open handle server - {:?}", e));
 		let pp = loader::new_process(fh, path.as_bytes(), &[]).expect("Could not spawn handle server");
 		pp.send_obj( "RwRoot", VFS_ROOT.clone() );
+		pp.send_obj( "guigrp", ::syscalls::gui::clone_group_handle() );
 		pp.send_obj( "HsChan", hs_svr_chan );
 		pp.start()
 		};
@@ -26,7 +26,7 @@ impl InputStack
 		kernel_log!("handle_key: (ev={:?},...)", ev);
 		match ev
 		{
-		::syscalls::gui::Event::KeyUp(keycode) =>
+		::syscalls::gui::Event::KeyFire(keycode) =>
 			match KeyCode::from(keycode as u8)
 			{
 			KeyCode::Return | KeyCode::KpEnter => Some( ::std::mem::replace(&mut self.buffer, String::new()) ),
@@ -135,6 +135,9 @@ struct ShellState
 
 	/// Current working directory, relative to /
 	cwd_rel: String,
+
+	/// Attached loopback devices (detached when removed)
+	loop_devices: Vec<::syscalls::vfs::LoopDevice>,
 }
 
 
@@ -147,6 +150,7 @@ impl ShellState
 	pub fn new() -> ShellState {
 		ShellState {
 			cwd_rel: Default::default(),
+			loop_devices: Vec::new(),
 			root_handle: panic!("TODO: Open/acquire the root directory"),
 			}
 	}
@@ -189,8 +193,91 @@ impl ShellState
 			while let Some(v) = args.next() {
 				print!(term, "{} ", v);
 			},
+		// 'ps' - List running processes
+		Some("ps") => command_ps(term),
+		// 'losetup' - Attach/detach/list loopback devices
+		Some("losetup") =>
+			match args.next()
+			{
+			None => for dev in self.loop_devices.iter() {
+				print_loop_device(term, dev);
+				},
+			Some("-d") =>
+				if let Some(name) = args.next()
+				{
+					let mut buf = [0; 32];
+					match self.loop_devices.iter().position(|d| d.name(&mut buf) == name.as_bytes())
+					{
+					Some(i) => { self.loop_devices.remove(i); },
+					None => print!(term, "No loopback device '{}'", name),
+					}
+				}
+				else
+				{
+					print!(term, "Usage: losetup -d <device>");
+				},
+			Some(arg) => {
+				use syscalls::vfs::FileOpenMode;
+				// `-w` attaches read-write (the file is opened exclusively)
+				let (path, mode) = match arg
+					{
+					"-w" => match args.next()
+						{
+						Some(path) => (path, FileOpenMode::ExclRW),
+						None => {
+							print!(term, "Usage: losetup [-w] <file>");
+							return ;
+							},
+						},
+					path => (path, FileOpenMode::ReadOnly),
+					};
+				match self.root_handle.open_child_path(path).and_then(|n| n.into_file(mode))
+				{
+				Ok(file) => match file.attach_loop()
+					{
+					Ok(dev) => {
+						print_loop_device(term, &dev);
+						self.loop_devices.push(dev);
+						},
+					Err(e) => print!(term, "Unable to attach '{}': {:?}", path, e),
+					},
+				Err(e) => print!(term, "Unable to open '{}': {:?}", path, e),
+				}
+				},
+			},
+		// 'mount' - Mount a volume from a loopback device
+		Some("mount") =>
+			match (args.next(), args.next())
+			{
+			(Some(name), Some(dir)) => {
+				let volume = args.next().unwrap_or("");
+				let fs = args.next().unwrap_or("");
+				let mut buf = [0; 32];
+				match self.loop_devices.iter().find(|d| d.name(&mut buf) == name.as_bytes())
+				{
+				Some(dev) => match self.root_handle.open_child_path(dir).and_then(|n| n.into_dir())
+					{
+					Ok(dir_h) => if let Err(e) = dir_h.mount(dev, volume, fs) {
+						print!(term, "Unable to mount: {:?}", e);
+						},
+					Err(e) => print!(term, "Unable to open '{}': {:?}", dir, e),
+					},
+				None => print!(term, "No loopback device '{}'", name),
+				}
+				},
+			_ => print!(term, "Usage: mount <device> <dir> [volume] [filesystem]"),
+			},
+		// 'keymap' - Change the keyboard layout
+		Some("keymap") =>
+			match args.next()
+			{
+			Some(name) => if ::syscalls::gui::set_keymap(name).is_err() {
+				print!(term, "Unknown keymap '{}'", name);
+				},
+			None => print!(term, "Usage: keymap <layout>"),
+			},
 		Some("help") => {
-			print!(term, "Builtins: pwd, cd, ls, cat, help, echo");
+			print!(term, "Builtins: pwd, cd, ls, cat, help, echo, ps, losetup, mount, keymap");
 			},
 		Some(cmd @_) => {
 			print!(term, "Unkown command '{}'", cmd);
@@ -267,6 +354,35 @@ fn command_ls<T: ::Terminal>(term: &T, root: &::syscalls::vfs::Dir, path: &str)
 	}
 }
 
+/// Print a loopback device's name and the volumes found on it
+fn print_loop_device<T: ::Terminal>(term: &T, dev: &::syscalls::vfs::LoopDevice)
+{
+	let mut buf = [0; 32];
+	print!(term, "{}:", ::std::str::from_utf8(dev.name(&mut buf)).unwrap_or("?"));
+	let mut idx = 0;
+	while let Some(v) = dev.volume(idx, &mut buf)
+	{
+		print!(term, " {}", ::std::str::from_utf8(v).unwrap_or("?"));
+		idx += 1;
+	}
+	print!(term, "\n");
+}
+
+/// List running processes and their resource usage
+fn command_ps<T: ::Terminal>(term: &T)
+{
+	print!(term, "  PID THR HND   PAGES    CPU(ms) NAME\n");
+	for p in ::syscalls::threads::processes()
+	{
+		let mut name_buf = [0; 64];
+		let name = ::syscalls::get_text_info(::syscalls::TEXTINFO_PROCESS, p.pid, &mut name_buf);
+		print!(term, "{:5} {:3} {:3} {:7} {:10} {}{}\n",
+			p.pid, p.thread_count, p.handle_count, p.resident_pages, p.cpu_time_ms,
+			name, if p.exited != 0 { " (exited)" } else { "" }
+			);
+	}
+}
+
 
 /// Trait to provde 'is_combining', used by render code
 pub trait UnicodeCombining
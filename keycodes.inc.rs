@@ -145,7 +145,26 @@ pub enum KeyCode
 	RightCtrl,
 	RightShift,
 	RightAlt,
-	RightGui
+	RightGui,
+
+	// Media and ACPI keys (no HID keyboard usage, so placed after the modifiers)
+	MediaPlayPause = 0xE8,
+	MediaStop,
+	MediaPrevTrack,
+	MediaNextTrack,
+	MediaSelect,
+	WwwHome,
+	WwwBack,
+	WwwForward,
+	WwwStop,
+	WwwRefresh,
+	WwwSearch,
+	WwwFavourites,
+	Mail,
+	Calculator,
+	MyComputer,
+	Sleep,
+	Wake
 }
 
 impl KeyCode
@@ -178,6 +197,12 @@ impl ::core::convert::From<u8> for KeyCode
 			else if v <= KeyCode::RightGui as u8 {
 				::core::mem::transmute(v as u8)
 			}
+			else if v < KeyCode::MediaPlayPause as u8 {
+				panic!("KeyCode::from - Out of range");
+			}
+			else if v <= KeyCode::Wake as u8 {
+				::core::mem::transmute(v as u8)
+			}
 			else {
 				panic!("KeyCode::from - Out of range");
 			}
@@ -71,6 +71,8 @@ def_groups! {
 		=8: CORE_FUTEX_SLEEP,
 		/// Wake a number of sleepers on a futex
 		=9: CORE_FUTEX_WAKE,
+		/// Obtain resource usage for the first process with a PID at or above the passed PID
+		=10: CORE_PROCESSINFO,
 	},
 	/// GUI System calls
 	=1: GROUP_GUI = {
@@ -82,17 +84,28 @@ def_groups! {
 		=2: GUI_GETGROUP,
 		/// Create a new window in the current group
 		=3: GUI_NEWWINDOW,
+		/// Select the keyboard layout by name (e.g. "us", "de"), errors if there's no such layout
+		=4: GUI_SETKEYMAP,
 	},
 	/// Process memory management
 	=2: GROUP_MEM = {
+		/// Allocate a range of (zeroed) pages
 		=0: MEM_ALLOCATE,
+		/// Change the protection of a range of pages (guard pages can only be released)
 		=1: MEM_REPROTECT,
+		/// Release a range of pages
 		=2: MEM_DEALLOCATE,
+		/// Query the state of the address space at an address (returns page count and `MemoryState`)
+		=3: MEM_QUERY,
+		/// Reserve a range of inaccessible guard pages
+		=4: MEM_GUARD,
 	},
 	/// Process memory management
 	=3: GROUP_IPC = {
 		/// Allocate a handle pair (returns two object handles)
 		=0: IPC_NEWPAIR,
+		/// Allocate a shared memory buffer of the given number of pages (returns an object handle)
+		=1: IPC_NEWBUFFER,
 	},
 	/// Netwokring
 	=4: GROUP_NETOWRK = {
@@ -102,11 +115,45 @@ def_groups! {
 		=1: NET_LISTEN,
 		/// Open a free-form datagram 'socket'
 		=2: NET_BIND,
+	},
+	/// Storage management
+	=5: GROUP_STORAGE = {
+		/// Unlock an encrypted volume (volume name, passphrase)
+		/// - Only available to PID0 (init)
+		=0: STORAGE_CRYPT_UNLOCK,
+		/// Write a new encryption header to a volume (volume name, passphrase, `CRYPT_KEY_MATERIAL_LEN` random bytes)
+		/// - Only available to PID0 (init)
+		=1: STORAGE_CRYPT_FORMAT,
+		/// Lock an unlocked encrypted volume, removing the decrypted volume (volume name)
+		/// - Only available to PID0 (init)
+		=2: STORAGE_CRYPT_LOCK,
 	}
 }
 
 /// Value for `get_text_info`'s `unit` argument, indicating kernel core
 pub const TEXTINFO_KERNEL: u32 = 0;
+/// Value for `get_text_info`'s `unit` argument, requesting the name of the process with PID `id`
+pub const TEXTINFO_PROCESS: u32 = 1;
+/// Value for `get_text_info`'s `unit` argument, requesting block cache statistics for the `id`th cached volume
+pub const TEXTINFO_BLOCKCACHE: u32 = 2;
+
+#[repr(C)]
+#[derive(Debug,Default)]
+/// Process information returned by the CORE_PROCESSINFO system call
+pub struct ProcessInfo {
+	/// Process ID
+	pub pid: u32,
+	/// Number of live threads
+	pub thread_count: u32,
+	/// Number of object handles held
+	pub handle_count: u32,
+	/// Non-zero if the process has exited (but has not yet been reaped)
+	pub exited: u32,
+	/// Number of user pages mapped
+	pub resident_pages: u64,
+	/// CPU time used (in milliseconds)
+	pub cpu_time_ms: u64,
+}
 
 #[repr(C)]
 #[derive(Debug)]
@@ -123,10 +170,21 @@ pub fn get_class_name(class_idx: u16) -> &'static str {
 	CLASS_NAMES.get(class_idx as usize).unwrap_or(&"UNK")
 }
 
+/// Create a new handle with a subset of the rights of the original (returns the new handle)
+pub const OBJECT_RESTRICT: u16 = 0x3FD;
 pub const OBJECT_CLONE: u16 = 0x3FE;
 pub const OBJECT_GETCLASS: u16 = 0x3FF;
 pub const OBJECT_DROP: u16 = 0x7FF;
 
+/// Handle right: The handle may be cloned (or restricted)
+pub const OBJECT_RIGHT_CLONE: u32 = 1 << 0;
+/// Handle right: The handle may be given to another process (or sent over IPC)
+pub const OBJECT_RIGHT_TRANSFER: u32 = 1 << 1;
+/// Handle right: The object may be modified using this handle (class-specific, e.g. VFS write access)
+pub const OBJECT_RIGHT_WRITE: u32 = 1 << 2;
+/// All handle rights
+pub const OBJECT_RIGHTS_ALL: u32 = OBJECT_RIGHT_CLONE | OBJECT_RIGHT_TRANSFER | OBJECT_RIGHT_WRITE;
+
 // Define all classes, using c-like enums to ensure that values are not duplicated
 macro_rules! def_classes {
 	(
@@ -218,6 +276,8 @@ def_classes! {
 		=2: VFS_FILE_WRITEAT,
 		/// Map part of the file into the current address space
 		=3: VFS_FILE_MEMMAP,
+		/// Attach the file as a loopback volume (returns a `CLASS_VFS_LOOP` handle)
+		=4: VFS_FILE_ATTACHLOOP,
 		--
 	}|{
 	},
@@ -229,6 +289,10 @@ def_classes! {
 		=1: VFS_DIR_OPENCHILD,
 		/// Open a sub-path
 		=2: VFS_DIR_OPENPATH,
+		/// Mount a volume from a loopback handle on this directory (loop handle, volume name, filesystem name)
+		=3: VFS_DIR_MOUNT,
+		/// Create and open a new file (name, open mode)
+		=4: VFS_DIR_CREATEFILE,
 		--
 	}|{
 	},
@@ -322,17 +386,28 @@ def_classes! {
 	--
 	}|{
 	},
-/*
-	/// A registered read/write buffer
-	=12: CLASS_BUFFER = {
+	/// Shared memory buffer
+	=14: CLASS_IPC_BUFFER = {
+		/// Get the size of the buffer (in pages)
+		=0: IPC_BUFFER_GETSIZE,
+		/// Map the buffer into the current address space (address, writable), errors are `MemoryError` (writable mappings need `OBJECT_RIGHT_WRITE`)
+		=1: IPC_BUFFER_MAP,
+		/// Mark the buffer as consumed (populated/read), waking waiters on `EV_IPC_BUFFER_CONSUMED`
+		=2: IPC_BUFFER_SIGNAL,
 		--
-		/// Release the buffer (and return the memory to userland)
-		=0: BUFFER_RELEASE,
 	}|{
-		/// Fires when the buffer is used (populated/read)
-		=0: EV_BUFFER_CONSUMED,
-	}
-*/
+		/// Fires when the buffer is marked as consumed (cleared once observed)
+		=0: EV_IPC_BUFFER_CONSUMED,
+	},
+	/// Loopback volume (detached when dropped, unless mounted)
+	=15: CLASS_VFS_LOOP = {
+		/// Get the name of the physical volume (returns the full length)
+		=0: VFS_LOOP_GETNAME,
+		/// Get the name of the Nth logical volume on this volume (returns the full length, or !0 if out of range)
+		=1: VFS_LOOP_GETVOLUME,
+		--
+	}|{
+	},
 }
 
 
@@ -380,6 +455,24 @@ enum_to_from!{ VFSError => u32:
 	PermissionDenied = 2,
 	FileLocked = 3,
 	MalformedPath = 4,
+	AlreadyExists = 5,
+	OutOfSpace = 6,
+}
+enum_to_from!{ VFSMountError => u32:
+	/// The named filesystem driver isn't present
+	UnknownFilesystem = 0,
+	/// No filesystem driver recognised the volume
+	NoHandler = 1,
+	/// The mountpoint can't be used (e.g. not a directory)
+	InvalidMountpoint = 2,
+	/// Something is already mounted there
+	MountpointUsed = 3,
+	/// The filesystem driver failed to mount the volume
+	CallFailed = 4,
+	/// The named volume doesn't exist on this device
+	NoSuchVolume = 5,
+	/// The volume is already open
+	VolumeLocked = 6,
 }
 enum_to_from!{ VFSNodeType => u32:
 	File = 0,
@@ -407,6 +500,49 @@ enum_to_from!{ VFSMemoryMapMode => u8:
 	WriteBack = 3,
 }
 
+enum_to_from!{ MemoryError => u32:
+	/// Part of the requested range was already mapped
+	AlreadyMapped = 0,
+	/// Insufficient memory to complete the request
+	OutOfMemory = 1,
+	/// The address was invalid (unaligned, outside of user memory, or not mapped)
+	BadAddress = 2,
+	/// The mapping's backing (or handle) does not allow the requested access, or the page is a guard page
+	PermissionDenied = 3,
+}
+enum_to_from!{ MemoryState => u8:
+	ReadOnly = 0,
+	ReadWrite = 1,
+	Executable = 2,
+	ReadWriteExecute = 3,
+	/// Reserved but inaccessible (guard page)
+	Guard = 4,
+	/// Not mapped
+	Unmapped = 5,
+}
+/// Bit offset of the `MemoryState` in the `MEM_QUERY` return value (lower bits are the page count)
+pub const MEM_QUERY_STATE_SHIFT: u32 = 24;
+
+enum_to_from!{ CryptError => u32:
+	/// The named volume doesn't exist
+	NoSuchVolume = 0,
+	/// The volume is already open (or already unlocked)
+	VolumeLocked = 1,
+	/// The volume doesn't have a valid encryption header
+	BadHeader = 2,
+	/// The passphrase doesn't match the volume's header
+	BadPassphrase = 3,
+	/// Key material was the wrong length, or the volume is too small
+	BadParameter = 4,
+	/// An I/O error occurred accessing the volume
+	IoError = 5,
+	/// The calling process isn't allowed to perform this operation
+	PermissionDenied = 6,
+	/// The decrypted volume is still in use
+	VolumeInUse = 7,
+}
+/// Length of the random key material passed to `STORAGE_CRYPT_FORMAT`
+pub const CRYPT_KEY_MATERIAL_LEN: usize = 96;
 
 enum_to_from!{ GuiWinFlag => u8:
 	Visible = 0,
@@ -514,6 +650,8 @@ pub enum GuiEvent
 	MouseDblClick(u32,u32, u8),
 	/// Triple-clicked
 	MouseTriClick(u32,u32, u8),
+	/// Mouse wheel scrolled - X,Y, dX, dY (positive is right/down)
+	MouseScroll(u32,u32, i16,i16),
 }
 
 pub type RpcMessage = [u8; 32];